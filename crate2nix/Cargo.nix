@@ -7,9 +7,29 @@
 , pkgs ? import nixpkgs { config = {}; }
 , lib ? pkgs.lib
 , stdenv ? pkgs.stdenv
-, buildRustCrateForPkgs ? pkgs: pkgs.buildRustCrate
-  # This is used as the `crateOverrides` argument for `buildRustCrate`.
+  # The rustc and cargo to build with, the ones of `pkgs` by default.
+  # `pkgs.callPackage` passes the ones of `pkgs`, so these are only used if they differ.
+, rustc ? null
+, cargo ? rustc
+, buildRustCrateForPkgs ? pkgs:
+    if rustc == null || rustc == pkgs.rustc
+    then pkgs.buildRustCrate
+    else pkgs.buildRustCrate.override { inherit rustc cargo; }
+  # Whether to fail evaluation if a crate declares a `rust-version` newer than `rustc`.
+, checkRustVersion ? true
+  # This is used as the `crateOverrides` argument for `buildRustCrate`, merged with the
+  # built-in overrides for `-sys` crates and `extraCrateOverrides`, see `crateOverrides`.
 , defaultCrateOverrides ? pkgs.defaultCrateOverrides
+  # Crate overrides that take precedence over `defaultCrateOverrides`. Each override of a crate
+  # sees the attributes returned by the overrides with lower precedence.
+  # The overrides in crate2nix-overrides.nix next to this file are used if it exists.
+, extraCrateOverrides ?
+    if builtins.pathExists ./crate2nix-overrides.nix
+    then pkgs.callPackage ./crate2nix-overrides.nix { }
+    else { }
+  # Whether to add the built-in overrides of crate2nix for `-sys` crates, e.g. `pkg-config`
+  # and the library for `zstd-sys`, with the lowest precedence.
+, builtinSysOverrides ? true
   # The features to enable for the root_crate or the workspace_members.
 , rootFeatures ? [ "default" ]
   # If true, throw errors instead of issueing deprecation warnings.
@@ -20,11 +40,31 @@
 , targetFeatures ? []
   # Whether to perform release builds: longer compile times, faster binaries.
 , release ? true
+  # Whether `rootCrate.build` and `workspaceMembers.<name>.build` also build and run the tests
+  # of the crate. The tests of dependencies never run.
+, runWorkspaceTests ? false
+  # Overrides `runWorkspaceTests` for individual workspace members, e.g. `{ my_crate = true; }`.
+, runTestsByCrateName ? { }
+  # Whether to strip the symbol tables of the binaries of workspace members in release builds.
+, stripBinaries ? false
+  # Whether to move the debug info of the binaries of workspace members in release builds
+  # to a separate `debug` output, see `separateDebugInfo` of `stdenv.mkDerivation`.
+, separateDebugInfo ? false
+  # Whether to remove the build directory from paths that rustc embeds in the crates
+  # and to pin `SOURCE_DATE_EPOCH`, so that builds are reproducible across machines.
+, reproducible ? true
+  # Sources that replace the ones of individual crates by `<crateName>-<version>`, e.g.
+  # `{ "serde-1.0.203" = ./mirror/serde-1.0.203.crate; }` for air-gapped builds.
+  # Both packed crates and directories work.
+, crateSources ? { }
   # Additional crate2nix configuration if it exists.
 , crateConfig
   ? if builtins.pathExists ./crate-config.nix
     then pkgs.callPackage ./crate-config.nix {}
     else {}
+  # Additional arguments between these markers are kept when this file is regenerated.
+  # crate2nix:user-begin arguments
+  # crate2nix:user-end arguments
 }:
 
 rec {
@@ -39,6 +79,7 @@ rec {
     # You can override the features with rootCrate.build.override { features = [ "default" "feature1" ... ]; }.
     build = internal.buildRustCrateWithFeatures {
       inherit packageId;
+      runTests = internal.workspaceMemberRunTests { inherit packageId; };
     };
 
     # Debug support which might change between releases.
@@ -53,6 +94,7 @@ rec {
       packageId = "crate2nix";
       build = internal.buildRustCrateWithFeatures {
         packageId = "crate2nix";
+        runTests = internal.workspaceMemberRunTests { packageId = "crate2nix"; };
       };
 
       # Debug support which might change between releases.
@@ -61,6 +103,20 @@ rec {
     };
   };
 
+  # Build derivations for all crates by attribute names that stay stable if other versions
+  # of a crate are added or removed: `<crateName>_<version>` (e.g. `serde_1_0_200`) and
+  # `<crateName>` for the newest version of a crate.
+  cratesByName = lib.mapAttrs
+    (_: packageId: internal.buildRustCrateWithFeatures { inherit packageId; })
+    (internal.stablePackageIds { });
+
+  # The crate overrides that all crates are built with: the built-in overrides for `-sys` crates,
+  # `defaultCrateOverrides` and `extraCrateOverrides`, in increasing precedence.
+  # `mergeCrateOverrides [ crateOverrides myOverrides ]` adds more with the same precedence rules,
+  # e.g. for `rootCrate.build.override { crateOverrides = ...; }`.
+  crateOverrides = internal.mergedCrateOverrides;
+  inherit (internal) mergeCrateOverrides;
+
   # A derivation that joins the outputs of all workspace members together.
   allWorkspaceMembers = pkgs.symlinkJoin {
       name = "all-workspace-members";
@@ -69,6 +125,42 @@ rec {
         in builtins.map (m: m.build) members;
   };
 
+  # Flake apps for the binaries of all workspace members.
+  # Use `apps = cargoNix.apps;` in the `perSystem` outputs of a flake for `nix run .#<binary>`.
+  apps = internal.flakeApps workspaceMembers {
+    "crate2nix" = [ "crate2nix" ];
+  };
+
+  # Checks for `nix flake check`, e.g. `checks = cargoNix.checks;` in the `perSystem` outputs
+  # of a flake: the tests of all workspace members
+  # and clippy, rustfmt and the documentation of the workspace.
+  # `cargoNix.checksWith { advisoryDb = inputs.advisory-db; }` also runs `cargo audit`.
+  checks = checksWith { };
+  checksWith = args: internal.flakeChecks ({
+    members = workspaceMembers;
+    src = lib.cleanSourceWith {
+      filter = internal.gitignoreSourceFilter ./. ./.;
+      src = ./.;
+    };
+    cargoConfig = devShell.cargoConfig;
+  } // args);
+
+  # The downloaded crates in the layout of `cargo vendor --versioned-dirs`, for tools that
+  # expect a vendor directory. The crates of the workspace are not included.
+  vendorDir = internal.vendoredSources { };
+
+  # A shell in which plain `cargo build` and `cargo test` work offline with exactly the crates
+  # of this file, e.g. `nix-shell Cargo.nix -A devShell`.
+  devShell = internal.cargoDevShell {
+    sourceReplacements = {
+      "crates-io" = { };
+    };
+  };
+
+  # Additional attributes between these markers are kept when this file is regenerated.
+  # crate2nix:user-begin attributes
+  # crate2nix:user-end attributes
+
   #
   # "internal" ("private") attributes that may change in every new version of crate2nix.
   #
@@ -89,7 +181,9 @@ rec {
         crateName = "aho-corasick";
         version = "1.1.3";
         edition = "2021";
+        rustVersion = "1.60.0";
         sha256 = "05mrpkvdgp5d20y2p989f187ry9diliijgwrs254fs9s1m1x6q4f";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/aho-corasick/aho-corasick-1.1.3.crate"; };
         libName = "aho_corasick";
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
@@ -115,6 +209,7 @@ rec {
         version = "0.12.1";
         edition = "2015";
         sha256 = "1ljmkbilxgmhavxvxqa7qvm6f3fjggi7q2l3a72q9x0cxjvrnanm";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/ansi_term/ansi_term-0.12.1.crate"; };
         authors = [
           "ogham@bsago.me"
           "Ryan Scheel (Havvy) <ryan.havvy@gmail.com>"
@@ -137,7 +232,9 @@ rec {
         crateName = "anyhow";
         version = "1.0.86";
         edition = "2018";
+        rustVersion = "1.39.0";
         sha256 = "1nk301x8qhpdaks6a9zvcp7yakjqnczjmqndbg7vk4494d3d1ldk";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/anyhow/anyhow-1.0.86.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -152,6 +249,7 @@ rec {
         version = "0.2.14";
         edition = "2015";
         sha256 = "1s7yslcs6a28c5vz7jwj63lkfgyx8mx99fdirlhi9lbhhzhrpcyr";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/atty/atty-0.2.14.crate"; };
         authors = [
           "softprops <d.tangren@gmail.com>"
         ];
@@ -176,11 +274,42 @@ rec {
         ];
 
       };
+      "autocfg" = rec {
+        crateName = "autocfg";
+        version = "1.5.1";
+        edition = "2015";
+        rustVersion = "1.0.0";
+        sha256 = "0lqasy5i30flcgih1b50kvsk6z32g09r1q4ql7q81pj6228jy0zj";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/autocfg/autocfg-1.5.1.crate"; };
+        authors = [
+          "Josh Stone <cuviper@gmail.com>"
+        ];
+
+      };
+      "base64" = rec {
+        crateName = "base64";
+        version = "0.21.7";
+        edition = "2018";
+        rustVersion = "1.48.0";
+        sha256 = "0rw52yvsk75kar9wgqfwgb414kvil1gn7mqkrhn9zf1537mpsacx";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/base64/base64-0.21.7.crate"; };
+        authors = [
+          "Alice Maz <alice@alicemaz.com>"
+          "Marshall Pierce <marshall@mpierce.org>"
+        ];
+        features = {
+          "default" = [ "std" ];
+          "std" = [ "alloc" ];
+        };
+        resolvedDefaultFeatures = [ "alloc" "default" "std" ];
+      };
       "bitflags 1.3.2" = rec {
         crateName = "bitflags";
         version = "1.3.2";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "12ki6w8gn1ldq7yz9y680llwk5gmrhrzszaa17g1sbrw2r2qvwxy";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/bitflags/bitflags-1.3.2.crate"; };
         authors = [
           "The Rust Project Developers"
         ];
@@ -195,7 +324,9 @@ rec {
         crateName = "bitflags";
         version = "2.6.0";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1pkidwzn3hnxlsl8zizh0bncgbjnw7c41cx7bby26ncbzmiznj5h";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/bitflags/bitflags-2.6.0.crate"; };
         authors = [
           "The Rust Project Developers"
         ];
@@ -212,7 +343,9 @@ rec {
         crateName = "block-buffer";
         version = "0.10.4";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0w9sa2ypmrsqqvc20nhwr75wbb5cjr4kkyhpjm1z1lv2kdicfy1h";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/block-buffer/block-buffer-0.10.4.crate"; };
         authors = [
           "RustCrypto Developers"
         ];
@@ -228,7 +361,9 @@ rec {
         crateName = "bstr";
         version = "1.9.1";
         edition = "2021";
+        rustVersion = "1.65.0";
         sha256 = "01ipr5rncw3kf4dyc1p2g00njn1df2b0xpviwhb8830iv77wbvq5";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/bstr/bstr-1.9.1.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
         ];
@@ -258,7 +393,9 @@ rec {
         crateName = "camino";
         version = "1.1.7";
         edition = "2018";
+        rustVersion = "1.34.0";
         sha256 = "0ff28kc3qjcrmi8k88b2j2p7mzrvbag20yqcrj9sl30n3fanpv70";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/camino/camino-1.1.7.crate"; };
         authors = [
           "Without Boats <saoirse@without.boats>"
           "Ashley Williams <ashley666ashley@gmail.com>"
@@ -285,7 +422,9 @@ rec {
         crateName = "cargo-platform";
         version = "0.1.8";
         edition = "2021";
+        rustVersion = "1.73.0";
         sha256 = "1z5b7ivbj508wkqdg2vb0hw4vi1k1pyhcn6h1h1b8svcb8vg1c94";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/cargo-platform/cargo-platform-0.1.8.crate"; };
         dependencies = [
           {
             name = "serde";
@@ -298,7 +437,9 @@ rec {
         crateName = "cargo_metadata";
         version = "0.18.1";
         edition = "2018";
+        rustVersion = "1.56.0";
         sha256 = "0drh0zndl4qgndy6kg6783cydbvhxgv0hcg7d9hhqx0zwi3nb21d";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/cargo_metadata/cargo_metadata-0.18.1.crate"; };
         authors = [
           "Oliver Schneider <git-spam-no-reply9815368754983@oli-obk.de>"
         ];
@@ -342,7 +483,9 @@ rec {
         crateName = "cfg-if";
         version = "1.0.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1za0vb97n4brpzpv8lsbnzmq5r8f2b0cpqqr0sy8h5bn751xxwds";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/cfg-if/cfg-if-1.0.0.crate"; };
         authors = [
           "Alex Crichton <alex@alexcrichton.com>"
         ];
@@ -356,7 +499,9 @@ rec {
         crateName = "clap";
         version = "2.34.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "071q5d8jfwbazi6zhik9xwpacx5i6kb2vkzy060vhf0c3120aqd0";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/clap/clap-2.34.0.crate"; };
         authors = [
           "Kevin K. <kbknapp@gmail.com>"
         ];
@@ -417,6 +562,7 @@ rec {
         version = "0.2.3";
         edition = "2015";
         sha256 = "1dfwjxd13f8l8bdzm76kkp6cp4sr1pyc8lavp52avwy313mhh0j1";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/colored-diff/colored-diff-0.2.3.crate"; };
         dependencies = [
           {
             name = "ansi_term";
@@ -434,11 +580,31 @@ rec {
         ];
 
       };
+      "countme" = rec {
+        crateName = "countme";
+        version = "3.0.1";
+        edition = "2018";
+        rustVersion = "1.31.0";
+        sha256 = "0dn62hhvgmwyxslh14r4nlbvz8h50cp5mnn1qhqsw63vs7yva13p";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/countme/countme-3.0.1.crate"; };
+        authors = [
+          "Aleksey Kladov <aleksey.kladov@gmail.com>"
+        ];
+        features = {
+          "dashmap" = [ "dep:dashmap" ];
+          "enable" = [ "dashmap" "once_cell" "rustc-hash" ];
+          "once_cell" = [ "dep:once_cell" ];
+          "print_at_exit" = [ "enable" ];
+          "rustc-hash" = [ "dep:rustc-hash" ];
+        };
+      };
       "cpufeatures" = rec {
         crateName = "cpufeatures";
         version = "0.2.12";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "012m7rrak4girqlii3jnqwrr73gv1i980q4wra5yyyhvzwk5xzjk";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/cpufeatures/cpufeatures-0.2.12.crate"; };
         authors = [
           "RustCrypto Developers"
         ];
@@ -470,6 +636,7 @@ rec {
         crateName = "crate2nix";
         version = "0.14.1";
         edition = "2021";
+        rustVersion = "1.56.0";
         crateBin = [
           {
             name = "crate2nix";
@@ -477,7 +644,8 @@ rec {
             requiredFeatures = [ ];
           }
         ];
-        src = lib.cleanSourceWith { filter = sourceFilter;  src = ./.; };
+        src = lib.cleanSourceWith { filter = gitignoreSourceFilter ./. ./.;  src = ./.; };
+        provenance = { kind = "local"; path = "./."; };
         authors = [
           "Peter Kolloch <info@eigenvalue.net>"
         ];
@@ -486,6 +654,10 @@ rec {
             name = "anyhow";
             packageId = "anyhow";
           }
+          {
+            name = "base64";
+            packageId = "base64";
+          }
           {
             name = "cargo-platform";
             packageId = "cargo-platform";
@@ -494,6 +666,11 @@ rec {
             name = "cargo_metadata";
             packageId = "cargo_metadata";
           }
+          {
+            name = "env_logger";
+            packageId = "env_logger";
+            usesDefaultFeatures = false;
+          }
           {
             name = "hex";
             packageId = "hex";
@@ -506,6 +683,10 @@ rec {
             name = "lazy_static";
             packageId = "lazy_static";
           }
+          {
+            name = "log";
+            packageId = "log";
+          }
           {
             name = "nix-base32";
             packageId = "nix-base32";
@@ -514,6 +695,14 @@ rec {
             name = "pathdiff";
             packageId = "pathdiff";
           }
+          {
+            name = "rayon";
+            packageId = "rayon";
+          }
+          {
+            name = "rnix";
+            packageId = "rnix";
+          }
           {
             name = "semver";
             packageId = "semver";
@@ -529,6 +718,10 @@ rec {
             packageId = "serde_json";
             features = [ "unbounded_depth" ];
           }
+          {
+            name = "sha2";
+            packageId = "sha2";
+          }
           {
             name = "structopt";
             packageId = "structopt";
@@ -538,6 +731,10 @@ rec {
             packageId = "tera";
             usesDefaultFeatures = false;
           }
+          {
+            name = "thiserror";
+            packageId = "thiserror";
+          }
           {
             name = "toml";
             packageId = "toml";
@@ -568,7 +765,9 @@ rec {
         crateName = "crossbeam-deque";
         version = "0.8.5";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "03bp38ljx4wj6vvy4fbhx41q8f585zyqix6pncz1mkz93z08qgv1";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/crossbeam-deque/crossbeam-deque-0.8.5.crate"; };
         dependencies = [
           {
             name = "crossbeam-epoch";
@@ -591,7 +790,9 @@ rec {
         crateName = "crossbeam-epoch";
         version = "0.9.18";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "03j2np8llwf376m3fxqx859mgp9f83hj1w34153c7a9c7i5ar0jv";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/crossbeam-epoch/crossbeam-epoch-0.9.18.crate"; };
         dependencies = [
           {
             name = "crossbeam-utils";
@@ -612,18 +813,22 @@ rec {
         crateName = "crossbeam-utils";
         version = "0.8.20";
         edition = "2021";
+        rustVersion = "1.60.0";
         sha256 = "100fksq5mm1n7zj242cclkw6yf7a4a8ix3lvpfkhxvdhbda9kv12";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/crossbeam-utils/crossbeam-utils-0.8.20.crate"; };
         features = {
           "default" = [ "std" ];
           "loom" = [ "dep:loom" ];
         };
-        resolvedDefaultFeatures = [ "std" ];
+        resolvedDefaultFeatures = [ "default" "std" ];
       };
       "crypto-common" = rec {
         crateName = "crypto-common";
         version = "0.1.6";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1cvby95a6xg7kxdz5ln3rl9xh66nz66w46mm3g56ri1z5x815yqv";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/crypto-common/crypto-common-0.1.6.crate"; };
         authors = [
           "RustCrypto Developers"
         ];
@@ -642,12 +847,15 @@ rec {
           "getrandom" = [ "rand_core/getrandom" ];
           "rand_core" = [ "dep:rand_core" ];
         };
+        resolvedDefaultFeatures = [ "std" ];
       };
       "digest" = rec {
         crateName = "digest";
         version = "0.10.7";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "14p2n6ih29x81akj097lvz7wi9b6b9hvls0lwrv7b6xwyy0s5ncy";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/digest/digest-0.10.7.crate"; };
         authors = [
           "RustCrypto Developers"
         ];
@@ -675,13 +883,15 @@ rec {
           "std" = [ "alloc" "crypto-common/std" ];
           "subtle" = [ "dep:subtle" ];
         };
-        resolvedDefaultFeatures = [ "block-buffer" "core-api" "default" ];
+        resolvedDefaultFeatures = [ "alloc" "block-buffer" "core-api" "default" "std" ];
       };
       "dissimilar" = rec {
         crateName = "dissimilar";
         version = "1.0.9";
         edition = "2018";
+        rustVersion = "1.36.0";
         sha256 = "0bcn4s99ghigd3yadpd7i3gljv5z2hkr07ijvvxvsxmz3yfygy2r";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/dissimilar/dissimilar-1.0.9.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -691,7 +901,9 @@ rec {
         crateName = "either";
         version = "1.13.0";
         edition = "2018";
+        rustVersion = "1.37.0";
         sha256 = "1w2c1mybrd7vljyxk77y9f4w9dyjrmp3yp82mk7bcm8848fazcb0";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/either/either-1.13.0.crate"; };
         authors = [
           "bluss"
         ];
@@ -701,18 +913,79 @@ rec {
         };
         resolvedDefaultFeatures = [ "use_std" ];
       };
+      "env_filter" = rec {
+        crateName = "env_filter";
+        version = "2.0.0";
+        edition = "2021";
+        rustVersion = "1.71.0";
+        sha256 = "05s267np8pphhpxzrzl4j956gjj87f4ik6yas7l1x6kr0cd2f3ch";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/env_filter/env_filter-2.0.0.crate"; };
+        dependencies = [
+          {
+            name = "log";
+            packageId = "log";
+          }
+          {
+            name = "regex";
+            packageId = "regex";
+            optional = true;
+            usesDefaultFeatures = false;
+            features = [ "perf" ];
+          }
+        ];
+        features = {
+          "default" = [ "std" "regex" ];
+          "regex" = [ "dep:regex" ];
+          "std" = [ "log/std" "regex?/std" ];
+        };
+        resolvedDefaultFeatures = [ "std" ];
+      };
+      "env_logger" = rec {
+        crateName = "env_logger";
+        version = "0.11.11";
+        edition = "2021";
+        rustVersion = "1.71.0";
+        sha256 = "1xnkbhnlwf45a6val2340bi7avi7fwgbm2g2kbf9g9vmgb91nryy";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/env_logger/env_logger-0.11.11.crate"; };
+        dependencies = [
+          {
+            name = "env_filter";
+            packageId = "env_filter";
+            usesDefaultFeatures = false;
+            features = [ "std" ];
+          }
+          {
+            name = "log";
+            packageId = "log";
+            features = [ "std" ];
+          }
+        ];
+        features = {
+          "auto-color" = [ "color" "anstream/auto" ];
+          "color" = [ "dep:anstream" "dep:anstyle" ];
+          "default" = [ "auto-color" "humantime" "regex" ];
+          "humantime" = [ "dep:jiff" ];
+          "kv" = [ "log/kv" ];
+          "regex" = [ "env_filter/regex" ];
+          "unstable-kv" = [ "kv" ];
+        };
+      };
       "equivalent" = rec {
         crateName = "equivalent";
         version = "1.0.1";
         edition = "2015";
+        rustVersion = "1.6.0";
         sha256 = "1malmx5f4lkfvqasz319lq6gb3ddg19yzf9s8cykfsgzdmyq0hsl";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/equivalent/equivalent-1.0.1.crate"; };
 
       };
       "form_urlencoded" = rec {
         crateName = "form_urlencoded";
         version = "1.2.1";
         edition = "2018";
+        rustVersion = "1.51.0";
         sha256 = "0milh8x7nl4f450s3ddhg57a3flcv6yq8hlkyk6fyr3mcb128dp1";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/form_urlencoded/form_urlencoded-1.2.1.crate"; };
         authors = [
           "The rust-url developers"
         ];
@@ -734,7 +1007,9 @@ rec {
         crateName = "fs_extra";
         version = "1.3.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "075i25z70j2mz9r7i9p9r521y8xdj81q7skslyb7zhqnnw33fw22";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/fs_extra/fs_extra-1.3.0.crate"; };
         authors = [
           "Denis Kurilenko <webdesus@gmail.com>"
         ];
@@ -744,7 +1019,9 @@ rec {
         crateName = "fuchsia-cprng";
         version = "0.1.1";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1fnkqrbz7ixxzsb04bsz9p0zzazanma8znfdqjvh39n14vapfvx0";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/fuchsia-cprng/fuchsia-cprng-0.1.1.crate"; };
         authors = [
           "Erick Tryzelaar <etryzelaar@google.com>"
         ];
@@ -755,6 +1032,7 @@ rec {
         version = "0.14.7";
         edition = "2015";
         sha256 = "16lyyrzrljfq424c3n8kfwkqihlimmsg5nhshbbp48np3yjrqr45";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/generic-array/generic-array-0.14.7.crate"; };
         libName = "generic_array";
         authors = [
           "Bartłomiej Kamiński <fizyk20@gmail.com>"
@@ -782,7 +1060,9 @@ rec {
         crateName = "globset";
         version = "0.4.14";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1qab0c1drpybgm4nc92lf8b46x0ap44c9y4k23rndgc5bfdkpnjp";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/globset/globset-0.4.14.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
         ];
@@ -827,7 +1107,9 @@ rec {
         crateName = "globwalk";
         version = "0.9.1";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0mz7bsa66p2rrgnz3l94ac4kbklh7mq8j30iizyxjy4qyvmn1xqb";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/globwalk/globwalk-0.9.1.crate"; };
         authors = [
           "Gilad Naaman <gilad@naaman.io>"
         ];
@@ -851,7 +1133,9 @@ rec {
         crateName = "hashbrown";
         version = "0.14.5";
         edition = "2021";
+        rustVersion = "1.63.0";
         sha256 = "1wa1vy1xs3mp11bn3z9dv0jricgr6a2j0zkf1g19yz3vw4il89z5";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/hashbrown/hashbrown-0.14.5.crate"; };
         authors = [
           "Amanieu d'Antras <amanieu@gmail.com>"
         ];
@@ -869,13 +1153,15 @@ rec {
           "rustc-dep-of-std" = [ "nightly" "core" "compiler_builtins" "alloc" "rustc-internal-api" ];
           "serde" = [ "dep:serde" ];
         };
-        resolvedDefaultFeatures = [ "raw" ];
+        resolvedDefaultFeatures = [ "inline-more" "raw" ];
       };
       "heck" = rec {
         crateName = "heck";
         version = "0.3.3";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0b0kkr790p66lvzn9nsmfjvydrbmh9z5gb664jchwgw64vxiwqkd";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/heck/heck-0.3.3.crate"; };
         authors = [
           "Without Boats <woboats@gmail.com>"
         ];
@@ -891,7 +1177,9 @@ rec {
         crateName = "hermit-abi";
         version = "0.1.19";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0cxcm8093nf5fyn114w8vxbrbcyvv91d4015rdnlgfll7cs6gd32";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/hermit-abi/hermit-abi-0.1.19.crate"; };
         authors = [
           "Stefan Lankes"
         ];
@@ -913,7 +1201,9 @@ rec {
         crateName = "hex";
         version = "0.4.3";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0w1a4davm1lgzpamwnba907aysmlrnygbqmfis2mqjx5m552a93z";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/hex/hex-0.4.3.crate"; };
         authors = [
           "KokaKiwi <kokakiwi@kokakiwi.net>"
         ];
@@ -928,7 +1218,9 @@ rec {
         crateName = "idna";
         version = "0.5.0";
         edition = "2018";
+        rustVersion = "1.51.0";
         sha256 = "1xhjrcjqq0l5bpzvdgylvpkgk94panxgsirzhjnnqfdgc4a9nkb3";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/idna/idna-0.5.0.crate"; };
         authors = [
           "The rust-url developers"
         ];
@@ -955,7 +1247,9 @@ rec {
         crateName = "ignore";
         version = "0.4.22";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1wcaqpi6djqgi1brghrdyw4d5qgnwzhqrqyn4mar4vp677gi0s5l";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/ignore/ignore-0.4.22.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
         ];
@@ -1003,7 +1297,9 @@ rec {
         crateName = "indexmap";
         version = "2.2.6";
         edition = "2021";
+        rustVersion = "1.63.0";
         sha256 = "09hgwi2ig0wyj5rjziia76zmhgfj95k0jb4ic3iiawm4vlavg3qn";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/indexmap/indexmap-2.2.6.crate"; };
         dependencies = [
           {
             name = "equivalent";
@@ -1032,7 +1328,9 @@ rec {
         crateName = "itertools";
         version = "0.10.5";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0ww45h7nxx5kj6z2y6chlskxd1igvs4j507anr6dzg99x1h25zdh";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/itertools/itertools-0.10.5.crate"; };
         authors = [
           "bluss"
         ];
@@ -1052,7 +1350,9 @@ rec {
         crateName = "itertools";
         version = "0.12.1";
         edition = "2018";
+        rustVersion = "1.43.1";
         sha256 = "0s95jbb3ndj1lvfxyq5wanc0fm0r6hg6q4ngb92qlfdxvci10ads";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/itertools/itertools-0.12.1.crate"; };
         authors = [
           "bluss"
         ];
@@ -1073,7 +1373,9 @@ rec {
         crateName = "itoa";
         version = "1.0.11";
         edition = "2018";
+        rustVersion = "1.36.0";
         sha256 = "0nv9cqjwzr3q58qz84dcz63ggc54yhf1yqar1m858m1kfd4g3wa9";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/itoa/itoa-1.0.11.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -1086,6 +1388,7 @@ rec {
         version = "1.5.0";
         edition = "2015";
         sha256 = "1zk6dqqni0193xg6iijh7i3i44sryglwgvx20spdvwk3r6sbrlmv";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/lazy_static/lazy_static-1.5.0.crate"; };
         authors = [
           "Marvin Löbel <loebel.marvin@gmail.com>"
         ];
@@ -1099,6 +1402,7 @@ rec {
         version = "0.2.155";
         edition = "2015";
         sha256 = "0z44c53z54znna8n322k5iwg80arxxpdzjj5260pxxzc9a58icwp";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/libc/libc-0.2.155.crate"; };
         authors = [
           "The Rust Project Developers"
         ];
@@ -1112,9 +1416,11 @@ rec {
       };
       "log" = rec {
         crateName = "log";
-        version = "0.4.22";
+        version = "0.4.34";
         edition = "2021";
-        sha256 = "093vs0wkm1rgyykk7fjbqp2lwizbixac1w52gv109p5r4jh0p9x7";
+        rustVersion = "1.71.0";
+        sha256 = "1ihkzn0m33ab79fcl4mkb04n5iwqzbxzyw7l7hazqkffaqzbvy7r";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/log/log-0.4.34.crate"; };
         authors = [
           "The Rust Project Developers"
         ];
@@ -1126,17 +1432,22 @@ rec {
           "kv_unstable_serde" = [ "kv_serde" "kv_unstable_std" ];
           "kv_unstable_std" = [ "kv_std" "kv_unstable" ];
           "kv_unstable_sval" = [ "kv_sval" "kv_unstable" ];
-          "serde" = [ "dep:serde" ];
+          "serde" = [ "serde_core" ];
+          "serde_core" = [ "dep:serde_core" ];
+          "std" = [ "alloc" ];
           "sval" = [ "dep:sval" ];
           "sval_ref" = [ "dep:sval_ref" ];
           "value-bag" = [ "dep:value-bag" ];
         };
+        resolvedDefaultFeatures = [ "alloc" "std" ];
       };
       "memchr" = rec {
         crateName = "memchr";
         version = "2.7.4";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "18z32bhxrax0fnjikv475z7ii718hq457qwmaryixfxsl2qrmjkq";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/memchr/memchr-2.7.4.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
           "bluss"
@@ -1152,11 +1463,32 @@ rec {
         };
         resolvedDefaultFeatures = [ "alloc" "default" "std" ];
       };
+      "memoffset" = rec {
+        crateName = "memoffset";
+        version = "0.9.1";
+        edition = "2015";
+        sha256 = "12i17wh9a9plx869g7j4whf62xw68k5zd4k0k5nh6ys5mszid028";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/memoffset/memoffset-0.9.1.crate"; };
+        authors = [
+          "Gilad Naaman <gilad.naaman@gmail.com>"
+        ];
+        buildDependencies = [
+          {
+            name = "autocfg";
+            packageId = "autocfg";
+          }
+        ];
+        features = {
+        };
+        resolvedDefaultFeatures = [ "default" ];
+      };
       "nix-base32" = rec {
         crateName = "nix-base32";
         version = "0.1.1";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "04jnq6arig0amz0scadavbzn9bg9k4zphmrm1562n6ygfj1dnj45";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/nix-base32/nix-base32-0.1.1.crate"; };
         authors = [
           "Peter Kolloch <info@eigenvalue.net>"
         ];
@@ -1166,7 +1498,9 @@ rec {
         crateName = "once_cell";
         version = "1.19.0";
         edition = "2021";
+        rustVersion = "1.60.0";
         sha256 = "14kvw7px5z96dk4dwdm1r9cqhhy2cyj1l5n5b29mynbb8yr15nrz";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/once_cell/once_cell-1.19.0.crate"; };
         authors = [
           "Aleksey Kladov <aleksey.kladov@gmail.com>"
         ];
@@ -1185,7 +1519,9 @@ rec {
         crateName = "pathdiff";
         version = "0.2.1";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1pa4dcmb7lwir4himg1mnl97a05b2z0svczg62l8940pbim12dc8";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/pathdiff/pathdiff-0.2.1.crate"; };
         authors = [
           "Manish Goregaokar <manishsmail@gmail.com>"
         ];
@@ -1197,7 +1533,9 @@ rec {
         crateName = "percent-encoding";
         version = "2.3.1";
         edition = "2018";
+        rustVersion = "1.51.0";
         sha256 = "0gi8wgx0dcy8rnv1kywdv98lwcx67hz0a0zwpib5v2i08r88y573";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/percent-encoding/percent-encoding-2.3.1.crate"; };
         authors = [
           "The rust-url developers"
         ];
@@ -1211,7 +1549,9 @@ rec {
         crateName = "pest";
         version = "2.7.10";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "1s4fvis7h6l872g6nk17r130kcllj4c0hjvwkzd3hi196g3320an";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/pest/pest-2.7.10.crate"; };
         authors = [
           "Dragoș Tiselice <dragostiselice@gmail.com>"
         ];
@@ -1244,7 +1584,9 @@ rec {
         crateName = "pest_derive";
         version = "2.7.10";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "0n8lsk9s21dp7958p9yarbk2gsc8wg0rvdzr7cd7pjpvjf8kqa96";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/pest_derive/pest_derive-2.7.10.crate"; };
         procMacro = true;
         authors = [
           "Dragoș Tiselice <dragostiselice@gmail.com>"
@@ -1273,7 +1615,9 @@ rec {
         crateName = "pest_generator";
         version = "2.7.10";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "11s6q0vf25lckbzak0qndzpv87ksaxy6pa9cvn2hlizvsgvjmhiy";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/pest_generator/pest_generator-2.7.10.crate"; };
         authors = [
           "Dragoș Tiselice <dragostiselice@gmail.com>"
         ];
@@ -1312,7 +1656,9 @@ rec {
         crateName = "pest_meta";
         version = "2.7.10";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "1kdxl164yyjsmn01lvllsll4sz3xbgy4dmkq33n63hrp5w1418np";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/pest_meta/pest_meta-2.7.10.crate"; };
         authors = [
           "Dragoș Tiselice <dragostiselice@gmail.com>"
         ];
@@ -1342,7 +1688,9 @@ rec {
         crateName = "proc-macro-error";
         version = "1.0.4";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1373bhxaf0pagd8zkyd03kkx6bchzf6g0dkwrwzsnal9z47lj9fs";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/proc-macro-error/proc-macro-error-1.0.4.crate"; };
         authors = [
           "CreepySkeleton <creepy-skeleton@yandex.ru>"
         ];
@@ -1383,7 +1731,9 @@ rec {
         crateName = "proc-macro-error-attr";
         version = "1.0.4";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0sgq6m5jfmasmwwy8x4mjygx5l7kp8s4j60bv25ckv2j1qc41gm1";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/proc-macro-error-attr/proc-macro-error-attr-1.0.4.crate"; };
         procMacro = true;
         authors = [
           "CreepySkeleton <creepy-skeleton@yandex.ru>"
@@ -1410,7 +1760,9 @@ rec {
         crateName = "proc-macro2";
         version = "1.0.86";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0xrv22p8lqlfdf1w0pj4si8n2ws4aw0kilmziwf0vpv5ys6rwway";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/proc-macro2/proc-macro2-1.0.86.crate"; };
         libName = "proc_macro2";
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
@@ -1431,7 +1783,9 @@ rec {
         crateName = "quote";
         version = "1.0.36";
         edition = "2018";
+        rustVersion = "1.56.0";
         sha256 = "19xcmh445bg6simirnnd4fvkmp6v2qiwxh5f6rw4a70h76pnm9qg";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/quote/quote-1.0.36.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -1453,6 +1807,7 @@ rec {
         version = "0.4.6";
         edition = "2015";
         sha256 = "14qjfv3gggzhnma20k0sc1jf8y6pplsaq7n1j9ls5c8kf2wl0a2m";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rand/rand-0.4.6.crate"; };
         authors = [
           "The Rust Project Developers"
         ];
@@ -1499,6 +1854,7 @@ rec {
         version = "0.3.1";
         edition = "2015";
         sha256 = "0jzdgszfa4bliigiy4hi66k7fs3gfwi2qxn8vik84ph77fwdwvvs";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rand_core/rand_core-0.3.1.crate"; };
         authors = [
           "The Rand Project Developers"
           "The Rust Project Developers"
@@ -1521,6 +1877,7 @@ rec {
         version = "0.4.2";
         edition = "2015";
         sha256 = "1p09ynysrq1vcdlmcqnapq4qakl2yd1ng3kxh3qscpx09k2a6cww";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rand_core/rand_core-0.4.2.crate"; };
         authors = [
           "The Rand Project Developers"
           "The Rust Project Developers"
@@ -1532,11 +1889,56 @@ rec {
           "std" = [ "alloc" ];
         };
       };
+      "rayon" = rec {
+        crateName = "rayon";
+        version = "1.12.0";
+        edition = "2021";
+        rustVersion = "1.80.0";
+        sha256 = "0vcj63xgnk72c30vdrak7dhl53snnaqv9x2faf1d94hzg1kb2fgv";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rayon/rayon-1.12.0.crate"; };
+        dependencies = [
+          {
+            name = "either";
+            packageId = "either";
+            usesDefaultFeatures = false;
+          }
+          {
+            name = "rayon-core";
+            packageId = "rayon-core";
+          }
+        ];
+        features = {
+          "web_spin_lock" = [ "dep:wasm_sync" "rayon-core/web_spin_lock" ];
+        };
+      };
+      "rayon-core" = rec {
+        crateName = "rayon-core";
+        version = "1.13.0";
+        edition = "2021";
+        links = "rayon-core";
+        rustVersion = "1.80.0";
+        sha256 = "14dbr0sq83a6lf1rfjq5xdpk5r6zgzvmzs5j6110vlv2007qpq92";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rayon-core/rayon-core-1.13.0.crate"; };
+        dependencies = [
+          {
+            name = "crossbeam-deque";
+            packageId = "crossbeam-deque";
+          }
+          {
+            name = "crossbeam-utils";
+            packageId = "crossbeam-utils";
+          }
+        ];
+        features = {
+          "web_spin_lock" = [ "dep:wasm_sync" ];
+        };
+      };
       "rdrand" = rec {
         crateName = "rdrand";
         version = "0.4.0";
         edition = "2015";
         sha256 = "1cjq0kwx1bk7jx3kzyciiish5gqsj7620dm43dc52sr8fzmm9037";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rdrand/rdrand-0.4.0.crate"; };
         authors = [
           "Simonas Kazlauskas <rdrand@kazlauskas.me>"
         ];
@@ -1554,9 +1956,11 @@ rec {
       };
       "regex" = rec {
         crateName = "regex";
-        version = "1.10.5";
+        version = "1.13.1";
         edition = "2021";
-        sha256 = "0zsiqk2sxc1kd46qw0yp87s2a14ialwyxinpl0k266ddkm1i64mr";
+        rustVersion = "1.65.0";
+        sha256 = "1391a0a4100ik8cp7l577p3ip3haqq03rd9c5vdr7vcfdixj687h";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/regex/regex-1.13.1.crate"; };
         authors = [
           "The Rust Project Developers"
           "Andrew Gallant <jamslam@gmail.com>"
@@ -1612,9 +2016,11 @@ rec {
       };
       "regex-automata" = rec {
         crateName = "regex-automata";
-        version = "0.4.7";
+        version = "0.4.18";
         edition = "2021";
-        sha256 = "1pwjdi4jckpbaivpl6x4v5g4crb37zr2wac93wlfsbzgqn6gbjiq";
+        rustVersion = "1.65.0";
+        sha256 = "1cml0rm0ssqfkibh9nh3gy4b6hbsbicj1rihpwf2a4v4nawm71dd";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/regex-automata/regex-automata-0.4.18.crate"; };
         libName = "regex_automata";
         authors = [
           "The Rust Project Developers"
@@ -1656,7 +2062,7 @@ rec {
           "nfa-thompson" = [ "alloc" ];
           "perf" = [ "perf-inline" "perf-literal" ];
           "perf-literal" = [ "perf-literal-substring" "perf-literal-multisubstring" ];
-          "perf-literal-multisubstring" = [ "std" "dep:aho-corasick" ];
+          "perf-literal-multisubstring" = [ "dep:aho-corasick" ];
           "perf-literal-substring" = [ "aho-corasick?/perf-literal" "dep:memchr" ];
           "std" = [ "regex-syntax?/std" "memchr?/std" "aho-corasick?/std" "alloc" ];
           "syntax" = [ "dep:regex-syntax" "alloc" ];
@@ -1673,9 +2079,11 @@ rec {
       };
       "regex-syntax" = rec {
         crateName = "regex-syntax";
-        version = "0.8.4";
+        version = "0.8.11";
         edition = "2021";
-        sha256 = "16r0kjy20vx33dr4mhasj5l1f87czas714x2fz6zl0f8wwxa0rks";
+        rustVersion = "1.65.0";
+        sha256 = "1m25h5q2wp976fb9gc3dsc9l99svcvd5cri8lncb51c46ydgzxnn";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/regex-syntax/regex-syntax-0.8.11.crate"; };
         libName = "regex_syntax";
         authors = [
           "The Rust Project Developers"
@@ -1693,6 +2101,7 @@ rec {
         version = "0.5.3";
         edition = "2015";
         sha256 = "1rzqbsgkmr053bxxl04vmvsd1njyz0nxvly97aip6aa2cmb15k9s";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/remove_dir_all/remove_dir_all-0.5.3.crate"; };
         authors = [
           "Aaronepower <theaaronepower@gmail.com>"
         ];
@@ -1706,11 +2115,84 @@ rec {
         ];
 
       };
+      "rnix" = rec {
+        crateName = "rnix";
+        version = "0.11.0";
+        edition = "2021";
+        rustVersion = "1.56.0";
+        sha256 = "0pybq9gp4b7lp0066236jpqi9lgb1bzvqc9axymwrq3hxgdwwddv";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rnix/rnix-0.11.0.crate"; };
+        authors = [
+          "jD91mZM2 <me@krake.one>"
+        ];
+        dependencies = [
+          {
+            name = "rowan";
+            packageId = "rowan";
+          }
+        ];
+
+      };
+      "rowan" = rec {
+        crateName = "rowan";
+        version = "0.15.19";
+        edition = "2021";
+        rustVersion = "1.56.0";
+        sha256 = "0wh7radkra3k4rxda7inkx26ms9q04gb4n0g9xyjdx2hrfn1li52";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rowan/rowan-0.15.19.crate"; };
+        authors = [
+          "Aleksey Kladov <aleksey.kladov@gmail.com>"
+        ];
+        dependencies = [
+          {
+            name = "countme";
+            packageId = "countme";
+          }
+          {
+            name = "hashbrown";
+            packageId = "hashbrown";
+            usesDefaultFeatures = false;
+            features = [ "inline-more" ];
+          }
+          {
+            name = "memoffset";
+            packageId = "memoffset";
+          }
+          {
+            name = "rustc-hash";
+            packageId = "rustc-hash";
+          }
+          {
+            name = "text-size";
+            packageId = "text-size";
+          }
+        ];
+        features = {
+          "serde" = [ "dep:serde" ];
+          "serde1" = [ "serde" "text-size/serde" ];
+        };
+      };
+      "rustc-hash" = rec {
+        crateName = "rustc-hash";
+        version = "1.1.0";
+        edition = "2015";
+        sha256 = "1qkc5khrmv5pqi5l5ca9p5nl5hs742cagrndhbrlk3dhlrx3zm08";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/rustc-hash/rustc-hash-1.1.0.crate"; };
+        authors = [
+          "The Rust Project Developers"
+        ];
+        features = {
+          "default" = [ "std" ];
+        };
+        resolvedDefaultFeatures = [ "default" "std" ];
+      };
       "ryu" = rec {
         crateName = "ryu";
         version = "1.0.18";
         edition = "2018";
+        rustVersion = "1.36.0";
         sha256 = "17xx2s8j1lln7iackzd9p0sv546vjq71i779gphjq923vjh5pjzk";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/ryu/ryu-1.0.18.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -1722,7 +2204,9 @@ rec {
         crateName = "same-file";
         version = "1.0.6";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "00h5j1w87dmhnvbv9l8bic3y7xxsnjmssvifw2ayvgx9mb1ivz4k";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/same-file/same-file-1.0.6.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
         ];
@@ -1739,7 +2223,9 @@ rec {
         crateName = "semver";
         version = "1.0.23";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "12wqpxfflclbq4dv8sa6gchdh92ahhwn4ci1ls22wlby3h57wsb1";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/semver/semver-1.0.23.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -1761,7 +2247,9 @@ rec {
         crateName = "serde";
         version = "1.0.203";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1500ghq198n6py5anvz5qbqagd9h1hq04f4qpsvjzrvix56snlvj";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/serde/serde-1.0.203.crate"; };
         authors = [
           "Erick Tryzelaar <erick.tryzelaar@gmail.com>"
           "David Tolnay <dtolnay@gmail.com>"
@@ -1795,7 +2283,9 @@ rec {
         crateName = "serde_derive";
         version = "1.0.203";
         edition = "2015";
+        rustVersion = "1.56.0";
         sha256 = "1fmmqmfza3mwxb1v80737dj01gznrh8mhgqgylkndx5npq7bq32h";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/serde_derive/serde_derive-1.0.203.crate"; };
         procMacro = true;
         authors = [
           "Erick Tryzelaar <erick.tryzelaar@gmail.com>"
@@ -1829,7 +2319,9 @@ rec {
         crateName = "serde_json";
         version = "1.0.118";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1r7jpqdfnrv8skn5va1r202g6lhdhka0vyn42vm5g21x2srzciyr";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/serde_json/serde_json-1.0.118.crate"; };
         authors = [
           "Erick Tryzelaar <erick.tryzelaar@gmail.com>"
           "David Tolnay <dtolnay@gmail.com>"
@@ -1869,7 +2361,9 @@ rec {
         crateName = "serde_spanned";
         version = "0.6.6";
         edition = "2021";
+        rustVersion = "1.65.0";
         sha256 = "1839b6m5p9ijjmcwamiya2r612ks2vg6w2pp95yg76lr3zh79rkr";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/serde_spanned/serde_spanned-0.6.6.crate"; };
         dependencies = [
           {
             name = "serde";
@@ -1892,7 +2386,9 @@ rec {
         crateName = "sha2";
         version = "0.10.8";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1j1x78zk9il95w9iv46dh9wm73r6xrgj32y6lzzw7bxws9dbfgbr";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/sha2/sha2-0.10.8.crate"; };
         authors = [
           "RustCrypto Developers"
         ];
@@ -1926,12 +2422,14 @@ rec {
           "sha2-asm" = [ "dep:sha2-asm" ];
           "std" = [ "digest/std" ];
         };
+        resolvedDefaultFeatures = [ "default" "std" ];
       };
       "strsim" = rec {
         crateName = "strsim";
         version = "0.8.0";
         edition = "2015";
         sha256 = "0sjsm7hrvjdifz661pjxq5w4hf190hx53fra8dfvamacvff139cf";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/strsim/strsim-0.8.0.crate"; };
         authors = [
           "Danny Guo <dannyguo91@gmail.com>"
         ];
@@ -1941,7 +2439,9 @@ rec {
         crateName = "structopt";
         version = "0.3.26";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "043sg3qxllann6q9i71d05qp3q13scmcvhxhd950ka2v8ij5qsqc";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/structopt/structopt-0.3.26.crate"; };
         authors = [
           "Guillaume Pinot <texitoi@texitoi.eu>"
           "others"
@@ -1980,7 +2480,9 @@ rec {
         crateName = "structopt-derive";
         version = "0.4.18";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1q5gcigmvw0cinjxzpyrkflliq5r1ivljmrvfrl3phcwgwraxdfw";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/structopt-derive/structopt-derive-0.4.18.crate"; };
         procMacro = true;
         authors = [
           "Guillaume Pinot <texitoi@texitoi.eu>"
@@ -2015,7 +2517,9 @@ rec {
         crateName = "syn";
         version = "1.0.109";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0ds2if4600bd59wsv7jjgfkayfzy3hnazs394kz6zdkmna8l3dkj";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/syn/syn-1.0.109.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -2049,7 +2553,9 @@ rec {
         crateName = "syn";
         version = "2.0.68";
         edition = "2021";
+        rustVersion = "1.61.0";
         sha256 = "1sf1y2hajhjav38ipg63c934xrgkz4v42fz24a0ckmmri06sf7wh";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/syn/syn-2.0.68.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -2083,6 +2589,7 @@ rec {
         version = "0.3.7";
         edition = "2015";
         sha256 = "1n5n86zxpgd85y0mswrp5cfdisizq2rv3la906g6ipyc03xvbwhm";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/tempdir/tempdir-0.3.7.crate"; };
         authors = [
           "The Rust Project Developers"
         ];
@@ -2102,7 +2609,9 @@ rec {
         crateName = "tera";
         version = "1.20.0";
         edition = "2018";
+        rustVersion = "1.70.0";
         sha256 = "1vnj9imw2h9szkd1izsrhwrc9jvazvdsp84x65wg2rg88ldqb7db";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/tera/tera-1.20.0.crate"; };
         authors = [
           "Vincent Prouillet <hello@prouilletvincent.com>"
         ];
@@ -2154,11 +2663,27 @@ rec {
           "urlencode" = [ "percent-encoding" ];
         };
       };
+      "text-size" = rec {
+        crateName = "text-size";
+        version = "1.1.1";
+        edition = "2018";
+        rustVersion = "1.31.0";
+        sha256 = "0cwjbkl7w3xc8mnkhg1nwij6p5y2qkcfldgss8ddnawvhf3s32pi";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/text-size/text-size-1.1.1.crate"; };
+        authors = [
+          "Aleksey Kladov <aleksey.kladov@gmail.com>"
+          "Christopher Durham (CAD97) <cad97@cad97.com>"
+        ];
+        features = {
+          "serde" = [ "dep:serde" ];
+        };
+      };
       "textwrap" = rec {
         crateName = "textwrap";
         version = "0.11.0";
         edition = "2015";
         sha256 = "0q5hky03ik3y50s9sz25r438bc4nwhqc6dqwynv4wylc807n29nk";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/textwrap/textwrap-0.11.0.crate"; };
         authors = [
           "Martin Geisler <martin@geisler.net>"
         ];
@@ -2177,7 +2702,9 @@ rec {
         crateName = "thiserror";
         version = "1.0.61";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "028prh962l16cmjivwb1g9xalbpqip0305zhq006mg74dc6whin5";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/thiserror/thiserror-1.0.61.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -2193,7 +2720,9 @@ rec {
         crateName = "thiserror-impl";
         version = "1.0.61";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0cvm37hp0kbcyk1xac1z0chpbd9pbn2g456iyid6sah0a113ihs6";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/thiserror-impl/thiserror-impl-1.0.61.crate"; };
         procMacro = true;
         libName = "thiserror_impl";
         authors = [
@@ -2219,7 +2748,9 @@ rec {
         crateName = "tinyvec";
         version = "1.6.1";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "10idfhsvp7zhbr8pn37wfra2bn02vr5xg6mhdvrbxlp2zg31alf5";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/tinyvec/tinyvec-1.6.1.crate"; };
         authors = [
           "Lokathor <zefria@gmail.com>"
         ];
@@ -2245,7 +2776,9 @@ rec {
         crateName = "tinyvec_macros";
         version = "0.1.1";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "081gag86208sc3y6sdkshgw3vysm5d34p431dzw0bshz66ncng0z";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/tinyvec_macros/tinyvec_macros-0.1.1.crate"; };
         authors = [
           "Soveu <marx.tomasz@gmail.com>"
         ];
@@ -2255,7 +2788,9 @@ rec {
         crateName = "toml";
         version = "0.8.14";
         edition = "2021";
+        rustVersion = "1.65.0";
         sha256 = "0dgk8bacrza09npifba1xsx7wyjjvhz3igxpdnyjcbqxn8mfnjbg";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/toml/toml-0.8.14.crate"; };
         authors = [
           "Alex Crichton <alex@alexcrichton.com>"
         ];
@@ -2302,7 +2837,9 @@ rec {
         crateName = "toml_datetime";
         version = "0.6.6";
         edition = "2021";
+        rustVersion = "1.65.0";
         sha256 = "1grcrr3gh7id3cy3j700kczwwfbn04p5ncrrj369prjaj9bgvbab";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/toml_datetime/toml_datetime-0.6.6.crate"; };
         authors = [
           "Alex Crichton <alex@alexcrichton.com>"
         ];
@@ -2322,7 +2859,9 @@ rec {
         crateName = "toml_edit";
         version = "0.22.14";
         edition = "2021";
+        rustVersion = "1.65.0";
         sha256 = "0f2fw0viqvisjhqwjavgypz5mgbldh53przrsjlrrggijyppl77j";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/toml_edit/toml_edit-0.22.14.crate"; };
         authors = [
           "Andronik Ordian <write@reusable.software>"
           "Ed Page <eopage@gmail.com>"
@@ -2366,7 +2905,9 @@ rec {
         crateName = "typenum";
         version = "1.17.0";
         edition = "2018";
+        rustVersion = "1.37.0";
         sha256 = "09dqxv69m9lj9zvv6xw5vxaqx15ps0vxyy5myg33i0kbqvq0pzs2";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/typenum/typenum-1.17.0.crate"; };
         build = "build/main.rs";
         authors = [
           "Paho Lurie-Gregg <paho@paholg.com>"
@@ -2381,7 +2922,9 @@ rec {
         crateName = "ucd-trie";
         version = "0.1.6";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1ff4yfksirqs37ybin9aw71aa5gva00hw7jdxbw8w668zy964r7d";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/ucd-trie/ucd-trie-0.1.6.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
         ];
@@ -2394,7 +2937,9 @@ rec {
         crateName = "unic-char-property";
         version = "0.9.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "08g21dn3wwix3ycfl0vrbahn0835nv2q3swm8wms0vwvgm07mid8";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unic-char-property/unic-char-property-0.9.0.crate"; };
         authors = [
           "The UNIC Project Developers"
         ];
@@ -2410,7 +2955,9 @@ rec {
         crateName = "unic-char-range";
         version = "0.9.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1g0z7iwvjhqspi6194zsff8vy6i3921hpqcrp3v1813hbwnh5603";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unic-char-range/unic-char-range-0.9.0.crate"; };
         authors = [
           "The UNIC Project Developers"
         ];
@@ -2424,7 +2971,9 @@ rec {
         crateName = "unic-common";
         version = "0.9.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1g1mm954m0zr497dl4kx3vr09yaly290zs33bbl4wrbaba1gzmw0";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unic-common/unic-common-0.9.0.crate"; };
         authors = [
           "The UNIC Project Developers"
         ];
@@ -2436,7 +2985,9 @@ rec {
         crateName = "unic-segment";
         version = "0.9.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "08wgz2q6vrdvmbd23kf9pbg8cyzm5q8hq9spc4blzy2ppqk5vvg4";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unic-segment/unic-segment-0.9.0.crate"; };
         authors = [
           "The UNIC Project Developers"
         ];
@@ -2452,7 +3003,9 @@ rec {
         crateName = "unic-ucd-segment";
         version = "0.9.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0027lczcg0r401g6fnzm2bq9fxhgxvri1nlryhhv8192lqic2y90";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unic-ucd-segment/unic-ucd-segment-0.9.0.crate"; };
         authors = [
           "The UNIC Project Developers"
         ];
@@ -2476,7 +3029,9 @@ rec {
         crateName = "unic-ucd-version";
         version = "0.9.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1i5hnzpfnxkp4ijfk8kvhpvj84bij575ybqx1b6hyigy6wi2zgcn";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unic-ucd-version/unic-ucd-version-0.9.0.crate"; };
         authors = [
           "The UNIC Project Developers"
         ];
@@ -2492,7 +3047,9 @@ rec {
         crateName = "unicode-bidi";
         version = "0.3.15";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0xcdxm7h0ydyprwpcbh436rbs6s6lph7f3gr527lzgv6lw053y88";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unicode-bidi/unicode-bidi-0.3.15.crate"; };
         libName = "unicode_bidi";
         authors = [
           "The Servo Project Developers"
@@ -2511,7 +3068,9 @@ rec {
         crateName = "unicode-ident";
         version = "1.0.12";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0jzf1znfpb2gx8nr8mvmyqs1crnv79l57nxnbiszc7xf7ynbjm1k";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unicode-ident/unicode-ident-1.0.12.crate"; };
         authors = [
           "David Tolnay <dtolnay@gmail.com>"
         ];
@@ -2521,7 +3080,9 @@ rec {
         crateName = "unicode-normalization";
         version = "0.1.23";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "1x81a50h2zxigj74b9bqjsirxxbyhmis54kg600xj213vf31cvd5";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unicode-normalization/unicode-normalization-0.1.23.crate"; };
         authors = [
           "kwantam <kwantam@gmail.com>"
           "Manish Goregaokar <manishsmail@gmail.com>"
@@ -2542,7 +3103,9 @@ rec {
         crateName = "unicode-segmentation";
         version = "1.11.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "00kjpwp1g8fqm45drmwivlacn3y9jx73bvs09n6s3x73nqi7vj6l";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unicode-segmentation/unicode-segmentation-1.11.0.crate"; };
         authors = [
           "kwantam <kwantam@gmail.com>"
           "Manish Goregaokar <manishsmail@gmail.com>"
@@ -2554,7 +3117,9 @@ rec {
         crateName = "unicode-width";
         version = "0.1.13";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0p92vl8n7qc8mxz45xn6qbgi0259z96n32a158l6vj5bywwdadh3";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/unicode-width/unicode-width-0.1.13.crate"; };
         authors = [
           "kwantam <kwantam@gmail.com>"
           "Manish Goregaokar <manishsmail@gmail.com>"
@@ -2571,7 +3136,9 @@ rec {
         crateName = "url";
         version = "2.5.2";
         edition = "2018";
+        rustVersion = "1.56.0";
         sha256 = "0v2dx50mx7xzl9454cl5qmpjnhkbahmn59gd3apyipbgyyylsy12";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/url/url-2.5.2.crate"; };
         authors = [
           "The rust-url developers"
         ];
@@ -2612,6 +3179,7 @@ rec {
         version = "0.8.2";
         edition = "2015";
         sha256 = "1481w9g1dw9rxp3l6snkdqihzyrd2f8vispzqmwjwsdyhw8xzggi";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/vec_map/vec_map-0.8.2.crate"; };
         authors = [
           "Alex Crichton <alex@alexcrichton.com>"
           "Jorge Aparicio <japaricious@gmail.com>"
@@ -2650,6 +3218,7 @@ rec {
         version = "0.9.4";
         edition = "2015";
         sha256 = "0gs8grwdlgh0xq660d7wr80x14vxbizmd8dbp29p2pdncx8lp1s9";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/version_check/version_check-0.9.4.crate"; };
         authors = [
           "Sergio Benitez <sb@sergio.bz>"
         ];
@@ -2659,7 +3228,9 @@ rec {
         crateName = "walkdir";
         version = "2.5.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         sha256 = "0jsy7a710qv8gld5957ybrnc07gavppp963gs32xk4ag8130jy99";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/walkdir/walkdir-2.5.0.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
         ];
@@ -2681,6 +3252,7 @@ rec {
         version = "0.3.9";
         edition = "2015";
         sha256 = "06gl025x418lchw1wxj64ycr7gha83m44cjr5sarhynd9xkrm0sw";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/winapi/winapi-0.3.9.crate"; };
         authors = [
           "Peter Atashian <retep998@gmail.com>"
         ];
@@ -2706,6 +3278,7 @@ rec {
         version = "0.4.0";
         edition = "2015";
         sha256 = "1dmpa6mvcvzz16zg6d5vrfy4bxgg541wxrcip7cnshi06v38ffxc";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/winapi-i686-pc-windows-gnu/winapi-i686-pc-windows-gnu-0.4.0.crate"; };
         authors = [
           "Peter Atashian <retep998@gmail.com>"
         ];
@@ -2715,7 +3288,9 @@ rec {
         crateName = "winapi-util";
         version = "0.1.8";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0svcgddd2rw06mj4r76gj655qsa1ikgz3d3gzax96fz7w62c6k2d";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/winapi-util/winapi-util-0.1.8.crate"; };
         authors = [
           "Andrew Gallant <jamslam@gmail.com>"
         ];
@@ -2734,6 +3309,7 @@ rec {
         version = "0.4.0";
         edition = "2015";
         sha256 = "0gqq64czqb64kskjryj8isp62m2sgvx25yyj3kpc2myh85w24bki";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/winapi-x86_64-pc-windows-gnu/winapi-x86_64-pc-windows-gnu-0.4.0.crate"; };
         authors = [
           "Peter Atashian <retep998@gmail.com>"
         ];
@@ -2743,7 +3319,9 @@ rec {
         crateName = "windows-sys";
         version = "0.52.0";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0gd3v4ji88490zgb6b5mq5zgbvwv7zx1ibn8v3x83rwcdbryaar8";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows-sys/windows-sys-0.52.0.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -2990,7 +3568,9 @@ rec {
         crateName = "windows-targets";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1sz7jrnkygmmlj1ia8fk85wbyil450kq5qkh5qh9sh2rcnj161vg";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows-targets/windows-targets-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3042,7 +3622,9 @@ rec {
         crateName = "windows_aarch64_gnullvm";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0qrjimbj67nnyn7zqy15mzzmqg0mn5gsr2yciqjxm3cb3vbyx23h";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_aarch64_gnullvm/windows_aarch64_gnullvm-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3052,7 +3634,9 @@ rec {
         crateName = "windows_aarch64_msvc";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1dmga8kqlmln2ibckk6mxc9n59vdg8ziqa2zr8awcl720hazv1cr";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_aarch64_msvc/windows_aarch64_msvc-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3062,7 +3646,9 @@ rec {
         crateName = "windows_i686_gnu";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "0w4np3l6qwlra9s2xpflqrs60qk1pz6ahhn91rr74lvdy4y0gfl8";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_i686_gnu/windows_i686_gnu-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3072,7 +3658,9 @@ rec {
         crateName = "windows_i686_gnullvm";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1s9f4gff0cixd86mw3n63rpmsm4pmr4ffndl6s7qa2h35492dx47";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_i686_gnullvm/windows_i686_gnullvm-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3082,7 +3670,9 @@ rec {
         crateName = "windows_i686_msvc";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1gw7fklxywgpnwbwg43alb4hm0qjmx72hqrlwy5nanrxs7rjng6v";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_i686_msvc/windows_i686_msvc-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3092,7 +3682,9 @@ rec {
         crateName = "windows_x86_64_gnu";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1n8p2mcf3lw6300k77a0knksssmgwb9hynl793mhkzyydgvlchjf";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_x86_64_gnu/windows_x86_64_gnu-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3102,7 +3694,9 @@ rec {
         crateName = "windows_x86_64_gnullvm";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "15n56jrh4s5bz66zimavr1rmcaw6wa306myrvmbc6rydhbj9h8l5";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_x86_64_gnullvm/windows_x86_64_gnullvm-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3112,7 +3706,9 @@ rec {
         crateName = "windows_x86_64_msvc";
         version = "0.52.5";
         edition = "2021";
+        rustVersion = "1.56.0";
         sha256 = "1w1bn24ap8dp9i85s8mlg8cim2bl2368bd6qyvm0xzqvzmdpxi5y";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/windows_x86_64_msvc/windows_x86_64_msvc-0.52.5.crate"; };
         authors = [
           "Microsoft"
         ];
@@ -3122,7 +3718,9 @@ rec {
         crateName = "winnow";
         version = "0.6.13";
         edition = "2021";
+        rustVersion = "1.65.0";
         sha256 = "189b0mrr9lkckdyr0177hwj1c59igxc2lsl71f4wg8wrqbvfbdar";
+        provenance = { kind = "crates-io"; url = "https://static.crates.io/crates/winnow/winnow-0.6.13.crate"; };
         dependencies = [
           {
             name = "memchr";
@@ -3142,6 +3740,22 @@ rec {
       };
     };
 
+    # Whether to download crates.io crates with `pkgs.fetchCrate`, see `--no-fetch-crate`.
+    useFetchCrate = true;
+    # The download URL template for crates.io crates, see `--crates-io-mirror`.
+    cratesIoMirror = null;
+    # The version of cargo's feature resolver, "2" resolves the features of crates for the build
+    # platform separately.
+    featureResolver = "2";
+    # The nix systems whose dependencies this file contains, see `--platforms`. `null` for all.
+    platforms = null;
+    # The command that runs the test executables of the workspace members, see `--wasi`.
+    workspaceTestRunner = null;
+    # The rust target whose crates only see `core` and `alloc`, see `--no-std`. `null` for none.
+    noStd = null;
+    # The toolchain that rust-toolchain.toml requests. `null` if there is none.
+    rustToolchain = null;
+
     #
 # crate2nix/default.nix (excerpt start)
 #
@@ -3169,7 +3783,6 @@ rec {
   };
 
   /* Filters common temp files and build files. */
-  # TODO(pkolloch): Substitute with gitignore filter
   sourceFilter = name: type:
     let
       baseName = builtins.baseNameOf (builtins.toString name);
@@ -3214,13 +3827,97 @@ rec {
         || baseName == "tests.nix"
       );
 
+  /* Filters like `sourceFilter` and additionally drops everything that is matched by the
+    `.gitignore` files in `root` and in the directories between `root` and `src`.
+
+    `root` is the directory of the generated `Cargo.nix`, `src` the directory of a local crate.
+    If `src` is not below `root`, only the `.gitignore` file in `src` is considered.
+  */
+  gitignoreSourceFilter = root: src:
+    let
+      rootString = toString root;
+      srcString = toString src;
+      directories =
+        if srcString == rootString
+        then [ root ]
+        else if lib.hasPrefix "${rootString}/" srcString
+        then
+          let
+            components = lib.splitString "/" (lib.removePrefix "${rootString}/" srcString);
+          in
+          [ root ] ++ builtins.genList
+            (n: root + "/${lib.concatStringsSep "/" (lib.take (n + 1) components)}")
+            (builtins.length components)
+        else [ src ];
+      gitignoreFilters = builtins.map
+        (dir: pkgs.nix-gitignore.gitignoreFilterPure
+          (_: _: true)
+          (builtins.readFile (dir + "/.gitignore"))
+          dir)
+        (builtins.filter (dir: builtins.pathExists (dir + "/.gitignore")) directories);
+    in
+    name: type:
+      sourceFilter name type
+      && builtins.all (filter: filter name type) gitignoreFilters;
+
+  /* Filters the files of the local crate in `src` like `cargo package` does with the
+    `package.include` and `package.exclude` lists of gitignore-style patterns in `Cargo.toml`.
+
+    If `include` is not empty, `exclude` is ignored. `Cargo.toml` and the files matched by
+    `testInclude` are always kept, the latter is used for the sources of test runs.
+  */
+  cargoSourceFilter = { include ? [ ], exclude ? [ ], testInclude ? [ ] }: src:
+    let
+      srcString = toString src;
+      # Whether `relativePath` or any of its parent directories is matched by `patterns`.
+      matchedBy = patterns:
+        let
+          notMatched = pkgs.nix-gitignore.gitignoreFilterPure
+            (_: _: true)
+            (lib.concatStringsSep "\n" patterns)
+            src;
+        in
+        relativePath: type:
+          let
+            components = lib.splitString "/" relativePath;
+            parents = builtins.genList
+              (n: "${srcString}/${lib.concatStringsSep "/" (lib.take (n + 1) components)}")
+              (builtins.length components - 1);
+          in
+          !(notMatched "${srcString}/${relativePath}" type)
+          || builtins.any (parent: !(notMatched parent "directory")) parents;
+      included = matchedBy include;
+      excluded = matchedBy exclude;
+      testIncluded = matchedBy testInclude;
+    in
+    name: type:
+      let
+        relativePath = lib.removePrefix "${srcString}/" (toString name);
+      in
+      if relativePath == "Cargo.toml" then true
+      else if testInclude != [ ] && testIncluded relativePath type then true
+      else if include != [ ] then type == "directory" || included relativePath type
+      else !(excluded relativePath type);
+
   /* Returns a crate which depends on successful test execution
     of crate given as the second argument.
 
     testCrateFlags: list of flags to pass to the test exectuable
     testInputs: list of packages that should be available during test execution
+    testThreads, testFilters, testShard, testRunner: see `libtestCommand`
   */
-  crateWithTest = { crate, testCrate, testCrateFlags, testInputs, testPreRun, testPostRun }:
+  crateWithTest =
+    { crate
+    , testCrate
+    , testCrateFlags
+    , testInputs
+    , testPreRun
+    , testPostRun
+    , testThreads ? null
+    , testFilters ? [ ]
+    , testShard ? null
+    , testRunner ? null
+    }:
     assert builtins.typeOf testCrateFlags == "list";
     assert builtins.typeOf testInputs == "list";
     assert builtins.typeOf testPreRun == "string";
@@ -3244,14 +3941,14 @@ rec {
           testCommand = pkgs.lib.concatStringsSep "\n"
             (pkgs.lib.filter (s: s != "") [
               testPreRun
-              "$f $testCrateFlags 2>&1 | tee -a $out"
+              (libtestCommand { inherit testThreads testFilters testShard testRunner; })
               testPostRun
             ]);
         in
         pkgs.stdenvNoCC.mkDerivation {
           name = "run-tests-${testCrate.name}";
 
-          inherit (crate) src;
+          inherit (testCrate) src;
 
           inherit testCrateFlags;
 
@@ -3291,17 +3988,488 @@ rec {
           inherit test;
         };
       }
-      (lib.optionalString (stdenv.buildPlatform.canExecute stdenv.hostPlatform) ''
+      (lib.optionalString (testRunner != null || stdenv.buildPlatform.canExecute stdenv.hostPlatform) ''
         echo tested by ${test}
       '' + ''
         ${lib.concatMapStringsSep "\n" (output: "ln -s ${crate.${output}} ${"$"}${output}") crate.outputs}
       '');
 
+  /* Returns the command that runs the tests of the test executable `$f` with the flags in
+    `$testCrateFlags` and appends their output to `$out`.
+
+    testThreads: the number of tests to run in parallel, by default the number of CPUs
+    testFilters: only run the tests whose names contain one of these strings
+    testShard: `{ index; count; }` to only run every `count`th test, starting at the
+      zero-based `index`, so that `count` derivations run all tests
+    testRunner: a command that runs the test executable on the building platform, e.g.
+      `wasmtimeTestRunner wasmtime` for WASI, `null` to run it directly
+  */
+  libtestCommand = { testThreads ? null, testFilters ? [ ], testShard ? null, testRunner ? null }:
+    assert testThreads == null || builtins.isInt testThreads && testThreads > 0;
+    assert builtins.isList testFilters;
+    assert testShard == null || testShard.count > 0 && testShard.index >= 0 && testShard.index < testShard.count;
+    let
+      threadArgs = lib.optional (testThreads != null) "--test-threads=${toString testThreads}";
+      runner = lib.optionalString (testRunner != null) "${testRunner} ";
+      run = args: "${runner}$f $testCrateFlags${lib.concatMapStrings (arg: " ${arg}") args} 2>&1 | tee -a $out";
+    in
+    if testShard == null
+    then run (threadArgs ++ builtins.map lib.escapeShellArg testFilters)
+    else ''
+      tests=$(${runner}$f $testCrateFlags --list${lib.concatMapStrings (filter: " ${lib.escapeShellArg filter}") testFilters} \
+        | sed -n 's/: test$//p' | awk '(NR - 1) % ${toString testShard.count} == ${toString testShard.index}')
+      if [ -n "$tests" ]; then
+        ${run (threadArgs ++ [ "--exact" "$tests" ])}
+      else
+        echo "No tests of $f in shard ${toString testShard.index} of ${toString testShard.count}." >> $out
+      fi'';
+
+  /* Returns the test runner that runs the WASI test executables with `wasmtime`, with access to
+    the directory of the tests like cargo runs them.
+  */
+  wasmtimeTestRunner = wasmtime: "${wasmtime}/bin/wasmtime run --dir=.";
+
+  /* Returns the package IDs of all crates by attribute names that do not change when other
+    versions of a crate are added or removed.
+
+    Every crate is available as `<crateName>_<version>` with `.`, `+` and `-` in the version
+    replaced by `_`, e.g. `serde_1_0_200`. Additionally, `<crateName>` refers to the newest
+    version of a crate.
+  */
+  stablePackageIds = { crateConfigs ? crates }:
+    let
+      sanitizeVersion = builtins.replaceStrings [ "." "+" "-" ] [ "_" "_" "_" ];
+      withPackageIds = lib.mapAttrsToList
+        (packageId: crate: crate // { inherit packageId; })
+        crateConfigs;
+      versioned = builtins.map
+        (crate: lib.nameValuePair
+          "${crate.crateName}_${sanitizeVersion crate.version}"
+          crate.packageId)
+        withPackageIds;
+      newest = lib.mapAttrsToList
+        (crateName: versions:
+          let
+            sorted = builtins.sort
+              (a: b: builtins.compareVersions a.version b.version > 0)
+              versions;
+          in
+          lib.nameValuePair crateName (builtins.head sorted).packageId)
+        (builtins.groupBy (crate: crate.crateName) withPackageIds);
+    in
+    builtins.listToAttrs (versioned ++ newest);
+
+  /* Returns whether the `build` attribute of the workspace member `packageId` runs its tests:
+    the entry for its crate name in `byCrateName` if there is one, `default` otherwise.
+  */
+  workspaceMemberRunTests =
+    { packageId
+    , crateConfigs ? crates
+    , default ? runWorkspaceTests
+    , byCrateName ? runTestsByCrateName
+    }:
+    byCrateName.${crateConfigs.${packageId}.crateName} or default;
+
+  /* Returns how to build the crate that `buildRustCrateWithFeatures` was asked for:
+    `extraRustcOpts` and the attributes to set with `overrideAttrs`.
+
+    With `strip`, the binaries lose their symbol tables. With `separate`, rustc emits debug info
+    which ends up in a separate `debug` output, like `separateDebugInfo` of `mkDerivation`: the
+    `out` closure stays small while debuggers find the symbols via their build id. Dev builds keep
+    their debug info where it is.
+  */
+  binaryDebugInfo =
+    { isRelease ? release
+    , strip ? stripBinaries
+    , separate ? separateDebugInfo
+    }:
+    let
+      enabled = isRelease && (strip || separate);
+    in
+    {
+      extraRustcOpts = lib.optional (isRelease && separate) "-C debuginfo=2";
+      attrs = lib.optionalAttrs enabled (
+        { dontStrip = false; }
+        // lib.optionalAttrs strip { stripAllList = [ "bin" ]; }
+        // lib.optionalAttrs separate { separateDebugInfo = true; }
+      );
+    };
+
+  /* Returns the `extraRustcOpts` and attributes that make crate builds independent of
+    where and when they run.
+
+    rustc embeds source paths in panic messages, `file!()` and debug info. Those below the build
+    directory are replaced by `/build`, the build directory of sandboxed Linux builds, so that
+    builds without sandbox, e.g. on Darwin, produce the same binaries. `$NIX_BUILD_TOP` is
+    expanded by the build script of `buildRustCrate`. Build scripts that embed the build time
+    get a fixed `SOURCE_DATE_EPOCH`.
+  */
+  reproducibleBuild = { enable ? reproducible }:
+    {
+      extraRustcOpts = lib.optional enable "--remap-path-prefix=$NIX_BUILD_TOP=/build";
+      attrs = lib.optionalAttrs enable { SOURCE_DATE_EPOCH = "315532800"; };
+    };
+
+  /* Returns a sysroot with only the `core` and `alloc` libraries of `rustc` for `rustTarget`,
+    so that crates built against it fail to compile if they use std.
+  */
+  coreAllocSysroot = { rustc, rustTarget }:
+    pkgs.runCommand "core-alloc-sysroot-${rustTarget}" { } ''
+      lib=lib/rustlib/${rustTarget}/lib
+      src=$(${rustc}/bin/rustc --print sysroot)/$lib
+      mkdir -p $out/$lib
+      for name in core alloc compiler_builtins; do
+        ln -s $src/lib$name-*.rlib $out/$lib/
+      done
+    '';
+
+  /* Returns the `extraRustcOpts` that build crates for the `--no-std` target `noStd` against
+    a sysroot without std. Crates for other targets, the build platform and tests keep std.
+  */
+  noStdRustcOpts = { pkgs, isBuild, isTested }:
+    lib.optionals
+      (noStd != null && !isBuild && !isTested
+        && pkgs.stdenv.hostPlatform.rust.rustcTarget == noStd.rustTarget)
+      [
+        "--sysroot"
+        "${if noStd.sysroot != null then noStd.sysroot else coreAllocSysroot {
+          rustc = if rustc != null then rustc else pkgs.buildPackages.rustc;
+          inherit (noStd) rustTarget;
+        }}"
+      ];
+
+  /* Returns an OCI image that runs the binary `binName` of the crate derivation `crate`.
+
+    The image configuration can be changed with `.override`, e.g.
+    `dockerImage { ... }.override { exposedPorts = [ 8080 "53/udp" ]; }`.
+    Ports without protocol use TCP.
+  */
+  dockerImage = lib.makeOverridable (
+    { crate
+    , binName
+    , name ? binName
+    , tag ? crate.version or "latest"
+    , entrypoint ? [ "${crate}/bin/${binName}" ]
+    , exposedPorts ? [ ]
+    , config ? { }
+    }:
+    let
+      portName = port: if builtins.isString port then port else "${toString port}/tcp";
+    in
+    pkgs.dockerTools.buildLayeredImage {
+      inherit name tag;
+      contents = [ crate ];
+      config = {
+        Entrypoint = entrypoint;
+        ExposedPorts = builtins.listToAttrs
+          (builtins.map (port: lib.nameValuePair (portName port) { }) exposedPorts);
+      } // config;
+    }
+  );
+
+  /* Returns the C header of the workspace member `packageId` in the `dev` output and the
+    `staticlib`/`cdylib` libraries of the crate derivation `crate` in `out`.
+
+    The header is generated by cbindgen from the library source, with the `cbindgen.toml` of
+    the crate if there is one and as C otherwise. Use `lang` to override the language of
+    the configuration.
+  */
+  cHeader = lib.makeOverridable (
+    { crate
+    , packageId
+    , crateConfig ? crates.${packageId}
+    , headerName ? "${lib.replaceStrings [ "-" ] [ "_" ] crateConfig.crateName}.h"
+    , lang ? null
+    , cbindgen ? pkgs.rust-cbindgen
+    }:
+    let
+      src = crateSrc crateConfig;
+      libPath = crateConfig.libPath or "src/lib.rs";
+    in
+    pkgs.runCommand "${crateConfig.crateName}-c-header-${crateConfig.version}"
+      {
+        outputs = [ "out" "dev" ];
+        nativeBuildInputs = [ cbindgen ];
+      }
+      ''
+        args=()
+        if [ -f ${src}/cbindgen.toml ]; then
+          args+=(--config ${src}/cbindgen.toml)
+        ${lib.optionalString (lang == null) ''
+        else
+          args+=(--lang c)
+        ''}
+        fi
+        ${lib.optionalString (lang != null) ''args+=(--lang ${lib.escapeShellArg lang})''}
+        mkdir -p $dev/include $out
+        cbindgen "''${args[@]}" --output $dev/include/${lib.escapeShellArg headerName} ${src}/${libPath}
+        ln -s ${crate.lib or crate}/lib $out/lib
+      ''
+  );
+
+  /* Returns the PyO3 extension module of the workspace member `packageId` as a package of
+    `python.pkgs`, e.g. for the `propagatedBuildInputs` of a Python package.
+
+    `crate` is built again with the pyo3 crates configured for `python`. The library is
+    installed as `moduleName`, where dots separate the packages, e.g. `my_package._native`.
+  */
+  pythonModule = lib.makeOverridable (
+    { crate
+    , packageId
+    , moduleName
+    , crateConfig ? crates.${packageId}
+    , python ? pkgs.python3
+    }:
+    let
+      withPython = overrides: name: extraAttrs:
+        overrides // {
+          ${name} = attrs:
+            let previous = (overrides.${name} or (_: { })) attrs;
+            in
+            previous // {
+              PYO3_PYTHON = python.interpreter;
+              nativeBuildInputs = (previous.nativeBuildInputs or attrs.nativeBuildInputs or [ ]) ++ [ python ];
+            } // extraAttrs previous;
+        };
+      # `-undefined dynamic_lookup` resolves the symbols of libpython when the module is loaded.
+      memberAttrs = previous: lib.optionalAttrs stdenv.hostPlatform.isDarwin {
+        extraRustcOpts = (previous.extraRustcOpts or [ ])
+          ++ [ "-C" "link-arg=-undefined" "-C" "link-arg=dynamic_lookup" ];
+      };
+      pythonCrateOverrides = overrides: lib.foldl'
+        (overrides: name: withPython overrides name (_: { }))
+        (withPython overrides crateConfig.crateName memberAttrs)
+        [ "pyo3" "pyo3-build-config" "pyo3-ffi" "pyo3-macros-backend" ];
+      built = crate.override (args: {
+        crateOverrides = pythonCrateOverrides args.crateOverrides;
+      });
+      libName = crateConfig.libName or (lib.replaceStrings [ "-" ] [ "_" ] crateConfig.crateName);
+      modulePath = "${python.sitePackages}/${lib.replaceStrings [ "." ] [ "/" ] moduleName}.so";
+    in
+    python.pkgs.toPythonModule (pkgs.runCommand "${python.libPrefix}-${crateConfig.crateName}-${crateConfig.version}"
+      { }
+      ''
+        mkdir -p $(dirname $out/${modulePath})
+        cp ${built.lib or built}/lib/lib${libName}${stdenv.hostPlatform.extensions.sharedLibrary} $out/${modulePath}
+      '')
+  );
+
+  /* Returns flake apps by binary name. `binaries` maps the names of workspace `members` to
+    the names of their binaries. If several members have a binary with the same name,
+    the member that comes first alphabetically wins.
+  */
+  flakeApps = members: binaries:
+    builtins.listToAttrs (lib.concatLists (lib.mapAttrsToList
+      (member: binNames: builtins.map
+        (binName: lib.nameValuePair binName {
+          type = "app";
+          program = "${members.${member}.build}/bin/${binName}";
+        })
+        binNames)
+      binaries));
+
+  /* Returns the checks for `nix flake check`: the tests of all `members` as `<member>-tests`,
+    except for the crates with `skip-tests` in `[package.metadata.crate2nix]`, and, if the
+    workspace source `src` is given, `clippy`, `rustfmt` and `doc`. With an `advisoryDb`, e.g.
+    a checkout of https://github.com/rustsec/advisory-db, `audit` checks `Cargo.lock` with
+    `cargo audit`.
+
+    `cargoConfig` is a `.cargo/config.toml` that replaces the sources with vendored ones, so
+    that cargo runs offline. Set `clippy`, `rustfmt` or `doc` to `false` to leave them out.
+  */
+  flakeChecks =
+    { members
+    , src ? null
+    , cargoConfig ? null
+    , clippy ? true
+    , rustfmt ? true
+    , doc ? true
+    , advisoryDb ? null
+    , clippyArgs ? [ "-D" "warnings" ]
+    }:
+    let
+      toolchain =
+        if rustc == null
+        then [ pkgs.rustc pkgs.cargo ]
+        else lib.unique [ rustc cargo ];
+      cargoCheck = name: nativeBuildInputs: script: pkgs.runCommand "${name}-check"
+        {
+          nativeBuildInputs = toolchain ++ nativeBuildInputs;
+          CARGO_NET_OFFLINE = "true";
+        } ''
+        export CARGO_HOME="$(mktemp -d)"
+        ${lib.optionalString (cargoConfig != null) "ln -s ${cargoConfig} \"$CARGO_HOME/config.toml\""}
+        export CARGO_TARGET_DIR="$(mktemp -d)"
+        cp -r ${src} source
+        chmod -R u+w source
+        cd source
+        ${script}
+        mkdir -p $out
+      '';
+      tests = lib.mapAttrs'
+        (name: member: lib.nameValuePair "${name}-tests" (member.build.override { runTests = true; }))
+        (lib.filterAttrs (name: member: !(crates.${member.packageId}.skipTests or false)) members);
+    in
+    tests
+    // lib.optionalAttrs (src != null && clippy) {
+      clippy = cargoCheck "clippy" [ pkgs.clippy ]
+        "cargo clippy --frozen --workspace --all-targets -- ${lib.escapeShellArgs clippyArgs}";
+    }
+    // lib.optionalAttrs (src != null && rustfmt) {
+      rustfmt = cargoCheck "rustfmt" [ pkgs.rustfmt ] "cargo fmt --all --check";
+    }
+    // lib.optionalAttrs (src != null && doc) {
+      doc = cargoCheck "doc" [ ] ''
+        cargo doc --frozen --workspace --no-deps
+        mkdir -p $out/share
+        cp -r "$CARGO_TARGET_DIR/doc" $out/share/doc
+      '';
+    }
+    // lib.optionalAttrs (src != null && advisoryDb != null) {
+      audit = cargoCheck "audit" [ pkgs.cargo-audit ]
+        "cargo audit --no-fetch --db ${advisoryDb} --file Cargo.lock";
+    };
+
+  /* Returns a NixOS module that defines the `services.<name>` options and a systemd service
+    running the binary `binName` of the crate derivation `crate`.
+
+    This is meant as a starting point: if `port` is set, it is only passed to the service
+    in the `PORT` environment variable.
+  */
+  nixosModule = { crate, binName, name ? binName }:
+    { config, lib, ... }:
+    let
+      cfg = config.services.${name};
+      createUser = cfg.user == name;
+    in
+    {
+      options.services.${name} = {
+        enable = lib.mkEnableOption "the ${binName} service";
+        package = lib.mkOption {
+          type = lib.types.package;
+          default = crate;
+          description = "The package that provides bin/${binName}.";
+        };
+        user = lib.mkOption {
+          type = lib.types.str;
+          default = name;
+          description = "The user that runs the service. Created as system user if left at the default.";
+        };
+        port = lib.mkOption {
+          type = lib.types.nullOr lib.types.port;
+          default = null;
+          description = "The port passed to the service in the PORT environment variable.";
+        };
+        extraArgs = lib.mkOption {
+          type = lib.types.listOf lib.types.str;
+          default = [ ];
+          description = "Additional command line arguments for ${binName}.";
+        };
+      };
+
+      config = lib.mkIf cfg.enable {
+        systemd.services.${name} = {
+          description = "${binName} (built by crate2nix)";
+          wantedBy = [ "multi-user.target" ];
+          after = [ "network.target" ];
+          environment = lib.optionalAttrs (cfg.port != null) { PORT = toString cfg.port; };
+          serviceConfig = {
+            ExecStart = lib.escapeShellArgs ([ "${cfg.package}/bin/${binName}" ] ++ cfg.extraArgs);
+            User = cfg.user;
+            Restart = "on-failure";
+          };
+        };
+        users.users = lib.mkIf createUser {
+          ${name} = { isSystemUser = true; group = name; };
+        };
+        users.groups = lib.mkIf createUser { ${name} = { }; };
+      };
+    };
+
+  /* Turns the crates of a JSON file written with `crate2nix generate --crates-json` into the
+    attribute sets of `crates`: sources into derivations and dependency targets into functions.
+  */
+  cratesFromJSON = lib.mapAttrs (_: crate:
+    let
+      dependency = dep: dep // lib.optionalAttrs (dep ? target) {
+        target = { target, features }: evalCfgJSON { inherit target features; } dep.target;
+      };
+      dependencyLists = builtins.filter (name: crate ? ${name})
+        [ "dependencies" "buildDependencies" "devDependencies" ];
+    in
+    crate
+    // lib.genAttrs dependencyLists (name: builtins.map dependency crate.${name})
+    // lib.optionalAttrs (crate ? src) {
+      src =
+        if crate.src ? fetchurl
+        then pkgs.fetchurl ({ name = "${crate.crateName}-${crate.version}.tar.gz"; } // crate.src.fetchurl)
+        else pkgs.fetchgit crate.src.fetchgit;
+    });
+
+  /* Evaluates the cfg tree of a dependency target in the JSON file of `cratesFromJSON`
+    like the nix expressions of the targets in `Cargo.nix`.
+  */
+  evalCfgJSON = { target, features }: cfg:
+    let
+      eval = evalCfgJSON { inherit target features; };
+      targetAttr = lib.removePrefix "target_";
+    in
+    if cfg ? all then lib.all eval cfg.all
+    else if cfg ? any then lib.any eval cfg.any
+    else if cfg ? not then !(eval cfg.not)
+    else if cfg ? rustcTarget then stdenv.hostPlatform.rust.rustcTarget == cfg.rustcTarget
+    else if cfg ? name then target.${targetAttr cfg.name} or false
+    else if cfg.key == "feature" then builtins.elem cfg.value features
+    else if cfg.key == "target_feature" then builtins.elem cfg.value targetFeatures
+    else if cfg.key == "target_family" then builtins.elem cfg.value target.family
+    else cfg.value == target.${targetAttr cfg.key} or null;
+
+  /* Merges a list of crate override sets, e.g. for the `crateOverrides` of `buildRustCrate`.
+
+    Later sets take precedence: the overrides of a crate are applied in order, each to the
+    attributes of the crate with the results of the previous ones, and later results replace the
+    attributes of earlier ones. To add to a list, use e.g.
+    `buildInputs = (attrs.buildInputs or [ ]) ++ [ pkgs.openssl ];`.
+  */
+  mergeCrateOverrides = overrideSets:
+    lib.zipAttrsWith
+      (_: overrides: attrs:
+        lib.foldl' (merged: override: merged // override (attrs // merged)) { } overrides)
+      (builtins.map (lib.filterAttrs (_: lib.isFunction)) overrideSets);
+
+  /* Overrides for `-sys` crates that link to libraries found with `pkg-config`.
+
+    They have the lowest precedence, so `pkgs.defaultCrateOverrides` wins for crates that it knows.
+  */
+  sysCrateOverrides =
+    let
+      withLibraries = libraries: env: attrs: {
+        nativeBuildInputs = (attrs.nativeBuildInputs or [ ]) ++ [ pkgs.pkg-config ];
+        buildInputs = (attrs.buildInputs or [ ]) ++ libraries;
+      } // env;
+    in
+    {
+      bzip2-sys = withLibraries [ pkgs.bzip2 ] { };
+      libdbus-sys = withLibraries [ pkgs.dbus ] { };
+      libssh2-sys = withLibraries [ pkgs.libssh2 pkgs.openssl pkgs.zlib ] { };
+      libsodium-sys = withLibraries [ pkgs.libsodium ] { SODIUM_USE_PKG_CONFIG = "1"; };
+      libudev-sys = withLibraries [ pkgs.udev ] { };
+      libusb1-sys = withLibraries [ pkgs.libusb1 ] { };
+      lzma-sys = withLibraries [ pkgs.xz ] { };
+      onig_sys = withLibraries [ pkgs.oniguruma ] { RUSTONIG_SYSTEM_LIBONIG = "1"; };
+      pq-sys = withLibraries [ pkgs.postgresql ] { };
+      zstd-sys = withLibraries [ pkgs.zstd ] { ZSTD_SYS_USE_PKG_CONFIG = "1"; };
+    };
+
+  /* The crate overrides that the crates are built with by default. */
+  mergedCrateOverrides = mergeCrateOverrides
+    ((lib.optional builtinSysOverrides sysCrateOverrides) ++ [ defaultCrateOverrides extraCrateOverrides ]);
+
   /* A restricted overridable version of builtRustCratesWithFeatures. */
   buildRustCrateWithFeatures =
     { packageId
     , features ? rootFeatures
-    , crateOverrides ? defaultCrateOverrides
+    , crateOverrides ? mergedCrateOverrides
     , buildRustCrateForPkgsFunc ? null
     , runTests ? false
     , testCrateFlags ? [ ]
@@ -3310,6 +4478,13 @@ rec {
     , testPreRun ? ""
       # Any command run immediatelly after a test is executed.
     , testPostRun ? ""
+      # The number of tests to run in parallel, the tests to run and the shard of them, see
+      # `libtestCommand`.
+    , testThreads ? null
+    , testFilters ? [ ]
+    , testShard ? null
+      # The command that runs the test executables, e.g. wasmtime for WASI, see `libtestCommand`.
+    , testRunner ? workspaceTestRunner
     }:
     lib.makeOverridable
       (
@@ -3320,6 +4495,10 @@ rec {
         , testInputs
         , testPreRun
         , testPostRun
+        , testThreads
+        , testFilters
+        , testShard
+        , testRunner
         }:
         let
           buildRustCrateForPkgsFuncOverriden =
@@ -3352,16 +4531,95 @@ rec {
                 {
                   crate = drv;
                   testCrate = testDrv;
-                  inherit testCrateFlags testInputs testPreRun testPostRun;
+                  inherit testCrateFlags testInputs testPreRun testPostRun testThreads testFilters testShard testRunner;
                 }
             else drv;
+          warnings = builtins.filter (warning: warning != null) [
+            (unsupportedPlatformWarning { })
+            (rustToolchainWarning { })
+          ];
+          rustVersionError =
+            if checkRustVersion
+            then rustVersionTooOldError { inherit (builtRustCrates) packageIds; }
+            else null;
         in
-        derivation
+        lib.foldr lib.warn
+          (if rustVersionError != null then builtins.throw rustVersionError else derivation)
+          warnings
       )
-      { inherit features crateOverrides runTests testCrateFlags testInputs testPreRun testPostRun; };
+      { inherit features crateOverrides runTests testCrateFlags testInputs testPreRun testPostRun testThreads testFilters testShard testRunner; };
+
+  /* Returns a warning if `Cargo.nix` was generated with `--platforms` and lacks the dependencies
+    for `system`, `null` otherwise.
+  */
+  unsupportedPlatformWarning =
+    { system ? stdenv.hostPlatform.system
+    , generatedFor ? platforms
+    }:
+    if generatedFor == null || builtins.elem system generatedFor
+    then null
+    else
+      "Cargo.nix was generated with --platforms ${lib.concatStringsSep "," generatedFor} "
+      + "and lacks the dependencies for ${system}. Add ${system} to --platforms and regenerate it.";
+
+  /* Returns a warning if `rust-toolchain.toml` pins a rust version but the rustc of `pkgs` with
+    a different version is used, `null` otherwise.
+
+    Channels like "stable" or "nightly" are not checked.
+  */
+  rustToolchainWarning =
+    { toolchain ? rustToolchain
+    , rustcVersion ? if rustc == null || rustc == pkgs.rustc then pkgs.rustc.version else null
+    }:
+    let
+      channel = toolchain.channel;
+      pinsVersion = builtins.match "[0-9]+\\.[0-9]+(\\.[0-9]+)?" channel != null;
+      matches =
+        if builtins.length (lib.splitString "." channel) == 3
+        then rustcVersion == channel
+        else lib.versions.majorMinor rustcVersion == channel;
+    in
+    if toolchain == null || rustcVersion == null || !pinsVersion || matches
+    then null
+    else
+      "rust-toolchain.toml requests rust ${channel} but rustc ${rustcVersion} of pkgs is used. "
+      + "Pass the requested toolchain as `rustc`, e.g. from fenix or rust-overlay.";
+
+  /* Returns an error message if one of the crates of `packageIds` needs a newer rustc than
+    `rustcVersion`, `null` otherwise.
+
+    The `rustVersion` of a crate is its `rust-version` or the first rustc version that supports its
+    edition. The message names the crate with the newest `rustVersion`.
+  */
+  rustVersionTooOldError =
+    { packageIds
+    , crateConfigs ? crates
+    , rustcVersion ? (if rustc == null then pkgs.rustc else rustc).version or null
+    }:
+    let
+      withRustVersion = builtins.filter
+        (packageId: crateConfigs.${packageId} ? rustVersion)
+        packageIds;
+      newest = lib.foldl'
+        (newest: packageId:
+          let crateConfig = crateConfigs.${packageId};
+          in
+          if newest == null || lib.versionOlder newest.rustVersion crateConfig.rustVersion
+          then crateConfig
+          else newest)
+        null
+        withRustVersion;
+    in
+    if rustcVersion == null || newest == null || !(lib.versionOlder rustcVersion newest.rustVersion)
+    then null
+    else
+      "${newest.crateName} ${newest.version} requires rustc ${newest.rustVersion} but rustc ${rustcVersion} is used. "
+      + "Pass a newer `rustc` to Cargo.nix or set `checkRustVersion = false` to try anyway.";
 
   /* Returns an attr set with packageId mapped to the result of buildRustCrateForPkgsFunc
     for the corresponding crate.
+
+    `packageIds` lists the crates that are part of the build.
   */
   builtRustCratesWithFeatures =
     { packageId
@@ -3378,34 +4636,39 @@ rec {
       assert (builtins.isBool runTests);
       let
         rootPackageId = packageId;
+        splitBuildFeatures = featureResolver == "2";
         mergedFeatures = mergePackageFeatures
           (
             args // {
-              inherit rootPackageId;
+              inherit rootPackageId splitBuildFeatures;
               target = makeTarget stdenv.hostPlatform // { test = runTests; };
+              buildTarget = makeTarget stdenv.buildPlatform;
             }
           );
         # Memoize built packages so that reappearing packages are only built once.
-        builtByPackageIdByPkgs = mkBuiltByPackageIdByPkgs pkgs;
-        mkBuiltByPackageIdByPkgs = pkgs:
+        builtByPackageIdByPkgs = mkBuiltByPackageIdByPkgs false pkgs;
+        mkBuiltByPackageIdByPkgs = isBuild: pkgs:
           let
             self = {
-              crates = lib.mapAttrs (packageId: value: buildByPackageIdForPkgsImpl self pkgs packageId) crateConfigs;
-              target = makeTarget stdenv.hostPlatform;
-              build = mkBuiltByPackageIdByPkgs pkgs.buildPackages;
+              crates = lib.mapAttrs (packageId: value: buildByPackageIdForPkgsImpl self pkgs packageId false) crateConfigs;
+              target = makeTarget (if isBuild then stdenv.buildPlatform else stdenv.hostPlatform);
+              build = mkBuiltByPackageIdByPkgs true pkgs.buildPackages;
+              inherit isBuild;
             };
           in
           self;
-        buildByPackageIdForPkgsImpl = self: pkgs: packageId:
+        # `isTested` is only true for the root crate when running its tests.
+        buildByPackageIdForPkgsImpl = self: pkgs: packageId: isTested:
           let
-            features = mergedFeatures."${packageId}" or [ ];
+            features = mergedFeatures.${packageFeaturesKey {
+              inherit splitBuildFeatures packageId;
+              inherit (self) isBuild;
+            }} or [ ];
             crateConfig' = crateConfigs."${packageId}";
             crateConfig =
-              builtins.removeAttrs crateConfig' [ "resolvedDefaultFeatures" "devDependencies" ];
+              builtins.removeAttrs crateConfig' [ "resolvedDefaultFeatures" "devDependencies" "buildScriptEnv" "preBuilt" "exportOutDir" "storeManifestDir" "skipTests" "crateEnv" "crateReplacements" "testSrc" "rustVersion" "profileRustcOpts" "crateRustcOpts" "provenance" ];
             devDependencies =
-              lib.optionals
-                (runTests && packageId == rootPackageId)
-                (crateConfig'.devDependencies or [ ]);
+              lib.optionals isTested (crateConfig'.devDependencies or [ ]);
             dependencies =
               dependencyDerivations {
                 inherit features;
@@ -3462,27 +4725,277 @@ rec {
                   { inherit (dep) rename; inherit (package) version; };
               in
               lib.mapAttrs (name: builtins.map versionAndRename) grouped;
+            # Paths to the binaries and libraries of artifact dependencies as cargo would set them.
+            artifactEnv =
+              artifactDependencyEnv
+                {
+                  inherit features crateConfigs;
+                  inherit (self) target;
+                  buildByPackageId = depPackageId: self.crates.${depPackageId};
+                  dependencies = crateConfig.dependencies or [ ] ++ devDependencies;
+                }
+              // artifactDependencyEnv {
+                inherit features crateConfigs;
+                inherit (self.build) target;
+                buildByPackageId = depPackageId: self.build.crates.${depPackageId};
+                buildForTargetByPackageId = depPackageId: self.crates.${depPackageId};
+                dependencies = crateConfig.buildDependencies or [ ];
+              };
+            # The build script outputs of dependencies with `exportOutDir`.
+            outDirEnv =
+              outDirDependencyEnv {
+                inherit features crateConfigs;
+                inherit (self) target;
+                buildByPackageId = depPackageId: self.crates.${depPackageId};
+                dependencies = crateConfig.dependencies or [ ];
+              };
+            # Installs the `OUT_DIR` of the build script and points the metadata in `env` there.
+            exportOutDirHook = lib.optionalString (crateConfig'.exportOutDir or false) ''
+              if [ -d target/build/${crateConfig.crateName}.out ]; then
+                mkdir -p $lib/out-dir
+                cp -r target/build/${crateConfig.crateName}.out/. $lib/out-dir
+                if [ -f $lib/env ]; then
+                  sed -i "s|$(pwd)/target/build/${crateConfig.crateName}.out|$lib/out-dir|g" $lib/env
+                fi
+              fi
+            '';
+            # Copies the crate to the nix store so that paths from `env!("CARGO_MANIFEST_DIR")`
+            # still exist at runtime.
+            storeManifestDirHook = lib.optionalString (crateConfig'.storeManifestDir or false) ''
+              mkdir -p $lib/manifest-dir
+              tar --exclude=./target -cf - . | tar -xf - -C $lib/manifest-dir
+              export CARGO_MANIFEST_DIR=$lib/manifest-dir
+            '';
+            # The `replacements` of crate2nix.json.
+            replacementsHook = sourceReplacementsHook (crateConfig'.crateReplacements or [ ]);
+            # The metadata of `links` dependencies under the names that cargo uses.
+            linksHook =
+              linksDependencyHook {
+                inherit features crateConfigs;
+                inherit (self) target;
+                buildByPackageId = depPackageId: self.crates.${depPackageId};
+                dependencies = crateConfig.dependencies or [ ];
+              };
+            # The flags for the `[profile]` settings of the workspace. Tests need to unwind, so crates
+            # are built with `panic = "abort"` only if no tests run.
+            profileRustcOpts = builtins.filter
+              (opt: !(runTests && opt == "-C panic=abort"))
+              (crateConfig'.profileRustcOpts.${if release then "release" else "dev"} or [ ]);
+            # Stripping and separate debug info only apply to the requested crate, e.g. a workspace member.
+            binaryDebugInfo' =
+              if packageId == rootPackageId && !self.isBuild
+              then binaryDebugInfo { }
+              else binaryDebugInfo { strip = false; separate = false; };
+            reproducibleBuild' = reproducibleBuild { };
+            withBinaryDebugInfo = drv:
+              if binaryDebugInfo'.attrs == { }
+              then drv
+              else drv.overrideAttrs (_: binaryDebugInfo'.attrs);
+            # Where the source comes from, e.g. for auditing the supply chain.
+            withProvenance = drv:
+              if crateConfig' ? provenance
+              then lib.addMetaAttrs { inherit (crateConfig') provenance; } drv
+              else drv;
           in
-          buildRustCrateForPkgsFunc pkgs
+          # Substitutes for crates whose build scripts need network access, see `buildScripts` in crate2nix.json.
+          crateConfig'.preBuilt or (withProvenance (withBinaryDebugInfo (buildRustCrateForPkgsFunc pkgs
             (
               crateConfig // {
-                src = crateConfig.src or (
-                  pkgs.fetchurl rec {
-                    name = "${crateConfig.crateName}-${crateConfig.version}.tar.gz";
-                    # https://www.pietroalbini.org/blog/downloading-crates-io/
-                    # Not rate-limited, CDN URL.
-                    url = "https://static.crates.io/crates/${crateConfig.crateName}/${crateConfig.crateName}-${crateConfig.version}.crate";
-                    sha256 =
-                      assert (lib.assertMsg (crateConfig ? sha256) "Missing sha256 for ${name}");
-                      crateConfig.sha256;
-                  }
-                );
-                extraRustcOpts = lib.lists.optional (targetFeatures != [ ]) "-C target-feature=${lib.concatMapStringsSep "," (x: "+${x}") targetFeatures}";
+                src = crateSrc crateConfig;
+                extraRustcOpts = lib.lists.optional (targetFeatures != [ ]) "-C target-feature=${lib.concatMapStringsSep "," (x: "+${x}") targetFeatures}"
+                  ++ profileRustcOpts
+                  ++ (crateConfig'.crateRustcOpts or [ ])
+                  ++ binaryDebugInfo'.extraRustcOpts
+                  ++ reproducibleBuild'.extraRustcOpts
+                  ++ noStdRustcOpts { inherit pkgs isTested; inherit (self) isBuild; };
                 inherit features dependencies buildDependencies crateRenames release;
-              }
-            );
+              } // lib.optionalAttrs (linksHook != "") { preConfigure = linksHook + (crateConfig.preConfigure or ""); }
+              // lib.optionalAttrs (replacementsHook != "") { postPatch = (crateConfig.postPatch or "") + replacementsHook; }
+              // lib.optionalAttrs (exportOutDirHook != "") { postInstall = exportOutDirHook + (crateConfig.postInstall or ""); }
+              // lib.optionalAttrs (storeManifestDirHook != "") { preBuild = storeManifestDirHook + (crateConfig.preBuild or ""); }
+              // reproducibleBuild'.attrs // artifactEnv // outDirEnv // (crateConfig'.buildScriptEnv or { }) // (crateConfig'.crateEnv or { })
+              // lib.optionalAttrs (isTested && crateConfig' ? testSrc) { src = crateConfig'.testSrc; }
+            ))));
+      in
+      # Crates that depend on the root crate, e.g. because it dev-depends on them,
+      # use the root crate without its dev dependencies to avoid a cycle.
+      (if runTests
+      then
+        builtByPackageIdByPkgs // {
+          crates = builtByPackageIdByPkgs.crates // {
+            ${rootPackageId} = buildByPackageIdForPkgsImpl builtByPackageIdByPkgs pkgs rootPackageId true;
+          };
+        }
+      else builtByPackageIdByPkgs) // {
+        packageIds = lib.unique
+          (builtins.map (lib.removePrefix "build ") (builtins.attrNames mergedFeatures));
+      };
+
+  /* Returns the output paths of all crates built for the given root crates by packageId,
+    e.g. for `crate2nix report`, or another attribute of their derivations like `drvPath`.
+
+    Crates that cannot be evaluated, e.g. because they are not supported on this platform,
+    are left out.
+  */
+  builtCrateOutPaths = { packageIds, features ? rootFeatures, attr ? "outPath" }:
+    let
+      outPaths = packageId:
+        let
+          built = builtRustCratesWithFeatures {
+            inherit packageId features;
+            buildRustCrateForPkgsFunc = buildRustCrateForPkgs;
+            runTests = false;
+          };
+          outPath = id: builtins.tryEval built.crates.${id}.${attr};
+          evaluated = lib.genAttrs built.packageIds outPath;
+        in
+        lib.mapAttrs (id: result: result.value)
+          (lib.filterAttrs (id: result: result.success) evaluated);
+    in
+    lib.foldl' (acc: packageId: acc // outPaths packageId) { } packageIds;
+
+  /* Returns the source of a crate: its entry in `crateSources`, its `src` or the crate
+    downloaded from crates.io.
+  */
+  crateSrc = crateConfig:
+    crateSources."${crateConfig.crateName}-${crateConfig.version}" or crateConfig.src or (
+      let
+        name = "${crateConfig.crateName}-${crateConfig.version}.tar.gz";
+        sha256 =
+          assert (lib.assertMsg (crateConfig ? sha256) "Missing sha256 for ${name}");
+          crateConfig.sha256;
       in
-      builtByPackageIdByPkgs;
+      if cratesIoMirror != null
+      then
+        pkgs.fetchurl {
+          inherit name sha256;
+          url = crateDownloadUrl cratesIoMirror crateConfig.crateName crateConfig.version;
+        }
+      # The checksums in Cargo.lock are of the packed crate, hence `unpack = false`.
+      else if useFetchCrate
+      then
+        pkgs.fetchCrate {
+          inherit (crateConfig) crateName version;
+          inherit sha256;
+          unpack = false;
+        }
+      else
+        pkgs.fetchurl {
+          inherit name sha256;
+          # https://www.pietroalbini.org/blog/downloading-crates-io/
+          # Not rate-limited, CDN URL.
+          url = "https://static.crates.io/crates/${crateConfig.crateName}/${crateConfig.crateName}-${crateConfig.version}.crate";
+        }
+    );
+
+  /* Expands a download URL template like cargo does for the `dl` field in a registry's config.json.
+
+    Mirrors `download_url` in crate2nix/src/resolve.rs.
+  */
+  crateDownloadUrl = template: crateName: version:
+    let
+      prefix = name:
+        let length = builtins.stringLength name;
+        in
+        if length <= 2 then toString length
+        else if length == 3 then "3/${builtins.substring 0 1 name}"
+        else "${builtins.substring 0 2 name}/${builtins.substring 2 2 name}";
+      markers = [ "{crate}" "{version}" "{prefix}" "{lowerprefix}" ];
+      template' =
+        if lib.any (marker: lib.hasInfix marker template) markers
+        then template
+        else "${template}/{crate}/{version}/download";
+    in
+    builtins.replaceStrings
+      markers
+      [ crateName version (prefix crateName) (prefix (lib.toLower crateName)) ]
+      template';
+
+  /* Returns the package IDs of the crates from registries and git repositories,
+    i.e. the ones that are downloaded.
+  */
+  vendoredPackageIds = { crateConfigs ? crates }:
+    builtins.filter
+      (packageId:
+        let crateConfig = crateConfigs.${packageId};
+        in
+        !(crateConfig ? src)
+        # Crates that crate2nix could not resolve throw.
+        || (builtins.tryEval (lib.isDerivation crateConfig.src)).value)
+      (builtins.attrNames crateConfigs);
+
+  /* Returns a directory source for cargo with the downloaded crates of `crateConfigs`.
+
+    The layout is the one of `cargo vendor --versioned-dirs`: every crate is in a
+    `<crateName>-<version>` directory with a `.cargo-checksum.json` that lists the checksums of
+    its files and of the packed crate. Crates from git repositories are looked up by name in the
+    checkout and have no package checksum, like with cargo.
+  */
+  vendoredSources = { crateConfigs ? crates }:
+    let
+      vendorCrate = packageId:
+        let crateConfig = crateConfigs.${packageId};
+        in
+        lib.escapeShellArgs [
+          crateConfig.crateName
+          "${crateConfig.crateName}-${crateConfig.version}"
+          (crateSrc crateConfig)
+        ];
+    in
+    pkgs.runCommand "vendored-sources" { nativeBuildInputs = [ pkgs.jq ]; } ''
+      mkdir $out
+      vendor_crate() {
+        local name="$1" dir="$out/$2" src="$3"
+        if [ -f "$src" ]; then
+          mkdir "$dir"
+          tar -xzf "$src" --strip-components=1 -C "$dir"
+          checksum="\"$(sha256sum "$src" | cut -d' ' -f1)\""
+        else
+          mapfile -t manifests < <(grep -l -r -s --include Cargo.toml -E "^name *= *\"$name\"" "$src")
+          manifest="''${manifests[0]:-}"
+          if [ -z "$manifest" ]; then
+            echo "Could not find the crate $name in $src" >&2
+            exit 1
+          fi
+          cp -r "$(dirname "$manifest")" "$dir"
+          chmod -R u+w "$dir"
+          checksum=null
+        fi
+        # `sha256sum` prints the 64 hex digits of the checksum, two spaces and the file name.
+        files=$(cd "$dir" && find . -type f -printf '%P\0' | sort -z | xargs -0 -r sha256sum \
+          | jq -R -s 'split("\n") | map(select(. != "") | { key: .[66:], value: .[0:64] }) | from_entries')
+        jq -n -c --argjson files "$files" --argjson package "$checksum" \
+          '{ files: $files, package: $package }' > "$dir/.cargo-checksum.json"
+      }
+      ${lib.concatMapStringsSep "\n" (packageId: "vendor_crate ${vendorCrate packageId}")
+        (vendoredPackageIds { inherit crateConfigs; })}
+    '';
+
+  /* Returns a shell in which cargo builds offline with the crates of `crateConfigs`.
+
+    `sourceReplacements` are the `[source]` definitions in `.cargo/config.toml` of all downloaded
+    sources, by key. `CARGO_HOME` points to a temporary directory with that configuration.
+  */
+  cargoDevShell = { sourceReplacements, crateConfigs ? crates }:
+    let
+      replaceWith = "vendored-sources";
+      config = (pkgs.formats.toml { }).generate "cargo-config.toml" {
+        source = lib.mapAttrs (_: source: source // { replace-with = replaceWith; }) sourceReplacements
+          // { ${replaceWith}.directory = "${vendoredSources { inherit crateConfigs; }}"; };
+      };
+    in
+    pkgs.mkShell {
+      nativeBuildInputs =
+        if rustc == null
+        then [ pkgs.rustc pkgs.cargo ]
+        else lib.unique [ rustc cargo ];
+      CARGO_NET_OFFLINE = "true";
+      shellHook = ''
+        export CARGO_HOME="$(mktemp -d)"
+        ln -s ${config} "$CARGO_HOME/config.toml"
+      '';
+      passthru.cargoConfig = config;
+    };
 
   /* Returns the actual derivations for the given dependencies. */
   dependencyDerivations =
@@ -3498,9 +5011,129 @@ rec {
         enabledDependencies = filterEnabledDependencies {
           inherit dependencies features target;
         };
+        # Artifact dependencies are only linked if they are also requested as library.
+        isLinked = dependency: !(dependency ? artifact) || dependency.artifact.lib or false;
         depDerivation = dependency: buildByPackageId dependency.packageId;
       in
-      map depDerivation enabledDependencies;
+      map depDerivation (builtins.filter isLinked enabledDependencies);
+
+  /* Returns the environment variables that cargo sets for the enabled artifact dependencies,
+    e.g. `CARGO_BIN_FILE_<DEP>_<NAME>` for `artifact = "bin"`.
+
+    Artifacts with `target = "target"` are built with `buildForTargetByPackageId`, all others
+    with `buildByPackageId`.
+  */
+  artifactDependencyEnv =
+    { buildByPackageId
+    , buildForTargetByPackageId ? buildByPackageId
+    , crateConfigs ? crates
+    , features
+    , dependencies
+    , target
+    }:
+    let
+      artifactDependencies = builtins.filter (dep: dep ? artifact)
+        (filterEnabledDependencies { inherit dependencies features target; });
+      envForDependency = dep:
+        let
+          depName = lib.toUpper (builtins.replaceStrings [ "-" ] [ "_" ] (dep.rename or dep.name));
+          crateConfig = crateConfigs.${dep.packageId};
+          drv =
+            if (dep.artifact.target or null) == "target"
+            then buildForTargetByPackageId dep.packageId
+            else buildByPackageId dep.packageId;
+          inherit (dep.artifact) kinds;
+          allBinNames = builtins.map (bin: bin.name)
+            (crateConfig.crateBin or [{ name = crateConfig.crateName; }]);
+          binNames =
+            if builtins.elem "bin" kinds
+            then allBinNames
+            else builtins.map (lib.removePrefix "bin:") (builtins.filter (lib.hasPrefix "bin:") kinds);
+          binFile = bin: "${drv}/bin/${bin}";
+        in
+        lib.optionalAttrs (binNames != [ ])
+          (
+            { "CARGO_BIN_DIR_${depName}" = "${drv}/bin"; }
+            // builtins.listToAttrs
+              (builtins.map (bin: lib.nameValuePair "CARGO_BIN_FILE_${depName}_${bin}" (binFile bin)) binNames)
+            // lib.optionalAttrs (builtins.elem crateConfig.crateName binNames) {
+              "CARGO_BIN_FILE_${depName}" = binFile crateConfig.crateName;
+            }
+          )
+        // lib.optionalAttrs (builtins.elem "cdylib" kinds) {
+          "CARGO_CDYLIB_DIR_${depName}" = "${drv.lib}/lib";
+        }
+        // lib.optionalAttrs (builtins.elem "staticlib" kinds) {
+          "CARGO_STATICLIB_DIR_${depName}" = "${drv.lib}/lib";
+        };
+    in
+    lib.foldl' (env: dep: env // envForDependency dep) { } artifactDependencies;
+
+  /* Returns the environment variables `DEP_<NAME>_OUT_DIR` with the installed `OUT_DIR`s of
+    the enabled dependencies with `exportOutDir`, by their names in `Cargo.toml`.
+  */
+  outDirDependencyEnv =
+    { buildByPackageId
+    , crateConfigs ? crates
+    , features
+    , dependencies
+    , target
+    }:
+    let
+      exportingDependencies = builtins.filter (dep: crateConfigs.${dep.packageId}.exportOutDir or false)
+        (filterEnabledDependencies { inherit dependencies features target; });
+      envForDependency = dep:
+        let
+          depName = lib.toUpper (builtins.replaceStrings [ "-" ] [ "_" ] (dep.rename or dep.name));
+        in
+        lib.nameValuePair "DEP_${depName}_OUT_DIR" "${(buildByPackageId dep.packageId).lib}/out-dir";
+    in
+    builtins.listToAttrs (builtins.map envForDependency exportingDependencies);
+
+  /* Returns a shell snippet that exports the metadata of the enabled dependencies with a
+    `links` key as `DEP_<LINKS>_<KEY>` for the build script, see
+    https://doc.rust-lang.org/cargo/reference/build-scripts.html#the-links-manifest-key.
+
+    buildRustCrate derives the prefix of these variables from the crate name, e.g. `DEP_LIBZ_`
+    for `libz-sys`, while cargo uses the `links` value, e.g. `DEP_Z_` for `links = "z"`.
+  */
+  linksDependencyHook =
+    { buildByPackageId
+    , crateConfigs ? crates
+    , features
+    , dependencies
+    , target
+    }:
+    let
+      envPrefix = name: lib.toUpper (builtins.replaceStrings [ "-" ] [ "_" ] name);
+      linkingDependencies = builtins.filter (dep: (crateConfigs.${dep.packageId}.links or null) != null)
+        (filterEnabledDependencies { inherit dependencies features target; });
+      hookForDependency = dep:
+        let
+          crateConfig = crateConfigs.${dep.packageId};
+          namePrefix = envPrefix (lib.removeSuffix "-sys" crateConfig.crateName);
+          linksPrefix = envPrefix crateConfig.links;
+          env = "${(buildByPackageId dep.packageId).lib}/env";
+        in
+        lib.optionalString (namePrefix != linksPrefix) ''
+          if [ -f ${env} ]; then
+            source <(sed -n 's/^export DEP_${namePrefix}_/export DEP_${linksPrefix}_/p' ${env})
+          fi
+        '';
+    in
+    lib.concatMapStrings hookForDependency linkingDependencies;
+
+  /* Returns a script that replaces `from` with `to` in `file` for every replacement, relative
+    to the crate source. The build fails if `from` does not occur, e.g. because a new release of
+    the crate made the replacement obsolete.
+  */
+  sourceReplacementsHook = replacements:
+    lib.concatMapStrings
+      (replacement: ''
+        substituteInPlace ${lib.escapeShellArg replacement.file} \
+          --replace-fail ${lib.escapeShellArg replacement.from} ${lib.escapeShellArg replacement.to}
+      '')
+      replacements;
 
   /* Returns a sanitized version of val with all values substituted that cannot
     be serialized as JSON.
@@ -3515,7 +5148,11 @@ rec {
     else val;
 
   /* Returns various tools to debug a crate. */
-  debugCrate = { packageId, target ? makeDefaultTarget stdenv.hostPlatform }:
+  debugCrate =
+    { packageId
+    , target ? makeDefaultTarget stdenv.hostPlatform
+    , buildTarget ? makeDefaultTarget stdenv.buildPlatform
+    }:
     assert (builtins.isString packageId);
     let
       debug = rec {
@@ -3536,9 +5173,12 @@ rec {
               inherit packageId;
             }
           );
+        # With feature resolver "2", crates built for the build platform appear separately
+        # as "build <packageId>".
         mergedPackageFeatures = mergePackageFeatures {
           features = rootFeatures;
-          inherit packageId target;
+          splitBuildFeatures = featureResolver == "2";
+          inherit packageId target buildTarget;
         };
         diffedDefaultPackageFeatures = diffDefaultPackageFeatures {
           inherit packageId target;
@@ -3585,10 +5225,21 @@ rec {
         inherit onlyInCargo onlyInCrate2Nix differentFeatures;
       };
 
+  /* Returns the key of a package in the result of mergePackageFeatures.
+
+    With `splitBuildFeatures`, crates that are built for the build platform have their own
+    features, as with cargo's feature resolver version 2.
+  */
+  packageFeaturesKey = { splitBuildFeatures, isBuild, packageId }:
+    if splitBuildFeatures && isBuild then "build ${packageId}" else packageId;
+
   /* Returns an attrset mapping packageId to the list of enabled features.
 
     If multiple paths to a dependency enable different features, the
     corresponding feature sets are merged. Features in rust are additive.
+
+    With `splitBuildFeatures`, build dependencies, proc macros and their dependencies
+    are merged separately under the keys returned by packageFeaturesKey.
   */
   mergePackageFeatures =
     { crateConfigs ? crates
@@ -3597,9 +5248,15 @@ rec {
     , features ? rootFeatures
     , dependencyPath ? [ crates.${packageId}.crateName ]
     , featuresByPackageId ? { }
+      # The platform that this package is built for.
     , target
+      # The platform that build dependencies and proc macros are built for.
+    , buildTarget ? target
       # Adds devDependencies to the crate with rootPackageId.
     , runTests ? false
+    , splitBuildFeatures ? false
+      # Whether this package is built for the build platform.
+    , isBuild ? false
     , ...
     } @ args:
       assert (builtins.isAttrs crateConfigs);
@@ -3609,6 +5266,7 @@ rec {
       assert (builtins.isList dependencyPath);
       assert (builtins.isAttrs featuresByPackageId);
       assert (builtins.isAttrs target);
+      assert (builtins.isAttrs buildTarget);
       assert (builtins.isBool runTests);
       let
         crateConfig = crateConfigs."${packageId}" or (builtins.throw "Package not found: ${packageId}");
@@ -3625,7 +5283,8 @@ rec {
           assert (builtins.isList dependencies);
           let
             enabledDependencies = filterEnabledDependencies {
-              inherit dependencies target;
+              inherit dependencies;
+              target = if path == "build" then buildTarget else target;
               features = enabledFeatures;
             };
             directDependencies = map depWithResolvedFeatures enabledDependencies;
@@ -3635,25 +5294,34 @@ rec {
             (
               cache: { packageId, features }:
                 let
-                  cacheFeatures = cache.${packageId} or [ ];
+                  isBuild' =
+                    isBuild || path == "build" || (crateConfigs.${packageId}.procMacro or false);
+                  key = packageFeaturesKey {
+                    inherit splitBuildFeatures packageId;
+                    isBuild = isBuild';
+                  };
+                  cacheFeatures = cache.${key} or [ ];
                   combinedFeatures = sortedUnique (cacheFeatures ++ features);
                 in
-                if cache ? ${packageId} && cache.${packageId} == combinedFeatures
+                if cache ? ${key} && cache.${key} == combinedFeatures
                 then cache
                 else
                   mergePackageFeatures {
                     features = combinedFeatures;
                     featuresByPackageId = cache;
-                    inherit crateConfigs packageId target runTests rootPackageId;
+                    isBuild = isBuild';
+                    target = if isBuild' then buildTarget else target;
+                    inherit crateConfigs packageId buildTarget runTests rootPackageId splitBuildFeatures;
                   }
             );
         cacheWithSelf =
           let
-            cacheFeatures = featuresByPackageId.${packageId} or [ ];
+            key = packageFeaturesKey { inherit splitBuildFeatures isBuild packageId; };
+            cacheFeatures = featuresByPackageId.${key} or [ ];
             combinedFeatures = sortedUnique (cacheFeatures ++ enabledFeatures);
           in
           featuresByPackageId // {
-            "${packageId}" = combinedFeatures;
+            "${key}" = combinedFeatures;
           };
         cacheWithDependencies =
           resolveDependencies cacheWithSelf "dep"
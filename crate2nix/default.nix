@@ -79,8 +79,10 @@ symlinkJoin {
     rm -rf $out/lib $out/bin/crate2nix.d
     mkdir -p \
       $out/share/bash-completion/completions \
-      $out/share/zsh/vendor-completions
+      $out/share/zsh/vendor-completions \
+      $out/share/fish/vendor_completions.d
     $out/bin/crate2nix completions -s 'bash' -o $out/share/bash-completion/completions
     $out/bin/crate2nix completions -s 'zsh' -o $out/share/zsh/vendor-completions
+    $out/bin/crate2nix completions -s 'fish' -o $out/share/fish/vendor_completions.d
   '';
 }
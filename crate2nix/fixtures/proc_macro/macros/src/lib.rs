@@ -0,0 +1,8 @@
+use proc_macro::TokenStream;
+
+#[proc_macro]
+pub fn greeting(_input: TokenStream) -> TokenStream {
+    "fn greeting() -> &'static str { \"Hello from a proc macro\" }"
+        .parse()
+        .unwrap()
+}
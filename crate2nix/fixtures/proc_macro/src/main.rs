@@ -0,0 +1,5 @@
+macros::greeting!();
+
+fn main() {
+    println!("{}", greeting());
+}
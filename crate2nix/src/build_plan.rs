@@ -0,0 +1,200 @@
+//! A build plan of the resolved crates in build order, e.g. for `crate2nix build-plan`.
+//!
+//! External schedulers can use it to distribute the builds of `Cargo.nix` without evaluating
+//! nix: every crate depends only on crates of earlier levels, so the crates of one level can be
+//! built in parallel. The dependencies of all targets are included and dev dependencies are not,
+//! since the crates are built without them.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Error};
+use serde::Serialize;
+
+use crate::resolve::CrateDerivation;
+use crate::BuildInfo;
+
+/// The build plan of all crates.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildPlan {
+    /// The crates in build order.
+    pub crates: Vec<PlannedCrate>,
+    /// The package IDs of the crates by level, the crates of a level only depend on the crates of
+    /// earlier levels.
+    pub levels: Vec<Vec<String>>,
+}
+
+/// A crate in the build plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedCrate {
+    /// The package ID, as in `crates` of `Cargo.nix`.
+    pub package_id: String,
+    /// The crate name.
+    pub crate_name: String,
+    /// The crate version.
+    pub version: String,
+    /// The package IDs of the crates that have to be built before this one.
+    pub dependencies: Vec<String>,
+    /// The level of the crate, one more than the highest level of its dependencies.
+    pub level: usize,
+    /// Whether the crate has a build script, which is compiled and run before the crate.
+    pub build_script: bool,
+    /// Whether the crate is a proc-macro, which is built for the build platform.
+    pub proc_macro: bool,
+    /// Whether the crate is a workspace member.
+    pub workspace_member: bool,
+    /// The number of crates that depend on this one directly.
+    pub dependents: usize,
+    /// A rough estimate of the build cost in arbitrary units, see `weight`.
+    pub weight: usize,
+}
+
+/// A rough estimate of the build cost of a crate: one unit per crate and per dependency to link,
+/// two more for compiling and running a build script and one more for a proc-macro.
+fn weight(crate_derivation: &CrateDerivation, dependencies: usize) -> usize {
+    1 + dependencies
+        + 2 * usize::from(crate_derivation.build.is_some())
+        + usize::from(crate_derivation.proc_macro)
+}
+
+/// Returns the build plan of all crates of `build_info`.
+pub fn of(build_info: &BuildInfo) -> Result<BuildPlan, Error> {
+    plan(&build_info.crates)
+}
+
+fn plan(crates: &[CrateDerivation]) -> Result<BuildPlan, Error> {
+    let dependencies: Vec<BTreeSet<usize>> = crates
+        .iter()
+        .map(|crate_derivation| {
+            crate_derivation
+                .dependencies
+                .iter()
+                .chain(&crate_derivation.build_dependencies)
+                .filter_map(|dependency| {
+                    crates
+                        .iter()
+                        .position(|c| c.package_id == dependency.package_id)
+                })
+                .collect()
+        })
+        .collect();
+    let mut dependents = vec![0; crates.len()];
+    for index in dependencies.iter().flatten() {
+        dependents[*index] += 1;
+    }
+
+    let mut levels: Vec<Option<usize>> = vec![None; crates.len()];
+    let mut by_level: Vec<Vec<usize>> = Vec::new();
+    while by_level.iter().map(Vec::len).sum::<usize>() < crates.len() {
+        let level = by_level.len();
+        let ready: Vec<usize> = (0..crates.len())
+            .filter(|&i| levels[i].is_none())
+            .filter(|&i| dependencies[i].iter().all(|&d| levels[d].is_some()))
+            .collect();
+        if ready.is_empty() {
+            let cyclic = (0..crates.len())
+                .filter(|&i| levels[i].is_none())
+                .map(|i| crates[i].package_id.repr.as_str())
+                .collect::<Vec<_>>();
+            bail!("the dependencies of {} form a cycle", cyclic.join(", "));
+        }
+        for &i in &ready {
+            levels[i] = Some(level);
+        }
+        by_level.push(ready);
+    }
+
+    let crates_in_order = by_level
+        .iter()
+        .flatten()
+        .map(|&i| {
+            let crate_derivation = &crates[i];
+            PlannedCrate {
+                package_id: crate_derivation.package_id.repr.clone(),
+                crate_name: crate_derivation.crate_name.to_string(),
+                version: crate_derivation.version.to_string(),
+                dependencies: dependencies[i]
+                    .iter()
+                    .map(|&d| crates[d].package_id.repr.clone())
+                    .collect(),
+                level: levels[i].unwrap_or_default(),
+                build_script: crate_derivation.build.is_some(),
+                proc_macro: crate_derivation.proc_macro,
+                workspace_member: crate_derivation.is_root_or_workspace_member,
+                dependents: dependents[i],
+                weight: weight(crate_derivation, dependencies[i].len()),
+            }
+        })
+        .collect();
+    Ok(BuildPlan {
+        crates: crates_in_order,
+        levels: by_level
+            .into_iter()
+            .map(|level| {
+                level
+                    .into_iter()
+                    .map(|i| crates[i].package_id.repr.clone())
+                    .collect()
+            })
+            .collect(),
+    })
+}
+
+/// Renders the build plan of all crates of `build_info` as JSON.
+pub fn render(build_info: &BuildInfo) -> Result<String, Error> {
+    let mut json = serde_json::to_string_pretty(&of(build_info)?)?;
+    json.push('\n');
+    Ok(json)
+}
+
+#[test]
+fn test_plan() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("helper");
+    env.add_package_and_node("base");
+    let indexed = env.indexed_metadata();
+    let mut crates: Vec<CrateDerivation> = indexed
+        .pkgs_by_id
+        .values()
+        .map(|package| CrateDerivation::unresolved(&indexed, package, String::new()))
+        .collect();
+    let index = |name: &str| crates.iter().position(|c| *c.crate_name == *name).unwrap();
+    let (main, helper, base) = (index("main"), index("helper"), index("base"));
+    let dependency_on = |crate_derivation: &CrateDerivation| crate::resolve::ResolvedDependency {
+        name: crate_derivation.crate_name.clone(),
+        rename: None,
+        package_id: crate_derivation.package_id.clone(),
+        target: None,
+        optional: false,
+        uses_default_features: true,
+        features: Vec::new(),
+        artifact: None,
+    };
+    let (on_helper, on_base) = (dependency_on(&crates[helper]), dependency_on(&crates[base]));
+    crates[main].dependencies.push(on_helper);
+    crates[helper].build_dependencies.push(on_base);
+    // Dev dependencies are not built before the crate.
+    let on_main = dependency_on(&crates[main]);
+    crates[base].dev_dependencies.push(on_main);
+
+    let build_plan = plan(&crates).unwrap();
+    let names: Vec<&str> = build_plan.crates.iter().map(|c| &*c.crate_name).collect();
+    assert_eq!(names, vec!["base", "helper", "main"]);
+    assert_eq!(build_plan.levels.len(), 3);
+    assert_eq!(
+        build_plan.levels[1],
+        vec![crates[helper].package_id.repr.clone()]
+    );
+    assert_eq!(build_plan.crates[0].dependents, 1);
+    assert_eq!(build_plan.crates[1].level, 1);
+    assert_eq!(build_plan.crates[1].weight, 2);
+    assert!(build_plan.crates[2].workspace_member);
+
+    let on_main = dependency_on(&crates[main]);
+    crates[base].dependencies.push(on_main);
+    assert!(plan(&crates).is_err());
+
+    env.close();
+}
@@ -0,0 +1,170 @@
+//! Heuristics for build scripts that try to access the network.
+//!
+//! Nix builds run in a sandbox without network access, so such build scripts fail, often
+//! with confusing errors. We warn during generation so that users can configure
+//! `buildScripts` in `crate2nix.json` up front.
+
+use std::path::Path;
+
+use cargo_metadata::PackageId;
+use log::warn;
+
+use crate::metadata::IndexedMetadata;
+use crate::resolve::CrateDerivation;
+#[cfg(test)]
+use crate::test;
+
+/// Crates whose build scripts are known to download files by default, with the reason.
+const KNOWN_OFFENDERS: &[(&str, &str)] = &[
+    (
+        "v8",
+        "downloads a prebuilt static library unless RUSTY_V8_ARCHIVE is set",
+    ),
+    ("torch-sys", "downloads libtorch unless LIBTORCH is set"),
+    (
+        "tensorflow-sys",
+        "downloads the TensorFlow C library unless it is found on the system",
+    ),
+    (
+        "onnxruntime-sys",
+        "downloads the ONNX runtime unless ORT_STRATEGY=system is set",
+    ),
+    (
+        "ort-sys",
+        "downloads the ONNX runtime unless ORT_LIB_LOCATION is set",
+    ),
+];
+
+/// Build dependencies that are HTTP clients.
+const HTTP_CLIENTS: &[&str] = &[
+    "attohttpc",
+    "curl",
+    "hyper",
+    "isahc",
+    "minreq",
+    "reqwest",
+    "ureq",
+];
+
+/// Snippets in build script sources that indicate downloads.
+const DOWNLOAD_SNIPPETS: &[&str] = &[
+    "Command::new(\"curl\")",
+    "Command::new(\"wget\")",
+    "Command::new(\"git\")",
+];
+
+/// A crate whose build script probably needs network access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkUsage {
+    /// The package ID of the crate.
+    pub package_id: PackageId,
+    /// Why we think that the build script needs network access.
+    pub reason: String,
+}
+
+/// Returns the crates whose build scripts probably need network access and warns about them.
+///
+/// Crates with `allowNetwork`, `env` or `preBuilt` in their build script configuration are skipped.
+pub fn detect_network_usage(
+    metadata: &IndexedMetadata,
+    crates: &[CrateDerivation],
+) -> Vec<NetworkUsage> {
+    let mut usages = Vec::new();
+    for crate_derivation in crates {
+        let Some(build) = crate_derivation.build.as_ref() else {
+            continue;
+        };
+        if let Some(config) = crate_derivation.build_script_config.as_ref() {
+            if config.allow_network || !config.env.is_empty() || config.pre_built.is_some() {
+                continue;
+            }
+        }
+
+        let package_dir = metadata
+            .pkgs_by_id
+            .get(&crate_derivation.package_id)
+            .and_then(|package| package.manifest_path.parent())
+            .map(|dir| dir.as_std_path().to_path_buf());
+        let reason = network_usage_reason(
+            crate_derivation,
+            package_dir.map(|dir| dir.join(&build.src_path)).as_deref(),
+        );
+        if let Some(reason) = reason {
+            warn!(
+                "The build script of {} {} probably needs network access: {}. \
+                 Configure buildScripts.\"{}\" in crate2nix.json to provide the files \
+                 or to silence this warning.",
+                crate_derivation.crate_name,
+                crate_derivation.version,
+                reason,
+                crate_derivation.crate_name
+            );
+            usages.push(NetworkUsage {
+                package_id: (*crate_derivation.package_id).clone(),
+                reason,
+            });
+        }
+    }
+    usages
+}
+
+fn network_usage_reason(
+    crate_derivation: &CrateDerivation,
+    build_script: Option<&Path>,
+) -> Option<String> {
+    if let Some((_, reason)) = KNOWN_OFFENDERS
+        .iter()
+        .find(|(name, _)| *name == &*crate_derivation.crate_name)
+    {
+        return Some(reason.to_string());
+    }
+    if let Some(client) = crate_derivation
+        .build_dependencies
+        .iter()
+        .find(|dependency| HTTP_CLIENTS.contains(&&*dependency.name))
+    {
+        return Some(format!("it depends on the HTTP client {}", client.name));
+    }
+    let source = std::fs::read_to_string(build_script?).ok()?;
+    DOWNLOAD_SNIPPETS
+        .iter()
+        .find(|snippet| source.contains(*snippet))
+        .map(|snippet| format!("it runs {}", snippet))
+}
+
+#[test]
+fn test_network_usage_reason() {
+    let mut env = test::MetadataEnv::default();
+    env.add_package_and_node("torch-sys");
+    env.add_package_and_node("innocent");
+    let indexed = env.indexed_metadata();
+    let crate_derivation = |name: &str| {
+        let package = indexed
+            .pkgs_by_id
+            .values()
+            .find(|p| p.name == name)
+            .unwrap();
+        CrateDerivation::unresolved(&indexed, package, String::new())
+    };
+
+    let reason = network_usage_reason(&crate_derivation("torch-sys"), None);
+    assert!(reason.unwrap().contains("libtorch"));
+    assert_eq!(
+        network_usage_reason(&crate_derivation("innocent"), None),
+        None
+    );
+
+    let dir = env.temp_dir();
+    let build_script = dir.join("build.rs");
+    std::fs::write(
+        &build_script,
+        "fn main() { Command::new(\"curl\").arg(URL).status().unwrap(); }",
+    )
+    .unwrap();
+    assert_eq!(
+        network_usage_reason(&crate_derivation("innocent"), Some(&build_script)),
+        Some("it runs Command::new(\"curl\")".to_string())
+    );
+
+    env.close();
+}
@@ -0,0 +1,94 @@
+//! Listing the store paths of the third-party crates, e.g. for `crate2nix cache-manifest`.
+//!
+//! Third-party crates are the crates that are neither workspace members nor local
+//! directories. Their derivations only change with `Cargo.lock`, so CI can build and push them
+//! to a binary cache before building the workspace.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use cargo_metadata::PackageId;
+
+use crate::resolve::{CrateDerivation, ResolvedSource};
+use crate::BuildInfo;
+
+/// Returns the package IDs of the third-party crates in `build_info`.
+pub fn third_party_package_ids(build_info: &BuildInfo) -> Vec<&PackageId> {
+    let members: BTreeSet<&PackageId> = build_info.workspace_members.values().collect();
+    third_party_in(&build_info.crates, &members)
+}
+
+fn third_party_in<'a>(
+    crates: &'a [CrateDerivation],
+    members: &BTreeSet<&PackageId>,
+) -> Vec<&'a PackageId> {
+    crates
+        .iter()
+        .filter(|c| {
+            !members.contains(&*c.package_id)
+                && !matches!(c.source, ResolvedSource::LocalDirectory(_))
+        })
+        .map(|c| &*c.package_id)
+        .collect()
+}
+
+/// Renders the paths of `package_ids` in `paths_by_id`, sorted and one per line.
+///
+/// Crates without a path, e.g. because they are not built on this platform, are left out.
+pub fn render(package_ids: &[&PackageId], paths_by_id: &BTreeMap<String, String>) -> String {
+    let paths: BTreeSet<&str> = package_ids
+        .iter()
+        .filter_map(|id| paths_by_id.get(&id.repr))
+        .map(String::as_str)
+        .collect();
+    paths
+        .into_iter()
+        .map(|path| format!("{}\n", path))
+        .collect()
+}
+
+#[test]
+fn test_cache_manifest() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("serde");
+    env.add_package_and_node("vendored");
+    let indexed = env.indexed_metadata();
+    let crates: Vec<CrateDerivation> = indexed
+        .pkgs_by_id
+        .values()
+        .map(|package| {
+            let mut crate_derivation =
+                CrateDerivation::unresolved(&indexed, package, String::new());
+            if package.name == "vendored" {
+                crate_derivation.source =
+                    serde_json::from_str(r#"{"LocalDirectory": {"path": "vendor/vendored"}}"#)
+                        .unwrap();
+            }
+            crate_derivation
+        })
+        .collect();
+    let id_of = |name: &str| {
+        &*crates
+            .iter()
+            .find(|c| *c.crate_name == *name)
+            .unwrap()
+            .package_id
+    };
+    let members = BTreeSet::from([id_of("main")]);
+
+    let third_party = third_party_in(&crates, &members);
+    assert_eq!(third_party, vec![id_of("serde")]);
+
+    let paths_by_id: BTreeMap<String, String> = crates
+        .iter()
+        .map(|c| {
+            (
+                c.package_id.repr.clone(),
+                format!("/nix/store/{}.drv", c.crate_name),
+            )
+        })
+        .collect();
+    assert_eq!(render(&third_party, &paths_by_id), "/nix/store/serde.drv\n");
+    assert_eq!(render(&third_party, &BTreeMap::new()), "");
+    env.close();
+}
@@ -0,0 +1,284 @@
+//! The source replacements of `.cargo/config.toml`, e.g. `[source.crates-io] replace-with = ...`.
+//!
+//! `cargo metadata` reports the original source of replaced crates, e.g. crates.io for a crate
+//! from a `cargo vendor` directory. To build with the sources that cargo actually uses, we follow
+//! the `replace-with` chain of a source to the directory, local registry or mirror at its end,
+//! see https://doc.rust-lang.org/cargo/reference/source-replacement.html.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, format_err, Error};
+use log::debug;
+
+/// The configured sources by the `cargo_toml` files whose configuration they are.
+static SOURCE_CONFIGS: Mutex<BTreeMap<Vec<PathBuf>, SourceConfig>> = Mutex::new(BTreeMap::new());
+
+/// The source that cargo uses instead of a replaced one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplacedSource {
+    /// A directory with unpacked crates, e.g. from `cargo vendor`.
+    Directory(PathBuf),
+    /// A directory with `.crate` files and an index, e.g. from `cargo local-registry`.
+    LocalRegistry(PathBuf),
+    /// Another registry, e.g. a mirror, by source ID.
+    Registry(String),
+}
+
+/// The `[source.*]` tables of the cargo configuration.
+#[derive(Debug, Clone, Default)]
+struct SourceConfig {
+    /// The keys of every source by name, with directories relative to their config file resolved.
+    sources: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// `$CARGO_HOME`, `$HOME/.cargo` without `CARGO_HOME`.
+pub fn cargo_home() -> Option<PathBuf> {
+    std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")))
+}
+
+/// The cargo configuration files that apply to `dir`, with the highest precedence first.
+pub fn config_files(dir: &Path, cargo_home: Option<&Path>) -> Vec<PathBuf> {
+    let mut config_files = Vec::new();
+    for dir in dir.ancestors() {
+        config_files.push(dir.join(".cargo").join("config.toml"));
+        config_files.push(dir.join(".cargo").join("config"));
+    }
+    if let Some(cargo_home) = cargo_home {
+        config_files.push(cargo_home.join("config.toml"));
+        config_files.push(cargo_home.join("config"));
+    }
+    config_files.retain(|file| file.is_file());
+    config_files.dedup();
+    config_files
+}
+
+/// Returns the source that replaces the source with the given ID in the configuration of
+/// `cargo_toml`, if any.
+pub fn replaced_source(
+    cargo_toml: &[PathBuf],
+    source_id: &str,
+) -> Result<Option<ReplacedSource>, Error> {
+    let mut configs = SOURCE_CONFIGS.lock().expect("source config lock");
+    let config = match configs.get(cargo_toml) {
+        Some(config) => config,
+        None => {
+            let config = SourceConfig::load(cargo_toml)?;
+            configs.entry(cargo_toml.to_vec()).or_insert(config)
+        }
+    };
+    config.replaced_source(source_id)
+}
+
+impl SourceConfig {
+    fn load(cargo_toml: &[PathBuf]) -> Result<SourceConfig, Error> {
+        let cargo_home = cargo_home();
+        let mut config = SourceConfig::default();
+        for cargo_toml in cargo_toml {
+            let dir = cargo_toml
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+            for config_file in config_files(&dir, cargo_home.as_deref()) {
+                let content = std::fs::read_to_string(&config_file)
+                    .map_err(|e| format_err!("while reading {}: {}", config_file.display(), e))?;
+                config.add(&config_file, &content)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Adds the sources of a config file with lower precedence than the ones added before.
+    fn add(&mut self, config_file: &Path, content: &str) -> Result<(), Error> {
+        let table: toml::Table = toml::from_str(content)
+            .map_err(|e| format_err!("while parsing {}: {}", config_file.display(), e))?;
+        let Some(sources) = table.get("source").and_then(toml::Value::as_table) else {
+            return Ok(());
+        };
+        // Relative paths are relative to the parent of the directory that contains the file.
+        let base = config_file
+            .parent()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new("/"));
+        for (name, source) in sources {
+            let Some(source) = source.as_table() else {
+                continue;
+            };
+            let keys = self.sources.entry(name.clone()).or_default();
+            for (key, value) in source {
+                let Some(value) = value.as_str() else {
+                    continue;
+                };
+                let value = if key == "directory" || key == "local-registry" {
+                    base.join(value).to_string_lossy().to_string()
+                } else {
+                    value.to_string()
+                };
+                keys.entry(key.clone()).or_insert(value);
+            }
+        }
+        debug!("Read the sources of {}.", config_file.display());
+        Ok(())
+    }
+
+    fn replaced_source(&self, source_id: &str) -> Result<Option<ReplacedSource>, Error> {
+        let Some((key, definition)) = crate::vendor::replacement(source_id) else {
+            return Ok(None);
+        };
+        let name = if key == "crates-io" {
+            key
+        } else {
+            match self
+                .sources
+                .iter()
+                .find(|(_, keys)| defines(keys, &definition))
+            {
+                Some((name, _)) => name.clone(),
+                None => return Ok(None),
+            }
+        };
+
+        let mut current = &name;
+        let mut seen = BTreeSet::new();
+        loop {
+            if !seen.insert(current) {
+                bail!(
+                    "the source replacements in .cargo/config.toml of {} form a cycle",
+                    source_id
+                );
+            }
+            let Some(keys) = self.sources.get(current) else {
+                if current == &name {
+                    return Ok(None);
+                }
+                bail!(
+                    "the replacement {} of {} is not defined in .cargo/config.toml",
+                    current,
+                    source_id
+                );
+            };
+            match keys.get("replace-with") {
+                Some(next) => current = next,
+                None if current == &name => return Ok(None),
+                None => {
+                    return Ok(Some(if let Some(directory) = keys.get("directory") {
+                        ReplacedSource::Directory(directory.into())
+                    } else if let Some(directory) = keys.get("local-registry") {
+                        ReplacedSource::LocalRegistry(directory.into())
+                    } else if let Some(registry) = keys.get("registry") {
+                        ReplacedSource::Registry(registry_source_id(registry))
+                    } else {
+                        bail!(
+                            "the replacement {} of {} is neither a directory, \
+                             local registry nor registry",
+                            current,
+                            source_id
+                        );
+                    }));
+                }
+            }
+        }
+    }
+}
+
+/// Whether the keys of a configured source define the source of `definition`,
+/// e.g. `registry = "https://my.registry/index"`.
+fn defines(keys: &BTreeMap<String, String>, definition: &BTreeMap<String, String>) -> bool {
+    let keys = keys
+        .iter()
+        .filter(|(key, _)| key.as_str() != "replace-with")
+        .map(|(key, value)| match key.as_str() {
+            "registry" => (key, registry_source_id(value)),
+            _ => (key, value.clone()),
+        });
+    let definition = definition.iter().map(|(key, value)| match key.as_str() {
+        "registry" => (key, registry_source_id(value)),
+        _ => (key, value.clone()),
+    });
+    keys.eq(definition)
+}
+
+/// The source ID of a registry URL in the configuration, e.g. `registry+https://...` for git
+/// indices, which are configured without prefix.
+fn registry_source_id(url: &str) -> String {
+    if url.starts_with("sparse+") || url.starts_with("registry+") {
+        url.to_string()
+    } else {
+        format!("registry+{}", url)
+    }
+}
+
+#[test]
+fn test_replaced_source() {
+    let mut config = SourceConfig::default();
+    config
+        .add(
+            Path::new("/project/.cargo/config.toml"),
+            r#"
+            [source.crates-io]
+            replace-with = "vendored-sources"
+
+            [source."git+https://github.com/kolloch/nix-base32?branch=main"]
+            git = "https://github.com/kolloch/nix-base32"
+            branch = "main"
+            replace-with = "vendored-sources"
+
+            [source.vendored-sources]
+            directory = "vendor"
+
+            [source.corp]
+            registry = "https://git.corp/index"
+            replace-with = "corp-mirror"
+
+            [source.corp-mirror]
+            registry = "sparse+https://mirror.corp/index/"
+            "#,
+        )
+        .unwrap();
+    config
+        .add(
+            Path::new("/home/user/.cargo/config.toml"),
+            r#"
+            [source.vendored-sources]
+            directory = "/elsewhere"
+
+            [source.loop]
+            registry = "sparse+https://loop/index/"
+            replace-with = "loop"
+            "#,
+        )
+        .unwrap();
+
+    let replaced = |source_id: &str| config.replaced_source(source_id);
+    assert_eq!(
+        replaced("registry+https://github.com/rust-lang/crates.io-index").unwrap(),
+        Some(ReplacedSource::Directory("/project/vendor".into()))
+    );
+    assert_eq!(
+        replaced("git+https://github.com/kolloch/nix-base32?branch=main#42f5544e51187f0c7535d453fcffb4b524c99eb2")
+            .unwrap(),
+        Some(ReplacedSource::Directory("/project/vendor".into()))
+    );
+    assert_eq!(
+        replaced("registry+https://git.corp/index").unwrap(),
+        Some(ReplacedSource::Registry(
+            "sparse+https://mirror.corp/index/".to_string()
+        ))
+    );
+    assert_eq!(replaced("sparse+https://mirror.corp/index/").unwrap(), None);
+    assert_eq!(replaced("sparse+https://other/index/").unwrap(), None);
+    assert_eq!(
+        SourceConfig::default()
+            .replaced_source("registry+https://github.com/rust-lang/crates.io-index")
+            .unwrap(),
+        None
+    );
+    assert!(replaced("sparse+https://loop/index/")
+        .unwrap_err()
+        .to_string()
+        .contains("cycle"));
+}
@@ -1,6 +1,7 @@
 //! Utilities for dealing with spawned commands.
 
 use anyhow::{bail, format_err, Error};
+use log::{debug, log_enabled, Level};
 use std::process::{Child, Stdio};
 use std::thread;
 use std::{
@@ -14,7 +15,11 @@ use std::{
 /// The output will be printed indented if and only if the command does not
 /// return succesfully.
 pub fn run(caption: &str, command: &mut std::process::Command) -> Result<(), Error> {
-    eprint!("{}: ", caption);
+    let show_progress = log_enabled!(Level::Info);
+    if show_progress {
+        eprint!("{}: ", caption);
+    }
+    debug!("Running {:?}", command);
 
     let mut spawned: Child = command
         .stdin(Stdio::null())
@@ -38,11 +43,15 @@ pub fn run(caption: &str, command: &mut std::process::Command) -> Result<(), Err
         .map_err(|e| format_err!("while waiting for the {:?} to finish: {}", command, e))?;
 
     if status.success() {
-        eprintln!("done.");
+        if show_progress {
+            eprintln!("done.");
+        }
         return Ok(());
     }
 
-    eprintln!();
+    if show_progress {
+        eprintln!();
+    }
     eprintln!("  {:?}", command);
     let line_reader = BufReader::new(Cursor::new(out));
     for line in line_reader.lines() {
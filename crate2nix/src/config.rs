@@ -7,7 +7,7 @@ use std::{
     fmt::Display,
     fs::File,
     io::{BufReader, BufWriter},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 impl Config {
@@ -39,9 +39,301 @@ impl Config {
 pub struct Config {
     /// Out of tree sources.
     pub sources: BTreeMap<String, Source>,
+    /// Build script settings by crate name.
+    #[serde(
+        rename = "buildScripts",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub build_scripts: BTreeMap<String, BuildScriptConfig>,
+    /// Additional attributes for crate derivations by crate name or a pattern like `aws-*`.
+    #[serde(
+        rename = "crateOverrides",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub crate_overrides: BTreeMap<String, CrateOverride>,
+    /// Sources that replace the resolved source of a crate by crate name or `name@version`,
+    /// e.g. to build with a patched fork without changing `Cargo.toml`.
+    #[serde(
+        rename = "sourceOverrides",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub source_overrides: BTreeMap<String, SourceOverride>,
+    /// Additional platforms for `--platforms` by nix system, e.g. `wasm32-wasi`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub platforms: BTreeMap<String, PlatformDefinition>,
+}
+
+/// Additional attributes for the derivation of a crate, rendered into the generated nix.
+///
+/// Use `crateOverrides` in nix for anything more involved.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrateOverride {
+    /// Environment variables for the build as nix expressions by name,
+    /// e.g. `"OPENSSL_NO_VENDOR": "\"1\""`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Nix expressions for additional `buildInputs`, e.g. `pkgs.openssl`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub build_inputs: Vec<String>,
+    /// Nix expressions for additional `nativeBuildInputs`, e.g. `pkgs.pkg-config`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub native_build_inputs: Vec<String>,
+    /// Whether to fetch the submodules of a git dependency, overriding the detection
+    /// by `.gitmodules`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetch_submodules: Option<bool>,
+    /// Additional rustc flags, e.g. `-C target-cpu=native`. Unlike the other settings,
+    /// these are plain strings.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rustc_flags: Vec<String>,
+    /// Configuration options to enable with `--cfg`, e.g. `tokio_unstable`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cfg: Vec<String>,
+    /// The lint level to cap all lints at, e.g. `warn` to not fail on `#![deny(warnings)]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cap_lints: Option<String>,
+    /// A shell script to run before the build script, e.g. to patch the sources.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_configure: Option<String>,
+    /// A shell script to run after the build script and before compiling the crate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_build: Option<String>,
+    /// A shell script to run after compiling the crate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_build: Option<String>,
+    /// Whether to compile the crate with `CARGO_MANIFEST_DIR` pointing to a copy of the crate in
+    /// the nix store instead of the build directory, for crates that load files relative to
+    /// `env!("CARGO_MANIFEST_DIR")` at runtime.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_manifest_dir: Option<bool>,
+    /// Patch files to apply to the sources, relative to `crate2nix.json`, e.g. to fix a broken
+    /// release.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<PathBuf>,
+    /// Replacements in the sources, applied with `substituteInPlace` after the patches.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replacements: Vec<Replacement>,
+    /// What the build needs from outside of the nix sandbox, only for trusted builders.
+    #[serde(default, skip_serializing_if = "SandboxHints::is_empty")]
+    pub sandbox: SandboxHints,
+}
+
+/// The requirements of a crate build that escape the nix sandbox, see `CrateOverride::sandbox`.
+///
+/// They are rendered as the derivation attributes that nix reads, e.g. `requiredSystemFeatures`,
+/// so `crateOverrides` can still change them.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SandboxHints {
+    /// The features that a builder must support, e.g. `kvm` to get `/dev/kvm` in the sandbox.
+    /// Also useful to route the build to builders with configured `extra-sandbox-paths`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_system_features: Vec<String>,
+    /// The paths outside of the nix store to make available in the macOS sandbox,
+    /// `__impureHostDeps`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub impure_host_deps: Vec<String>,
+    /// Whether to build without the sandbox, `__noChroot`, which needs `sandbox = relaxed` on
+    /// the builders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_chroot: Option<bool>,
+}
+
+impl SandboxHints {
+    /// Whether the build needs nothing from outside of the sandbox.
+    pub fn is_empty(&self) -> bool {
+        self == &SandboxHints::default()
+    }
+}
+
+/// A replacement of a string in a source file of a crate, see `CrateOverride::replacements`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replacement {
+    /// The file relative to the root of the crate, e.g. `build.rs`.
+    pub file: String,
+    /// The string to replace. The build fails if the file does not contain it.
+    pub from: String,
+    /// The replacement.
+    pub to: String,
+}
+
+impl CrateOverride {
+    /// Adds the settings of `other`, which take precedence.
+    pub(crate) fn merge(&mut self, other: &CrateOverride) {
+        self.env.extend(other.env.clone());
+        self.build_inputs.extend(other.build_inputs.iter().cloned());
+        self.native_build_inputs
+            .extend(other.native_build_inputs.iter().cloned());
+        self.fetch_submodules = other.fetch_submodules.or(self.fetch_submodules);
+        self.rustc_flags.extend(other.rustc_flags.iter().cloned());
+        self.cfg.extend(other.cfg.iter().cloned());
+        self.cap_lints = other.cap_lints.clone().or(self.cap_lints.take());
+        self.store_manifest_dir = other.store_manifest_dir.or(self.store_manifest_dir);
+        self.patches.extend(other.patches.iter().cloned());
+        self.replacements.extend(other.replacements.iter().cloned());
+        self.sandbox
+            .required_system_features
+            .extend(other.sandbox.required_system_features.iter().cloned());
+        self.sandbox
+            .impure_host_deps
+            .extend(other.sandbox.impure_host_deps.iter().cloned());
+        self.sandbox.no_chroot = other.sandbox.no_chroot.or(self.sandbox.no_chroot);
+        for (hook, other_hook) in [
+            (&mut self.pre_configure, &other.pre_configure),
+            (&mut self.pre_build, &other.pre_build),
+            (&mut self.post_build, &other.post_build),
+        ] {
+            *hook = match (hook.take(), other_hook) {
+                (Some(hook), Some(other_hook)) => Some(format!("{}\n{}", hook, other_hook)),
+                (hook, other_hook) => hook.or_else(|| other_hook.clone()),
+            };
+        }
+    }
+}
+
+/// A source that replaces the resolved source of a crate.
+///
+/// The dependencies and features are still taken from `Cargo.lock`, so the replacement must be
+/// compatible with the resolved version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SourceOverride {
+    /// A local directory with the crate, relative to `crate2nix.json`.
+    Path {
+        /// The directory that contains the `Cargo.toml` of the crate.
+        path: PathBuf,
+    },
+    /// A git repository that contains the crate.
+    Git {
+        /// The URL of the git repository.
+        url: url::Url,
+        /// The revision hash.
+        rev: String,
+        /// The sha256 of the fetched result, e.g. from `nix-prefetch-git`.
+        sha256: String,
+        /// Whether to fetch the submodules of the repository.
+        #[serde(default, rename = "fetchSubmodules")]
+        fetch_submodules: bool,
+    },
+    /// A nix expression that evaluates to a directory with the crate, e.g. a derivation that
+    /// generates it.
+    Nix {
+        /// The nix file, relative to the generated nix like in `sources`.
+        #[serde(flatten)]
+        file: NixFile,
+        /// A Nix attribute path which will be resolved against the file.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attr: Option<String>,
+    },
+}
+
+impl Display for SourceOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceOverride::Path { path } => write!(f, "{}", path.display()),
+            SourceOverride::Git { url, rev, .. } => write!(f, "{}#{}", url, rev),
+            SourceOverride::Nix { file, attr: None } => write!(f, "{}", file),
+            SourceOverride::Nix {
+                file,
+                attr: Some(attr),
+            } => write!(f, "({}).{}", file, attr),
+        }
+    }
+}
+
+/// The rust target of a nix system and its `cfg` values, i.e. `target_arch` etc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlatformDefinition {
+    /// The rust target triple that nixpkgs uses for the system, e.g. `wasm32-wasip1`.
+    pub rust_target: String,
+    /// `target_arch`, e.g. `wasm32`.
+    pub arch: String,
+    /// `target_os`, e.g. `wasi`.
+    pub os: String,
+    /// `target_vendor`.
+    #[serde(default = "PlatformDefinition::default_vendor")]
+    pub vendor: String,
+    /// `target_env`, e.g. `gnu`, `musl` or empty.
+    #[serde(default)]
+    pub env: String,
+    /// `target_endian`.
+    #[serde(default = "PlatformDefinition::default_endian")]
+    pub endian: String,
+    /// `target_pointer_width`, e.g. `32`.
+    pub pointer_width: String,
+    /// `target_family`, e.g. `unix` or `wasm`.
+    #[serde(default)]
+    pub families: Vec<String>,
+}
+
+impl PlatformDefinition {
+    fn default_vendor() -> String {
+        "unknown".to_string()
+    }
+
+    fn default_endian() -> String {
+        "little".to_string()
+    }
+}
+
+/// How to deal with the build script of a crate, usually one that needs network access.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildScriptConfig {
+    /// Do not warn if the build script looks like it needs network access.
+    #[serde(default)]
+    pub allow_network: bool,
+    /// Environment variables for the build as nix expressions by name,
+    /// e.g. to point the build script to a prefetched archive instead of downloading it.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// A nix expression for a derivation that is used instead of building the crate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_built: Option<String>,
+    /// Install the `OUT_DIR` of the build script so that the build scripts of dependents can
+    /// read the generated files, e.g. `.proto` files or generated code.
+    #[serde(default)]
+    pub export_out_dir: bool,
 }
 
 impl Config {
+    /// Returns the overrides for `crate_name`: those of all matching patterns in alphabetical
+    /// order, followed by the ones for the exact name.
+    pub fn crate_override_for(&self, crate_name: &str) -> Option<CrateOverride> {
+        let mut matching = self
+            .crate_overrides
+            .iter()
+            .filter(|(pattern, _)| {
+                pattern.as_str() != crate_name && crate::util::wildcard_match(pattern, crate_name)
+            })
+            .map(|(_, crate_override)| crate_override)
+            .chain(self.crate_overrides.get(crate_name))
+            .peekable();
+        matching.peek()?;
+        let mut merged = CrateOverride::default();
+        for crate_override in matching {
+            merged.merge(crate_override);
+        }
+        Some(merged)
+    }
+
+    /// Returns the source override for `name@version` or, if there is none, for `name`.
+    pub fn source_override_for(
+        &self,
+        crate_name: &str,
+        version: &semver::Version,
+    ) -> Option<&SourceOverride> {
+        self.source_overrides
+            .get(&format!("{}@{}", crate_name, version))
+            .or_else(|| self.source_overrides.get(crate_name))
+    }
+
     /// Add or replace a source. Returns the old source if there was one.
     pub fn upsert_source(
         &mut self,
@@ -153,7 +445,7 @@ impl Source {
             Source::CratesIo { name, .. } => Some(name),
             Source::Git { url, .. } => {
                 let path = url.path();
-                let after_last_slash = path.split('/').last().unwrap_or(path);
+                let after_last_slash = path.split('/').next_back().unwrap_or(path);
                 let without_dot_git = after_last_slash
                     .strip_suffix(".git")
                     .unwrap_or(after_last_slash);
@@ -161,7 +453,7 @@ impl Source {
             }
             Source::Nix {
                 attr: Some(attr), ..
-            } => attr.split('.').last().or(if attr.trim().is_empty() {
+            } => attr.split('.').next_back().or(if attr.trim().is_empty() {
                 None
             } else {
                 Some(attr.trim())
@@ -211,3 +503,51 @@ impl Source {
         }
     }
 }
+
+#[test]
+fn test_crate_override_for() {
+    let config: Config = serde_json::from_str(
+        r#"{
+            "sources": {},
+            "crateOverrides": {
+                "aws-*": {
+                    "cfg": ["aws_sdk_unstable"],
+                    "capLints": "allow",
+                    "preBuild": "a",
+                    "storeManifestDir": true,
+                    "sandbox": { "requiredSystemFeatures": ["kvm"] }
+                },
+                "aws-sdk-s3": {
+                    "rustcFlags": ["-C opt-level=s"],
+                    "capLints": "warn",
+                    "preBuild": "b",
+                    "storeManifestDir": false,
+                    "sandbox": { "requiredSystemFeatures": ["big-parallel"], "noChroot": true }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let s3 = config.crate_override_for("aws-sdk-s3").unwrap();
+    assert_eq!(s3.cfg, vec!["aws_sdk_unstable".to_string()]);
+    assert_eq!(s3.rustc_flags, vec!["-C opt-level=s".to_string()]);
+    assert_eq!(s3.cap_lints.as_deref(), Some("warn"));
+    assert_eq!(s3.pre_build.as_deref(), Some("a\nb"));
+    assert_eq!(s3.store_manifest_dir, Some(false));
+    assert_eq!(
+        s3.sandbox.required_system_features,
+        vec!["kvm".to_string(), "big-parallel".to_string()]
+    );
+    assert_eq!(s3.sandbox.no_chroot, Some(true));
+
+    let sts = config.crate_override_for("aws-sdk-sts").unwrap();
+    assert_eq!(sts.cap_lints.as_deref(), Some("allow"));
+    assert!(sts.rustc_flags.is_empty());
+    assert_eq!(sts.pre_build.as_deref(), Some("a"));
+    assert_eq!(sts.post_build, None);
+    assert_eq!(sts.store_manifest_dir, Some(true));
+    assert_eq!(sts.sandbox.no_chroot, None);
+
+    assert_eq!(config.crate_override_for("serde"), None);
+}
@@ -0,0 +1,411 @@
+//! Reading and writing `crate-hashes.json`, the hashes of prefetched sources by package ID.
+//!
+//! The keys are the `stable_package_id`s of the packages, older files with cargo's package IDs
+//! are still read. The plain format maps them to nix base32 hashes. The detailed format also
+//! records the SRI form of the hash and the URL of the source, so that the file explains itself,
+//! and puts every entry on its own line, so that branches which add different crates merge
+//! without conflicts. Both formats are always read.
+//!
+//! With `split_crate_hashes`, the hashes are written to one file per registry next to the
+//! configured path, e.g. `crate-hashes.crates-io.json` and `crate-hashes.git.json`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, format_err, Error};
+use cargo_metadata::PackageId;
+use serde::{Deserialize, Serialize};
+
+use crate::resolve::ResolvedSource;
+use crate::GenerateConfig;
+
+/// The format in which `crate-hashes.json` is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CrateHashesFormat {
+    /// The nix base32 hash by package ID.
+    #[default]
+    Plain,
+    /// The nix base32 hash, its SRI form and the source URL by package ID, one per line.
+    Detailed,
+}
+
+impl FromStr for CrateHashesFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(CrateHashesFormat::Plain),
+            "detailed" => Ok(CrateHashesFormat::Detailed),
+            _ => bail!(
+                "unknown crate hashes format '{}', expected 'plain' or 'detailed'",
+                s
+            ),
+        }
+    }
+}
+
+/// An entry of `crate-hashes.json` in either format.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Entry {
+    Plain(String),
+    Detailed {
+        sha256: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sri: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+    },
+}
+
+impl Entry {
+    fn sha256(self) -> String {
+        match self {
+            Entry::Plain(sha256) | Entry::Detailed { sha256, .. } => sha256,
+        }
+    }
+
+    fn url(&self) -> Option<&String> {
+        match self {
+            Entry::Plain(_) => None,
+            Entry::Detailed { url, .. } => url.as_ref(),
+        }
+    }
+}
+
+/// Returns the existing hash files of `config`, in both the single and the split layout.
+pub fn files(config: &GenerateConfig) -> Vec<PathBuf> {
+    let path = &config.crate_hashes_json;
+    let mut files: Vec<PathBuf> = split_files(path).into_values().collect();
+    if path.is_file() {
+        files.insert(0, path.clone());
+    }
+    files
+}
+
+/// Reads the hashes of all files of `config`.
+pub fn read(config: &GenerateConfig) -> Result<BTreeMap<PackageId, String>, Error> {
+    Ok(read_entries(config)?
+        .into_iter()
+        .map(|(id, entry)| (id, entry.sha256()))
+        .collect())
+}
+
+fn read_entries(config: &GenerateConfig) -> Result<BTreeMap<PackageId, Entry>, Error> {
+    let mut entries = BTreeMap::new();
+    for file in files(config) {
+        let content = std::fs::read_to_string(&file)
+            .map_err(|e| format_err!("while reading {}: {}", file.display(), e))?;
+        let file_entries: BTreeMap<PackageId, Entry> = serde_json::from_str(&content)
+            .map_err(|e| format_err!("while parsing {}: {}", file.display(), e))?;
+        entries.extend(file_entries);
+    }
+    Ok(entries)
+}
+
+/// Writes `hashes` in the configured format and layout, removing the files of the other
+/// layout and of registries without hashes.
+///
+/// `sources` are the sources of the hashed packages, for the URLs and the split by registry.
+/// Hashes of packages without a source, e.g. of crates that are not in `Cargo.lock` anymore,
+/// keep the URL that the files have for them.
+pub fn write(
+    config: &GenerateConfig,
+    hashes: &BTreeMap<PackageId, String>,
+    sources: &BTreeMap<PackageId, &ResolvedSource>,
+) -> Result<(), Error> {
+    let path = &config.crate_hashes_json;
+    let previous_urls: BTreeMap<PackageId, String> =
+        if config.crate_hashes_format == CrateHashesFormat::Detailed {
+            read_entries(config)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(id, _)| !sources.contains_key(id))
+                .filter_map(|(id, entry)| entry.url().cloned().map(|url| (id, url)))
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+    let mut contents = BTreeMap::new();
+    if config.split_crate_hashes {
+        let mut by_registry: BTreeMap<String, BTreeMap<PackageId, String>> = BTreeMap::new();
+        for (id, sha256) in hashes {
+            let registry = sources
+                .get(id)
+                .map(|source| registry_name(source))
+                .unwrap_or_else(|| registry_name_of_id(id));
+            by_registry
+                .entry(registry)
+                .or_default()
+                .insert(id.clone(), sha256.clone());
+        }
+        for (registry, hashes) in by_registry {
+            let content = render(config.crate_hashes_format, &hashes, sources, &previous_urls)?;
+            contents.insert(split_file(path, &registry), content);
+        }
+    } else {
+        contents.insert(
+            path.clone(),
+            render(config.crate_hashes_format, hashes, sources, &previous_urls)?,
+        );
+    }
+
+    for stale in files(config)
+        .into_iter()
+        .filter(|f| !contents.contains_key(f))
+    {
+        std::fs::remove_file(&stale)
+            .map_err(|e| format_err!("while removing {}: {}", stale.display(), e))?;
+    }
+    for (file, content) in contents {
+        std::fs::write(&file, content)
+            .map_err(|e| format_err!("while writing hashes to {}: {}", file.display(), e))?;
+    }
+    Ok(())
+}
+
+fn render(
+    format: CrateHashesFormat,
+    hashes: &BTreeMap<PackageId, String>,
+    sources: &BTreeMap<PackageId, &ResolvedSource>,
+    previous_urls: &BTreeMap<PackageId, String>,
+) -> Result<Vec<u8>, Error> {
+    if format == CrateHashesFormat::Plain {
+        return Ok(serde_json::to_vec_pretty(hashes)?);
+    }
+    let mut lines = Vec::new();
+    for (id, sha256) in hashes {
+        let entry = Entry::Detailed {
+            sha256: sha256.clone(),
+            sri: sri(sha256),
+            url: sources
+                .get(id)
+                .map(|source| source.to_string())
+                .or_else(|| previous_urls.get(id).cloned()),
+        };
+        lines.push(format!(
+            "  {}: {}",
+            serde_json::to_string(&id.repr)?,
+            serde_json::to_string(&entry)?
+        ));
+    }
+    if lines.is_empty() {
+        return Ok(b"{}".to_vec());
+    }
+    Ok(format!("{{\n{}\n}}", lines.join(",\n")).into_bytes())
+}
+
+/// The name of the registry of a source in the split layout, e.g. `crates-io`, `git` or the
+/// host of another registry like `my.registry`.
+fn registry_name(source: &ResolvedSource) -> String {
+    match source {
+        ResolvedSource::CratesIo(_) => "crates-io".to_string(),
+        ResolvedSource::Git(_) => "git".to_string(),
+        ResolvedSource::Registry(registry) => {
+            let index = registry
+                .index
+                .trim_start_matches("sparse+")
+                .trim_start_matches("registry+");
+            url::Url::parse(index)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_else(|| "registry".to_string())
+        }
+        ResolvedSource::LocalDirectory(_) | ResolvedSource::Nix(_) => "other".to_string(),
+    }
+}
+
+/// The name of the registry of a package ID like `registry_name`, for packages without a
+/// resolved source.
+fn registry_name_of_id(id: &PackageId) -> String {
+    // Either `name version (source)` or cargo's `source#name@version`.
+    let source = match id.repr.rfind(" (") {
+        Some(start) if id.repr.ends_with(')') => &id.repr[start + 2..id.repr.len() - 1],
+        _ => id.repr.split('#').next().unwrap_or_default(),
+    };
+    if source.starts_with("git+") {
+        return "git".to_string();
+    }
+    let Some(index) = source
+        .strip_prefix("sparse+")
+        .or_else(|| source.strip_prefix("registry+"))
+    else {
+        return "other".to_string();
+    };
+    if index == "https://github.com/rust-lang/crates.io-index"
+        || index == "https://index.crates.io/"
+    {
+        return "crates-io".to_string();
+    }
+    url::Url::parse(index)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "registry".to_string())
+}
+
+/// `crate-hashes.<registry>.json` next to `crate-hashes.json`.
+fn split_file(path: &Path, registry: &str) -> PathBuf {
+    let (stem, extension) = stem_and_extension(path);
+    path.with_file_name(format!("{}.{}{}", stem, registry, extension))
+}
+
+/// The existing files of the split layout by registry.
+fn split_files(path: &Path) -> BTreeMap<String, PathBuf> {
+    let (stem, extension) = stem_and_extension(path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return BTreeMap::new();
+    };
+    let prefix = format!("{}.", stem);
+    entries
+        .filter_map(|entry| {
+            let file_name = entry.ok()?.file_name().into_string().ok()?;
+            let registry = file_name
+                .strip_prefix(&prefix)?
+                .strip_suffix(&extension)?
+                .to_string();
+            (!registry.is_empty()).then(|| (registry, path.with_file_name(&file_name)))
+        })
+        .collect()
+}
+
+fn stem_and_extension(path: &Path) -> (String, String) {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (stem, extension)
+}
+
+/// The SRI form of a nix base32 or hex sha256 hash, e.g. `sha256-47DEQpj8...`.
+fn sri(sha256: &str) -> Option<String> {
+    let bytes = match sha256.len() {
+        52 => from_nix_base32(sha256)?,
+        64 => hex::decode(sha256).ok()?,
+        _ => return None,
+    };
+    use base64::Engine as _;
+    Some(format!(
+        "sha256-{}",
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    ))
+}
+
+/// Decodes the nix base32 format of `nix_base32::to_nix_base32`.
+fn from_nix_base32(s: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    let mut bytes = vec![0u8; s.len() * 5 / 8];
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = ALPHABET.iter().position(|&a| a == c)? as u16;
+        let (i, j) = (n * 5 / 8, n * 5 % 8);
+        let shifted = digit << j;
+        bytes[i] |= shifted as u8;
+        let carry = (shifted >> 8) as u8;
+        match bytes.get_mut(i + 1) {
+            Some(byte) => *byte |= carry,
+            None if carry != 0 => return None,
+            None => {}
+        }
+    }
+    Some(bytes)
+}
+
+#[test]
+fn test_sri() {
+    // The sha256 of the empty string.
+    let bytes =
+        hex::decode("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855").unwrap();
+    let expected = Some("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".to_string());
+    assert_eq!(sri(&nix_base32::to_nix_base32(&bytes)), expected);
+    assert_eq!(sri(&hex::encode(&bytes)), expected);
+    assert_eq!(sri("not a hash"), None);
+}
+
+#[test]
+fn test_registry_name_of_id() {
+    let name = |repr: &str| {
+        registry_name_of_id(&PackageId {
+            repr: repr.to_string(),
+        })
+    };
+    assert_eq!(
+        name("nix-base32 0.1.2 (git+https://github.com/kolloch/nix-base32#42f5544)"),
+        "git"
+    );
+    assert_eq!(
+        name("serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)"),
+        "crates-io"
+    );
+    assert_eq!(
+        name("sparse+https://my.registry/index/#private@0.1.0"),
+        "my.registry"
+    );
+    assert_eq!(name("helper 0.1.0 (path+file:///src/helper)"), "other");
+}
+
+#[test]
+fn test_write_and_read() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    let git = ResolvedSource::Git(crate::resolve::GitSource {
+        url: "https://github.com/kolloch/nix-base32".parse().unwrap(),
+        rev: "42f5544e51187f0c7535d453fcffb4b524c99eb2".to_string(),
+        r#ref: None,
+        sha256: None,
+        fetch_submodules: false,
+    });
+    let id = PackageId {
+        repr: "git+https://github.com/kolloch/nix-base32#nix-base32@0.1.2".to_string(),
+    };
+    let sha256 = nix_base32::to_nix_base32(&[0; 32]);
+    let hashes = BTreeMap::from([(id.clone(), sha256.clone())]);
+    let sources = BTreeMap::from([(id.clone(), &git)]);
+    let mut config = GenerateConfig {
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        crate_hashes_format: CrateHashesFormat::Detailed,
+        ..crate::test::generate_config()
+    };
+
+    write(&config, &hashes, &sources).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.join("crate-hashes.json")).unwrap(),
+        format!(
+            "{{\n  \"{}\": {{\"sha256\":\"{}\",\"sri\":\"sha256-{}\",\"url\":\"{}\"}}\n}}",
+            id.repr,
+            sha256,
+            "A".repeat(43) + "=",
+            git
+        )
+    );
+    assert_eq!(read(&config).unwrap(), hashes);
+
+    config.split_crate_hashes = true;
+    write(&config, &hashes, &sources).unwrap();
+    assert!(!dir.join("crate-hashes.json").exists());
+    assert!(dir.join("crate-hashes.git.json").exists());
+    assert_eq!(read(&config).unwrap(), hashes);
+
+    // Hashes without a source stay in their registry and keep their URL.
+    let no_sources = BTreeMap::new();
+    write(&config, &hashes, &no_sources).unwrap();
+    assert!(dir.join("crate-hashes.git.json").exists());
+    assert!(std::fs::read_to_string(dir.join("crate-hashes.git.json"))
+        .unwrap()
+        .contains(&git.to_string()));
+
+    config.split_crate_hashes = false;
+    config.crate_hashes_format = CrateHashesFormat::Plain;
+    write(&config, &hashes, &sources).unwrap();
+    assert!(!dir.join("crate-hashes.git.json").exists());
+    assert_eq!(read(&config).unwrap(), hashes);
+
+    env.close();
+}
@@ -0,0 +1,357 @@
+//! Writing the crates to a JSON file that `Cargo.nix` reads, see `--crates-json`.
+//!
+//! The attributes of a crate have the same names as in `Cargo.nix`. Sources become
+//! `{ "fetchurl": ... }` or `{ "fetchgit": ... }` and the targets of dependencies cfg trees like
+//! `{ "all": [ { "name": "unix" }, { "key": "target_os", "value": "linux" } ] }`, which
+//! `internal.cratesFromJSON` turns back into nix.
+//!
+//! Crates that need nix expressions, e.g. local sources or the overrides of `crate2nix.json`,
+//! are still defined in `Cargo.nix`, which takes precedence. The file contains them as well, so
+//! that other tools find every crate in it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use cargo_platform::{Cfg, CfgExpr, Platform};
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+
+use crate::resolve::{CrateDerivation, ResolvedDependency, ResolvedSource};
+use crate::{BuildInfo, GenerateConfig};
+
+/// Whether `Cargo.nix` has to define the crate because it needs nix expressions.
+pub fn needs_nix(crate_derivation: &CrateDerivation) -> bool {
+    crate_derivation.problem.is_some()
+        || crate_derivation.imported.is_some()
+        || crate_derivation.shared_source.is_some()
+        || crate_derivation.crate_override.is_some()
+        || crate_derivation
+            .build_script_config
+            .as_ref()
+            .is_some_and(|config| !config.env.is_empty() || config.pre_built.is_some())
+        || source_json(&crate_derivation.source).is_none()
+}
+
+/// The path of the JSON file, see `GenerateConfig::crates_json_file`.
+pub fn path(build_info: &BuildInfo) -> PathBuf {
+    path_for(&build_info.config)
+}
+
+fn path_for(config: &GenerateConfig) -> PathBuf {
+    let output_dir = match config.output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    output_dir.join(config.crates_json_file())
+}
+
+/// Removes the JSON file of an earlier run with `--crates-json`.
+///
+/// A file at the same path that does not look like one written by `write` is kept.
+pub fn remove_stale(config: &GenerateConfig) -> Result<(), Error> {
+    let path = path_for(config);
+    let Ok(content) = std::fs::read_to_string(&path) else {
+        return Ok(());
+    };
+    let written_by_us = serde_json::from_str::<Map<String, Value>>(&content)
+        .is_ok_and(|crates| crates.values().all(|c| c.get("crateName").is_some()));
+    if !written_by_us {
+        warn!(
+            "Keeping {}, it was not written by --crates-json.",
+            path.to_string_lossy()
+        );
+        return Ok(());
+    }
+    std::fs::remove_file(&path)
+        .with_context(|| format!("while removing {}", path.to_string_lossy()))?;
+    info!(
+        "Removed {} of an earlier run with --crates-json.",
+        path.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Writes all crates of `build_info` by package ID.
+pub fn write(build_info: &BuildInfo) -> Result<(), Error> {
+    let crates: Map<String, Value> = build_info
+        .crates
+        .iter()
+        .map(|c| (c.package_id.repr.clone(), crate_json(c)))
+        .collect();
+    let path = path(build_info);
+    let mut content = serde_json::to_string_pretty(&crates)?;
+    content.push('\n');
+    std::fs::write(&path, content)
+        .with_context(|| format!("while writing {}", path.to_string_lossy()))?;
+    info!("Generated {} successfully.", path.to_string_lossy());
+    Ok(())
+}
+
+/// The attributes of the crate in `Cargo.nix`, leaving out the defaults like `crate.tera`.
+fn crate_json(crate_derivation: &CrateDerivation) -> Value {
+    let mut attrs = Map::new();
+    attrs.insert("crateName".into(), json!(crate_derivation.crate_name));
+    attrs.insert(
+        "version".into(),
+        json!(crate_derivation.version.to_string()),
+    );
+    attrs.insert("edition".into(), json!(crate_derivation.edition));
+    if let Some(links) = &crate_derivation.links {
+        attrs.insert("links".into(), json!(links));
+    }
+    if let Some(rust_version) = &crate_derivation.rust_version {
+        attrs.insert("rustVersion".into(), json!(rust_version));
+    }
+    if !crate_derivation.binaries.is_empty() {
+        let build_binaries =
+            crate_derivation.is_root_or_workspace_member || crate_derivation.is_artifact_dependency;
+        let mut binaries: Vec<_> = crate_derivation.binaries.iter().collect();
+        binaries.sort_by(|a, b| a.name.cmp(&b.name));
+        let binaries: Vec<Value> = binaries
+            .into_iter()
+            .filter(|_| build_binaries)
+            .map(|bin| {
+                json!({
+                    "name": bin.name,
+                    "path": bin.src_path,
+                    "requiredFeatures": bin.required_features,
+                })
+            })
+            .collect();
+        attrs.insert("crateBin".into(), json!(binaries));
+    }
+    match (
+        &crate_derivation.source,
+        source_json(&crate_derivation.source),
+    ) {
+        (ResolvedSource::CratesIo(source), _) => {
+            attrs.insert("sha256".into(), json!(source.sha256));
+        }
+        (_, Some(src)) => {
+            attrs.insert("src".into(), src);
+        }
+        (_, None) => {}
+    }
+    attrs.insert(
+        "provenance".into(),
+        json!(crate_derivation.source.provenance()),
+    );
+    if matches!(crate_derivation.source, ResolvedSource::Git(_)) {
+        attrs.insert("workspace_member".into(), Value::Null);
+    }
+    if crate_derivation.proc_macro {
+        attrs.insert("procMacro".into(), json!(true));
+    }
+    if let Some(build) = &crate_derivation.build {
+        if build.src_path != Path::new("build.rs") {
+            attrs.insert("build".into(), json!(build.src_path));
+        }
+    }
+    if crate_derivation.skip_tests {
+        attrs.insert("skipTests".into(), json!(true));
+    }
+    if let Some(config) = &crate_derivation.build_script_config {
+        if config.export_out_dir {
+            attrs.insert("exportOutDir".into(), json!(true));
+        }
+    }
+    let profile_flags = &crate_derivation.profile_flags;
+    if !profile_flags.release.is_empty() || !profile_flags.dev.is_empty() {
+        let mut flags = Map::new();
+        for (profile, profile_flags) in [
+            ("release", &profile_flags.release),
+            ("dev", &profile_flags.dev),
+        ] {
+            if !profile_flags.is_empty() {
+                flags.insert(profile.into(), json!(profile_flags));
+            }
+        }
+        attrs.insert("profileRustcOpts".into(), Value::Object(flags));
+    }
+    if let Some(lib) = &crate_derivation.lib {
+        if *lib.name != *crate_derivation.crate_name {
+            attrs.insert("libName".into(), json!(lib.name));
+        }
+        if lib.src_path != Path::new("src/lib.rs") {
+            attrs.insert("libPath".into(), json!(lib.src_path));
+        }
+    }
+    let crate_types = &crate_derivation.lib_crate_types;
+    if !crate_derivation.proc_macro
+        && !crate_types.is_empty()
+        && (crate_types.len() != 1 || crate_types[0] != "lib")
+    {
+        attrs.insert("type".into(), json!(crate_types));
+    }
+    if !crate_derivation.authors.is_empty() {
+        attrs.insert("authors".into(), json!(crate_derivation.authors));
+    }
+    for (name, dependencies) in [
+        ("dependencies", &crate_derivation.dependencies),
+        ("buildDependencies", &crate_derivation.build_dependencies),
+        ("devDependencies", &crate_derivation.dev_dependencies),
+    ] {
+        if !dependencies.is_empty() {
+            let dependencies: Vec<Value> = dependencies.iter().map(dependency_json).collect();
+            attrs.insert(name.into(), json!(dependencies));
+        }
+    }
+    let features: Map<String, Value> = crate_derivation
+        .features
+        .iter()
+        .filter(|(_, features)| !features.is_empty())
+        .map(|(feature, features)| (feature.to_string(), json!(features)))
+        .collect();
+    if !crate_derivation.features.is_empty() {
+        attrs.insert("features".into(), Value::Object(features));
+    }
+    if !crate_derivation.resolved_default_features.is_empty() {
+        attrs.insert(
+            "resolvedDefaultFeatures".into(),
+            json!(crate_derivation.resolved_default_features),
+        );
+    }
+    Value::Object(attrs)
+}
+
+/// The source as data, `None` if it needs a nix expression. crates.io crates only need their
+/// `sha256`.
+fn source_json(source: &ResolvedSource) -> Option<Value> {
+    match source {
+        ResolvedSource::CratesIo(source) => source.sha256.as_ref().map(|_| Value::Null),
+        ResolvedSource::Registry(source) => source
+            .sha256
+            .as_ref()
+            .map(|sha256| json!({ "fetchurl": { "url": source.url, "sha256": sha256 } })),
+        ResolvedSource::Git(source) => {
+            let mut fetchgit = json!({
+                "url": source.url.as_str(),
+                "rev": source.rev,
+                "fetchSubmodules": source.fetch_submodules,
+            });
+            if let Some(sha256) = &source.sha256 {
+                fetchgit["sha256"] = json!(sha256);
+            }
+            Some(json!({ "fetchgit": fetchgit }))
+        }
+        ResolvedSource::LocalDirectory(_) | ResolvedSource::Nix(_) => None,
+    }
+}
+
+fn dependency_json(dependency: &ResolvedDependency) -> Value {
+    let mut attrs = Map::new();
+    attrs.insert("name".into(), json!(dependency.name));
+    attrs.insert("packageId".into(), json!(dependency.package_id.repr));
+    if let Some(rename) = &dependency.rename {
+        attrs.insert("rename".into(), json!(rename));
+    }
+    if dependency.optional {
+        attrs.insert("optional".into(), json!(true));
+    }
+    if !dependency.uses_default_features {
+        attrs.insert("usesDefaultFeatures".into(), json!(false));
+    }
+    if let Some(target) = &dependency.target {
+        attrs.insert("target".into(), platform_json(target));
+    }
+    if !dependency.features.is_empty() {
+        attrs.insert("features".into(), json!(dependency.features));
+    }
+    if let Some(artifact) = &dependency.artifact {
+        let mut artifact_attrs = Map::new();
+        artifact_attrs.insert("kinds".into(), json!(artifact.kinds));
+        if let Some(target) = &artifact.target {
+            artifact_attrs.insert("target".into(), json!(target));
+        }
+        if artifact.lib {
+            artifact_attrs.insert("lib".into(), json!(true));
+        }
+        attrs.insert("artifact".into(), Value::Object(artifact_attrs));
+    }
+    Value::Object(attrs)
+}
+
+/// The target of a dependency as cfg tree, a target triple as `{ "rustcTarget": ... }`.
+fn platform_json(platform: &Platform) -> Value {
+    match platform {
+        Platform::Name(triple) => json!({ "rustcTarget": triple }),
+        Platform::Cfg(expr) => cfg_json(expr),
+    }
+}
+
+fn cfg_json(expr: &CfgExpr) -> Value {
+    match expr {
+        CfgExpr::Value(Cfg::Name(name)) => json!({ "name": name }),
+        CfgExpr::Value(Cfg::KeyPair(key, value)) => json!({ "key": key, "value": value }),
+        CfgExpr::Not(expr) => json!({ "not": cfg_json(expr) }),
+        CfgExpr::All(exprs) => json!({ "all": exprs.iter().map(cfg_json).collect::<Vec<_>>() }),
+        CfgExpr::Any(exprs) => json!({ "any": exprs.iter().map(cfg_json).collect::<Vec<_>>() }),
+    }
+}
+
+#[test]
+fn test_platform_json() {
+    assert_eq!(
+        platform_json(
+            &"cfg(all(unix, not(target_os = \"macos\")))"
+                .parse()
+                .unwrap()
+        ),
+        json!({ "all": [ { "name": "unix" }, { "not": { "key": "target_os", "value": "macos" } } ] })
+    );
+    assert_eq!(
+        platform_json(&"x86_64-unknown-linux-gnu".parse().unwrap()),
+        json!({ "rustcTarget": "x86_64-unknown-linux-gnu" })
+    );
+}
+
+#[test]
+fn test_crate_json() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    let indexed = env.indexed_metadata();
+    let package = indexed.pkgs_by_id.values().next().unwrap();
+    let mut crate_derivation = CrateDerivation::unresolved(&indexed, package, String::new());
+    assert!(needs_nix(&crate_derivation), "unresolved");
+    crate_derivation.problem = None;
+    assert!(needs_nix(&crate_derivation), "no sha256");
+
+    crate_derivation.source = serde_json::from_value(json!({
+        "CratesIo": { "name": "main", "version": "0.1.0", "sha256": "abc" }
+    }))
+    .unwrap();
+    assert!(!needs_nix(&crate_derivation));
+    let attrs = crate_json(&crate_derivation);
+    assert_eq!(attrs["crateName"], json!("main"));
+    assert_eq!(attrs["sha256"], json!("abc"));
+    assert!(attrs.get("src").is_none());
+    assert_eq!(
+        attrs["provenance"],
+        json!({ "kind": "crates-io", "url": "https://static.crates.io/crates/main/main-0.1.0.crate" })
+    );
+
+    crate_derivation.crate_override = Some(Default::default());
+    assert!(needs_nix(&crate_derivation));
+    env.close();
+}
+
+#[test]
+fn test_remove_stale() {
+    let dir = tempdir::TempDir::new("crate2nix_crates_json").expect("temp dir");
+    let config = GenerateConfig {
+        output: dir.path().join("Cargo.nix"),
+        ..crate::test::generate_config()
+    };
+    let path = dir.path().join("Cargo.crates.json");
+    remove_stale(&config).unwrap();
+
+    std::fs::write(&path, r#"{ "foo 1.0.0": { "crateName": "foo" } }"#).unwrap();
+    remove_stale(&config).unwrap();
+    assert!(!path.exists());
+
+    std::fs::write(&path, r#"{ "mine": true }"#).unwrap();
+    remove_stale(&config).unwrap();
+    assert!(path.exists());
+
+    dir.close().unwrap();
+}
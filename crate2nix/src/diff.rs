@@ -0,0 +1,607 @@
+//! Semantic differences between two generated build files, e.g. for `crate2nix diff`.
+//!
+//! Regenerating `Cargo.nix` after a `cargo update` often touches thousands of lines.
+//! Comparing the crates instead of the text tells reviewers what actually changed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{bail, format_err, Context, Error};
+use rnix::ast::{self, HasEntry};
+use serde::Deserialize;
+
+/// The parts of a crate in `Cargo.nix` that are relevant for reviews.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrateSummary {
+    /// The crate name.
+    pub crate_name: String,
+    /// The crate version.
+    pub version: String,
+    /// The hash of the crate source, if it is not a local crate.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// The features enabled during resolution.
+    #[serde(default)]
+    pub resolved_default_features: BTreeSet<String>,
+}
+
+/// Reads the crates by package ID from a `Cargo.nix` file or a JSON file.
+///
+/// JSON files must contain an object from package ID to crates, with the attribute names as in
+/// `Cargo.nix`, e.g. `{ "serde": { "crateName": "serde", "version": "1.0.0" } }`, like the
+/// files of `--crates-json`.
+pub fn read_crates(path: &Path) -> Result<BTreeMap<String, CrateSummary>, Error> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("while reading {}", path.to_string_lossy()))?;
+    let crates = if path.extension().map(|e| e == "json").unwrap_or(false) {
+        crates_from_json(&content)
+    } else {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        crates_from_cargo_nix(&content, dir)
+    };
+    crates.with_context(|| format!("while parsing {}", path.to_string_lossy()))
+}
+
+/// Extracts the crates from a JSON file, see `read_crates`.
+///
+/// The hashes of crates that are not from crates.io are read from their sources.
+pub fn crates_from_json(content: &str) -> Result<BTreeMap<String, CrateSummary>, Error> {
+    let mut crates: BTreeMap<String, serde_json::Value> = serde_json::from_str(content)?;
+    for crate_json in crates.values_mut() {
+        let Some(attrs) = crate_json.as_object_mut() else {
+            continue;
+        };
+        let source_sha256 = ["fetchurl", "fetchgit"]
+            .iter()
+            .find_map(|fetcher| attrs.get("src")?.get(fetcher)?.get("sha256"))
+            .cloned();
+        if let (false, Some(sha256)) = (attrs.contains_key("sha256"), source_sha256) {
+            attrs.insert("sha256".to_string(), sha256);
+        }
+    }
+    crates
+        .into_iter()
+        .map(|(package_id, crate_json)| {
+            let summary = serde_json::from_value(crate_json)
+                .with_context(|| format!("while reading crate {}", package_id))?;
+            Ok((package_id, summary))
+        })
+        .collect()
+}
+
+/// The attributes of a crate that `CrateSummary` reads.
+const SUMMARY_ATTRIBUTES: &[&str] = &["crateName", "version", "sha256", "resolvedDefaultFeatures"];
+
+/// Extracts the crates from the `crates` attribute set of a generated `Cargo.nix`.
+///
+/// References to `sharedLists`, see `--share-lists`, are replaced by the lists. The crates of a
+/// JSON file that `crates` imports, see `--crates-json`, are read relative to `dir`.
+pub fn crates_from_cargo_nix(
+    content: &str,
+    dir: &Path,
+) -> Result<BTreeMap<String, CrateSummary>, Error> {
+    let root = rnix::Root::parse(content)
+        .ok()
+        .map_err(|e| format_err!("invalid nix: {}", e))?;
+    let Some((crates_json, crates)) = root
+        .expr()
+        .and_then(|expr| find_attr(expr, "crates", &crates_value))
+    else {
+        bail!("no crates attribute set found, sharded output (--shard) is not supported")
+    };
+    let shared_lists = match root
+        .expr()
+        .and_then(|expr| find_attr(expr, "sharedLists", &attr_set))
+    {
+        Some(lists) => shared_lists(&lists)?,
+        None => BTreeMap::new(),
+    };
+
+    // The crates in `Cargo.nix` take precedence over the ones in the JSON file.
+    let mut summaries = match crates_json {
+        Some(file) => read_crates(&dir.join(file))?,
+        None => BTreeMap::new(),
+    };
+    for entry in crates.attrpath_values() {
+        let package_id = entry
+            .attrpath()
+            .and_then(|p| p.attrs().next())
+            .and_then(|attr| attr_name(&attr))
+            .ok_or_else(|| format_err!("unexpected crate attribute {}", entry))?;
+        let Some(ast::Expr::AttrSet(attrs)) = entry.value().map(own_attrs) else {
+            bail!("{} is not an attribute set", package_id);
+        };
+        let mut values = BTreeMap::new();
+        for attr in attrs.attrpath_values() {
+            let (Some(name), Some(value)) = (attr.attrpath().map(|p| p.to_string()), attr.value())
+            else {
+                continue;
+            };
+            if !SUMMARY_ATTRIBUTES.contains(&name.as_str()) {
+                continue;
+            }
+            let Some(json) = to_json(&value, &shared_lists) else {
+                bail!(
+                    "{} of crate {} is not a literal but {}",
+                    name,
+                    package_id,
+                    value
+                );
+            };
+            values.insert(name, json);
+        }
+        let summary =
+            serde_json::from_value(serde_json::Value::Object(values.into_iter().collect()))
+                .with_context(|| format!("while reading crate {}", package_id))?;
+        summaries.insert(package_id, summary);
+    }
+    Ok(summaries)
+}
+
+/// The lists of `sharedLists = { s0 = [ ... ]; ... }` by their references, e.g. `sharedLists.s0`.
+fn shared_lists(lists: &ast::AttrSet) -> Result<BTreeMap<String, serde_json::Value>, Error> {
+    let mut shared_lists = BTreeMap::new();
+    for entry in lists.attrpath_values() {
+        let (Some(name), Some(value)) = (entry.attrpath().map(|p| p.to_string()), entry.value())
+        else {
+            continue;
+        };
+        let list = to_json(&value, &BTreeMap::new())
+            .ok_or_else(|| format_err!("shared list {} is not a literal but {}", name, value))?;
+        shared_lists.insert(format!("sharedLists.{}", name), list);
+    }
+    Ok(shared_lists)
+}
+
+/// The attributes of `imported // rec { ... }` that are defined in this file, see `imports`.
+fn own_attrs(expr: ast::Expr) -> ast::Expr {
+    match expr {
+        ast::Expr::BinOp(bin_op) if bin_op.operator() == Some(ast::BinOpKind::Update) => bin_op
+            .rhs()
+            .map(own_attrs)
+            .unwrap_or(ast::Expr::BinOp(bin_op)),
+        expr => expr,
+    }
+}
+
+/// Finds the first attribute `name` whose value is accepted by `accept`, looking through
+/// functions, lets and attribute sets.
+fn find_attr<T>(expr: ast::Expr, name: &str, accept: &dyn Fn(ast::Expr) -> Option<T>) -> Option<T> {
+    let find = |expr| find_attr(expr, name, accept);
+    match expr {
+        ast::Expr::Lambda(lambda) => lambda.body().and_then(find),
+        ast::Expr::LetIn(let_in) => let_in
+            .attrpath_values()
+            .filter_map(|entry| entry.value())
+            .chain(let_in.body())
+            .find_map(find),
+        ast::Expr::With(with) => with.body().and_then(find),
+        ast::Expr::Paren(paren) => paren.expr().and_then(find),
+        ast::Expr::AttrSet(attr_set) => attr_set.attrpath_values().find_map(|entry| {
+            let value = entry.value()?;
+            if entry.attrpath().map(|p| p.to_string()).as_deref() == Some(name) {
+                if let Some(accepted) = accept(value.clone()) {
+                    return Some(accepted);
+                }
+            }
+            find(value)
+        }),
+        _ => None,
+    }
+}
+
+fn attr_set(expr: ast::Expr) -> Option<ast::AttrSet> {
+    match expr {
+        ast::Expr::AttrSet(attr_set) => Some(attr_set),
+        _ => None,
+    }
+}
+
+/// `{ ... }` or `cratesFromJSON (lib.importJSON ./Cargo.crates.json) // { ... }` with the path
+/// of the JSON file, see `--crates-json`.
+fn crates_value(expr: ast::Expr) -> Option<(Option<String>, ast::AttrSet)> {
+    let ast::Expr::BinOp(bin_op) = expr else {
+        return attr_set(expr).map(|crates| (None, crates));
+    };
+    if bin_op.operator() != Some(ast::BinOpKind::Update) {
+        return None;
+    }
+    let ast::Expr::Apply(from_json) = bin_op.lhs()? else {
+        return None;
+    };
+    if from_json.lambda()?.to_string() != "cratesFromJSON" {
+        return None;
+    }
+    let ast::Expr::Apply(import_json) = strip_parens(from_json.argument()?) else {
+        return None;
+    };
+    if import_json.lambda()?.to_string() != "lib.importJSON" {
+        return None;
+    }
+    let ast::Expr::Path(path) = import_json.argument()? else {
+        return None;
+    };
+    Some((Some(path.to_string()), attr_set(bin_op.rhs()?)?))
+}
+
+fn strip_parens(expr: ast::Expr) -> ast::Expr {
+    match expr {
+        ast::Expr::Paren(paren) => paren
+            .expr()
+            .map(strip_parens)
+            .unwrap_or(ast::Expr::Paren(paren)),
+        expr => expr,
+    }
+}
+
+fn attr_name(attr: &ast::Attr) -> Option<String> {
+    match attr {
+        ast::Attr::Ident(ident) => Some(ident.to_string()),
+        ast::Attr::Str(s) => literal_string(s),
+        ast::Attr::Dynamic(_) => None,
+    }
+}
+
+fn literal_string(s: &ast::Str) -> Option<String> {
+    s.normalized_parts()
+        .into_iter()
+        .map(|part| match part {
+            ast::InterpolPart::Literal(literal) => Some(literal),
+            ast::InterpolPart::Interpolation(_) => None,
+        })
+        .collect()
+}
+
+/// Converts string literals, lists of them and references to `shared_lists`.
+fn to_json(
+    expr: &ast::Expr,
+    shared_lists: &BTreeMap<String, serde_json::Value>,
+) -> Option<serde_json::Value> {
+    match expr {
+        ast::Expr::Str(s) => literal_string(s).map(serde_json::Value::String),
+        ast::Expr::List(list) => list
+            .items()
+            .map(|item| to_json(&item, shared_lists))
+            .collect::<Option<_>>()
+            .map(serde_json::Value::Array),
+        ast::Expr::Select(select) => shared_lists.get(&select.to_string()).cloned(),
+        _ => None,
+    }
+}
+
+/// The differences between two sets of crates.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CratesDiff {
+    /// Crates only in the new file, as (name, version).
+    pub added: Vec<(String, String)>,
+    /// Crates only in the old file, as (name, version).
+    pub removed: Vec<(String, String)>,
+    /// Crates with a single version in both files that changed, as (name, old, new).
+    pub changed_versions: Vec<(String, String, String)>,
+    /// Crates with the same version whose resolved features changed,
+    /// as (name, version, removed, added).
+    pub changed_features: Vec<(String, String, Vec<String>, Vec<String>)>,
+    /// Crates with the same version whose source hash changed, as (name, version).
+    pub changed_hashes: Vec<(String, String)>,
+}
+
+impl CratesDiff {
+    /// Compares crates by name and version, package IDs are ignored since they vary with
+    /// the number of versions of a crate.
+    pub fn new(
+        old: &BTreeMap<String, CrateSummary>,
+        new: &BTreeMap<String, CrateSummary>,
+    ) -> CratesDiff {
+        fn by_name(
+            crates: &BTreeMap<String, CrateSummary>,
+        ) -> BTreeMap<&str, BTreeMap<&str, &CrateSummary>> {
+            let mut by_name: BTreeMap<&str, BTreeMap<&str, &CrateSummary>> = BTreeMap::new();
+            for summary in crates.values() {
+                by_name
+                    .entry(&summary.crate_name)
+                    .or_default()
+                    .insert(&summary.version, summary);
+            }
+            by_name
+        }
+
+        let old = by_name(old);
+        let new = by_name(new);
+        let empty = BTreeMap::new();
+        let names: BTreeSet<&str> = old.keys().chain(new.keys()).copied().collect();
+
+        let mut diff = CratesDiff::default();
+        for name in names {
+            let old_versions = old.get(name).unwrap_or(&empty);
+            let new_versions = new.get(name).unwrap_or(&empty);
+            let only_old: Vec<&str> = old_versions
+                .keys()
+                .filter(|v| !new_versions.contains_key(*v))
+                .copied()
+                .collect();
+            let only_new: Vec<&str> = new_versions
+                .keys()
+                .filter(|v| !old_versions.contains_key(*v))
+                .copied()
+                .collect();
+
+            if let ([old_version], [new_version]) = (only_old.as_slice(), only_new.as_slice()) {
+                diff.changed_versions.push((
+                    name.to_string(),
+                    old_version.to_string(),
+                    new_version.to_string(),
+                ));
+            } else {
+                diff.removed
+                    .extend(only_old.iter().map(|v| (name.to_string(), v.to_string())));
+                diff.added
+                    .extend(only_new.iter().map(|v| (name.to_string(), v.to_string())));
+            }
+
+            for (version, old_crate) in old_versions {
+                let Some(new_crate) = new_versions.get(version) else {
+                    continue;
+                };
+                let removed_features: Vec<String> = old_crate
+                    .resolved_default_features
+                    .difference(&new_crate.resolved_default_features)
+                    .cloned()
+                    .collect();
+                let added_features: Vec<String> = new_crate
+                    .resolved_default_features
+                    .difference(&old_crate.resolved_default_features)
+                    .cloned()
+                    .collect();
+                if !removed_features.is_empty() || !added_features.is_empty() {
+                    diff.changed_features.push((
+                        name.to_string(),
+                        version.to_string(),
+                        removed_features,
+                        added_features,
+                    ));
+                }
+                if old_crate.sha256 != new_crate.sha256 {
+                    diff.changed_hashes
+                        .push((name.to_string(), version.to_string()));
+                }
+            }
+        }
+        diff
+    }
+
+    /// Returns true if there are no differences.
+    pub fn is_empty(&self) -> bool {
+        *self == CratesDiff::default()
+    }
+
+    /// Renders the differences for humans.
+    pub fn render(&self) -> String {
+        if self.is_empty() {
+            return "No crates changed.\n".to_string();
+        }
+
+        let mut report = String::new();
+        for (name, version) in &self.added {
+            writeln!(report, "+ {} {}", name, version).unwrap();
+        }
+        for (name, version) in &self.removed {
+            writeln!(report, "- {} {}", name, version).unwrap();
+        }
+        for (name, old, new) in &self.changed_versions {
+            writeln!(report, "~ {} {} -> {}", name, old, new).unwrap();
+        }
+        for (name, version, removed, added) in &self.changed_features {
+            let changes: Vec<String> = removed
+                .iter()
+                .map(|f| format!("-{}", f))
+                .chain(added.iter().map(|f| format!("+{}", f)))
+                .collect();
+            writeln!(
+                report,
+                "~ {} {} features: {}",
+                name,
+                version,
+                changes.join(" ")
+            )
+            .unwrap();
+        }
+        for (name, version) in &self.changed_hashes {
+            writeln!(report, "! {} {} hash changed", name, version).unwrap();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+const OLD_CARGO_NIX: &str = r#"
+rec {
+  internal = rec {
+    crates = {
+      "libc" = rec {
+        crateName = "libc";
+        version = "0.2.1";
+        sha256 = "aaaa";
+        resolvedDefaultFeatures = [ "default" "std" ];
+      };
+      "log" = rec {
+        crateName = "log";
+        version = "0.4.0";
+        sha256 = "bbbb";
+      };
+      "main" = rec {
+        crateName = "main";
+        version = "0.1.0";
+        src = lib.cleanSourceWith { filter = sourceFilter; src = ./.; };
+      };
+    };
+  };
+}
+"#;
+
+#[test]
+fn test_crates_from_cargo_nix() {
+    let crates = crates_from_cargo_nix(OLD_CARGO_NIX, Path::new(".")).unwrap();
+    assert_eq!(crates.len(), 3);
+    assert_eq!(
+        crates["libc"],
+        CrateSummary {
+            crate_name: "libc".to_string(),
+            version: "0.2.1".to_string(),
+            sha256: Some("aaaa".to_string()),
+            resolved_default_features: ["default", "std"].iter().map(|f| f.to_string()).collect(),
+        }
+    );
+    assert_eq!(crates["main"].sha256, None);
+
+    let imported = crates_from_cargo_nix(
+        &OLD_CARGO_NIX.replace(
+            r#""log" = rec {"#,
+            r#""log" = importedCrates."../log"."log" // rec {"#,
+        ),
+        Path::new("."),
+    )
+    .unwrap();
+    assert_eq!(imported, crates);
+}
+
+#[test]
+fn test_crates_diff() {
+    let old = crates_from_cargo_nix(OLD_CARGO_NIX, Path::new(".")).unwrap();
+    let new = crates_from_cargo_nix(
+        &OLD_CARGO_NIX
+            .replace(r#""default" "std""#, r#""default" "extra""#)
+            .replace(r#"version = "0.4.0""#, r#"version = "0.4.1""#)
+            .replace(
+                r#""main" = rec {"#,
+                r#""serde" = rec { crateName = "serde"; version = "1.0.0"; }; "main" = rec {"#,
+            )
+            .replace("aaaa", "cccc"),
+        Path::new("."),
+    )
+    .unwrap();
+
+    let diff = CratesDiff::new(&old, &new);
+    assert_eq!(
+        diff,
+        CratesDiff {
+            added: vec![("serde".to_string(), "1.0.0".to_string())],
+            removed: vec![],
+            changed_versions: vec![("log".to_string(), "0.4.0".to_string(), "0.4.1".to_string())],
+            changed_features: vec![(
+                "libc".to_string(),
+                "0.2.1".to_string(),
+                vec!["std".to_string()],
+                vec!["extra".to_string()]
+            )],
+            changed_hashes: vec![("libc".to_string(), "0.2.1".to_string())],
+        }
+    );
+    assert!(CratesDiff::new(&old, &old).is_empty());
+}
+
+#[test]
+fn test_crates_from_cargo_nix_with_shared_lists() {
+    let shared = |features: &str| {
+        OLD_CARGO_NIX
+            .replace(
+                "crates = {",
+                &format!(
+                    "sharedLists = {{ s0 = [ \"The Authors\" ]; s1 = [ {} ]; }};\n    crates = {{",
+                    features
+                ),
+            )
+            .replace(
+                r#"resolvedDefaultFeatures = [ "default" "std" ];"#,
+                "authors = sharedLists.s0; resolvedDefaultFeatures = sharedLists.s1;",
+            )
+    };
+    let old = crates_from_cargo_nix(&shared(r#""default" "std""#), Path::new(".")).unwrap();
+    assert_eq!(
+        old,
+        crates_from_cargo_nix(OLD_CARGO_NIX, Path::new(".")).unwrap()
+    );
+    let new = crates_from_cargo_nix(&shared(r#""default" "extra""#), Path::new(".")).unwrap();
+    assert_eq!(
+        CratesDiff::new(&old, &new).changed_features,
+        vec![(
+            "libc".to_string(),
+            "0.2.1".to_string(),
+            vec!["std".to_string()],
+            vec!["extra".to_string()]
+        )]
+    );
+
+    let error = crates_from_cargo_nix(
+        &OLD_CARGO_NIX.replace(
+            r#"version = "0.4.0";"#,
+            r#"version = lib.versions.major "0.4.0";"#,
+        ),
+        Path::new("."),
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        r#"version of crate log is not a literal but lib.versions.major "0.4.0""#
+    );
+}
+
+#[test]
+fn test_crates_from_cargo_nix_with_crates_json() {
+    let dir = tempdir::TempDir::new("crate2nix_diff").expect("temp dir");
+    let cargo_nix = |sha256: &str| {
+        std::fs::write(
+            dir.path().join("Cargo.crates.json"),
+            serde_json::json!({
+                "libc": {
+                    "crateName": "libc",
+                    "version": "0.2.1",
+                    "sha256": sha256,
+                    "resolvedDefaultFeatures": ["default", "std"],
+                },
+                "log": {
+                    "crateName": "log",
+                    "version": "0.4.0",
+                    "src": { "fetchurl": { "url": "https://example.com/log.crate", "sha256": "bbbb" } },
+                },
+                "main": { "crateName": "main", "version": "0.0.1" },
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let content = r#"
+rec {
+  internal = rec {
+    crates = cratesFromJSON (lib.importJSON ./Cargo.crates.json) // {
+      "main" = rec {
+        crateName = "main";
+        version = "0.1.0";
+        src = lib.cleanSourceWith { filter = sourceFilter; src = ./.; };
+      };
+    };
+  };
+}
+"#;
+        let path = dir.path().join("Cargo.nix");
+        std::fs::write(&path, content).unwrap();
+        read_crates(&path).unwrap()
+    };
+
+    let old = cargo_nix("aaaa");
+    // The crates in Cargo.nix take precedence.
+    assert_eq!(old["main"].version, "0.1.0");
+    assert_eq!(
+        old,
+        crates_from_cargo_nix(OLD_CARGO_NIX, Path::new(".")).unwrap()
+    );
+    let new = cargo_nix("cccc");
+    assert_eq!(
+        CratesDiff::new(&old, &new).changed_hashes,
+        vec![("libc".to_string(), "0.2.1".to_string())]
+    );
+    dir.close().unwrap();
+}
@@ -0,0 +1,222 @@
+//! Finding crates that are resolved in several versions, e.g. for `crate2nix duplicates`.
+//!
+//! Every version is built separately, so converging them saves build time and closure size.
+//! Versions that are semver compatible with the newest one can be converged with
+//! `cargo update -p`, all others only by upgrading the crates that depend on them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use semver::Version;
+use serde::Serialize;
+
+use crate::metadata::IndexedMetadata;
+use crate::BuildInfo;
+
+/// A crate that is resolved in several versions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DuplicateCrate {
+    /// The crate name.
+    pub name: String,
+    /// The resolved versions, oldest first.
+    pub versions: Vec<DuplicateVersion>,
+}
+
+/// One of the resolved versions of a duplicate crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DuplicateVersion {
+    /// The resolved version.
+    pub version: Version,
+    /// The crates that depend on this version, as `name version`.
+    pub dependents: Vec<String>,
+}
+
+impl DuplicateCrate {
+    fn newest(&self) -> &Version {
+        &self
+            .versions
+            .last()
+            .expect("duplicates have versions")
+            .version
+    }
+
+    /// Returns the `cargo update` commands that update the older versions
+    /// that are semver compatible with the newest version.
+    pub fn update_commands(&self) -> Vec<String> {
+        let newest = self.newest();
+        self.versions
+            .iter()
+            .filter(|v| &v.version != newest && semver_compatible(&v.version, newest))
+            .map(|v| format!("cargo update -p {}@{}", self.name, v.version))
+            .collect()
+    }
+}
+
+/// Returns all crates in `build_info` that are resolved in more than one version, by name.
+pub fn duplicates(build_info: &BuildInfo) -> Vec<DuplicateCrate> {
+    duplicates_in(&build_info.indexed_metadata)
+}
+
+fn duplicates_in(metadata: &IndexedMetadata) -> Vec<DuplicateCrate> {
+    let mut dependents: BTreeMap<(&str, &Version), BTreeSet<String>> = BTreeMap::new();
+    for package in metadata.pkgs_by_id.values() {
+        dependents
+            .entry((package.name.as_str(), &package.version))
+            .or_default();
+    }
+    for node in metadata.nodes_by_id.values() {
+        let Some(dependent) = metadata.pkgs_by_id.get(&node.id) else {
+            continue;
+        };
+        for dep in &node.deps {
+            if let Some(package) = metadata.pkgs_by_id.get(&dep.pkg) {
+                dependents
+                    .entry((package.name.as_str(), &package.version))
+                    .or_default()
+                    .insert(format!("{} {}", dependent.name, dependent.version));
+            }
+        }
+    }
+
+    let mut by_name: BTreeMap<&str, Vec<DuplicateVersion>> = BTreeMap::new();
+    for ((name, version), dependents) in dependents {
+        by_name.entry(name).or_default().push(DuplicateVersion {
+            version: version.clone(),
+            dependents: dependents.into_iter().collect(),
+        });
+    }
+    by_name
+        .into_iter()
+        .filter(|(_, versions)| versions.len() > 1)
+        .map(|(name, versions)| DuplicateCrate {
+            name: name.to_string(),
+            versions,
+        })
+        .collect()
+}
+
+/// Whether cargo may resolve a requirement on `older` to `newer`.
+fn semver_compatible(older: &Version, newer: &Version) -> bool {
+    match (older.major, older.minor) {
+        (0, 0) => newer.major == 0 && newer.minor == 0 && older.patch == newer.patch,
+        (0, minor) => newer.major == 0 && newer.minor == minor,
+        (major, _) => newer.major == major,
+    }
+}
+
+/// Renders the duplicates with their dependents and suggestions to converge them.
+pub fn render(duplicates: &[DuplicateCrate]) -> String {
+    if duplicates.is_empty() {
+        return "Every crate is resolved in a single version.\n".to_string();
+    }
+
+    let mut report = String::new();
+    for duplicate in duplicates {
+        writeln!(report, "{}", duplicate.name).unwrap();
+        let width = duplicate
+            .versions
+            .iter()
+            .map(|v| v.version.to_string().len())
+            .max()
+            .unwrap_or(0);
+        for version in &duplicate.versions {
+            writeln!(
+                report,
+                "  {:width$}  used by {}",
+                version.version.to_string(),
+                version.dependents.join(", "),
+                width = width
+            )
+            .unwrap();
+        }
+        for command in duplicate.update_commands() {
+            writeln!(report, "  suggestion: {}", command).unwrap();
+        }
+        let newest = duplicate.newest();
+        for version in &duplicate.versions {
+            if &version.version != newest && !semver_compatible(&version.version, newest) {
+                writeln!(
+                    report,
+                    "  suggestion: upgrade {} to {} {}",
+                    version.dependents.join(", "),
+                    duplicate.name,
+                    newest
+                )
+                .unwrap();
+            }
+        }
+        report.push('\n');
+    }
+    let versions: usize = duplicates.iter().map(|d| d.versions.len()).sum();
+    writeln!(
+        report,
+        "{} crates are resolved in {} versions.",
+        duplicates.len(),
+        versions
+    )
+    .unwrap();
+    report
+}
+
+#[test]
+fn test_semver_compatible() {
+    let v = |s: &str| Version::parse(s).unwrap();
+    assert!(semver_compatible(&v("1.0.100"), &v("1.2.0")));
+    assert!(!semver_compatible(&v("1.0.100"), &v("2.0.1")));
+    assert!(semver_compatible(&v("0.3.1"), &v("0.3.9")));
+    assert!(!semver_compatible(&v("0.3.1"), &v("0.4.0")));
+    assert!(!semver_compatible(&v("0.0.1"), &v("0.0.2")));
+}
+
+#[test]
+fn test_duplicates() {
+    let mut env = crate::test::MetadataEnv::default();
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.add_dependency("syn").version_and_package_id("1.0.100");
+    main.add_dependency("serde").version_and_package_id("1.0.1");
+    let mut helper = main.add_dependency("helper");
+    helper
+        .update_package_and_node(|helper| {
+            helper.add_dependency("syn").version_and_package_id("2.0.1");
+            helper
+                .add_dependency("serde")
+                .version_and_package_id("1.0.2");
+        })
+        .version_and_package_id("0.2.0");
+
+    let duplicates = duplicates_in(&env.indexed_metadata());
+    let names: Vec<&str> = duplicates.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, vec!["serde", "syn"]);
+    assert_eq!(
+        duplicates[1].versions[0],
+        DuplicateVersion {
+            version: Version::parse("1.0.100").unwrap(),
+            dependents: vec!["main 0.1.0".to_string()],
+        }
+    );
+    assert_eq!(
+        duplicates[0].update_commands(),
+        vec!["cargo update -p serde@1.0.1".to_string()]
+    );
+    assert!(duplicates[1].update_commands().is_empty());
+
+    let report = render(&duplicates);
+    assert!(
+        report.contains("  2.0.1    used by helper 0.2.0\n"),
+        "{}",
+        report
+    );
+    assert!(
+        report.contains("  suggestion: upgrade main 0.1.0 to syn 2.0.1\n"),
+        "{}",
+        report
+    );
+    assert!(
+        report.ends_with("2 crates are resolved in 4 versions.\n"),
+        "{}",
+        report
+    );
+
+    env.close();
+}
@@ -0,0 +1,88 @@
+//! The rust editions that the generated build supports.
+//!
+//! `Cargo.nix` passes the edition of every crate on to `rustc`, so supporting a new edition only
+//! needs an entry in `EDITIONS`. Crates of other editions are rejected during generation instead
+//! of failing to compile with an unhelpful error. The first rustc version of an edition is the
+//! `rust-version` of crates that do not declare one.
+
+use anyhow::{bail, Error};
+
+/// A rust edition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edition {
+    /// The name as in `Cargo.toml`, e.g. "2021".
+    pub name: &'static str,
+    /// The first stable rustc version that supports the edition, `None` for all versions.
+    pub rustc_version: Option<&'static str>,
+    /// The feature resolver that packages of this edition use unless they specify one.
+    pub default_feature_resolver: &'static str,
+}
+
+/// All supported editions, oldest first.
+pub const EDITIONS: &[Edition] = &[
+    Edition {
+        name: "2015",
+        rustc_version: None,
+        default_feature_resolver: "1",
+    },
+    Edition {
+        name: "2018",
+        rustc_version: Some("1.31.0"),
+        default_feature_resolver: "1",
+    },
+    Edition {
+        name: "2021",
+        rustc_version: Some("1.56.0"),
+        default_feature_resolver: "2",
+    },
+    Edition {
+        name: "2024",
+        rustc_version: Some("1.85.0"),
+        default_feature_resolver: "2",
+    },
+];
+
+impl Edition {
+    /// Returns the supported edition with the given name, if there is one.
+    pub fn find(name: &str) -> Option<&'static Edition> {
+        EDITIONS.iter().find(|edition| edition.name == name)
+    }
+
+    /// Returns the edition of a package or an error if it is not supported.
+    pub fn of_package(name: &str, package_name: &str) -> Result<&'static Edition, Error> {
+        match Edition::find(name) {
+            Some(edition) => Ok(edition),
+            None => bail!(
+                "{} uses the rust edition {} which this version of crate2nix does not support. \
+                 Supported editions: {}. Please update crate2nix.",
+                package_name,
+                name,
+                EDITIONS
+                    .iter()
+                    .map(|edition| edition.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_of_package() {
+    assert_eq!(
+        Edition::of_package("2024", "a").unwrap().rustc_version,
+        Some("1.85.0")
+    );
+    assert_eq!(
+        Edition::of_package("2018", "a")
+            .unwrap()
+            .default_feature_resolver,
+        "1"
+    );
+    let error = Edition::of_package("2027", "future-crate").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "future-crate uses the rust edition 2027 which this version of crate2nix does not \
+         support. Supported editions: 2015, 2018, 2021, 2024. Please update crate2nix."
+    );
+}
@@ -0,0 +1,143 @@
+//! Typed errors for failures that users can act upon.
+//!
+//! Every variant names the offending package (if any) and renders a remediation hint
+//! as part of its message.
+
+use cargo_metadata::PackageId;
+use thiserror::Error;
+
+/// Errors which are common enough to deserve individual treatment.
+#[derive(Debug, Error)]
+pub enum Crate2NixError {
+    /// `cargo metadata` did not return a resolve node for a package.
+    #[error("Could not find resolve node for {package_id}.\nhint: {}", self.hint())]
+    MissingNode {
+        /// The package without a node.
+        package_id: PackageId,
+    },
+    /// A resolved dependency refers to a package that is not part of the metadata.
+    #[error(
+        "No matching package for dependency with package id {dependency_id} in {package_id}.\nhint: {}",
+        self.hint()
+    )]
+    MissingDependencyPackage {
+        /// The package with the dangling dependency.
+        package_id: PackageId,
+        /// The package ID that the dependency points to.
+        dependency_id: PackageId,
+    },
+    /// A hash was requested for a source that crate2nix cannot prefetch.
+    #[error("Cannot determine hash for {package_id} with source {source_description}.\nhint: {}", self.hint())]
+    UnhashableSource {
+        /// The package with the source.
+        package_id: PackageId,
+        /// A human readable representation of the source.
+        source_description: String,
+    },
+    /// A prefetched crate does not match the checksum in `Cargo.lock`.
+    #[error(
+        "The prefetched source of {package_id} has the hash {prefetched} \
+         but Cargo.lock expects {expected}.\nhint: {}",
+        self.hint()
+    )]
+    ChecksumMismatch {
+        /// The package with the source.
+        package_id: PackageId,
+        /// The hash of the prefetched source in nix base32.
+        prefetched: String,
+        /// The checksum from `Cargo.lock` in nix base32.
+        expected: String,
+    },
+    /// A `cfg(...)` or target expression that crate2nix cannot translate to nix.
+    #[error(
+        "Unsupported target expression '{expression}' in {package_id}: {reason}\nhint: {}",
+        self.hint()
+    )]
+    UnsupportedTargetExpression {
+        /// The package with the dependency on the target.
+        package_id: PackageId,
+        /// The expression as found in `Cargo.toml`.
+        expression: String,
+        /// Why the expression could not be translated.
+        reason: String,
+    },
+    /// Locked crate versions have been yanked and `--deny-yanked` was given.
+    #[error("Cargo.lock contains yanked crate versions: {crates}.\nhint: {}", self.hint())]
+    YankedCrates {
+        /// The yanked crates as `name version`, comma separated.
+        crates: String,
+    },
+    /// Locked crate versions could not be checked for being yanked and `--deny-yanked` was given.
+    #[error("Could not check whether these crates have been yanked: {crates}.\nhint: {}", self.hint())]
+    UncheckedYankedCrates {
+        /// The names of the unchecked crates, comma separated.
+        crates: String,
+    },
+}
+
+impl Crate2NixError {
+    /// A sentence describing what the user can try to fix the error.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Crate2NixError::MissingNode { .. }
+            | Crate2NixError::MissingDependencyPackage { .. } => {
+                "The output of `cargo metadata` is inconsistent. \
+                 Try `cargo update -p <crate>` or deleting Cargo.lock and regenerating it."
+            }
+            Crate2NixError::UnhashableSource { .. } => {
+                "Only registry and git sources can be prefetched. \
+                 Add the hash to crate-hashes.json manually or use an out-of-tree source \
+                 (`crate2nix source add`)."
+            }
+            Crate2NixError::ChecksumMismatch { .. } => {
+                "The download differs from the crate that Cargo.lock was created with, \
+                 e.g. because of a corrupted mirror or tampering. Check the registry or mirror \
+                 and remove the hash from crate-hashes.json before generating again."
+            }
+            Crate2NixError::UnsupportedTargetExpression { .. } => {
+                "Please file a bug at https://github.com/nix-community/crate2nix/issues \
+                 including the expression. Meanwhile, consider patching the dependency."
+            }
+            Crate2NixError::YankedCrates { .. } => {
+                "Yanked crates may disappear from mirrors and caches. \
+                 Update them with `cargo update -p <crate>@<version>` and regenerate."
+            }
+            Crate2NixError::UncheckedYankedCrates { .. } => {
+                "The index files of their registries could not be fetched or read. \
+                 Check the network access and --proxy, rerun with -v for details."
+            }
+        }
+    }
+}
+
+#[test]
+fn test_error_message_contains_hint_and_package_id() {
+    let error = Crate2NixError::MissingNode {
+        package_id: PackageId {
+            repr: "some-crate 1.0.0".to_string(),
+        },
+    };
+    let message = error.to_string();
+    assert!(message.contains("some-crate 1.0.0"), "{}", message);
+    assert!(
+        message.contains(&format!("hint: {}", error.hint())),
+        "{}",
+        message
+    );
+
+    let error = Crate2NixError::UnsupportedTargetExpression {
+        package_id: PackageId {
+            repr: "some-crate 1.0.0".to_string(),
+        },
+        expression: "cfg(unix".to_string(),
+        reason: "expected `)`".to_string(),
+    };
+    let message = error.to_string();
+    assert!(message.contains("some-crate 1.0.0"), "{}", message);
+    assert!(message.contains("cfg(unix"), "{}", message);
+    assert!(
+        message.contains(&format!("hint: {}", error.hint())),
+        "{}",
+        message
+    );
+}
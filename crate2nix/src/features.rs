@@ -0,0 +1,264 @@
+//! Explaining why the features of a crate are enabled, e.g. for `crate2nix explain-features`.
+//!
+//! A feature is enabled by the crate's own features, by the dependency declarations of its
+//! dependents (`features = [...]` and default features) or by the features of its dependents
+//! (`dep/feature` and `dep?/feature`). For workspace members, it may also be requested on the
+//! command line. We only follow the edges of the resolved dependency graph.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use anyhow::{bail, Error};
+use cargo_metadata::{Dependency, Package};
+use semver::Version;
+use serde::Serialize;
+
+use crate::metadata::IndexedMetadata;
+use crate::BuildInfo;
+
+/// The enabled features of one version of a crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrateFeatures {
+    /// The crate name.
+    pub name: String,
+    /// The resolved version.
+    pub version: Version,
+    /// Every enabled feature with the reasons why it is enabled.
+    pub features: Vec<EnabledFeature>,
+}
+
+/// An enabled feature of a crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EnabledFeature {
+    /// The feature name.
+    pub name: String,
+    /// Why the feature is enabled, e.g. "enabled by its feature `default`".
+    pub reasons: Vec<String>,
+}
+
+/// Returns the enabled features of every resolved version of `crate_spec`, which is a crate
+/// name or `name@version`.
+pub fn explain(build_info: &BuildInfo, crate_spec: &str) -> Result<Vec<CrateFeatures>, Error> {
+    explain_in(&build_info.indexed_metadata, crate_spec)
+}
+
+fn explain_in(metadata: &IndexedMetadata, crate_spec: &str) -> Result<Vec<CrateFeatures>, Error> {
+    let (name, version) = match crate_spec.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (crate_spec, None),
+    };
+    let explained: Vec<CrateFeatures> = metadata
+        .pkgs_by_id
+        .values()
+        .filter(|package| {
+            package.name == name && version.is_none_or(|v| package.version.to_string() == v)
+        })
+        .map(|package| explain_package(metadata, package))
+        .collect();
+    if explained.is_empty() {
+        bail!("{} is not in the resolved dependency graph", crate_spec);
+    }
+    Ok(explained)
+}
+
+fn explain_package(metadata: &IndexedMetadata, package: &Package) -> CrateFeatures {
+    let enabled: BTreeSet<&str> = enabled_features(metadata, package);
+    let is_member = metadata.workspace_members.contains(&package.id);
+    let features = enabled
+        .iter()
+        .map(|feature| {
+            let mut reasons = BTreeSet::new();
+            for (enabling, entries) in &package.features {
+                if !enabled.contains(enabling.as_str()) {
+                    continue;
+                }
+                for entry in entries {
+                    if enables_own_feature(entry, feature) {
+                        reasons.insert(if entry == feature {
+                            format!("enabled by its feature `{}`", enabling)
+                        } else {
+                            format!("enabled by its feature `{}` (`{}`)", enabling, entry)
+                        });
+                    }
+                }
+            }
+            for (dependent, declarations) in dependents(metadata, package) {
+                let dependent_name = format!("{} {}", dependent.name, dependent.version);
+                let keys: BTreeSet<&str> = declarations
+                    .iter()
+                    .map(|d| d.rename.as_deref().unwrap_or(&d.name))
+                    .collect();
+                for declaration in &declarations {
+                    if *feature == "default" && declaration.uses_default_features {
+                        reasons.insert(format!(
+                            "requested by the dependency of {} with default features",
+                            dependent_name
+                        ));
+                    }
+                    if declaration.features.iter().any(|f| f == feature) {
+                        reasons
+                            .insert(format!("requested by the dependency of {}", dependent_name));
+                    }
+                }
+                let dependent_features = enabled_features(metadata, dependent);
+                for (enabling, entries) in &dependent.features {
+                    if !dependent_features.contains(enabling.as_str()) {
+                        continue;
+                    }
+                    for entry in entries {
+                        if keys
+                            .iter()
+                            .any(|key| enables_dependency_feature(entry, key, feature))
+                        {
+                            reasons.insert(format!(
+                                "requested by feature `{}` of {} (`{}`)",
+                                enabling, dependent_name, entry
+                            ));
+                        }
+                    }
+                }
+            }
+            if is_member && reasons.is_empty() {
+                reasons.insert("requested for the workspace member".to_string());
+            }
+            EnabledFeature {
+                name: feature.to_string(),
+                reasons: reasons.into_iter().collect(),
+            }
+        })
+        .collect();
+    CrateFeatures {
+        name: package.name.clone(),
+        version: package.version.clone(),
+        features,
+    }
+}
+
+/// The resolved features of `package`.
+fn enabled_features<'a>(metadata: &'a IndexedMetadata, package: &Package) -> BTreeSet<&'a str> {
+    metadata
+        .nodes_by_id
+        .get(&package.id)
+        .map(|node| node.features.iter().map(String::as_str).collect())
+        .unwrap_or_default()
+}
+
+/// The packages that depend on `package` in the resolved graph, with their declarations that
+/// resolve to it, i.e. with a matching version requirement and a resolved kind.
+fn dependents<'a>(
+    metadata: &'a IndexedMetadata,
+    package: &Package,
+) -> Vec<(&'a Package, Vec<&'a Dependency>)> {
+    let mut dependents = Vec::new();
+    for node in metadata.nodes_by_id.values() {
+        let Some(dep) = node.deps.iter().find(|dep| dep.pkg == package.id) else {
+            continue;
+        };
+        let Some(dependent) = metadata.pkgs_by_id.get(&node.id) else {
+            continue;
+        };
+        let declarations = dependent
+            .dependencies
+            .iter()
+            .filter(|d| {
+                d.name == package.name
+                    && d.req.matches(&package.version)
+                    && dep.dep_kinds.iter().any(|k| k.kind == d.kind)
+            })
+            .collect();
+        dependents.push((dependent, declarations));
+    }
+    dependents
+}
+
+/// Whether the entry of a feature enables the feature `feature` of the same crate,
+/// e.g. `"std"` or `"serde/std"` for the implicit feature of an optional dependency `serde`.
+fn enables_own_feature(entry: &str, feature: &str) -> bool {
+    entry == feature || entry.split_once('/').is_some_and(|(dep, _)| dep == feature)
+}
+
+/// Whether the entry of a feature enables `feature` of the dependency with manifest key `key`.
+fn enables_dependency_feature(entry: &str, key: &str, feature: &str) -> bool {
+    let Some((dep, dep_feature)) = entry.split_once('/') else {
+        return false;
+    };
+    dep_feature == feature && (dep == key || dep.strip_suffix('?') == Some(key))
+}
+
+/// Renders the explanations with one line per reason.
+pub fn render(explained: &[CrateFeatures]) -> String {
+    let mut report = String::new();
+    for crate_features in explained {
+        writeln!(report, "{} {}", crate_features.name, crate_features.version).unwrap();
+        if crate_features.features.is_empty() {
+            writeln!(report, "  no features enabled").unwrap();
+        }
+        for feature in &crate_features.features {
+            writeln!(report, "  {}", feature.name).unwrap();
+            for reason in &feature.reasons {
+                writeln!(report, "    {}", reason).unwrap();
+            }
+        }
+    }
+    report
+}
+
+#[test]
+fn test_enables_feature() {
+    assert!(enables_own_feature("std", "std"));
+    assert!(enables_own_feature("serde/std", "serde"));
+    assert!(!enables_own_feature("dep:serde", "serde"));
+    assert!(enables_dependency_feature("serde/std", "serde", "std"));
+    assert!(enables_dependency_feature("serde?/std", "serde", "std"));
+    assert!(!enables_dependency_feature(
+        "serde/std",
+        "serde_json",
+        "std"
+    ));
+    assert!(!enables_dependency_feature("serde", "serde", "std"));
+}
+
+#[test]
+fn test_explain() {
+    let mut env = crate::test::MetadataEnv::default();
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.update_package(|p| {
+        p.features
+            .insert("default".to_string(), vec!["json".to_string()]);
+        p.features
+            .insert("json".to_string(), vec!["serde/std".to_string()]);
+    })
+    .update_node(|n| n.features = vec!["default".to_string(), "json".to_string()]);
+    main.add_dependency("serde")
+        .update_package_dep(|d| d.features = vec!["derive".to_string()])
+        .update_package(|p| {
+            p.features
+                .insert("default".to_string(), vec!["std".to_string()]);
+            p.features.insert("std".to_string(), vec![]);
+            p.features.insert("derive".to_string(), vec![]);
+        })
+        .update_node(|n| {
+            n.features = vec![
+                "default".to_string(),
+                "derive".to_string(),
+                "std".to_string(),
+            ]
+        })
+        .version_and_package_id("1.0.0");
+
+    let explained = explain_in(&env.indexed_metadata(), "serde").unwrap();
+    assert_eq!(
+        render(&explained),
+        "serde 1.0.0\n  \
+           default\n    \
+             requested by the dependency of main 0.1.0 with default features\n  \
+           derive\n    \
+             requested by the dependency of main 0.1.0\n  \
+           std\n    \
+             enabled by its feature `default`\n    \
+             requested by feature `json` of main 0.1.0 (`serde/std`)\n"
+    );
+    assert!(explain_in(&env.indexed_metadata(), "serde@2.0.0").is_err());
+    env.close();
+}
@@ -0,0 +1,467 @@
+//! Rendering the resolved dependency graph, e.g. for `crate2nix graph`.
+//!
+//! The graph contains exactly the crates and dependency edges that end up in `Cargo.nix`,
+//! annotated with the dependency kind, target conditions and whether they are optional.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::resolve::{CrateDerivation, ResolvedDependency, ResolvedSource};
+#[cfg(test)]
+use crate::test;
+use crate::BuildInfo;
+
+/// The supported output formats for the dependency graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum GraphFormat {
+    /// [Graphviz](https://graphviz.org/) DOT.
+    Dot,
+    /// A [Mermaid](https://mermaid.js.org/) flowchart.
+    Mermaid,
+}
+
+impl FromStr for GraphFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => bail!("unknown graph format '{}', expected 'dot' or 'mermaid'", s),
+        }
+    }
+}
+
+/// A dependency edge between two crates.
+struct Edge {
+    from: usize,
+    to: usize,
+    label: String,
+    optional: bool,
+}
+
+/// Renders the dependency graph of all crates in `build_info`.
+pub fn render(build_info: &BuildInfo, format: GraphFormat) -> String {
+    let crates = &build_info.crates;
+    let index_of = |dependency: &ResolvedDependency| {
+        crates
+            .iter()
+            .position(|c| c.package_id == dependency.package_id)
+    };
+
+    let mut edges = Vec::new();
+    for (from, crate_derivation) in crates.iter().enumerate() {
+        let kinds = [
+            ("", &crate_derivation.dependencies),
+            ("build", &crate_derivation.build_dependencies),
+            ("dev", &crate_derivation.dev_dependencies),
+        ];
+        for (kind, dependencies) in kinds {
+            for dependency in dependencies {
+                if let Some(to) = index_of(dependency) {
+                    edges.push(Edge {
+                        from,
+                        to,
+                        label: edge_label(kind, dependency),
+                        optional: dependency.optional,
+                    });
+                }
+            }
+        }
+    }
+
+    match format {
+        GraphFormat::Dot => render_dot(crates, &edges),
+        GraphFormat::Mermaid => render_mermaid(crates, &edges),
+    }
+}
+
+fn edge_label(kind: &str, dependency: &ResolvedDependency) -> String {
+    let mut annotations = Vec::new();
+    if !kind.is_empty() {
+        annotations.push(kind.to_string());
+    }
+    if let Some(rename) = dependency.rename.as_ref() {
+        annotations.push(format!("as {}", rename));
+    }
+    if let Some(target) = dependency.target.as_ref() {
+        annotations.push(target.to_string());
+    }
+    if dependency.optional {
+        annotations.push("optional".to_string());
+    }
+    annotations.join(", ")
+}
+
+fn node_label(crate_derivation: &CrateDerivation) -> String {
+    format!(
+        "{} {}",
+        crate_derivation.crate_name, crate_derivation.version
+    )
+}
+
+fn render_dot(crates: &[CrateDerivation], edges: &[Edge]) -> String {
+    fn quote(s: &str) -> String {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    let mut dot = String::from("digraph dependencies {\n  node [shape=ellipse];\n");
+    for (index, crate_derivation) in crates.iter().enumerate() {
+        let mut attributes = vec![format!("label={}", quote(&node_label(crate_derivation)))];
+        if crate_derivation.is_root_or_workspace_member {
+            attributes.push("shape=box".to_string());
+        }
+        if crate_derivation.problem.is_some() {
+            attributes.push("color=red".to_string());
+        }
+        writeln!(dot, "  n{} [{}];", index, attributes.join(", ")).unwrap();
+    }
+    for edge in edges {
+        let mut attributes = Vec::new();
+        if !edge.label.is_empty() {
+            attributes.push(format!("label={}", quote(&edge.label)));
+        }
+        if edge.optional {
+            attributes.push("style=dashed".to_string());
+        }
+        if attributes.is_empty() {
+            writeln!(dot, "  n{} -> n{};", edge.from, edge.to).unwrap();
+        } else {
+            writeln!(
+                dot,
+                "  n{} -> n{} [{}];",
+                edge.from,
+                edge.to,
+                attributes.join(", ")
+            )
+            .unwrap();
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn render_mermaid(crates: &[CrateDerivation], edges: &[Edge]) -> String {
+    fn quote(s: &str) -> String {
+        format!("\"{}\"", s.replace('"', "#quot;"))
+    }
+
+    let mut mermaid = String::from("flowchart LR\n");
+    for (index, crate_derivation) in crates.iter().enumerate() {
+        let label = quote(&node_label(crate_derivation));
+        if crate_derivation.is_root_or_workspace_member {
+            writeln!(mermaid, "  n{}[[{}]]", index, label).unwrap();
+        } else {
+            writeln!(mermaid, "  n{}({})", index, label).unwrap();
+        }
+    }
+    for edge in edges {
+        let arrow = if edge.optional { "-.->" } else { "-->" };
+        if edge.label.is_empty() {
+            writeln!(mermaid, "  n{} {} n{}", edge.from, arrow, edge.to).unwrap();
+        } else {
+            writeln!(
+                mermaid,
+                "  n{} {}|{}| n{}",
+                edge.from,
+                arrow,
+                quote(&edge.label),
+                edge.to
+            )
+            .unwrap();
+        }
+    }
+    mermaid
+}
+
+/// Renders the dependency trees of `crate_spec`, a crate name or `name@version`, or of the
+/// workspace members like `cargo tree`. With `invert`, the trees show the dependents instead.
+pub fn render_tree(
+    build_info: &BuildInfo,
+    crate_spec: Option<&str>,
+    invert: bool,
+) -> Result<String, Error> {
+    // Like cargo, we show the directories of local crates rather than their Nix paths.
+    let directories: Vec<Option<String>> = build_info
+        .crates
+        .iter()
+        .map(|crate_derivation| {
+            let ResolvedSource::LocalDirectory(_) = crate_derivation.source else {
+                return None;
+            };
+            let package = build_info
+                .indexed_metadata
+                .pkgs_by_id
+                .get(&crate_derivation.package_id)?;
+            Some(package.manifest_path.parent()?.to_string())
+        })
+        .collect();
+    tree(&build_info.crates, &directories, crate_spec, invert)
+}
+
+fn tree(
+    crates: &[CrateDerivation],
+    directories: &[Option<String>],
+    crate_spec: Option<&str>,
+    invert: bool,
+) -> Result<String, Error> {
+    let roots: Vec<usize> = match crate_spec {
+        Some(crate_spec) => {
+            let (name, version) = match crate_spec.split_once('@') {
+                Some((name, version)) => (name, Some(version)),
+                None => (crate_spec, None),
+            };
+            let roots: Vec<usize> = (0..crates.len())
+                .filter(|&i| {
+                    *crates[i].crate_name == *name
+                        && version.is_none_or(|v| crates[i].version.to_string() == v)
+                })
+                .collect();
+            if roots.is_empty() {
+                bail!("{} is not in the resolved dependency graph", crate_spec);
+            }
+            roots
+        }
+        None if invert => bail!("--invert needs the crate whose dependents to show"),
+        None => (0..crates.len())
+            .filter(|&i| crates[i].is_root_or_workspace_member)
+            .collect(),
+    };
+
+    let mut tree = Tree {
+        crates,
+        directories,
+        children: vec![Vec::new(); crates.len()],
+        printed: vec![false; crates.len()],
+        output: String::new(),
+    };
+    for (from, crate_derivation) in crates.iter().enumerate() {
+        let mut kinds = vec![
+            (DependencyKind::Normal, &crate_derivation.dependencies),
+            (DependencyKind::Build, &crate_derivation.build_dependencies),
+        ];
+        // Like cargo, only the dev dependencies of the workspace members are resolved.
+        if crate_derivation.is_root_or_workspace_member {
+            kinds.push((DependencyKind::Dev, &crate_derivation.dev_dependencies));
+        }
+        for (kind, dependencies) in kinds {
+            for dependency in dependencies {
+                let Some(to) = crates
+                    .iter()
+                    .position(|c| c.package_id == dependency.package_id)
+                else {
+                    continue;
+                };
+                let (parent, child) = if invert { (to, from) } else { (from, to) };
+                if !tree.children[parent].contains(&(kind, child)) {
+                    tree.children[parent].push((kind, child));
+                }
+            }
+        }
+    }
+    for children in &mut tree.children {
+        children.sort_by(|(kind_a, a), (kind_b, b)| {
+            (kind_a, &crates[*a].crate_name, &crates[*a].version).cmp(&(
+                kind_b,
+                &crates[*b].crate_name,
+                &crates[*b].version,
+            ))
+        });
+    }
+
+    for (i, root) in roots.into_iter().enumerate() {
+        if i > 0 {
+            tree.output.push('\n');
+        }
+        tree.print(root, "", &mut vec![root]);
+    }
+    Ok(tree.output)
+}
+
+/// The kind of a dependency edge in a tree, in the order of the sections of `cargo tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum DependencyKind {
+    Normal,
+    Build,
+    Dev,
+}
+
+struct Tree<'a> {
+    crates: &'a [CrateDerivation],
+    /// The directories of the local crates.
+    directories: &'a [Option<String>],
+    /// The dependencies, or dependents if inverted, of every crate with the kind of the edge.
+    children: Vec<Vec<(DependencyKind, usize)>>,
+    /// Whether the children of a crate were printed already.
+    printed: Vec<bool>,
+    output: String,
+}
+
+impl Tree<'_> {
+    /// Prints the line of `index` and below it its children, unless they were printed before.
+    fn print(&mut self, index: usize, prefix: &str, stack: &mut Vec<usize>) {
+        let crate_derivation = &self.crates[index];
+        write!(
+            self.output,
+            "{} v{}",
+            crate_derivation.crate_name, crate_derivation.version
+        )
+        .unwrap();
+        if crate_derivation.proc_macro {
+            self.output.push_str(" (proc-macro)");
+        }
+        if let Some(directory) = &self.directories[index] {
+            write!(self.output, " ({})", directory).unwrap();
+        }
+        let children = self.children[index].clone();
+        if !children.is_empty() && self.printed[index] {
+            self.output.push_str(" (*)\n");
+            return;
+        }
+        self.output.push('\n');
+        self.printed[index] = true;
+
+        let mut previous_kind = DependencyKind::Normal;
+        for (i, &(kind, child)) in children.iter().enumerate() {
+            if kind != previous_kind {
+                let header = match kind {
+                    DependencyKind::Normal => "",
+                    DependencyKind::Build => "[build-dependencies]",
+                    DependencyKind::Dev => "[dev-dependencies]",
+                };
+                writeln!(self.output, "{}{}", prefix, header).unwrap();
+                previous_kind = kind;
+            }
+            let last = children.get(i + 1).is_none_or(|(next, _)| *next != kind);
+            let (branch, indent) = if last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            self.output.push_str(prefix);
+            self.output.push_str(branch);
+            // Dev dependencies can form cycles.
+            if stack.contains(&child) {
+                let child = &self.crates[child];
+                writeln!(self.output, "{} v{} (*)", child.crate_name, child.version).unwrap();
+                continue;
+            }
+            stack.push(child);
+            self.print(child, &format!("{}{}", prefix, indent), stack);
+            stack.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_build_info() -> (test::MetadataEnv, Vec<CrateDerivation>) {
+    let mut env = test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("helper");
+    let indexed = env.indexed_metadata();
+    let helper_id = indexed
+        .pkgs_by_id
+        .values()
+        .find(|p| p.name == "helper")
+        .map(|p| p.id.clone())
+        .unwrap();
+    let crates = indexed
+        .pkgs_by_id
+        .values()
+        .map(|package| {
+            let mut crate_derivation =
+                CrateDerivation::unresolved(&indexed, package, String::new());
+            crate_derivation.problem = None;
+            if package.name == "main" {
+                crate_derivation
+                    .build_dependencies
+                    .push(ResolvedDependency {
+                        name: "helper".into(),
+                        rename: None,
+                        package_id: helper_id.clone().into(),
+                        target: Some(cargo_platform::Platform::from_str("cfg(unix)").unwrap()),
+                        optional: true,
+                        uses_default_features: true,
+                        features: Vec::new(),
+                        artifact: None,
+                    });
+            }
+            crate_derivation
+        })
+        .collect();
+    (env, crates)
+}
+
+#[test]
+fn test_render_dot() {
+    let (mut env, crates) = test_build_info();
+    let edges = [Edge {
+        from: 1,
+        to: 0,
+        label: edge_label("build", &crates[1].build_dependencies[0]),
+        optional: true,
+    }];
+
+    let dot = render_dot(&crates, &edges);
+    assert!(dot.starts_with("digraph dependencies {"), "{}", dot);
+    assert!(
+        dot.contains("n1 [label=\"main 0.1.0\", shape=box];"),
+        "{}",
+        dot
+    );
+    assert!(
+        dot.contains("n1 -> n0 [label=\"build, cfg(unix), optional\", style=dashed];"),
+        "{}",
+        dot
+    );
+
+    env.close();
+}
+
+#[test]
+fn test_render_mermaid() {
+    let (mut env, crates) = test_build_info();
+    let edges = [Edge {
+        from: 1,
+        to: 0,
+        label: edge_label("build", &crates[1].build_dependencies[0]),
+        optional: true,
+    }];
+
+    let mermaid = render_mermaid(&crates, &edges);
+    assert!(mermaid.contains("n0(\"helper 0.1.0\")"), "{}", mermaid);
+    assert!(mermaid.contains("n1[[\"main 0.1.0\"]]"), "{}", mermaid);
+    assert!(
+        mermaid.contains("n1 -.->|\"build, cfg(unix), optional\"| n0"),
+        "{}",
+        mermaid
+    );
+
+    env.close();
+}
+
+#[test]
+fn test_tree() {
+    let (mut env, crates) = test_build_info();
+    let directories = [None, Some("/src/main".to_string())];
+
+    assert_eq!(
+        tree(&crates, &directories, None, false).unwrap(),
+        "main v0.1.0 (/src/main)\n\
+         [build-dependencies]\n\
+         └── helper v0.1.0\n"
+    );
+    assert_eq!(
+        tree(&crates, &directories, Some("helper"), true).unwrap(),
+        "helper v0.1.0\n\
+         [build-dependencies]\n\
+         └── main v0.1.0 (/src/main)\n"
+    );
+    assert!(tree(&crates, &directories, None, true).is_err());
+    assert!(tree(&crates, &directories, Some("helper@0.2.0"), false).is_err());
+
+    env.close();
+}
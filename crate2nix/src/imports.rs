@@ -0,0 +1,211 @@
+//! Reusing the crate definitions of other crate2nix projects.
+//!
+//! Path dependencies often belong to another project that ships its own `Cargo.nix`, e.g. a
+//! repository checked out next to this one. That file knows best how to build its crates, e.g.
+//! with the source filters and build script settings of its `crate2nix.json`. Such crates
+//! therefore start from the definition in the other `Cargo.nix` and only override what depends
+//! on this dependency graph.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::diff::CrateSummary;
+use crate::resolve::ResolvedSource;
+use crate::BuildInfo;
+
+/// A crate whose definition is imported from the `Cargo.nix` of another project.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImportedCrate {
+    /// The key of the other `Cargo.nix` in `BuildInfo::imported_cargo_nix`.
+    pub cargo_nix: String,
+    /// The attribute name of the crate in the other `Cargo.nix`.
+    pub package_id: String,
+}
+
+/// Sets `imported` for local crates outside of the workspace whose project has a generated
+/// `Cargo.nix` and records the imported files in `imported_cargo_nix`.
+pub fn import_crates(build_info: &mut BuildInfo) {
+    let Some(output) = canonical_output(&build_info.config.output) else {
+        return;
+    };
+    let output_dir = output.parent().unwrap_or_else(|| Path::new("/"));
+    let mut crates_by_cargo_nix: BTreeMap<PathBuf, Option<BTreeMap<String, CrateSummary>>> =
+        BTreeMap::new();
+
+    for crate_derivation in &mut build_info.crates {
+        if crate_derivation.is_root_or_workspace_member || crate_derivation.problem.is_some() {
+            continue;
+        }
+        let ResolvedSource::LocalDirectory(source) = &crate_derivation.source else {
+            continue;
+        };
+        let Some(crate_dir) = build_info
+            .indexed_metadata
+            .pkgs_by_id
+            .get(&crate_derivation.package_id)
+            .and_then(|package| package.manifest_path.parent())
+            .and_then(|dir| dir.as_std_path().canonicalize().ok())
+        else {
+            continue;
+        };
+        let Some(project_dir) = project_with_cargo_nix(&crate_dir) else {
+            continue;
+        };
+        // Our own output or a project that contains it, e.g. a parent workspace.
+        if output.starts_with(&project_dir) {
+            continue;
+        }
+        let cargo_nix = project_dir.join("Cargo.nix");
+        let crates = crates_by_cargo_nix
+            .entry(cargo_nix.clone())
+            .or_insert_with(|| read_generated_crates(&cargo_nix));
+        let Some(package_id) = crates.as_ref().and_then(|crates| {
+            crates
+                .iter()
+                .find(|(_, c)| {
+                    *c.crate_name == *crate_derivation.crate_name
+                        && c.version == crate_derivation.version.to_string()
+                })
+                .map(|(package_id, _)| package_id.clone())
+        }) else {
+            continue;
+        };
+        let Some(expr) = cargo_nix_expr(
+            &source.path().to_string_lossy(),
+            crate_dir
+                .strip_prefix(&project_dir)
+                .unwrap_or(Path::new("")),
+        ) else {
+            debug!(
+                "Not importing {}: cannot express the path of {}.",
+                crate_derivation.package_id,
+                cargo_nix.display()
+            );
+            continue;
+        };
+        let key = pathdiff::diff_paths(&project_dir, output_dir)
+            .unwrap_or_else(|| project_dir.clone())
+            .to_string_lossy()
+            .to_string();
+        info!(
+            "Using the definition of {} {} in {}.",
+            crate_derivation.crate_name,
+            crate_derivation.version,
+            cargo_nix.display()
+        );
+        build_info.imported_cargo_nix.insert(key.clone(), expr);
+        crate_derivation.imported = Some(ImportedCrate {
+            cargo_nix: key,
+            package_id,
+        });
+    }
+}
+
+fn canonical_output(output: &Path) -> Option<PathBuf> {
+    let dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Some(dir.canonicalize().ok()?.join(output.file_name()?))
+}
+
+/// Returns the first directory from `crate_dir` upwards that contains a `Cargo.nix`,
+/// stopping at the root of the repository.
+fn project_with_cargo_nix(crate_dir: &Path) -> Option<PathBuf> {
+    for dir in crate_dir.ancestors() {
+        if dir.join("Cargo.nix").is_file() {
+            return Some(dir.to_path_buf());
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+/// The crates of a `Cargo.nix` if it was generated by crate2nix and is not sharded.
+fn read_generated_crates(cargo_nix: &Path) -> Option<BTreeMap<String, CrateSummary>> {
+    if crate::render::check_generated_by_crate2nix(cargo_nix).is_err() {
+        debug!(
+            "Ignoring {}: not generated by crate2nix.",
+            cargo_nix.display()
+        );
+        return None;
+    }
+    crate::diff::read_crates(cargo_nix)
+        .map_err(|e| debug!("Ignoring {}: {:#}", cargo_nix.display(), e))
+        .ok()
+}
+
+/// Returns the nix expression for the `Cargo.nix` of the project at `crate_path` minus
+/// `path_in_project`, where `crate_path` is the rendered source path of a crate.
+fn cargo_nix_expr(crate_path: &str, path_in_project: &Path) -> Option<String> {
+    let suffix = path_in_project.to_string_lossy();
+    let strip = |path: &str| -> Option<String> {
+        if suffix.is_empty() {
+            return Some(path.trim_end_matches("/.").to_string());
+        }
+        path.strip_suffix(suffix.as_ref())
+            .and_then(|path| path.strip_suffix('/'))
+            .map(str::to_string)
+    };
+    if crate_path == "sourceRoot" {
+        return suffix
+            .is_empty()
+            .then(|| "(sourceRoot + \"/Cargo.nix\")".to_string());
+    }
+    if let Some(relative) = crate_path
+        .strip_prefix("(sourceRoot + \"")
+        .and_then(|path| path.strip_suffix("\")"))
+    {
+        return Some(format!("(sourceRoot + \"{}/Cargo.nix\")", strip(relative)?));
+    }
+    let base = strip(crate_path)?;
+    Some(if base.is_empty() || base == "." {
+        "./Cargo.nix".to_string()
+    } else {
+        format!("{}/Cargo.nix", base)
+    })
+}
+
+#[test]
+fn test_cargo_nix_expr() {
+    assert_eq!(
+        cargo_nix_expr("./third_party/foo/crates/a", Path::new("crates/a")).as_deref(),
+        Some("./third_party/foo/Cargo.nix")
+    );
+    assert_eq!(
+        cargo_nix_expr("./third_party/foo", Path::new("")).as_deref(),
+        Some("./third_party/foo/Cargo.nix")
+    );
+    assert_eq!(
+        cargo_nix_expr("(sourceRoot + \"/other/crates/a\")", Path::new("crates/a")).as_deref(),
+        Some("(sourceRoot + \"/other/Cargo.nix\")")
+    );
+    assert_eq!(
+        cargo_nix_expr("sourceRoot", Path::new("")).as_deref(),
+        Some("(sourceRoot + \"/Cargo.nix\")")
+    );
+    assert_eq!(cargo_nix_expr("./other/b", Path::new("crates/a")), None);
+}
+
+#[test]
+fn test_project_with_cargo_nix() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    let crate_dir = dir.join("other").join("crates").join("a");
+    std::fs::create_dir_all(&crate_dir).unwrap();
+    assert_eq!(project_with_cargo_nix(&crate_dir), None);
+
+    std::fs::write(dir.join("Cargo.nix"), "").unwrap();
+    std::fs::create_dir(dir.join("other").join(".git")).unwrap();
+    assert_eq!(project_with_cargo_nix(&crate_dir), None);
+
+    std::fs::write(dir.join("other").join("Cargo.nix"), "").unwrap();
+    assert_eq!(project_with_cargo_nix(&crate_dir), Some(dir.join("other")));
+
+    env.close();
+}
@@ -0,0 +1,841 @@
+//! Skipping regeneration if none of the inputs changed since the last run, see `--incremental`.
+//!
+//! The expensive parts of `crate2nix generate` are calling `cargo metadata` and prefetching.
+//! After a successful run, we record fingerprints of all files that influenced the output: the
+//! inputs of `cargo metadata` as in `metadata_cache`, the crate hashes, `crate2nix.json` and the
+//! outputs. The arguments, the crate2nix version and the version and environment of cargo are
+//! part of the fingerprint of the configuration. On the next run, we compare them before doing
+//! anything else.
+//!
+//! If an input changed, only the crates whose packages changed are resolved again, see
+//! `ResolvedCrates`, and only the crates whose resolution changed are rendered again, see
+//! `RenderedCrates`. The metadata and the crate hashes are cached anyway.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use cargo_metadata::{Package, PackageId};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::lock::{EncodableDependency, EncodableResolve};
+use crate::metadata::IndexedMetadata;
+use crate::resolve::CrateDerivation;
+use crate::{metadata_cache, BuildInfo, GenerateConfig, GenerateInfo};
+
+const CACHE_FILE_NAME: &str = ".crate2nix-cache.json";
+const RESOLVED_FILE_NAME: &str = ".crate2nix-resolved.json";
+const RENDERED_FILE_NAME: &str = ".crate2nix-rendered.json";
+
+/// The fingerprints of a previous generation.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerationCache {
+    /// Fingerprint of the crate2nix version, arguments and configuration.
+    pub config: String,
+    /// Fingerprints of input and output files by path. `None` for files that did not exist.
+    pub files: BTreeMap<PathBuf, Option<String>>,
+    /// The invocation and configuration of the generation, so that `crate2nix run` can
+    /// regenerate with them.
+    #[serde(default)]
+    pub generation: Option<(GenerateInfo, GenerateConfig)>,
+    /// The package ID of the root crate of the output.
+    #[serde(default)]
+    pub root_package_id: Option<PackageId>,
+    /// The binary targets of every workspace member in the output by member name.
+    #[serde(default)]
+    pub member_binaries: BTreeMap<String, Vec<String>>,
+}
+
+impl GenerationCache {
+    /// The path of the cache file for the given configuration.
+    pub fn path(config: &GenerateConfig) -> PathBuf {
+        output_dir(config).join(CACHE_FILE_NAME)
+    }
+
+    /// Returns true if the output of a previous run with the same inputs is still in place.
+    pub fn is_up_to_date(info: &GenerateInfo, config: &GenerateConfig) -> Result<bool, Error> {
+        Ok(Self::up_to_date(info, config)?.is_some())
+    }
+
+    /// Returns the cache of a previous run with the same inputs if its output is still in place.
+    fn up_to_date(
+        info: &GenerateInfo,
+        config: &GenerateConfig,
+    ) -> Result<Option<GenerationCache>, Error> {
+        let path = Self::path(config);
+        if !path.exists() {
+            debug!("No generation cache at {}.", path.to_string_lossy());
+            return Ok(None);
+        }
+        let Some(cache) = Self::read(&path)? else {
+            return Ok(None);
+        };
+
+        if cache.config != config_fingerprint(info, config)? {
+            debug!("Configuration changed since the last generation.");
+            return Ok(None);
+        }
+        for (file, fingerprint) in &cache.files {
+            if fingerprint_path(file) != *fingerprint {
+                debug!(
+                    "{} changed since the last generation.",
+                    file.to_string_lossy()
+                );
+                return Ok(None);
+            }
+        }
+        Ok(Some(cache).filter(|cache| !cache.files.is_empty()))
+    }
+
+    /// Returns the invocation and configuration of the last generation of `output`,
+    /// if they were recorded.
+    ///
+    /// The version in the returned info is the one of this crate2nix instance.
+    pub fn previous_generation(
+        output: &Path,
+    ) -> Result<Option<(GenerateInfo, GenerateConfig)>, Error> {
+        let path = output_dir_of(output).join(CACHE_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Self::read(&path)?
+            .and_then(|cache| cache.generation)
+            .filter(|(_, config)| config.output == output)
+            .map(|(info, config)| {
+                let info = GenerateInfo {
+                    crate2nix_version: GenerateInfo::default().crate2nix_version,
+                    ..info
+                };
+                (info, config)
+            }))
+    }
+
+    fn read(path: &Path) -> Result<Option<GenerationCache>, Error> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("while reading {}", path.to_string_lossy()))?;
+        let cache = serde_json::from_str::<GenerationCache>(&content).ok();
+        if cache.is_none() {
+            debug!(
+                "Ignoring unreadable generation cache {}.",
+                path.to_string_lossy()
+            );
+        }
+        Ok(cache)
+    }
+
+    /// Regenerates the output unless it is up to date. Returns the cache of the output.
+    pub fn regenerate_if_stale(
+        info: &GenerateInfo,
+        config: &GenerateConfig,
+    ) -> Result<GenerationCache, Error> {
+        if let Some(cache) = Self::up_to_date(info, config)? {
+            debug!("{} is up to date.", config.output.to_string_lossy());
+            return Ok(cache);
+        }
+        let build_info = BuildInfo::for_config(info, config)?;
+        crate::render::write_outputs(&build_info, config)?;
+        let cache = GenerationCache::new(info, config, &build_info)?;
+        cache.write(config)?;
+        Ok(cache)
+    }
+
+    /// Returns the fingerprints for all inputs and outputs of the given build.
+    pub fn new(
+        info: &GenerateInfo,
+        config: &GenerateConfig,
+        build_info: &BuildInfo,
+    ) -> Result<GenerationCache, Error> {
+        let mut paths = vec![config.output.clone(), config.crate_hashes_json.clone()];
+        // The files of the split layout, see `--split-crate-hashes`.
+        paths.extend(
+            crate::crate_hashes::files(config)
+                .into_iter()
+                .filter(|file| file != &config.crate_hashes_json),
+        );
+        paths.push(
+            config
+                .crate_hashes_json
+                .parent()
+                .unwrap_or_else(|| Path::new("."))
+                .join("crate2nix.json"),
+        );
+        let local_manifests: Vec<&Path> = build_info
+            .indexed_metadata
+            .pkgs_by_id
+            .values()
+            .filter(|package| package.source.is_none())
+            .map(|package| package.manifest_path.as_std_path())
+            .collect();
+        for cargo_toml in &config.cargo_toml {
+            let manifest_dir = cargo_toml.parent().unwrap_or_else(|| Path::new("."));
+            let lock_file = manifest_dir
+                .ancestors()
+                .map(|dir| dir.join("Cargo.lock"))
+                .find(|lock_file| lock_file.exists());
+            let workspace_root = lock_file
+                .as_deref()
+                .and_then(Path::parent)
+                .unwrap_or(manifest_dir);
+            paths.extend(metadata_cache::workspace_input_files(
+                cargo_toml,
+                workspace_root,
+                local_manifests.iter().copied(),
+            ));
+            if let Some(lockfile) = &config.lockfile {
+                paths.push(lockfile.clone());
+            }
+        }
+        if config.shard {
+            paths.push(output_dir(config).join(config.shard_directory()));
+        }
+        if config.crates_json {
+            paths.push(output_dir(config).join(config.crates_json_file()));
+        }
+        if config.overlay {
+            paths.push(output_dir(config).join(crate::render::OVERLAY_FILE_NAME));
+        }
+        if config.release_nix {
+            paths.push(output_dir(config).join(crate::render::RELEASE_FILE_NAME));
+        }
+        // The Cargo.nix files of other projects whose crate definitions are used.
+        for project in build_info.imported_cargo_nix.keys() {
+            paths.push(output_dir(config).join(project).join("Cargo.nix"));
+        }
+        // Other files of local crates, e.g. a `build.rs` that is only found by cargo.
+        for manifest_path in &local_manifests {
+            if let Some(package_dir) = manifest_path.parent() {
+                paths.push(package_dir.to_path_buf());
+            }
+        }
+
+        Ok(GenerationCache {
+            config: config_fingerprint(info, config)?,
+            files: paths
+                .into_iter()
+                .map(|path| {
+                    let fingerprint = fingerprint_path(&path);
+                    (path, fingerprint)
+                })
+                .collect(),
+            generation: Some((info.clone(), config.clone())),
+            root_package_id: build_info.root_package_id.clone(),
+            member_binaries: build_info.member_binaries.clone(),
+        })
+    }
+
+    /// The attribute of the generated `cargo_nix` that builds `package`, the workspace member
+    /// with the binary `bin` or the root crate, in that order. `None` if neither is given and
+    /// there is no root crate.
+    pub fn build_attr(
+        &self,
+        cargo_nix: &Path,
+        package: Option<&str>,
+        bin: Option<&str>,
+    ) -> Result<Option<String>, Error> {
+        let package = match (package, bin) {
+            (Some(package), _) => Some(package.to_string()),
+            (None, Some(bin)) => Some(self.member_with_binary(cargo_nix, bin)?),
+            (None, None) => None,
+        };
+        Ok(match package {
+            Some(package) => Some(format!("workspaceMembers.{}.build", package)),
+            None if self.root_package_id.is_some() => Some("rootCrate.build".to_string()),
+            None => None,
+        })
+    }
+
+    /// The workspace member with the binary `bin` in the generated `cargo_nix`.
+    fn member_with_binary(&self, cargo_nix: &Path, bin: &str) -> Result<String, Error> {
+        let members: Vec<&str> = self
+            .member_binaries
+            .iter()
+            .filter(|(_, binaries)| binaries.iter().any(|b| b == bin))
+            .map(|(member, _)| member.as_str())
+            .collect();
+        match members.as_slice() {
+            [member] => Ok(member.to_string()),
+            [] => bail!(
+                "No workspace member in {} has a binary named {}.",
+                cargo_nix.to_string_lossy(),
+                bin
+            ),
+            _ => bail!(
+                "The workspace members {} all have a binary named {}, select one with --package.",
+                members.join(", "),
+                bin
+            ),
+        }
+    }
+
+    /// Writes the cache next to the output.
+    pub fn write(&self, config: &GenerateConfig) -> Result<(), Error> {
+        let path = Self::path(config);
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("while writing {}", path.to_string_lossy()))
+    }
+}
+
+/// The crates resolved by the last generation with `--incremental`, see `BuildInfo::new`.
+///
+/// Every crate is stored with the fingerprints of its package. A crate is only resolved again if
+/// they changed, or if one of the packages it depends on changed since it refers to their IDs and
+/// versions.
+pub(crate) struct ResolvedCrates {
+    /// Fingerprint of the invocation, the configuration and `crate2nix.json`.
+    config: String,
+    /// The fingerprints of the packages in the metadata of this generation.
+    fingerprints: HashMap<PackageId, PackageFingerprints>,
+    /// The crates of the last generation that are still up to date.
+    reusable: HashMap<PackageId, CrateDerivation>,
+}
+
+/// The fingerprints of the package that a crate was resolved from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PackageFingerprints {
+    /// The sha256 of the entry of the package in `Cargo.lock`, `None` if it has none.
+    source: Option<String>,
+    /// The sha256 of the package and its node in the metadata and of the manifest of a local
+    /// package.
+    manifest: String,
+}
+
+/// The content of the file of `ResolvedCrates`.
+#[derive(Serialize, Deserialize)]
+struct StoredCrates<'a> {
+    config: String,
+    crates: BTreeMap<PackageId, StoredCrate<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredCrate<'a> {
+    fingerprints: PackageFingerprints,
+    #[serde(rename = "crate")]
+    crate_derivation: Cow<'a, CrateDerivation>,
+}
+
+impl ResolvedCrates {
+    /// Fingerprints the packages of `metadata` and reads the crates of the last generation with
+    /// the same configuration.
+    pub(crate) fn read(
+        info: &GenerateInfo,
+        config: &GenerateConfig,
+        crate2nix_json: &crate::config::Config,
+        metadata: &IndexedMetadata,
+    ) -> Result<ResolvedCrates, Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(config_fingerprint(info, config)?);
+        hasher.update(serde_json::to_vec(crate2nix_json)?);
+        hasher.update(serde_json::to_vec(&metadata.target_directories)?);
+        let config_fingerprint = hex::encode(hasher.finalize());
+        let fingerprints = package_fingerprints(config, metadata)?;
+
+        let path = output_dir(config).join(RESOLVED_FILE_NAME);
+        let previous = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<StoredCrates>(&content)
+                .inspect_err(|_| {
+                    debug!(
+                        "Ignoring unreadable resolved crates {}.",
+                        path.to_string_lossy()
+                    )
+                })
+                .ok()
+                .filter(|stored| stored.config == config_fingerprint)
+                .map(|stored| stored.crates)
+                .unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+
+        let changed: HashSet<&PackageId> = fingerprints
+            .iter()
+            .filter(|(id, fingerprints)| {
+                previous.get(*id).map(|stored| &stored.fingerprints) != Some(*fingerprints)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        let dependents_of_changed = metadata
+            .nodes_by_id
+            .values()
+            .filter(|node| node.deps.iter().any(|dep| changed.contains(&dep.pkg)))
+            .map(|node| &node.id);
+        let stale: HashSet<&PackageId> = changed
+            .iter()
+            .copied()
+            .chain(dependents_of_changed)
+            .collect();
+        let is_root_or_workspace_member = |id: &PackageId| {
+            metadata
+                .root
+                .iter()
+                .chain(&metadata.workspace_members)
+                .any(|member| member == id)
+        };
+        let reusable: HashMap<PackageId, CrateDerivation> = previous
+            .into_iter()
+            .filter(|(id, stored)| {
+                fingerprints.contains_key(id)
+                    && !stale.contains(id)
+                    && stored.crate_derivation.is_root_or_workspace_member
+                        == is_root_or_workspace_member(id)
+            })
+            .map(|(id, stored)| (id, stored.crate_derivation.into_owned()))
+            .collect();
+        debug!(
+            "Reusing {} of {} resolved crates.",
+            reusable.len(),
+            fingerprints.len()
+        );
+
+        Ok(ResolvedCrates {
+            config: config_fingerprint,
+            fingerprints,
+            reusable,
+        })
+    }
+
+    /// The crate of the last generation for `package_id` if it is still up to date.
+    pub(crate) fn reusable(
+        &self,
+        package_id: &PackageId,
+        metadata: &IndexedMetadata,
+    ) -> Option<CrateDerivation> {
+        let mut crate_derivation = self.reusable.get(package_id)?.clone();
+        crate_derivation.intern(&metadata.interner);
+        Some(crate_derivation)
+    }
+
+    /// Stores the resolved `crates` for the next generation.
+    ///
+    /// Crates that could not be resolved are left out so that they are tried again.
+    pub(crate) fn write(
+        &self,
+        config: &GenerateConfig,
+        crates: &[CrateDerivation],
+    ) -> Result<(), Error> {
+        let stored = StoredCrates {
+            config: self.config.clone(),
+            crates: crates
+                .iter()
+                .filter(|crate_derivation| crate_derivation.problem.is_none())
+                .filter_map(|crate_derivation| {
+                    let package_id = &*crate_derivation.package_id;
+                    let stored = StoredCrate {
+                        fingerprints: self.fingerprints.get(package_id)?.clone(),
+                        crate_derivation: Cow::Borrowed(crate_derivation),
+                    };
+                    Some((package_id.clone(), stored))
+                })
+                .collect(),
+        };
+        let path = output_dir(config).join(RESOLVED_FILE_NAME);
+        std::fs::write(&path, serde_json::to_vec(&stored)?)
+            .with_context(|| format!("while writing {}", path.to_string_lossy()))
+    }
+}
+
+/// The fingerprints of the packages of `metadata`, with their entries in the lock files of the
+/// configuration.
+fn package_fingerprints(
+    config: &GenerateConfig,
+    metadata: &IndexedMetadata,
+) -> Result<HashMap<PackageId, PackageFingerprints>, Error> {
+    let mut lock_files = Vec::new();
+    for cargo_toml in &config.cargo_toml {
+        let lock_file = match &config.lockfile {
+            Some(lockfile) => Some(lockfile.clone()),
+            None => crate::find_lock_file_for(cargo_toml),
+        };
+        if let Some(lock_file) = lock_file.filter(|lock_file| lock_file.exists()) {
+            lock_files.push(EncodableResolve::load_lock_file(&lock_file)?);
+        }
+    }
+    let mut entries: HashMap<&PackageId, &EncodableDependency> = HashMap::new();
+    for lock_file in &lock_files {
+        entries.extend(lock_file.entries_by_package_id(metadata.pkgs_by_id.values()));
+    }
+
+    let sha256 = |parts: &[&[u8]]| {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hex::encode(hasher.finalize())
+    };
+    metadata
+        .pkgs_by_id
+        .iter()
+        .map(|(id, package)| {
+            let source = entries
+                .get(id)
+                .map(|entry| serde_json::to_vec(entry).map(|entry| sha256(&[&entry])))
+                .transpose()?;
+            let manifest = sha256(&[
+                &serde_json::to_vec(package)?,
+                &serde_json::to_vec(&metadata.nodes_by_id.get(id))?,
+                &local_manifest(package),
+            ]);
+            Ok((id.clone(), PackageFingerprints { source, manifest }))
+        })
+        .collect()
+}
+
+/// The content of the manifest of a local package. The others cannot change without their
+/// entry in `Cargo.lock`.
+fn local_manifest(package: &Package) -> Vec<u8> {
+    if package.source.is_some() {
+        return Vec::new();
+    }
+    std::fs::read(&package.manifest_path).unwrap_or_default()
+}
+
+/// The crates rendered by the last generation with `--incremental`, see `render::write_outputs`.
+///
+/// A crate is stored by the hash of its template and everything it is rendered from, so it is
+/// rendered again exactly if any of that changed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RenderedCrates {
+    /// The crate2nix version whose templates rendered the crates.
+    crate2nix_version: String,
+    /// The crates rendered for this generation by hash.
+    rendered: BTreeMap<String, String>,
+    /// The crates rendered by the last generation by hash.
+    #[serde(skip)]
+    previous: BTreeMap<String, String>,
+}
+
+impl RenderedCrates {
+    /// Reads the crates rendered by the last generation with the same crate2nix version.
+    pub(crate) fn read(config: &GenerateConfig) -> RenderedCrates {
+        let crate2nix_version = GenerateInfo::default().crate2nix_version;
+        let path = output_dir(config).join(RENDERED_FILE_NAME);
+        let previous = std::fs::read(&path)
+            .ok()
+            .and_then(|content| serde_json::from_slice::<RenderedCrates>(&content).ok())
+            .filter(|stored| stored.crate2nix_version == crate2nix_version)
+            .map(|stored| stored.rendered)
+            .unwrap_or_default();
+        RenderedCrates {
+            crate2nix_version,
+            rendered: BTreeMap::new(),
+            previous,
+        }
+    }
+
+    /// Returns the crate that `render` renders with `template` from `context` and the
+    /// `shared` context of all crates, or the same crate of the last generation.
+    pub(crate) fn render<C: Serialize>(
+        &mut self,
+        template: &str,
+        shared: &[u8],
+        context: &C,
+        render: impl FnOnce() -> Result<String, Error>,
+    ) -> Result<&str, Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(template);
+        hasher.update(shared);
+        hasher.update(serde_json::to_vec(context)?);
+        let hash = hex::encode(hasher.finalize());
+        let rendered = match self.previous.remove(&hash) {
+            Some(rendered) => rendered,
+            None => render()?,
+        };
+        Ok(self.rendered.entry(hash).or_insert(rendered))
+    }
+
+    /// Stores the crates rendered for this generation for the next one.
+    pub(crate) fn write(&self, config: &GenerateConfig) -> Result<(), Error> {
+        let path = output_dir(config).join(RENDERED_FILE_NAME);
+        std::fs::write(&path, serde_json::to_vec(self)?)
+            .with_context(|| format!("while writing {}", path.to_string_lossy()))
+    }
+}
+
+fn output_dir(config: &GenerateConfig) -> PathBuf {
+    output_dir_of(&config.output)
+}
+
+fn output_dir_of(output: &Path) -> PathBuf {
+    match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// The fingerprint of the invocation, the configuration and the cargo that resolves.
+fn config_fingerprint(info: &GenerateInfo, config: &GenerateConfig) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_string(info)?);
+    hasher.update(serde_json::to_string(config)?);
+    if !config.lockfile_only {
+        hasher.update(metadata_cache::cargo_environment().unwrap_or_default());
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// The sha256 of the content of a file, or of the names and contents of the entries of a
+/// directory. Subdirectories of a directory only contribute their names.
+pub(crate) fn fingerprint_path(path: &Path) -> Option<String> {
+    let mut hasher = Sha256::new();
+    if path.is_dir() {
+        let mut entries: Vec<_> = std::fs::read_dir(path)
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|entry| {
+                entry.file_name().is_some_and(|name| {
+                    ![CACHE_FILE_NAME, RESOLVED_FILE_NAME, RENDERED_FILE_NAME]
+                        .iter()
+                        .any(|cache_file| name == *cache_file)
+                })
+            })
+            .collect();
+        entries.sort();
+        for entry in entries {
+            let name = entry.file_name().unwrap_or_default().to_string_lossy();
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name.as_bytes());
+            if entry.is_dir() {
+                hasher.update(b"/");
+            } else {
+                let content = std::fs::read(&entry).unwrap_or_default();
+                hasher.update((content.len() as u64).to_le_bytes());
+                hasher.update(content);
+            }
+        }
+    } else {
+        hasher.update(std::fs::read(path).ok()?);
+    }
+    Some(hex::encode(hasher.finalize()))
+}
+
+#[test]
+fn test_fingerprint_path() {
+    let dir = tempdir::TempDir::new("crate2nix_incremental").expect("temp dir");
+    let file = dir.path().join("Cargo.toml");
+
+    assert_eq!(fingerprint_path(&file), None);
+    let empty_dir = fingerprint_path(dir.path());
+
+    std::fs::write(&file, "[package]").unwrap();
+    let first = fingerprint_path(&file);
+    assert!(first.is_some());
+    assert_eq!(fingerprint_path(&file), first);
+    assert_ne!(fingerprint_path(dir.path()), empty_dir);
+
+    let with_file = fingerprint_path(dir.path());
+    std::fs::write(&file, "[package]\nname = \"x\"").unwrap();
+    assert_ne!(fingerprint_path(&file), first);
+    // Directories also change with the contents of their files.
+    assert_ne!(fingerprint_path(dir.path()), with_file);
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_cargo_configuration_invalidates() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    env.add_package_and_node("main").make_root();
+    let config = GenerateConfig {
+        cargo_toml: vec![dir.join("member").join("Cargo.toml")],
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..crate::test::generate_config()
+    };
+    let info = GenerateInfo::default();
+    let build_info = BuildInfo::new(&info, &config, env.indexed_metadata()).unwrap();
+    GenerationCache::new(&info, &config, &build_info)
+        .unwrap()
+        .write(&config)
+        .unwrap();
+    assert!(GenerationCache::is_up_to_date(&info, &config).unwrap());
+
+    // E.g. a source replacement above the workspace.
+    std::fs::create_dir(dir.join(".cargo")).unwrap();
+    std::fs::write(
+        dir.join(".cargo").join("config.toml"),
+        "[source.crates-io]\nreplace-with = \"vendored-sources\"\n",
+    )
+    .unwrap();
+    assert!(!GenerationCache::is_up_to_date(&info, &config).unwrap());
+    env.close();
+}
+
+#[test]
+fn test_resolve_changed_crates_only() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    let crates_io = cargo_metadata::Source {
+        repr: "registry+https://github.com/rust-lang/crates.io-index".to_string(),
+    };
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    for name in ["a", "b"] {
+        main.add_dependency(name)
+            .version_and_package_id("1.0.0")
+            .update_package(|p| p.source = Some(crates_io.clone()));
+    }
+    let config = GenerateConfig {
+        cargo_toml: vec![dir.join("Cargo.toml")],
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        incremental: true,
+        ..crate::test::generate_config()
+    };
+    let lock_file = |b_checksum: &str| {
+        let entry = |name: &str, checksum: &str| {
+            format!(
+                "[[package]]\nname = \"{}\"\nversion = \"1.0.0\"\nsource = \"{}\"\n\
+                 checksum = \"{}\"\n\n",
+                name, crates_io.repr, checksum
+            )
+        };
+        let lock_file = format!(
+            "version = 3\n\n{}{}[[package]]\nname = \"main\"\nversion = \"0.1.0\"\n\
+             dependencies = [\"a\", \"b\"]\n",
+            entry("a", "aaaa"),
+            entry("b", b_checksum)
+        );
+        std::fs::write(dir.join("Cargo.lock"), lock_file).unwrap();
+    };
+    let info = GenerateInfo::default();
+    let metadata = env.indexed_metadata();
+    let crate2nix_json = crate::config::Config::default();
+    let reusable = || {
+        let previous = ResolvedCrates::read(&info, &config, &crate2nix_json, &metadata).unwrap();
+        let mut reusable: Vec<String> = previous
+            .reusable
+            .values()
+            .map(|c| c.crate_name.to_string())
+            .collect();
+        reusable.sort();
+        reusable
+    };
+
+    lock_file("bbbb");
+    assert!(reusable().is_empty());
+    let build_info = BuildInfo::new(&info, &config, env.indexed_metadata()).unwrap();
+    assert_eq!(reusable(), ["a", "b", "main"]);
+
+    // The crate of the changed entry and the crates that depend on it are resolved again.
+    lock_file("cccc");
+    assert_eq!(reusable(), ["a"]);
+    let rebuilt = BuildInfo::new(&info, &config, env.indexed_metadata()).unwrap();
+    assert_eq!(
+        crate::render::render_cargo_nix(&rebuilt).unwrap(),
+        crate::render::render_cargo_nix(&build_info).unwrap()
+    );
+    assert_eq!(reusable(), ["a", "b", "main"]);
+
+    // Another configuration resolves everything again.
+    let other_config = GenerateConfig {
+        keep_going: true,
+        ..config.clone()
+    };
+    let previous = ResolvedCrates::read(&info, &other_config, &crate2nix_json, &metadata).unwrap();
+    assert!(previous.reusable.is_empty());
+    env.close();
+}
+
+#[test]
+fn test_render_changed_crates_only() {
+    let config = GenerateConfig {
+        output: tempdir::TempDir::new("crate2nix_incremental")
+            .expect("temp dir")
+            .into_path()
+            .join("Cargo.nix"),
+        ..crate::test::generate_config()
+    };
+    let mut renders = 0;
+    let mut render = |rendered: &mut RenderedCrates, context: &str| {
+        rendered
+            .render("crate.nix.tera", &[], &context, || {
+                renders += 1;
+                Ok(format!("rendered {}", context))
+            })
+            .unwrap()
+            .to_string()
+    };
+
+    let mut rendered = RenderedCrates::read(&config);
+    assert_eq!(render(&mut rendered, "a"), "rendered a");
+    assert_eq!(render(&mut rendered, "b"), "rendered b");
+    rendered.write(&config).unwrap();
+
+    let mut rendered = RenderedCrates::read(&config);
+    assert_eq!(render(&mut rendered, "a"), "rendered a");
+    assert_eq!(render(&mut rendered, "c"), "rendered c");
+    assert_eq!(renders, 3);
+    rendered.write(&config).unwrap();
+
+    // Only the crates of the last generation are kept.
+    let rendered = RenderedCrates::read(&config);
+    assert_eq!(rendered.previous.len(), 2);
+    std::fs::remove_dir_all(config.output.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_build_attr() {
+    let cargo_nix = Path::new("Cargo.nix");
+    // A virtual workspace.
+    let generation = GenerationCache {
+        member_binaries: BTreeMap::from([
+            (
+                "cli".to_string(),
+                vec!["cli".to_string(), "tool".to_string()],
+            ),
+            (
+                "server".to_string(),
+                vec!["server".to_string(), "tool".to_string()],
+            ),
+            ("core".to_string(), vec![]),
+        ]),
+        ..GenerationCache::default()
+    };
+    let build_attr = |package: Option<&str>, bin: Option<&str>| {
+        generation
+            .build_attr(cargo_nix, package, bin)
+            .map_err(|e| e.to_string())
+    };
+    assert_eq!(build_attr(None, None), Ok(None));
+    assert_eq!(
+        build_attr(None, Some("server")),
+        Ok(Some("workspaceMembers.server.build".to_string()))
+    );
+    assert_eq!(
+        build_attr(Some("cli"), Some("tool")),
+        Ok(Some("workspaceMembers.cli.build".to_string()))
+    );
+    assert_eq!(
+        build_attr(None, Some("tool")),
+        Err(
+            "The workspace members cli, server all have a binary named tool, select one with \
+             --package."
+                .to_string()
+        )
+    );
+    assert_eq!(
+        build_attr(None, Some("missing")),
+        Err("No workspace member in Cargo.nix has a binary named missing.".to_string())
+    );
+
+    // The binary of another member than the root crate.
+    let with_root = GenerationCache {
+        root_package_id: Some(PackageId {
+            repr: "cli 0.1.0 (path+file:///workspace/cli)".to_string(),
+        }),
+        ..generation
+    };
+    assert_eq!(
+        with_root.build_attr(cargo_nix, None, None).unwrap(),
+        Some("rootCrate.build".to_string())
+    );
+    assert_eq!(
+        with_root
+            .build_attr(cargo_nix, None, Some("server"))
+            .unwrap(),
+        Some("workspaceMembers.server.build".to_string())
+    );
+}
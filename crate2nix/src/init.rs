@@ -0,0 +1,255 @@
+//! Bootstrapping crate2nix in an existing cargo project, see `crate2nix init`.
+//!
+//! The starter files are written once and belong to the user afterwards: existing files are
+//! only replaced with `force`. `Cargo.nix` itself is left to `crate2nix generate`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+
+use crate::render::{InitNix, INIT_DEFAULT_NIX, INIT_FLAKE_NIX};
+use crate::BuildInfo;
+
+/// The starter `crate2nix.json`, without any overrides yet.
+const CRATE2NIX_JSON: &str = "{\n  \"sources\": {},\n  \"crateOverrides\": {}\n}\n";
+
+/// The entries that `.gitignore` should have: the result links of nix and the target directory
+/// of cargo, each with the lines that already cover it.
+const GITIGNORE_ENTRIES: &[(&str, &[&str])] = &[
+    ("/result*", &["result", "/result", "result*", "/result*"]),
+    ("/target", &["target", "/target", "target/", "/target/"]),
+];
+
+/// The lines of a `.gitignore` that would ignore the generated files, which must be committed.
+const IGNORING_GENERATED: &[&str] = &[
+    "Cargo.nix",
+    "/Cargo.nix",
+    "*.nix",
+    "crate-hashes.json",
+    "/crate-hashes.json",
+    "*.json",
+];
+
+/// How to set up the project, see `crate2nix init`.
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    /// Write a `flake.nix` instead of a `default.nix`.
+    pub flake: bool,
+    /// Replace existing starter files.
+    pub force: bool,
+    /// Add the missing entries to `.gitignore` instead of only listing them.
+    pub gitignore: bool,
+}
+
+/// What `init` did and what is left to the user.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InitPlan {
+    /// The files that were written.
+    pub written: Vec<PathBuf>,
+    /// The files that existed already and were kept.
+    pub kept: Vec<PathBuf>,
+    /// The entries that `.gitignore` lacks, or that were added with `InitOptions::gitignore`.
+    pub gitignore_entries: Vec<String>,
+    /// Whether the entries were added to `.gitignore`.
+    pub gitignore_written: bool,
+    /// The lines of `.gitignore` that ignore files that must be committed.
+    pub ignoring_generated: Vec<String>,
+    /// The crates that link native libraries, whose builds may need `buildInputs`.
+    pub native_crates: Vec<String>,
+}
+
+/// Writes the starter files for the project of `build_info` into `project_dir`.
+pub fn init(
+    build_info: &BuildInfo,
+    project_dir: &Path,
+    options: &InitOptions,
+) -> Result<InitPlan, Error> {
+    let mut plan = InitPlan::default();
+    let context = init_nix(build_info, project_dir);
+    let (nix_file, template) = if options.flake {
+        ("flake.nix", &INIT_FLAKE_NIX)
+    } else {
+        ("default.nix", &INIT_DEFAULT_NIX)
+    };
+    let nix_content = template.render(&context)?;
+    for (file_name, content) in [
+        ("crate2nix.json", CRATE2NIX_JSON),
+        (nix_file, nix_content.as_str()),
+    ] {
+        let path = project_dir.join(file_name);
+        if path.exists() && !options.force {
+            plan.kept.push(path);
+            continue;
+        }
+        std::fs::write(&path, content)
+            .with_context(|| format!("while writing {}", path.to_string_lossy()))?;
+        plan.written.push(path);
+    }
+
+    let gitignore_path = project_dir.join(".gitignore");
+    let gitignore = std::fs::read_to_string(&gitignore_path).unwrap_or_default();
+    let lines: Vec<&str> = gitignore.lines().map(str::trim).collect();
+    plan.gitignore_entries = GITIGNORE_ENTRIES
+        .iter()
+        .filter(|(_, covering)| !lines.iter().any(|line| covering.contains(line)))
+        .map(|(entry, _)| entry.to_string())
+        .collect();
+    plan.ignoring_generated = lines
+        .iter()
+        .filter(|line| IGNORING_GENERATED.contains(line))
+        .map(|line| line.to_string())
+        .collect();
+    if options.gitignore && !plan.gitignore_entries.is_empty() {
+        let mut content = gitignore;
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        for entry in &plan.gitignore_entries {
+            content.push_str(entry);
+            content.push('\n');
+        }
+        std::fs::write(&gitignore_path, content)
+            .with_context(|| format!("while writing {}", gitignore_path.to_string_lossy()))?;
+        plan.gitignore_written = true;
+    }
+
+    plan.native_crates = build_info
+        .crates
+        .iter()
+        .filter(|c| !c.is_root_or_workspace_member)
+        .filter_map(|c| {
+            let links = c.links.as_ref()?;
+            Some(format!("{} (links {})", c.crate_name, links))
+        })
+        .collect();
+    plan.native_crates.sort();
+    plan.native_crates.dedup();
+    Ok(plan)
+}
+
+/// The context of the starter nix files.
+fn init_nix(build_info: &BuildInfo, project_dir: &Path) -> InitNix {
+    let root_name = build_info
+        .root_package_id
+        .as_ref()
+        .and_then(|root| build_info.indexed_metadata.pkgs_by_id.get(root))
+        .map(|package| package.name.clone());
+    let project = root_name.clone().unwrap_or_else(|| {
+        project_dir
+            .canonicalize()
+            .ok()
+            .and_then(|dir| {
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .unwrap_or_else(|| "project".to_string())
+    });
+    let default_member = root_name.or_else(|| {
+        let mut with_binaries = build_info.member_binaries.keys();
+        match (with_binaries.next(), with_binaries.next()) {
+            (Some(only), None) => Some(only.clone()),
+            _ => None,
+        }
+    });
+    InitNix {
+        project,
+        cargo_nix: build_info.config.output.file_name().map_or_else(
+            || "Cargo.nix".to_string(),
+            |name| name.to_string_lossy().to_string(),
+        ),
+        members: build_info.workspace_members.keys().cloned().collect(),
+        default_member,
+    }
+}
+
+impl InitPlan {
+    /// The plan as text for the terminal.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for path in &self.written {
+            text.push_str(&format!("Wrote {}.\n", path.to_string_lossy()));
+        }
+        for path in &self.kept {
+            text.push_str(&format!(
+                "Kept the existing {}, use --force to replace it.\n",
+                path.to_string_lossy()
+            ));
+        }
+        if !self.gitignore_entries.is_empty() {
+            if self.gitignore_written {
+                text.push_str("Added to .gitignore:\n");
+            } else {
+                text.push_str("Add to .gitignore, or rerun with --gitignore:\n");
+            }
+            for entry in &self.gitignore_entries {
+                text.push_str(&format!("    {}\n", entry));
+            }
+        }
+        for line in &self.ignoring_generated {
+            text.push_str(&format!(
+                "Remove `{}` from .gitignore: Cargo.nix and crate-hashes.json must be committed.\n",
+                line
+            ));
+        }
+        if !self.native_crates.is_empty() {
+            text.push_str(
+                "These crates link native libraries. If their builds fail, add the libraries to \
+                 their buildInputs in the crateOverrides of crate2nix.json:\n",
+            );
+            for name in &self.native_crates {
+                text.push_str(&format!("    {}\n", name));
+            }
+        }
+        text.push_str("Next, run `crate2nix generate` to create Cargo.nix.\n");
+        text
+    }
+}
+
+#[test]
+fn test_init() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    env.add_package_and_node("main").make_root();
+    let config = crate::GenerateConfig {
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..crate::test::generate_config()
+    };
+    let build_info = BuildInfo::new(
+        &crate::GenerateInfo::default(),
+        &config,
+        env.indexed_metadata(),
+    )
+    .unwrap();
+    std::fs::write(dir.join(".gitignore"), "target\n*.nix").unwrap();
+
+    let plan = init(&build_info, &dir, &InitOptions::default()).unwrap();
+    assert_eq!(
+        plan.written,
+        vec![dir.join("crate2nix.json"), dir.join("default.nix")]
+    );
+    assert_eq!(plan.gitignore_entries, vec!["/result*"]);
+    assert_eq!(plan.ignoring_generated, vec!["*.nix"]);
+    let default_nix = std::fs::read_to_string(dir.join("default.nix")).unwrap();
+    assert!(default_nix.contains("cargoNix = pkgs.callPackage ./Cargo.nix { };"));
+    assert!(default_nix.contains("\"main\" = cargoNix.workspaceMembers.\"main\".build;"));
+    let crate2nix_json: crate::config::Config =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("crate2nix.json")).unwrap())
+            .unwrap();
+    assert!(crate2nix_json.crate_overrides.is_empty());
+
+    let options = InitOptions {
+        flake: true,
+        gitignore: true,
+        ..Default::default()
+    };
+    let plan = init(&build_info, &dir, &options).unwrap();
+    assert_eq!(plan.kept, vec![dir.join("crate2nix.json")]);
+    assert_eq!(plan.written, vec![dir.join("flake.nix")]);
+    let flake_nix = std::fs::read_to_string(dir.join("flake.nix")).unwrap();
+    assert!(flake_nix.contains("default = cargoNix.workspaceMembers.\"main\".build;"));
+    assert_eq!(
+        std::fs::read_to_string(dir.join(".gitignore")).unwrap(),
+        "target\n*.nix\n/result*\n"
+    );
+    env.close();
+}
@@ -11,36 +11,80 @@
 use std::env;
 use std::path::PathBuf;
 use std::{
-    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     path::Path,
 };
 
+use anyhow::bail;
 use anyhow::format_err;
 use anyhow::Context;
 use anyhow::Error;
 use cargo_metadata::Metadata;
+use cargo_metadata::Package;
 use cargo_metadata::PackageId;
-use metadata::MergedMetadata;
+use cargo_platform::Platform;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::crate_hashes::CrateHashesFormat;
+use crate::error::Crate2NixError;
+use crate::incremental::fingerprint_path;
 use crate::metadata::IndexedMetadata;
-use crate::resolve::{CrateDerivation, ResolvedSource};
+use crate::platforms::TargetPlatform;
+use crate::prefetch::PrefetchableSource;
+use crate::resolve::{CrateDerivation, ResolvedDependency, ResolvedSource};
+use crate::rust_toolchain::RustToolchain;
 use itertools::Itertools;
+use log::{debug, warn};
+use rayon::prelude::*;
 use resolve::CratesIoSource;
 
+pub mod build_plan;
+pub mod build_scripts;
+pub mod cache_manifest;
+mod cargo_config;
 mod command;
 pub mod config;
+pub mod crate_hashes;
+pub mod crates_json;
+pub mod diff;
+pub mod duplicates;
+mod editions;
+pub mod error;
+pub mod features;
+pub mod graph;
+mod imports;
+pub mod incremental;
+pub mod init;
+pub mod licenses;
 mod lock;
+mod lockfile_metadata;
+pub mod lockfile_profiles;
+mod manifest_hints;
 mod metadata;
+mod metadata_cache;
 pub mod nix_build;
+mod no_std;
+pub mod outdated;
+mod platforms;
 mod prefetch;
+mod profiles;
+mod progress;
+mod proxy;
+mod python_modules;
+mod registry;
 pub mod render;
+pub mod report;
 mod resolve;
+mod rust_toolchain;
+mod shared_lists;
 pub mod sources;
 #[cfg(test)]
 pub mod test;
+pub mod test_templates;
+pub mod upgrade;
 pub mod util;
+mod vendor;
 
 /// The resolved build info and the input for rendering the build.nix.tera template.
 #[derive(Debug, Deserialize, Serialize)]
@@ -49,6 +93,10 @@ pub struct BuildInfo {
     pub root_package_id: Option<PackageId>,
     /// Workspaces member package IDs by package names.
     pub workspace_members: BTreeMap<String, PackageId>,
+    /// The package names of the workspace members whose directory has another name by directory
+    /// name.
+    #[serde(default)]
+    pub workspace_member_aliases: BTreeMap<String, String>,
     /// Build info for all crates needed for this build.
     pub crates: Vec<CrateDerivation>,
     /// For convenience include the source for tests.
@@ -57,20 +105,150 @@ pub struct BuildInfo {
     pub info: GenerateInfo,
     /// The generation configuration.
     pub config: GenerateConfig,
+    /// Problems that were tolerated because of `--keep-going`.
+    #[serde(default)]
+    pub warnings: Vec<GenerationWarning>,
+    /// Sources that are used by more than one crate by key.
+    #[serde(default)]
+    pub shared_sources: BTreeMap<String, ResolvedSource>,
+    /// The file of every crate relative to the output directory if `shard` is configured.
+    #[serde(default)]
+    pub shards: BTreeMap<PackageId, PathBuf>,
+    /// The default for the `sourceRoot` argument relative to the output directory,
+    /// if there are local crates outside of the output directory.
+    #[serde(default)]
+    pub source_root: Option<PathBuf>,
+    /// The directory of the `Cargo.toml` as nix expression if there is only one, e.g. `./.`,
+    /// for the checks that run cargo on the whole workspace.
+    #[serde(default)]
+    pub workspace_root: Option<PathBuf>,
+    /// The binary targets of every workspace member by member name.
+    #[serde(default)]
+    pub member_binaries: BTreeMap<String, Vec<String>>,
+    /// The names of the workspace members with a `staticlib` or `cdylib` target.
+    #[serde(default)]
+    pub c_library_members: Vec<String>,
+    /// The Python module names of the workspace members that are PyO3 extension modules,
+    /// if `python_modules` is configured.
+    #[serde(default)]
+    pub python_modules: BTreeMap<String, String>,
+    /// The version of cargo's feature resolver whose semantics the generated build follows.
+    #[serde(default)]
+    pub feature_resolver: String,
+    /// The names of the workspace members in `allWorkspaceMembers`,
+    /// if not all members are built by default.
+    #[serde(default)]
+    pub default_workspace_members: Option<Vec<String>>,
+    /// The `default-run` binary of the root package, if it declares one.
+    #[serde(default)]
+    pub default_run: Option<String>,
+    /// The toolchain that `rust-toolchain.toml` requests, if the project has one.
+    #[serde(default)]
+    pub rust_toolchain: Option<RustToolchain>,
+    /// The `--crate-overrides` file relative to the output directory.
+    #[serde(default)]
+    pub crate_overrides_file: Option<String>,
+    /// The sources that the vendored sources replace in `.cargo/config.toml`.
+    #[serde(default)]
+    pub source_replacements: vendor::SourceReplacements,
+    /// The `Cargo.nix` files of other projects that crates are imported from, see `imports`.
+    #[serde(default)]
+    pub imported_cargo_nix: BTreeMap<String, String>,
+    /// The rust target of `--no-std`, whose crates are built against `core` and `alloc` only.
+    #[serde(default)]
+    pub no_std_target: Option<String>,
+}
+
+/// A crate that could not be fully resolved but was still included in the output.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GenerationWarning {
+    /// The package ID of the affected crate.
+    pub package_id: PackageId,
+    /// The generation phase in which the problem occurred, e.g. "resolve" or "prefetch".
+    pub phase: String,
+    /// The error message.
+    pub message: String,
 }
 
 impl BuildInfo {
     /// Return the `NixBuildInfo` data ready for rendering the nix build file.
     pub fn for_config(info: &GenerateInfo, config: &GenerateConfig) -> Result<BuildInfo, Error> {
+        if let Some(mirror) = config.crates_io_mirror.as_ref() {
+            resolve::check_download_url_template(mirror)?;
+        }
+        let mut default_nix = BuildInfo::resolve(info, config)?;
+
+        check_yanked(config, &default_nix)?;
+
+        prefetch_and_fill_crates_sha256(config, &mut default_nix)?;
+
+        default_nix.shared_sources = resolve::share_duplicate_sources(&mut default_nix.crates);
+
+        default_nix.source_root =
+            resolve::use_source_root_for_out_of_tree_crates(&mut default_nix.crates, config)?;
+        default_nix.workspace_root =
+            resolve::workspace_root(config, default_nix.source_root.as_deref())?;
+
+        imports::import_crates(&mut default_nix);
+
+        if config.shard {
+            default_nix.assign_shards();
+        }
+
+        Ok(default_nix)
+    }
+
+    /// Returns the resolved crates that are needed for the build, without prefetching
+    /// any sources.
+    ///
+    /// This is enough for inspecting the dependency graph, e.g. in `crate2nix graph`.
+    pub fn resolve_without_prefetch(
+        info: &GenerateInfo,
+        config: &GenerateConfig,
+    ) -> Result<BuildInfo, Error> {
+        BuildInfo::resolve(info, config)
+    }
+
+    fn resolve(info: &GenerateInfo, config: &GenerateConfig) -> Result<BuildInfo, Error> {
+        let crate2nix_json =
+            crate::config::Config::read_from_or_default(&crate2nix_json_path(config))?;
+        let no_std_platform = config
+            .no_std
+            .as_ref()
+            .map(|system| TargetPlatform::from_system(system, &crate2nix_json.platforms))
+            .transpose()?;
+        let mut platforms = config
+            .platforms
+            .iter()
+            .map(|system| TargetPlatform::from_system(system, &crate2nix_json.platforms))
+            .collect::<Result<Vec<_>, _>>()?;
+        if platforms.is_empty() {
+            platforms.extend(no_std_platform.clone());
+        }
         let merged = {
             let mut metadatas = Vec::new();
+            let filter_platforms: Vec<Option<&str>> =
+                if config.filter_platforms.is_empty() || config.lockfile_only {
+                    if !config.filter_platforms.is_empty() {
+                        warn!("--filter-platform has no effect with --lockfile-only.");
+                    }
+                    vec![None]
+                } else {
+                    config
+                        .filter_platforms
+                        .iter()
+                        .map(|t| Some(t.as_str()))
+                        .collect()
+                };
             for cargo_toml in &config.cargo_toml {
-                metadatas.push(cargo_metadata(config, cargo_toml)?);
+                for filter_platform in &filter_platforms {
+                    metadatas.push(cargo_metadata(config, cargo_toml, *filter_platform)?);
+                }
             }
             metadata::MergedMetadata::merge(metadatas)?
         };
 
-        let indexed_metadata = IndexedMetadata::new_from_merged(&merged).map_err(|e| {
+        let indexed_metadata = IndexedMetadata::new_from_merged(merged).map_err(|e| {
             format_err!(
                 "while indexing metadata for {:#?}: {}",
                 config
@@ -83,13 +261,160 @@ impl BuildInfo {
         })?;
         let mut default_nix = BuildInfo::new(info, config, indexed_metadata)?;
 
+        let target_platforms = if config.wasi {
+            vec![TargetPlatform::from_system(
+                platforms::WASI_SYSTEM,
+                &crate2nix_json.platforms,
+            )?]
+        } else {
+            platforms.clone()
+        };
+        if !target_platforms.is_empty() {
+            default_nix.remove_dependencies_for_other_platforms(&target_platforms, &platforms);
+        }
         default_nix.prune_unneeded_crates();
-
-        prefetch_and_fill_crates_sha256(config, &merged, &mut default_nix)?;
+        resolve::check_links(
+            &default_nix.crates,
+            default_nix
+                .root_package_id
+                .iter()
+                .chain(default_nix.workspace_members.values()),
+        )?;
+        if let Some(platform) = &no_std_platform {
+            no_std::check(&default_nix, platform)?;
+            default_nix.no_std_target = Some(platform.rust_target().to_string());
+        }
 
         Ok(default_nix)
     }
 
+    /// Assigns every crate a file name in the shard directory.
+    ///
+    /// The names are derived from crate name and version so that they stay stable
+    /// when unrelated crates are added or removed.
+    fn assign_shards(&mut self) {
+        let shard_directory = self.config.shard_directory();
+        let mut used = HashSet::new();
+        for crate_derivation in &self.crates {
+            let base_name = format!(
+                "{}-{}",
+                crate_derivation.crate_name, crate_derivation.version
+            );
+            let mut file_name = format!("{}.nix", base_name);
+            let mut suffix = 1;
+            while !used.insert(file_name.clone()) {
+                suffix += 1;
+                file_name = format!("{}-{}.nix", base_name, suffix);
+            }
+            self.shards.insert(
+                (*crate_derivation.package_id).clone(),
+                shard_directory.join(file_name),
+            );
+        }
+    }
+
+    /// Removes the dependencies that none of the given platforms needs.
+    ///
+    /// The crates built for `target` only keep the dependencies for it. Proc macros, build
+    /// dependencies and their dependencies run on the building platform and keep the
+    /// dependencies for `host`, or all if it is empty.
+    fn remove_dependencies_for_other_platforms(
+        &mut self,
+        target: &[TargetPlatform],
+        host: &[TargetPlatform],
+    ) {
+        let (built_for_target, built_for_host) = self.crates_by_built_platform();
+        let needed_on = |platforms: &[TargetPlatform], target: &Platform| {
+            platforms.is_empty() || platforms.iter().any(|platform| platform.may_match(target))
+        };
+        for crate_derivation in &mut self.crates {
+            let package_id = &crate_derivation.package_id;
+            let for_target = built_for_target.contains(package_id);
+            let for_host = built_for_host.contains(package_id);
+            let (crate_name, version) = (&crate_derivation.crate_name, &crate_derivation.version);
+            let retain = |dependencies: &mut Vec<ResolvedDependency>, is_build: bool| {
+                dependencies.retain(|dependency| {
+                    let Some(target_condition) = dependency.target.as_ref() else {
+                        return true;
+                    };
+                    let keep = if is_build {
+                        needed_on(host, target_condition)
+                    } else {
+                        (!for_target && !for_host)
+                            || for_target && needed_on(target, target_condition)
+                            || for_host && needed_on(host, target_condition)
+                    };
+                    if !keep {
+                        debug!(
+                            "Removing dependency {} of {} {}: only needed for {}.",
+                            dependency.name, crate_name, version, target_condition
+                        );
+                    }
+                    keep
+                });
+            };
+            retain(&mut crate_derivation.dependencies, false);
+            retain(&mut crate_derivation.build_dependencies, true);
+            retain(&mut crate_derivation.dev_dependencies, false);
+        }
+    }
+
+    /// The package IDs of the crates built for the target platform, i.e. the dependencies of the
+    /// root crate and the workspace members, and of the crates built for the building platform,
+    /// i.e. proc macros, build dependencies and their dependencies. A crate can be both.
+    fn crates_by_built_platform(&self) -> (HashSet<PackageId>, HashSet<PackageId>) {
+        let indexed_crates: BTreeMap<_, _> =
+            self.crates.iter().map(|c| (&*c.package_id, c)).collect();
+        let closure = |mut queue: Vec<PackageId>, is_host: bool| {
+            let mut reachable = HashSet::new();
+            while let Some(package_id) = queue.pop() {
+                let Some(crate_derivation) = indexed_crates.get(&package_id) else {
+                    continue;
+                };
+                if !is_host && crate_derivation.proc_macro || !reachable.insert(package_id) {
+                    continue;
+                }
+                let others = if is_host {
+                    &crate_derivation.build_dependencies
+                } else {
+                    &crate_derivation.dev_dependencies
+                };
+                queue.extend(
+                    crate_derivation
+                        .dependencies
+                        .iter()
+                        .chain(others)
+                        .map(|d| (*d.package_id).clone()),
+                );
+            }
+            reachable
+        };
+        let built_for_target = closure(
+            self.root_package_id
+                .iter()
+                .chain(self.workspace_members.values())
+                .cloned()
+                .collect(),
+            false,
+        );
+        let built_for_host = closure(
+            self.crates
+                .iter()
+                .filter(|c| c.proc_macro)
+                .map(|c| &*c.package_id)
+                .chain(
+                    self.crates
+                        .iter()
+                        .flat_map(|c| &c.build_dependencies)
+                        .map(|d| &*d.package_id),
+                )
+                .cloned()
+                .collect(),
+            true,
+        );
+        (built_for_target, built_for_host)
+    }
+
     fn prune_unneeded_crates(&mut self) {
         let mut queue: VecDeque<&PackageId> = self
             .root_package_id
@@ -98,7 +423,7 @@ impl BuildInfo {
             .collect();
         let mut reachable = HashSet::new();
         let indexed_crates: BTreeMap<_, _> =
-            self.crates.iter().map(|c| (&c.package_id, c)).collect();
+            self.crates.iter().map(|c| (&*c.package_id, c)).collect();
         while let Some(next_package_id) = queue.pop_back() {
             if !reachable.insert(next_package_id.clone()) {
                 continue;
@@ -114,10 +439,19 @@ impl BuildInfo {
                             .chain(c.build_dependencies.iter())
                             .chain(c.dev_dependencies.iter())
                     })
-                    .map(|d| &d.package_id),
+                    .map(|d| &*d.package_id),
             );
         }
-        self.crates.retain(|c| reachable.contains(&c.package_id));
+        self.crates.retain(|c| {
+            let keep = reachable.contains(&*c.package_id);
+            if !keep {
+                debug!(
+                    "Pruning {} {}: not reachable from the root crate or workspace members.",
+                    c.crate_name, c.version
+                );
+            }
+            keep
+        });
     }
 
     fn new(
@@ -125,72 +459,572 @@ impl BuildInfo {
         config: &GenerateConfig,
         metadata: IndexedMetadata,
     ) -> Result<BuildInfo, Error> {
-        let crate2nix_json = crate::config::Config::read_from_or_default(
-            &config
-                .crate_hashes_json
-                .parent()
-                .expect("crate-hashes.json has parent dir")
-                .join("crate2nix.json"),
-        )?;
+        let crate2nix_json =
+            crate::config::Config::read_from_or_default(&crate2nix_json_path(config))?;
 
-        Ok(BuildInfo {
-            root_package_id: metadata.root.clone(),
-            workspace_members: metadata
-                .workspace_members
-                .iter()
-                .flat_map(|pkg_id| {
-                    metadata
-                        .pkgs_by_id
-                        .get(pkg_id)
-                        .map(|pkg| (pkg.name.clone(), pkg_id.clone()))
+        let previous = config
+            .incremental
+            .then(|| incremental::ResolvedCrates::read(info, config, &crate2nix_json, &metadata))
+            .transpose()?;
+
+        // The packages are resolved independently, in parallel for large dependency graphs.
+        let resolved: Vec<(&Package, Result<CrateDerivation, Error>)> = metadata
+            .pkgs_by_id
+            .par_iter()
+            .map(|(package_id, package)| {
+                let reusable = previous
+                    .as_ref()
+                    .and_then(|previous| previous.reusable(package_id, &metadata));
+                let crate_derivation = match reusable {
+                    Some(crate_derivation) => Ok(crate_derivation),
+                    None => CrateDerivation::resolve(config, &crate2nix_json, &metadata, package)
+                        .with_context(|| format!("while resolving {}", package.id)),
+                };
+                (package, crate_derivation)
+            })
+            .collect();
+
+        let mut warnings = Vec::new();
+        let mut crates = Vec::new();
+        for (package, crate_derivation) in resolved {
+            match crate_derivation {
+                Ok(crate_derivation) => crates.push(crate_derivation),
+                Err(e) if config.keep_going => {
+                    warn!("{:#}", e);
+                    let message = format!("{:#}", e);
+                    crates.push(CrateDerivation::unresolved(
+                        &metadata,
+                        package,
+                        message.clone(),
+                    ));
+                    warnings.push(GenerationWarning {
+                        package_id: package.id.clone(),
+                        phase: "resolve".to_string(),
+                        message,
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(previous) = previous {
+            previous.write(config, &crates)?;
+        }
+
+        for key in crate2nix_json.source_overrides.keys() {
+            if !crates.iter().any(|c| {
+                **key == *c.crate_name || *key == format!("{}@{}", c.crate_name, c.version)
+            }) {
+                warn!("The source override {} matches no crate.", key);
+            }
+        }
+
+        resolve::mark_artifact_dependencies(&mut crates);
+        profiles_of(config)?.apply(&mut crates);
+        build_scripts::detect_network_usage(&metadata, &crates);
+
+        let workspace_members = select_targets(
+            config,
+            &metadata,
+            &mut crates,
+            workspace_members_of(&metadata)?,
+        )?;
+        let root_package_id = metadata
+            .root
+            .clone()
+            .filter(|root| workspace_members.values().any(|pkg_id| pkg_id == root));
+        let workspace_member_aliases = workspace_member_aliases(&metadata, &workspace_members);
+        let member_binaries = workspace_members
+            .iter()
+            .filter_map(|(name, pkg_id)| {
+                let crate_derivation = crates.iter().find(|c| *c.package_id == *pkg_id)?;
+                let binaries: Vec<String> = crate_derivation
+                    .binaries
+                    .iter()
+                    .map(|bin| bin.name.clone())
+                    .collect();
+                (!binaries.is_empty()).then(|| (name.clone(), binaries))
+            })
+            .collect();
+        let c_library_members = workspace_members
+            .iter()
+            .filter(|(_, pkg_id)| {
+                crates.iter().any(|c| {
+                    *c.package_id == **pkg_id
+                        && c.lib_crate_types
+                            .iter()
+                            .any(|t| t == "staticlib" || t == "cdylib")
                 })
-                .collect(),
-            crates: metadata
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut python_modules = BTreeMap::new();
+        if config.python_modules {
+            for (name, pkg_id) in &workspace_members {
+                let (Some(crate_derivation), Some(package)) = (
+                    crates.iter().find(|c| *c.package_id == *pkg_id),
+                    metadata.pkgs_by_id.get(pkg_id),
+                ) else {
+                    continue;
+                };
+                if let Some(module_name) = python_modules::module_name(
+                    crate_derivation,
+                    package.manifest_path.as_std_path(),
+                )? {
+                    python_modules.insert(name.clone(), module_name);
+                }
+            }
+        }
+
+        let default_workspace_members: Vec<String> = workspace_members
+            .iter()
+            .filter(|(_, pkg_id)| metadata.workspace_default_members.contains(pkg_id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let default_workspace_members = (!config.all_members
+            && default_workspace_members.len() < workspace_members.len())
+        .then_some(default_workspace_members);
+
+        let default_run = metadata
+            .root
+            .as_ref()
+            .and_then(|root| metadata.pkgs_by_id.get(root))
+            .and_then(|package| package.default_run.clone());
+
+        let feature_resolvers = config
+            .cargo_toml
+            .iter()
+            .map(|cargo_toml| resolve::feature_resolver(cargo_toml))
+            .collect::<Result<BTreeSet<_>, Error>>()?;
+        let feature_resolver = if feature_resolvers.len() == 1 {
+            feature_resolvers.into_iter().next().unwrap()
+        } else {
+            warn!("The workspaces use different feature resolvers, using version 1 for all.");
+            "1"
+        };
+
+        let output_dir = match config.output.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let rust_toolchain = match config.cargo_toml.first().and_then(|path| path.parent()) {
+            Some(dir) => RustToolchain::find(
+                if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                },
+                output_dir,
+            )?,
+            None => None,
+        };
+        let crate_overrides_file = match &config.crate_overrides {
+            Some(file) if !file.is_file() => {
+                bail!("The crate overrides {} do not exist.", file.display())
+            }
+            Some(file) => Some(rust_toolchain::relative_file(file, output_dir)),
+            None => None,
+        };
+
+        let source_replacements = vendor::source_replacements(
+            metadata
                 .pkgs_by_id
                 .values()
-                .map(|package| {
-                    CrateDerivation::resolve(config, &crate2nix_json, &metadata, package)
-                })
-                .collect::<Result<_, Error>>()?,
+                .filter_map(|package| package.source.as_ref())
+                .map(|source| source.repr.as_str()),
+        );
+
+        Ok(BuildInfo {
+            root_package_id,
+            workspace_members,
+            workspace_member_aliases,
+            crates,
             indexed_metadata: metadata,
             info: info.clone(),
             config: config.clone(),
+            warnings,
+            shared_sources: BTreeMap::new(),
+            shards: BTreeMap::new(),
+            source_root: None,
+            workspace_root: None,
+            member_binaries,
+            c_library_members,
+            python_modules,
+            feature_resolver: feature_resolver.to_string(),
+            default_workspace_members,
+            default_run,
+            rust_toolchain,
+            crate_overrides_file,
+            source_replacements,
+            imported_cargo_nix: BTreeMap::new(),
+            no_std_target: None,
+        })
+    }
+}
+
+/// The `crate2nix.json` next to `crate-hashes.json`.
+fn crate2nix_json_path(config: &GenerateConfig) -> PathBuf {
+    config
+        .crate_hashes_json
+        .parent()
+        .expect("crate-hashes.json has parent dir")
+        .join("crate2nix.json")
+}
+
+/// The package IDs of the workspace members by package name.
+///
+/// The members of several workspaces may have the same name, they would get the same attribute
+/// in `workspaceMembers`.
+fn workspace_members_of(metadata: &IndexedMetadata) -> Result<BTreeMap<String, PackageId>, Error> {
+    let mut workspace_members = BTreeMap::new();
+    for pkg_id in &metadata.workspace_members {
+        let Some(package) = metadata.pkgs_by_id.get(pkg_id) else {
+            continue;
+        };
+        if let Some(other) = workspace_members.insert(package.name.clone(), pkg_id.clone()) {
+            bail!(
+                "The workspace members {} and {} are both named {}, rename one of the packages.",
+                other.repr,
+                pkg_id.repr,
+                package.name
+            );
+        }
+    }
+    Ok(workspace_members)
+}
+
+/// Restricts the workspace members to the targets selected with `GenerateConfig::packages`,
+/// `bins` and `lib`, like the target selection of `cargo build`.
+///
+/// The binaries of the selected members that `bins` does not name are removed. Without
+/// `lib`, members without a selected binary are left out.
+fn select_targets(
+    config: &GenerateConfig,
+    metadata: &IndexedMetadata,
+    crates: &mut [CrateDerivation],
+    mut workspace_members: BTreeMap<String, PackageId>,
+) -> Result<BTreeMap<String, PackageId>, Error> {
+    if !config.packages.is_empty() {
+        let mut selected = BTreeMap::new();
+        for spec in &config.packages {
+            let (name, version) = match spec.split_once('@') {
+                Some((name, version)) => (name, Some(version)),
+                None => (spec.as_str(), None),
+            };
+            let pkg_id = workspace_members.get(name).filter(|pkg_id| {
+                version.is_none_or(|version| {
+                    metadata
+                        .pkgs_by_id
+                        .get(*pkg_id)
+                        .is_some_and(|package| package.version.to_string() == version)
+                })
+            });
+            let Some(pkg_id) = pkg_id else {
+                bail!("The package {} is not a workspace member.", spec);
+            };
+            selected.insert(name.to_string(), pkg_id.clone());
+        }
+        workspace_members = selected;
+    }
+    if config.bins.is_empty() && !config.lib {
+        return Ok(workspace_members);
+    }
+
+    let mut found_bins = BTreeSet::new();
+    workspace_members.retain(|_, pkg_id| {
+        let Some(crate_derivation) = crates.iter_mut().find(|c| *c.package_id == *pkg_id) else {
+            return false;
+        };
+        crate_derivation
+            .binaries
+            .retain(|bin| config.bins.contains(&bin.name));
+        found_bins.extend(crate_derivation.binaries.iter().map(|bin| bin.name.clone()));
+        !crate_derivation.binaries.is_empty() || config.lib && crate_derivation.lib.is_some()
+    });
+    if let Some(bin) = config.bins.iter().find(|bin| !found_bins.contains(*bin)) {
+        bail!("No selected workspace member has a binary named {}.", bin);
+    }
+    if workspace_members.is_empty() {
+        bail!("No selected workspace member has a library.");
+    }
+    Ok(workspace_members)
+}
+
+/// The package names of the workspace members by the name of their directory, for the members
+/// whose directory has another name than the package, e.g. `core` for `my-core` in `core/`.
+///
+/// Aliases that are the name of another member or the directory of several members are left out
+/// with a warning.
+fn workspace_member_aliases(
+    metadata: &IndexedMetadata,
+    workspace_members: &BTreeMap<String, PackageId>,
+) -> BTreeMap<String, String> {
+    let mut by_directory: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, pkg_id) in workspace_members {
+        let directory = metadata
+            .pkgs_by_id
+            .get(pkg_id)
+            .and_then(|package| package.manifest_path.parent())
+            .and_then(|dir| dir.file_name());
+        if let Some(directory) = directory.filter(|directory| directory != name) {
+            by_directory
+                .entry(directory.to_string())
+                .or_default()
+                .push(name.clone());
+        }
+    }
+    by_directory
+        .into_iter()
+        .filter_map(|(directory, names)| match names.as_slice() {
+            [name] if !workspace_members.contains_key(&directory) => {
+                Some((directory, name.clone()))
+            }
+            [name] => {
+                warn!(
+                    "The directory {} of the workspace member {} is the name of another member, \
+                     it is no alias.",
+                    directory, name
+                );
+                None
+            }
+            _ => {
+                warn!(
+                    "The workspace members {} are all in directories named {}, it is no alias.",
+                    names.join(", "),
+                    directory
+                );
+                None
+            }
         })
+        .collect()
+}
+
+/// The profiles of the first workspace that configures any.
+fn profiles_of(config: &GenerateConfig) -> Result<profiles::Profiles, Error> {
+    let mut configured = Vec::new();
+    for cargo_toml in &config.cargo_toml {
+        let profiles = profiles::Profiles::read(cargo_toml)?;
+        if !profiles.is_empty() {
+            configured.push(profiles);
+        }
+    }
+    if configured.len() > 1 {
+        warn!("Several workspaces configure profiles, using the ones of the first for all.");
     }
+    Ok(configured.into_iter().next().unwrap_or_default())
 }
 
-/// Call `cargo metadata` and return result.
-fn cargo_metadata(config: &GenerateConfig, cargo_toml: &Path) -> Result<Metadata, Error> {
+/// Call `cargo metadata` and return result, only with the crates for `filter_platform` if given.
+fn cargo_metadata(
+    config: &GenerateConfig,
+    cargo_toml: &Path,
+    filter_platform: Option<&str>,
+) -> Result<Metadata, Error> {
     let mut cmd = cargo_metadata::MetadataCommand::new();
-    let mut other_options = config.other_metadata_options.clone();
-    other_options.push("--locked".into());
-    cmd.manifest_path(cargo_toml).other_options(&*other_options);
-    cmd.exec().map_err(|e| {
+    let other_options = metadata_options(config, cargo_toml, filter_platform)?;
+    if config.lockfile_only {
+        return lockfile_metadata::metadata(cargo_toml, config.lockfile.as_deref(), &other_options);
+    }
+
+    // Cargo reads the alternative lockfile in a mirror of the workspace, which is removed when
+    // this is dropped.
+    let mirror = match &config.lockfile {
+        Some(lockfile) => Some(lockfile_profiles::MirroredWorkspace::new(
+            &lockfile_profiles::workspace_root(cargo_toml)?,
+            lockfile,
+        )?),
+        None => None,
+    };
+    match &mirror {
+        Some(mirror) => cmd.manifest_path(mirror.path_of(cargo_toml)?),
+        None => cmd.manifest_path(cargo_toml),
+    };
+    cmd.other_options(&*other_options);
+
+    let lock_file = match &mirror {
+        Some(mirror) => Some(mirror.lock_file()),
+        None => find_lock_file_for(cargo_toml),
+    };
+    if lock_file.is_none() && (config.locked || config.frozen) {
+        bail!(
+            "No Cargo.lock found for {} but --locked/--frozen was specified.\n\
+             Run `cargo generate-lockfile` and commit the result.",
+            cargo_toml.to_string_lossy()
+        );
+    }
+    let lock_file_before = lock_file.as_deref().and_then(fingerprint_path);
+
+    // The cache only knows about the Cargo.lock of the workspace.
+    let cache_dir = if config.metadata_cache && config.lockfile.is_none() {
+        metadata_cache::cache_dir()
+    } else {
+        None
+    };
+    // Cached metadata is only used if Cargo.lock is unchanged since cargo ran with the same
+    // options and the checks below passed, so cargo would not change Cargo.lock now either.
+    if let Some(metadata) = cache_dir
+        .as_ref()
+        .and_then(|dir| metadata_cache::read(dir, cargo_toml, &other_options))
+    {
+        return Ok(metadata);
+    }
+
+    let metadata = exec_metadata(&cmd, mirror.as_ref()).map_err(|e| {
+        let hint = if !config.allow_lock_update && lock_file_before.is_none() {
+            "\nNo Cargo.lock exists yet. Run `cargo generate-lockfile` and commit the result, \
+             or pass --allow-lock-update to let cargo generate it."
+        } else if !config.allow_lock_update && e.to_string().contains("--locked") {
+            "\nPass --allow-lock-update to let cargo update Cargo.lock."
+        } else {
+            ""
+        };
         format_err!(
-            "while retrieving metadata about {}: {}",
+            "while retrieving metadata about {}: {}{}",
             &cargo_toml.to_string_lossy(),
-            e
+            e,
+            hint
         )
-    })
+    })?;
+
+    let lock_file =
+        lock_file.unwrap_or_else(|| metadata.workspace_root.as_std_path().join("Cargo.lock"));
+    let changed =
+        check_lock_file_unchanged(config, cargo_toml, &lock_file, lock_file_before.as_deref())?;
+    if let (true, Some(mirror)) = (changed, &mirror) {
+        mirror.copy_updates()?;
+    }
+
+    if let Some(dir) = cache_dir {
+        if let Err(e) = metadata_cache::write(&dir, cargo_toml, &other_options, &metadata) {
+            warn!("Could not cache the cargo metadata: {:#}", e);
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// The options to pass to `cargo metadata` for `cargo_toml`.
+///
+/// Unless `--allow-lock-update` is given, cargo must not change `Cargo.lock`, so `--locked` is
+/// passed, or `--frozen` with `--frozen`.
+fn metadata_options(
+    config: &GenerateConfig,
+    cargo_toml: &Path,
+    filter_platform: Option<&str>,
+) -> Result<Vec<String>, Error> {
+    let mut other_options = config.other_metadata_options.clone();
+    if let Some(triple) = filter_platform {
+        other_options.push("--filter-platform".into());
+        other_options.push(triple.to_string());
+    }
+    if config.frozen {
+        other_options.push("--frozen".into());
+    } else if !config.allow_lock_update {
+        other_options.push("--locked".into());
+    }
+    if let Some(proxy) = &config.proxy {
+        other_options.push("--config".into());
+        other_options.push(format!("http.proxy={}", toml::Value::String(proxy.clone())));
+    }
+    if other_options.iter().any(|option| option == "--no-deps") {
+        bail!(
+            "--no-deps cannot be passed to cargo metadata: \
+             crate2nix needs the resolved dependencies of {}.",
+            cargo_toml.to_string_lossy()
+        );
+    }
+    Ok(other_options)
+}
+
+/// Fails if `lock_file` does not have the fingerprint `before` anymore after resolving
+/// `cargo_toml`, unless `--allow-lock-update` is given. Returns whether it changed.
+///
+/// The generated files must correspond to the committed `Cargo.lock`. `lock_file` is the file
+/// that cargo read, `config.lockfile` is named in the messages if given.
+fn check_lock_file_unchanged(
+    config: &GenerateConfig,
+    cargo_toml: &Path,
+    lock_file: &Path,
+    before: Option<&str>,
+) -> Result<bool, Error> {
+    if fingerprint_path(lock_file).as_deref() == before {
+        return Ok(false);
+    }
+    let lock_file = config.lockfile.as_deref().unwrap_or(lock_file);
+    if !config.allow_lock_update {
+        bail!(
+            "{} changed while resolving {}.\n\
+             Run `cargo update` or `cargo generate-lockfile` and commit the result, \
+             or pass --allow-lock-update.",
+            lock_file.to_string_lossy(),
+            cargo_toml.to_string_lossy()
+        );
+    }
+    warn!(
+        "{} changed while resolving {}. Commit it together with the generated files.",
+        lock_file.to_string_lossy(),
+        cargo_toml.to_string_lossy()
+    );
+    Ok(true)
+}
+
+/// Runs `cmd`, in `mirror` if given.
+fn exec_metadata(
+    cmd: &cargo_metadata::MetadataCommand,
+    mirror: Option<&lockfile_profiles::MirroredWorkspace>,
+) -> Result<Metadata, cargo_metadata::Error> {
+    let Some(mirror) = mirror else {
+        return cmd.exec();
+    };
+    let output = cmd.cargo_command().output()?;
+    if !output.status.success() {
+        return Err(cargo_metadata::Error::CargoMetadata {
+            stderr: mirror.unmirror(&String::from_utf8(output.stderr)?),
+        });
+    }
+    let stdout = std::str::from_utf8(&output.stdout)?
+        .lines()
+        .find(|line| line.starts_with('{'))
+        .ok_or(cargo_metadata::Error::NoJson)?;
+    cargo_metadata::MetadataCommand::parse(mirror.unmirror(stdout))
+}
+
+/// Returns the path of the `Cargo.lock` that applies to the given `Cargo.toml`, if it exists.
+///
+/// For workspace members, this is the lock file in the closest parent directory.
+fn find_lock_file_for(cargo_toml: &Path) -> Option<PathBuf> {
+    let manifest_dir = cargo_toml.parent().unwrap_or_else(|| Path::new("."));
+    manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lock_file| lock_file.exists())
 }
 
 /// Prefetch hashes when necessary.
 fn prefetch_and_fill_crates_sha256(
     config: &GenerateConfig,
-    merged: &MergedMetadata,
     default_nix: &mut BuildInfo,
 ) -> Result<(), Error> {
     let mut from_lock_file: HashMap<PackageId, String> =
-        extract_hashes_from_lockfile(config, merged, default_nix)?;
-    for (_package_id, hash) in from_lock_file.iter_mut() {
-        let bytes =
-            hex::decode(&hash).map_err(|e| format_err!("while decoding '{}': {}", hash, e))?;
-        *hash = nix_base32::to_nix_base32(&bytes);
+        extract_hashes_from_lockfile(config, default_nix)?;
+    expand_registry_checksums(&mut default_nix.crates, &from_lock_file);
+    to_nix_base32(&mut from_lock_file)?;
+
+    // Without using them as hashes, the checksums allow finding crates in the nix store and
+    // verifying the prefetched crates.
+    let mut expected = HashMap::new();
+    if !config.use_cargo_lock_checksums {
+        match read_lockfile_hashes(config, default_nix) {
+            Ok(hashes) => expected = hashes,
+            Err(e) => debug!("Not looking for crates in the nix store: {:#}", e),
+        }
+        expand_registry_checksums(&mut default_nix.crates, &expected);
+        to_nix_base32(&mut expected)?;
     }
 
     let prefetched = prefetch::prefetch(
         config,
         &from_lock_file,
+        &expected,
         &default_nix.crates,
         &default_nix.indexed_metadata.id_shortener,
     )
@@ -203,41 +1037,156 @@ fn prefetch_and_fill_crates_sha256(
                     default_nix
                         .indexed_metadata
                         .id_shortener
-                        .lengthen_ref(&package.package_id),
+                        .stable_ref(&package.package_id),
                 )
                 .or_else(|| from_lock_file.get(&package.package_id))
             {
+                debug!("Using hash {} for {}.", hash, package.package_id);
                 package.source = package.source.with_sha256(hash.clone());
             }
         }
+
+        if package.problem.is_none()
+            && package.source.sha256().is_none()
+            && package.source.needs_prefetch()
+        {
+            let error = Crate2NixError::UnhashableSource {
+                package_id: (*package.package_id).clone(),
+                source_description: package.source.to_string(),
+            };
+            if !config.keep_going {
+                return Err(error.into());
+            }
+            package.problem = Some(error.to_string());
+            default_nix.warnings.push(GenerationWarning {
+                package_id: (*package.package_id).clone(),
+                phase: "prefetch".to_string(),
+                message: error.to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Warns about locked crate versions that have been yanked with `check_yanked`, or fails with
+/// `deny_yanked`, also if some crates could not be checked.
+fn check_yanked(config: &GenerateConfig, build_info: &BuildInfo) -> Result<(), Error> {
+    if !config.check_yanked && !config.deny_yanked {
+        return Ok(());
+    }
+    if config.frozen || config.lockfile_only {
+        if config.deny_yanked {
+            bail!("--deny-yanked needs access to the registries, it cannot be combined with --frozen or --lockfile-only.");
+        }
+        debug!("Not checking for yanked crates without access to the registries.");
+        return Ok(());
+    }
+    let outdated::YankedCheck { yanked, unchecked } =
+        outdated::yanked_crates(build_info, config.prefetch_jobs)?;
+    if config.deny_yanked && !unchecked.is_empty() {
+        return Err(Crate2NixError::UncheckedYankedCrates {
+            crates: unchecked.join(", "),
+        }
+        .into());
+    }
+    if yanked.is_empty() {
+        return Ok(());
+    }
+    if config.deny_yanked {
+        let crates = yanked
+            .iter()
+            .map(|c| format!("{} {}", c.name, c.version))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(Crate2NixError::YankedCrates { crates }.into());
+    }
+    for yanked_crate in yanked {
+        warn!(
+            "{} {} has been yanked from its registry, consider `cargo update -p {}@{}`.",
+            yanked_crate.name, yanked_crate.version, yanked_crate.name, yanked_crate.version
+        );
+    }
+    Ok(())
+}
+
+/// Expands the checksum in the download URLs of registry crates with the hex `checksums`.
+fn expand_registry_checksums(
+    crates: &mut [CrateDerivation],
+    checksums: &HashMap<PackageId, String>,
+) {
+    for package in crates {
+        if let (ResolvedSource::Registry(source), Some(checksum)) =
+            (&package.source, checksums.get(&package.package_id))
+        {
+            package.source = ResolvedSource::Registry(source.with_checksum(checksum));
+        }
     }
+}
 
+fn to_nix_base32(hashes: &mut HashMap<PackageId, String>) -> Result<(), Error> {
+    for (_package_id, hash) in hashes.iter_mut() {
+        let bytes =
+            hex::decode(&hash).map_err(|e| format_err!("while decoding '{}': {}", hash, e))?;
+        *hash = nix_base32::to_nix_base32(&bytes);
+    }
     Ok(())
 }
 
 fn extract_hashes_from_lockfile(
     config: &GenerateConfig,
-    merged: &MergedMetadata,
     default_nix: &mut BuildInfo,
 ) -> Result<HashMap<PackageId, String>, Error> {
     if !config.use_cargo_lock_checksums {
         return Ok(HashMap::new());
     }
 
+    let hashes_with_shortened_ids = read_lockfile_hashes(config, default_nix)?;
+
+    let mut missing_hashes = Vec::new();
+    for package in default_nix.crates.iter_mut().filter(|c| match &c.source {
+        ResolvedSource::CratesIo(CratesIoSource { sha256, .. }) if sha256.is_none() => {
+            !hashes_with_shortened_ids.contains_key(&c.package_id)
+        }
+        _ => false,
+    }) {
+        missing_hashes.push(format!("{} {}", package.crate_name, package.version));
+    }
+    if !missing_hashes.is_empty() {
+        warn!(
+            "Did not find all crates.io hashes in Cargo.lock. Hashes for e.g. {} are missing.\n\
+             This is probably a bug.",
+            missing_hashes.iter().take(10).join(", ")
+        );
+    }
+    Ok(hashes_with_shortened_ids)
+}
+
+/// Returns the hex encoded checksums in the `Cargo.lock` files by shortened package ID.
+fn read_lockfile_hashes(
+    config: &GenerateConfig,
+    default_nix: &BuildInfo,
+) -> Result<HashMap<PackageId, String>, Error> {
     let mut hashes: HashMap<PackageId, String> = HashMap::new();
 
     for cargo_toml in &config.cargo_toml {
-        let lock_file_path = cargo_toml.parent().unwrap().join("Cargo.lock");
+        let lock_file_path = config
+            .lockfile
+            .clone()
+            .unwrap_or_else(|| cargo_toml.parent().unwrap().join("Cargo.lock"));
         let lock_file = crate::lock::EncodableResolve::load_lock_file(&lock_file_path)?;
         lock_file
-            .get_hashes_by_package_id(merged, &mut hashes)
+            .get_hashes_by_package_id(
+                default_nix.indexed_metadata.pkgs_by_id.values(),
+                &mut hashes,
+            )
             .context(format!(
                 "while parsing checksums from Lockfile {}",
                 &lock_file_path.to_string_lossy()
             ))?;
     }
 
-    let hashes_with_shortened_ids: HashMap<PackageId, String> = hashes
+    Ok(hashes
         .into_iter()
         .map(|(package_id, hash)| {
             (
@@ -248,29 +1197,11 @@ fn extract_hashes_from_lockfile(
                 hash,
             )
         })
-        .collect();
-
-    let mut missing_hashes = Vec::new();
-    for package in default_nix.crates.iter_mut().filter(|c| match &c.source {
-        ResolvedSource::CratesIo(CratesIoSource { sha256, .. }) if sha256.is_none() => {
-            !hashes_with_shortened_ids.contains_key(&c.package_id)
-        }
-        _ => false,
-    }) {
-        missing_hashes.push(format!("{} {}", package.crate_name, package.version));
-    }
-    if !missing_hashes.is_empty() {
-        eprintln!(
-            "Did not find all crates.io hashes in Cargo.lock. Hashes for e.g. {} are missing.\n\
-             This is probably a bug.",
-            missing_hashes.iter().take(10).join(", ")
-        );
-    }
-    Ok(hashes_with_shortened_ids)
+        .collect())
 }
 
 /// Some info about the crate2nix invocation.
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct GenerateInfo {
     /// The version of this `crate2nix` instance.
     pub crate2nix_version: String,
@@ -288,7 +1219,7 @@ impl Default for GenerateInfo {
 }
 
 /// Configuration for the default.nix generation.
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct GenerateConfig {
     /// The path to `Cargo.toml`.
     pub cargo_toml: Vec<PathBuf>,
@@ -299,10 +1230,688 @@ pub struct GenerateConfig {
     /// The path of the `crate-hashes.json` file which is used to look up hashes and/or store
     /// prefetched hashes at.
     pub crate_hashes_json: PathBuf,
+    /// The format in which `crate-hashes.json` is written.
+    pub crate_hashes_format: CrateHashesFormat,
+    /// Write one `crate-hashes.<registry>.json` per registry instead of `crate-hashes.json`.
+    pub split_crate_hashes: bool,
+    /// Keep the hashes of crates that are not resolved anymore in `crate-hashes.json`, e.g. for
+    /// the crates of another lockfile that shares the file.
+    pub keep_unused_hashes: bool,
     /// The nix expression for the nixpkgs path to use.
     pub nixpkgs_path: String,
     /// Additional arguments to pass to `cargo metadata`.
     pub other_metadata_options: Vec<String>,
     /// Whether to read a `crate-hashes.json` file.
     pub read_crate_hashes: bool,
+    /// Fail if `Cargo.lock` is missing.
+    pub locked: bool,
+    /// Like `locked` but additionally pass `--frozen` to `cargo metadata` to forbid network access.
+    pub frozen: bool,
+    /// Generate the output even if some crates cannot be resolved or prefetched.
+    pub keep_going: bool,
+    /// Skip regeneration if no input changed since the last run.
+    pub incremental: bool,
+    /// Write every crate to its own file which is only imported if it is needed.
+    pub shard: bool,
+    /// Write the crates to a JSON file that the generated nix reads, see `crates_json_file`.
+    pub crates_json: bool,
+    /// Define the lists that many crates repeat once in `internal.sharedLists` and refer to them.
+    pub share_lists: bool,
+    /// The directory which contains all local crates outside of the output directory.
+    pub source_root: Option<PathBuf>,
+    /// Generate an OCI image attribute for every binary of the workspace members.
+    pub docker_images: bool,
+    /// Generate a NixOS module with a systemd service for every binary of the workspace members.
+    pub nixos_modules: bool,
+    /// Generate a C header derivation for every workspace member with a staticlib or cdylib.
+    pub c_headers: bool,
+    /// Generate a Python module derivation for every workspace member that is a PyO3 extension
+    /// module.
+    pub python_modules: bool,
+    /// Generate an `overlay.nix` that adds the workspace members with binaries to nixpkgs.
+    pub overlay: bool,
+    /// Generate a `release.nix` with a Hydra job for every workspace member and check.
+    pub release_nix: bool,
+    /// Download crates.io crates with `pkgs.fetchCrate` instead of `pkgs.fetchurl`.
+    pub use_fetch_crate: bool,
+    /// The download URL template for crates.io crates if they are mirrored,
+    /// as in the `dl` field of a registry's `config.json`.
+    pub crates_io_mirror: Option<String>,
+    /// Include all workspace members in `allWorkspaceMembers`, not only the `default-members`.
+    pub all_members: bool,
+    /// Reuse the output of `cargo metadata` from previous runs if its inputs did not change.
+    pub metadata_cache: bool,
+    /// Build the metadata from `Cargo.lock` and the manifests instead of calling
+    /// `cargo metadata` (experimental).
+    pub lockfile_only: bool,
+    /// The nix systems to include the dependencies for, all if empty.
+    pub platforms: Vec<String>,
+    /// The rust target triples to pass to `cargo metadata --filter-platform`, all if empty.
+    pub filter_platforms: Vec<String>,
+    /// The nix system to check that the crates built for it do not need std.
+    pub no_std: Option<String>,
+    /// Whether to generate for WASI: the crates built for `wasm32-wasi` only keep the
+    /// dependencies for it and the tests run with wasmtime.
+    pub wasi: bool,
+    /// The workspace members that get top-level attributes, by package name or `name@version`,
+    /// like `cargo build --package`. All members if empty.
+    pub packages: Vec<String>,
+    /// The binaries of the selected members to build, like `cargo build --bin`.
+    pub bins: Vec<String>,
+    /// Select the members with a library, like `cargo build --lib`.
+    pub lib: bool,
+    /// A nix file with crate overrides for the `extraCrateOverrides` of the generated nix.
+    pub crate_overrides: Option<PathBuf>,
+    /// An alternative lockfile to resolve with instead of `Cargo.lock`, see `lockfile_profiles`.
+    pub lockfile: Option<PathBuf>,
+    /// Let `cargo metadata` update `Cargo.lock` and only warn about it instead of failing.
+    pub allow_lock_update: bool,
+    /// How often to retry a failed prefetch.
+    pub prefetch_retries: u32,
+    /// The maximal number of concurrent prefetches.
+    pub prefetch_jobs: usize,
+    /// The proxy for downloads, taking precedence over `HTTP_PROXY` and `HTTPS_PROXY`.
+    pub proxy: Option<String>,
+    /// Warn if a locked crate version has been yanked from its registry.
+    pub check_yanked: bool,
+    /// Fail if a locked crate version has been yanked from its registry or cannot be checked.
+    pub deny_yanked: bool,
+}
+
+/// The defaults of `crate2nix generate` without options.
+impl Default for GenerateConfig {
+    fn default() -> Self {
+        GenerateConfig {
+            cargo_toml: vec!["./Cargo.toml".into()],
+            use_cargo_lock_checksums: true,
+            output: "./Cargo.nix".into(),
+            crate_hashes_json: "./crate-hashes.json".into(),
+            crate_hashes_format: Default::default(),
+            split_crate_hashes: false,
+            keep_unused_hashes: false,
+            nixpkgs_path: "<nixpkgs>".to_string(),
+            other_metadata_options: vec!["--all-features".to_string()],
+            read_crate_hashes: true,
+            locked: false,
+            frozen: false,
+            keep_going: false,
+            incremental: false,
+            shard: false,
+            crates_json: false,
+            share_lists: false,
+            source_root: None,
+            docker_images: false,
+            nixos_modules: false,
+            c_headers: false,
+            python_modules: false,
+            overlay: false,
+            release_nix: false,
+            use_fetch_crate: true,
+            crates_io_mirror: None,
+            all_members: false,
+            metadata_cache: true,
+            lockfile_only: false,
+            platforms: Vec::new(),
+            filter_platforms: Vec::new(),
+            no_std: None,
+            wasi: false,
+            packages: Vec::new(),
+            bins: Vec::new(),
+            lib: false,
+            crate_overrides: None,
+            lockfile: None,
+            allow_lock_update: false,
+            prefetch_retries: 3,
+            prefetch_jobs: 4,
+            proxy: None,
+            check_yanked: false,
+            deny_yanked: false,
+        }
+    }
+}
+
+impl GenerateConfig {
+    /// The directory for the crate files if `shard` is set, relative to the output directory.
+    ///
+    /// ```
+    /// let mut config = crate2nix::GenerateConfig::default();
+    /// config.output = "nix/Cargo.nix".into();
+    /// assert_eq!(config.shard_directory(), std::path::PathBuf::from("Cargo.crates"));
+    /// ```
+    pub fn shard_directory(&self) -> PathBuf {
+        let stem = self
+            .output
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "Cargo".to_string());
+        PathBuf::from(format!("{}.crates", stem))
+    }
+
+    /// The JSON file for the crates if `crates_json` is set, relative to the output directory,
+    /// e.g. `Cargo.crates.json` for `Cargo.nix`.
+    pub fn crates_json_file(&self) -> PathBuf {
+        self.shard_directory().with_extension("crates.json")
+    }
+}
+
+#[test]
+fn test_workspace_member_aliases() {
+    let mut env = test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    let members = [
+        ("my-core", "core"),
+        ("cli", "cli"),
+        ("a", "shared"),
+        ("b", "shared"),
+        ("tool", "cli"),
+    ];
+    for (name, directory) in members {
+        env.add_package_and_node(name)
+            .make_root()
+            .update_package(|package| {
+                package.manifest_path = dir
+                    .join(name)
+                    .join(directory)
+                    .join("Cargo.toml")
+                    .try_into()
+                    .unwrap();
+            });
+    }
+    let metadata = env.indexed_metadata();
+    let workspace_members = workspace_members_of(&metadata).unwrap();
+    assert_eq!(workspace_members.len(), 5);
+    let aliases = workspace_member_aliases(&metadata, &workspace_members);
+    // `shared` is the directory of two members and `cli` the name of a member.
+    assert_eq!(
+        aliases,
+        BTreeMap::from([("core".to_string(), "my-core".to_string())])
+    );
+
+    let mut metadata = metadata;
+    let core = metadata.workspace_members[0].clone();
+    let mut other = metadata.pkgs_by_id[&core].clone();
+    other.id = PackageId {
+        repr: "my-core 0.2.0 (path+file:///other/core)".to_string(),
+    };
+    metadata.workspace_members.push(other.id.clone());
+    metadata.pkgs_by_id.insert(other.id.clone(), other);
+    let error = workspace_members_of(&metadata).unwrap_err().to_string();
+    assert!(error.contains("are both named my-core"), "{}", error);
+    env.close();
+}
+
+#[test]
+fn test_select_targets() {
+    let mut env = test::MetadataEnv::default();
+    for name in ["cli", "server", "lib-only"] {
+        env.add_package_and_node(name).make_root();
+    }
+    let metadata = env.indexed_metadata();
+    let bin = |name: &str| crate::resolve::BuildTarget {
+        name: name.to_string(),
+        src_path: format!("src/bin/{}.rs", name).into(),
+        required_features: Vec::new(),
+    };
+    let crates = || -> Vec<CrateDerivation> {
+        metadata
+            .pkgs_by_id
+            .values()
+            .map(|package| {
+                let mut c = CrateDerivation::unresolved(&metadata, package, String::new());
+                c.binaries = match &*c.crate_name {
+                    "cli" => vec![bin("cli"), bin("helper")],
+                    "server" => vec![bin("server")],
+                    _ => Vec::new(),
+                };
+                c.lib = (&*c.crate_name != "server").then(|| bin("lib"));
+                c
+            })
+            .collect()
+    };
+    let select = |config: GenerateConfig, crates: &mut Vec<CrateDerivation>| {
+        select_targets(
+            &config,
+            &metadata,
+            crates,
+            workspace_members_of(&metadata).unwrap(),
+        )
+        .map(|members| members.into_keys().collect::<Vec<_>>())
+    };
+
+    let mut all = crates();
+    assert_eq!(
+        select(test::generate_config(), &mut all).unwrap(),
+        vec!["cli", "lib-only", "server"]
+    );
+    let packages = GenerateConfig {
+        packages: vec!["server".to_string(), "cli@0.1.0".to_string()],
+        ..test::generate_config()
+    };
+    assert_eq!(
+        select(packages, &mut crates()).unwrap(),
+        vec!["cli", "server"]
+    );
+
+    let mut with_bin = crates();
+    let bins = GenerateConfig {
+        bins: vec!["helper".to_string()],
+        ..test::generate_config()
+    };
+    assert_eq!(select(bins, &mut with_bin).unwrap(), vec!["cli"]);
+    let cli = with_bin.iter().find(|c| &*c.crate_name == "cli").unwrap();
+    let names: Vec<&str> = cli.binaries.iter().map(|b| b.name.as_str()).collect();
+    assert_eq!(names, vec!["helper"]);
+
+    let mut libs = crates();
+    let lib = GenerateConfig {
+        lib: true,
+        ..test::generate_config()
+    };
+    assert_eq!(select(lib, &mut libs).unwrap(), vec!["cli", "lib-only"]);
+    assert!(libs.iter().all(|c| c.binaries.is_empty()));
+
+    for (packages, bins, error) in [
+        (
+            vec!["cli@0.2.0"],
+            vec![],
+            "The package cli@0.2.0 is not a workspace member.",
+        ),
+        (
+            vec!["cli"],
+            vec!["server"],
+            "No selected workspace member has a binary named server.",
+        ),
+    ] {
+        let config = GenerateConfig {
+            packages: packages.into_iter().map(str::to_string).collect(),
+            bins: bins.into_iter().map(str::to_string).collect(),
+            ..test::generate_config()
+        };
+        assert_eq!(
+            select(config, &mut crates()).unwrap_err().to_string(),
+            error
+        );
+    }
+    env.close();
+}
+
+#[test]
+fn test_remove_dependencies_for_other_platforms() {
+    let mut env = test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    env.add_package_and_node("main").make_root();
+    for name in ["macro", "unix-dep", "windows-dep", "wasi-dep"] {
+        env.add_package_and_node(name);
+    }
+    let config = GenerateConfig {
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..test::generate_config()
+    };
+    let build_info = || {
+        let mut build_info =
+            BuildInfo::new(&GenerateInfo::default(), &config, env.indexed_metadata()).unwrap();
+        let dependency_on = |name: &str, target: Option<&str>| {
+            let package_id = &build_info
+                .crates
+                .iter()
+                .find(|c| *c.crate_name == *name)
+                .unwrap()
+                .package_id;
+            ResolvedDependency {
+                name: name.into(),
+                rename: None,
+                package_id: package_id.clone(),
+                target: target.map(|target| target.parse().unwrap()),
+                optional: false,
+                uses_default_features: true,
+                features: Vec::new(),
+                artifact: None,
+            }
+        };
+        let main_dependencies = vec![
+            dependency_on("macro", None),
+            dependency_on("windows-dep", Some("cfg(windows)")),
+            dependency_on("wasi-dep", Some("cfg(target_os = \"wasi\")")),
+        ];
+        let on_unix_dep = dependency_on("unix-dep", Some("cfg(unix)"));
+        for crate_derivation in &mut build_info.crates {
+            match &*crate_derivation.crate_name {
+                "main" => {
+                    crate_derivation.dependencies = main_dependencies.clone();
+                    crate_derivation.build_dependencies = vec![on_unix_dep.clone()];
+                }
+                "macro" => {
+                    crate_derivation.proc_macro = true;
+                    crate_derivation.dependencies = vec![on_unix_dep.clone()];
+                }
+                _ => {}
+            }
+        }
+        build_info
+    };
+    let dependency_names = |build_info: &BuildInfo, name: &str| {
+        let crate_derivation = build_info
+            .crates
+            .iter()
+            .find(|c| *c.crate_name == *name)
+            .unwrap();
+        crate_derivation
+            .dependencies
+            .iter()
+            .chain(&crate_derivation.build_dependencies)
+            .map(|d| d.name.to_string())
+            .collect::<Vec<_>>()
+    };
+    let platform = |system: &str| TargetPlatform::from_system(system, &BTreeMap::new()).unwrap();
+
+    // The proc macro and the build dependencies run on the building platform.
+    let mut wasi = build_info();
+    wasi.remove_dependencies_for_other_platforms(&[platform(platforms::WASI_SYSTEM)], &[]);
+    assert_eq!(
+        dependency_names(&wasi, "main"),
+        vec!["macro", "wasi-dep", "unix-dep"]
+    );
+    assert_eq!(dependency_names(&wasi, "macro"), vec!["unix-dep"]);
+
+    let mut built_on_windows = build_info();
+    built_on_windows.remove_dependencies_for_other_platforms(
+        &[platform(platforms::WASI_SYSTEM)],
+        &[platform("x86_64-windows")],
+    );
+    assert_eq!(
+        dependency_names(&built_on_windows, "main"),
+        vec!["macro", "wasi-dep"]
+    );
+    assert!(dependency_names(&built_on_windows, "macro").is_empty());
+
+    let mut linux = build_info();
+    let linux_platforms = [platform("x86_64-linux")];
+    linux.remove_dependencies_for_other_platforms(&linux_platforms, &linux_platforms);
+    assert_eq!(dependency_names(&linux, "main"), vec!["macro", "unix-dep"]);
+    env.close();
+}
+
+/// Writes a library without dependencies named `name` to `dir`. Returns its `Cargo.toml` and
+/// the content of a matching `Cargo.lock`.
+#[cfg(test)]
+fn write_test_package(dir: &Path, name: &str) -> (PathBuf, String) {
+    let cargo_toml = dir.join("Cargo.toml");
+    std::fs::write(
+        &cargo_toml,
+        format!(
+            "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+            name
+        ),
+    )
+    .unwrap();
+    std::fs::create_dir(dir.join("src")).unwrap();
+    std::fs::write(dir.join("src").join("lib.rs"), "").unwrap();
+    let lock = format!(
+        "# This file is automatically @generated by Cargo.\n\
+         # It is not intended for manual editing.\n\
+         version = 3\n\n\
+         [[package]]\nname = \"{}\"\nversion = \"0.1.0\"\n",
+        name
+    );
+    (cargo_toml, lock)
+}
+
+#[test]
+fn test_cargo_metadata_with_alternative_lockfile() {
+    let dir = tempdir::TempDir::new("crate2nix_alternative_lockfile").expect("temp dir");
+    let (cargo_toml, lock) = write_test_package(dir.path(), "profiled");
+    let workspace_lock = dir.path().join("Cargo.lock");
+    std::fs::write(&workspace_lock, "original").unwrap();
+    let alternative = dir.path().join("Cargo.minimal.lock");
+    std::fs::write(&alternative, lock).unwrap();
+
+    let config = GenerateConfig {
+        lockfile: Some(alternative.clone()),
+        ..test::generate_config()
+    };
+    let metadata = cargo_metadata(&config, &cargo_toml, None).unwrap();
+    assert_eq!(metadata.workspace_root.as_std_path(), dir.path());
+    assert_eq!(
+        metadata.packages[0].manifest_path.as_std_path(),
+        cargo_toml.as_path()
+    );
+    assert_eq!(
+        std::fs::read_to_string(&workspace_lock).unwrap(),
+        "original"
+    );
+
+    // Cargo updates the alternative lockfile, not the one of the workspace.
+    std::fs::write(&alternative, "version = 3\n").unwrap();
+    let error = cargo_metadata(&config, &cargo_toml, None).unwrap_err();
+    assert!(
+        error.to_string().contains("--allow-lock-update"),
+        "{}",
+        error
+    );
+    assert_eq!(
+        std::fs::read_to_string(&alternative).unwrap(),
+        "version = 3\n"
+    );
+    let config = GenerateConfig {
+        allow_lock_update: true,
+        ..config
+    };
+    cargo_metadata(&config, &cargo_toml, None).unwrap();
+    assert!(std::fs::read_to_string(&alternative)
+        .unwrap()
+        .contains("name = \"profiled\""));
+    assert_eq!(
+        std::fs::read_to_string(&workspace_lock).unwrap(),
+        "original"
+    );
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_metadata_options() {
+    let cargo_toml = Path::new("Cargo.toml");
+    let options = |config: GenerateConfig| metadata_options(&config, cargo_toml, None).unwrap();
+    assert_eq!(options(test::generate_config()), vec!["--locked"]);
+    assert_eq!(
+        options(GenerateConfig {
+            frozen: true,
+            ..test::generate_config()
+        }),
+        vec!["--frozen"]
+    );
+    assert_eq!(
+        options(GenerateConfig {
+            frozen: true,
+            allow_lock_update: true,
+            ..test::generate_config()
+        }),
+        vec!["--frozen"]
+    );
+    assert!(options(GenerateConfig {
+        allow_lock_update: true,
+        ..test::generate_config()
+    })
+    .is_empty());
+    assert_eq!(
+        metadata_options(&test::generate_config(), cargo_toml, Some("wasm32-wasip1")).unwrap(),
+        vec!["--filter-platform", "wasm32-wasip1", "--locked"]
+    );
+
+    let no_deps = GenerateConfig {
+        other_metadata_options: vec!["--no-deps".to_string()],
+        ..test::generate_config()
+    };
+    assert!(metadata_options(&no_deps, cargo_toml, None).is_err());
+}
+
+#[test]
+fn test_cargo_metadata_without_lock_file() {
+    let dir = tempdir::TempDir::new("crate2nix_without_lock_file").expect("temp dir");
+    let cargo_toml = dir.path().join("Cargo.toml");
+    std::fs::write(
+        &cargo_toml,
+        "[package]\nname = \"unlocked\"\nversion = \"0.1.0\"\n",
+    )
+    .unwrap();
+    for config in [
+        GenerateConfig {
+            locked: true,
+            ..test::generate_config()
+        },
+        GenerateConfig {
+            frozen: true,
+            ..test::generate_config()
+        },
+    ] {
+        let error = cargo_metadata(&config, &cargo_toml, None).unwrap_err();
+        assert!(
+            error.to_string().starts_with("No Cargo.lock found for"),
+            "{}",
+            error
+        );
+    }
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_lock_file_changed_during_resolution() {
+    let dir = tempdir::TempDir::new("crate2nix_lock_file_changed").expect("temp dir");
+    let cargo_toml = dir.path().join("Cargo.toml");
+    let lock_file = dir.path().join("Cargo.lock");
+    std::fs::write(&lock_file, "version = 3\n").unwrap();
+    let before = fingerprint_path(&lock_file);
+    let config = test::generate_config();
+
+    assert!(
+        !check_lock_file_unchanged(&config, &cargo_toml, &lock_file, before.as_deref()).unwrap()
+    );
+    std::fs::write(&lock_file, "version = 4\n").unwrap();
+    let error =
+        check_lock_file_unchanged(&config, &cargo_toml, &lock_file, before.as_deref()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "{} changed while resolving {}.\n\
+             Run `cargo update` or `cargo generate-lockfile` and commit the result, \
+             or pass --allow-lock-update.",
+            lock_file.display(),
+            cargo_toml.display()
+        )
+    );
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_cargo_metadata_locked_by_default() {
+    let dir = tempdir::TempDir::new("crate2nix_locked_by_default").expect("temp dir");
+    let (cargo_toml, lock) = write_test_package(dir.path(), "locked");
+    let lock_file = dir.path().join("Cargo.lock");
+    let config = test::generate_config();
+
+    // Without a Cargo.lock, cargo would have to create one.
+    let error = cargo_metadata(&config, &cargo_toml, None).unwrap_err();
+    assert!(
+        error.to_string().contains("No Cargo.lock exists yet."),
+        "{}",
+        error
+    );
+    assert!(!lock_file.exists());
+
+    // An outdated Cargo.lock is not updated.
+    std::fs::write(&lock_file, "version = 3\n").unwrap();
+    let error = cargo_metadata(&config, &cargo_toml, None).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Pass --allow-lock-update to let cargo update Cargo.lock."),
+        "{}",
+        error
+    );
+    assert_eq!(
+        std::fs::read_to_string(&lock_file).unwrap(),
+        "version = 3\n"
+    );
+
+    std::fs::write(&lock_file, &lock).unwrap();
+    let metadata = cargo_metadata(&config, &cargo_toml, None).unwrap();
+    assert_eq!(metadata.packages[0].name, "locked");
+    assert_eq!(std::fs::read_to_string(&lock_file).unwrap(), lock);
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_cargo_metadata_allow_lock_update() {
+    let dir = tempdir::TempDir::new("crate2nix_allow_lock_update").expect("temp dir");
+    let (cargo_toml, _) = write_test_package(dir.path(), "updated");
+    let lock_file = dir.path().join("Cargo.lock");
+    let config = GenerateConfig {
+        allow_lock_update: true,
+        ..test::generate_config()
+    };
+
+    // Cargo creates Cargo.lock and updates an outdated one.
+    cargo_metadata(&config, &cargo_toml, None).unwrap();
+    assert!(std::fs::read_to_string(&lock_file)
+        .unwrap()
+        .contains("name = \"updated\""));
+    std::fs::write(&lock_file, "version = 3\n").unwrap();
+    let metadata = cargo_metadata(&config, &cargo_toml, None).unwrap();
+    assert_eq!(metadata.packages[0].name, "updated");
+    assert!(std::fs::read_to_string(&lock_file)
+        .unwrap()
+        .contains("name = \"updated\""));
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_lock_file_fingerprint_drift() {
+    let dir = tempdir::TempDir::new("crate2nix_lock_file_drift").expect("temp dir");
+    let cargo_toml = dir.path().join("Cargo.toml");
+    let lock_file = dir.path().join("Cargo.lock");
+    let before = fingerprint_path(&lock_file);
+    assert_eq!(before, None);
+
+    // Created during resolution.
+    std::fs::write(&lock_file, "version = 3\n").unwrap();
+    let error = check_lock_file_unchanged(
+        &test::generate_config(),
+        &cargo_toml,
+        &lock_file,
+        before.as_deref(),
+    )
+    .unwrap_err();
+    assert!(
+        error.to_string().contains("changed while resolving"),
+        "{}",
+        error
+    );
+
+    let allow_lock_update = GenerateConfig {
+        allow_lock_update: true,
+        ..test::generate_config()
+    };
+    assert!(check_lock_file_unchanged(
+        &allow_lock_update,
+        &cargo_toml,
+        &lock_file,
+        before.as_deref()
+    )
+    .unwrap());
+    // The alternative lockfile is named instead of the one that cargo read.
+    let with_lockfile = GenerateConfig {
+        lockfile: Some(dir.path().join("Cargo.minimal.lock")),
+        ..test::generate_config()
+    };
+    let error =
+        check_lock_file_unchanged(&with_lockfile, &cargo_toml, &lock_file, before.as_deref())
+            .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Cargo.minimal.lock changed while resolving"),
+        "{}",
+        error
+    );
+    dir.close().unwrap();
 }
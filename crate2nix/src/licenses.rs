@@ -0,0 +1,164 @@
+//! License reports for all crates in the resolved dependency graph, e.g. for `crate2nix licenses`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use cargo_metadata::Package;
+use serde::{Deserialize, Serialize};
+
+use crate::resolve::CrateDerivation;
+#[cfg(test)]
+use crate::test;
+use crate::BuildInfo;
+
+/// The license reported for crates that neither specify `license` nor `license-file`.
+const UNKNOWN_LICENSE: &str = "UNKNOWN";
+
+/// The supported output formats for license reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LicenseReportFormat {
+    /// One block per license, listing the crates indented below.
+    Text,
+    /// A JSON object from license to crates.
+    Json,
+    /// One line per crate with a header.
+    Csv,
+}
+
+impl FromStr for LicenseReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LicenseReportFormat::Text),
+            "json" => Ok(LicenseReportFormat::Json),
+            "csv" => Ok(LicenseReportFormat::Csv),
+            _ => bail!(
+                "unknown license report format '{}', expected 'text', 'json' or 'csv'",
+                s
+            ),
+        }
+    }
+}
+
+/// A crate in the license report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LicensedCrate {
+    /// The crate name.
+    pub name: String,
+    /// The crate version.
+    pub version: String,
+    /// Where the source of the crate comes from, e.g. the crates.io download URL.
+    pub source: String,
+}
+
+/// Returns all crates of `build_info` grouped by their license expression.
+pub fn by_license(build_info: &BuildInfo) -> BTreeMap<String, Vec<LicensedCrate>> {
+    let mut by_license: BTreeMap<String, Vec<LicensedCrate>> = BTreeMap::new();
+    for crate_derivation in &build_info.crates {
+        let package = build_info
+            .indexed_metadata
+            .pkgs_by_id
+            .get(&crate_derivation.package_id);
+        by_license
+            .entry(license(package))
+            .or_default()
+            .push(licensed_crate(crate_derivation));
+    }
+    by_license
+}
+
+fn license(package: Option<&Package>) -> String {
+    match package {
+        Some(Package {
+            license: Some(license),
+            ..
+        }) => license.clone(),
+        Some(Package {
+            license_file: Some(license_file),
+            ..
+        }) => format!("see {}", license_file),
+        _ => UNKNOWN_LICENSE.to_string(),
+    }
+}
+
+fn licensed_crate(crate_derivation: &CrateDerivation) -> LicensedCrate {
+    LicensedCrate {
+        name: crate_derivation.crate_name.to_string(),
+        version: crate_derivation.version.to_string(),
+        source: crate_derivation.source.to_string(),
+    }
+}
+
+/// Renders the license report for all crates in `build_info`.
+pub fn render(build_info: &BuildInfo, format: LicenseReportFormat) -> Result<String, Error> {
+    let by_license = by_license(build_info);
+    let mut report = String::new();
+    match format {
+        LicenseReportFormat::Text => {
+            for (license, crates) in &by_license {
+                writeln!(report, "{} ({})", license, crates.len())?;
+                for c in crates {
+                    writeln!(report, "    {} {} {}", c.name, c.version, c.source)?;
+                }
+            }
+        }
+        LicenseReportFormat::Json => {
+            report = serde_json::to_string_pretty(&by_license)?;
+            report.push('\n');
+        }
+        LicenseReportFormat::Csv => {
+            writeln!(report, "license,name,version,source")?;
+            for (license, crates) in &by_license {
+                for c in crates {
+                    writeln!(
+                        report,
+                        "{},{},{},{}",
+                        csv_field(license),
+                        csv_field(&c.name),
+                        csv_field(&c.version),
+                        csv_field(&c.source)
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Quotes `field` if necessary as described in RFC 4180.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[test]
+fn test_csv_field() {
+    assert_eq!(csv_field("MIT OR Apache-2.0"), "MIT OR Apache-2.0");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+}
+
+#[test]
+fn test_license() {
+    let mut env = test::MetadataEnv::default();
+    env.add_package_and_node("licensed")
+        .update_package(|p| p.license = Some("MIT".to_string()));
+    env.add_package_and_node("license_file")
+        .update_package(|p| p.license_file = Some("LICENSE.txt".into()));
+    env.add_package_and_node("unlicensed");
+    let indexed = env.indexed_metadata();
+    let license_of = |name: &str| license(indexed.pkgs_by_id.values().find(|p| p.name == name));
+
+    assert_eq!(license_of("licensed"), "MIT");
+    assert_eq!(license_of("license_file"), "see LICENSE.txt");
+    assert_eq!(license_of("unlicensed"), UNKNOWN_LICENSE);
+    assert_eq!(license(None), UNKNOWN_LICENSE);
+
+    env.close();
+}
@@ -8,7 +8,7 @@ use std::fmt;
 use std::path::Path;
 use std::str::FromStr;
 
-use crate::metadata::MergedMetadata;
+use cargo_metadata::Package;
 
 impl EncodableResolve {
     pub fn load_lock_file(path: &Path) -> Result<EncodableResolve, Error> {
@@ -27,13 +27,13 @@ impl EncodableResolve {
         Ok(v)
     }
 
-    pub fn get_hashes_by_package_id(
+    pub fn get_hashes_by_package_id<'a>(
         &self,
-        metadata: &MergedMetadata,
+        packages: impl IntoIterator<Item = &'a Package>,
         hashes: &mut HashMap<PackageId, String>,
     ) -> Result<(), Error> {
         let mut package_id_by_source = HashMap::new();
-        for p in &metadata.packages {
+        for p in packages {
             let Some(ref source) = p.source else {
                 // local crate
                 continue;
@@ -81,6 +81,36 @@ impl EncodableResolve {
 
         Ok(())
     }
+
+    /// Returns the entries of the given packages in this lock file by package ID.
+    ///
+    /// Packages without an entry, e.g. because they come from another lock file, are left out.
+    pub fn entries_by_package_id<'p>(
+        &self,
+        packages: impl IntoIterator<Item = &'p Package>,
+    ) -> HashMap<&'p PackageId, &EncodableDependency> {
+        let entries: HashMap<_, _> = self
+            .package
+            .iter()
+            .map(|entry| {
+                let key = (
+                    entry.name.as_str(),
+                    entry.version.as_str(),
+                    entry.source.as_deref(),
+                );
+                (key, entry)
+            })
+            .collect();
+        packages
+            .into_iter()
+            .filter_map(|p| {
+                let version = p.version.to_string();
+                let source = p.source.as_ref().map(|source| source.repr.as_str());
+                let entry = entries.get(&(p.name.as_str(), version.as_str(), source))?;
+                Some((&p.id, *entry))
+            })
+            .collect()
+    }
 }
 
 //
@@ -90,32 +120,27 @@ impl EncodableResolve {
 /// The `Cargo.lock` structure.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EncodableResolve {
-    package: Vec<EncodableDependency>,
+    pub(crate) package: Vec<EncodableDependency>,
     /// `root` is optional to allow backward compatibility.
     root: Option<EncodableDependency>,
     metadata: Option<BTreeMap<String, String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-struct Patch {
-    unused: Vec<EncodableDependency>,
-}
-
 #[derive(Serialize, Deserialize, Debug, PartialOrd, Ord, PartialEq, Eq)]
 pub struct EncodableDependency {
-    name: String,
-    version: String,
-    source: Option<String>,
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) source: Option<String>,
     checksum: Option<String>,
-    dependencies: Option<Vec<EncodablePackageId>>,
+    pub(crate) dependencies: Option<Vec<EncodablePackageId>>,
     replace: Option<EncodablePackageId>,
 }
 
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Clone)]
 pub struct EncodablePackageId {
-    name: String,
-    version: Option<String>,
-    source: Option<String>,
+    pub(crate) name: String,
+    pub(crate) version: Option<String>,
+    pub(crate) source: Option<String>,
 }
 
 impl fmt::Display for EncodablePackageId {
@@ -0,0 +1,1785 @@
+//! Building the output of `cargo metadata` from `Cargo.lock` and the manifests.
+//!
+//! This is the experimental `--lockfile-only` mode for environments without a cargo binary or
+//! without access to the registry index. It needs a complete `Cargo.lock` and the manifests of
+//! all locked crates on disk:
+//!
+//! * Local crates are found by following the workspace members and their path dependencies.
+//! * Other crates are looked up in the directory sources configured in `.cargo/config.toml`,
+//!   e.g. by `cargo vendor`, and in the sources that cargo extracted to
+//!   `$CARGO_HOME/registry/src` and `$CARGO_HOME/git/checkouts`.
+//!
+//! Like `cargo metadata`, we resolve the features of all workspace members, unified over all
+//! platforms and dependency kinds, and drop the optional dependencies that are not enabled.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{bail, format_err, Context, Error};
+use cargo_metadata::{DependencyKind, Metadata};
+use log::{debug, warn};
+use semver::{Version, VersionReq};
+use serde_json::{json, Value};
+
+use crate::editions::Edition;
+use crate::lock::{EncodableDependency, EncodablePackageId, EncodableResolve};
+
+const CRATES_IO_SOURCE: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+const DEPENDENCY_SECTIONS: &[(&str, DependencyKind)] = &[
+    ("dependencies", DependencyKind::Normal),
+    ("dev-dependencies", DependencyKind::Development),
+    ("dev_dependencies", DependencyKind::Development),
+    ("build-dependencies", DependencyKind::Build),
+    ("build_dependencies", DependencyKind::Build),
+];
+
+/// `[package]` keys that we copy to the metadata, with their names in the metadata.
+const PACKAGE_FIELDS: &[(&str, &str)] = &[
+    ("authors", "authors"),
+    ("description", "description"),
+    ("license", "license"),
+    ("license-file", "license_file"),
+    ("homepage", "homepage"),
+    ("repository", "repository"),
+    ("documentation", "documentation"),
+    ("keywords", "keywords"),
+    ("categories", "categories"),
+    ("links", "links"),
+    ("default-run", "default_run"),
+    ("rust-version", "rust_version"),
+    ("metadata", "metadata"),
+];
+
+/// Returns the metadata of `cargo_toml` as `cargo metadata` with the given options would.
+///
+/// Of the options, only `--all-features`, `--no-default-features` and `--features` have an
+/// effect.
+pub fn metadata(
+    cargo_toml: &Path,
+    lock_file: Option<&Path>,
+    options: &[String],
+) -> Result<Metadata, Error> {
+    let cargo_toml = cargo_toml
+        .canonicalize()
+        .with_context(|| format!("while resolving {}", cargo_toml.display()))?;
+    let mut files = TomlFiles::default();
+    let workspace = Workspace::find(&mut files, &cargo_toml)?;
+    let lock_file = lock_file
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| workspace.root.join("Cargo.lock"));
+    if !lock_file.exists() {
+        bail!(
+            "--lockfile-only needs {} but it does not exist.\n\
+             Run `cargo generate-lockfile` and commit the result.",
+            lock_file.display()
+        );
+    }
+    let lock = EncodableResolve::load_lock_file(&lock_file)?;
+    let packages = locked_packages(&mut files, &workspace, &lock.package)?;
+    let resolution = Resolution::resolve(&packages, &FeatureOptions::parse(options));
+    let metadata = metadata_json(&cargo_toml, &workspace, &packages, &resolution)?;
+    serde_json::from_value(metadata)
+        .with_context(|| format!("while building the metadata from {}", lock_file.display()))
+}
+
+/// Parsed TOML files by path.
+#[derive(Default)]
+struct TomlFiles(HashMap<PathBuf, Rc<toml::Table>>);
+
+impl TomlFiles {
+    fn load(&mut self, path: &Path) -> Result<Rc<toml::Table>, Error> {
+        if let Some(table) = self.0.get(path) {
+            return Ok(table.clone());
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("while reading {}: {}", path.display(), e))?;
+        let table: toml::Table = toml::from_str(&content)
+            .map_err(|e| format_err!("while parsing {}: {}", path.display(), e))?;
+        let table = Rc::new(table);
+        self.0.insert(path.to_path_buf(), table.clone());
+        Ok(table)
+    }
+
+    /// The directory and manifest of the closest workspace root, starting with `manifest_path`
+    /// itself.
+    fn workspace_of(
+        &mut self,
+        manifest_path: &Path,
+    ) -> Result<Option<(PathBuf, Rc<toml::Table>)>, Error> {
+        for dir in manifest_path.parent().into_iter().flat_map(Path::ancestors) {
+            let candidate = dir.join("Cargo.toml");
+            if !candidate.is_file() {
+                continue;
+            }
+            let manifest = self.load(&candidate)?;
+            if manifest.contains_key("workspace") {
+                return Ok(Some((dir.to_path_buf(), manifest)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The workspace that contains the `Cargo.toml` that we generate the build file for.
+struct Workspace {
+    root: PathBuf,
+    manifest: Rc<toml::Table>,
+    /// The manifest paths of the members.
+    members: Vec<PathBuf>,
+    /// The manifest paths of the `default-members`.
+    default_members: Vec<PathBuf>,
+}
+
+impl Workspace {
+    fn find(files: &mut TomlFiles, cargo_toml: &Path) -> Result<Workspace, Error> {
+        if let Some((root, manifest)) = files.workspace_of(cargo_toml)? {
+            let workspace = manifest.get("workspace");
+            let globs = |key: &str| str_list(workspace.and_then(|w| w.get(key)));
+            let excluded: Vec<PathBuf> = globs("exclude")
+                .iter()
+                .map(|dir| normalize(root.join(dir)))
+                .collect();
+            let expand = |globs: Vec<String>| -> Vec<PathBuf> {
+                globs
+                    .iter()
+                    .flat_map(|glob| expand_glob(&root, glob))
+                    .map(|dir| normalize(dir.join("Cargo.toml")))
+                    .filter(|manifest_path| {
+                        manifest_path.is_file()
+                            && !excluded.iter().any(|dir| manifest_path.starts_with(dir))
+                    })
+                    .collect()
+            };
+
+            let root_package = manifest
+                .contains_key("package")
+                .then(|| root.join("Cargo.toml"));
+            let mut members: Vec<PathBuf> = root_package.iter().cloned().collect();
+            for member in expand(globs("members")) {
+                if !members.contains(&member) {
+                    members.push(member);
+                }
+            }
+            if root.join("Cargo.toml") == cargo_toml
+                || members.iter().any(|member| member == cargo_toml)
+            {
+                let mut default_members = expand(globs("default-members"));
+                if default_members.is_empty() {
+                    default_members = match root_package {
+                        Some(root_package) => vec![root_package],
+                        None => members.clone(),
+                    };
+                }
+                return Ok(Workspace {
+                    root,
+                    manifest,
+                    members,
+                    default_members,
+                });
+            }
+            debug!(
+                "{} is not a member of the workspace in {}.",
+                cargo_toml.display(),
+                root.display()
+            );
+        }
+
+        let root = cargo_toml
+            .parent()
+            .ok_or_else(|| format_err!("{} has no parent directory", cargo_toml.display()))?
+            .to_path_buf();
+        Ok(Workspace {
+            root,
+            manifest: files.load(cargo_toml)?,
+            members: vec![cargo_toml.to_path_buf()],
+            default_members: vec![cargo_toml.to_path_buf()],
+        })
+    }
+}
+
+/// A package from `Cargo.lock` with the information from its manifest.
+struct LockedPackage {
+    id: String,
+    name: String,
+    manifest_path: PathBuf,
+    is_member: bool,
+    /// The package as reported in the `packages` of `cargo metadata`.
+    json: Value,
+    dependencies: Vec<ManifestDependency>,
+    /// The locked package of every dependency, if it is locked for this package.
+    dependency_packages: Vec<Option<usize>>,
+    /// The features including the implicit features of optional dependencies.
+    features: BTreeMap<String, Vec<String>>,
+    lib_name: Option<String>,
+}
+
+impl LockedPackage {
+    fn new(
+        files: &mut TomlFiles,
+        locked: &EncodableDependency,
+        version: &Version,
+        manifest_path: PathBuf,
+        is_member: bool,
+    ) -> Result<LockedPackage, Error> {
+        let manifest = files.load(&manifest_path)?;
+        let dir = manifest_path
+            .parent()
+            .ok_or_else(|| format_err!("{} has no parent directory", manifest_path.display()))?;
+        let id = match &locked.source {
+            Some(source) => format!("{} {} ({})", locked.name, version, source),
+            None => format!(
+                "{} {} (path+file://{})",
+                locked.name,
+                version,
+                dir.display()
+            ),
+        };
+
+        let empty = toml::Table::new();
+        let package = manifest
+            .get("package")
+            .and_then(toml::Value::as_table)
+            .unwrap_or(&empty);
+        let edition = package_value(files, &manifest_path, package, "edition")?
+            .and_then(|edition| edition.as_str().map(str::to_string))
+            .unwrap_or_else(|| "2015".to_string());
+        Edition::of_package(&edition, &locked.name)?;
+
+        let mut dependencies = Vec::new();
+        for (kind, target, section) in dependency_sections(&manifest) {
+            for (key, value) in section {
+                dependencies.push(ManifestDependency::new(
+                    files,
+                    &manifest_path,
+                    key,
+                    value,
+                    kind,
+                    target,
+                )?);
+            }
+        }
+
+        let mut features: BTreeMap<String, Vec<String>> = manifest
+            .get("features")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flatten()
+            .map(|(feature, values)| (feature.clone(), str_list(Some(values))))
+            .collect();
+        // Like cargo, add implicit features for optional dependencies that no feature enables
+        // with the `dep:` syntax.
+        let dep_references: BTreeSet<&str> = features
+            .values()
+            .flatten()
+            .filter_map(|value| value.strip_prefix("dep:"))
+            .collect();
+        let implicit: Vec<String> = dependencies
+            .iter()
+            .filter(|dep| dep.optional && !dep_references.contains(dep.key.as_str()))
+            .map(|dep| dep.key.clone())
+            .collect();
+        for key in implicit {
+            features
+                .entry(key.clone())
+                .or_insert_with(|| vec![format!("dep:{}", key)]);
+        }
+
+        let (targets, lib_name) = targets(dir, &manifest, package, &locked.name, &edition);
+
+        let mut json = serde_json::Map::new();
+        json.insert("name".to_string(), json!(locked.name));
+        json.insert("version".to_string(), json!(version.to_string()));
+        json.insert("id".to_string(), json!(id));
+        json.insert("source".to_string(), json!(locked.source));
+        json.insert("manifest_path".to_string(), json!(manifest_path));
+        json.insert(
+            "dependencies".to_string(),
+            dependencies
+                .iter()
+                .map(ManifestDependency::to_json)
+                .collect(),
+        );
+        json.insert("targets".to_string(), json!(targets));
+        json.insert("features".to_string(), json!(features));
+        json.insert("edition".to_string(), json!(edition));
+        for (key, metadata_key) in PACKAGE_FIELDS {
+            if let Some(value) = package_value(files, &manifest_path, package, key)? {
+                json.insert(metadata_key.to_string(), serde_json::to_value(value)?);
+            }
+        }
+        if let Some(publish) = package_value(files, &manifest_path, package, "publish")? {
+            // `cargo metadata` reports the allowed registries, `null` for all of them.
+            let registries = match publish {
+                toml::Value::Boolean(true) => Value::Null,
+                toml::Value::Boolean(false) => json!([]),
+                registries => serde_json::to_value(registries)?,
+            };
+            json.insert("publish".to_string(), registries);
+        }
+
+        Ok(LockedPackage {
+            id,
+            name: locked.name.clone(),
+            manifest_path,
+            is_member,
+            json: Value::Object(json),
+            dependency_packages: vec![None; dependencies.len()],
+            dependencies,
+            features,
+            lib_name,
+        })
+    }
+
+    /// The locked package of the dependency with the given index, unless cargo ignores the
+    /// dependency for this package.
+    fn dependency_package(&self, dependency: usize) -> Option<usize> {
+        if self.dependencies[dependency].kind == DependencyKind::Development && !self.is_member {
+            return None;
+        }
+        self.dependency_packages[dependency]
+    }
+}
+
+/// A dependency as declared in a manifest.
+struct ManifestDependency {
+    /// The name in the dependency section, i.e. the new name for renamed dependencies.
+    key: String,
+    /// The package name.
+    name: String,
+    rename: Option<String>,
+    req: VersionReq,
+    kind: DependencyKind,
+    target: Option<String>,
+    optional: bool,
+    uses_default_features: bool,
+    features: Vec<String>,
+    source: Option<String>,
+    registry: Option<String>,
+    path: Option<PathBuf>,
+}
+
+impl ManifestDependency {
+    fn new(
+        files: &mut TomlFiles,
+        manifest_path: &Path,
+        key: &str,
+        value: &toml::Value,
+        kind: DependencyKind,
+        target: Option<&str>,
+    ) -> Result<ManifestDependency, Error> {
+        let mut features = Vec::new();
+        let mut optional = None;
+        let (spec, spec_dir) = if is_inherited(value) {
+            let Some((root, workspace)) = files.workspace_of(manifest_path)? else {
+                bail!(
+                    "{} inherits the dependency {} but is not part of a workspace.",
+                    manifest_path.display(),
+                    key
+                );
+            };
+            let spec = workspace
+                .get("workspace")
+                .and_then(|workspace| workspace.get("dependencies"))
+                .and_then(|dependencies| dependencies.get(key))
+                .cloned()
+                .ok_or_else(|| {
+                    format_err!(
+                        "{} inherits the dependency {} but {} does not define it.",
+                        manifest_path.display(),
+                        key,
+                        root.join("Cargo.toml").display()
+                    )
+                })?;
+            // The dependent can only add features and make the dependency optional.
+            features.extend(str_list(value.get("features")));
+            optional = value.get("optional").and_then(toml::Value::as_bool);
+            (spec, root)
+        } else {
+            let dir = manifest_path.parent().unwrap_or(Path::new("/"));
+            (value.clone(), dir.to_path_buf())
+        };
+
+        let get = |key: &str| spec.get(key);
+        let req = match &spec {
+            toml::Value::String(req) => req.as_str(),
+            _ => get("version").and_then(toml::Value::as_str).unwrap_or("*"),
+        };
+        let req = VersionReq::parse(req).map_err(|e| {
+            format_err!(
+                "while parsing the version requirement of {} in {}: {}",
+                key,
+                manifest_path.display(),
+                e
+            )
+        })?;
+        let rename = get("package").map(|_| key.to_string());
+        let path = get("path")
+            .and_then(toml::Value::as_str)
+            .map(|path| normalize(spec_dir.join(path)));
+        let registry = get("registry")
+            .and_then(toml::Value::as_str)
+            .map(str::to_string);
+        let source = match get("git").and_then(toml::Value::as_str) {
+            _ if path.is_some() || registry.is_some() => None,
+            Some(git) => Some(format!("git+{}", git)),
+            None => Some(CRATES_IO_SOURCE.to_string()),
+        };
+        let mut all_features = str_list(get("features"));
+        all_features.extend(features);
+
+        Ok(ManifestDependency {
+            key: key.to_string(),
+            name: get("package")
+                .and_then(toml::Value::as_str)
+                .unwrap_or(key)
+                .to_string(),
+            rename,
+            req,
+            kind,
+            target: target.map(str::to_string),
+            optional: optional
+                .or_else(|| get("optional").and_then(toml::Value::as_bool))
+                .unwrap_or(false),
+            uses_default_features: get("default-features")
+                .or_else(|| get("default_features"))
+                .and_then(toml::Value::as_bool)
+                .unwrap_or(true),
+            features: all_features,
+            source,
+            registry,
+            path,
+        })
+    }
+
+    /// Whether the dependency can be resolved to the given package.
+    fn matches(&self, name: &str, version: &Version) -> bool {
+        if self.name != name {
+            return false;
+        }
+        // Like `ResolvedDependencies`, fall back to ignoring the prerelease, e.g. "*" does not
+        // match a prerelease version in semver but cargo thinks differently.
+        let mut without_metadata = version.clone();
+        without_metadata.pre = semver::Prerelease::EMPTY;
+        without_metadata.build = semver::BuildMetadata::EMPTY;
+        self.req.matches(version) || self.req.matches(&without_metadata)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "source": self.source,
+            "req": self.req.to_string(),
+            "kind": kind_json(self.kind),
+            "rename": self.rename,
+            "optional": self.optional,
+            "uses_default_features": self.uses_default_features,
+            "features": self.features,
+            "target": self.target,
+            "registry": self.registry,
+            "path": self.path,
+        })
+    }
+}
+
+/// Locates the manifests of all packages in `Cargo.lock`.
+fn locked_packages(
+    files: &mut TomlFiles,
+    workspace: &Workspace,
+    lock: &[EncodableDependency],
+) -> Result<Vec<LockedPackage>, Error> {
+    let local = local_packages(files, workspace)?;
+    let cargo_home = crate::cargo_config::cargo_home();
+    let directory_sources = directory_sources(files, &workspace.root, cargo_home.as_deref())?;
+
+    let mut packages = Vec::new();
+    let mut versions = Vec::new();
+    let mut missing = Vec::new();
+    for locked in lock {
+        let version = Version::parse(&locked.version)
+            .with_context(|| format!("while parsing the version of {}", locked.name))?;
+        let manifest_path = match &locked.source {
+            None => local
+                .iter()
+                .find(|(name, local_version, _)| *name == locked.name && *local_version == version)
+                .map(|(_, _, manifest_path)| manifest_path.clone()),
+            Some(source) => match directory_sources.get(&(locked.name.clone(), version.clone())) {
+                Some(manifest_path) => Some(manifest_path.clone()),
+                None => match &cargo_home {
+                    Some(cargo_home) => {
+                        downloaded_manifest(files, cargo_home, &locked.name, &version, source)
+                    }
+                    None => None,
+                },
+            },
+        };
+        let Some(manifest_path) = manifest_path else {
+            missing.push(format!(
+                "{} {} ({})",
+                locked.name,
+                version,
+                locked.source.as_deref().unwrap_or("local")
+            ));
+            continue;
+        };
+        let is_member = locked.source.is_none() && workspace.members.contains(&manifest_path);
+        packages.push(LockedPackage::new(
+            files,
+            locked,
+            &version,
+            manifest_path,
+            is_member,
+        )?);
+        versions.push(version);
+    }
+    if !missing.is_empty() {
+        bail!(
+            "--lockfile-only did not find the manifests of these crates:\n  {}\n\
+             Run `cargo fetch` once, vendor them with `cargo vendor` or drop --lockfile-only.",
+            missing.join("\n  ")
+        );
+    }
+    if let Some(member) = workspace
+        .members
+        .iter()
+        .find(|member| !packages.iter().any(|p| p.manifest_path == **member))
+    {
+        bail!(
+            "{} is not part of Cargo.lock.\n\
+             Run `cargo generate-lockfile` and commit the result.",
+            member.display()
+        );
+    }
+
+    for (index, locked) in lock.iter().enumerate() {
+        let mut locked_dependencies = Vec::new();
+        for dependency in locked.dependencies.iter().flatten() {
+            let dependency_index = find_locked(lock, dependency).ok_or_else(|| {
+                format_err!(
+                    "{} depends on {} which is not part of Cargo.lock.",
+                    locked.name,
+                    dependency
+                )
+            })?;
+            locked_dependencies.push(dependency_index);
+        }
+        let package = &mut packages[index];
+        for (dependency, package_index) in package
+            .dependencies
+            .iter()
+            .zip(package.dependency_packages.iter_mut())
+        {
+            *package_index = locked_dependencies
+                .iter()
+                .copied()
+                .find(|i| dependency.matches(&lock[*i].name, &versions[*i]));
+        }
+    }
+
+    Ok(packages)
+}
+
+/// The index of the package with the given ID in `Cargo.lock`.
+fn find_locked(lock: &[EncodableDependency], id: &EncodablePackageId) -> Option<usize> {
+    lock.iter().position(|locked| {
+        locked.name == id.name
+            && id.version.iter().all(|version| *version == locked.version)
+            && id
+                .source
+                .iter()
+                .all(|source| Some(source) == locked.source.as_ref())
+    })
+}
+
+/// The name, version and manifest path of the workspace members and their transitive path
+/// dependencies.
+fn local_packages(
+    files: &mut TomlFiles,
+    workspace: &Workspace,
+) -> Result<Vec<(String, Version, PathBuf)>, Error> {
+    let mut queue = workspace.members.clone();
+    // For path dependencies in `[workspace.dependencies]` and `[patch]`.
+    queue.push(workspace.root.join("Cargo.toml"));
+    let mut seen = BTreeSet::new();
+    let mut packages = Vec::new();
+    while let Some(manifest_path) = queue.pop() {
+        if !seen.insert(manifest_path.clone()) || !manifest_path.is_file() {
+            continue;
+        }
+        let manifest = files.load(&manifest_path)?;
+        let dir = manifest_path.parent().unwrap_or(Path::new("/"));
+
+        let workspace_dependencies = manifest
+            .get("workspace")
+            .and_then(|workspace| workspace.get("dependencies"))
+            .and_then(toml::Value::as_table);
+        let patches = manifest
+            .get("patch")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flat_map(|patches| patches.values())
+            .filter_map(toml::Value::as_table);
+        let replacements = manifest.get("replace").and_then(toml::Value::as_table);
+        let sections = dependency_sections(&manifest)
+            .into_iter()
+            .map(|(_, _, section)| section)
+            .chain(workspace_dependencies)
+            .chain(patches)
+            .chain(replacements);
+        for dependency in sections.flat_map(|section| section.values()) {
+            if let Some(path) = dependency.get("path").and_then(toml::Value::as_str) {
+                queue.push(normalize(dir.join(path).join("Cargo.toml")));
+            }
+        }
+
+        if let Some((name, version)) = name_and_version(files, &manifest_path, &manifest)? {
+            packages.push((name, version, manifest_path));
+        }
+    }
+    Ok(packages)
+}
+
+/// The manifests in directory sources by package name and version, e.g. from `cargo vendor`.
+fn directory_sources(
+    files: &mut TomlFiles,
+    workspace_root: &Path,
+    cargo_home: Option<&Path>,
+) -> Result<HashMap<(String, Version), PathBuf>, Error> {
+    let mut manifests = HashMap::new();
+    for config_file in crate::cargo_config::config_files(workspace_root, cargo_home) {
+        let config = files.load(&config_file)?;
+        // Relative paths are relative to the parent of the directory that contains the file.
+        let base = config_file
+            .parent()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new("/"));
+        let directories = config
+            .get("source")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flat_map(|sources| sources.values())
+            .filter_map(|source| source.get("directory")?.as_str());
+        for directory in directories {
+            let directory = base.join(directory);
+            if !directory.is_dir() {
+                warn!(
+                    "Ignoring the directory source {} from {}: it does not exist.",
+                    directory.display(),
+                    config_file.display()
+                );
+                continue;
+            }
+            for crate_dir in sorted_entries(&directory) {
+                let manifest_path = crate_dir.join("Cargo.toml");
+                if !manifest_path.is_file() {
+                    continue;
+                }
+                let manifest = files.load(&manifest_path)?;
+                if let Some(name_and_version) = name_and_version(files, &manifest_path, &manifest)?
+                {
+                    manifests.entry(name_and_version).or_insert(manifest_path);
+                }
+            }
+        }
+    }
+    Ok(manifests)
+}
+
+/// The manifest of a package that cargo extracted below `$CARGO_HOME`.
+fn downloaded_manifest(
+    files: &mut TomlFiles,
+    cargo_home: &Path,
+    name: &str,
+    version: &Version,
+    source: &str,
+) -> Option<PathBuf> {
+    if source.starts_with("git+") {
+        // Cargo checks out every revision to `checkouts/<repository>-<hash>/<short revision>`.
+        let (_, rev) = source.rsplit_once('#')?;
+        let short_rev = rev.get(..7).unwrap_or(rev);
+        return sorted_entries(&cargo_home.join("git").join("checkouts"))
+            .into_iter()
+            .find_map(|checkout| find_manifest(files, &checkout.join(short_rev), name, version));
+    }
+    sorted_entries(&cargo_home.join("registry").join("src"))
+        .into_iter()
+        .map(|index| {
+            index
+                .join(format!("{}-{}", name, version))
+                .join("Cargo.toml")
+        })
+        .find(|manifest_path| manifest_path.is_file())
+}
+
+/// Searches `dir` recursively for the manifest of the given package, e.g. in a git checkout.
+fn find_manifest(
+    files: &mut TomlFiles,
+    dir: &Path,
+    name: &str,
+    version: &Version,
+) -> Option<PathBuf> {
+    let manifest_path = dir.join("Cargo.toml");
+    if let Ok(manifest) = files.load(&manifest_path) {
+        if let Ok(Some((found_name, found_version))) =
+            name_and_version(files, &manifest_path, &manifest)
+        {
+            if found_name == name && found_version == *version {
+                return Some(manifest_path);
+            }
+        }
+    }
+    sorted_entries(dir)
+        .into_iter()
+        .filter(|entry| {
+            entry.is_dir()
+                && !matches!(
+                    entry.file_name().and_then(|name| name.to_str()),
+                    Some(".git" | "target")
+                )
+        })
+        .find_map(|entry| find_manifest(files, &entry, name, version))
+}
+
+/// The package name and version of a manifest, `None` for virtual manifests.
+fn name_and_version(
+    files: &mut TomlFiles,
+    manifest_path: &Path,
+    manifest: &toml::Table,
+) -> Result<Option<(String, Version)>, Error> {
+    let Some(package) = manifest.get("package").and_then(toml::Value::as_table) else {
+        return Ok(None);
+    };
+    let Some(name) = package.get("name").and_then(toml::Value::as_str) else {
+        bail!("{} has no package name.", manifest_path.display());
+    };
+    let version = package_value(files, manifest_path, package, "version")?;
+    let version = version
+        .as_ref()
+        .and_then(toml::Value::as_str)
+        .unwrap_or("0.0.0");
+    let version = Version::parse(version).with_context(|| {
+        format!(
+            "while parsing the package version in {}",
+            manifest_path.display()
+        )
+    })?;
+    Ok(Some((name.to_string(), version)))
+}
+
+/// The value of `key` in `[package]`, from `[workspace.package]` for `key.workspace = true`.
+fn package_value(
+    files: &mut TomlFiles,
+    manifest_path: &Path,
+    package: &toml::Table,
+    key: &str,
+) -> Result<Option<toml::Value>, Error> {
+    let Some(value) = package.get(key) else {
+        return Ok(None);
+    };
+    if !is_inherited(value) {
+        return Ok(Some(value.clone()));
+    }
+    let Some((_, workspace)) = files.workspace_of(manifest_path)? else {
+        bail!(
+            "{} inherits package.{} but is not part of a workspace.",
+            manifest_path.display(),
+            key
+        );
+    };
+    Ok(workspace
+        .get("workspace")
+        .and_then(|workspace| workspace.get("package"))
+        .and_then(|package| package.get(key))
+        .cloned())
+}
+
+fn is_inherited(value: &toml::Value) -> bool {
+    value.get("workspace").and_then(toml::Value::as_bool) == Some(true)
+}
+
+/// The dependency sections of a manifest, including the platform-specific ones.
+fn dependency_sections(
+    manifest: &toml::Table,
+) -> Vec<(DependencyKind, Option<&str>, &toml::Table)> {
+    let targets = manifest
+        .get("target")
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flatten()
+        .filter_map(|(target, table)| Some((Some(target.as_str()), table.as_table()?)));
+    let mut sections = Vec::new();
+    for (target, table) in std::iter::once((None, manifest)).chain(targets) {
+        for (key, kind) in DEPENDENCY_SECTIONS {
+            if let Some(section) = table.get(*key).and_then(toml::Value::as_table) {
+                sections.push((*kind, target, section));
+            }
+        }
+    }
+    sections
+}
+
+/// The targets of a package like `cargo metadata` reports them, and the name of the library.
+///
+/// Explicitly declared targets are combined with the auto-discovered ones.
+fn targets(
+    dir: &Path,
+    manifest: &toml::Table,
+    package: &toml::Table,
+    package_name: &str,
+    edition: &str,
+) -> (Vec<Value>, Option<String>) {
+    let target = |name: &str,
+                  kind: Vec<String>,
+                  crate_types: Vec<String>,
+                  src_path: PathBuf,
+                  table: Option<&toml::Table>| {
+        let value = |key: &str| table.and_then(|table| table.get(key));
+        let flag =
+            |key: &str, default: bool| value(key).and_then(toml::Value::as_bool).unwrap_or(default);
+        let is_lib = kind
+            .iter()
+            .any(|kind| kind.ends_with("lib") || kind == "proc-macro");
+        json!({
+            "name": name,
+            "kind": kind,
+            "crate_types": crate_types,
+            "required-features": str_list(value("required-features")),
+            "src_path": src_path,
+            "edition": value("edition").and_then(toml::Value::as_str).unwrap_or(edition),
+            "doctest": flag("doctest", is_lib),
+            "test": flag("test", true),
+            "doc": flag("doc", true),
+        })
+    };
+    let mut targets = Vec::new();
+
+    let lib = manifest.get("lib").and_then(toml::Value::as_table);
+    let lib_value = |key: &str| lib.and_then(|lib| lib.get(key));
+    let lib_path = match lib_value("path").and_then(toml::Value::as_str) {
+        Some(path) => Some(dir.join(path)),
+        None => Some(dir.join("src").join("lib.rs")).filter(|path| path.is_file()),
+    };
+    let mut lib_name = None;
+    if let Some(lib_path) = lib_path {
+        let proc_macro = lib_value("proc-macro")
+            .or_else(|| lib_value("proc_macro"))
+            .and_then(toml::Value::as_bool)
+            == Some(true);
+        let mut crate_types = str_list(lib_value("crate-type").or_else(|| lib_value("crate_type")));
+        if crate_types.is_empty() {
+            crate_types.push(if proc_macro { "proc-macro" } else { "lib" }.to_string());
+        }
+        let name = lib_value("name")
+            .and_then(toml::Value::as_str)
+            .map(str::to_string)
+            .unwrap_or_else(|| package_name.replace('-', "_"));
+        targets.push(target(
+            &name,
+            crate_types.clone(),
+            crate_types,
+            lib_path,
+            lib,
+        ));
+        lib_name = Some(name);
+    }
+
+    for (section, auto_key, auto_dir) in [
+        ("bin", "autobins", dir.join("src").join("bin")),
+        ("example", "autoexamples", dir.join("examples")),
+        ("test", "autotests", dir.join("tests")),
+        ("bench", "autobenches", dir.join("benches")),
+    ] {
+        let mut discovered = Vec::new();
+        let main = dir.join("src").join("main.rs");
+        if section == "bin" && main.is_file() {
+            discovered.push((package_name.to_string(), main));
+        }
+        discovered.extend(discover_targets(&auto_dir));
+
+        let mut declared = Vec::new();
+        let mut section_targets = Vec::new();
+        let tables = manifest
+            .get(section)
+            .and_then(toml::Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(toml::Value::as_table);
+        for table in tables {
+            let Some(name) = table.get("name").and_then(toml::Value::as_str) else {
+                continue;
+            };
+            let path = match table.get("path").and_then(toml::Value::as_str) {
+                Some(path) => dir.join(path),
+                None => discovered
+                    .iter()
+                    .find(|(discovered_name, _)| discovered_name == name)
+                    .map(|(_, path)| path.clone())
+                    .unwrap_or_else(|| auto_dir.join(format!("{}.rs", name))),
+            };
+            let mut crate_types = Vec::new();
+            if section == "example" {
+                crate_types = str_list(table.get("crate-type").or_else(|| table.get("crate_type")));
+            }
+            if crate_types.is_empty() {
+                crate_types.push("bin".to_string());
+            }
+            section_targets.push((
+                name.to_string(),
+                target(
+                    name,
+                    vec![section.to_string()],
+                    crate_types,
+                    path.clone(),
+                    Some(table),
+                ),
+            ));
+            declared.push((name.to_string(), path));
+        }
+
+        if package.get(auto_key).and_then(toml::Value::as_bool) != Some(false) {
+            for (name, path) in discovered {
+                if declared.iter().any(|(declared_name, declared_path)| {
+                    *declared_name == name || *declared_path == path
+                }) {
+                    continue;
+                }
+                let discovered_target = target(
+                    &name,
+                    vec![section.to_string()],
+                    vec!["bin".to_string()],
+                    path,
+                    None,
+                );
+                section_targets.push((name, discovered_target));
+            }
+        }
+        // Like cargo, sort the targets of every kind by name.
+        section_targets.sort_by(|(a, _), (b, _)| a.cmp(b));
+        targets.extend(section_targets.into_iter().map(|(_, target)| target));
+    }
+
+    let build = match package.get("build") {
+        Some(toml::Value::String(path)) => Some(dir.join(path)),
+        Some(toml::Value::Boolean(false)) => None,
+        _ => Some(dir.join("build.rs")).filter(|path| path.is_file()),
+    };
+    if let Some(build) = build {
+        targets.push(target(
+            "build-script-build",
+            vec!["custom-build".to_string()],
+            vec!["bin".to_string()],
+            build,
+            None,
+        ));
+    }
+
+    (targets, lib_name)
+}
+
+/// The auto-discovered targets in a directory: `<name>.rs` files and `<name>/main.rs`.
+fn discover_targets(dir: &Path) -> Vec<(String, PathBuf)> {
+    sorted_entries(dir)
+        .into_iter()
+        .filter_map(|path| {
+            if path.is_dir() {
+                let main = path.join("main.rs");
+                let name = path.file_name()?.to_string_lossy().to_string();
+                main.is_file().then_some((name, main))
+            } else if path.extension().and_then(|extension| extension.to_str()) == Some("rs") {
+                let name = path.file_stem()?.to_string_lossy().to_string();
+                Some((name, path))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The `cargo metadata` options that influence the resolution.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FeatureOptions {
+    all_features: bool,
+    no_default_features: bool,
+    /// Features of workspace members, optionally prefixed with `<package>/`.
+    features: Vec<String>,
+}
+
+impl FeatureOptions {
+    fn parse(options: &[String]) -> FeatureOptions {
+        let mut feature_options = FeatureOptions::default();
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.as_str() {
+                "--all-features" => feature_options.all_features = true,
+                "--no-default-features" => feature_options.no_default_features = true,
+                "--features" => feature_options.features.extend(
+                    options
+                        .next()
+                        .into_iter()
+                        .flat_map(|features| features.split([' ', ',']))
+                        .filter(|feature| !feature.is_empty())
+                        .map(str::to_string),
+                ),
+                "--locked" | "--frozen" | "--offline" => {}
+                other => warn!(
+                    "Ignoring the cargo metadata option {} with --lockfile-only.",
+                    other
+                ),
+            }
+        }
+        feature_options
+    }
+
+    /// The features to enable for a workspace member.
+    fn features_of(&self, package: &LockedPackage) -> Vec<String> {
+        if self.all_features {
+            return package.features.keys().cloned().collect();
+        }
+        let mut features = Vec::new();
+        if !self.no_default_features {
+            features.push("default".to_string());
+        }
+        for feature in &self.features {
+            match feature.split_once('/') {
+                Some((package_name, feature)) if package_name == package.name => {
+                    features.push(feature.to_string())
+                }
+                Some(_) => {}
+                None if package.features.contains_key(feature) => features.push(feature.clone()),
+                None => {}
+            }
+        }
+        features
+    }
+}
+
+/// The enabled features and dependencies of all packages in the resolved graph.
+#[derive(Debug, Default)]
+struct Resolution {
+    /// Enabled features by package index. Packages outside of the resolved graph are missing.
+    features: BTreeMap<usize, BTreeSet<String>>,
+    /// Enabled dependencies by package index, as indices into `LockedPackage::dependencies`.
+    dependencies: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+impl Resolution {
+    fn resolve(packages: &[LockedPackage], options: &FeatureOptions) -> Resolution {
+        let mut resolver = Resolver {
+            packages,
+            resolution: Resolution::default(),
+            changed: false,
+        };
+        for (index, package) in packages.iter().enumerate() {
+            if package.is_member {
+                resolver.activate(index);
+                for feature in options.features_of(package) {
+                    resolver.enable(index, &feature);
+                }
+            }
+        }
+        resolver.run();
+        resolver.resolution
+    }
+}
+
+/// Enables features and dependencies until nothing changes anymore.
+struct Resolver<'a> {
+    packages: &'a [LockedPackage],
+    resolution: Resolution,
+    changed: bool,
+}
+
+impl Resolver<'_> {
+    fn run(&mut self) {
+        let packages = self.packages;
+        loop {
+            self.changed = false;
+            let active: Vec<usize> = self.resolution.features.keys().copied().collect();
+            for index in active {
+                let package = &packages[index];
+                let features: Vec<String> =
+                    self.resolution.features[&index].iter().cloned().collect();
+                for feature in features {
+                    for value in &package.features[&feature] {
+                        self.enable(index, value);
+                    }
+                }
+                let dependencies: Vec<usize> = self.resolution.dependencies[&index]
+                    .iter()
+                    .copied()
+                    .collect();
+                for dependency_index in dependencies {
+                    let Some(dependency_package) = package.dependency_package(dependency_index)
+                    else {
+                        continue;
+                    };
+                    let dependency = &package.dependencies[dependency_index];
+                    self.activate(dependency_package);
+                    if dependency.uses_default_features {
+                        self.enable(dependency_package, "default");
+                    }
+                    for feature in &dependency.features {
+                        self.enable(dependency_package, feature);
+                    }
+                }
+            }
+            if !self.changed {
+                break;
+            }
+        }
+    }
+
+    /// Adds a package to the resolved graph with its non-optional dependencies.
+    fn activate(&mut self, index: usize) {
+        if self.resolution.features.contains_key(&index) {
+            return;
+        }
+        let package = &self.packages[index];
+        let required = (0..package.dependencies.len())
+            .filter(|i| {
+                !package.dependencies[*i].optional && package.dependency_package(*i).is_some()
+            })
+            .collect();
+        self.resolution.features.insert(index, BTreeSet::new());
+        self.resolution.dependencies.insert(index, required);
+        self.changed = true;
+    }
+
+    /// Applies a feature value: a feature, `dep:<dependency>` or `<dependency>[?]/<feature>`.
+    fn enable(&mut self, index: usize, value: &str) {
+        let packages = self.packages;
+        let package = &packages[index];
+        self.activate(index);
+        if let Some(key) = value.strip_prefix("dep:") {
+            self.enable_dependency(index, key);
+        } else if let Some((key, feature)) = value.split_once('/') {
+            // Like the dependency resolver of cargo, we also add optional dependencies with weak
+            // features (`dependency?/feature`) to the graph. Only the feature resolution in Nix
+            // applies them conditionally.
+            let weak = key.ends_with('?');
+            let key = key.trim_end_matches('?');
+            self.enable_dependency(index, key);
+            if !weak && package.features.contains_key(key) {
+                self.enable(index, key);
+            }
+            let dependency_packages: Vec<usize> = self.resolution.dependencies[&index]
+                .iter()
+                .filter(|i| package.dependencies[**i].key == key)
+                .filter_map(|i| package.dependency_package(*i))
+                .collect();
+            for dependency_package in dependency_packages {
+                self.activate(dependency_package);
+                self.enable(dependency_package, feature);
+            }
+        } else if let Some(values) = package.features.get(value) {
+            let features = self.resolution.features.entry(index).or_default();
+            if features.insert(value.to_string()) {
+                self.changed = true;
+                for value in values {
+                    self.enable(index, value);
+                }
+            }
+        } else {
+            // Cargo also accepts the names of optional dependencies without implicit features.
+            self.enable_dependency(index, value);
+        }
+    }
+
+    /// Enables the optional dependencies with the given name in the dependency sections.
+    fn enable_dependency(&mut self, index: usize, key: &str) {
+        let package = &self.packages[index];
+        let enabled = self.resolution.dependencies.entry(index).or_default();
+        for (dependency_index, dependency) in package.dependencies.iter().enumerate() {
+            if dependency.key == key
+                && package.dependency_package(dependency_index).is_some()
+                && enabled.insert(dependency_index)
+            {
+                self.changed = true;
+            }
+        }
+    }
+}
+
+/// Renders the resolved graph in the JSON format of `cargo metadata`.
+fn metadata_json(
+    cargo_toml: &Path,
+    workspace: &Workspace,
+    packages: &[LockedPackage],
+    resolution: &Resolution,
+) -> Result<Value, Error> {
+    let mut nodes = Vec::new();
+    for (index, features) in &resolution.features {
+        let package = &packages[*index];
+        let mut deps: BTreeMap<usize, (String, Vec<Value>)> = BTreeMap::new();
+        for dependency_index in &resolution.dependencies[index] {
+            let Some(dependency_package) = package.dependency_package(*dependency_index) else {
+                continue;
+            };
+            let dependency = &package.dependencies[*dependency_index];
+            let name = match &dependency.rename {
+                Some(rename) => rename.replace('-', "_"),
+                None => packages[dependency_package]
+                    .lib_name
+                    .clone()
+                    .unwrap_or_else(|| dependency.name.replace('-', "_")),
+            };
+            let (_, dep_kinds) = deps
+                .entry(dependency_package)
+                .or_insert_with(|| (name, Vec::new()));
+            let dep_kind = json!({
+                "kind": kind_json(dependency.kind),
+                "target": dependency.target,
+            });
+            if !dep_kinds.contains(&dep_kind) {
+                dep_kinds.push(dep_kind);
+            }
+        }
+        nodes.push(json!({
+            "id": package.id,
+            "dependencies": deps.keys().map(|i| &packages[*i].id).collect::<Vec<_>>(),
+            "deps": deps
+                .iter()
+                .map(|(i, (name, dep_kinds))| json!({
+                    "name": name,
+                    "pkg": packages[*i].id,
+                    "dep_kinds": dep_kinds,
+                }))
+                .collect::<Vec<_>>(),
+            "features": features,
+        }));
+    }
+
+    let ids = |manifest_paths: &[PathBuf]| -> Vec<&str> {
+        packages
+            .iter()
+            .filter(|package| package.is_member && manifest_paths.contains(&package.manifest_path))
+            .map(|package| package.id.as_str())
+            .collect()
+    };
+    let root = packages
+        .iter()
+        .find(|package| package.is_member && package.manifest_path == cargo_toml)
+        .map(|package| &package.id);
+    let workspace_metadata = workspace
+        .manifest
+        .get("workspace")
+        .and_then(|workspace| workspace.get("metadata"))
+        .map(serde_json::to_value)
+        .transpose()?;
+
+    Ok(json!({
+        "packages": resolution
+            .features
+            .keys()
+            .map(|i| &packages[*i].json)
+            .collect::<Vec<_>>(),
+        "workspace_members": ids(&workspace.members),
+        "workspace_default_members": ids(&workspace.default_members),
+        "resolve": {
+            "nodes": nodes,
+            "root": root,
+        },
+        "workspace_root": workspace.root,
+        "target_directory": workspace.root.join("target"),
+        "version": 1,
+        "metadata": workspace_metadata,
+    }))
+}
+
+fn kind_json(kind: DependencyKind) -> Value {
+    match kind {
+        DependencyKind::Development => json!("dev"),
+        DependencyKind::Build => json!("build"),
+        _ => Value::Null,
+    }
+}
+
+fn str_list(value: Option<&toml::Value>) -> Vec<String> {
+    value
+        .and_then(toml::Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+/// Canonicalizes existing paths and returns the others unchanged.
+fn normalize(path: PathBuf) -> PathBuf {
+    path.canonicalize().unwrap_or(path)
+}
+
+fn sorted_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// Expands the `*` and `?` wildcards in the path of a workspace member.
+fn expand_glob(root: &Path, glob: &str) -> Vec<PathBuf> {
+    let mut dirs = vec![root.to_path_buf()];
+    for component in glob.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        dirs = if component.contains(['*', '?']) {
+            dirs.iter()
+                .flat_map(|dir| sorted_entries(dir))
+                .filter(|entry| {
+                    entry.is_dir()
+                        && entry.file_name().is_some_and(|name| {
+                            crate::util::wildcard_match(component, &name.to_string_lossy())
+                        })
+                })
+                .collect()
+        } else {
+            dirs.iter().map(|dir| dir.join(component)).collect()
+        };
+    }
+    dirs
+}
+
+#[test]
+fn test_metadata() {
+    let dir = tempdir::TempDir::new("crate2nix_lockfile_metadata").expect("temp dir");
+    let write = |path: &str, content: &str| {
+        let path = dir.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    };
+    write(
+        "Cargo.toml",
+        r#"
+        [workspace]
+        members = ["crates/*"]
+        [workspace.package]
+        version = "0.2.0"
+        edition = "2021"
+        [workspace.dependencies]
+        vendored = { version = "1", default-features = false }
+        "#,
+    );
+    write(
+        "crates/app/Cargo.toml",
+        r#"
+        [package]
+        name = "app"
+        version.workspace = true
+        edition.workspace = true
+        [dependencies]
+        vendored = { workspace = true, features = ["extra"] }
+        util = { path = "../../helper", package = "helper" }
+        "#,
+    );
+    write("crates/app/src/main.rs", "");
+    write(
+        "helper/Cargo.toml",
+        r#"
+        [package]
+        name = "helper"
+        version = "0.1.0"
+        [dependencies]
+        vendored = { version = "1", optional = true }
+        [features]
+        fancy = ["vendored?/extra"]
+        "#,
+    );
+    write("helper/src/lib.rs", "");
+    write(
+        ".cargo/config.toml",
+        r#"
+        [source.crates-io]
+        replace-with = "vendored-sources"
+        [source.vendored-sources]
+        directory = "vendor"
+        "#,
+    );
+    write(
+        "vendor/vendored/Cargo.toml",
+        r#"
+        [package]
+        name = "vendored"
+        version = "1.0.3"
+        [features]
+        default = ["std"]
+        std = []
+        extra = []
+        "#,
+    );
+    write("vendor/vendored/src/lib.rs", "");
+    write(
+        "Cargo.lock",
+        r#"
+        version = 3
+        [[package]]
+        name = "app"
+        version = "0.2.0"
+        dependencies = ["helper", "vendored"]
+        [[package]]
+        name = "helper"
+        version = "0.1.0"
+        dependencies = ["vendored"]
+        [[package]]
+        name = "vendored"
+        version = "1.0.3"
+        source = "registry+https://github.com/rust-lang/crates.io-index"
+        "#,
+    );
+
+    let metadata = metadata(
+        &dir.path().join("Cargo.toml"),
+        None,
+        &["--locked".to_string()],
+    )
+    .unwrap();
+    let package = |name: &str| metadata.packages.iter().find(|p| p.name == name).unwrap();
+    let node = |name: &str| {
+        let id = &package(name).id;
+        let resolve = metadata.resolve.as_ref().unwrap();
+        resolve.nodes.iter().find(|n| n.id == *id).unwrap()
+    };
+
+    assert_eq!(metadata.workspace_members, vec![package("app").id.clone()]);
+    assert_eq!(package("app").version, Version::new(0, 2, 0));
+    assert_eq!(package("app").edition.to_string(), "2021");
+    assert_eq!(package("app").targets[0].name, "app");
+    assert!(package("vendored")
+        .manifest_path
+        .starts_with(dir.path().join("vendor")));
+    assert_eq!(
+        package("helper").features.get("vendored"),
+        Some(&vec!["dep:vendored".to_string()])
+    );
+
+    let dep_names: Vec<&str> = node("app").deps.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(dep_names, vec!["util", "vendored"]);
+    // The optional dependency is not enabled.
+    assert!(node("helper").deps.is_empty());
+    // No default features from the workspace dependency but the additional feature.
+    assert_eq!(node("vendored").features, vec!["extra".to_string()]);
+
+    dir.close().unwrap();
+}
+
+#[cfg(test)]
+fn write_files(dir: &Path, files: &[(&str, &str)]) {
+    for (path, content) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+}
+
+#[test]
+fn test_discover_targets() {
+    let dir = tempdir::TempDir::new("crate2nix_lockfile_targets").expect("temp dir");
+    write_files(
+        dir.path(),
+        &[
+            ("src/main.rs", ""),
+            ("src/bin/tool.rs", ""),
+            ("src/bin/server/main.rs", ""),
+            ("src/bin/helpers/mod.rs", ""),
+            ("src/bin/README.md", ""),
+            ("examples/demo.rs", ""),
+            ("build.rs", ""),
+        ],
+    );
+
+    let bin_dir = dir.path().join("src").join("bin");
+    assert_eq!(
+        discover_targets(&bin_dir),
+        vec![
+            ("server".to_string(), bin_dir.join("server").join("main.rs")),
+            ("tool".to_string(), bin_dir.join("tool.rs")),
+        ]
+    );
+    assert_eq!(discover_targets(&dir.path().join("benches")), vec![]);
+
+    let manifest: toml::Table = toml::from_str(
+        r#"
+        [package]
+        name = "my-app"
+        [[example]]
+        name = "declared"
+        path = "examples/other/declared.rs"
+        "#,
+    )
+    .unwrap();
+    let package = manifest["package"].as_table().unwrap();
+    let (discovered, lib_name) = targets(dir.path(), &manifest, package, "my-app", "2021");
+    let kinds_and_names: Vec<(&str, &str)> = discovered
+        .iter()
+        .map(|t| (t["kind"][0].as_str().unwrap(), t["name"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        kinds_and_names,
+        vec![
+            ("bin", "my-app"),
+            ("bin", "server"),
+            ("bin", "tool"),
+            ("example", "declared"),
+            ("example", "demo"),
+            ("custom-build", "build-script-build"),
+        ]
+    );
+    assert_eq!(lib_name, None);
+    assert_eq!(
+        discovered[5]["src_path"],
+        json!(dir.path().join("build.rs"))
+    );
+
+    // `build = false` and `autobins = false` disable the discovery.
+    let manifest: toml::Table = toml::from_str(
+        r#"
+        [package]
+        name = "my-app"
+        build = false
+        autobins = false
+        autoexamples = false
+        "#,
+    )
+    .unwrap();
+    let package = manifest["package"].as_table().unwrap();
+    let (discovered, _) = targets(dir.path(), &manifest, package, "my-app", "2021");
+    assert!(discovered.is_empty());
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_platform_and_build_dependencies() {
+    let dir = tempdir::TempDir::new("crate2nix_lockfile_dependencies").expect("temp dir");
+    let package = |name: &str| {
+        format!(
+            "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+            name
+        )
+    };
+    let app = package("app")
+        + r#"
+        [target.'cfg(unix)'.dependencies]
+        unix-only = { path = "../unix-only" }
+        [target.x86_64-pc-windows-msvc.dependencies]
+        windows-only = { path = "../windows-only" }
+        [build-dependencies]
+        codegen = { path = "../codegen" }
+        "#;
+    write_files(
+        dir.path(),
+        &[
+            ("app/Cargo.toml", &app),
+            ("app/src/lib.rs", ""),
+            ("unix-only/Cargo.toml", &package("unix-only")),
+            ("unix-only/src/lib.rs", ""),
+            ("windows-only/Cargo.toml", &package("windows-only")),
+            ("windows-only/src/lib.rs", ""),
+            ("codegen/Cargo.toml", &package("codegen")),
+            ("codegen/src/lib.rs", ""),
+            (
+                "app/Cargo.lock",
+                r#"
+                version = 3
+                [[package]]
+                name = "app"
+                version = "0.1.0"
+                dependencies = ["codegen", "unix-only", "windows-only"]
+                [[package]]
+                name = "codegen"
+                version = "0.1.0"
+                [[package]]
+                name = "unix-only"
+                version = "0.1.0"
+                [[package]]
+                name = "windows-only"
+                version = "0.1.0"
+                "#,
+            ),
+        ],
+    );
+
+    let metadata = metadata(&dir.path().join("app").join("Cargo.toml"), None, &[]).unwrap();
+    let resolve = metadata.resolve.as_ref().unwrap();
+    let app_id = &metadata
+        .packages
+        .iter()
+        .find(|p| p.name == "app")
+        .unwrap()
+        .id;
+    let app = resolve.nodes.iter().find(|n| n.id == *app_id).unwrap();
+    let dep_kinds: Vec<(&str, String, Option<String>)> = app
+        .deps
+        .iter()
+        .flat_map(|dep| {
+            dep.dep_kinds.iter().map(|kind| {
+                (
+                    dep.name.as_str(),
+                    kind.kind.to_string(),
+                    kind.target.as_ref().map(|target| target.to_string()),
+                )
+            })
+        })
+        .collect();
+    assert_eq!(
+        dep_kinds,
+        vec![
+            ("codegen", "build".to_string(), None),
+            (
+                "unix_only",
+                "normal".to_string(),
+                Some("cfg(unix)".to_string())
+            ),
+            (
+                "windows_only",
+                "normal".to_string(),
+                Some("x86_64-pc-windows-msvc".to_string())
+            ),
+        ]
+    );
+
+    dir.close().unwrap();
+}
+
+#[test]
+fn test_downloaded_manifest() {
+    let cargo_home = tempdir::TempDir::new("crate2nix_lockfile_cargo_home").expect("temp dir");
+    let version = Version::new(1, 2, 3);
+    write_files(
+        cargo_home.path(),
+        &[
+            (
+                "registry/src/index.crates.io-6f17d22bba15001f/serde-1.2.3/Cargo.toml",
+                "[package]\nname = \"serde\"\nversion = \"1.2.3\"\n",
+            ),
+            (
+                "git/checkouts/mono-0123456789abcdef/abcdef1/crates/tool/Cargo.toml",
+                "[package]\nname = \"tool\"\nversion = \"1.2.3\"\n",
+            ),
+            (
+                "git/checkouts/mono-0123456789abcdef/abcdef1/Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\"]\n",
+            ),
+        ],
+    );
+    let mut files = TomlFiles::default();
+
+    assert_eq!(
+        downloaded_manifest(
+            &mut files,
+            cargo_home.path(),
+            "serde",
+            &version,
+            "registry+https://github.com/rust-lang/crates.io-index",
+        ),
+        Some(
+            cargo_home
+                .path()
+                .join("registry/src/index.crates.io-6f17d22bba15001f/serde-1.2.3/Cargo.toml")
+        )
+    );
+    assert_eq!(
+        downloaded_manifest(
+            &mut files,
+            cargo_home.path(),
+            "serde",
+            &Version::new(1, 0, 0),
+            "registry+https://github.com/rust-lang/crates.io-index",
+        ),
+        None
+    );
+    assert_eq!(
+        downloaded_manifest(
+            &mut files,
+            cargo_home.path(),
+            "tool",
+            &version,
+            "git+https://example.com/mono.git#abcdef1234567890",
+        ),
+        Some(
+            cargo_home
+                .path()
+                .join("git/checkouts/mono-0123456789abcdef/abcdef1/crates/tool/Cargo.toml")
+        )
+    );
+    // Another revision is not checked out.
+    assert_eq!(
+        downloaded_manifest(
+            &mut files,
+            cargo_home.path(),
+            "tool",
+            &version,
+            "git+https://example.com/mono.git#fedcba9876543210",
+        ),
+        None
+    );
+
+    cargo_home.close().unwrap();
+}
+
+#[test]
+fn test_feature_options() {
+    let dir = tempdir::TempDir::new("crate2nix_lockfile_features").expect("temp dir");
+    write_files(
+        dir.path(),
+        &[
+            (
+                "Cargo.toml",
+                r#"
+                [package]
+                name = "app"
+                version = "0.1.0"
+                [features]
+                default = ["std"]
+                std = []
+                fast = []
+                extra = []
+                "#,
+            ),
+            ("src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                "version = 3\n[[package]]\nname = \"app\"\nversion = \"0.1.0\"\n",
+            ),
+        ],
+    );
+    let features = |options: &[&str]| {
+        let options: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+        let metadata = metadata(&dir.path().join("Cargo.toml"), None, &options).unwrap();
+        let mut features = metadata.resolve.unwrap().nodes[0].features.clone();
+        features.sort();
+        features
+    };
+
+    assert_eq!(features(&[]), vec!["default", "std"]);
+    assert_eq!(features(&["--no-default-features"]), Vec::<String>::new());
+    assert_eq!(
+        features(&["--no-default-features", "--features", "fast app/extra"]),
+        vec!["extra", "fast"]
+    );
+    assert_eq!(
+        features(&["--features", "fast,other/extra"]),
+        vec!["default", "fast", "std"]
+    );
+    assert_eq!(
+        features(&["--all-features"]),
+        vec!["default", "extra", "fast", "std"]
+    );
+
+    assert_eq!(
+        FeatureOptions::parse(&[
+            "--no-default-features".to_string(),
+            "--features".to_string(),
+            "a, b".to_string(),
+            "--locked".to_string(),
+        ]),
+        FeatureOptions {
+            all_features: false,
+            no_default_features: true,
+            features: vec!["a".to_string(), "b".to_string()],
+        }
+    );
+
+    dir.close().unwrap();
+}
@@ -0,0 +1,259 @@
+//! Generating against an alternative `Cargo.lock`, see `--lockfile`.
+//!
+//! Stable cargo always reads the `Cargo.lock` next to the workspace manifest, so cargo runs on a
+//! mirror of the workspace in a temporary directory: Every entry of the workspace directory is
+//! symlinked into the mirror, except `Cargo.lock`, which is a copy of the alternative lockfile.
+//! The directories above the workspace are mirrored the same way, so that path dependencies
+//! outside of the workspace resolve as well. The mirror has the same absolute paths below the
+//! temporary directory, which are mapped back in the output of cargo. The workspace itself is
+//! never written to. If cargo updates the lockfile, e.g. with `--allow-lock-update`, the update
+//! is copied to the alternative lockfile.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, format_err, Context, Error};
+use log::{debug, warn};
+
+/// The default output for `lockfile`: `Cargo.<profile>.nix` in the current directory, where the
+/// profile is the file stem of `lockfile` without a `Cargo.` prefix.
+pub fn default_output(lockfile: &Path) -> PathBuf {
+    let stem = lockfile
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let profile = stem.strip_prefix("Cargo.").unwrap_or(&stem);
+    PathBuf::from(format!("./Cargo.{}.nix", profile))
+}
+
+/// The workspace root that cargo uses for `cargo_toml`, i.e. the directory of its `Cargo.lock`.
+pub(crate) fn workspace_root(cargo_toml: &Path) -> Result<PathBuf, Error> {
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = std::process::Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .arg("--manifest-path")
+        .arg(cargo_toml)
+        .output()
+        .context("while running cargo locate-project")?;
+    if !output.status.success() {
+        bail!(
+            "cargo locate-project failed for {}: {}",
+            cargo_toml.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let manifest = String::from_utf8(output.stdout)
+        .context("while reading the output of cargo locate-project")?;
+    Path::new(manifest.trim())
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| format_err!("cargo located no workspace for {}", cargo_toml.display()))
+}
+
+/// A mirror of a workspace with an alternative lockfile, removed when dropped.
+pub(crate) struct MirroredWorkspace {
+    /// The temporary directory that the mirror is in.
+    dir: PathBuf,
+    /// The mirror of the workspace root.
+    root: PathBuf,
+    alternative: PathBuf,
+    alternative_content: Vec<u8>,
+}
+
+impl MirroredWorkspace {
+    /// Mirrors `workspace_root` with `alternative` as its `Cargo.lock`.
+    pub fn new(workspace_root: &Path, alternative: &Path) -> Result<MirroredWorkspace, Error> {
+        static MIRRORS: AtomicUsize = AtomicUsize::new(0);
+
+        let alternative_content = std::fs::read(alternative)
+            .with_context(|| format!("while reading {}", alternative.display()))?;
+        let workspace_root = std::path::absolute(workspace_root)
+            .with_context(|| format!("while resolving {}", workspace_root.display()))?;
+        let dir = std::env::temp_dir().join(format!(
+            "crate2nix-lockfile-{}-{}",
+            std::process::id(),
+            MIRRORS.fetch_add(1, Ordering::SeqCst)
+        ));
+        // Left behind if an earlier crate2nix with the same process id was killed.
+        let _ = std::fs::remove_dir_all(&dir);
+        // Removes what was mirrored so far if mirroring fails.
+        let mirror = MirroredWorkspace {
+            root: mirror_path(&dir, &workspace_root),
+            dir,
+            alternative: alternative.to_path_buf(),
+            alternative_content,
+        };
+
+        let mut skip = Some(std::ffi::OsString::from("Cargo.lock"));
+        for (level, directory) in workspace_root.ancestors().enumerate() {
+            let entries = match std::fs::read_dir(directory) {
+                Ok(entries) => entries,
+                Err(e) if level > 0 => {
+                    debug!("Not mirroring {}: {}", directory.display(), e);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("while reading {}", directory.display()))
+                }
+            };
+            let mirrored = mirror_path(&mirror.dir, directory);
+            std::fs::create_dir_all(&mirrored)
+                .with_context(|| format!("while creating {}", mirrored.display()))?;
+            for entry in entries {
+                let name = entry?.file_name();
+                let original = directory.join(&name);
+                if Some(&name) != skip.as_ref() && original != mirror.dir {
+                    symlink(&original, &mirrored.join(&name))?;
+                }
+            }
+            skip = directory.file_name().map(|name| name.to_os_string());
+        }
+
+        let lock_file = mirror.lock_file();
+        std::fs::write(&lock_file, &mirror.alternative_content)
+            .with_context(|| format!("while writing {}", lock_file.display()))?;
+        debug!(
+            "Resolving with {} in {}.",
+            mirror.alternative.display(),
+            mirror.root.display()
+        );
+        Ok(mirror)
+    }
+
+    /// The path of `path`, which is in the mirrored workspace, in the mirror.
+    pub fn path_of(&self, path: &Path) -> Result<PathBuf, Error> {
+        let path = std::path::absolute(path)
+            .with_context(|| format!("while resolving {}", path.display()))?;
+        Ok(mirror_path(&self.dir, &path))
+    }
+
+    /// The `Cargo.lock` that cargo reads and updates.
+    pub fn lock_file(&self) -> PathBuf {
+        self.root.join("Cargo.lock")
+    }
+
+    /// Maps the paths in the mirror back to the original paths in the JSON output of cargo.
+    pub fn unmirror(&self, json: &str) -> String {
+        json.replace(&format!("{}/", self.dir.display()), "/")
+    }
+
+    /// Copies the updates of cargo to the lockfile, if any, to the alternative lockfile.
+    pub fn copy_updates(&self) -> Result<(), Error> {
+        let content = std::fs::read(self.lock_file()).ok();
+        match content {
+            Some(content) if content != self.alternative_content => {
+                std::fs::write(&self.alternative, content)
+                    .with_context(|| format!("while updating {}", self.alternative.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for MirroredWorkspace {
+    fn drop(&mut self) {
+        // Only removes the symlinks, not what they point to.
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            warn!("Could not remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// The path of the absolute `path` below `dir`.
+fn mirror_path(dir: &Path, path: &Path) -> PathBuf {
+    let mut mirrored = dir.to_path_buf();
+    mirrored.extend(path.components().filter_map(|component| match component {
+        Component::Normal(name) => Some(Path::new(name)),
+        Component::ParentDir => Some(Path::new("..")),
+        Component::Prefix(_) | Component::RootDir | Component::CurDir => None,
+    }));
+    mirrored
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(original, link)
+        .with_context(|| format!("while linking {}", original.display()))
+}
+
+#[cfg(not(unix))]
+fn symlink(original: &Path, _link: &Path) -> Result<(), Error> {
+    bail!(
+        "--lockfile needs symlinks to mirror {}, it is only supported on unix.",
+        original.display()
+    )
+}
+
+#[test]
+fn test_default_output() {
+    assert_eq!(
+        default_output(Path::new("lockfiles/Cargo.minimal.lock")),
+        PathBuf::from("./Cargo.minimal.nix")
+    );
+    assert_eq!(
+        default_output(Path::new("msrv.lock")),
+        PathBuf::from("./Cargo.msrv.nix")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_mirrored_workspace() {
+    let dir = tempdir::TempDir::new("crate2nix_lockfile_profiles").expect("temp dir");
+    let workspace = dir.path().join("workspace");
+    std::fs::create_dir_all(workspace.join("member")).unwrap();
+    std::fs::create_dir_all(dir.path().join("outside")).unwrap();
+    let workspace_lock = workspace.join("Cargo.lock");
+    let alternative = workspace.join("Cargo.minimal.lock");
+    std::fs::write(&workspace_lock, "original").unwrap();
+    std::fs::write(&alternative, "minimal").unwrap();
+    std::fs::write(workspace.join("member").join("Cargo.toml"), "member").unwrap();
+    std::fs::write(dir.path().join("outside").join("Cargo.toml"), "outside").unwrap();
+
+    let mirror = MirroredWorkspace::new(&workspace, &alternative).unwrap();
+    let mirrored_member = mirror
+        .path_of(&workspace.join("member").join("Cargo.toml"))
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&mirrored_member).unwrap(), "member");
+    // Path dependencies outside of the workspace.
+    let outside = mirror
+        .path_of(&dir.path().join("outside").join("Cargo.toml"))
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(outside).unwrap(), "outside");
+    assert_eq!(
+        std::fs::read_to_string(mirror.lock_file()).unwrap(),
+        "minimal"
+    );
+    assert_eq!(
+        mirror.unmirror(&format!(
+            r#"{{"manifest_path":"{}","id":"path+file://{}#0.1.0"}}"#,
+            mirrored_member.display(),
+            mirrored_member.parent().unwrap().display()
+        )),
+        format!(
+            r#"{{"manifest_path":"{}","id":"path+file://{}#0.1.0"}}"#,
+            workspace.join("member").join("Cargo.toml").display(),
+            workspace.join("member").display()
+        )
+    );
+
+    std::fs::write(mirror.lock_file(), "minimal, updated").unwrap();
+    mirror.copy_updates().unwrap();
+    let mirror_dir = mirror.dir.clone();
+    drop(mirror);
+    assert!(!mirror_dir.exists());
+    assert_eq!(
+        std::fs::read_to_string(&workspace_lock).unwrap(),
+        "original"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&alternative).unwrap(),
+        "minimal, updated"
+    );
+    assert_eq!(
+        std::fs::read_to_string(workspace.join("member").join("Cargo.toml")).unwrap(),
+        "member"
+    );
+
+    dir.close().unwrap();
+}
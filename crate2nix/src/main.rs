@@ -1,16 +1,21 @@
 use std::path::{Path, PathBuf};
-use structopt::clap::ArgGroup;
+use structopt::clap::{ArgGroup, Shell};
 use structopt::StructOpt;
 
 use anyhow::format_err;
 use anyhow::{bail, Error};
 use crate2nix::{
     config::{Config, NixFile},
+    graph::GraphFormat,
+    incremental::GenerationCache,
+    licenses::LicenseReportFormat,
     render,
 };
+use log::{info, warn, Level, LevelFilter};
 use semver::Version;
 use serde::Deserialize;
 use serde::Serialize;
+use std::io::Write;
 use std::str::FromStr;
 
 const DEFAULT_OUTPUT: &str = "./Cargo.nix";
@@ -20,7 +25,35 @@ const DEFAULT_OUTPUT: &str = "./Cargo.nix";
     name = "crate2nix",
     about = "Nix build file generator for a cargo rust projects."
 )]
-pub enum Opt {
+pub struct Opt {
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        parse(from_occurrences),
+        global = true,
+        help = "Log more details, e.g. resolution decisions. \
+                Specify twice for even more output. \
+                The CRATE2NIX_LOG environment variable accepts env_logger filters."
+    )]
+    verbose: u8,
+
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        parse(from_occurrences),
+        global = true,
+        help = "Only log warnings and errors. Specify twice to only log errors."
+    )]
+    quiet: u8,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+// Parsed once, so the size of `Generate` does not matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt, Deserialize, Serialize)]
+pub enum Command {
     #[structopt(
         name = "generate",
         about = "Generates a Cargo.nix file from a cargo rust project."
@@ -98,6 +131,313 @@ pub enum Opt {
         )]
         crate_hashes: Option<PathBuf>,
 
+        #[structopt(
+            long = "crate-hashes-format",
+            help = "The format of the crate hash cache file: 'plain' maps package IDs to hashes, \
+                    'detailed' also records the SRI hash and the source URL, one crate per line.",
+            default_value = "plain"
+        )]
+        crate_hashes_format: crate2nix::crate_hashes::CrateHashesFormat,
+
+        #[structopt(
+            long = "split-crate-hashes",
+            help = "Write the crate hashes to one 'crate-hashes.<registry>.json' file per registry, \
+                    e.g. 'crate-hashes.crates-io.json' and 'crate-hashes.git.json'."
+        )]
+        split_crate_hashes: bool,
+
+        #[structopt(
+            long = "keep-unused-hashes",
+            help = "Keep the hashes of crates that are not resolved anymore in the crate hash cache \
+                    file instead of removing them, e.g. to share it between lockfiles generated \
+                    with --lockfile."
+        )]
+        keep_unused_hashes: bool,
+
+        #[structopt(
+            long = "source-root",
+            parse(from_os_str),
+            help = "The directory that contains all path dependencies outside of the output directory. \
+                    These crates are referenced relative to the overridable `sourceRoot` argument \
+                    of Cargo.nix. Uses the closest common ancestor directory by default."
+        )]
+        source_root: Option<PathBuf>,
+
+        #[structopt(
+            long = "locked",
+            help = "Fail if Cargo.lock is missing. \
+                    crate2nix always resolves with `cargo metadata --locked` and fails if \
+                    Cargo.lock changed during resolution unless --allow-lock-update is given."
+        )]
+        locked: bool,
+
+        #[structopt(
+            long = "frozen",
+            help = "Like --locked but also forbids network access \
+                    by passing --frozen to `cargo metadata`."
+        )]
+        frozen: bool,
+
+        #[structopt(
+            long = "allow-lock-update",
+            conflicts_with_all = &["locked", "frozen"],
+            help = "Let `cargo metadata` update Cargo.lock, e.g. after editing Cargo.toml, \
+                    and only warn if it changed instead of failing."
+        )]
+        allow_lock_update: bool,
+
+        #[structopt(
+            long = "prefetch-retries",
+            help = "How often to retry a failed prefetch, waiting twice as long before every retry. \
+                    Hashes that were prefetched before a failure are kept in crate-hashes.json \
+                    and reused by the next run.",
+            default_value = "3"
+        )]
+        prefetch_retries: u32,
+
+        #[structopt(
+            long = "prefetch-jobs",
+            help = "The maximal number of sources to prefetch concurrently.",
+            default_value = "4"
+        )]
+        prefetch_jobs: usize,
+
+        #[structopt(
+            long = "proxy",
+            help = "The proxy for cargo and the prefetch helpers, e.g. 'http://proxy:3128'. \
+                    By default, HTTP_PROXY, HTTPS_PROXY and NO_PROXY are used."
+        )]
+        proxy: Option<String>,
+
+        #[structopt(
+            long = "check-yanked",
+            help = "Look up the locked crate versions in the sparse index of their registry and \
+                    warn about yanked ones."
+        )]
+        check_yanked: bool,
+
+        #[structopt(
+            long = "deny-yanked",
+            help = "Like --check-yanked but fail if a locked crate version has been yanked or \
+                    cannot be checked, e.g. without network access."
+        )]
+        deny_yanked: bool,
+
+        #[structopt(
+            long = "keep-going",
+            help = "Generate the output even if some crates cannot be resolved or prefetched. \
+                    Building the affected crates fails with the original error. \
+                    A JSON summary of all problems is printed to stdout."
+        )]
+        keep_going: bool,
+
+        #[structopt(
+            long = "incremental",
+            help = "Skip regeneration if Cargo.lock, the local manifests and sources, the cargo \
+                    configuration and version and the arguments did not change since the last run. \
+                    Otherwise, only the crates whose Cargo.lock entry or manifest changed and the \
+                    crates that depend on them are resolved and rendered again. The fingerprints \
+                    and crates are stored in .crate2nix-*.json files next to the output."
+        )]
+        incremental: bool,
+
+        #[structopt(
+            long = "shard",
+            help = "Write every crate to its own file in a directory next to the output, e.g. \
+                    Cargo.crates/ for Cargo.nix. The files are only imported when a crate is needed \
+                    which speeds up evaluation for large dependency graphs."
+        )]
+        shard: bool,
+
+        #[structopt(
+            long = "crates-json",
+            conflicts_with = "shard",
+            help = "Write the crates to a JSON file next to the output, e.g. Cargo.crates.json for \
+                    Cargo.nix, which the output reads with `builtins.fromJSON`. Crates that need \
+                    nix expressions, e.g. local crates, are still defined in the output."
+        )]
+        crates_json: bool,
+
+        #[structopt(
+            long = "share-lists",
+            conflicts_with = "shard",
+            help = "Define the author and feature lists that many crates repeat once in the output \
+                    and refer to them, which makes the output smaller for large dependency graphs."
+        )]
+        share_lists: bool,
+
+        #[structopt(
+            long = "docker-images",
+            help = "Add a `dockerImages.<binary>` attribute to every workspace member with binaries \
+                    that wraps the binary in an OCI image built by `pkgs.dockerTools.buildLayeredImage`. \
+                    Entrypoint and exposed ports can be changed with `.override`."
+        )]
+        docker_images: bool,
+
+        #[structopt(
+            long = "nixos-modules",
+            help = "Add a `nixosModules.<binary>` attribute to every workspace member with binaries. \
+                    The module defines `services.<binary>` with enable, user, port and extraArgs options \
+                    and a systemd service that runs the binary."
+        )]
+        nixos_modules: bool,
+
+        #[structopt(
+            long = "c-headers",
+            help = "Add a `cHeader` attribute to every workspace member with a `staticlib` or `cdylib` \
+                    target that runs cbindgen on the crate, using its `cbindgen.toml` if present. \
+                    The header is in the `dev` output, the libraries in the default output."
+        )]
+        c_headers: bool,
+
+        #[structopt(
+            long = "python-modules",
+            help = "Add a `pythonModule` attribute to every workspace member that is a PyO3 extension \
+                    module, i.e. a `cdylib` depending on pyo3. It links against the given Python and \
+                    installs the library under the module name of `[tool.maturin] module-name` or the \
+                    library name, wrapped with `toPythonModule`."
+        )]
+        python_modules: bool,
+
+        #[structopt(
+            long = "overlay",
+            help = "Also generate an `overlay.nix` next to the output that adds every workspace member \
+                    with binaries as a top-level package, e.g. for `nixpkgs.overlays`."
+        )]
+        overlay: bool,
+
+        #[structopt(
+            long = "release-nix",
+            help = "Also generate a `release.nix` next to the output for Hydra, with jobs for every \
+                    workspace member and check on the systems of --platforms, or on x86_64-linux and \
+                    aarch64-linux by default."
+        )]
+        release_nix: bool,
+
+        #[structopt(
+            long = "no-fetch-crate",
+            help = "Download crates.io crates with `pkgs.fetchurl` from a hard-coded URL \
+                    instead of `pkgs.fetchCrate`, as done by crate2nix up to 0.14."
+        )]
+        no_fetch_crate: bool,
+
+        #[structopt(
+            long = "crates-io-mirror",
+            help = "Download crates.io crates from a mirror, both for prefetching and in Cargo.nix. \
+                    The URL template supports the same markers as the `dl` field in a registry's \
+                    config.json, e.g. 'https://mirror.example/api/v1/crates/{crate}/{version}/download'. \
+                    Without markers, '/{crate}/{version}/download' is appended."
+        )]
+        crates_io_mirror: Option<String>,
+
+        #[structopt(
+            long = "all-members",
+            help = "Include all workspace members in allWorkspaceMembers. By default, only the \
+                    `default-members` of the workspace are included, as built by `cargo build` \
+                    in the workspace root."
+        )]
+        all_members: bool,
+
+        #[structopt(
+            long = "no-metadata-cache",
+            help = "Always run `cargo metadata` instead of reusing its output from a previous run \
+                    with unchanged manifests, Cargo.lock and cargo configuration."
+        )]
+        no_metadata_cache: bool,
+
+        #[structopt(
+            long = "lockfile-only",
+            help = "(EXPERIMENTAL) Resolve from Cargo.lock and the manifests without running cargo. \
+                    The manifests of non-local crates are read from vendored directory sources \
+                    or from the crates that cargo downloaded previously to $CARGO_HOME."
+        )]
+        lockfile_only: bool,
+
+        #[structopt(
+            long = "platforms",
+            use_delimiter = true,
+            help = "Only include the dependencies needed on the given comma-separated nix systems, \
+                    e.g. 'x86_64-linux,aarch64-darwin'. The generated file still selects the \
+                    dependencies for the building platform at evaluation time. \
+                    Includes the dependencies of all platforms by default. \
+                    Other systems can be defined in the platforms of crate2nix.json."
+        )]
+        platforms: Vec<String>,
+
+        #[structopt(
+            long = "filter-platform",
+            use_delimiter = true,
+            help = "Only resolve the crates that can be built for the given comma-separated rust \
+                    target triples, e.g. 'x86_64-unknown-linux-gnu', by passing them to \
+                    `cargo metadata --filter-platform`. With several triples, cargo metadata \
+                    runs once per triple and the results are combined."
+        )]
+        filter_platforms: Vec<String>,
+
+        #[structopt(
+            long = "no-std",
+            help = "Check that the crates built for the given nix system do not need std, e.g. \
+                    for 'thumbv7em-none' defined in the platforms of crate2nix.json, and build \
+                    them against a sysroot with only core and alloc. Only the dependencies for \
+                    the system are included unless --platforms says otherwise."
+        )]
+        no_std: Option<String>,
+
+        #[structopt(
+            long = "wasi",
+            help = "Generate for WASI, i.e. the nix system 'wasm32-wasi' with the rust target \
+                    'wasm32-wasip1': only include the dependencies that the crates built for it need. \
+                    Proc macros, build scripts and their dependencies run on the building platform \
+                    and keep the dependencies of --platforms, or of all platforms without it. \
+                    The tests of the workspace members run with wasmtime."
+        )]
+        wasi: bool,
+
+        #[structopt(
+            short = "p",
+            long = "package",
+            help = "Only generate the top-level attributes for the given workspace member, by \
+                    package name or <name>@<version>, like `cargo build --package`. Can be \
+                    given several times. Dependencies that only other members need are left out \
+                    and not prefetched."
+        )]
+        packages: Vec<String>,
+
+        #[structopt(
+            long = "bin",
+            help = "Only build the given binary of the selected workspace members, like \
+                    `cargo build --bin`. Can be given several times. Members without a selected \
+                    binary are left out unless --lib selects them."
+        )]
+        bins: Vec<String>,
+
+        #[structopt(
+            long = "lib",
+            help = "Select the workspace members with a library, like `cargo build --lib`. \
+                    Their binaries are only built if --bin selects them."
+        )]
+        lib: bool,
+
+        #[structopt(
+            long = "crate-overrides",
+            parse(from_os_str),
+            help = "A nix file with crate overrides, called with `pkgs.callPackage`, that the \
+                    generated Cargo.nix merges with the built-in overrides for -sys crates and \
+                    `pkgs.defaultCrateOverrides`, taking precedence over both. Defaults to \
+                    crate2nix-overrides.nix next to the output if it exists."
+        )]
+        crate_overrides: Option<PathBuf>,
+
+        #[structopt(
+            long = "lockfile",
+            parse(from_os_str),
+            help = "Resolve with this lockfile instead of Cargo.lock, e.g. one generated with \
+                    `cargo update -Z minimal-versions`. Cargo.lock is moved aside while cargo runs \
+                    and restored afterwards. The output defaults to ./Cargo.<name>.nix for \
+                    Cargo.<name>.lock or <name>.lock."
+        )]
+        lockfile: Option<PathBuf>,
+
         // Mostly useful for testing
         #[structopt(
             long = "no-cargo-lock-checksums",
@@ -113,6 +453,34 @@ pub enum Opt {
         dont_read_crate_hashes: bool,
     },
 
+    #[structopt(
+        name = "init",
+        about = "Writes a starter crate2nix.json and a default.nix or flake.nix that builds the \
+                 project from the generated Cargo.nix, and lists the .gitignore entries to add."
+    )]
+    Init {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: PathBuf,
+
+        #[structopt(long = "flake", help = "Write a flake.nix instead of a default.nix.")]
+        flake: bool,
+
+        #[structopt(long = "force", help = "Replace existing starter files.")]
+        force: bool,
+
+        #[structopt(
+            long = "gitignore",
+            help = "Add the missing entries to .gitignore instead of only listing them."
+        )]
+        gitignore: bool,
+    },
+
     #[structopt(name = "source", about = "Manage out of tree sources for crate2nix.")]
     Source {
         #[structopt(
@@ -128,27 +496,467 @@ pub enum Opt {
         command: SourceCommands,
     },
 
+    #[structopt(
+        name = "graph",
+        about = "Prints the resolved dependency graph as Graphviz DOT or Mermaid."
+    )]
+    Graph {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+
+        #[structopt(
+            long = "format",
+            help = "The output format: 'dot' or 'mermaid'.",
+            default_value = "dot"
+        )]
+        format: GraphFormat,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the output file. Prints to stdout by default."
+        )]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(
+        name = "build-plan",
+        about = "Prints the resolved crates in build order as JSON, for external schedulers."
+    )]
+    BuildPlan {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the output file. Prints to stdout by default."
+        )]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(
+        name = "tree",
+        about = "Prints the resolved dependency tree like `cargo tree`."
+    )]
+    Tree {
+        #[structopt(help = "The crate whose tree to print, as name or name@version. \
+                    Defaults to the workspace members.")]
+        crate_spec: Option<String>,
+
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+
+        #[structopt(
+            short = "i",
+            long = "invert",
+            help = "Print the crates that depend on the given crate instead of its dependencies."
+        )]
+        invert: bool,
+    },
+
+    #[structopt(
+        name = "licenses",
+        about = "Prints the license of every crate in the resolved dependency graph, grouped by license."
+    )]
+    Licenses {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+
+        #[structopt(
+            long = "format",
+            help = "The output format: 'text', 'json' or 'csv'.",
+            default_value = "text"
+        )]
+        format: LicenseReportFormat,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the output file. Prints to stdout by default."
+        )]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(
+        name = "outdated",
+        about = "Lists crates with newer versions on their registries than in Cargo.lock."
+    )]
+    Outdated {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+    },
+
+    #[structopt(
+        name = "upgrade",
+        about = "Updates Cargo.lock with cargo update, regenerates and summarizes the changed crates."
+    )]
+    Upgrade {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: PathBuf,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the Cargo.nix file to regenerate.",
+            default_value = DEFAULT_OUTPUT
+        )]
+        output: PathBuf,
+
+        #[structopt(
+            short = "p",
+            long = "package",
+            help = "A package to update, passed on to 'cargo update -p'. \
+                    Can be repeated. By default, all packages are updated."
+        )]
+        package: Vec<String>,
+    },
+
+    #[structopt(
+        name = "duplicates",
+        about = "Lists crates resolved in several versions and suggests how to converge them."
+    )]
+    Duplicates {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+    },
+
+    #[structopt(
+        name = "explain-features",
+        about = "Explains why each enabled feature of a crate is enabled."
+    )]
+    ExplainFeatures {
+        #[structopt(help = "The crate to explain, as name or name@version.")]
+        crate_spec: String,
+
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+
+        #[structopt(
+            long = "features",
+            use_delimiter = true,
+            help = "The features to enable for the workspace members, as passed to cargo."
+        )]
+        features: Vec<String>,
+
+        #[structopt(
+            long = "all-features",
+            help = "Enable all features of the workspace members."
+        )]
+        all_features: bool,
+
+        #[structopt(
+            long = "no-default-features",
+            help = "Do not enable the default features of the workspace members."
+        )]
+        no_default_features: bool,
+    },
+
+    #[structopt(
+        name = "report",
+        about = "Prints the estimated build order, dependency counts and closure sizes of all crates."
+    )]
+    Report {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+
+        #[structopt(
+            short = "n",
+            long = "nix-file",
+            parse(from_os_str),
+            help = "The generated Cargo.nix to evaluate the output paths of the crates with.",
+            default_value = DEFAULT_OUTPUT
+        )]
+        nix_file: PathBuf,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the output file. Prints to stdout by default."
+        )]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(
+        name = "cache-manifest",
+        about = "Prints the derivation paths of all third-party crates, e.g. to build and push \
+                 them to a binary cache before building the workspace."
+    )]
+    CacheManifest {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: Vec<PathBuf>,
+
+        #[structopt(
+            short = "n",
+            long = "nix-file",
+            parse(from_os_str),
+            help = "The generated Cargo.nix to evaluate the derivations of the crates with.",
+            default_value = DEFAULT_OUTPUT
+        )]
+        nix_file: PathBuf,
+
+        #[structopt(
+            long = "out-paths",
+            help = "Print the output paths instead of the derivation paths."
+        )]
+        out_paths: bool,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the output file. Prints to stdout by default."
+        )]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(
+        name = "diff",
+        about = "Shows added, removed and upgraded crates, changed features and changed hashes \
+                 between two Cargo.nix files."
+    )]
+    Diff {
+        #[structopt(
+            parse(from_os_str),
+            help = "The old Cargo.nix or a JSON file with the crates by package ID."
+        )]
+        old: PathBuf,
+
+        #[structopt(
+            parse(from_os_str),
+            help = "The new Cargo.nix or a JSON file with the crates by package ID."
+        )]
+        new: PathBuf,
+    },
+
+    #[structopt(
+        name = "build",
+        about = "Generates Cargo.nix if it is out of date and builds the project with nix."
+    )]
+    Build {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: PathBuf,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the Cargo.nix file to generate and build.",
+            default_value = DEFAULT_OUTPUT
+        )]
+        output: PathBuf,
+
+        #[structopt(
+            short = "p",
+            long = "package",
+            help = "The workspace member to build. \
+                    By default, the root crate or all workspace members."
+        )]
+        package: Option<String>,
+
+        #[structopt(
+            long = "bin",
+            conflicts_with = "lib",
+            help = "The binary to build, like `cargo build --bin`: builds the workspace member \
+                    with the binary and prints the path of the binary."
+        )]
+        bin: Option<String>,
+
+        #[structopt(
+            long = "lib",
+            help = "Build the library of the selected workspace member, like `cargo build --lib`, \
+                    and print the path of its `lib` output."
+        )]
+        lib: bool,
+
+        #[structopt(
+            short = "A",
+            long = "attr",
+            conflicts_with_all = &["package", "bin", "lib"],
+            help = "The attribute of Cargo.nix to build, e.g. 'cratesByName.serde'."
+        )]
+        attr: Option<String>,
+
+        #[structopt(
+            long = "profile",
+            help = "'release' for optimized builds, 'dev' for faster compilation.",
+            possible_values = &["release", "dev"],
+            default_value = "release"
+        )]
+        profile: String,
+
+        #[structopt(
+            long = "out-link",
+            parse(from_os_str),
+            help = "The symlink to the build result.",
+            default_value = "result"
+        )]
+        out_link: PathBuf,
+    },
+
+    #[structopt(
+        name = "run",
+        about = "Generates Cargo.nix if it is out of date, builds a binary of the project with nix \
+                 and runs it with the given arguments, like `cargo run`."
+    )]
+    Run {
+        #[structopt(
+            short = "f",
+            long = "cargo-toml",
+            parse(from_os_str),
+            help = "The path to the Cargo.toml of the project.",
+            default_value = "./Cargo.toml"
+        )]
+        cargo_toml: PathBuf,
+
+        #[structopt(
+            short = "o",
+            long = "output",
+            parse(from_os_str),
+            help = "The path of the Cargo.nix file to generate and build.",
+            default_value = DEFAULT_OUTPUT
+        )]
+        output: PathBuf,
+
+        #[structopt(
+            short = "p",
+            long = "package",
+            help = "The workspace member to build. By default, the member with the binary of \
+                    --bin or the root crate."
+        )]
+        package: Option<String>,
+
+        #[structopt(
+            long = "bin",
+            help = "The binary to run. By default, the `default-run` binary or the only binary."
+        )]
+        bin: Option<String>,
+
+        #[structopt(last = true, help = "The arguments to pass to the binary.")]
+        args: Vec<String>,
+    },
+
+    #[structopt(
+        name = "test-templates",
+        about = "Generates Cargo.nix for bundled fixture projects and evaluates it with \
+                 nix-instantiate to check the templates."
+    )]
+    TestTemplates {
+        #[structopt(
+            short = "n",
+            long = "nixpkgs-path",
+            help = "The path for the nixpkgs to evaluate with.",
+            default_value = "<nixpkgs>"
+        )]
+        nixpkgs_path: String,
+
+        #[structopt(
+            long = "dir",
+            parse(from_os_str),
+            help = "The directory to write the fixtures to. \
+                    By default, a temporary directory that is removed afterwards."
+        )]
+        dir: Option<PathBuf>,
+    },
+
     #[structopt(
         name = "completions",
         about = "Generates auto-completions for the shell."
     )]
     Completions {
+        #[structopt(
+            help = "The shell to generate completions for: bash, zsh, fish, powershell or elvish.",
+            possible_values = &Shell::variants()
+        )]
+        shell_name: Option<String>,
+
         #[structopt(
             short = "s",
             long = "shell",
             parse(from_str),
-            help = "The shell to generate completions for. Specify 'invalid' to get a list of possibilities.",
-            default_value = "bash"
+            help = "The shell to generate completions for, same as the positional argument.",
+            possible_values = &Shell::variants(),
+            conflicts_with = "shell-name"
         )]
-        shell: String,
+        shell: Option<String>,
 
         #[structopt(
             short = "o",
             long = "output",
-            help = "The path of the output directory.",
-            default_value = "."
+            help = "The path of the output directory. Prints the completions to stdout by default."
         )]
-        output: PathBuf,
+        output: Option<PathBuf>,
     },
 }
 
@@ -202,7 +1010,7 @@ impl SourceCommands {
                     let removed = config.sources.remove(&name);
                     if let Some(removed) = removed {
                         config.write_to(crate2nix_json)?;
-                        eprintln!("Removed source\n\t{}", removed);
+                        info!("Removed source\n\t{}", removed);
                     } else {
                         eprintln!("Source '{}' not found among the following sources.\n", name);
                         config.print_sources();
@@ -342,33 +1150,192 @@ impl SourceAddingCommands {
         config.write_to(crate2nix_json)?;
         match old_source {
             Some(old_source) => {
-                eprintln!(
+                info!(
                     "Updated existing source\n\t{}\nto\n\t{}",
                     old_source, source
                 );
             }
             None => {
-                eprintln!("Added new source: {}", source);
+                info!("Added new source: {}", source);
             }
         }
         Ok(())
     }
 }
 
+/// Configures the logger according to the `-v`/`-q` flags.
+fn init_logging(verbose: u8, quiet: u8) {
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=-2 => LevelFilter::Error,
+        -1 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_env("CRATE2NIX_LOG")
+        .format(|buf, record| match record.level() {
+            Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(buf, "{}: {}", level.as_str().to_lowercase(), record.args()),
+        })
+        .init();
+}
+
+/// The configuration for commands that only inspect the resolved crates.
+fn inspection_config(cargo_toml: Vec<PathBuf>) -> crate2nix::GenerateConfig {
+    crate2nix::GenerateConfig {
+        cargo_toml,
+        output: DEFAULT_OUTPUT.into(),
+        keep_going: true,
+        ..Default::default()
+    }
+}
+
+/// The invocation and configuration for commands that regenerate `Cargo.nix` if it is out of date.
+///
+/// Reuses the ones of the last generation if they were recorded, so that the options passed to
+/// `crate2nix generate` stay in effect. Otherwise, falls back to the defaults only if the existing
+/// output was generated without other options.
+fn regeneration_config(
+    cargo_toml: PathBuf,
+    output: PathBuf,
+) -> Result<(crate2nix::GenerateInfo, crate2nix::GenerateConfig), Error> {
+    crate2nix::render::check_generated_by_crate2nix(&output)?;
+    if let Some(generation) = GenerationCache::previous_generation(&output)? {
+        return Ok(generation);
+    }
+    if output.exists() {
+        let arguments = crate2nix::render::generated_with_arguments(&output)?.unwrap_or_default();
+        if !is_default_generation(&arguments, &cargo_toml, &output) {
+            bail!(
+                "{} was generated with `crate2nix {}` but the options were not recorded. \
+                 Rerun it with --incremental to record them.",
+                output.to_string_lossy(),
+                arguments.join(" ")
+            );
+        }
+    }
+    // The equivalent `crate2nix generate` invocation.
+    let generate_info = crate2nix::GenerateInfo {
+        crate2nix_arguments: vec![
+            "generate".to_string(),
+            "-f".to_string(),
+            cargo_toml.to_string_lossy().to_string(),
+            "-o".to_string(),
+            output.to_string_lossy().to_string(),
+            "--incremental".to_string(),
+        ],
+        ..crate2nix::GenerateInfo::default()
+    };
+    let crate_hashes_json = output
+        .parent()
+        .expect("Cargo.nix has parent")
+        .join("crate-hashes.json");
+    let generate_config = crate2nix::GenerateConfig {
+        cargo_toml: vec![cargo_toml],
+        output,
+        crate_hashes_json,
+        keep_going: false,
+        incremental: true,
+        ..inspection_config(vec![])
+    };
+    Ok((generate_info, generate_config))
+}
+
+/// Whether `arguments` are a `crate2nix generate` invocation that only selects `cargo_toml` and
+/// `output`.
+fn is_default_generation(arguments: &[String], cargo_toml: &Path, output: &Path) -> bool {
+    let same = |argument: Option<&str>, path: &Path| {
+        let strip = |p: &Path| p.strip_prefix(".").unwrap_or(p).to_path_buf();
+        argument.is_some_and(|a| strip(Path::new(a)) == strip(path))
+    };
+    let mut arguments = arguments.iter().map(String::as_str);
+    if arguments.next() != Some("generate") {
+        return false;
+    }
+    while let Some(argument) = arguments.next() {
+        let matches = match argument {
+            "--incremental" => true,
+            "-f" | "--cargo-toml" => same(arguments.next(), cargo_toml),
+            "-o" | "--output" => same(arguments.next(), output),
+            _ => false,
+        };
+        if !matches {
+            return false;
+        }
+    }
+    true
+}
+
+/// Writes `content` to `output` if given, otherwise to stdout.
+fn print_or_write(output: Option<PathBuf>, content: &str) -> Result<(), Error> {
+    match output {
+        Some(output) => std::fs::write(&output, content)
+            .map_err(|e| format_err!("while writing {}: {}", output.to_string_lossy(), e)),
+        None => {
+            print!("{}", content);
+            Ok(())
+        }
+    }
+}
+
 fn main() -> anyhow::Result<()> {
-    let opt = Opt::from_args();
-    match opt {
-        Opt::Generate {
+    let Opt {
+        verbose,
+        quiet,
+        command,
+    } = Opt::from_args();
+    init_logging(verbose, quiet);
+    match command {
+        Command::Generate {
             crate2nix_json,
             mut cargo_toml,
             output: opt_output,
             nixpkgs_path,
             crate_hashes,
+            crate_hashes_format,
+            split_crate_hashes,
+            keep_unused_hashes,
             all_features,
             default_features,
             no_default_features,
             features,
             no_cargo_lock_checksums,
+            locked,
+            frozen,
+            keep_going,
+            incremental,
+            shard,
+            crates_json,
+            share_lists,
+            source_root,
+            docker_images,
+            nixos_modules,
+            c_headers,
+            python_modules,
+            overlay,
+            release_nix,
+            no_fetch_crate,
+            crates_io_mirror,
+            all_members,
+            no_metadata_cache,
+            lockfile_only,
+            platforms,
+            filter_platforms,
+            no_std,
+            wasi,
+            packages,
+            bins,
+            lib,
+            crate_overrides,
+            lockfile,
+            allow_lock_update,
+            prefetch_retries,
+            prefetch_jobs,
+            proxy,
+            check_yanked,
+            deny_yanked,
             dont_read_crate_hashes,
         } => {
             let config = crate2nix::config::Config::read_from_or_default(&crate2nix_json)?;
@@ -383,11 +1350,19 @@ fn main() -> anyhow::Result<()> {
                 cargo_toml.push("./Cargo.toml".into());
             }
 
+            if lockfile.is_some() && cargo_toml.len() > 1 {
+                bail!("--lockfile only works with a single Cargo.toml.");
+            }
+
             let output: PathBuf = opt_output
                 .map(|v| Ok(v) as Result<_, Error>)
                 .unwrap_or_else(|| {
-                    crate2nix::render::check_generated_by_crate2nix(DEFAULT_OUTPUT)?;
-                    Ok(DEFAULT_OUTPUT.into())
+                    let output = match &lockfile {
+                        Some(lockfile) => crate2nix::lockfile_profiles::default_output(lockfile),
+                        None => DEFAULT_OUTPUT.into(),
+                    };
+                    crate2nix::render::check_generated_by_crate2nix(&output)?;
+                    Ok(output)
                 })?;
 
             let crate_hashes_json = crate_hashes.unwrap_or_else(|| {
@@ -446,18 +1421,399 @@ fn main() -> anyhow::Result<()> {
                 output: output.clone(),
                 nixpkgs_path,
                 crate_hashes_json,
+                crate_hashes_format,
+                split_crate_hashes,
+                keep_unused_hashes,
                 other_metadata_options: feature_metadata_options()?,
                 use_cargo_lock_checksums: !no_cargo_lock_checksums,
                 read_crate_hashes: !dont_read_crate_hashes,
+                locked,
+                frozen,
+                keep_going,
+                incremental,
+                shard,
+                crates_json,
+                share_lists,
+                source_root,
+                docker_images,
+                nixos_modules,
+                c_headers,
+                python_modules,
+                overlay,
+                release_nix,
+                use_fetch_crate: !no_fetch_crate,
+                crates_io_mirror,
+                all_members,
+                metadata_cache: !no_metadata_cache,
+                lockfile_only,
+                platforms,
+                filter_platforms,
+                no_std,
+                wasi,
+                packages,
+                bins,
+                lib,
+                crate_overrides,
+                lockfile,
+                allow_lock_update,
+                prefetch_retries,
+                prefetch_jobs,
+                proxy,
+                check_yanked,
+                deny_yanked,
             };
+            if incremental && GenerationCache::is_up_to_date(&generate_info, &generate_config)? {
+                info!("{} is up to date.", output.to_string_lossy());
+                return Ok(());
+            }
             let build_info = crate2nix::BuildInfo::for_config(&generate_info, &generate_config)?;
-            render::CARGO_NIX.write_to_file(&output, &build_info)?;
+            render::write_outputs(&build_info, &generate_config)?;
+            if incremental {
+                GenerationCache::new(&generate_info, &generate_config, &build_info)?
+                    .write(&generate_config)?;
+            }
+            if !build_info.warnings.is_empty() {
+                warn!(
+                    "{} crate(s) could not be resolved completely, building them will fail.",
+                    build_info.warnings.len()
+                );
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&serde_json::json!({
+                        "warnings": build_info.warnings
+                    }))?
+                );
+            }
+        }
+        Command::Init {
+            cargo_toml,
+            flake,
+            force,
+            gitignore,
+        } => {
+            let project_dir = match cargo_toml.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            let config = crate2nix::GenerateConfig {
+                crate_hashes_json: project_dir.join("crate-hashes.json"),
+                ..inspection_config(vec![cargo_toml])
+            };
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &config,
+            )?;
+            let options = crate2nix::init::InitOptions {
+                flake,
+                force,
+                gitignore,
+            };
+            let plan = crate2nix::init::init(&build_info, &project_dir, &options)?;
+            print!("{}", plan.render());
+        }
+        Command::Graph {
+            cargo_toml,
+            format,
+            output,
+        } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            let graph = crate2nix::graph::render(&build_info, format);
+            print_or_write(output, &graph)?;
         }
-        Opt::Completions { shell, output } => {
-            let shell = FromStr::from_str(&shell).map_err(|s| format_err!("{}", s))?;
-            Opt::clap().gen_completions(env!("CARGO_PKG_NAME"), shell, output);
+        Command::BuildPlan { cargo_toml, output } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            print_or_write(output, &crate2nix::build_plan::render(&build_info)?)?;
+        }
+        Command::Tree {
+            crate_spec,
+            cargo_toml,
+            invert,
+        } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            let tree = crate2nix::graph::render_tree(&build_info, crate_spec.as_deref(), invert)?;
+            print!("{}", tree);
+        }
+        Command::Licenses {
+            cargo_toml,
+            format,
+            output,
+        } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            let report = crate2nix::licenses::render(&build_info, format)?;
+            print_or_write(output, &report)?;
+        }
+        Command::Outdated { cargo_toml } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            let outdated = crate2nix::outdated::outdated_crates(&build_info)?;
+            print!("{}", crate2nix::outdated::render(&outdated));
+        }
+        Command::Upgrade {
+            cargo_toml,
+            output,
+            package,
+        } => {
+            let (generate_info, generate_config) = regeneration_config(cargo_toml, output)?;
+            let diff = crate2nix::upgrade::upgrade(&generate_info, &generate_config, &package)?;
+            print!("{}", diff.render());
+        }
+        Command::Duplicates { cargo_toml } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            let duplicates = crate2nix::duplicates::duplicates(&build_info);
+            print!("{}", crate2nix::duplicates::render(&duplicates));
+        }
+        Command::ExplainFeatures {
+            crate_spec,
+            cargo_toml,
+            features,
+            all_features,
+            no_default_features,
+        } => {
+            let mut other_metadata_options = Vec::new();
+            if !features.is_empty() {
+                other_metadata_options.push(format!("--features={}", features.join(",")));
+            }
+            if all_features {
+                other_metadata_options.push("--all-features".to_string());
+            }
+            if no_default_features {
+                other_metadata_options.push("--no-default-features".to_string());
+            }
+            let config = crate2nix::GenerateConfig {
+                other_metadata_options,
+                ..inspection_config(cargo_toml)
+            };
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &config,
+            )?;
+            let explained = crate2nix::features::explain(&build_info, &crate_spec)?;
+            print!("{}", crate2nix::features::render(&explained));
+        }
+        Command::Report {
+            cargo_toml,
+            nix_file,
+            output,
+        } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            let mut reports = crate2nix::report::crate_reports(&build_info);
+            let root_package_ids: Vec<_> = build_info
+                .root_package_id
+                .iter()
+                .chain(
+                    build_info
+                        .workspace_members
+                        .values()
+                        .filter(|id| build_info.root_package_id.as_ref() != Some(*id)),
+                )
+                .collect();
+            let sizes =
+                crate2nix::report::out_paths(&nix_file, &root_package_ids).and_then(|out_paths| {
+                    let sizes =
+                        crate2nix::report::closure_sizes(out_paths.values().map(String::as_str))?;
+                    Ok((out_paths, sizes))
+                });
+            match sizes {
+                Ok((out_paths, sizes)) => {
+                    crate2nix::report::add_closure_sizes(&mut reports, &out_paths, &sizes)
+                }
+                Err(e) => warn!("Not reporting closure sizes: {}", e),
+            }
+            print_or_write(output, &crate2nix::report::render(&reports))?;
+        }
+        Command::CacheManifest {
+            cargo_toml,
+            nix_file,
+            out_paths,
+            output,
+        } => {
+            let build_info = crate2nix::BuildInfo::resolve_without_prefetch(
+                &crate2nix::GenerateInfo::default(),
+                &inspection_config(cargo_toml),
+            )?;
+            let root_package_ids: Vec<_> = build_info
+                .root_package_id
+                .iter()
+                .chain(
+                    build_info
+                        .workspace_members
+                        .values()
+                        .filter(|id| build_info.root_package_id.as_ref() != Some(*id)),
+                )
+                .collect();
+            let paths = crate2nix::report::built_crate_paths(
+                &nix_file,
+                &root_package_ids,
+                if out_paths { "outPath" } else { "drvPath" },
+            )?;
+            let third_party = crate2nix::cache_manifest::third_party_package_ids(&build_info);
+            print_or_write(
+                output,
+                &crate2nix::cache_manifest::render(&third_party, &paths),
+            )?;
+        }
+        Command::Diff { old, new } => {
+            let diff = crate2nix::diff::CratesDiff::new(
+                &crate2nix::diff::read_crates(&old)?,
+                &crate2nix::diff::read_crates(&new)?,
+            );
+            print!("{}", diff.render());
+        }
+        Command::Build {
+            cargo_toml,
+            output,
+            package,
+            bin,
+            lib,
+            attr,
+            profile,
+            out_link,
+        } => {
+            let (generate_info, generate_config) = regeneration_config(cargo_toml, output)?;
+            let generation =
+                GenerationCache::regenerate_if_stale(&generate_info, &generate_config)?;
+            let nix_attr = match attr {
+                Some(attr) => attr,
+                None => {
+                    let build_attr = generation.build_attr(
+                        &generate_config.output,
+                        package.as_deref(),
+                        bin.as_deref(),
+                    )?;
+                    match build_attr {
+                        Some(build_attr) => build_attr,
+                        None if lib => bail!(
+                            "{} has no root crate, select a workspace member with --package.",
+                            generate_config.output.to_string_lossy()
+                        ),
+                        None => "allWorkspaceMembers".to_string(),
+                    }
+                }
+            };
+            let nix_attr = if lib {
+                format!("{}.lib", nix_attr)
+            } else {
+                nix_attr
+            };
+            let out_link = out_link.to_string_lossy();
+            let mut args = vec!["--out-link", &out_link];
+            if profile == "dev" {
+                args.extend(["--arg", "release", "false"]);
+            }
+            info!(
+                "Building with: nix build -f {} {} {}",
+                generate_config.output.to_string_lossy(),
+                nix_attr,
+                args.join(" ")
+            );
+            let out_path = crate2nix::nix_build::nix_build_out_path(
+                &generate_config.output,
+                &nix_attr,
+                &args,
+            )?;
+            let out_path = match &bin {
+                Some(bin) => {
+                    let binary = out_path.join("bin").join(bin);
+                    if !binary.exists() {
+                        bail!("{} has no binary named {}.", nix_attr, bin);
+                    }
+                    binary
+                }
+                None => out_path,
+            };
+            println!("{}", out_path.to_string_lossy());
+        }
+        Command::Run {
+            cargo_toml,
+            output,
+            package,
+            bin,
+            args,
+        } => {
+            let (generate_info, generate_config) = regeneration_config(cargo_toml, output)?;
+            let generation =
+                GenerationCache::regenerate_if_stale(&generate_info, &generate_config)?;
+            let nix_attr = generation
+                .build_attr(&generate_config.output, package.as_deref(), bin.as_deref())?
+                .ok_or_else(|| {
+                    format_err!(
+                        "{} has no root crate, select a workspace member with --package or \
+                         a binary with --bin.",
+                        generate_config.output.to_string_lossy()
+                    )
+                })?;
+            let out_path = crate2nix::nix_build::nix_build_out_path(
+                &generate_config.output,
+                &nix_attr,
+                &["--no-link"],
+            )?;
+            let binary = crate2nix::nix_build::select_binary(&out_path, bin.as_deref(), || {
+                crate2nix::nix_build::main_program(&generate_config.output, &nix_attr)
+            })?;
+            let status = std::process::Command::new(&binary)
+                .args(&args)
+                .status()
+                .map_err(|e| format_err!("while running {}: {}", binary.to_string_lossy(), e))?;
+            if !status.success() {
+                std::process::exit(status.code().unwrap_or(1));
+            }
+        }
+        Command::TestTemplates { nixpkgs_path, dir } => {
+            let (dir, is_temporary) = match dir {
+                Some(dir) => (dir, false),
+                None => (
+                    std::env::temp_dir()
+                        .join(format!("crate2nix-test-templates-{}", std::process::id())),
+                    true,
+                ),
+            };
+            let result = crate2nix::test_templates::run(&dir, &nixpkgs_path);
+            if is_temporary {
+                std::fs::remove_dir_all(&dir).ok();
+            }
+            result?;
+            println!(
+                "All {} fixtures evaluated successfully.",
+                crate2nix::test_templates::FIXTURES.len()
+            );
+        }
+        Command::Completions {
+            shell_name,
+            shell,
+            output,
+        } => {
+            let shell = shell_name.or(shell).unwrap_or_else(|| "bash".to_string());
+            let shell = Shell::from_str(&shell).map_err(|s| format_err!("{}", s))?;
+            match output {
+                Some(output) => Opt::clap().gen_completions(env!("CARGO_PKG_NAME"), shell, output),
+                None => Opt::clap().gen_completions_to(
+                    env!("CARGO_PKG_NAME"),
+                    shell,
+                    &mut std::io::stdout(),
+                ),
+            }
         }
-        Opt::Source {
+        Command::Source {
             crate2nix_json,
             command,
         } => {
@@ -0,0 +1,125 @@
+//! Hints for crate2nix in the `[package.metadata.crate2nix]` section of a `Cargo.toml`, e.g.
+//!
+//! ```toml
+//! [package.metadata.crate2nix]
+//! build-inputs = ["pkgs.openssl"]
+//! native-build-inputs = ["pkgs.pkg-config"]
+//! env = { OPENSSL_NO_VENDOR = "1" }
+//! skip-tests = true
+//! exclude = ["/fixtures/large"]
+//! ```
+//!
+//! Only the hints of local crates, i.e. workspace members and path dependencies, are read. The
+//! hints of downloaded crates would add nix expressions of third parties to `Cargo.nix`.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Error};
+use cargo_metadata::Package;
+use serde::Deserialize;
+
+use crate::config::CrateOverride;
+use crate::render::escape_nix_string;
+
+/// The `[package.metadata.crate2nix]` section of a local crate.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ManifestHints {
+    /// Environment variables for the build by name. Unlike in `crate2nix.json`, the values are
+    /// plain strings.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Nix expressions for additional `buildInputs`, e.g. `pkgs.openssl`.
+    #[serde(default)]
+    pub build_inputs: Vec<String>,
+    /// Nix expressions for additional `nativeBuildInputs`, e.g. `pkgs.pkg-config`.
+    #[serde(default)]
+    pub native_build_inputs: Vec<String>,
+    /// Whether to leave the tests of the crate out of the `checks` of `Cargo.nix`.
+    #[serde(default)]
+    pub skip_tests: bool,
+    /// Globs of the files to include in the source, in addition to `package.include`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs of the files to exclude from the source, in addition to `package.exclude`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl ManifestHints {
+    /// Reads the hints of `package`, `None` if it has none or is not a local crate.
+    pub fn of(package: &Package) -> Result<Option<ManifestHints>, Error> {
+        if package.source.is_some() {
+            return Ok(None);
+        }
+        let Some(hints) = package.metadata.get("crate2nix") else {
+            return Ok(None);
+        };
+        let hints = serde_json::from_value(hints.clone()).with_context(|| {
+            format!(
+                "while reading package.metadata.crate2nix in {}",
+                package.manifest_path
+            )
+        })?;
+        Ok(Some(hints))
+    }
+
+    /// The settings for the derivation as override, `None` if there are none.
+    pub fn crate_override(&self) -> Option<CrateOverride> {
+        if self.env.is_empty()
+            && self.build_inputs.is_empty()
+            && self.native_build_inputs.is_empty()
+        {
+            return None;
+        }
+        Some(CrateOverride {
+            env: self
+                .env
+                .iter()
+                .map(|(name, value)| (name.clone(), escape_nix_string(value)))
+                .collect(),
+            build_inputs: self.build_inputs.clone(),
+            native_build_inputs: self.native_build_inputs.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+#[test]
+fn test_manifest_hints() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    let mut package = env.indexed_metadata().root_package().unwrap().clone();
+    assert_eq!(ManifestHints::of(&package).unwrap(), None);
+
+    package.metadata = serde_json::json!({
+        "crate2nix": {
+            "build-inputs": ["pkgs.openssl"],
+            "env": { "OPENSSL_NO_VENDOR": "1" },
+            "skip-tests": true,
+        }
+    });
+    let hints = ManifestHints::of(&package).unwrap().unwrap();
+    assert!(hints.skip_tests);
+    let crate_override = hints.crate_override().unwrap();
+    assert_eq!(crate_override.build_inputs, vec!["pkgs.openssl"]);
+    assert_eq!(crate_override.env["OPENSSL_NO_VENDOR"], "\"1\"");
+    assert_eq!(
+        ManifestHints {
+            skip_tests: true,
+            ..Default::default()
+        }
+        .crate_override(),
+        None
+    );
+
+    package.metadata = serde_json::json!({ "crate2nix": { "skip_tests": true } });
+    let error = format!("{:#}", ManifestHints::of(&package).unwrap_err());
+    assert!(error.contains("unknown field `skip_tests`"), "{}", error);
+
+    package.source = Some(cargo_metadata::Source {
+        repr: "registry+https://github.com/rust-lang/crates.io-index".to_string(),
+    });
+    assert_eq!(ManifestHints::of(&package).unwrap(), None);
+    env.close();
+}
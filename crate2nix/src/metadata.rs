@@ -1,14 +1,18 @@
 //! Indexing cargo metadata.
 
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use anyhow::format_err;
+use anyhow::{bail, format_err};
 use anyhow::{Error, Result};
 use cargo_metadata::Node;
 use cargo_metadata::Package;
 use cargo_metadata::PackageId;
 use cargo_metadata::{Metadata, NodeDep};
 use itertools::Itertools;
+use log::warn;
+use semver::Version;
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -22,46 +26,75 @@ use serde::Serialize;
 #[derive(Debug)]
 pub struct MergedMetadata {
     workspace_members: Vec<PackageId>,
+    workspace_default_members: Vec<PackageId>,
     pub(crate) packages: Vec<Package>,
     root: Option<PackageId>,
     nodes: Vec<Node>,
+    target_directories: Vec<PathBuf>,
 }
 
 impl MergedMetadata {
     pub fn merge(metadatas: Vec<Metadata>) -> Result<MergedMetadata> {
-        assert!(!metadatas.is_empty());
+        if metadatas.is_empty() {
+            bail!("no cargo metadata to generate a build for");
+        }
         let mut workspace_members = Vec::new();
+        let mut workspace_default_members = Vec::new();
         let mut package_ids = HashSet::new();
         let mut packages = Vec::new();
-        let mut node_package_ids = HashSet::new();
-        let mut nodes = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut node_indices: HashMap<PackageId, usize> = HashMap::new();
+        let mut target_directories = Vec::new();
 
         for metadata in metadatas.into_iter() {
-            let resolve = metadata
-                .resolve
-                .ok_or_else(|| format_err!("no resolve in metadata"))?;
+            let resolve = metadata.resolve.ok_or_else(|| {
+                format_err!(
+                    "cargo metadata returned no dependency graph for {}.\n\
+                     crate2nix needs the resolved dependencies: do not pass --no-deps to \
+                     cargo metadata and make sure that a Cargo.lock exists, \
+                     e.g. with `cargo generate-lockfile`.",
+                    metadata.workspace_root
+                )
+            })?;
             if let Some(root) = resolve.root {
                 if metadata.workspace_members != vec![root.clone()] {
                     // Usually, cargo metadata also puts the root into workspace_members.
                     // Therefore, I only saw this warning in unit tests.
-                    eprintln!("WARNING: root missing from workspace_members.");
+                    warn!("root missing from workspace_members.");
                 }
             }
+            // Cargo versions before 1.71 do not report the default members.
+            if cargo_metadata::workspace_default_members_is_missing(
+                &metadata.workspace_default_members,
+            ) {
+                workspace_default_members.extend(metadata.workspace_members.iter().cloned());
+            } else {
+                workspace_default_members
+                    .extend(metadata.workspace_default_members.iter().cloned());
+            }
             workspace_members.extend(metadata.workspace_members);
+            if !metadata.target_directory.as_str().is_empty() {
+                target_directories.push(metadata.target_directory.into_std_path_buf());
+            }
             packages.extend(
                 metadata
                     .packages
                     .into_iter()
                     .filter(|p| package_ids.insert(p.id.clone())),
             );
-            nodes.extend(
-                resolve
-                    .nodes
-                    .into_iter()
-                    .filter(|p| node_package_ids.insert(p.id.clone())),
-            );
+            for node in resolve.nodes {
+                match node_indices.get(&node.id) {
+                    // The same package resolved for another platform, see `--filter-platform`.
+                    Some(&index) => merge_node(&mut nodes[index], node),
+                    None => {
+                        node_indices.insert(node.id.clone(), nodes.len());
+                        nodes.push(node);
+                    }
+                }
+            }
         }
 
+        let workspace_members: Vec<PackageId> = workspace_members.into_iter().unique().collect();
         let root = if workspace_members.len() <= 1 {
             workspace_members.first().cloned()
         } else {
@@ -71,67 +104,122 @@ impl MergedMetadata {
         Ok(MergedMetadata {
             packages,
             root,
-            workspace_members: workspace_members.into_iter().unique().collect(),
+            workspace_members,
+            workspace_default_members: workspace_default_members.into_iter().unique().collect(),
             nodes,
+            target_directories: target_directories.into_iter().unique().collect(),
         })
     }
 }
 
+/// Adds the dependencies and features of `other` to `node`, which resolve the same package.
+fn merge_node(node: &mut Node, other: Node) {
+    for dep in other.deps {
+        match node
+            .deps
+            .iter_mut()
+            .find(|d| d.pkg == dep.pkg && d.name == dep.name)
+        {
+            Some(existing) => {
+                for dep_kind in dep.dep_kinds {
+                    let is_new = !existing
+                        .dep_kinds
+                        .iter()
+                        .any(|k| k.kind == dep_kind.kind && k.target == dep_kind.target);
+                    if is_new {
+                        existing.dep_kinds.push(dep_kind);
+                    }
+                }
+            }
+            None => node.deps.push(dep),
+        }
+    }
+    for dependency in other.dependencies {
+        if !node.dependencies.contains(&dependency) {
+            node.dependencies.push(dependency);
+        }
+    }
+    for feature in other.features {
+        if !node.features.contains(&feature) {
+            node.features.push(feature);
+        }
+    }
+    node.features.sort();
+}
+
 /// The metadata with maps indexed by {{PackageId}} instead of flat lists.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct IndexedMetadata {
     pub root: Option<PackageId>,
     pub workspace_members: Vec<PackageId>,
+    /// The members that `cargo build` builds in the workspace root, see `default-members`.
+    #[serde(default)]
+    pub workspace_default_members: Vec<PackageId>,
     pub pkgs_by_id: BTreeMap<PackageId, Package>,
     pub nodes_by_id: BTreeMap<PackageId, Node>,
     pub id_shortener: PackageIdShortener,
+    /// The cargo target directories of the workspaces.
+    #[serde(default)]
+    pub target_directories: Vec<PathBuf>,
+    /// The IDs, names, versions and features of the packages, shared by the crates that
+    /// refer to them.
+    #[serde(skip)]
+    pub interner: Interner,
 }
 
 impl IndexedMetadata {
     pub fn new_from(metadata: Metadata) -> Result<IndexedMetadata, Error> {
         let merged = MergedMetadata::merge(vec![metadata])?;
-        Self::new_from_merged(&merged)
+        Self::new_from_merged(merged)
     }
 
     pub fn new_from_merged(
         MergedMetadata {
             root,
             workspace_members,
+            workspace_default_members,
             packages,
             nodes,
-        }: &MergedMetadata,
+            target_directories,
+        }: MergedMetadata,
     ) -> Result<IndexedMetadata, Error> {
+        // The packages and nodes are moved, the metadata is the biggest data structure of a
+        // generation and is only kept once.
         let id_shortener = PackageIdShortener::new(packages.iter());
 
         let pkgs_by_id: BTreeMap<PackageId, Package> = packages
-            .iter()
+            .into_iter()
             .map(|pkg| {
-                (
-                    id_shortener.shorten(&pkg.id),
-                    id_shortener.shorten_in_package(pkg),
-                )
+                let pkg = id_shortener.shorten_in_package(pkg);
+                (pkg.id.clone(), pkg)
             })
             .collect();
 
         let nodes_by_id: BTreeMap<PackageId, Node> = nodes
-            .iter()
+            .into_iter()
             .map(|node| {
-                (
-                    id_shortener.shorten(&node.id),
-                    id_shortener.shorten_in_node(node),
-                )
+                let node = id_shortener.shorten_in_node(node);
+                (node.id.clone(), node)
             })
             .collect();
 
+        let interner = Interner::of(pkgs_by_id.values());
+
         Ok(IndexedMetadata {
-            root: root.as_ref().map(|id| id_shortener.shorten(id)),
+            root: root.map(|id| id_shortener.shorten_owned(id)),
             workspace_members: workspace_members
-                .iter()
-                .map(|id| id_shortener.shorten(id))
+                .into_iter()
+                .map(|id| id_shortener.shorten_owned(id))
+                .collect(),
+            workspace_default_members: workspace_default_members
+                .into_iter()
+                .map(|id| id_shortener.shorten_owned(id))
                 .collect(),
             pkgs_by_id,
             nodes_by_id,
+            target_directories,
             id_shortener,
+            interner,
         })
     }
 
@@ -142,6 +230,79 @@ impl IndexedMetadata {
     }
 }
 
+/// The strings and versions of the packages, each allocated once.
+///
+/// A crate refers to the strings of its package, and a dependency to the ID and the name of the
+/// package it depends on. Instead of cloning them, they share the `Arc`s in here. The interner
+/// is only written while indexing, so resolving in parallel needs no locks. Values that were not
+/// interned, e.g. of packages added later, are allocated on their own.
+#[derive(Debug, Default, Clone)]
+pub struct Interner {
+    package_ids: HashMap<PackageId, Arc<PackageId>>,
+    strings: HashSet<Arc<str>>,
+    versions: HashSet<Arc<Version>>,
+}
+
+impl Interner {
+    fn of<'a>(packages: impl IntoIterator<Item = &'a Package>) -> Interner {
+        let mut strings = HashSet::new();
+        let mut add = |s: &str| {
+            if !strings.contains(s) {
+                strings.insert(Arc::from(s));
+            }
+        };
+        let mut package_ids = HashMap::new();
+        let mut versions = HashSet::new();
+        for package in packages {
+            add(&package.name);
+            add(package.edition.as_str());
+            package.authors.iter().for_each(|author| add(author));
+            package.links.iter().for_each(|links| add(links));
+            for (feature, enabled) in &package.features {
+                add(feature);
+                enabled.iter().for_each(|enabled| add(enabled));
+            }
+            for dependency in &package.dependencies {
+                add(&dependency.name);
+                dependency.features.iter().for_each(|feature| add(feature));
+            }
+            package_ids.insert(package.id.clone(), Arc::new(package.id.clone()));
+            versions.insert(Arc::new(package.version.clone()));
+        }
+        Interner {
+            package_ids,
+            strings,
+            versions,
+        }
+    }
+
+    /// The shared package ID.
+    pub fn package_id(&self, id: &PackageId) -> Arc<PackageId> {
+        self.package_ids
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(id.clone()))
+    }
+
+    /// The shared string.
+    pub fn str(&self, s: &str) -> Arc<str> {
+        self.strings.get(s).cloned().unwrap_or_else(|| Arc::from(s))
+    }
+
+    /// The shared strings.
+    pub fn strs<S: AsRef<str>>(&self, strings: &[S]) -> Vec<Arc<str>> {
+        strings.iter().map(|s| self.str(s.as_ref())).collect()
+    }
+
+    /// The shared version.
+    pub fn version(&self, version: &Version) -> Arc<Version> {
+        self.versions
+            .get(version)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(version.clone()))
+    }
+}
+
 /// "Shortens" package IDs to potentially remove local paths from
 /// the IDs. The local paths can make the build file generation
 /// depend on the local systems path.
@@ -149,6 +310,31 @@ impl IndexedMetadata {
 pub struct PackageIdShortener {
     substitution: HashMap<PackageId, PackageId>,
     reverse: HashMap<PackageId, PackageId>,
+    #[serde(default)]
+    stable: HashMap<PackageId, PackageId>,
+}
+
+/// Returns an ID for `package` that does not depend on the cargo version, e.g.
+/// `serde 1.0.100 (registry+https://github.com/rust-lang/crates.io-index)`.
+///
+/// Cargo changed the format of its package IDs to
+/// `registry+https://github.com/rust-lang/crates.io-index#serde@1.0.100`. Everything that
+/// crate2nix writes to files uses this ID instead, so that a cargo upgrade does not change them.
+pub fn stable_package_id(package: &Package) -> PackageId {
+    let source = match &package.source {
+        Some(source) => source.repr.clone(),
+        None => {
+            let dir = package
+                .manifest_path
+                .parent()
+                .map(|dir| dir.as_str())
+                .unwrap_or_default();
+            format!("path+file://{}", dir)
+        }
+    };
+    PackageId {
+        repr: format!("{} {} ({})", package.name, package.version, source),
+    }
 }
 
 impl PackageIdShortener {
@@ -157,13 +343,12 @@ impl PackageIdShortener {
     ///
     /// * Just the crate name.
     /// * The crate name and the version.
-    ///
-    /// If the shortening substitution wasn't successful, the package_id is not contained in
-    /// the returned map.
+    /// * The [stable_package_id].
     #[allow(clippy::needless_lifetimes)]
     fn new<'a>(all_packages: impl Iterator<Item = &'a Package>) -> PackageIdShortener {
         let mut substitution = HashMap::new();
         let mut reverse = HashMap::new();
+        let mut stable = HashMap::new();
 
         for (_crate_name, group) in &all_packages
             .sorted_by_key(|p| &p.name)
@@ -174,7 +359,7 @@ impl PackageIdShortener {
             enum UniqueComponent {
                 Name,
                 NameVersion,
-                PackageId,
+                StableId,
             }
 
             let unique_component = if packages.len() == 1 {
@@ -182,32 +367,49 @@ impl PackageIdShortener {
             } else if packages.iter().map(|p| &p.version).unique().count() == packages.len() {
                 UniqueComponent::NameVersion
             } else {
-                eprintln!(
+                warn!(
                     "Using same version of crate from different sources: {:#?}",
                     packages.iter().map(|p| &p.id.repr).collect::<Vec<_>>()
                 );
-                UniqueComponent::PackageId
+                UniqueComponent::StableId
             };
 
             for p in &packages {
+                let stable_id = stable_package_id(p);
                 let substitute = match unique_component {
-                    UniqueComponent::Name => Some(p.name.clone()),
-                    UniqueComponent::NameVersion => Some(format!("{} {}", p.name, p.version)),
-                    UniqueComponent::PackageId => None,
+                    UniqueComponent::Name => PackageId {
+                        repr: p.name.clone(),
+                    },
+                    UniqueComponent::NameVersion => PackageId {
+                        repr: format!("{} {}", p.name, p.version),
+                    },
+                    UniqueComponent::StableId => stable_id.clone(),
                 };
-                if let Some(repr) = substitute {
-                    substitution.insert(p.id.clone(), PackageId { repr: repr.clone() });
-                    reverse.insert(PackageId { repr }, p.id.clone());
+                // IDs in the stable format, e.g. from old Cargo.lock checksums, are shortened
+                // the same way.
+                if stable_id != p.id {
+                    substitution.insert(stable_id.clone(), substitute.clone());
                 }
+                substitution.insert(p.id.clone(), substitute.clone());
+                reverse.insert(substitute, p.id.clone());
+                stable.insert(p.id.clone(), stable_id);
             }
         }
 
         PackageIdShortener {
             substitution,
             reverse,
+            stable,
         }
     }
 
+    /// Returns the [stable_package_id] for a package ID, shortened or not.
+    pub fn stable_ref<'a>(&'a self, package_id: &'a PackageId) -> &'a PackageId {
+        self.stable
+            .get(self.lengthen_ref(package_id))
+            .unwrap_or(package_id)
+    }
+
     pub fn lengthen_ref<'a>(&'a self, package_id: &'a PackageId) -> &'a PackageId {
         self.reverse.get(package_id).unwrap_or(package_id)
     }
@@ -230,31 +432,145 @@ impl PackageIdShortener {
             .unwrap_or(package_id)
     }
 
-    fn shorten_in_package(&self, package: &Package) -> Package {
-        let mut p = package.clone();
-        p.id = self.shorten(&package.id);
-        p
+    fn shorten_in_package(&self, mut package: Package) -> Package {
+        package.id = self.shorten_owned(package.id);
+        package
     }
 
-    fn shorten_in_node(&self, node: &Node) -> Node {
-        let mut n = node.clone();
-        n.id = self.shorten_owned(n.id);
-        n.dependencies = n
+    fn shorten_in_node(&self, mut node: Node) -> Node {
+        node.id = self.shorten_owned(node.id);
+        node.dependencies = node
             .dependencies
             .into_iter()
             .map(|id| self.shorten_owned(id))
             .collect();
-        n.deps = n
+        node.deps = node
             .deps
-            .iter()
+            .into_iter()
             .map(|dep| self.shorten_in_node_dep(dep))
             .collect();
-        n
+        node
     }
 
-    fn shorten_in_node_dep(&self, nod_dep: &NodeDep) -> NodeDep {
-        let mut n = nod_dep.clone();
-        n.pkg = self.shorten_owned(n.pkg);
-        n
+    fn shorten_in_node_dep(&self, mut node_dep: NodeDep) -> NodeDep {
+        node_dep.pkg = self.shorten_owned(node_dep.pkg);
+        node_dep
     }
 }
+
+#[test]
+fn test_workspace_default_members() {
+    let mut env = crate::test::MetadataEnv::default();
+    let main = env.add_package_and_node("main").get_package().id.clone();
+    let tool = env.add_package_and_node("tool").get_package().id.clone();
+    let mut metadata = env.metadata();
+    metadata.workspace_members = vec![main.clone(), tool.clone()];
+
+    let indexed = IndexedMetadata::new_from(metadata.clone()).unwrap();
+    assert_eq!(indexed.workspace_default_members.len(), 2);
+
+    metadata.workspace_default_members =
+        serde_json::from_value(serde_json::json!([main.repr])).unwrap();
+    let indexed = IndexedMetadata::new_from(metadata).unwrap();
+    assert_eq!(
+        indexed.workspace_default_members,
+        vec![indexed.id_shortener.shorten(&main)]
+    );
+
+    env.close();
+}
+
+#[test]
+fn test_stable_package_ids() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    let sources = [
+        "registry+https://github.com/rust-lang/crates.io-index",
+        "git+https://github.com/serde-rs/serde#42f5544e51187f0c7535d453fcffb4b524c99eb2",
+    ];
+    for source in sources {
+        env.add_package_and_node("serde").update_package(|p| {
+            p.version = semver::Version::new(1, 0, 0);
+            p.id = PackageId {
+                repr: format!("{}#serde@1.0.0", source),
+            };
+            p.source = Some(cargo_metadata::Source {
+                repr: source.to_string(),
+            });
+        });
+    }
+    let shortener = env.indexed_metadata().id_shortener;
+
+    let git = PackageId {
+        repr: format!("{}#serde@1.0.0", sources[1]),
+    };
+    let stable = PackageId {
+        repr: format!("serde 1.0.0 ({})", sources[1]),
+    };
+    assert_eq!(shortener.shorten(&git), stable);
+    assert_eq!(shortener.shorten(&stable), stable);
+    assert_eq!(shortener.lengthen_ref(&stable), &git);
+    assert_eq!(shortener.stable_ref(&git), &stable);
+    let main = PackageId {
+        repr: "main".to_string(),
+    };
+    assert!(shortener
+        .stable_ref(&main)
+        .repr
+        .starts_with("main 0.1.0 (path+file://"));
+
+    env.close();
+}
+
+#[test]
+fn test_merge_without_resolve() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    let mut metadata = env.metadata();
+    metadata.resolve = None;
+
+    let error = MergedMetadata::merge(vec![metadata]).unwrap_err();
+    assert!(
+        error.to_string().contains("no dependency graph"),
+        "{}",
+        error
+    );
+    assert!(MergedMetadata::merge(Vec::new()).is_err());
+
+    env.close();
+}
+
+#[test]
+fn test_merge_platform_specific_nodes() {
+    let mut env = crate::test::MetadataEnv::default();
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.add_dependency("libc").version_and_package_id("0.2.0");
+    main.add_dependency("winapi")
+        .version_and_package_id("0.3.0");
+    let full = env.metadata();
+    let only = |name: &str| {
+        let mut metadata = full.clone();
+        let resolve = metadata.resolve.as_mut().unwrap();
+        resolve.nodes.retain(|node| !node.id.repr.contains(name));
+        for node in &mut resolve.nodes {
+            node.deps.retain(|dep| !dep.pkg.repr.contains(name));
+            node.dependencies.retain(|id| !id.repr.contains(name));
+        }
+        metadata
+    };
+
+    let merged = MergedMetadata::merge(vec![only("winapi"), only("libc")]).unwrap();
+    assert!(merged.root.is_some());
+    assert_eq!(merged.workspace_members.len(), 1);
+    assert_eq!(merged.nodes.len(), 3);
+    let main = merged
+        .nodes
+        .iter()
+        .find(|node| Some(&node.id) == merged.root.as_ref())
+        .unwrap();
+    assert_eq!(main.deps.len(), 2);
+    assert_eq!(main.dependencies.len(), 2);
+
+    env.close();
+}
@@ -0,0 +1,195 @@
+//! Reusing the output of `cargo metadata` across runs.
+//!
+//! `cargo metadata` takes several seconds for huge workspaces, even if nothing changed.
+//! Its output only depends on the manifests, `Cargo.lock`, the cargo configuration, the cargo
+//! version, its environment and the options, so we store it in the user's cache directory
+//! together with fingerprints of these files. `crate2nix generate`, `graph`, `licenses` and
+//! `outdated` reuse it while the fingerprints match.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use cargo_metadata::Metadata;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::incremental::fingerprint_path;
+
+/// The cached metadata for one `Cargo.toml` and the fingerprints it was created with.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedMetadata {
+    /// Fingerprints of all inputs by path. `None` for files that did not exist.
+    files: BTreeMap<PathBuf, Option<String>>,
+    metadata: Metadata,
+}
+
+/// The directory for the cache files: `$XDG_CACHE_HOME/crate2nix/metadata`,
+/// `$HOME/.cache/crate2nix/metadata` without `XDG_CACHE_HOME`.
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("crate2nix").join("metadata"))
+}
+
+/// Returns the cached metadata for `cargo_toml` if none of its inputs changed.
+pub fn read(cache_dir: &Path, cargo_toml: &Path, options: &[String]) -> Option<Metadata> {
+    let path = cache_path(cache_dir, cargo_toml, options)?;
+    let content = std::fs::read(&path).ok()?;
+    let Ok(cached) = serde_json::from_slice::<CachedMetadata>(&content) else {
+        debug!("Ignoring unreadable metadata cache {}.", path.display());
+        return None;
+    };
+    if let Some((file, _)) = cached
+        .files
+        .iter()
+        .find(|(file, fingerprint)| fingerprint_path(file) != **fingerprint)
+    {
+        debug!(
+            "{} changed since cargo metadata was cached.",
+            file.display()
+        );
+        return None;
+    }
+    debug!("Using cached metadata from {}.", path.display());
+    Some(cached.metadata)
+}
+
+/// Caches `metadata` for `cargo_toml`.
+pub fn write(
+    cache_dir: &Path,
+    cargo_toml: &Path,
+    options: &[String],
+    metadata: &Metadata,
+) -> Result<(), Error> {
+    let Some(path) = cache_path(cache_dir, cargo_toml, options) else {
+        return Ok(());
+    };
+    let files = input_files(cargo_toml, metadata)
+        .into_iter()
+        .map(|file| {
+            let fingerprint = fingerprint_path(&file);
+            (file, fingerprint)
+        })
+        .collect();
+    let cached = CachedMetadata {
+        files,
+        metadata: metadata.clone(),
+    };
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("while creating {}", cache_dir.display()))?;
+    std::fs::write(&path, serde_json::to_vec(&cached)?)
+        .with_context(|| format!("while writing {}", path.display()))
+}
+
+/// The environment variables that change the output of `cargo metadata`.
+const CARGO_ENV: &[&str] = &["CARGO_HOME", "CARGO_TARGET_DIR", "CARGO_BUILD_TARGET_DIR"];
+
+/// The version and the environment of the cargo that `cargo_metadata` runs, `None` if it cannot
+/// be run.
+pub(crate) fn cargo_environment() -> Option<String> {
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let cargo_version = std::process::Command::new(&cargo)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?
+        .stdout;
+
+    let mut environment = String::from_utf8_lossy(&cargo_version).trim().to_string();
+    for var in CARGO_ENV {
+        let value = std::env::var_os(var);
+        environment.push_str(&format!("\n{}={:?}", var, value));
+    }
+    Some(environment)
+}
+
+/// The cache file for the given manifest and `cargo metadata` options, with the version and
+/// environment of the cargo that `cargo_metadata` runs.
+fn cache_path(cache_dir: &Path, cargo_toml: &Path, options: &[String]) -> Option<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    cargo_environment()?.hash(&mut hasher);
+    cargo_toml.canonicalize().ok()?.hash(&mut hasher);
+    options.hash(&mut hasher);
+    Some(cache_dir.join(format!("{:016x}.json", hasher.finish())))
+}
+
+/// The files and directories whose changes can change the output of `cargo metadata`.
+fn input_files(cargo_toml: &Path, metadata: &Metadata) -> Vec<PathBuf> {
+    let local_manifests = metadata
+        .packages
+        .iter()
+        .filter(|p| p.source.is_none())
+        .map(|p| p.manifest_path.as_std_path());
+    workspace_input_files(
+        cargo_toml,
+        metadata.workspace_root.as_std_path(),
+        local_manifests,
+    )
+}
+
+/// The files and directories whose changes can change the output of `cargo metadata` for
+/// `cargo_toml` in `workspace_root` with the given manifests of local crates: The manifests,
+/// `Cargo.lock`, the cargo configuration and the directories that cargo uses for
+/// auto-discovering targets.
+pub(crate) fn workspace_input_files<'a>(
+    cargo_toml: &Path,
+    workspace_root: &Path,
+    local_manifests: impl IntoIterator<Item = &'a Path>,
+) -> Vec<PathBuf> {
+    let mut files = vec![cargo_toml.to_path_buf()];
+    files.push(workspace_root.join("Cargo.lock"));
+    // Cargo reads the configuration in the directories above the current directory.
+    let current_dir = std::env::current_dir().ok();
+    let config_dirs = workspace_root
+        .ancestors()
+        .chain(current_dir.iter().flat_map(|dir| dir.ancestors()));
+    for dir in config_dirs {
+        files.push(dir.join(".cargo").join("config"));
+        files.push(dir.join(".cargo").join("config.toml"));
+    }
+    if let Some(cargo_home) = crate::cargo_config::cargo_home() {
+        files.push(cargo_home.join("config"));
+        files.push(cargo_home.join("config.toml"));
+    }
+    for manifest_path in local_manifests {
+        files.push(manifest_path.to_path_buf());
+        if let Some(package_dir) = manifest_path.parent() {
+            for dir in ["src", "src/bin", "tests", "examples", "benches"] {
+                files.push(package_dir.join(dir));
+            }
+            files.push(package_dir.join("build.rs"));
+        }
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+#[test]
+fn test_read_and_write() {
+    let cache_dir = tempdir::TempDir::new("crate2nix_metadata_cache").expect("temp dir");
+    let project = tempdir::TempDir::new("crate2nix_metadata_project").expect("temp dir");
+    let cargo_toml = project.path().join("Cargo.toml");
+    std::fs::write(&cargo_toml, "[package]").unwrap();
+    let cache_dir_path = cache_dir.path().join("metadata");
+
+    let mut metadata = crate::test::empty_metadata();
+    metadata.workspace_root = project.path().to_path_buf().try_into().unwrap();
+    let options = vec!["--locked".to_string()];
+    assert!(read(&cache_dir_path, &cargo_toml, &options).is_none());
+
+    write(&cache_dir_path, &cargo_toml, &options, &metadata).unwrap();
+    assert!(read(&cache_dir_path, &cargo_toml, &options).is_some());
+    assert!(read(&cache_dir_path, &cargo_toml, &[]).is_none());
+
+    std::fs::write(project.path().join("Cargo.lock"), "version = 3").unwrap();
+    assert!(read(&cache_dir_path, &cargo_toml, &options).is_none());
+
+    project.close().unwrap();
+    cache_dir.close().unwrap();
+}
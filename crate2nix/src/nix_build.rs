@@ -2,8 +2,8 @@
 
 use std::io::BufRead;
 use std::io::Write;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
 use anyhow::bail;
 use anyhow::format_err;
@@ -80,3 +80,121 @@ pub fn run_cmd(cmd_path: impl AsRef<Path>) -> Result<String, Error> {
     String::from_utf8(output.stdout)
         .map_err(|_e| format_err!("output of {} is not UTF8!", cmd_path))
 }
+
+/// Builds the attribute `nix_attr` of the given nix file and returns its output path.
+///
+/// `args` are passed on to `nix build`, e.g. `--no-link`. The build log goes to stderr.
+pub fn nix_build_out_path(
+    nix_file: &Path,
+    nix_attr: &str,
+    args: &[&str],
+) -> Result<PathBuf, Error> {
+    let output = Command::new("nix")
+        .args(["build", "--print-out-paths", "-f"])
+        .arg(nix_file)
+        .arg(nix_attr)
+        .args(args)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format_err!("while spawning nix build: {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "nix build of {} in {} failed.",
+            nix_attr,
+            nix_file.to_string_lossy()
+        );
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_e| format_err!("output of nix build is not UTF8!"))?;
+    match stdout.lines().next() {
+        Some(out_path) => Ok(PathBuf::from(out_path.trim())),
+        None => bail!("nix build of {} printed no output path.", nix_attr),
+    }
+}
+
+/// Evaluates `meta.mainProgram` of the attribute `nix_attr` of the given nix file, if it is set.
+pub fn main_program(nix_file: &Path, nix_attr: &str) -> Option<String> {
+    let output = Command::new("nix")
+        .args(["eval", "--raw", "-f"])
+        .arg(nix_file)
+        .arg(format!("{}.meta.mainProgram", nix_attr))
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Returns the binary to run in the `bin` directory of a build output.
+///
+/// Uses `bin` if given, otherwise `main_program` or the only binary.
+pub fn select_binary(
+    out_path: &Path,
+    bin: Option<&str>,
+    main_program: impl FnOnce() -> Option<String>,
+) -> Result<PathBuf, Error> {
+    let bin_dir = out_path.join("bin");
+    let mut binaries: Vec<String> = std::fs::read_dir(&bin_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    binaries.sort();
+
+    let name = match (bin, binaries.as_slice()) {
+        (Some(bin), _) if binaries.iter().any(|b| b == bin) => bin.to_string(),
+        (Some(bin), _) => bail!(
+            "{} has no binary {}. Available binaries: {}",
+            out_path.to_string_lossy(),
+            bin,
+            binaries.join(", ")
+        ),
+        (None, []) => bail!("{} contains no binaries.", out_path.to_string_lossy()),
+        (None, [only]) => only.clone(),
+        (None, _) => match main_program() {
+            Some(main_program) if binaries.contains(&main_program) => main_program,
+            _ => bail!(
+                "{} has several binaries, select one with --bin: {}",
+                out_path.to_string_lossy(),
+                binaries.join(", ")
+            ),
+        },
+    };
+    Ok(bin_dir.join(name))
+}
+
+#[test]
+fn test_select_binary() {
+    let dir = tempdir::TempDir::new("crate2nix_select_binary").expect("temp dir");
+    let out = dir.path();
+    assert!(select_binary(out, None, || None).is_err());
+
+    std::fs::create_dir(out.join("bin")).unwrap();
+    std::fs::write(out.join("bin").join("server"), "").unwrap();
+    assert_eq!(
+        select_binary(out, None, || None).unwrap(),
+        out.join("bin").join("server")
+    );
+
+    std::fs::write(out.join("bin").join("client"), "").unwrap();
+    let error = select_binary(out, None, || None).unwrap_err();
+    assert!(error
+        .to_string()
+        .ends_with("select one with --bin: client, server"));
+    assert_eq!(
+        select_binary(out, None, || Some("client".to_string())).unwrap(),
+        out.join("bin").join("client")
+    );
+    assert_eq!(
+        select_binary(out, Some("server"), || None).unwrap(),
+        out.join("bin").join("server")
+    );
+    assert!(select_binary(out, Some("other"), || None).is_err());
+
+    dir.close().unwrap();
+}
@@ -0,0 +1,259 @@
+//! Checking that the crates built for a platform do not need std, see `--no-std`.
+//!
+//! A crate can be built without std if its crate root has `#![no_std]`, possibly behind a
+//! `cfg_attr` like `#![cfg_attr(not(feature = "std"), no_std)]`, which we evaluate with the
+//! resolved features. Proc macros and build dependencies run on the building platform and may
+//! use std.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Error};
+use cargo_metadata::PackageId;
+use cargo_platform::{Cfg, CfgExpr};
+use log::warn;
+
+use crate::platforms::TargetPlatform;
+use crate::resolve::CrateDerivation;
+use crate::BuildInfo;
+
+/// Fails with the crates that need std if any crate built for `platform` does.
+pub fn check(build_info: &BuildInfo, platform: &TargetPlatform) -> Result<(), Error> {
+    let crates_by_id: HashMap<&PackageId, &CrateDerivation> = build_info
+        .crates
+        .iter()
+        .map(|c| (&*c.package_id, c))
+        .collect();
+    let mut queue: VecDeque<&PackageId> = build_info
+        .root_package_id
+        .iter()
+        .chain(build_info.workspace_members.values())
+        .collect();
+    let mut seen = HashSet::new();
+    let mut needing_std = Vec::new();
+    while let Some(package_id) = queue.pop_front() {
+        if !seen.insert(package_id) {
+            continue;
+        }
+        let Some(crate_derivation) = crates_by_id.get(package_id) else {
+            continue;
+        };
+        if crate_derivation.proc_macro {
+            continue;
+        }
+        if let Some(reason) = std_requirement_of(build_info, crate_derivation, platform) {
+            needing_std.push(format!(
+                "{} {}: {}",
+                crate_derivation.crate_name, crate_derivation.version, reason
+            ));
+        }
+        queue.extend(
+            crate_derivation
+                .dependencies
+                .iter()
+                .filter(|d| d.target.as_ref().is_none_or(|t| platform.may_match(t)))
+                .map(|d| &*d.package_id),
+        );
+    }
+
+    if !needing_std.is_empty() {
+        bail!(
+            "These crates need std on {}:\n    {}\n\
+             Use `crate2nix explain-features <crate>` to find out why their features are enabled.",
+            platform.system,
+            needing_std.join("\n    ")
+        );
+    }
+    Ok(())
+}
+
+/// Why the crate needs std, if it does, judging by the roots of its library or binaries.
+fn std_requirement_of(
+    build_info: &BuildInfo,
+    crate_derivation: &CrateDerivation,
+    platform: &TargetPlatform,
+) -> Option<String> {
+    let package = build_info
+        .indexed_metadata
+        .pkgs_by_id
+        .get(&crate_derivation.package_id)?;
+    let package_dir = package.manifest_path.parent()?.as_std_path();
+    let features: BTreeSet<&str> = build_info
+        .indexed_metadata
+        .nodes_by_id
+        .get(&crate_derivation.package_id)
+        .map(|node| node.features.iter().map(String::as_str).collect())
+        .unwrap_or_default();
+    let roots: Vec<&Path> = match &crate_derivation.lib {
+        Some(lib) => vec![&lib.src_path],
+        None => crate_derivation
+            .binaries
+            .iter()
+            .map(|bin| bin.src_path.as_path())
+            .collect(),
+    };
+    roots.into_iter().find_map(|root| {
+        let path = package_dir.join(root);
+        match std::fs::read_to_string(&path) {
+            Ok(source) => std_requirement(&source, &features, platform),
+            Err(e) => {
+                warn!("Could not check {} for no_std: {}", path.display(), e);
+                None
+            }
+        }
+    })
+}
+
+/// Why a crate root needs std, if it does.
+fn std_requirement(
+    source: &str,
+    features: &BTreeSet<&str>,
+    platform: &TargetPlatform,
+) -> Option<String> {
+    // Comments and whitespace would get in the way of matching attributes.
+    let code: String = source
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("//"))
+        .flat_map(|line| line.chars().filter(|c| !c.is_whitespace()))
+        .collect();
+    if code.contains("#![no_std]") {
+        return None;
+    }
+
+    let mut conditions = Vec::new();
+    let mut rest = code.as_str();
+    while let Some(start) = rest.find("#![cfg_attr(") {
+        rest = &rest[start + "#![cfg_attr(".len()..];
+        let Some((condition, attrs)) = split_cfg_attr(rest) else {
+            break;
+        };
+        if attrs.split(',').any(|attr| attr == "no_std") {
+            conditions.push(condition);
+        }
+    }
+    if conditions.is_empty() {
+        return Some("its crate root has no `#![no_std]`".to_string());
+    }
+    for condition in &conditions {
+        let Ok(expr) = CfgExpr::from_str(condition) else {
+            warn!("Could not parse the no_std condition `{}`.", condition);
+            return None;
+        };
+        match eval(&expr, features, platform) {
+            Some(true) => return None,
+            Some(false) => {}
+            None => {
+                warn!(
+                    "Could not evaluate the no_std condition `{}`, assuming that it holds.",
+                    condition
+                );
+                return None;
+            }
+        }
+    }
+    Some(format!(
+        "it is only `no_std` with `{}`, enabled features: {}",
+        conditions.join("` or `"),
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.iter().copied().collect::<Vec<_>>().join(", ")
+        }
+    ))
+}
+
+/// Splits the arguments of `cfg_attr(` at the first top-level comma into the condition and the
+/// attributes, up to the closing parenthesis.
+fn split_cfg_attr(arguments: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    let mut comma = None;
+    for (i, c) in arguments.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some((&arguments[..comma?], &arguments[comma? + 1..i])),
+            ')' => depth -= 1,
+            ',' if depth == 0 && comma.is_none() => comma = Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Evaluates a cfg expression with the enabled `features` on `platform`, `None` if the result
+/// depends on more than that.
+fn eval(expr: &CfgExpr, features: &BTreeSet<&str>, platform: &TargetPlatform) -> Option<bool> {
+    match expr {
+        CfgExpr::Value(Cfg::KeyPair(key, value)) if key == "feature" => {
+            Some(features.contains(value.as_str()))
+        }
+        CfgExpr::Value(_) => platform.eval(expr),
+        CfgExpr::Not(expr) => eval(expr, features, platform).map(|result| !result),
+        CfgExpr::All(exprs) => {
+            let results: Vec<Option<bool>> =
+                exprs.iter().map(|e| eval(e, features, platform)).collect();
+            if results.contains(&Some(false)) {
+                Some(false)
+            } else if results.iter().all(|result| *result == Some(true)) {
+                Some(true)
+            } else {
+                None
+            }
+        }
+        CfgExpr::Any(exprs) => {
+            let results: Vec<Option<bool>> =
+                exprs.iter().map(|e| eval(e, features, platform)).collect();
+            if results.contains(&Some(true)) {
+                Some(true)
+            } else if results.iter().all(|result| *result == Some(false)) {
+                Some(false)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_std_requirement() {
+    let platform =
+        TargetPlatform::from_system("x86_64-linux", &std::collections::BTreeMap::new()).unwrap();
+    let no_features = BTreeSet::new();
+    let std = BTreeSet::from(["default", "std"]);
+
+    assert_eq!(
+        std_requirement("//! Docs\n#![no_std]\n", &no_features, &platform),
+        None
+    );
+    assert_eq!(
+        std_requirement("// #![no_std]\npub fn f() {}\n", &no_features, &platform),
+        Some("its crate root has no `#![no_std]`".to_string())
+    );
+
+    let conditional =
+        "#![cfg_attr(not(feature = \"std\"), no_std)]\n#![cfg_attr(docsrs, feature(doc_cfg))]\n";
+    assert_eq!(std_requirement(conditional, &no_features, &platform), None);
+    assert_eq!(
+        std_requirement(conditional, &std, &platform),
+        Some(
+            "it is only `no_std` with `not(feature=\"std\")`, enabled features: default, std"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        std_requirement(
+            "#![cfg_attr(all(not(test), target_os = \"linux\"), no_std)]",
+            &no_features,
+            &platform
+        ),
+        None
+    );
+    assert_eq!(
+        std_requirement(
+            "#![cfg_attr(target_os = \"none\", no_std, no_main)]",
+            &no_features,
+            &platform
+        ),
+        Some("it is only `no_std` with `target_os=\"none\"`, enabled features: none".to_string())
+    );
+}
@@ -0,0 +1,431 @@
+//! Comparing resolved crate versions with the latest versions on their registries,
+//! e.g. for `crate2nix outdated`, and finding yanked versions during generation.
+//!
+//! The versions are looked up in the [sparse index](https://doc.rust-lang.org/cargo/reference/registry-index.html)
+//! of the registry that a crate was resolved from. Crates from git registries are skipped.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, format_err, Error};
+use log::{debug, warn};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+use crate::BuildInfo;
+
+const SPARSE_PREFIX: &str = "sparse+";
+const CRATES_IO_GIT_INDEX: &str = "registry+https://github.com/rust-lang/crates.io-index";
+const CRATES_IO_SPARSE_INDEX: &str = "https://index.crates.io/";
+
+/// A crate for which a newer version exists on its registry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutdatedCrate {
+    /// The crate name.
+    pub name: String,
+    /// The version in Cargo.lock and Cargo.nix.
+    pub resolved: Version,
+    /// The newest semver compatible version, if newer than `resolved`.
+    ///
+    /// Available by `cargo update` and regenerating.
+    pub compatible: Option<Version>,
+    /// The newest version, if newer than `resolved`.
+    ///
+    /// Might require changing the version requirement in the dependent `Cargo.toml`.
+    pub latest: Version,
+}
+
+/// An entry in a sparse registry index file, one per published version.
+#[derive(Debug, Deserialize)]
+struct IndexEntry {
+    vers: Version,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// Returns all crates in `build_info` that have newer versions on their registry.
+pub fn outdated_crates(build_info: &BuildInfo) -> Result<Vec<OutdatedCrate>, Error> {
+    let versions_by_index = versions_by_index(build_info);
+    let mut outdated = Vec::new();
+    for ((index_url, name), resolved_versions) in versions_by_index {
+        let published = match fetch_index_file(&index_url, &name) {
+            Ok(index_file) => published_versions(&index_file)?,
+            Err(e) => {
+                warn!("Could not look up versions of {}: {}", name, e);
+                continue;
+            }
+        };
+        for resolved in resolved_versions {
+            if let Some(outdated_crate) = compare(&name, &resolved, &published) {
+                outdated.push(outdated_crate);
+            }
+        }
+    }
+    Ok(outdated)
+}
+
+/// The resolved versions of the crates from sparse or crates.io registries
+/// by index URL and crate name.
+fn versions_by_index(build_info: &BuildInfo) -> BTreeMap<(String, String), Vec<Version>> {
+    let mut versions_by_index: BTreeMap<(String, String), Vec<Version>> = BTreeMap::new();
+    for crate_derivation in &build_info.crates {
+        let Some(source) = build_info
+            .indexed_metadata
+            .pkgs_by_id
+            .get(&crate_derivation.package_id)
+            .and_then(|package| package.source.as_ref())
+        else {
+            continue;
+        };
+        let Some(index_url) = sparse_index_url(&source.repr) else {
+            debug!(
+                "Skipping {} {} from {}.",
+                crate_derivation.crate_name, crate_derivation.version, source.repr
+            );
+            continue;
+        };
+        versions_by_index
+            .entry((index_url, crate_derivation.crate_name.to_string()))
+            .or_default()
+            .push((*crate_derivation.version).clone());
+    }
+    versions_by_index
+}
+
+/// A resolved crate version that has been yanked from its registry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct YankedCrate {
+    /// The crate name.
+    pub name: String,
+    /// The yanked version in Cargo.lock.
+    pub version: Version,
+}
+
+/// The result of looking up the resolved versions in the registry indexes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct YankedCheck {
+    /// The crates whose resolved versions have been yanked.
+    pub yanked: Vec<YankedCrate>,
+    /// The names of the crates that could not be checked.
+    pub unchecked: Vec<String>,
+}
+
+impl YankedCheck {
+    /// Adds the yanked `resolved_versions` of `name` according to its fetched `index_file`.
+    fn add(
+        &mut self,
+        name: &str,
+        resolved_versions: &[Version],
+        index_file: Result<String, Error>,
+    ) {
+        let index_file = match index_file {
+            Ok(index_file) => index_file,
+            Err(e) => {
+                debug!("Could not look up versions of {}: {}", name, e);
+                self.unchecked.push(name.to_string());
+                return;
+            }
+        };
+        let yanked_versions = match yanked_versions(&index_file) {
+            Ok(yanked_versions) => yanked_versions,
+            Err(e) => {
+                warn!("Could not read the index file of {}: {}", name, e);
+                self.unchecked.push(name.to_string());
+                return;
+            }
+        };
+        self.yanked.extend(
+            resolved_versions
+                .iter()
+                .filter(|v| yanked_versions.contains(v))
+                .map(|version| YankedCrate {
+                    name: name.to_string(),
+                    version: version.clone(),
+                }),
+        );
+    }
+}
+
+/// Looks up whether the resolved versions of the crates in `build_info` have been yanked.
+///
+/// Looks up `jobs` index files concurrently. Crates whose index files cannot be fetched,
+/// e.g. without network access, or cannot be parsed are skipped with a warning and listed in
+/// `YankedCheck::unchecked`.
+pub fn yanked_crates(build_info: &BuildInfo, jobs: usize) -> Result<YankedCheck, Error> {
+    let versions_by_index: Vec<_> = versions_by_index(build_info).into_iter().collect();
+    let next = AtomicUsize::new(0);
+    let index_files: Vec<(usize, Result<String, Error>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, versions_by_index.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut fetched = Vec::new();
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(((index_url, name), _)) = versions_by_index.get(idx) else {
+                            break fetched;
+                        };
+                        fetched.push((idx, fetch_index_file(index_url, name)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("index fetch panicked"))
+            .collect()
+    });
+
+    let mut check = YankedCheck::default();
+    for (idx, index_file) in index_files {
+        let ((_, name), resolved_versions) = &versions_by_index[idx];
+        check.add(name, resolved_versions, index_file);
+    }
+    if !check.unchecked.is_empty() {
+        warn!(
+            "Could not check {} crate(s) for yanked versions, rerun with -v for details.",
+            check.unchecked.len()
+        );
+    }
+    check
+        .yanked
+        .sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    check.unchecked.sort();
+    Ok(check)
+}
+
+/// Returns the URL of the sparse index for a package source, if any.
+fn sparse_index_url(source: &str) -> Option<String> {
+    let url = if source == CRATES_IO_GIT_INDEX {
+        CRATES_IO_SPARSE_INDEX
+    } else {
+        source.strip_prefix(SPARSE_PREFIX)?
+    };
+    Some(format!("{}/", url.trim_end_matches('/')))
+}
+
+/// Returns the path of the index file for the given crate relative to the index root.
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+fn fetch_index_file(index_url: &str, name: &str) -> Result<String, Error> {
+    let url = format!("{}{}", index_url, index_path(name));
+    debug!("Fetching {}", url);
+    let output = crate::proxy::apply(Command::new("curl").args([
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--location",
+        &url,
+    ]))
+    .output()
+    .map_err(|e| format_err!("while spawning curl for {}: {}", url, e))?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    String::from_utf8(output.stdout).map_err(|_e| format_err!("{} is not UTF8!", url))
+}
+
+/// Parses the non-yanked versions from a sparse index file.
+fn published_versions(index_file: &str) -> Result<Vec<Version>, Error> {
+    let mut versions = Vec::new();
+    for line in index_file.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: IndexEntry = serde_json::from_str(line)
+            .map_err(|e| format_err!("while parsing index entry '{}': {}", line, e))?;
+        if !entry.yanked {
+            versions.push(entry.vers);
+        }
+    }
+    Ok(versions)
+}
+
+/// Parses the yanked versions from a sparse index file.
+fn yanked_versions(index_file: &str) -> Result<Vec<Version>, Error> {
+    let mut versions = Vec::new();
+    for line in index_file.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: IndexEntry = serde_json::from_str(line)
+            .map_err(|e| format_err!("while parsing index entry '{}': {}", line, e))?;
+        if entry.yanked {
+            versions.push(entry.vers);
+        }
+    }
+    Ok(versions)
+}
+
+fn compare(name: &str, resolved: &Version, published: &[Version]) -> Option<OutdatedCrate> {
+    let candidates = || {
+        published
+            .iter()
+            .filter(|v| *v > resolved && (v.pre.is_empty() || !resolved.pre.is_empty()))
+    };
+    let latest = candidates().max()?.clone();
+    let requirement = VersionReq::parse(&format!("^{}", resolved)).ok()?;
+    let compatible = candidates()
+        .filter(|v| requirement.matches(v))
+        .max()
+        .cloned();
+    Some(OutdatedCrate {
+        name: name.to_string(),
+        resolved: resolved.clone(),
+        compatible,
+        latest,
+    })
+}
+
+/// Renders the outdated crates as a table.
+pub fn render(outdated: &[OutdatedCrate]) -> String {
+    if outdated.is_empty() {
+        return "All crates are up to date.\n".to_string();
+    }
+
+    let rows: Vec<[String; 4]> = outdated
+        .iter()
+        .map(|c| {
+            [
+                c.name.clone(),
+                c.resolved.to_string(),
+                c.compatible
+                    .as_ref()
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                c.latest.to_string(),
+            ]
+        })
+        .collect();
+    let header = ["crate", "resolved", "compatible", "latest"].map(String::from);
+    let mut widths = header.clone().map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(table, "{}", line.trim_end()).unwrap();
+    }
+
+    table.push('\n');
+    let compatible = outdated.iter().filter(|c| c.compatible.is_some()).count();
+    if compatible > 0 {
+        writeln!(
+            table,
+            "{} crate(s) can be updated with `cargo update` followed by regenerating Cargo.nix.",
+            compatible
+        )
+        .unwrap();
+    }
+    let incompatible = outdated
+        .iter()
+        .filter(|c| c.compatible.as_ref() != Some(&c.latest))
+        .count();
+    if incompatible > 0 {
+        writeln!(
+            table,
+            "{} crate(s) have newer versions that require changing version requirements.",
+            incompatible
+        )
+        .unwrap();
+    }
+    table
+}
+
+#[test]
+fn test_index_path() {
+    assert_eq!(index_path("a"), "1/a");
+    assert_eq!(index_path("ab"), "2/ab");
+    assert_eq!(index_path("abc"), "3/a/abc");
+    assert_eq!(index_path("Serde"), "se/rd/serde");
+}
+
+#[test]
+fn test_sparse_index_url() {
+    assert_eq!(
+        sparse_index_url(CRATES_IO_GIT_INDEX).as_deref(),
+        Some(CRATES_IO_SPARSE_INDEX)
+    );
+    assert_eq!(
+        sparse_index_url("sparse+https://my.registry/index").as_deref(),
+        Some("https://my.registry/index/")
+    );
+    assert_eq!(sparse_index_url("registry+https://my.registry/git"), None);
+}
+
+#[test]
+fn test_compare() {
+    let published = published_versions(
+        r#"{"name":"foo","vers":"1.0.0"}
+{"name":"foo","vers":"1.2.0"}
+{"name":"foo","vers":"1.3.0","yanked":true}
+{"name":"foo","vers":"2.0.0"}
+{"name":"foo","vers":"3.0.0-alpha.1"}
+"#,
+    )
+    .unwrap();
+    let v = |s: &str| Version::parse(s).unwrap();
+
+    assert_eq!(
+        compare("foo", &v("1.0.0"), &published),
+        Some(OutdatedCrate {
+            name: "foo".to_string(),
+            resolved: v("1.0.0"),
+            compatible: Some(v("1.2.0")),
+            latest: v("2.0.0"),
+        })
+    );
+    assert_eq!(
+        compare("foo", &v("1.2.0"), &published).and_then(|c| c.compatible),
+        None
+    );
+    assert_eq!(compare("foo", &v("2.0.0"), &published), None);
+}
+
+#[test]
+fn test_yanked_versions() {
+    assert_eq!(
+        yanked_versions(
+            r#"{"name":"foo","vers":"1.0.0"}
+{"name":"foo","vers":"1.3.0","yanked":true}
+"#
+        )
+        .unwrap(),
+        vec![Version::parse("1.3.0").unwrap()]
+    );
+}
+
+#[test]
+fn test_yanked_check_skips_unreadable_index_files() {
+    let resolved = vec![Version::parse("1.3.0").unwrap()];
+    let mut check = YankedCheck::default();
+    check.add(
+        "foo",
+        &resolved,
+        Ok(r#"{"name":"foo","vers":"1.3.0","yanked":true}"#.to_string()),
+    );
+    check.add("bar", &resolved, Ok("not json".to_string()));
+    check.add("baz", &resolved, Err(format_err!("no network")));
+    assert_eq!(
+        check,
+        YankedCheck {
+            yanked: vec![YankedCrate {
+                name: "foo".to_string(),
+                version: Version::parse("1.3.0").unwrap(),
+            }],
+            unchecked: vec!["bar".to_string(), "baz".to_string()],
+        }
+    );
+}
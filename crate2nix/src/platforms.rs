@@ -0,0 +1,294 @@
+//! Restricting the generated dependency graph to some platforms, see `--platforms`.
+//!
+//! `Cargo.nix` contains the dependencies for all platforms and selects them at evaluation time.
+//! With `--platforms`, we drop the dependencies that none of the given platforms needs, e.g. the
+//! `windows-*` crates of a project that is only built on Linux and macOS. The remaining
+//! dependencies keep their conditions, so one file still serves all given platforms.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Error};
+use cargo_platform::{Cfg, CfgExpr, Platform};
+
+use crate::config::PlatformDefinition;
+
+/// The CPUs of nix systems: nix name, architecture in the rust target triple,
+/// `target_arch` and `target_pointer_width`.
+const CPUS: &[(&str, &str, &str, &str)] = &[
+    ("x86_64", "x86_64", "x86_64", "64"),
+    ("aarch64", "aarch64", "aarch64", "64"),
+    ("i686", "i686", "x86", "32"),
+    ("armv6l", "arm", "arm", "32"),
+    ("armv7l", "armv7", "arm", "32"),
+    ("riscv64", "riscv64gc", "riscv64", "64"),
+    ("powerpc64le", "powerpc64le", "powerpc64", "64"),
+];
+
+/// The kernels of nix systems: nix name, vendor and system in the rust target triple,
+/// `target_os` and `target_family`.
+const KERNELS: &[(&str, &str, &str, &str, &str)] = &[
+    ("linux", "unknown", "linux-gnu", "linux", "unix"),
+    ("darwin", "apple", "darwin", "macos", "unix"),
+    ("freebsd", "unknown", "freebsd", "freebsd", "unix"),
+    ("windows", "pc", "windows-gnu", "windows", "windows"),
+];
+
+/// The nix system of WASI, whose rust target is `wasm32-wasip1`, see `--wasi`.
+pub const WASI_SYSTEM: &str = "wasm32-wasi";
+
+/// A platform that `Cargo.nix` is generated for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetPlatform {
+    /// The nix system, e.g. `x86_64-linux`.
+    pub system: String,
+    /// The rust target triple that nixpkgs uses for the system.
+    rust_target: String,
+    /// The configuration like `makeDefaultTarget` in `default.nix` sets it.
+    cfgs: Vec<Cfg>,
+}
+
+impl TargetPlatform {
+    /// The platform for a nix system like `x86_64-linux` or `aarch64-darwin`.
+    ///
+    /// `configured` are the platforms from `crate2nix.json`, which take precedence.
+    pub fn from_system(
+        system: &str,
+        configured: &BTreeMap<String, PlatformDefinition>,
+    ) -> Result<TargetPlatform, Error> {
+        if let Some(definition) = configured.get(system) {
+            return Ok(TargetPlatform::new(system, definition));
+        }
+        if system == WASI_SYSTEM {
+            let definition = PlatformDefinition {
+                rust_target: "wasm32-wasip1".to_string(),
+                arch: "wasm32".to_string(),
+                os: "wasi".to_string(),
+                vendor: "unknown".to_string(),
+                env: "p1".to_string(),
+                endian: "little".to_string(),
+                pointer_width: "32".to_string(),
+                families: vec!["wasm".to_string()],
+            };
+            return Ok(TargetPlatform::new(system, &definition));
+        }
+        let parsed = system.split_once('-').and_then(|(cpu, kernel)| {
+            let cpu = CPUS.iter().find(|(name, ..)| *name == cpu)?;
+            let kernel = KERNELS.iter().find(|(name, ..)| *name == kernel)?;
+            Some((cpu, kernel))
+        });
+        let Some((&(_, triple_cpu, arch, pointer_width), &(_, vendor, triple_system, os, family))) =
+            parsed
+        else {
+            bail!(
+                "Unsupported platform {}. Use a nix system of the form <cpu>-<kernel> \
+                 with a cpu out of {} and a kernel out of {}, or define it in the \
+                 platforms of crate2nix.json.",
+                system,
+                CPUS.iter()
+                    .map(|(name, ..)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                KERNELS
+                    .iter()
+                    .map(|(name, ..)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        // 32-bit ARM uses the hard-float ABI on linux.
+        let triple_system = if arch == "arm" && triple_system == "linux-gnu" {
+            "linux-gnueabihf"
+        } else {
+            triple_system
+        };
+        let definition = PlatformDefinition {
+            rust_target: format!("{}-{}-{}", triple_cpu, vendor, triple_system),
+            arch: arch.to_string(),
+            os: os.to_string(),
+            vendor: vendor.to_string(),
+            env: "gnu".to_string(),
+            endian: "little".to_string(),
+            pointer_width: pointer_width.to_string(),
+            families: vec![family.to_string()],
+        };
+        Ok(TargetPlatform::new(system, &definition))
+    }
+
+    fn new(system: &str, definition: &PlatformDefinition) -> TargetPlatform {
+        let key_pair = |key: &str, value: &str| Cfg::KeyPair(key.to_string(), value.to_string());
+        let mut cfgs: Vec<Cfg> = definition
+            .families
+            .iter()
+            .filter(|family| ["unix", "windows"].contains(&family.as_str()))
+            .map(|family| Cfg::Name(family.clone()))
+            .collect();
+        cfgs.extend([
+            Cfg::Name("fuchsia".to_string()),
+            key_pair("arch", &definition.arch),
+            key_pair("os", &definition.os),
+            key_pair("vendor", &definition.vendor),
+            key_pair("env", &definition.env),
+            key_pair("endian", &definition.endian),
+            key_pair("pointer_width", &definition.pointer_width),
+        ]);
+        cfgs.extend(
+            definition
+                .families
+                .iter()
+                .map(|family| key_pair("family", family)),
+        );
+        TargetPlatform {
+            system: system.to_string(),
+            rust_target: definition.rust_target.clone(),
+            cfgs,
+        }
+    }
+
+    /// The rust target triple that nixpkgs uses for the system.
+    pub fn rust_target(&self) -> &str {
+        &self.rust_target
+    }
+
+    /// Whether a dependency with the given target condition may be needed on this platform.
+    ///
+    /// Conditions that depend on more than the platform, e.g. on `target_feature`, may match.
+    pub fn may_match(&self, target: &Platform) -> bool {
+        match target {
+            Platform::Name(rust_target) => *rust_target == self.rust_target,
+            Platform::Cfg(expr) => self.eval(expr) != Some(false),
+        }
+    }
+
+    /// Evaluates a cfg expression, `None` if the result does not only depend on the platform.
+    pub fn eval(&self, expr: &CfgExpr) -> Option<bool> {
+        match expr {
+            CfgExpr::Value(Cfg::Name(name)) => match name.as_str() {
+                "unix" | "windows" | "fuchsia" => {
+                    Some(self.cfgs.contains(&Cfg::Name(name.clone())))
+                }
+                "test" | "debug_assertions" => Some(false),
+                _ => None,
+            },
+            CfgExpr::Value(Cfg::KeyPair(key, value)) => {
+                let key = key.strip_prefix("target_")?;
+                if ![
+                    "arch",
+                    "os",
+                    "vendor",
+                    "family",
+                    "env",
+                    "endian",
+                    "pointer_width",
+                ]
+                .contains(&key)
+                {
+                    return None;
+                }
+                Some(
+                    self.cfgs
+                        .contains(&Cfg::KeyPair(key.to_string(), value.clone())),
+                )
+            }
+            CfgExpr::Not(expr) => self.eval(expr).map(|result| !result),
+            CfgExpr::All(exprs) => {
+                let results: Vec<Option<bool>> = exprs.iter().map(|e| self.eval(e)).collect();
+                if results.contains(&Some(false)) {
+                    Some(false)
+                } else if results.iter().all(|result| *result == Some(true)) {
+                    Some(true)
+                } else {
+                    None
+                }
+            }
+            CfgExpr::Any(exprs) => {
+                let results: Vec<Option<bool>> = exprs.iter().map(|e| self.eval(e)).collect();
+                if results.contains(&Some(true)) {
+                    Some(true)
+                } else if results.iter().all(|result| *result == Some(false)) {
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_may_match() {
+    use std::str::FromStr;
+
+    let linux = TargetPlatform::from_system("x86_64-linux", &BTreeMap::new()).unwrap();
+    let darwin = TargetPlatform::from_system("aarch64-darwin", &BTreeMap::new()).unwrap();
+    let matches = |platform: &TargetPlatform, target: &str| {
+        platform.may_match(&Platform::from_str(target).unwrap())
+    };
+
+    assert!(matches(&linux, "cfg(unix)"));
+    assert!(!matches(&linux, "cfg(windows)"));
+    assert!(matches(&linux, "x86_64-unknown-linux-gnu"));
+    assert!(!matches(&linux, "x86_64-pc-windows-msvc"));
+    assert!(matches(&darwin, "aarch64-apple-darwin"));
+    assert!(matches(&darwin, "cfg(target_os = \"macos\")"));
+    assert!(!matches(
+        &darwin,
+        "cfg(all(unix, not(target_vendor = \"apple\")))"
+    ));
+    assert!(matches(
+        &linux,
+        "cfg(any(windows, target_pointer_width = \"64\"))"
+    ));
+    // Only decided by the target features at build time.
+    assert!(matches(&linux, "cfg(target_feature = \"crt-static\")"));
+    assert!(matches(
+        &darwin,
+        "cfg(any(windows, target_feature = \"sse2\"))"
+    ));
+    assert!(!matches(
+        &darwin,
+        "cfg(all(windows, target_feature = \"sse2\"))"
+    ));
+
+    assert_eq!(
+        TargetPlatform::from_system("armv7l-linux", &BTreeMap::new())
+            .unwrap()
+            .rust_target,
+        "armv7-unknown-linux-gnueabihf"
+    );
+    assert!(TargetPlatform::from_system("x86_64-plan9", &BTreeMap::new()).is_err());
+
+    let wasi = TargetPlatform::from_system(WASI_SYSTEM, &BTreeMap::new()).unwrap();
+    assert!(matches(&wasi, "wasm32-wasip1"));
+    assert!(matches(&wasi, "cfg(target_family = \"wasm\")"));
+    assert!(!matches(&wasi, "cfg(unix)"));
+    assert!(!matches(&wasi, "cfg(target_os = \"linux\")"));
+}
+
+#[test]
+fn test_configured_platform() {
+    use std::str::FromStr;
+
+    let configured: BTreeMap<String, PlatformDefinition> = serde_json::from_str(
+        r#"{
+            "wasm32-wasi": {
+                "rustTarget": "wasm32-wasip1",
+                "arch": "wasm32",
+                "os": "wasi",
+                "env": "p1",
+                "pointerWidth": "32",
+                "families": ["wasm"]
+            }
+        }"#,
+    )
+    .unwrap();
+    let wasi = TargetPlatform::from_system("wasm32-wasi", &configured).unwrap();
+    let matches = |target: &str| wasi.may_match(&Platform::from_str(target).unwrap());
+
+    assert!(matches("wasm32-wasip1"));
+    assert!(matches("cfg(target_family = \"wasm\")"));
+    assert!(matches(
+        "cfg(all(target_os = \"wasi\", target_pointer_width = \"32\"))"
+    ));
+    assert!(!matches("cfg(unix)"));
+    assert!(!matches("cfg(target_vendor = \"apple\")"));
+}
@@ -2,16 +2,29 @@
 
 use std::io::Write;
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
 
+use crate::error::Crate2NixError;
 use crate::metadata::PackageIdShortener;
-use crate::resolve::{CrateDerivation, CratesIoSource, GitSource, ResolvedSource};
+use crate::progress::Progress;
+use crate::resolve::{CrateDerivation, CratesIoSource, GitSource, RegistrySource, ResolvedSource};
 use crate::GenerateConfig;
 use anyhow::bail;
 use anyhow::format_err;
+use anyhow::Context;
 use anyhow::Error;
 use cargo_metadata::PackageId;
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
 use serde::Deserialize;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The number of prefetched sources after which the hashes are written, so that an interrupted
+/// run can be resumed without rewriting the file after every source.
+const WRITE_HASHES_EVERY: usize = 16;
 
 /// The source is important because we need to store only hashes for which we performed
 /// a prefetch.
@@ -38,22 +51,49 @@ struct SourcePrefetchBundle<'a> {
 /// Uses `nix-prefetch` to get the hashes of the sources for the given packages if they come from crates.io.
 ///
 /// Uses and updates the existing hashes in the `config.crate_hash_json` file.
+///
+/// Crates.io sources whose `expected` hash is already in the nix store are not downloaded again.
 pub fn prefetch(
     config: &GenerateConfig,
     from_lock_file: &HashMap<PackageId, String>,
+    expected: &HashMap<PackageId, String>,
     crate_derivations: &[CrateDerivation],
     id_shortener: &PackageIdShortener,
 ) -> Result<BTreeMap<PackageId, String>, Error> {
-    let hashes_string: String = if config.read_crate_hashes {
-        std::fs::read_to_string(&config.crate_hashes_json).unwrap_or_else(|_| "{}".to_string())
+    crate::proxy::set_configured_proxy(config.proxy.clone());
+
+    let old_prefetched_hashes: BTreeMap<PackageId, String> = if config.read_crate_hashes {
+        crate::crate_hashes::read(config)?
     } else {
-        "{}".to_string()
+        BTreeMap::new()
     };
+    let sources: BTreeMap<PackageId, &ResolvedSource> = crate_derivations
+        .iter()
+        .map(|c| (id_shortener.stable_ref(&c.package_id).clone(), &c.source))
+        .collect();
 
-    let old_prefetched_hashes: BTreeMap<PackageId, String> = serde_json::from_str(&hashes_string)?;
-
-    // Only copy used hashes over to the new map.
+    // Only copy used hashes over to the new map, the others are removed from the file unless
+    // they are kept for other lockfiles.
     let mut hashes = BTreeMap::<PackageId, String>::new();
+    let current_ids: HashSet<&PackageId> = crate_derivations
+        .iter()
+        .flat_map(|c| {
+            [
+                id_shortener.stable_ref(&c.package_id),
+                id_shortener.lengthen_ref(&c.package_id),
+            ]
+        })
+        .collect();
+    let unused = old_prefetched_hashes
+        .iter()
+        .filter(|(id, _)| !current_ids.contains(id));
+    if config.keep_unused_hashes {
+        hashes.extend(unused.map(|(id, sha256)| (id.clone(), sha256.clone())));
+    } else {
+        for (id, _) in unused {
+            info!("Removing the unused hash of {}.", id);
+        }
+    }
 
     // Multiple packages might be fetched from the same source.
     //
@@ -61,7 +101,7 @@ pub fn prefetch(
     // by multiple packages.
     let packages_by_source: HashMap<ResolvedSource, Vec<&CrateDerivation>> = {
         let mut index = HashMap::new();
-        for package in crate_derivations {
+        for package in crate_derivations.iter().filter(|c| c.problem.is_none()) {
             index
                 .entry(package.source.without_sha256())
                 .or_insert_with(Vec::new)
@@ -87,8 +127,13 @@ pub fn prefetch(
                             source: HashSource::Existing,
                         })
                         .or_else(|| {
+                            // Hash caches written by older versions use cargo's package IDs.
                             old_prefetched_hashes
-                                .get(id_shortener.lengthen_ref(&p.package_id))
+                                .get(id_shortener.stable_ref(&p.package_id))
+                                .or_else(|| {
+                                    old_prefetched_hashes
+                                        .get(id_shortener.lengthen_ref(&p.package_id))
+                                })
                                 .map(|hash| HashWithSource {
                                     sha256: hash.clone(),
                                     source: HashSource::Prefetched,
@@ -116,54 +161,110 @@ pub fn prefetch(
         })
         .collect();
 
-    let without_hash_num = prefetchable_sources
-        .iter()
-        .filter(|SourcePrefetchBundle { hash, .. }| hash.is_none())
-        .count();
-
-    let mut idx = 1;
-    for SourcePrefetchBundle {
-        source,
-        packages,
-        hash,
-    } in prefetchable_sources
-    {
-        let (sha256, hash_source) = if let Some(HashWithSource { sha256, source }) = hash {
-            (sha256.trim().to_string(), source)
-        } else {
-            eprintln!(
-                "Prefetching {:>4}/{}: {}",
-                idx,
-                without_hash_num,
-                source.to_string()
-            );
-            idx += 1;
-            (source.prefetch()?, HashSource::Prefetched)
+    let record =
+        |hashes: &mut BTreeMap<PackageId, String>, packages: &[&CrateDerivation], sha256: &str| {
+            for package in packages {
+                hashes.insert(
+                    id_shortener.stable_ref(&package.package_id).clone(),
+                    sha256.to_string(),
+                );
+            }
         };
 
-        for package in packages {
-            if hash_source == HashSource::Prefetched {
-                hashes.insert(
-                    id_shortener.lengthen_ref(&package.package_id).clone(),
-                    sha256.clone(),
+    let mut without_hash = Vec::new();
+    for bundle in prefetchable_sources {
+        match &bundle.hash {
+            Some(HashWithSource { sha256, source }) => {
+                debug!(
+                    "Hash cache hit ({:?}) for {}.",
+                    source, bundle.packages[0].package_id
                 );
+                if *source == HashSource::Prefetched {
+                    verify_checksum(&bundle, sha256.trim(), expected)?;
+                    record(&mut hashes, bundle.packages, sha256.trim());
+                }
             }
+            None => without_hash.push(bundle),
         }
     }
 
+    // Prefetch concurrently and write the hashes in batches, so that a failed run can be resumed.
+    let mut progress = Progress::new("Prefetching", without_hash.len());
+    let mut failure = None;
+    let mut unwritten = 0;
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..config.prefetch_jobs.clamp(1, without_hash.len().max(1)) {
+            let sender = sender.clone();
+            let (next, without_hash) = (&next, &without_hash);
+            scope.spawn(move || loop {
+                let idx = next.fetch_add(1, Ordering::SeqCst);
+                let Some(bundle) = without_hash.get(idx) else {
+                    break;
+                };
+                if sender.send((idx, None)).is_err() {
+                    break;
+                }
+                let sha256 = prefetch_bundle(bundle, expected, config.prefetch_retries);
+                if sender.send((idx, Some(sha256))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        for (idx, sha256) in &receiver {
+            let bundle = &without_hash[idx];
+            let Some(sha256) = sha256 else {
+                progress.start(&bundle.source.to_string());
+                continue;
+            };
+            progress.finish_item();
+            match sha256 {
+                Ok(sha256) => {
+                    // Mismatches are not skipped with --keep-going.
+                    if let Err(e) = verify_checksum(bundle, &sha256, expected) {
+                        failure = Some(e);
+                        break;
+                    }
+                    record(&mut hashes, bundle.packages, &sha256);
+                    unwritten += 1;
+                    if unwritten < WRITE_HASHES_EVERY {
+                        continue;
+                    }
+                    if let Err(e) = crate::crate_hashes::write(config, &hashes, &sources) {
+                        failure = Some(e);
+                        break;
+                    }
+                    unwritten = 0;
+                }
+                Err(e) if config.keep_going => {
+                    progress.clear();
+                    warn!("{:#}", e);
+                }
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        // Lets the remaining workers stop after their current source.
+        drop(receiver);
+    });
+    progress.finish();
+    if let Some(e) = failure {
+        if unwritten > 0 {
+            if let Err(write_error) = crate::crate_hashes::write(config, &hashes, &sources) {
+                warn!("Could not write the prefetched hashes: {:#}", write_error);
+            }
+        }
+        return Err(e);
+    }
+
     if hashes != old_prefetched_hashes {
-        std::fs::write(
-            &config.crate_hashes_json,
-            serde_json::to_vec_pretty(&hashes)?,
-        )
-        .map_err(|e| {
-            format_err!(
-                "while writing hashes to {}: {}",
-                config.crate_hashes_json.to_str().unwrap_or("<unknown>"),
-                e
-            )
-        })?;
-        eprintln!(
+        crate::crate_hashes::write(config, &hashes, &sources)?;
+        info!(
             "Wrote hashes to {}.",
             config.crate_hashes_json.to_string_lossy()
         );
@@ -172,9 +273,338 @@ pub fn prefetch(
     Ok(hashes)
 }
 
+/// Returns the hash of a source from the nix store or by prefetching it.
+fn prefetch_bundle(
+    bundle: &SourcePrefetchBundle,
+    expected: &HashMap<PackageId, String>,
+    retries: u32,
+) -> Result<String, Error> {
+    if let Some(sha256) = find_in_store(bundle.source, bundle.packages, expected) {
+        debug!("Found {} in the nix store.", bundle.source);
+        return Ok(sha256);
+    }
+    with_retries(retries, INITIAL_BACKOFF, &bundle.source.to_string(), || {
+        bundle.source.prefetch()
+    })
+    .with_context(|| {
+        format!(
+            "while prefetching the source of {}",
+            bundle.packages.iter().map(|p| &p.package_id).join(", ")
+        )
+    })
+}
+
+/// Fails if the hash of a registry source differs from the checksum of one of its packages in
+/// `Cargo.lock`.
+fn verify_checksum(
+    bundle: &SourcePrefetchBundle,
+    sha256: &str,
+    expected: &HashMap<PackageId, String>,
+) -> Result<(), Error> {
+    if !matches!(
+        bundle.source,
+        ResolvedSource::CratesIo(_) | ResolvedSource::Registry(_)
+    ) {
+        return Ok(());
+    }
+    for package in bundle.packages {
+        match expected.get(&package.package_id) {
+            Some(checksum) if checksum != sha256 => {
+                return Err(Crate2NixError::ChecksumMismatch {
+                    package_id: (*package.package_id).clone(),
+                    prefetched: sha256.to_string(),
+                    expected: checksum.clone(),
+                }
+                .into())
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_verify_checksum() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("serde")
+        .version_and_package_id("1.0.0");
+    let metadata = env.indexed_metadata();
+    let package = metadata.pkgs_by_id.values().next().unwrap();
+    let crate_derivation = CrateDerivation::unresolved(&metadata, package, String::new());
+    let packages = vec![&crate_derivation];
+    let bundle = SourcePrefetchBundle {
+        source: &crate_derivation.source,
+        packages: &packages,
+        hash: None,
+    };
+    let expected: HashMap<PackageId, String> = vec![(package.id.clone(), "1111".to_string())]
+        .into_iter()
+        .collect();
+
+    verify_checksum(&bundle, "1111", &expected).unwrap();
+    verify_checksum(&bundle, "2222", &HashMap::new()).unwrap();
+
+    let error = verify_checksum(&bundle, "2222", &expected)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains(&format!(
+            "The prefetched source of {} has the hash 2222 but Cargo.lock expects 1111.",
+            package.id
+        )),
+        "{}",
+        error
+    );
+    env.close();
+}
+
+#[test]
+fn test_prefetch_aborts_on_checksum_mismatch() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    env.add_package_and_node("serde")
+        .version_and_package_id("1.0.0");
+    let metadata = env.indexed_metadata();
+    let package = metadata.pkgs_by_id.values().next().unwrap();
+    let crate_derivations = vec![CrateDerivation {
+        problem: None,
+        ..CrateDerivation::unresolved(&metadata, package, String::new())
+    }];
+    let config = GenerateConfig {
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..crate::test::generate_config()
+    };
+    // A hash that was prefetched earlier, e.g. from a tampered mirror.
+    std::fs::write(
+        &config.crate_hashes_json,
+        serde_json::json!({
+            metadata.id_shortener.stable_ref(&package.id).repr.clone(): "2222"
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let expected: HashMap<PackageId, String> = vec![(package.id.clone(), "1111".to_string())]
+        .into_iter()
+        .collect();
+
+    let error = prefetch(
+        &config,
+        &HashMap::new(),
+        &expected,
+        &crate_derivations,
+        &metadata.id_shortener,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains(&package.id.repr), "{}", error);
+    assert!(error.contains("Cargo.lock expects 1111"), "{}", error);
+
+    let hashes = prefetch(
+        &config,
+        &HashMap::new(),
+        &HashMap::new(),
+        &crate_derivations,
+        &metadata.id_shortener,
+    )
+    .unwrap();
+    assert_eq!(hashes.values().collect::<Vec<_>>(), vec!["2222"]);
+    env.close();
+}
+
+#[test]
+fn test_prefetch_removes_unused_hashes() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    env.add_package_and_node("serde")
+        .version_and_package_id("1.0.0");
+    let metadata = env.indexed_metadata();
+    let package = metadata.pkgs_by_id.values().next().unwrap();
+    let crate_derivations = vec![CrateDerivation {
+        problem: None,
+        ..CrateDerivation::unresolved(&metadata, package, String::new())
+    }];
+    let config = GenerateConfig {
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..crate::test::generate_config()
+    };
+    let used = metadata.id_shortener.stable_ref(&package.id).clone();
+    let unused = crate::test::crates_io_package_id("serde", "0.9.0");
+    std::fs::write(
+        &config.crate_hashes_json,
+        serde_json::json!({ used.repr.clone(): "1111", unused.repr.clone(): "2222" }).to_string(),
+    )
+    .unwrap();
+
+    let hashes = prefetch(
+        &config,
+        &HashMap::new(),
+        &HashMap::new(),
+        &crate_derivations,
+        &metadata.id_shortener,
+    )
+    .unwrap();
+    let expected: BTreeMap<PackageId, String> =
+        vec![(used, "1111".to_string())].into_iter().collect();
+    assert_eq!(hashes, expected);
+    assert_eq!(crate::crate_hashes::read(&config).unwrap(), expected);
+
+    // Kept for other lockfiles.
+    let mut previous = expected.clone();
+    previous.insert(unused, "2222".to_string());
+    crate::crate_hashes::write(&config, &previous, &BTreeMap::new()).unwrap();
+    let config = GenerateConfig {
+        keep_unused_hashes: true,
+        ..config
+    };
+    let hashes = prefetch(
+        &config,
+        &HashMap::new(),
+        &HashMap::new(),
+        &crate_derivations,
+        &metadata.id_shortener,
+    )
+    .unwrap();
+    assert_eq!(hashes, previous);
+    env.close();
+}
+
+/// The time to wait before the first retry of a failed prefetch, doubled for every further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// The maximal time to wait between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Calls `f` until it succeeds or failed `retries + 1` times, with exponential backoff.
+fn with_retries<T>(
+    retries: u32,
+    initial_backoff: Duration,
+    what: &str,
+    mut f: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut backoff = initial_backoff;
+    for attempt in 1.. {
+        match f() {
+            Err(e) if attempt <= retries => {
+                warn!(
+                    "Prefetching {} failed (attempt {}/{}), retrying in {}s: {:#}",
+                    what,
+                    attempt,
+                    retries + 1,
+                    backoff.as_secs(),
+                    e
+                );
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            result => return result,
+        }
+    }
+    unreachable!()
+}
+
+#[test]
+fn test_with_retries() {
+    let mut calls = 0;
+    let result = with_retries(2, Duration::ZERO, "a", || {
+        calls += 1;
+        if calls < 3 {
+            bail!("flaky")
+        }
+        Ok(calls)
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    let mut calls = 0;
+    let result: Result<(), Error> = with_retries(1, Duration::ZERO, "a", || {
+        calls += 1;
+        bail!("broken")
+    });
+    assert_eq!(result.unwrap_err().to_string(), "broken");
+    assert_eq!(calls, 2);
+}
+
+lazy_static! {
+    /// Whether `nix` supports `nix store prefetch-file` and `nix flake prefetch`.
+    static ref MODERN_NIX: bool = get_command_output("nix", &["--version"])
+        .map(|version| supports_modern_prefetch(&version))
+        .unwrap_or(false);
+}
+
+/// The arguments that enable the new CLI on installations that did not enable it in `nix.conf`.
+const EXPERIMENTAL_FEATURES: &[&str] = &["--extra-experimental-features", "nix-command flakes"];
+
+/// Returns true for the output of `nix --version` of nix 2.4 and newer.
+fn supports_modern_prefetch(version_output: &str) -> bool {
+    let Some(version) = version_output.split_whitespace().last() else {
+        return false;
+    };
+    let mut parts = version.split('.').map(|p| p.parse::<u64>().ok());
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(major), Some(minor)) => (major, minor) >= (2, 4),
+        _ => false,
+    }
+}
+
+#[test]
+fn test_supports_modern_prefetch() {
+    assert!(supports_modern_prefetch("nix (Nix) 2.18.1"));
+    assert!(supports_modern_prefetch(
+        "nix (Determinate Nix 3.0.0) 2.26.3"
+    ));
+    assert!(!supports_modern_prefetch("nix (Nix) 2.3.16"));
+    assert!(!supports_modern_prefetch(""));
+}
+
+/// The output of `nix store prefetch-file --json` and `nix flake prefetch --json`.
+#[derive(Deserialize)]
+struct NixPrefetchInfo {
+    hash: String,
+}
+
+/// Runs a prefetch command of the new nix CLI and returns the hash in nix base32.
+fn modern_prefetch(args: &[&str]) -> Result<String, Error> {
+    let args: Vec<&str> = EXPERIMENTAL_FEATURES.iter().chain(args).copied().collect();
+    let json = get_command_output("nix", &args)?;
+    let info: NixPrefetchInfo = serde_json::from_str(&json)?;
+    sri_to_nix_base32(&info.hash)
+}
+
+/// Converts an SRI hash like `sha256-<base64>` to the nix base32 format in `Cargo.nix`.
+fn sri_to_nix_base32(sri: &str) -> Result<String, Error> {
+    use base64::Engine as _;
+
+    let encoded = sri
+        .strip_prefix("sha256-")
+        .ok_or_else(|| format_err!("not a sha256 SRI hash: {}", sri))?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| format_err!("invalid base64 in {}: {}", sri, e))?;
+    if bytes.len() != 32 {
+        bail!("unexpected length of sha256 hash {}", sri);
+    }
+    Ok(nix_base32::to_nix_base32(&bytes))
+}
+
+#[test]
+fn test_sri_to_nix_base32() {
+    // The sha256 of the empty string.
+    let bytes =
+        hex::decode("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855").unwrap();
+    assert_eq!(
+        sri_to_nix_base32("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap(),
+        nix_base32::to_nix_base32(&bytes)
+    );
+    // The checksum of anyhow 1.0.86 in Cargo.lock and its sha256 in Cargo.nix.
+    assert_eq!(
+        sri_to_nix_base32("sha256-s9HQRiOJkLnPW83iKj+zWE7lz2X7J2X0VO1CjHoAY9o=").unwrap(),
+        "1nk301x8qhpdaks6a9zvcp7yakjqnczjmqndbg7vk4494d3d1ldk"
+    );
+    assert!(sri_to_nix_base32("sha512-47DEQpj8HBSa").is_err());
+    assert!(sri_to_nix_base32("sha256-47DEQpj8HBSa").is_err());
+    assert!(sri_to_nix_base32("sha256-not base64!").is_err());
+}
+
 fn get_command_output(cmd: &str, args: &[&str]) -> Result<String, Error> {
-    let output = Command::new(cmd)
-        .args(args)
+    let output = crate::proxy::apply(Command::new(cmd).args(args))
         .output()
         .map_err(|e| format_err!("While spawning '{} {}': {}", cmd, args.join(" "), e))?;
 
@@ -193,6 +623,152 @@ fn get_command_output(cmd: &str, args: &[&str]) -> Result<String, Error> {
         .map_err(|_e| format_err!("output of '{} {}' is not UTF8!", cmd, args.join(" ")))
 }
 
+/// Returns the expected hash of the source if the nix store already contains it.
+fn find_in_store(
+    source: &ResolvedSource,
+    packages: &[&CrateDerivation],
+    expected: &HashMap<PackageId, String>,
+) -> Option<String> {
+    find_in_store_with(source, packages, expected, &mut |args| {
+        let output = Command::new("nix-store")
+            .args(args)
+            .output()
+            .map_err(|e| format_err!("While spawning 'nix-store {}': {}", args.join(" "), e))?;
+        if !output.status.success() {
+            bail!(
+                "'nix-store {}' failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    })
+}
+
+/// Like `find_in_store` but with `nix_store` running `nix-store` with the given arguments and
+/// returning its output, or an error if it failed.
+fn find_in_store_with(
+    source: &ResolvedSource,
+    packages: &[&CrateDerivation],
+    expected: &HashMap<PackageId, String>,
+    nix_store: &mut dyn FnMut(&[&str]) -> Result<String, Error>,
+) -> Option<String> {
+    let ResolvedSource::CratesIo(crates_io_source) = source else {
+        return None;
+    };
+    let sha256 = packages.iter().find_map(|p| expected.get(&p.package_id))?;
+    // The names used by the generated `Cargo.nix` and by `nix-prefetch-url`.
+    let names = [
+        format!(
+            "{}-{}.tar.gz",
+            crates_io_source.name, crates_io_source.version
+        ),
+        format!("{}-{}", crates_io_source.name, crates_io_source.version),
+    ];
+    names
+        .iter()
+        .any(|name| {
+            let path = match nix_store(&["--print-fixed-path", "sha256", sha256, name]) {
+                Ok(output) if !output.trim().is_empty() => output.trim().to_string(),
+                Ok(_) => return false,
+                Err(e) => {
+                    debug!("Could not compute the store path of {}: {:#}", name, e);
+                    return false;
+                }
+            };
+            debug!("Checking validity of {}", path);
+            nix_store(&["--check-validity", &path]).is_ok()
+        })
+        .then(|| sha256.clone())
+}
+
+#[test]
+fn test_find_in_store() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("serde")
+        .version_and_package_id("1.0.0");
+    let metadata = env.indexed_metadata();
+    let package = metadata.pkgs_by_id.values().next().unwrap();
+    let crate_derivation = CrateDerivation::unresolved(&metadata, package, String::new());
+    let packages = vec![&crate_derivation];
+    let expected: HashMap<PackageId, String> = vec![(package.id.clone(), "0000sha256".to_string())]
+        .into_iter()
+        .collect();
+
+    // Only the store path of the name that nix-prefetch-url uses is valid.
+    let mut calls = Vec::new();
+    let mut nix_store = |args: &[&str]| -> Result<String, Error> {
+        calls.push(args.join(" "));
+        match args {
+            ["--print-fixed-path", "sha256", sha256, name] => {
+                Ok(format!("/nix/store/{}-{}\n", sha256, name))
+            }
+            ["--check-validity", "/nix/store/0000sha256-serde-1.0.0"] => Ok(String::new()),
+            _ => bail!("path is not valid"),
+        }
+    };
+    assert_eq!(
+        find_in_store_with(
+            &crate_derivation.source,
+            &packages,
+            &expected,
+            &mut nix_store
+        ),
+        Some("0000sha256".to_string())
+    );
+    assert_eq!(
+        calls,
+        vec![
+            "--print-fixed-path sha256 0000sha256 serde-1.0.0.tar.gz",
+            "--check-validity /nix/store/0000sha256-serde-1.0.0.tar.gz",
+            "--print-fixed-path sha256 0000sha256 serde-1.0.0",
+            "--check-validity /nix/store/0000sha256-serde-1.0.0",
+        ]
+    );
+
+    // Missing from the store.
+    let mut nix_store = |args: &[&str]| -> Result<String, Error> {
+        match args {
+            ["--print-fixed-path", ..] => Ok("/nix/store/missing\n".to_string()),
+            _ => bail!("path '/nix/store/missing' is not valid"),
+        }
+    };
+    assert_eq!(
+        find_in_store_with(
+            &crate_derivation.source,
+            &packages,
+            &expected,
+            &mut nix_store
+        ),
+        None
+    );
+
+    // Without nix-store.
+    let mut nix_store = |_: &[&str]| -> Result<String, Error> { bail!("nix-store not found") };
+    assert_eq!(
+        find_in_store_with(
+            &crate_derivation.source,
+            &packages,
+            &expected,
+            &mut nix_store
+        ),
+        None
+    );
+    // Without an expected hash, nix-store is not asked at all.
+    let mut nix_store = |_: &[&str]| -> Result<String, Error> { panic!("not called") };
+    assert_eq!(
+        find_in_store_with(
+            &crate_derivation.source,
+            &packages,
+            &HashMap::new(),
+            &mut nix_store
+        ),
+        None
+    );
+
+    env.close();
+}
+
 /// A crate source that potentially has a prefetchable hash.
 pub trait PrefetchableSource: ToString {
     /// Returns whether we actually need a prefetch. `false` if
@@ -206,6 +782,7 @@ impl ResolvedSource {
     fn inner_prefetchable(&self) -> Option<&dyn PrefetchableSource> {
         match self {
             ResolvedSource::CratesIo(source) => Some(source),
+            ResolvedSource::Registry(source) => Some(source),
             ResolvedSource::Git(source) => Some(source),
             _ => None,
         }
@@ -232,15 +809,41 @@ impl PrefetchableSource for CratesIoSource {
     }
 
     fn prefetch(&self) -> Result<String, Error> {
-        let args = &[
-            &self.url(),
-            "--name",
-            &format!("{}-{}", self.name, self.version),
-        ];
-        get_command_output("nix-prefetch-url", args)
+        prefetch_file(&self.url(), &format!("{}-{}", self.name, self.version))
     }
 }
 
+impl PrefetchableSource for RegistrySource {
+    fn needs_prefetch(&self) -> bool {
+        self.sha256.is_none()
+    }
+
+    fn prefetch(&self) -> Result<String, Error> {
+        if self.url.contains("{sha256-checksum}") {
+            bail!(
+                "the download URL {} needs the checksum from Cargo.lock",
+                self.url
+            );
+        }
+        prefetch_file(&self.url, &format!("{}-{}", self.name, self.version))
+    }
+}
+
+/// Prefetches a single file and returns its hash.
+fn prefetch_file(url: &str, name: &str) -> Result<String, Error> {
+    if *MODERN_NIX {
+        let args = ["store", "prefetch-file", "--json", "--name", name, url];
+        match modern_prefetch(&args) {
+            Ok(sha256) => return Ok(sha256),
+            Err(e) => warn!(
+                "`nix store prefetch-file` failed, falling back to nix-prefetch-url: {:#}",
+                e
+            ),
+        }
+    }
+    get_command_output("nix-prefetch-url", &[url, "--name", name])
+}
+
 impl PrefetchableSource for GitSource {
     fn needs_prefetch(&self) -> bool {
         self.sha256.is_none()
@@ -257,13 +860,10 @@ impl PrefetchableSource for GitSource {
             sha256: String,
         }
 
-        let mut args = vec![
-            "--url",
-            self.url.as_str(),
-            "--fetch-submodules",
-            "--rev",
-            &self.rev,
-        ];
+        let mut args = vec!["--url", self.url.as_str(), "--rev", &self.rev];
+        if self.fetch_submodules {
+            args.push("--fetch-submodules");
+        }
 
         // TODO: --branch-name isn't documented in nix-prefetch-git --help
         // TODO: Consider the case when ref *isn't* a branch. You have to pass
@@ -272,8 +872,34 @@ impl PrefetchableSource for GitSource {
             args.extend_from_slice(&["--branch-name", r#ref]);
         }
 
-        let json = get_command_output("nix-prefetch-git", &args)?;
+        let json = match get_command_output("nix-prefetch-git", &args) {
+            Ok(json) => json,
+            Err(e) if *MODERN_NIX => {
+                warn!(
+                    "nix-prefetch-git failed, falling back to `nix flake prefetch`: {:#}",
+                    e
+                );
+                return self.prefetch_with_flake_command();
+            }
+            Err(e) => return Err(e),
+        };
         let prefetch_info: NixPrefetchGitInfo = serde_json::from_str(&json)?;
         Ok(prefetch_info.sha256)
     }
 }
+
+impl GitSource {
+    /// Prefetches with `nix flake prefetch` which is available without `nix-prefetch-git`,
+    /// e.g. on flakes-only installations.
+    fn prefetch_with_flake_command(&self) -> Result<String, Error> {
+        let mut flake_ref = url::Url::parse(&format!("git+{}", self.url))?;
+        flake_ref.query_pairs_mut().append_pair("rev", &self.rev);
+        if let Some(r#ref) = self.r#ref.as_ref() {
+            flake_ref.query_pairs_mut().append_pair("ref", r#ref);
+        }
+        if self.fetch_submodules {
+            flake_ref.query_pairs_mut().append_pair("submodules", "1");
+        }
+        modern_prefetch(&["flake", "prefetch", "--json", flake_ref.as_str()])
+    }
+}
@@ -0,0 +1,239 @@
+//! Translating the `[profile]` sections of the workspace manifest into rustc flags.
+//!
+//! `buildRustCrate` only distinguishes release and dev builds and ignores the profiles of
+//! `Cargo.toml`. We therefore compute the flags that cargo would pass for `[profile.release]`
+//! and `[profile.dev]`, including `[profile.<name>.package.<crate>]` overrides, for every crate.
+//! Settings that take effect at link time of the whole build, such as `lto`, are not supported.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{format_err, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::resolve::CrateDerivation;
+
+/// The rustc flags of a crate for the profiles that `Cargo.nix` builds with.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProfileFlags {
+    /// Passed with `release = true`, the default.
+    pub release: Vec<String>,
+    /// Passed with `release = false`.
+    pub dev: Vec<String>,
+}
+
+/// The `[profile]` section of a workspace manifest.
+#[derive(Debug, Default, Deserialize)]
+pub struct Profiles {
+    release: Option<Profile>,
+    dev: Option<Profile>,
+}
+
+/// A profile or a package override of a profile.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Profile {
+    opt_level: Option<toml::Value>,
+    debug: Option<toml::Value>,
+    debug_assertions: Option<bool>,
+    overflow_checks: Option<bool>,
+    codegen_units: Option<u32>,
+    /// Only allowed in the profile itself, not in package overrides.
+    panic: Option<String>,
+    #[serde(default)]
+    package: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    profile: Profiles,
+}
+
+impl Profiles {
+    /// Reads the profiles of the workspace manifest at `manifest_path`.
+    pub fn read(manifest_path: &Path) -> Result<Profiles, Error> {
+        if !manifest_path.exists() {
+            // Synthetic metadata, e.g. in tests.
+            return Ok(Profiles::default());
+        }
+        let content = std::fs::read_to_string(manifest_path)
+            .map_err(|e| format_err!("while reading {}: {}", manifest_path.display(), e))?;
+        let manifest: Manifest = toml::from_str(&content).map_err(|e| {
+            format_err!(
+                "while parsing the profiles in {}: {}",
+                manifest_path.display(),
+                e
+            )
+        })?;
+        Ok(manifest.profile)
+    }
+
+    /// Returns whether no release or dev profile is configured.
+    pub fn is_empty(&self) -> bool {
+        self.release.is_none() && self.dev.is_none()
+    }
+
+    /// Sets the `profile_flags` of all crates.
+    pub fn apply(&self, crates: &mut [CrateDerivation]) {
+        for crate_derivation in crates {
+            crate_derivation.profile_flags = self.flags(
+                &crate_derivation.crate_name,
+                crate_derivation.is_root_or_workspace_member,
+                crate_derivation.proc_macro,
+            );
+        }
+    }
+
+    fn flags(&self, crate_name: &str, is_member: bool, proc_macro: bool) -> ProfileFlags {
+        let flags = |profile: &Option<Profile>| {
+            profile.as_ref().map_or_else(Vec::new, |profile| {
+                profile
+                    .for_package(crate_name, is_member)
+                    .rustc_flags(crate_name, proc_macro)
+            })
+        };
+        ProfileFlags {
+            release: flags(&self.release),
+            dev: flags(&self.dev),
+        }
+    }
+}
+
+impl Profile {
+    /// Applies the overrides for `crate_name` like cargo: a named package takes precedence over
+    /// `"*"`, which only applies to crates outside of the workspace.
+    fn for_package(&self, crate_name: &str, is_member: bool) -> Profile {
+        let mut profile = Profile {
+            package: BTreeMap::new(),
+            ..self.clone()
+        };
+        let all = self.package.get("*").filter(|_| !is_member);
+        for package in all.into_iter().chain(self.package.get(crate_name)) {
+            profile.opt_level = package.opt_level.clone().or(profile.opt_level);
+            profile.debug = package.debug.clone().or(profile.debug);
+            profile.debug_assertions = package.debug_assertions.or(profile.debug_assertions);
+            profile.overflow_checks = package.overflow_checks.or(profile.overflow_checks);
+            profile.codegen_units = package.codegen_units.or(profile.codegen_units);
+        }
+        profile
+    }
+
+    fn rustc_flags(&self, crate_name: &str, proc_macro: bool) -> Vec<String> {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        let mut flags = Vec::new();
+        if let Some(opt_level) = &self.opt_level {
+            match opt_level {
+                toml::Value::Integer(level) => flags.push(format!("-C opt-level={}", level)),
+                toml::Value::String(level) => flags.push(format!("-C opt-level={}", level)),
+                other => warn!("Ignoring opt-level {} for {}.", other, crate_name),
+            }
+        }
+        if let Some(debug) = &self.debug {
+            match debuginfo(debug) {
+                Some(level) => flags.push(format!("-C debuginfo={}", level)),
+                None => warn!("Ignoring debug = {} for {}.", debug, crate_name),
+            }
+        }
+        if let Some(enabled) = self.debug_assertions {
+            flags.push(format!("-C debug-assertions={}", on_off(enabled)));
+        }
+        if let Some(enabled) = self.overflow_checks {
+            flags.push(format!("-C overflow-checks={}", on_off(enabled)));
+        }
+        if let Some(units) = self.codegen_units {
+            flags.push(format!("-C codegen-units={}", units));
+        }
+        // Proc macros are loaded by rustc and always unwind, just like with cargo.
+        if self.panic.as_deref() == Some("abort") && !proc_macro {
+            flags.push("-C panic=abort".to_string());
+        }
+        flags
+    }
+}
+
+/// The value of `-C debuginfo` for the `debug` setting of a profile.
+fn debuginfo(debug: &toml::Value) -> Option<String> {
+    let level = match debug {
+        toml::Value::Boolean(true) => "2",
+        toml::Value::Boolean(false) => "0",
+        toml::Value::Integer(level @ 0..=2) => return Some(level.to_string()),
+        toml::Value::String(level) => match level.as_str() {
+            "none" => "0",
+            "limited" => "1",
+            "full" => "2",
+            "line-tables-only" | "line-directives-only" => level,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(level.to_string())
+}
+
+#[test]
+fn test_profile_flags() {
+    let manifest: Manifest = toml::from_str(
+        r#"
+        [profile.release]
+        panic = "abort"
+        opt-level = "z"
+        debug = "line-tables-only"
+
+        [profile.release.package."*"]
+        opt-level = 3
+
+        [profile.release.package.my_member]
+        overflow-checks = true
+        codegen-units = 1
+
+        [profile.dev.package.image]
+        opt-level = 2
+        "#,
+    )
+    .unwrap();
+    let profiles = manifest.profile;
+
+    assert_eq!(
+        profiles.flags("my_member", true, false),
+        ProfileFlags {
+            release: vec![
+                "-C opt-level=z".to_string(),
+                "-C debuginfo=line-tables-only".to_string(),
+                "-C overflow-checks=on".to_string(),
+                "-C codegen-units=1".to_string(),
+                "-C panic=abort".to_string(),
+            ],
+            dev: vec![],
+        }
+    );
+    assert_eq!(
+        profiles.flags("image", false, false),
+        ProfileFlags {
+            release: vec![
+                "-C opt-level=3".to_string(),
+                "-C debuginfo=line-tables-only".to_string(),
+                "-C panic=abort".to_string(),
+            ],
+            dev: vec!["-C opt-level=2".to_string()],
+        }
+    );
+    assert_eq!(
+        profiles.flags("serde_derive", false, true).release,
+        vec![
+            "-C opt-level=3".to_string(),
+            "-C debuginfo=line-tables-only".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_debuginfo() {
+    assert_eq!(debuginfo(&toml::Value::Boolean(true)).as_deref(), Some("2"));
+    assert_eq!(debuginfo(&toml::Value::Integer(1)).as_deref(), Some("1"));
+    assert_eq!(
+        debuginfo(&toml::Value::String("none".to_string())).as_deref(),
+        Some("0")
+    );
+    assert_eq!(debuginfo(&toml::Value::Integer(3)), None);
+}
@@ -0,0 +1,108 @@
+//! Progress reporting for long-running steps such as prefetching.
+//!
+//! On a terminal, a single status line with the number of finished items, the current item and
+//! the estimated remaining time is redrawn in place. Otherwise, e.g. in CI logs, every item is
+//! logged on its own line. Nothing is shown with `--quiet`.
+
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use log::{info, log_enabled, Level};
+
+/// Reports the progress of processing a known number of items.
+#[derive(Debug)]
+pub struct Progress {
+    action: &'static str,
+    total: usize,
+    done: usize,
+    started: Instant,
+    status_line: bool,
+}
+
+impl Progress {
+    /// Starts reporting progress for `total` items, e.g. "Prefetching".
+    pub fn new(action: &'static str, total: usize) -> Progress {
+        Progress {
+            action,
+            total,
+            done: 0,
+            started: Instant::now(),
+            status_line: log_enabled!(Level::Info) && std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Reports that processing `item` started.
+    pub fn start(&self, item: &str) {
+        let counter = format!("{:>4}/{}", self.done + 1, self.total);
+        if !self.status_line {
+            info!("{} {}: {}", self.action, counter, item);
+            return;
+        }
+        let eta = estimate_remaining(self.started.elapsed(), self.done, self.total)
+            .map(|eta| format!(", {} left", format_duration(eta)))
+            .unwrap_or_default();
+        eprint!("\r\x1b[K{} {}{}: {}", self.action, counter, eta, item);
+        std::io::stderr().flush().ok();
+    }
+
+    /// Reports that the current item is done.
+    pub fn finish_item(&mut self) {
+        self.done += 1;
+    }
+
+    /// Removes the status line so that other output starts on a clean line.
+    pub fn clear(&self) {
+        if self.status_line {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    /// Removes the status line and logs a summary.
+    pub fn finish(self) {
+        self.clear();
+        if self.done > 0 {
+            info!(
+                "{} {} item(s) took {}.",
+                self.action,
+                self.done,
+                format_duration(self.started.elapsed())
+            );
+        }
+    }
+}
+
+/// Extrapolates the remaining time from the average time per finished item.
+fn estimate_remaining(elapsed: Duration, done: usize, total: usize) -> Option<Duration> {
+    if done == 0 || done >= total {
+        return None;
+    }
+    Some(elapsed / done as u32 * (total - done) as u32)
+}
+
+/// Formats a duration like "1h 02m", "3m 07s" or "12s".
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
+
+#[test]
+fn test_estimate_remaining() {
+    let elapsed = Duration::from_secs(30);
+    assert_eq!(estimate_remaining(elapsed, 0, 10), None);
+    assert_eq!(
+        estimate_remaining(elapsed, 3, 10),
+        Some(Duration::from_secs(70))
+    );
+    assert_eq!(estimate_remaining(elapsed, 10, 10), None);
+}
+
+#[test]
+fn test_format_duration() {
+    assert_eq!(format_duration(Duration::from_millis(12_400)), "12s");
+    assert_eq!(format_duration(Duration::from_secs(187)), "3m 07s");
+    assert_eq!(format_duration(Duration::from_secs(3720)), "1h 02m");
+}
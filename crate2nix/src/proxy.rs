@@ -0,0 +1,86 @@
+//! Proxy settings for the helpers that download sources, e.g. `nix-prefetch-url` and
+//! `nix-prefetch-git`.
+//!
+//! The helpers inherit our environment but do not agree on the variable names: curl ignores
+//! `HTTP_PROXY` and only knows `http_proxy`, while other tools only look at the upper case
+//! variants. We therefore pass every proxy variable in both spellings. A proxy configured with
+//! `--proxy` takes precedence over `HTTP_PROXY` and `HTTPS_PROXY`.
+
+use std::process::Command;
+use std::sync::Mutex;
+
+/// The proxy variables that the helpers understand, in lower case.
+const PROXY_VARIABLES: &[&str] = &["http_proxy", "https_proxy", "all_proxy", "no_proxy"];
+
+/// The proxy passed with `--proxy`.
+static CONFIGURED_PROXY: Mutex<Option<String>> = Mutex::new(None);
+
+/// Sets the proxy for all following downloads, `None` to only use the environment.
+pub fn set_configured_proxy(proxy: Option<String>) {
+    *CONFIGURED_PROXY.lock().expect("proxy lock") = proxy;
+}
+
+/// Passes the proxy settings to the given download helper.
+pub fn apply(command: &mut Command) -> &mut Command {
+    let configured = CONFIGURED_PROXY.lock().expect("proxy lock").clone();
+    let variables = proxy_variables(configured.as_deref(), |name| std::env::var(name).ok());
+    command.envs(variables)
+}
+
+/// Returns the proxy variables in lower and upper case.
+fn proxy_variables(
+    configured: Option<&str>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Vec<(String, String)> {
+    let mut variables = Vec::new();
+    for name in PROXY_VARIABLES {
+        let upper = name.to_uppercase();
+        let value = match configured {
+            Some(proxy) if *name == "http_proxy" || *name == "https_proxy" => {
+                Some(proxy.to_string())
+            }
+            _ => env(name).or_else(|| env(&upper)),
+        };
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            variables.push((name.to_string(), value.clone()));
+            variables.push((upper, value));
+        }
+    }
+    variables
+}
+
+#[test]
+fn test_proxy_variables() {
+    let env = |name: &str| match name {
+        "HTTPS_PROXY" => Some("http://env-proxy:3128".to_string()),
+        "no_proxy" => Some("localhost,.internal".to_string()),
+        _ => None,
+    };
+    assert_eq!(
+        proxy_variables(None, env),
+        vec![
+            (
+                "https_proxy".to_string(),
+                "http://env-proxy:3128".to_string()
+            ),
+            (
+                "HTTPS_PROXY".to_string(),
+                "http://env-proxy:3128".to_string()
+            ),
+            ("no_proxy".to_string(), "localhost,.internal".to_string()),
+            ("NO_PROXY".to_string(), "localhost,.internal".to_string()),
+        ]
+    );
+
+    let variables = proxy_variables(Some("http://configured:8080"), env);
+    assert!(variables.contains(&(
+        "HTTP_PROXY".to_string(),
+        "http://configured:8080".to_string()
+    )));
+    assert!(variables.contains(&(
+        "https_proxy".to_string(),
+        "http://configured:8080".to_string()
+    )));
+    assert!(variables.contains(&("NO_PROXY".to_string(), "localhost,.internal".to_string())));
+    assert!(proxy_variables(None, |_| None).is_empty());
+}
@@ -0,0 +1,92 @@
+//! Workspace members that are Python extension modules built with PyO3.
+//!
+//! Such a member is a `cdylib` that depends on `pyo3`. Python imports it by the name of its
+//! `#[pymodule]`, which is the library name unless maturin is told otherwise by
+//! `[tool.maturin] module-name` in the `pyproject.toml` next to `Cargo.toml`.
+
+use std::path::Path;
+
+use anyhow::{format_err, Error};
+use serde::Deserialize;
+
+use crate::resolve::CrateDerivation;
+
+#[derive(Debug, Default, Deserialize)]
+struct PyProject {
+    #[serde(default)]
+    tool: Tool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Tool {
+    #[serde(default)]
+    maturin: Maturin,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Maturin {
+    #[serde(rename = "module-name")]
+    module_name: Option<String>,
+}
+
+/// Returns the dotted module name, e.g. `my_package._native`, if `crate_derivation` is a Python
+/// extension module whose manifest is `manifest_path`.
+pub fn module_name(
+    crate_derivation: &CrateDerivation,
+    manifest_path: &Path,
+) -> Result<Option<String>, Error> {
+    let is_extension_module = crate_derivation
+        .lib_crate_types
+        .iter()
+        .any(|t| t == "cdylib")
+        && crate_derivation
+            .dependencies
+            .iter()
+            .any(|dep| &*dep.name == "pyo3");
+    if !is_extension_module {
+        return Ok(None);
+    }
+    let Some(lib) = &crate_derivation.lib else {
+        return Ok(None);
+    };
+
+    let pyproject = manifest_path.with_file_name("pyproject.toml");
+    let module_name = if pyproject.is_file() {
+        let content = std::fs::read_to_string(&pyproject)
+            .map_err(|e| format_err!("while reading {}: {}", pyproject.display(), e))?;
+        parse_module_name(&content)
+            .map_err(|e| format_err!("while parsing {}: {}", pyproject.display(), e))?
+    } else {
+        None
+    };
+    Ok(Some(module_name.unwrap_or_else(|| lib.name.clone())))
+}
+
+fn parse_module_name(pyproject: &str) -> Result<Option<String>, Error> {
+    let pyproject: PyProject = toml::from_str(pyproject)?;
+    Ok(pyproject.tool.maturin.module_name)
+}
+
+#[test]
+fn test_parse_module_name() {
+    assert_eq!(
+        parse_module_name(
+            r#"
+            [project]
+            name = "my-package"
+
+            [tool.maturin]
+            module-name = "my_package._native"
+            features = ["pyo3/extension-module"]
+            "#
+        )
+        .unwrap()
+        .as_deref(),
+        Some("my_package._native")
+    );
+    assert_eq!(
+        parse_module_name("[build-system]\nrequires = [\"maturin\"]\n").unwrap(),
+        None
+    );
+    assert!(parse_module_name("[tool.maturin]\nmodule-name = 1\n").is_err());
+}
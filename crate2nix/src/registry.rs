@@ -0,0 +1,101 @@
+//! Download URL templates of registries other than crates.io.
+//!
+//! Every registry index contains a `config.json` whose `dl` field tells cargo where to download
+//! crates from, see https://doc.rust-lang.org/cargo/reference/registry-index.html#index-configuration.
+//! We read it once per registry and expand it for every crate of that registry.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::Mutex;
+
+use anyhow::{bail, format_err, Context, Error};
+use log::debug;
+use serde::Deserialize;
+
+/// The `dl` templates by source ID, e.g. `sparse+https://my.registry/index/`.
+static DOWNLOAD_TEMPLATES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// The relevant part of a registry's `config.json`.
+#[derive(Debug, Deserialize)]
+struct RegistryConfig {
+    dl: String,
+}
+
+/// Returns the `dl` template of the registry with the given source ID.
+pub fn download_template(source_id: &str) -> Result<String, Error> {
+    if let Some(template) = DOWNLOAD_TEMPLATES
+        .lock()
+        .expect("registry lock")
+        .get(source_id)
+    {
+        return Ok(template.clone());
+    }
+    let config_json = read_config_json(source_id)
+        .with_context(|| format!("while reading config.json of the registry {}", source_id))?;
+    let config: RegistryConfig = serde_json::from_str(&config_json)
+        .map_err(|e| format_err!("while parsing config.json of {}: {}", source_id, e))?;
+    debug!("Registry {} downloads from {}.", source_id, config.dl);
+    DOWNLOAD_TEMPLATES
+        .lock()
+        .expect("registry lock")
+        .insert(source_id.to_string(), config.dl.clone());
+    Ok(config.dl)
+}
+
+fn read_config_json(source_id: &str) -> Result<String, Error> {
+    if let Some(url) = sparse_config_url(source_id) {
+        let output = crate::proxy::apply(Command::new("curl").args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--location",
+            &url,
+        ]))
+        .output()
+        .map_err(|e| format_err!("while spawning curl for {}: {}", url, e))?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        return Ok(String::from_utf8(output.stdout)?);
+    }
+
+    let Some(url) = source_id.strip_prefix("registry+") else {
+        bail!("unknown kind of registry");
+    };
+    // Git indices are only needed for their config.json, so a shallow clone is enough.
+    let checkout = std::env::temp_dir().join(format!(
+        "crate2nix-registry-{}-{}",
+        std::process::id(),
+        DOWNLOAD_TEMPLATES.lock().expect("registry lock").len()
+    ));
+    let result = crate::command::run(
+        &format!("Cloning the index of {}", url),
+        crate::proxy::apply(
+            Command::new("git")
+                .args(["clone", "--quiet", "--depth", "1", url])
+                .arg(&checkout),
+        ),
+    )
+    .and_then(|()| Ok(std::fs::read_to_string(checkout.join("config.json"))?));
+    std::fs::remove_dir_all(&checkout).ok();
+    result
+}
+
+/// The URL of `config.json` of a sparse registry.
+fn sparse_config_url(source_id: &str) -> Option<String> {
+    let url = source_id.strip_prefix("sparse+")?;
+    Some(format!("{}/config.json", url.trim_end_matches('/')))
+}
+
+#[test]
+fn test_sparse_config_url() {
+    assert_eq!(
+        sparse_config_url("sparse+https://my.registry/index/").as_deref(),
+        Some("https://my.registry/index/config.json")
+    );
+    assert_eq!(
+        sparse_config_url("sparse+https://my.registry/index").as_deref(),
+        Some("https://my.registry/index/config.json")
+    );
+    assert_eq!(sparse_config_url("registry+https://my.registry/git"), None);
+}
@@ -1,17 +1,26 @@
 //! "Render" files using tera templates.
 
-use std::io::{BufRead, Write};
-use std::path::Path;
-use std::{fs::File, iter};
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{borrow::Cow, fs::File, iter};
 
-use crate::{BuildInfo, GenerateInfo};
+use crate::error::Crate2NixError;
+use crate::incremental::RenderedCrates;
+use crate::resolve::{CrateDerivation, ResolvedSource, SourceProvenance};
+use crate::rust_toolchain::RustToolchain;
+use crate::vendor::SourceReplacements;
+use crate::{BuildInfo, GenerateConfig, GenerateInfo};
 use anyhow::format_err;
+use anyhow::Context as _;
 use anyhow::{bail, Error};
+use cargo_metadata::PackageId;
 use cargo_platform::{Cfg, CfgExpr};
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use log::{debug, info, warn};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::{fmt::Debug, marker::PhantomData, str::FromStr};
 use tera::{Context, Tera};
 
@@ -26,12 +35,187 @@ macro_rules! template {
     };
 }
 
-/// The template for generating Cargo.nix.
-pub const CARGO_NIX: Template<BuildInfo> = template!("Cargo.nix.tera");
+/// The template for generating Cargo.nix, without the crates, see `write_cargo_nix`.
+pub const CARGO_NIX: Template<CargoNixSkeleton> = template!("Cargo.nix.tera");
+
+/// Context argument for the `CARGO_NIX` template: the `BuildInfo` without the crates and the
+/// metadata, which would otherwise be copied into the template context as a whole.
+#[derive(Debug, Serialize)]
+pub struct CargoNixSkeleton {
+    root_package_id: Option<PackageId>,
+    workspace_members: BTreeMap<String, PackageId>,
+    workspace_member_aliases: BTreeMap<String, String>,
+    info: GenerateInfo,
+    config: GenerateConfig,
+    shared_sources: BTreeMap<String, ResolvedSource>,
+    shards: BTreeMap<PackageId, PathBuf>,
+    source_root: Option<PathBuf>,
+    workspace_root: Option<PathBuf>,
+    member_binaries: BTreeMap<String, Vec<String>>,
+    c_library_members: Vec<String>,
+    python_modules: BTreeMap<String, String>,
+    feature_resolver: String,
+    default_workspace_members: Option<Vec<String>>,
+    default_run: Option<String>,
+    rust_toolchain: Option<RustToolchain>,
+    crate_overrides_file: Option<String>,
+    source_replacements: SourceReplacements,
+    imported_cargo_nix: BTreeMap<String, String>,
+    no_std_target: Option<String>,
+    /// The file name of the JSON file with the crates if `GenerateConfig::crates_json` is set.
+    crates_json: Option<String>,
+    /// The lists that the crates share if `GenerateConfig::share_lists` is set.
+    shared_lists: Vec<Vec<Arc<str>>>,
+}
+
+impl CargoNixSkeleton {
+    /// Copies everything but the crates and the metadata of `build_info`.
+    pub fn of(build_info: &BuildInfo) -> CargoNixSkeleton {
+        // No `..`, so that new fields of `BuildInfo` are not forgotten here.
+        let BuildInfo {
+            root_package_id,
+            workspace_members,
+            workspace_member_aliases,
+            crates: _,
+            indexed_metadata: _,
+            info,
+            config,
+            warnings: _,
+            shared_sources,
+            shards,
+            source_root,
+            workspace_root,
+            member_binaries,
+            c_library_members,
+            python_modules,
+            feature_resolver,
+            default_workspace_members,
+            default_run,
+            rust_toolchain,
+            crate_overrides_file,
+            source_replacements,
+            imported_cargo_nix,
+            no_std_target,
+        } = build_info;
+        CargoNixSkeleton {
+            root_package_id: root_package_id.clone(),
+            workspace_members: workspace_members.clone(),
+            workspace_member_aliases: workspace_member_aliases.clone(),
+            info: info.clone(),
+            config: config.clone(),
+            shared_sources: shared_sources.clone(),
+            shards: shards.clone(),
+            source_root: source_root.clone(),
+            workspace_root: workspace_root.clone(),
+            member_binaries: member_binaries.clone(),
+            c_library_members: c_library_members.clone(),
+            python_modules: python_modules.clone(),
+            feature_resolver: feature_resolver.clone(),
+            default_workspace_members: default_workspace_members.clone(),
+            default_run: default_run.clone(),
+            rust_toolchain: rust_toolchain.clone(),
+            crate_overrides_file: crate_overrides_file.clone(),
+            source_replacements: source_replacements.clone(),
+            imported_cargo_nix: imported_cargo_nix.clone(),
+            no_std_target: no_std_target.clone(),
+            crates_json: config
+                .crates_json
+                .then(|| config.crates_json_file().to_string_lossy().to_string()),
+            shared_lists: if config.share_lists {
+                crate::shared_lists::of(nix_crates(build_info))
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+/// The line of the rendered `CARGO_NIX` template at which the crates are inserted.
+const CRATES_MARKER: &str = "\n    # crate2nix:crates";
+
+/// An attribute of the `crates` in `Cargo.nix`, rendered on its own by `write_cargo_nix`.
+const CRATE_ENTRY_NIX: Template<CrateEntry> = template!("crate-entry.nix.tera");
+
+/// Context argument for the `CRATE_ENTRY_NIX` template.
+#[derive(Debug, Serialize)]
+struct CrateEntry<'a> {
+    #[serde(rename = "crate")]
+    crate_derivation: &'a CrateDerivation,
+    provenance: SourceProvenance,
+}
 
 /// Included in build.nix.tera
 const DEFAULT_NIX: Template<()> = template!("nix/crate2nix/default.nix");
 
+/// The attribute set of a single crate, included in `CARGO_NIX` and `CRATE_SHARD_NIX`.
+const CRATE: Template<()> = template!("crate.tera");
+
+/// The template for the file of a single crate if the output is sharded.
+pub const CRATE_SHARD_NIX: Template<CrateShard> = template!("crate-shard.nix.tera");
+
+/// Context argument for the `CRATE_SHARD_NIX` template.
+#[derive(Debug, Serialize)]
+pub struct CrateShard {
+    /// The generate info for this invocation.
+    pub info: GenerateInfo,
+    /// The crate to render.
+    #[serde(rename = "crate")]
+    pub crate_derivation: CrateDerivation,
+    /// Where the source of the crate comes from, with the paths relative to `Cargo.nix`.
+    pub provenance: SourceProvenance,
+    /// The directory of `Cargo.nix` as a nix path relative to the crate file.
+    pub root_directory: String,
+}
+
+/// The template for the nixpkgs overlay next to `Cargo.nix`, see `GenerateConfig::overlay`.
+pub const OVERLAY_NIX: Template<OverlayNix> = template!("overlay.nix.tera");
+
+/// Context argument for the `OVERLAY_NIX` template.
+#[derive(Debug, Serialize)]
+pub struct OverlayNix {
+    /// The generate info for this invocation.
+    pub info: GenerateInfo,
+    /// The file name of the generated `Cargo.nix`.
+    pub cargo_nix: String,
+    /// The names of the workspace members with binaries.
+    pub members: Vec<String>,
+}
+
+/// The template for the Hydra jobset next to `Cargo.nix`, see `GenerateConfig::release_nix`.
+pub const RELEASE_NIX: Template<ReleaseNix> = template!("release.nix.tera");
+
+/// The template for the `default.nix` that `crate2nix init` writes.
+pub const INIT_DEFAULT_NIX: Template<InitNix> = template!("init-default.nix.tera");
+
+/// The template for the `flake.nix` that `crate2nix init --flake` writes.
+pub const INIT_FLAKE_NIX: Template<InitNix> = template!("init-flake.nix.tera");
+
+/// Context argument for the `INIT_DEFAULT_NIX` and `INIT_FLAKE_NIX` templates.
+#[derive(Debug, Serialize)]
+pub struct InitNix {
+    /// The name of the project, the root package or the workspace directory.
+    pub project: String,
+    /// The file name of the `Cargo.nix` to generate.
+    pub cargo_nix: String,
+    /// The names of all workspace members.
+    pub members: Vec<String>,
+    /// The workspace member to build by default, if there is an obvious one.
+    pub default_member: Option<String>,
+}
+
+/// Context argument for the `RELEASE_NIX` template.
+#[derive(Debug, Serialize)]
+pub struct ReleaseNix {
+    /// The generate info for this invocation.
+    pub info: GenerateInfo,
+    /// The file name of the generated `Cargo.nix`.
+    pub cargo_nix: String,
+    /// The names of all workspace members.
+    pub members: Vec<String>,
+    /// The default nix systems to build the jobs for.
+    pub systems: Vec<String>,
+}
+
 /// The template for generating a directory with all out of tree sources.
 pub const SOURCES_NIX: Template<GenerateInfo> = template!("crate2nix-sources.nix.tera");
 
@@ -64,7 +248,12 @@ pub struct Template<C: Serialize + Debug> {
 impl<C: Serialize + Debug> Template<C> {
     /// Returns the rendered template as a string.
     pub fn render(&self, context: &C) -> Result<String, Error> {
-        let rendered = TERA
+        self.render_with(&TERA, context)
+    }
+
+    /// Returns the template rendered by `tera` as a string, see `crate_tera`.
+    fn render_with(&self, tera: &Tera, context: &C) -> Result<String, Error> {
+        let rendered = tera
             .render(self.template, &Context::from_serialize(context)?)
             .map_err(|e| {
                 format_err!(
@@ -74,18 +263,29 @@ impl<C: Serialize + Debug> Template<C> {
                     context
                 )
             })?;
-        Ok(rendered
+        let rendered = rendered
             .lines()
             .map(|l| l.trim_end())
             .chain(iter::once("\n"))
-            .join("\n"))
+            .join("\n");
+        if self.template.ends_with(".nix.tera") {
+            validate_nix(self.template, &rendered, &template_source)?;
+        }
+        Ok(rendered)
     }
 
     /// Writes the rendered template to the given file path.
+    ///
+    /// The user regions of an existing file are kept, see `keep_user_regions`.
     pub fn write_to_file(&self, path: impl AsRef<Path>, context: &C) -> Result<(), Error> {
+        let mut rendered = self.render(context)?;
+        if let Ok(existing) = std::fs::read_to_string(&path) {
+            rendered = keep_user_regions(&existing, &rendered)
+                .with_context(|| format!("while updating {}", path.as_ref().to_string_lossy()))?;
+        }
         let mut output_file = File::create(&path)?;
-        output_file.write_all(self.render(context)?.as_bytes())?;
-        println!(
+        output_file.write_all(rendered.as_bytes())?;
+        info!(
             "Generated {} successfully.",
             path.as_ref().to_string_lossy()
         );
@@ -93,6 +293,950 @@ impl<C: Serialize + Debug> Template<C> {
     }
 }
 
+/// Writes the `Cargo.nix` for `build_info` to its output.
+///
+/// The crates are rendered and written one by one, so that the whole file is never in memory
+/// and an error names the crate that could not be rendered. The output is written to a
+/// temporary file first, an existing `Cargo.nix` is only replaced if everything succeeded.
+pub fn write_cargo_nix(build_info: &BuildInfo) -> Result<(), Error> {
+    write_cargo_nix_reusing(build_info, None)
+}
+
+/// Writes the `Cargo.nix` like `write_cargo_nix`, with the crates that did not change since the
+/// last generation taken from `rendered`.
+fn write_cargo_nix_reusing(
+    build_info: &BuildInfo,
+    rendered: Option<&mut RenderedCrates>,
+) -> Result<(), Error> {
+    let path = &build_info.config.output;
+    let skeleton_context = CargoNixSkeleton::of(build_info);
+    let tera = crate_tera(&skeleton_context.shared_lists);
+    let shared = serde_json::to_vec(&skeleton_context.shared_lists)?;
+    let mut skeleton = CARGO_NIX.render(&skeleton_context)?;
+    if let Ok(existing) = std::fs::read_to_string(path) {
+        skeleton = keep_user_regions(&existing, &skeleton)
+            .with_context(|| format!("while updating {}", path.to_string_lossy()))?;
+    }
+
+    let temp_path = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name()
+            .ok_or_else(|| format_err!("no file name in {}", path.to_string_lossy()))?
+            .to_string_lossy()
+    ));
+    let write = || -> Result<(), Error> {
+        let mut output = BufWriter::new(File::create(&temp_path)?);
+        write_crates_into_skeleton(
+            &tera,
+            &skeleton,
+            nix_crates(build_info),
+            rendered.map(|rendered| (rendered, shared.as_slice())),
+            &mut output,
+        )?;
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    };
+    write()
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp_path);
+        })
+        .with_context(|| format!("while writing {}", path.to_string_lossy()))?;
+    info!("Generated {} successfully.", path.to_string_lossy());
+    Ok(())
+}
+
+/// Renders the whole `Cargo.nix` for `build_info` into a string, e.g. to compare it with the
+/// existing file.
+pub fn render_cargo_nix(build_info: &BuildInfo) -> Result<String, Error> {
+    let skeleton_context = CargoNixSkeleton::of(build_info);
+    let tera = crate_tera(&skeleton_context.shared_lists);
+    let skeleton = CARGO_NIX.render(&skeleton_context)?;
+    let mut rendered = Vec::new();
+    write_crates_into_skeleton(
+        &tera,
+        &skeleton,
+        nix_crates(build_info),
+        None,
+        &mut rendered,
+    )?;
+    Ok(String::from_utf8(rendered)?)
+}
+
+/// The crates to define in `Cargo.nix` itself: all but the ones read from the JSON file with
+/// `GenerateConfig::crates_json`.
+fn nix_crates(build_info: &BuildInfo) -> impl Iterator<Item = &CrateDerivation> {
+    build_info
+        .crates
+        .iter()
+        .filter(|c| !build_info.config.crates_json || crate::crates_json::needs_nix(c))
+}
+
+/// Writes the rendered `CARGO_NIX` template with the crates inserted at the marker.
+///
+/// A sharded skeleton has no marker, its crates are in their own files. With `rendered`, the
+/// crates are taken from the last generation if they did not change, the bytes stand for what
+/// the `tera` of the crates adds to their context.
+fn write_crates_into_skeleton<'a>(
+    tera: &Tera,
+    skeleton: &str,
+    crates: impl IntoIterator<Item = &'a CrateDerivation>,
+    mut rendered: Option<(&mut RenderedCrates, &[u8])>,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let Some((header, footer)) = skeleton.split_once(CRATES_MARKER) else {
+        output.write_all(skeleton.as_bytes())?;
+        return Ok(());
+    };
+    output.write_all(header.as_bytes())?;
+    for crate_derivation in crates {
+        let context = CrateEntry {
+            crate_derivation,
+            provenance: crate_derivation.source.provenance(),
+        };
+        let render = || -> Result<String, Error> {
+            let entry = CRATE_ENTRY_NIX
+                .render_with(tera, &context)
+                .with_context(|| {
+                    format!(
+                        "while rendering crate {} with {}",
+                        crate_derivation.package_id, CRATE_ENTRY_NIX.template
+                    )
+                })?;
+            let entry = entry
+                .trim()
+                .strip_prefix('{')
+                .and_then(|entry| entry.strip_suffix('}'))
+                .ok_or_else(|| format_err!("unexpected braces in {}", entry))?;
+            Ok(entry.trim_matches('\n').trim_end().to_string())
+        };
+        let rendered_entry;
+        let entry = match &mut rendered {
+            Some((rendered, shared)) => {
+                rendered.render(CRATE_ENTRY_NIX.template, shared, &context, render)?
+            }
+            None => {
+                rendered_entry = render()?;
+                &rendered_entry
+            }
+        };
+        output.write_all(b"\n")?;
+        output.write_all(entry.as_bytes())?;
+    }
+    output.write_all(footer.as_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn test_write_cargo_nix() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    env.add_package_and_node("main").make_root();
+    let config = GenerateConfig {
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..crate::test::generate_config()
+    };
+    let mut build_info =
+        BuildInfo::new(&GenerateInfo::default(), &config, env.indexed_metadata()).unwrap();
+
+    write_cargo_nix(&build_info).unwrap();
+    let written = std::fs::read_to_string(&config.output).unwrap();
+    assert!(written.contains("    crates = {\n      \"main\" = rec {\n"));
+    assert!(!written.contains("crate2nix:crates"));
+    validate_nix("written", &written, &template_source).unwrap();
+
+    // A broken crate is named and the existing file is kept.
+    build_info.crates[0].crate_override = Some(crate::config::CrateOverride {
+        env: [("BROKEN".to_string(), "(".to_string())]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    });
+    let error = format!("{:#}", write_cargo_nix(&build_info).unwrap_err());
+    assert!(
+        error.contains("while rendering crate main with crate-entry.nix.tera: "),
+        "{}",
+        error
+    );
+    assert!(
+        error.contains("crate-entry.nix.tera rendered invalid nix at line"),
+        "{}",
+        error
+    );
+    assert!(
+        error.contains("It was rendered by crate.tera line"),
+        "{}",
+        error
+    );
+    assert_eq!(std::fs::read_to_string(&config.output).unwrap(), written);
+    assert!(!dir.join(".Cargo.nix.tmp").exists());
+
+    // Hooks are shell scripts, not nix expressions.
+    build_info.crates[0].crate_override = Some(crate::config::CrateOverride {
+        pre_build: Some("echo \"${FOO}\"\nrm -r ci".to_string()),
+        ..Default::default()
+    });
+    write_cargo_nix(&build_info).unwrap();
+    let written = std::fs::read_to_string(&config.output).unwrap();
+    assert!(
+        written.contains("preBuild = \"echo \\\"\\${FOO}\\\"\nrm -r ci\";"),
+        "{}",
+        written
+    );
+    validate_nix("written", &written, &template_source).unwrap();
+
+    build_info.crates[0].crate_override = Some(crate::config::CrateOverride {
+        sandbox: crate::config::SandboxHints {
+            required_system_features: vec!["kvm".to_string()],
+            no_chroot: Some(true),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    write_cargo_nix(&build_info).unwrap();
+    let written = std::fs::read_to_string(&config.output).unwrap();
+    assert!(written.contains("requiredSystemFeatures = [ \"kvm\" ];"));
+    assert!(written.contains("__noChroot = true;"));
+    assert!(!written.contains("__impureHostDeps"));
+    validate_nix("written", &written, &template_source).unwrap();
+
+    env.close();
+}
+
+#[test]
+fn test_write_cargo_nix_no_std() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    env.add_package_and_node("main").make_root();
+    let config = GenerateConfig {
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..crate::test::generate_config()
+    };
+    let mut build_info =
+        BuildInfo::new(&GenerateInfo::default(), &config, env.indexed_metadata()).unwrap();
+
+    write_cargo_nix(&build_info).unwrap();
+    let written = std::fs::read_to_string(&config.output).unwrap();
+    assert!(!written.contains("noStdSysroot"));
+    assert!(written.contains("    noStd = null;\n"));
+
+    build_info.no_std_target = Some("thumbv7em-none-eabihf".to_string());
+    write_cargo_nix(&build_info).unwrap();
+    let written = std::fs::read_to_string(&config.output).unwrap();
+    assert!(written.contains(", noStdSysroot ? null\n"));
+    assert!(written.contains(
+        "    noStd = {\n      rustTarget = \"thumbv7em-none-eabihf\";\n      sysroot = noStdSysroot;\n    };\n"
+    ));
+    assert!(
+        written.contains("++ noStdRustcOpts { inherit pkgs isTested; inherit (self) isBuild; };")
+    );
+    validate_nix("written", &written, &template_source).unwrap();
+
+    env.close();
+}
+
+#[test]
+fn test_render_shared_lists() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("a");
+    env.add_package_and_node("b");
+    let config = GenerateConfig {
+        share_lists: true,
+        ..crate::test::generate_config()
+    };
+    let mut build_info =
+        BuildInfo::new(&GenerateInfo::default(), &config, env.indexed_metadata()).unwrap();
+    for crate_derivation in &mut build_info.crates {
+        crate_derivation.authors = vec!["The Rust Project Developers".into()];
+    }
+
+    let rendered = render_cargo_nix(&build_info).unwrap();
+    assert!(
+        rendered.contains("sharedLists = {\n      s0 = [ \"The Rust Project Developers\" ];"),
+        "{}",
+        rendered
+    );
+    assert_eq!(rendered.matches("authors = sharedLists.s0;").count(), 3);
+    validate_nix("rendered", &rendered, &template_source).unwrap();
+
+    build_info.config.share_lists = false;
+    let rendered = render_cargo_nix(&build_info).unwrap();
+    assert!(!rendered.contains("sharedLists"));
+    env.close();
+}
+
+/// The marker comment for the start of a user region, followed by the region name.
+const USER_REGION_BEGIN: &str = "# crate2nix:user-begin ";
+/// The marker comment for the end of a user region, followed by the region name.
+const USER_REGION_END: &str = "# crate2nix:user-end ";
+
+/// Returns the user regions of a file by name: the lines between
+/// `# crate2nix:user-begin <name>` and `# crate2nix:user-end <name>`.
+fn user_regions(content: &str) -> Result<Vec<(String, Vec<&str>)>, Error> {
+    let mut regions = Vec::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    for (idx, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix(USER_REGION_BEGIN) {
+            if let Some((open, _)) = &current {
+                bail!(
+                    "line {}: user region {} starts before {} ends",
+                    idx + 1,
+                    name,
+                    open
+                );
+            }
+            current = Some((name.trim().to_string(), Vec::new()));
+        } else if let Some(name) = trimmed.strip_prefix(USER_REGION_END) {
+            match current.take() {
+                Some((open, lines)) if open == name.trim() => regions.push((open, lines)),
+                _ => bail!(
+                    "line {}: end of user region {} without its start",
+                    idx + 1,
+                    name.trim()
+                ),
+            }
+        } else if let Some((_, lines)) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    if let Some((open, _)) = current {
+        bail!("user region {} does not end", open);
+    }
+    Ok(regions)
+}
+
+/// Replaces the content of the user regions in `rendered` with the content of the regions
+/// with the same names in `existing`, so that regenerating a file keeps manual changes there.
+fn keep_user_regions(existing: &str, rendered: &str) -> Result<String, Error> {
+    let existing_regions = user_regions(existing)?;
+    if existing_regions.is_empty() {
+        return Ok(rendered.to_string());
+    }
+    let rendered_names: HashSet<String> = user_regions(rendered)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    if let Some((name, _)) = existing_regions
+        .iter()
+        .find(|(name, _)| !rendered_names.contains(name))
+    {
+        bail!(
+            "the user region {} does not exist in the regenerated file. \
+             Move its content into another user region.",
+            name
+        );
+    }
+
+    let existing_regions: HashMap<String, Vec<&str>> = existing_regions.into_iter().collect();
+    let mut result = Vec::new();
+    let mut in_region = false;
+    for line in rendered.split('\n') {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix(USER_REGION_BEGIN) {
+            result.push(line);
+            if let Some(lines) = existing_regions.get(name.trim()) {
+                result.extend(lines.iter().copied());
+                in_region = true;
+            }
+        } else if trimmed.starts_with(USER_REGION_END) {
+            result.push(line);
+            in_region = false;
+        } else if !in_region {
+            result.push(line);
+        }
+    }
+    Ok(result.join("\n"))
+}
+
+#[test]
+fn test_keep_user_regions() {
+    let rendered =
+        "{\n  # crate2nix:user-begin attributes\n  # crate2nix:user-end attributes\n  a = 2;\n}\n";
+    assert_eq!(keep_user_regions("", rendered).unwrap(), rendered);
+
+    let existing = "{\n  a = 1;\n  # crate2nix:user-begin attributes\n  b = 1;\n  c = 1;\n  # crate2nix:user-end attributes\n}\n";
+    assert_eq!(
+        keep_user_regions(existing, rendered).unwrap(),
+        "{\n  # crate2nix:user-begin attributes\n  b = 1;\n  c = 1;\n  # crate2nix:user-end attributes\n  a = 2;\n}\n"
+    );
+
+    let unknown = "# crate2nix:user-begin other\nb = 1;\n# crate2nix:user-end other\n";
+    assert!(keep_user_regions(unknown, rendered)
+        .unwrap_err()
+        .to_string()
+        .contains("the user region other does not exist"));
+    let unterminated = "# crate2nix:user-begin attributes\nb = 1;\n";
+    assert!(keep_user_regions(unterminated, rendered).is_err());
+}
+
+/// Parses the rendered nix code and fails with the location of the first syntax error.
+///
+/// Invalid output is always a bug in a crate2nix template, so we want to catch it before
+/// users run into confusing evaluation errors. The error names the lines of `template` and the
+/// templates that it includes which may have rendered the invalid line, as found by
+/// `template_lines` in the template sources returned by `sources`.
+fn validate_nix(
+    template: &str,
+    rendered: &str,
+    sources: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), Error> {
+    use rnix::parser::ParseError;
+
+    let parsed = rnix::Root::parse(rendered);
+    let Some(error) = parsed.errors().first() else {
+        return Ok(());
+    };
+    let offset = match error {
+        ParseError::Unexpected(range)
+        | ParseError::UnexpectedExtra(range)
+        | ParseError::UnexpectedWanted(_, range, _)
+        | ParseError::UnexpectedDoubleBind(range)
+        | ParseError::DuplicatedArgs(range, _) => usize::from(range.start()),
+        _ => rendered.len(),
+    };
+    let before = &rendered[..offset.min(rendered.len())];
+    let line_number = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|idx| idx + 1).unwrap_or(0) + 1;
+    let line = rendered.lines().nth(line_number - 1).unwrap_or_default();
+    let origin = match template_lines(template, line, sources).as_slice() {
+        [] => String::new(),
+        lines if lines.len() > MAX_TEMPLATE_LINES => String::new(),
+        lines => format!(
+            "\nIt was rendered by {}.",
+            lines
+                .iter()
+                .map(|(template, line)| format!("{} line {}", template, line))
+                .join(" or ")
+        ),
+    };
+    bail!(
+        "{} rendered invalid nix at line {}, column {} of its output: {}\n{:>5}: {}{}\n\
+         This is a bug in crate2nix, please report it.",
+        template,
+        line_number,
+        column,
+        error,
+        line_number,
+        line,
+        origin
+    );
+}
+
+/// More candidates for the origin of an invalid line are not worth reporting.
+const MAX_TEMPLATE_LINES: usize = 5;
+
+/// The source of a predefined template by name.
+fn template_source(template: &str) -> Option<String> {
+    let template = TEMPLATES.iter().find(|t| t.template() == template)?;
+    #[cfg(debug_assertions)]
+    return std::fs::read_to_string(
+        Path::new(&std::env::var_os("TEMPLATES_DIR")?).join(template.template()),
+    )
+    .ok();
+    #[cfg(not(debug_assertions))]
+    return Some(template.template_content().to_string());
+}
+
+/// The lines of `template` and the templates that it includes which can render `output_line`,
+/// as template names and line numbers.
+///
+/// Tera does not record where the output comes from, so the lines are matched as patterns
+/// instead: Expressions match anything, everything between the first and the last tag of a line
+/// as well, whitespace is ignored. Lines without any text are skipped.
+fn template_lines(
+    template: &str,
+    output_line: &str,
+    sources: &dyn Fn(&str) -> Option<String>,
+) -> Vec<(String, usize)> {
+    let output_line: String = output_line.split_whitespace().collect();
+    let mut templates = vec![template.to_string()];
+    let mut matches = Vec::new();
+    let mut visited = HashSet::new();
+    while let Some(template) = templates.pop() {
+        if !visited.insert(template.clone()) {
+            continue;
+        }
+        let Some(source) = sources(&template) else {
+            continue;
+        };
+        let (lines, includes) = template_line_parts(&source);
+        templates.extend(includes);
+        for (idx, parts) in lines.iter().enumerate() {
+            if matches_template_line(parts, &output_line) {
+                matches.push((template.clone(), idx + 1));
+            }
+        }
+    }
+    matches
+}
+
+/// A part of a template line, see `template_lines`.
+#[derive(Debug, PartialEq)]
+enum TemplatePart {
+    /// Literal text without whitespace.
+    Text(String),
+    /// A `{{ ... }}` expression.
+    Expression,
+    /// A `{% ... %}` tag.
+    Tag,
+}
+
+/// Whether `output` (without whitespace) can be rendered by a template line with `parts`.
+fn matches_template_line(parts: &[TemplatePart], output: &str) -> bool {
+    // Everything between the first and the last tag might not have been rendered.
+    let first_tag = parts.iter().position(|p| *p == TemplatePart::Tag);
+    let last_tag = parts.iter().rposition(|p| *p == TemplatePart::Tag);
+    let mut texts: Vec<Option<&str>> = Vec::new();
+    for (idx, part) in parts.iter().enumerate() {
+        let within_tags =
+            matches!((first_tag, last_tag), (Some(f), Some(l)) if f < idx && idx <= l);
+        match part {
+            TemplatePart::Text(text) if !within_tags => texts.push(Some(text)),
+            _ if texts.last() == Some(&None) => {}
+            _ => texts.push(None),
+        }
+    }
+    if texts.iter().all(Option::is_none) {
+        return false;
+    }
+
+    let mut rest = output;
+    if let Some(Some(first)) = texts.first() {
+        let Some(after) = rest.strip_prefix(first) else {
+            return false;
+        };
+        rest = after;
+        texts.remove(0);
+        if texts.is_empty() {
+            return rest.is_empty();
+        }
+    }
+    if let Some(Some(last)) = texts.last() {
+        let Some(before) = rest.strip_suffix(last) else {
+            return false;
+        };
+        rest = before;
+        texts.pop();
+    }
+    for text in texts.into_iter().flatten() {
+        let Some(idx) = rest.find(text) else {
+            return false;
+        };
+        rest = &rest[idx + text.len()..];
+    }
+    true
+}
+
+/// The parts of the lines of the template `source` and the names of the included templates.
+fn template_line_parts(source: &str) -> (Vec<Vec<TemplatePart>>, Vec<String>) {
+    let mut lines: Vec<Vec<TemplatePart>> = vec![Vec::new()];
+    let mut includes = Vec::new();
+    let mut rest = source;
+    loop {
+        let next = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| rest.find(open))
+            .min();
+        for (idx, text) in rest[..next.unwrap_or(rest.len())].split('\n').enumerate() {
+            if idx > 0 {
+                lines.push(Vec::new());
+            }
+            let text: String = text.split_whitespace().collect();
+            if !text.is_empty() {
+                lines
+                    .last_mut()
+                    .expect("a line")
+                    .push(TemplatePart::Text(text));
+            }
+        }
+        let Some(start) = next else {
+            break;
+        };
+        let close = match &rest[start..start + 2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let end = rest[start..]
+            .find(close)
+            .map(|idx| start + idx + close.len())
+            .unwrap_or(rest.len());
+        let inner = &rest[start + 2..end];
+        let statement = inner
+            .strip_suffix(close)
+            .unwrap_or(inner)
+            .trim_matches('-')
+            .trim();
+        let line = lines.last_mut().expect("a line");
+        match close {
+            "}}" => line.push(TemplatePart::Expression),
+            "%}" => {
+                line.push(TemplatePart::Tag);
+                if let Some(included) = statement.strip_prefix("include ") {
+                    includes.push(included.trim().trim_matches('"').to_string());
+                }
+            }
+            _ => {}
+        }
+        // The lines within tags and comments render nothing on their own.
+        for _ in rest[start..end].matches('\n') {
+            lines.push(Vec::new());
+        }
+        rest = &rest[end..];
+    }
+    (lines, includes)
+}
+
+#[test]
+fn test_validate_nix() {
+    validate_nix("test", "{ a = 1; }", &template_source).unwrap();
+    validate_nix(
+        "pregenerated",
+        include_str!("../Cargo.nix"),
+        &template_source,
+    )
+    .unwrap();
+
+    let error = validate_nix("test", "{\n  a = ;\n}", &template_source)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.starts_with("test rendered invalid nix at line 2, column 7 of its output"),
+        "{}",
+        error
+    );
+    assert!(error.contains("    2:   a = ;"), "{}", error);
+    assert!(!error.contains("It was rendered by"), "{}", error);
+}
+
+#[test]
+fn test_validate_nix_names_template_line() {
+    let sources: HashMap<&str, &str> = vec![
+        (
+            "broken.nix.tera",
+            "{#- A deliberately broken template. #}\n\
+             {\n\
+             {%- for name in names %}\n  \
+             {% include \"attribute.tera\" %}\n\
+             {%- endfor %}\n\
+             }\n",
+        ),
+        (
+            "attribute.tera",
+            "{{ name }}_enabled = true;\n  \
+             {{ name }}_features = [ {% for f in features %}{{ f }} {% endfor %};",
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let mut tera = Tera::default();
+    tera.add_raw_templates(sources.iter().map(|(name, source)| (*name, *source)))
+        .unwrap();
+    let rendered = tera
+        .render(
+            "broken.nix.tera",
+            &Context::from_serialize(serde_json::json!({
+                "names": ["a", "b"],
+                "features": ["\"x\""],
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+    let sources = |name: &str| sources.get(name).map(|source| source.to_string());
+
+    let error = validate_nix("broken.nix.tera", &rendered, &sources)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("It was rendered by attribute.tera line 2."),
+        "{}",
+        error
+    );
+
+    assert_eq!(
+        template_lines("broken.nix.tera", "  a_enabled = true;", &sources),
+        vec![("attribute.tera".to_string(), 1)]
+    );
+    assert_eq!(
+        template_lines("broken.nix.tera", "}", &sources),
+        vec![("broken.nix.tera".to_string(), 6)]
+    );
+    assert!(template_lines("broken.nix.tera", "c = 1;", &sources).is_empty());
+    assert!(template_lines("unknown.nix.tera", "}", &sources).is_empty());
+}
+
+/// Writes the crate files referenced by a sharded `Cargo.nix`.
+///
+/// Files for crates that are no longer part of the build are removed.
+pub fn write_crate_shards(build_info: &BuildInfo) -> Result<(), Error> {
+    write_crate_shards_reusing(build_info, None)
+}
+
+/// Writes the crate files like `write_crate_shards`, with the crates that did not change since
+/// the last generation taken from `rendered`.
+fn write_crate_shards_reusing(
+    build_info: &BuildInfo,
+    mut rendered: Option<&mut RenderedCrates>,
+) -> Result<(), Error> {
+    let output_dir = match build_info.config.output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let shard_dir = output_dir.join(build_info.config.shard_directory());
+    std::fs::create_dir_all(&shard_dir)
+        .with_context(|| format!("while creating {}", shard_dir.to_string_lossy()))?;
+
+    let mut written = HashSet::new();
+    for crate_derivation in &build_info.crates {
+        let shard = build_info
+            .shards
+            .get(&crate_derivation.package_id)
+            .ok_or_else(|| format_err!("no shard assigned to {}", crate_derivation.package_id))?;
+        let path = output_dir.join(shard);
+        check_generated_by_crate2nix(&path)?;
+        // The crate files live one directory below the output.
+        let crate_derivation_id = &crate_derivation.package_id;
+        let mut crate_derivation = crate_derivation.clone();
+        let provenance = crate_derivation.source.provenance();
+        crate_derivation.source = crate_derivation.source.in_subdirectory();
+        if let Some(crate_override) = &mut crate_derivation.crate_override {
+            for patch in &mut crate_override.patches {
+                if let Some(path) = patch.to_str().and_then(crate::resolve::one_level_up) {
+                    *patch = path.into();
+                }
+            }
+        }
+        let context = CrateShard {
+            info: build_info.info.clone(),
+            crate_derivation,
+            provenance,
+            root_directory: "../.".to_string(),
+        };
+        let render = || {
+            CRATE_SHARD_NIX.render(&context).with_context(|| {
+                format!(
+                    "while rendering crate {} with {}",
+                    crate_derivation_id, CRATE_SHARD_NIX.template
+                )
+            })
+        };
+        let rendered_content;
+        let content = match rendered.as_deref_mut() {
+            Some(rendered) => rendered.render(CRATE_SHARD_NIX.template, &[], &context, render)?,
+            None => {
+                rendered_content = render()?;
+                &rendered_content
+            }
+        };
+        std::fs::write(&path, content)
+            .with_context(|| format!("while writing {}", path.to_string_lossy()))?;
+        written.insert(path);
+    }
+
+    for entry in std::fs::read_dir(&shard_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "nix") && !written.contains(&path) {
+            check_generated_by_crate2nix(&path)?;
+            debug!("Removing stale {}.", path.to_string_lossy());
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    info!(
+        "Generated {} crate files in {} successfully.",
+        written.len(),
+        shard_dir.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Removes the crate files of an earlier run with `--shard`.
+///
+/// Files without the generated marker are kept, together with their directory.
+pub fn remove_crate_shards(config: &GenerateConfig) -> Result<(), Error> {
+    let output_dir = match config.output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let shard_dir = output_dir.join(config.shard_directory());
+    if !shard_dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(&shard_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "nix")
+            && check_generated_by_crate2nix(&path).is_ok()
+        {
+            debug!("Removing stale {}.", path.to_string_lossy());
+            std::fs::remove_file(&path)
+                .with_context(|| format!("while removing {}", path.to_string_lossy()))?;
+        }
+    }
+    if std::fs::remove_dir(&shard_dir).is_ok() {
+        info!(
+            "Removed {} of an earlier run with --shard.",
+            shard_dir.to_string_lossy()
+        );
+    } else {
+        warn!(
+            "{} of an earlier run with --shard contains other files, remove it.",
+            shard_dir.to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_remove_crate_shards() {
+    let dir = tempdir::TempDir::new("crate2nix_render_shards").expect("temp dir");
+    let config = GenerateConfig {
+        output: dir.path().join("Cargo.nix"),
+        ..crate::test::generate_config()
+    };
+    let shard_dir = dir.path().join("Cargo.crates");
+    let generated = "# This file was @generated by crate2nix.\n{ }\n";
+    std::fs::create_dir_all(&shard_dir).unwrap();
+    std::fs::write(shard_dir.join("a.nix"), generated).unwrap();
+    std::fs::write(shard_dir.join("b.nix"), generated).unwrap();
+
+    remove_crate_shards(&config).unwrap();
+    assert!(!shard_dir.exists());
+    remove_crate_shards(&config).unwrap();
+
+    std::fs::create_dir_all(&shard_dir).unwrap();
+    std::fs::write(shard_dir.join("a.nix"), generated).unwrap();
+    std::fs::write(shard_dir.join("mine.nix"), "{ }\n").unwrap();
+    remove_crate_shards(&config).unwrap();
+    assert!(!shard_dir.join("a.nix").exists());
+    assert!(shard_dir.join("mine.nix").exists());
+
+    dir.close().unwrap();
+}
+
+/// The file name of the overlay written by `write_overlay`.
+pub const OVERLAY_FILE_NAME: &str = "overlay.nix";
+
+/// Writes the `overlay.nix` next to the output, see `GenerateConfig::overlay`.
+pub fn write_overlay(build_info: &BuildInfo) -> Result<(), Error> {
+    let output = &build_info.config.output;
+    let path = output.with_file_name(OVERLAY_FILE_NAME);
+    check_generated_by_crate2nix(&path)?;
+    OVERLAY_NIX.write_to_file(
+        &path,
+        &OverlayNix {
+            info: build_info.info.clone(),
+            cargo_nix: output
+                .file_name()
+                .ok_or_else(|| format_err!("no file name in {}", output.to_string_lossy()))?
+                .to_string_lossy()
+                .to_string(),
+            members: build_info.member_binaries.keys().cloned().collect(),
+        },
+    )
+}
+
+#[test]
+fn test_render_overlay() {
+    let rendered = OVERLAY_NIX
+        .render(&OverlayNix {
+            info: GenerateInfo::default(),
+            cargo_nix: "Cargo.nix".to_string(),
+            members: vec!["my-cli".to_string()],
+        })
+        .unwrap();
+    assert!(rendered.contains("cargoNix = final.callPackage ./Cargo.nix { };"));
+    assert!(rendered.contains(r#""my-cli" = cargoNix.workspaceMembers."my-cli".build;"#));
+}
+
+/// The file name of the jobset written by `write_release_nix`.
+pub const RELEASE_FILE_NAME: &str = "release.nix";
+
+/// The systems of the jobset without `--platforms`.
+const DEFAULT_RELEASE_SYSTEMS: &[&str] = &["x86_64-linux", "aarch64-linux"];
+
+/// Writes the `release.nix` next to the output, see `GenerateConfig::release_nix`.
+///
+/// The jobs are built for the systems of `--platforms`, if given.
+pub fn write_release_nix(build_info: &BuildInfo) -> Result<(), Error> {
+    let output = &build_info.config.output;
+    let path = output.with_file_name(RELEASE_FILE_NAME);
+    check_generated_by_crate2nix(&path)?;
+    let systems = if build_info.config.platforms.is_empty() {
+        DEFAULT_RELEASE_SYSTEMS
+            .iter()
+            .map(|system| system.to_string())
+            .collect()
+    } else {
+        build_info.config.platforms.clone()
+    };
+    RELEASE_NIX.write_to_file(
+        &path,
+        &ReleaseNix {
+            info: build_info.info.clone(),
+            cargo_nix: output
+                .file_name()
+                .ok_or_else(|| format_err!("no file name in {}", output.to_string_lossy()))?
+                .to_string_lossy()
+                .to_string(),
+            members: build_info.workspace_members.keys().cloned().collect(),
+            systems,
+        },
+    )
+}
+
+#[test]
+fn test_render_release_nix() {
+    let rendered = RELEASE_NIX
+        .render(&ReleaseNix {
+            info: GenerateInfo::default(),
+            cargo_nix: "Cargo.nix".to_string(),
+            members: vec!["my-lib".to_string()],
+            systems: vec!["x86_64-linux".to_string()],
+        })
+        .unwrap();
+    assert!(rendered.contains(r#", supportedSystems ? [ "x86_64-linux" ]"#));
+    assert!(rendered.contains(".callPackage ./Cargo.nix { });"));
+    assert!(rendered.contains(
+        r#""my-lib" = forAllSystems (cargoNix: cargoNix.workspaceMembers."my-lib".build);"#
+    ));
+}
+
+/// Writes the `Cargo.nix` and the additional outputs selected in `config`.
+///
+/// With `--incremental`, the crates that did not change are not rendered again, see
+/// `RenderedCrates`.
+pub fn write_outputs(build_info: &BuildInfo, config: &GenerateConfig) -> Result<(), Error> {
+    let mut rendered = config.incremental.then(|| RenderedCrates::read(config));
+    write_cargo_nix_reusing(build_info, rendered.as_mut())?;
+    if config.shard {
+        write_crate_shards_reusing(build_info, rendered.as_mut())?;
+    } else {
+        remove_crate_shards(config)?;
+    }
+    if config.crates_json {
+        crate::crates_json::write(build_info)?;
+    } else {
+        crate::crates_json::remove_stale(config)?;
+    }
+    if config.overlay {
+        write_overlay(build_info)?;
+    }
+    if config.release_nix {
+        write_release_nix(build_info)?;
+    }
+    if let Some(rendered) = rendered {
+        rendered.write(config)?;
+    }
+    Ok(())
+}
+
 /// Ensures that the given path either does not exist or contains a file that
 /// was generated by crate2nix.
 pub fn check_generated_by_crate2nix(path: impl AsRef<Path>) -> Result<(), Error> {
@@ -114,6 +1258,22 @@ pub fn check_generated_by_crate2nix(path: impl AsRef<Path>) -> Result<(), Error>
     Ok(())
 }
 
+/// Returns the crate2nix arguments in the header of the generated file at `path`, if any.
+pub fn generated_with_arguments(path: impl AsRef<Path>) -> Result<Option<Vec<String>>, Error> {
+    let content = std::fs::read_to_string(path.as_ref())
+        .map_err(|e| format_err!("while reading {}: {}", path.as_ref().to_string_lossy(), e))?;
+    let mut lines = content.lines();
+    if !lines.any(|l| l.contains("@generated by crate2nix") && l.ends_with("with the command:")) {
+        return Ok(None);
+    }
+    Ok(lines.next().map(|l| {
+        l.trim_start_matches('#')
+            .split_whitespace()
+            .map(|arg| arg.trim_matches('"').to_string())
+            .collect()
+    }))
+}
+
 trait AbstractTemplate {
     fn template(&self) -> &'static str;
     #[cfg(not(debug_assertions))]
@@ -131,7 +1291,18 @@ impl<C: Serialize + Debug> AbstractTemplate for Template<C> {
     }
 }
 
-const TEMPLATES: &[&'static dyn AbstractTemplate] = &[&CARGO_NIX, &DEFAULT_NIX, &SOURCES_NIX];
+const TEMPLATES: &[&'static dyn AbstractTemplate] = &[
+    &CARGO_NIX,
+    &DEFAULT_NIX,
+    &CRATE,
+    &CRATE_ENTRY_NIX,
+    &CRATE_SHARD_NIX,
+    &OVERLAY_NIX,
+    &RELEASE_NIX,
+    &SOURCES_NIX,
+    &INIT_DEFAULT_NIX,
+    &INIT_FLAKE_NIX,
+];
 
 fn create_tera() -> Tera {
     let mut tera = Tera::default();
@@ -160,27 +1331,51 @@ fn create_tera() -> Tera {
     tera.autoescape_on(vec![".nix.tera", ".nix"]);
     tera.set_escape_fn(escape_nix_string);
     tera.register_filter("cfg_to_nix_expr", cfg_to_nix_expr_filter);
+    crate::shared_lists::register(&mut tera, &[]);
     tera
 }
 
+/// The tera instance for the crates of a `Cargo.nix` that refer to `shared_lists`.
+fn crate_tera(shared_lists: &[Vec<Arc<str>>]) -> Cow<'static, Tera> {
+    if shared_lists.is_empty() {
+        return Cow::Borrowed(&TERA);
+    }
+    let mut tera = TERA.clone();
+    crate::shared_lists::register(&mut tera, shared_lists);
+    Cow::Owned(tera)
+}
+
 lazy_static! {
     static ref TERA: Tera = create_tera();
 }
 
+/// Renders a target of a dependency of the crate with the `package_id` argument.
 fn cfg_to_nix_expr_filter(
     value: &tera::Value,
-    _args: &HashMap<String, tera::Value>,
+    args: &HashMap<String, tera::Value>,
 ) -> tera::Result<tera::Value> {
+    let package_id = match args.get("package_id") {
+        Some(tera::Value::String(package_id)) => PackageId {
+            repr: package_id.clone(),
+        },
+        other => {
+            return Err(tera::Error::msg(format!(
+                "cfg_to_nix_expr_filter: Expected a package_id argument, got {:?}",
+                other
+            )))
+        }
+    };
     match value {
         tera::Value::String(key) => {
             if key.starts_with("cfg(") && key.ends_with(')') {
                 let cfg = &key[4..key.len() - 1];
 
                 let expr = CfgExpr::from_str(cfg).map_err(|e| {
-                    tera::Error::msg(format!(
-                        "cfg_to_nix_expr_filter: Could not parse '{}': {}",
-                        cfg, e
-                    ))
+                    tera::Error::msg(Crate2NixError::UnsupportedTargetExpression {
+                        package_id: package_id.clone(),
+                        expression: key.clone(),
+                        reason: e.to_string(),
+                    })
                 })?;
                 Ok(tera::Value::String(cfg_to_nix_expr(&expr)))
             } else {
@@ -305,6 +1500,35 @@ fn test_render_cfg_to_nix_expr() {
     assert_eq!("false", &cfg_to_nix_expr(&CfgExpr::Any(vec![])));
 }
 
+#[test]
+fn test_cfg_to_nix_expr_filter_names_package() {
+    let args: HashMap<String, tera::Value> = vec![(
+        "package_id".to_string(),
+        tera::Value::String("some-crate 1.0.0".to_string()),
+    )]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        cfg_to_nix_expr_filter(&tera::Value::String("cfg(unix)".to_string()), &args).unwrap(),
+        tera::Value::String("(target.\"unix\" or false)".to_string())
+    );
+
+    let error = cfg_to_nix_expr_filter(&tera::Value::String("cfg(unix,,)".to_string()), &args)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("'cfg(unix,,)' in some-crate 1.0.0"),
+        "{}",
+        error
+    );
+
+    assert!(cfg_to_nix_expr_filter(
+        &tera::Value::String("cfg(unix)".to_string()),
+        &HashMap::new()
+    )
+    .is_err());
+}
+
 /// Escapes a string as a nix string.
 ///
 /// ```
@@ -0,0 +1,379 @@
+//! Estimating the build cost of the crates in the resolved dependency graph,
+//! e.g. for `crate2nix report`.
+//!
+//! The build level of a crate is the length of the longest chain of dependencies below it:
+//! crates without dependencies have level 0 and crates of the same level can be built in
+//! parallel. Closure sizes are only known for crates that are already in the nix store.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, format_err, Error};
+use cargo_metadata::PackageId;
+use serde::Serialize;
+
+use crate::resolve::CrateDerivation;
+#[cfg(test)]
+use crate::test;
+use crate::BuildInfo;
+
+/// The estimated build cost of a crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrateReport {
+    /// The package ID of the crate.
+    pub package_id: PackageId,
+    /// The crate name.
+    pub name: String,
+    /// The crate version.
+    pub version: String,
+    /// The length of the longest chain of dependencies below this crate.
+    pub build_level: usize,
+    /// The number of normal and build dependencies.
+    pub direct_dependencies: usize,
+    /// The number of crates that have to be built before this crate.
+    pub transitive_dependencies: usize,
+    /// The size of the closure of the build output in bytes, if it is in the nix store.
+    pub closure_size: Option<u64>,
+}
+
+/// Returns a report for every crate in `build_info`, in the estimated build order.
+pub fn crate_reports(build_info: &BuildInfo) -> Vec<CrateReport> {
+    reports_for(&build_info.crates)
+}
+
+fn reports_for(crate_derivations: &[CrateDerivation]) -> Vec<CrateReport> {
+    let crates: HashMap<&PackageId, &CrateDerivation> = crate_derivations
+        .iter()
+        .map(|c| (&*c.package_id, c))
+        .collect();
+    let mut levels = HashMap::new();
+    let mut reports: Vec<CrateReport> = crate_derivations
+        .iter()
+        .map(|crate_derivation| {
+            let mut transitive = BTreeSet::new();
+            collect_dependencies(&crates, crate_derivation, &mut transitive);
+            CrateReport {
+                package_id: (*crate_derivation.package_id).clone(),
+                name: crate_derivation.crate_name.to_string(),
+                version: crate_derivation.version.to_string(),
+                build_level: build_level(&crates, crate_derivation, &mut levels),
+                direct_dependencies: dependency_ids(crate_derivation).count(),
+                transitive_dependencies: transitive.len(),
+                closure_size: None,
+            }
+        })
+        .collect();
+    reports.sort_by(|a, b| {
+        (a.build_level, &a.name, &a.version).cmp(&(b.build_level, &b.name, &b.version))
+    });
+    reports
+}
+
+fn dependency_ids(crate_derivation: &CrateDerivation) -> impl Iterator<Item = &PackageId> {
+    let ids: BTreeSet<&PackageId> = crate_derivation
+        .dependencies
+        .iter()
+        .chain(&crate_derivation.build_dependencies)
+        .map(|d| &*d.package_id)
+        .collect();
+    ids.into_iter()
+}
+
+fn collect_dependencies<'a>(
+    crates: &HashMap<&PackageId, &'a CrateDerivation>,
+    crate_derivation: &'a CrateDerivation,
+    seen: &mut BTreeSet<&'a PackageId>,
+) {
+    for id in dependency_ids(crate_derivation) {
+        if let Some(dependency) = crates.get(id) {
+            if seen.insert(&*dependency.package_id) {
+                collect_dependencies(crates, dependency, seen);
+            }
+        }
+    }
+}
+
+fn build_level<'a>(
+    crates: &HashMap<&PackageId, &'a CrateDerivation>,
+    crate_derivation: &'a CrateDerivation,
+    levels: &mut HashMap<&'a PackageId, usize>,
+) -> usize {
+    if let Some(level) = levels.get(&*crate_derivation.package_id) {
+        return *level;
+    }
+    // Only dev dependencies may form cycles, this guards against broken metadata.
+    levels.insert(&crate_derivation.package_id, 0);
+    let level = dependency_ids(crate_derivation)
+        .filter_map(|id| crates.get(id))
+        .map(|dependency| build_level(crates, dependency, levels) + 1)
+        .max()
+        .unwrap_or(0);
+    levels.insert(&crate_derivation.package_id, level);
+    level
+}
+
+/// Evaluates the output paths of the crates built for `root_package_ids` in `nix_file`.
+///
+/// Returns the output path by package ID.
+pub fn out_paths(
+    nix_file: &Path,
+    root_package_ids: &[&PackageId],
+) -> Result<BTreeMap<String, String>, Error> {
+    built_crate_paths(nix_file, root_package_ids, "outPath")
+}
+
+/// Evaluates the attribute `attr` of the crate derivations built for `root_package_ids` in
+/// `nix_file`, e.g. `outPath` or `drvPath`, by package ID.
+pub fn built_crate_paths(
+    nix_file: &Path,
+    root_package_ids: &[&PackageId],
+    attr: &str,
+) -> Result<BTreeMap<String, String>, Error> {
+    let package_ids = root_package_ids
+        .iter()
+        .map(|id| crate::render::escape_nix_string(&id.repr))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let output = Command::new("nix")
+        .args(["eval", "--json", "-f"])
+        .arg(nix_file)
+        .arg("internal.builtCrateOutPaths")
+        .arg("--apply")
+        .arg(format!(
+            "f: f {{ packageIds = [ {} ]; attr = {}; }}",
+            package_ids,
+            crate::render::escape_nix_string(attr)
+        ))
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format_err!("while spawning nix eval: {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "nix eval of the crate {}s in {} failed.",
+            attr,
+            nix_file.to_string_lossy()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| format_err!("while parsing the {}s: {}", attr, e))
+}
+
+/// Returns the closure sizes of those `paths` that exist in the nix store.
+pub fn closure_sizes<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+) -> Result<BTreeMap<String, u64>, Error> {
+    let existing: Vec<&str> = paths
+        .into_iter()
+        .filter(|path| Path::new(path).exists())
+        .collect();
+    if existing.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let output = Command::new("nix")
+        .args(["path-info", "--closure-size", "--json"])
+        .args(&existing)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format_err!("while spawning nix path-info: {}", e))?;
+    if !output.status.success() {
+        bail!("nix path-info failed.");
+    }
+    parse_closure_sizes(&output.stdout)
+}
+
+/// Parses the output of `nix path-info --closure-size --json`.
+///
+/// Older versions of nix print a list of objects with a `path`,
+/// newer versions an object by path.
+fn parse_closure_sizes(json: &[u8]) -> Result<BTreeMap<String, u64>, Error> {
+    let value: serde_json::Value = serde_json::from_slice(json)
+        .map_err(|e| format_err!("while parsing the output of nix path-info: {}", e))?;
+    let entries: Vec<(String, &serde_json::Value)> = match &value {
+        serde_json::Value::Array(infos) => infos
+            .iter()
+            .filter_map(|info| Some((info.get("path")?.as_str()?.to_string(), info)))
+            .collect(),
+        serde_json::Value::Object(infos) => infos
+            .iter()
+            .map(|(path, info)| (path.clone(), info))
+            .collect(),
+        _ => bail!("unexpected output of nix path-info: {}", value),
+    };
+    Ok(entries
+        .into_iter()
+        .filter_map(|(path, info)| Some((path, info.get("closureSize")?.as_u64()?)))
+        .collect())
+}
+
+/// Sets the `closure_size` of the reports from the closure sizes by output path.
+pub fn add_closure_sizes(
+    reports: &mut [CrateReport],
+    out_paths: &BTreeMap<String, String>,
+    closure_sizes: &BTreeMap<String, u64>,
+) {
+    for report in reports {
+        report.closure_size = out_paths
+            .get(&report.package_id.repr)
+            .and_then(|path| closure_sizes.get(path))
+            .copied();
+    }
+}
+
+/// Renders the reports as a table.
+pub fn render(reports: &[CrateReport]) -> String {
+    let rows: Vec<[String; 6]> = reports
+        .iter()
+        .map(|r| {
+            [
+                r.build_level.to_string(),
+                r.name.clone(),
+                r.version.clone(),
+                r.direct_dependencies.to_string(),
+                r.transitive_dependencies.to_string(),
+                r.closure_size
+                    .map(human_size)
+                    .unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+    let header = ["level", "crate", "version", "deps", "transitive", "closure"].map(String::from);
+    let mut widths = header.clone().map(|h| h.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(table, "{}", line.trim_end()).unwrap();
+    }
+
+    let levels = reports.iter().map(|r| r.build_level + 1).max().unwrap_or(0);
+    writeln!(
+        table,
+        "\n{} crates in {} build levels.",
+        reports.len(),
+        levels
+    )
+    .unwrap();
+    table
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[test]
+fn test_crate_reports() {
+    use crate::resolve::ResolvedDependency;
+
+    let mut env = test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("helper");
+    env.add_package_and_node("leaf");
+    let indexed = env.indexed_metadata();
+    let id_of = |name: &str| {
+        indexed
+            .pkgs_by_id
+            .values()
+            .find(|p| p.name == name)
+            .map(|p| p.id.clone())
+            .unwrap()
+    };
+    let dependency = |name: &str| ResolvedDependency {
+        name: name.into(),
+        rename: None,
+        package_id: id_of(name).into(),
+        target: None,
+        optional: false,
+        uses_default_features: true,
+        features: Vec::new(),
+        artifact: None,
+    };
+    let crates: Vec<CrateDerivation> = indexed
+        .pkgs_by_id
+        .values()
+        .map(|package| {
+            let mut crate_derivation =
+                CrateDerivation::unresolved(&indexed, package, String::new());
+            match package.name.as_str() {
+                "main" => {
+                    crate_derivation.dependencies.push(dependency("leaf"));
+                    crate_derivation
+                        .build_dependencies
+                        .push(dependency("helper"));
+                }
+                "helper" => crate_derivation.dependencies.push(dependency("leaf")),
+                _ => {}
+            }
+            crate_derivation
+        })
+        .collect();
+
+    let reports = reports_for(&crates);
+    let summary: Vec<(&str, usize, usize, usize)> = reports
+        .iter()
+        .map(|r| {
+            (
+                r.name.as_str(),
+                r.build_level,
+                r.direct_dependencies,
+                r.transitive_dependencies,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![("leaf", 0, 0, 0), ("helper", 1, 1, 1), ("main", 2, 2, 2)]
+    );
+
+    let mut reports = reports;
+    let out_paths = vec![(id_of("leaf").repr, "/nix/store/leaf".to_string())]
+        .into_iter()
+        .collect();
+    let sizes = vec![("/nix/store/leaf".to_string(), 2048)]
+        .into_iter()
+        .collect();
+    add_closure_sizes(&mut reports, &out_paths, &sizes);
+    let table = render(&reports);
+    assert!(table.starts_with("level  crate   version  deps  transitive  closure\n"));
+    assert!(table.contains("2.0 KiB"));
+    assert!(table.ends_with("3 crates in 3 build levels.\n"));
+
+    env.close();
+}
+
+#[test]
+fn test_parse_closure_sizes() {
+    let expected: BTreeMap<String, u64> =
+        vec![("/nix/store/a".to_string(), 42)].into_iter().collect();
+    assert_eq!(
+        parse_closure_sizes(br#"[{"path": "/nix/store/a", "closureSize": 42}]"#).unwrap(),
+        expected
+    );
+    assert_eq!(
+        parse_closure_sizes(br#"{"/nix/store/a": {"closureSize": 42}, "/nix/store/b": null}"#)
+            .unwrap(),
+        expected
+    );
+}
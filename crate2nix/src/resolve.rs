@@ -1,7 +1,7 @@
 //! Resolve dependencies and other data for CrateDerivation.
 
 use anyhow::format_err;
-use anyhow::Error;
+use anyhow::{bail, Error};
 use cargo_metadata::Node;
 use cargo_metadata::Package;
 use cargo_metadata::PackageId;
@@ -13,28 +13,41 @@ use semver::Version;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::to_string_pretty;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::Into;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::metadata::IndexedMetadata;
+use crate::editions::Edition;
+use crate::error::Crate2NixError;
+use crate::manifest_hints::ManifestHints;
+use crate::metadata::{IndexedMetadata, Interner};
 #[cfg(test)]
 use crate::test;
 use crate::GenerateConfig;
 use itertools::Itertools;
+use log::{debug, trace, warn};
 use std::{collections::btree_map::BTreeMap, fmt::Display};
 use url::Url;
 
 /// All data necessary for creating a derivation for a crate.
-#[derive(Debug, Deserialize, Serialize)]
+///
+/// The IDs, names, versions and features are shared with the other crates through the
+/// `Interner` of the metadata instead of being cloned, they are only serialized when rendering.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CrateDerivation {
-    pub package_id: PackageId,
-    pub crate_name: String,
-    pub edition: String,
-    pub authors: Vec<String>,
-    pub version: Version,
+    pub package_id: Arc<PackageId>,
+    pub crate_name: Arc<str>,
+    pub edition: Arc<str>,
+    pub authors: Vec<Arc<str>>,
+    pub version: Arc<Version>,
     /// The name of a native library the package is linking to.
-    pub links: Option<String>,
+    pub links: Option<Arc<str>>,
+    /// The minimum rust version from `package.rust-version`, or the first rustc version that
+    /// supports the edition.
+    #[serde(default)]
+    pub rust_version: Option<String>,
     pub source: ResolvedSource,
     /// The crate types of the lib targets of this crate, e.g. "lib", "dylib", "rlib", ...
     pub lib_crate_types: Vec<String>,
@@ -42,9 +55,9 @@ pub struct CrateDerivation {
     pub build_dependencies: Vec<ResolvedDependency>,
     pub dev_dependencies: Vec<ResolvedDependency>,
     /// Feature rules. Which feature (key) enables which other features (values).
-    pub features: BTreeMap<String, Vec<String>>,
+    pub features: BTreeMap<Arc<str>, Vec<Arc<str>>>,
     /// The resolved features for this crate for a default build as returned by cargo.
-    pub resolved_default_features: Vec<String>,
+    pub resolved_default_features: Vec<Arc<str>>,
     /// The build target for the custom build script.
     pub build: Option<BuildTarget>,
     /// The build target for the library.
@@ -53,6 +66,30 @@ pub struct CrateDerivation {
     pub proc_macro: bool,
     /// This derivation builds the root crate or a workspace member.
     pub is_root_or_workspace_member: bool,
+    /// Set if this crate could not be resolved completely (see `--keep-going`).
+    /// Building it will fail with this message.
+    pub problem: Option<String>,
+    /// The key in `BuildInfo::shared_sources` if the source is fetched once for several crates.
+    pub shared_source: Option<String>,
+    /// Whether another crate depends on the binaries of this crate.
+    #[serde(default)]
+    pub is_artifact_dependency: bool,
+    /// The configured build script settings from `crate2nix.json`, if any.
+    #[serde(default)]
+    pub build_script_config: Option<crate::config::BuildScriptConfig>,
+    /// The configured additional derivation attributes from `crate2nix.json` and
+    /// `[package.metadata.crate2nix]`, if any.
+    #[serde(default)]
+    pub crate_override: Option<crate::config::CrateOverride>,
+    /// Whether `[package.metadata.crate2nix]` leaves the tests out of the checks.
+    #[serde(default)]
+    pub skip_tests: bool,
+    /// The rustc flags for the `[profile]` settings of the workspace.
+    #[serde(default)]
+    pub profile_flags: crate::profiles::ProfileFlags,
+    /// The definition in the `Cargo.nix` of another project that this crate starts from.
+    #[serde(default)]
+    pub imported: Option<crate::imports::ImportedCrate>,
 }
 
 impl CrateDerivation {
@@ -97,8 +134,8 @@ impl CrateDerivation {
                 .and_then(|file_name| crate2nix_json.sources.get(file_name).cloned());
 
             if !crate2nix_json.sources.is_empty() && configured_source.is_none() {
-                eprintln!(
-                    "warning: Could not find configured source for workspace member {:?}",
+                warn!(
+                    "Could not find configured source for workspace member {:?}",
                     package_path
                 );
             }
@@ -108,11 +145,86 @@ impl CrateDerivation {
             None
         };
 
-        let source = if let Some(configured) = configured_source {
+        let mut source = if let Some(configured) = configured_source {
             configured.into()
+        } else if let Some(source_override) =
+            crate2nix_json.source_override_for(&package.name, &package.version)
+        {
+            ResolvedSource::from_override(config, package, source_override)?
         } else {
+            let generated_in = metadata.target_directories.iter().find(|dir| {
+                package.source.is_none() && package_path.as_std_path().starts_with(dir)
+            });
+            if let Some(target_directory) = generated_in {
+                bail!(
+                    "{} {} in {} is generated into the target directory {}, which is not part of \
+                     the sources of the generated nix.\n\
+                     Add a source override with a nix expression that generates the crate to \
+                     {}, e.g.\n  \
+                     \"sourceOverrides\": {{ \"{}\": {{ \"type\": \"Nix\", \
+                     \"package\": \"./nix/{}.nix\" }} }}",
+                    package.name,
+                    package.version,
+                    package_path,
+                    target_directory.display(),
+                    crate::crate2nix_json_path(config).display(),
+                    package.name,
+                    package.name
+                );
+            }
             ResolvedSource::new(config, package, package_path)?
         };
+        let hints = ManifestHints::of(package)?.unwrap_or_default();
+        let has_globs = !hints.include.is_empty() || !hints.exclude.is_empty();
+        match &mut source {
+            ResolvedSource::LocalDirectory(local) if has_globs => {
+                *local = local
+                    .clone()
+                    .with_globs(&hints.include, &hints.exclude)
+                    .with_test_globs(package);
+            }
+            _ if has_globs => warn!(
+                "Ignoring the source globs of package.metadata.crate2nix in {}: the source is \
+                 not a local directory.",
+                package.manifest_path
+            ),
+            _ => {}
+        }
+        // The overrides of crate2nix.json take precedence over the hints of the crate.
+        let crate_override = match (
+            hints.crate_override(),
+            crate2nix_json.crate_override_for(&package.name),
+        ) {
+            (Some(mut from_manifest), Some(configured)) => {
+                from_manifest.merge(&configured);
+                Some(from_manifest)
+            }
+            (from_manifest, configured) => configured.or(from_manifest),
+        };
+        let crate_override = match crate_override {
+            Some(mut crate_override) if !crate_override.patches.is_empty() => {
+                crate_override.patches = crate_override
+                    .patches
+                    .iter()
+                    .map(|patch| ResolvedSource::patch_path(config, package, patch))
+                    .collect::<Result<_, Error>>()?;
+                Some(crate_override)
+            }
+            crate_override => crate_override,
+        };
+        if let Some(true) = crate_override.as_ref().and_then(|o| o.sandbox.no_chroot) {
+            warn!(
+                "{} {} is built without the nix sandbox, which needs `sandbox = relaxed` on the \
+                 builders.",
+                package.name, package.version
+            );
+        }
+        if let (ResolvedSource::Git(git), Some(fetch_submodules)) = (
+            &mut source,
+            crate_override.as_ref().and_then(|o| o.fetch_submodules),
+        ) {
+            git.fetch_submodules = fetch_submodules;
+        }
 
         let package_path = package_path.canonicalize().map_err(|e| {
             format_err!(
@@ -122,65 +234,102 @@ impl CrateDerivation {
             )
         })?;
 
-        let lib = package
-            .targets
-            .iter()
-            .find(|t| {
-                t.kind.iter().any(|k| {
-                    k == "lib" || k == "cdylib" || k == "dylib" || k == "rlib" || k == "proc-macro"
-                })
+        let build_target = |target: &Target| match BuildTarget::new(target, &package_path) {
+            Ok(build_target) => Some(build_target),
+            Err(e) => {
+                warn!(
+                    "Ignoring target {} of {} {}: {:#}",
+                    target.name, package.name, package.version, e
+                );
+                None
+            }
+        };
+
+        let lib_target = package.targets.iter().find(|t| {
+            t.kind.iter().any(|k| {
+                k == "lib"
+                    || k == "cdylib"
+                    || k == "dylib"
+                    || k == "rlib"
+                    || k == "staticlib"
+                    || k == "proc-macro"
             })
-            .and_then(|target| BuildTarget::new(target, &package_path).ok());
+        });
+        let lib = lib_target.and_then(build_target);
 
         let build = package
             .targets
             .iter()
             .find(|t| t.kind.iter().any(|k| k == "custom-build"))
-            .and_then(|target| BuildTarget::new(target, &package_path).ok());
+            .and_then(build_target);
+
+        let interner = &metadata.interner;
+        let resolved_default_features = metadata
+            .nodes_by_id
+            .get(&package.id)
+            .map(|n| interner.strs(&n.features))
+            .unwrap_or_default();
+        debug!(
+            "Resolved features for {}: {:?}",
+            package.id, resolved_default_features
+        );
 
-        let proc_macro = package
-            .targets
-            .iter()
-            .any(|t| t.kind.iter().any(|k| k == "proc-macro"));
+        // A package has at most one lib target, its crate types are the artifacts to build.
+        let mut lib_crate_types: Vec<String> = lib_target
+            .map(|target| {
+                if target.crate_types.is_empty() {
+                    &target.kind
+                } else {
+                    &target.crate_types
+                }
+            })
+            .into_iter()
+            .flatten()
+            .unique()
+            .cloned()
+            .collect();
+        let proc_macro = lib_crate_types.iter().any(|t| t == "proc-macro");
+        if proc_macro && lib_crate_types.len() > 1 {
+            warn!(
+                "{} {} declares the crate types {} for its library. \
+                 rustc cannot build proc-macro crates together with other crate types, \
+                 only the proc-macro is built.",
+                package.name,
+                package.version,
+                lib_crate_types.join(", ")
+            );
+            lib_crate_types.retain(|t| t == "proc-macro");
+        }
 
         let binaries = package
             .targets
             .iter()
-            .filter_map(|t| {
-                if t.kind.iter().any(|k| k == "bin") {
-                    BuildTarget::new(t, &package_path).ok()
-                } else {
-                    None
-                }
-            })
+            .filter(|t| t.kind.iter().any(|k| k == "bin"))
+            .filter_map(build_target)
             .collect();
 
+        let edition = Edition::of_package(package.edition.as_str(), &package.name)?;
+
         Ok(CrateDerivation {
-            crate_name: package.name.clone(),
-            edition: package.edition.to_string(),
-            authors: package.authors.clone(),
-            package_id: package.id.clone(),
-            version: package.version.clone(),
-            links: package.links.clone(),
+            crate_name: interner.str(&package.name),
+            edition: interner.str(edition.name),
+            authors: interner.strs(&package.authors),
+            package_id: interner.package_id(&package.id),
+            version: interner.version(&package.version),
+            links: package.links.as_deref().map(|links| interner.str(links)),
+            rust_version: package
+                .rust_version
+                .as_ref()
+                .map(|v| v.to_string())
+                .or_else(|| edition.rustc_version.map(str::to_string)),
             source,
             features: package
                 .features
                 .iter()
-                .map(|(name, feature_list)| (name.clone(), feature_list.clone()))
-                .collect(),
-            resolved_default_features: metadata
-                .nodes_by_id
-                .get(&package.id)
-                .map(|n| n.features.clone())
-                .unwrap_or_default(),
-            lib_crate_types: package
-                .targets
-                .iter()
-                .filter(|target| target.kind.iter().any(|kind| kind.ends_with("lib")))
-                .flat_map(|target| target.crate_types.iter())
-                .unique()
-                .cloned()
+                .map(|(name, feature_list)| (interner.str(name), interner.strs(feature_list)))
                 .collect(),
+            resolved_default_features,
+            lib_crate_types,
             dependencies,
             build_dependencies,
             dev_dependencies,
@@ -189,8 +338,90 @@ impl CrateDerivation {
             proc_macro,
             binaries,
             is_root_or_workspace_member,
+            problem: None,
+            shared_source: None,
+            is_artifact_dependency: false,
+            build_script_config: crate2nix_json.build_scripts.get(&package.name).cloned(),
+            crate_override,
+            skip_tests: hints.skip_tests,
+            profile_flags: Default::default(),
+            imported: None,
         })
     }
+
+    /// Returns a placeholder for a crate that could not be resolved.
+    pub fn unresolved(
+        metadata: &IndexedMetadata,
+        package: &Package,
+        problem: String,
+    ) -> CrateDerivation {
+        let interner = &metadata.interner;
+        CrateDerivation {
+            crate_name: interner.str(&package.name),
+            edition: interner.str(package.edition.as_str()),
+            authors: interner.strs(&package.authors),
+            package_id: interner.package_id(&package.id),
+            version: interner.version(&package.version),
+            links: package.links.as_deref().map(|links| interner.str(links)),
+            rust_version: package.rust_version.as_ref().map(|v| v.to_string()),
+            source: ResolvedSource::CratesIo(CratesIoSource {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                sha256: None,
+                mirror: None,
+            }),
+            lib_crate_types: Vec::new(),
+            dependencies: Vec::new(),
+            build_dependencies: Vec::new(),
+            dev_dependencies: Vec::new(),
+            features: BTreeMap::new(),
+            resolved_default_features: Vec::new(),
+            build: None,
+            lib: None,
+            binaries: Vec::new(),
+            proc_macro: false,
+            is_root_or_workspace_member: metadata
+                .root
+                .iter()
+                .chain(metadata.workspace_members.iter())
+                .any(|pkg_id| *pkg_id == package.id),
+            problem: Some(problem),
+            shared_source: None,
+            is_artifact_dependency: false,
+            build_script_config: None,
+            crate_override: None,
+            skip_tests: false,
+            profile_flags: Default::default(),
+            imported: None,
+        }
+    }
+
+    /// Shares the IDs, names, versions and features of a crate that was not resolved from
+    /// `interner`'s metadata, e.g. one reused by `--incremental`, with the other crates.
+    pub fn intern(&mut self, interner: &Interner) {
+        self.package_id = interner.package_id(&self.package_id);
+        self.crate_name = interner.str(&self.crate_name);
+        self.edition = interner.str(&self.edition);
+        self.authors = interner.strs(&self.authors);
+        self.version = interner.version(&self.version);
+        self.links = self.links.as_deref().map(|links| interner.str(links));
+        self.features = self
+            .features
+            .iter()
+            .map(|(name, feature_list)| (interner.str(name), interner.strs(feature_list)))
+            .collect();
+        self.resolved_default_features = interner.strs(&self.resolved_default_features);
+        for dependency in self
+            .dependencies
+            .iter_mut()
+            .chain(&mut self.build_dependencies)
+            .chain(&mut self.dev_dependencies)
+        {
+            dependency.name = interner.str(&dependency.name);
+            dependency.package_id = interner.package_id(&dependency.package_id);
+            dependency.features = interner.strs(&dependency.features);
+        }
+    }
 }
 
 #[test]
@@ -226,9 +457,9 @@ pub fn minimal_resolve() {
 
     println!("crate_derivation: {:#?}", crate_derivation);
 
-    assert_eq!(crate_derivation.crate_name, "main");
+    assert_eq!(&*crate_derivation.crate_name, "main");
     assert_eq!(
-        crate_derivation.version,
+        *crate_derivation.version,
         semver::Version::parse("1.2.3").unwrap()
     );
     assert!(crate_derivation.is_root_or_workspace_member);
@@ -238,6 +469,47 @@ pub fn minimal_resolve() {
     package.close().unwrap();
 }
 
+#[test]
+pub fn lib_crate_types() {
+    let mut package = test::package("macros", "0.1.0");
+    let src = package.manifest_path.parent().unwrap().join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    let mut lib = test::lib_target(&package, "lib");
+    let resolve = |package: &Package| {
+        let mut resolve = test::empty_resolve();
+        resolve.nodes = vec![test::node(&package.id.repr)];
+        let mut metadata = test::empty_metadata();
+        metadata.workspace_members = vec![package.id.clone()];
+        metadata.packages = vec![package.clone()];
+        metadata.resolve = Some(resolve);
+        let indexed = IndexedMetadata::new_from(metadata).unwrap();
+        CrateDerivation::resolve(
+            &test::generate_config(),
+            &crate::config::Config::default(),
+            &indexed,
+            indexed.pkgs_by_id.values().next().unwrap(),
+        )
+        .unwrap()
+    };
+
+    lib.kind = vec!["cdylib".to_string(), "rlib".to_string()];
+    lib.crate_types = lib.kind.clone();
+    package.targets = vec![lib.clone()];
+    let crate_derivation = resolve(&package);
+    assert!(!crate_derivation.proc_macro);
+    assert_eq!(crate_derivation.lib_crate_types, vec!["cdylib", "rlib"]);
+    assert!(crate_derivation.lib.is_some());
+
+    lib.kind = vec!["proc-macro".to_string()];
+    lib.crate_types = vec!["proc-macro".to_string(), "rlib".to_string()];
+    package.targets = vec![lib];
+    let crate_derivation = resolve(&package);
+    assert!(crate_derivation.proc_macro);
+    assert_eq!(crate_derivation.lib_crate_types, vec!["proc-macro"]);
+
+    package.close().unwrap();
+}
+
 #[test]
 pub fn configured_source_is_used_instead_of_local_directory() {
     use std::convert::TryInto;
@@ -288,6 +560,238 @@ pub fn configured_source_is_used_instead_of_local_directory() {
             name: "some_crate".to_string(),
             version: semver::Version::from_str("1.2.3").unwrap(),
             sha256: Some("123".to_string()),
+            mirror: None,
+        })
+    );
+
+    env.close();
+}
+
+#[test]
+pub fn source_overrides() {
+    let mut env = test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    let fork = dir.join("forks").join("helper");
+    std::fs::create_dir_all(&fork).unwrap();
+    std::fs::write(
+        fork.join("Cargo.toml"),
+        "[package]\nname = \"helper\"\nversion = \"0.2.0\"\nexclude = [\"/ci\"]\n",
+    )
+    .unwrap();
+    let config = GenerateConfig {
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..test::generate_config()
+    };
+
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.add_dependency("helper")
+        .version_and_package_id("0.2.0");
+    main.add_dependency("other").version_and_package_id("1.0.0");
+    let indexed = env.indexed_metadata();
+    let resolve = |crate2nix_json: &crate::config::Config, name: &str| {
+        let package = indexed
+            .pkgs_by_id
+            .values()
+            .find(|p| p.name == name)
+            .unwrap();
+        CrateDerivation::resolve(&config, crate2nix_json, &indexed, package).map(|c| c.source)
+    };
+
+    let crate2nix_json: crate::config::Config = serde_json::from_str(
+        r#"{
+            "sources": {},
+            "sourceOverrides": {
+                "helper@0.2.0": { "type": "Path", "path": "forks/helper" },
+                "other": {
+                    "type": "Git",
+                    "url": "https://github.com/me/other.git",
+                    "rev": "0123456789abcdef0123456789abcdef01234567",
+                    "sha256": "abc"
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        resolve(&crate2nix_json, "helper").unwrap(),
+        ResolvedSource::LocalDirectory(LocalDirectorySource {
+            path: "./forks/helper".into(),
+            include: Vec::new(),
+            exclude: vec!["/ci".to_string()],
+            test_include: Vec::new(),
+        })
+    );
+    let ResolvedSource::Git(git) = resolve(&crate2nix_json, "other").unwrap() else {
+        panic!("expected a git source");
+    };
+    assert_eq!(git.sha256.as_deref(), Some("abc"));
+
+    let missing: crate::config::Config = serde_json::from_str(
+        r#"{ "sources": {}, "sourceOverrides": { "helper": { "type": "Path", "path": "gone" } } }"#,
+    )
+    .unwrap();
+    assert!(format!("{:#}", resolve(&missing, "helper").unwrap_err()).contains("no Cargo.toml"));
+
+    env.close();
+}
+
+#[test]
+pub fn generated_path_dependencies() {
+    let mut env = test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    let config = GenerateConfig {
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..test::generate_config()
+    };
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.add_dependency("generated")
+        .version_and_package_id("0.1.0");
+    let mut indexed = env.indexed_metadata();
+    let generated = indexed
+        .pkgs_by_id
+        .values()
+        .find(|p| p.name == "generated")
+        .unwrap()
+        .clone();
+    indexed.target_directories = vec![generated
+        .manifest_path
+        .parent()
+        .unwrap()
+        .as_std_path()
+        .to_path_buf()];
+
+    let error = CrateDerivation::resolve(
+        &config,
+        &crate::config::Config::default(),
+        &indexed,
+        &generated,
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("is generated into the target directory"),
+        "{:#}",
+        error
+    );
+
+    let crate2nix_json: crate::config::Config = serde_json::from_str(
+        r#"{
+            "sources": {},
+            "sourceOverrides": {
+                "generated": { "type": "Nix", "package": "./nix/generated.nix" }
+            }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        CrateDerivation::resolve(&config, &crate2nix_json, &indexed, &generated)
+            .unwrap()
+            .source,
+        ResolvedSource::Nix(NixSource {
+            file: crate::config::NixFile::Package("./nix/generated.nix".to_string()),
+            attr: None,
+        })
+    );
+
+    env.close();
+}
+
+#[test]
+pub fn crate_override_patches() {
+    let mut env = test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    std::fs::create_dir_all(dir.join("patches")).unwrap();
+    std::fs::write(dir.join("patches").join("helper.patch"), "").unwrap();
+    let config = GenerateConfig {
+        output: dir.join("nix").join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..test::generate_config()
+    };
+    std::fs::create_dir_all(dir.join("nix")).unwrap();
+
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.add_dependency("helper")
+        .version_and_package_id("0.2.0");
+    let indexed = env.indexed_metadata();
+    let helper = indexed
+        .pkgs_by_id
+        .values()
+        .find(|p| p.name == "helper")
+        .unwrap();
+    let resolve = |crate2nix_json: &str| {
+        let crate2nix_json: crate::config::Config = serde_json::from_str(crate2nix_json).unwrap();
+        CrateDerivation::resolve(&config, &crate2nix_json, &indexed, helper)
+            .map(|c| c.crate_override.unwrap())
+    };
+
+    let crate_override = resolve(
+        r#"{
+            "sources": {},
+            "crateOverrides": {
+                "helper": {
+                    "patches": [ "patches/helper.patch" ],
+                    "replacements": [ { "file": "build.rs", "from": "a", "to": "b" } ]
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        crate_override.patches,
+        vec![PathBuf::from("../patches/helper.patch")]
+    );
+    assert_eq!(crate_override.replacements[0].file, "build.rs");
+
+    let error = resolve(
+        r#"{ "sources": {}, "crateOverrides": { "helper": { "patches": [ "gone.patch" ] } } }"#,
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", error).contains("the patch gone.patch of helper"));
+
+    env.close();
+}
+
+#[test]
+pub fn cargo_source_replacement() {
+    let mut env = test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    let vendored = dir.join("vendor").join("helper");
+    std::fs::create_dir_all(&vendored).unwrap();
+    std::fs::create_dir_all(dir.join(".cargo")).unwrap();
+    std::fs::write(
+        dir.join(".cargo").join("config.toml"),
+        "[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n\
+         [source.vendored-sources]\ndirectory = \"vendor\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        vendored.join("Cargo.toml"),
+        "[package]\nname = \"helper\"\nversion = \"0.2.0\"\n",
+    )
+    .unwrap();
+    let config = GenerateConfig {
+        cargo_toml: vec![dir.join("Cargo.toml")],
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        ..test::generate_config()
+    };
+
+    let mut package = test::package("helper", "0.2.0");
+    package.source = Some(Source {
+        repr: "registry+https://github.com/rust-lang/crates.io-index".to_string(),
+    });
+    package.manifest_path = vendored.join("Cargo.toml").try_into().unwrap();
+    assert_eq!(
+        ResolvedSource::new(&config, &package, &vendored).unwrap(),
+        ResolvedSource::LocalDirectory(LocalDirectorySource {
+            path: "./vendor/helper".into(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            test_include: Vec::new(),
         })
     );
 
@@ -331,8 +835,673 @@ pub fn double_crate_with_rename() {
     env.close();
 }
 
+#[test]
+pub fn unresolved_crate_keeps_identity_and_problem() {
+    let mut env = test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    let indexed = env.indexed_metadata();
+    let root_package = &indexed.root_package().expect("root package");
+
+    let crate_derivation =
+        CrateDerivation::unresolved(&indexed, root_package, "broken".to_string());
+
+    assert_eq!(&*crate_derivation.crate_name, "main");
+    assert!(crate_derivation.is_root_or_workspace_member);
+    assert!(crate_derivation.dependencies.is_empty());
+    assert_eq!(crate_derivation.problem.as_deref(), Some("broken"));
+
+    env.close();
+}
+
+#[test]
+pub fn relative_directory_resolves_symlinks() {
+    let mut env = test::MetadataEnv::default();
+    let temp_dir = env.temp_dir();
+    let workspace = temp_dir.join("workspace");
+    let real_member = workspace.join("crates").join("real");
+    std::fs::create_dir_all(&real_member).unwrap();
+    std::os::unix::fs::symlink(&real_member, workspace.join("linked")).unwrap();
+    let workspace_alias = temp_dir.join("alias");
+    std::os::unix::fs::symlink(&workspace, &workspace_alias).unwrap();
+
+    let mut config = test::generate_config();
+
+    // A workspace member that is a symlink to another directory in the workspace.
+    config.output = workspace.join("Cargo.nix");
+    assert_eq!(
+        ResolvedSource::relative_directory(&config, workspace.join("linked")).unwrap(),
+        PathBuf::from("./crates/real")
+    );
+
+    // The whole workspace is reached through a symlink.
+    config.output = workspace_alias.join("Cargo.nix");
+    assert_eq!(
+        ResolvedSource::relative_directory(&config, workspace_alias.join("crates").join("real"))
+            .unwrap(),
+        PathBuf::from("./crates/real")
+    );
+    assert_eq!(
+        ResolvedSource::relative_directory(&config, &workspace_alias).unwrap(),
+        PathBuf::from("./.")
+    );
+
+    env.close();
+}
+
+#[test]
+pub fn local_directory_in_subdirectory() {
+    let local = |path: &str| {
+        ResolvedSource::LocalDirectory(LocalDirectorySource {
+            path: PathBuf::from(path),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            test_include: Vec::new(),
+        })
+    };
+    assert_eq!(local("./.").in_subdirectory(), local("../."));
+    assert_eq!(local("./sub").in_subdirectory(), local("../sub"));
+    assert_eq!(
+        local("../sibling").in_subdirectory(),
+        local("../../sibling")
+    );
+    assert_eq!(local("/abs").in_subdirectory(), local("/abs"));
+}
+
+/// Fails like cargo if two crates that link to the same native library, see `package.links`,
+/// are in the dependency graph of the same root crate or workspace member.
+///
+/// Cargo does not resolve such graphs, but the metadata of several projects can be merged into
+/// one, which would otherwise only fail when the build scripts of both crates are combined.
+pub fn check_links<'a>(
+    crates: &[CrateDerivation],
+    roots: impl IntoIterator<Item = &'a PackageId>,
+) -> Result<(), Error> {
+    if crates.iter().filter(|c| c.links.is_some()).count() < 2 {
+        return Ok(());
+    }
+    let by_id: HashMap<&PackageId, &CrateDerivation> =
+        crates.iter().map(|c| (&*c.package_id, c)).collect();
+    for root in roots {
+        let mut linking: BTreeMap<&str, &CrateDerivation> = BTreeMap::new();
+        let mut seen = HashSet::new();
+        let mut queue = vec![root];
+        while let Some(package_id) = queue.pop() {
+            if !seen.insert(package_id) {
+                continue;
+            }
+            let Some(crate_derivation) = by_id.get(package_id) else {
+                continue;
+            };
+            if let Some(links) = crate_derivation.links.as_deref() {
+                if let Some(previous) = linking.insert(links, crate_derivation) {
+                    bail!(
+                        "the package `{}` links to the native library `{}`, \
+                         but it conflicts with a previous package which links to `{}` as well:\n\
+                         package `{} v{}`\n\
+                         Only one package in the dependency graph of {} may specify the same \
+                         links value. This helps ensure that only one copy of a native library \
+                         is linked in the final binary.",
+                        crate_derivation.crate_name,
+                        links,
+                        links,
+                        previous.crate_name,
+                        previous.version,
+                        by_id
+                            .get(root)
+                            .map_or(root.repr.as_str(), |c| &c.crate_name)
+                    );
+                }
+            }
+            let dev_dependencies = if package_id == root {
+                crate_derivation.dev_dependencies.as_slice()
+            } else {
+                &[]
+            };
+            queue.extend(
+                crate_derivation
+                    .dependencies
+                    .iter()
+                    .chain(&crate_derivation.build_dependencies)
+                    .chain(dev_dependencies)
+                    .map(|d| &*d.package_id),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn links_conflicts() {
+    let mut env = test::MetadataEnv::default();
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.add_dependency("sys-a")
+        .update_package(|p| p.links = Some("z".to_string()));
+    main.add_dependency("sys-b")
+        .update_package(|p| p.links = Some("ssl".to_string()));
+    env.add_package_and_node("other")
+        .update_package(|p| p.links = Some("z".to_string()));
+    let indexed = env.indexed_metadata();
+    let crates = |names: &[&str]| -> Vec<CrateDerivation> {
+        indexed
+            .pkgs_by_id
+            .values()
+            .filter(|p| names.contains(&p.name.as_str()))
+            .map(|p| {
+                CrateDerivation::resolve(
+                    &test::generate_config(),
+                    &crate::config::Config::default(),
+                    &indexed,
+                    p,
+                )
+                .unwrap()
+            })
+            .collect()
+    };
+    let root = indexed.root.clone().unwrap();
+
+    let unrelated = crates(&["main", "sys-a", "sys-b", "other"]);
+    check_links(&unrelated, [&root]).unwrap();
+
+    let mut conflicting = crates(&["main", "sys-a", "sys-b", "other"]);
+    let other = conflicting
+        .iter()
+        .find(|c| &*c.crate_name == "other")
+        .map(|c| c.package_id.clone())
+        .unwrap();
+    let main = conflicting
+        .iter_mut()
+        .find(|c| &*c.crate_name == "main")
+        .unwrap();
+    let mut dependency = main.dependencies[0].clone();
+    dependency.package_id = other;
+    main.build_dependencies.push(dependency);
+    let error = check_links(&conflicting, [&root]).unwrap_err().to_string();
+    assert!(
+        error.contains("links to the native library `z`") && error.contains("graph of main"),
+        "{}",
+        error
+    );
+
+    env.close();
+}
+
+/// Points local crates outside of the output directory to the `sourceRoot` argument of
+/// `Cargo.nix` and returns its default value relative to the output directory.
+///
+/// Relative paths like `../../other-repo/my-crate` only work if the parent directories of
+/// `Cargo.nix` are accessible, which is not the case e.g. for flakes. With the crates
+/// referenced relative to `sourceRoot`, users can override it with a directory that is.
+pub fn use_source_root_for_out_of_tree_crates(
+    crates: &mut [CrateDerivation],
+    config: &GenerateConfig,
+) -> Result<Option<PathBuf>, Error> {
+    let is_out_of_tree = |crate_derivation: &CrateDerivation| {
+        matches!(&crate_derivation.source, ResolvedSource::LocalDirectory(source)
+            if source.path.starts_with(".."))
+    };
+    if !crates.iter().any(is_out_of_tree) {
+        return Ok(None);
+    }
+
+    let output_dir = canonical_output_dir(config)?;
+
+    let out_of_tree_dirs: Vec<(usize, PathBuf)> = crates
+        .iter()
+        .enumerate()
+        .filter(|(_, crate_derivation)| is_out_of_tree(crate_derivation))
+        .filter_map(|(index, crate_derivation)| match &crate_derivation.source {
+            ResolvedSource::LocalDirectory(source) => {
+                Some((index, normalize_path(&output_dir.join(&source.path))))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let source_root = match config.source_root.as_ref() {
+        Some(source_root) => source_root.canonicalize().map_err(|e| {
+            format_err!(
+                "could not canonicalize source root '{}': {}",
+                source_root.to_string_lossy(),
+                e
+            )
+        })?,
+        None => out_of_tree_dirs
+            .iter()
+            .fold(output_dir.clone(), |ancestor, (_, dir)| {
+                ancestor
+                    .ancestors()
+                    .find(|candidate| dir.starts_with(candidate))
+                    .map(Path::to_path_buf)
+                    .unwrap_or(ancestor)
+            }),
+    };
+
+    for (index, dir) in out_of_tree_dirs {
+        let relative = dir.strip_prefix(&source_root).map_err(|_| {
+            format_err!(
+                "{} at {} is not inside the source root {}.",
+                crates[index].package_id,
+                dir.to_string_lossy(),
+                source_root.to_string_lossy()
+            )
+        })?;
+        let path = if relative.as_os_str().is_empty() {
+            "sourceRoot".to_string()
+        } else {
+            format!(
+                "(sourceRoot + {})",
+                crate::render::escape_nix_string(&format!("/{}", nix_path(relative)?))
+            )
+        };
+        if let ResolvedSource::LocalDirectory(source) = &mut crates[index].source {
+            source.path = PathBuf::from(path);
+        }
+    }
+
+    let relative_root = diff_paths(&source_root, &output_dir).unwrap_or(source_root);
+    relative_nix_path(&relative_root).map(Some)
+}
+
+/// Returns the directory of the only `Cargo.toml` as nix expression, relative to the output
+/// directory or, if it is outside of it and there is one, to `sourceRoot`.
+pub fn workspace_root(
+    config: &GenerateConfig,
+    source_root: Option<&Path>,
+) -> Result<Option<PathBuf>, Error> {
+    let [cargo_toml] = config.cargo_toml.as_slice() else {
+        return Ok(None);
+    };
+    let dir = match cargo_toml.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let dir = dir.canonicalize().map_err(|e| {
+        format_err!(
+            "could not canonicalize workspace directory '{}': {}",
+            dir.to_string_lossy(),
+            e
+        )
+    })?;
+    let output_dir = canonical_output_dir(config)?;
+    let relative = diff_paths(&dir, &output_dir).unwrap_or_else(|| dir.clone());
+    if let Some(source_root) = source_root.filter(|_| relative.starts_with("..")) {
+        let source_root = normalize_path(&output_dir.join(source_root));
+        if let Ok(inner) = dir.strip_prefix(&source_root) {
+            return Ok(Some(PathBuf::from(if inner.as_os_str().is_empty() {
+                "sourceRoot".to_string()
+            } else {
+                format!(
+                    "(sourceRoot + {})",
+                    crate::render::escape_nix_string(&format!("/{}", nix_path(inner)?))
+                )
+            })));
+        }
+    }
+    relative_nix_path(&relative).map(Some)
+}
+
+#[test]
+fn test_workspace_root() {
+    let dir = tempdir::TempDir::new("crate2nix_workspace_root").expect("temp dir");
+    std::fs::create_dir(dir.path().join("nix")).unwrap();
+    let mut config = crate::test::generate_config();
+    config.cargo_toml = vec![dir.path().join("Cargo.toml")];
+    config.output = dir.path().join("nix/Cargo.nix");
+
+    let root = |config: &GenerateConfig, source_root: Option<&str>| {
+        workspace_root(config, source_root.map(Path::new))
+            .unwrap()
+            .map(|root| root.to_string_lossy().into_owned())
+    };
+    assert_eq!(root(&config, None).as_deref(), Some("../."));
+    assert_eq!(root(&config, Some("../.")).as_deref(), Some("sourceRoot"));
+
+    config.output = dir.path().join("Cargo.nix");
+    assert_eq!(root(&config, None).as_deref(), Some("./."));
+    config.cargo_toml.push(dir.path().join("other/Cargo.toml"));
+    assert_eq!(root(&config, None), None);
+}
+
+/// The canonical directory of the output file.
+fn canonical_output_dir(config: &GenerateConfig) -> Result<PathBuf, Error> {
+    let output_dir = match config.output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    output_dir.canonicalize().map_err(|e| {
+        format_err!(
+            "could not canonicalize output file directory '{}': {}",
+            output_dir.to_string_lossy(),
+            e
+        )
+    })
+}
+
+/// Renders `path` as it appears in Nix expressions: valid UTF-8 with `/` as separator.
+///
+/// Nix only understands forward slashes, so the components of the path are joined with `/`,
+/// e.g. for paths computed on Windows. `\` is only a separator where the platform treats it as
+/// one, on unix it is part of the file name. Paths on another drive cannot be expressed relative
+/// to the output.
+fn nix_path(path: &Path) -> Result<String, Error> {
+    use std::path::Component;
+
+    let mut rendered = String::new();
+    for component in path.components() {
+        let part = match component {
+            Component::Prefix(_) => bail!(
+                "the path {} is on a different drive than the output, which Nix cannot represent",
+                path.display()
+            ),
+            Component::RootDir => {
+                rendered.push('/');
+                continue;
+            }
+            Component::CurDir => ".",
+            Component::ParentDir => "..",
+            Component::Normal(name) => name.to_str().ok_or_else(|| {
+                format_err!(
+                    "the path {} is not valid UTF-8, which Nix cannot represent",
+                    path.display()
+                )
+            })?,
+        };
+        if !rendered.is_empty() && !rendered.ends_with('/') {
+            rendered.push('/');
+        }
+        rendered.push_str(part);
+    }
+    Ok(rendered)
+}
+
+/// Renders a path relative to the output directory as a Nix path literal, e.g. `./sub`,
+/// `../sibling` or `./.`.
+///
+/// File names with characters that path literals cannot contain, e.g. spaces or `\`, are
+/// appended to the literal as a string, e.g. `(./. + "/my crate")`.
+fn relative_nix_path(path: &Path) -> Result<PathBuf, Error> {
+    let path = nix_path(path)?;
+    let path = path.trim_end_matches('/');
+    let literal = if path.is_empty() || path == "." {
+        "./.".to_string()
+    } else if path == ".." {
+        // Nix path literals need at least one slash.
+        "../.".to_string()
+    } else if path.starts_with("../") || path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("./{}", path.strip_prefix("./").unwrap_or(path))
+    };
+
+    let is_literal = |segment: &str| {
+        segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._+-".contains(c))
+    };
+    let segments: Vec<&str> = literal.split('/').collect();
+    let Some(first_string) = segments.iter().position(|segment| !is_literal(segment)) else {
+        return Ok(PathBuf::from(literal));
+    };
+    let base = match segments[..first_string].join("/").as_str() {
+        "" => "/.".to_string(),
+        "." => "./.".to_string(),
+        ".." => "../.".to_string(),
+        base => base.to_string(),
+    };
+    let rest = format!("/{}", segments[first_string..].join("/"));
+    Ok(PathBuf::from(format!(
+        "({} + {})",
+        base,
+        crate::render::escape_nix_string(&rest)
+    )))
+}
+
+#[test]
+fn test_relative_nix_path() {
+    let relative = |path: &str| relative_nix_path(Path::new(path)).unwrap();
+    assert_eq!(relative(""), PathBuf::from("./."));
+    assert_eq!(relative(".."), PathBuf::from("../."));
+    assert_eq!(relative("../"), PathBuf::from("../."));
+    assert_eq!(relative("sub/crate"), PathBuf::from("./sub/crate"));
+    assert_eq!(relative("../../sibling"), PathBuf::from("../../sibling"));
+    assert_eq!(relative("/nix/store/src"), PathBuf::from("/nix/store/src"));
+    assert_eq!(
+        relative("../my crates/a"),
+        PathBuf::from(r#"(../. + "/my crates/a")"#)
+    );
+    assert_eq!(
+        relative("sub/my crate"),
+        PathBuf::from(r#"(./sub + "/my crate")"#)
+    );
+    assert_eq!(
+        one_level_up(r#"(./. + "/my crate")"#).as_deref(),
+        Some(r#"(../. + "/my crate")"#)
+    );
+    assert_eq!(one_level_up(r#"(sourceRoot + "/a")"#), None);
+
+    #[cfg(windows)]
+    {
+        assert_eq!(relative(r"crates\a"), PathBuf::from("./crates/a"));
+        assert_eq!(relative(r"..\"), PathBuf::from("../."));
+        assert_eq!(
+            relative(r"..\other\crates\a"),
+            PathBuf::from("../other/crates/a")
+        );
+        let other_drive = relative_nix_path(Path::new(r"D:\projects\a")).unwrap_err();
+        assert!(
+            other_drive.to_string().contains("different drive"),
+            "{}",
+            other_drive
+        );
+    }
+
+    #[cfg(unix)]
+    {
+        // Backslashes and colons are part of file names.
+        assert_eq!(
+            relative(r"crates\a"),
+            PathBuf::from(r#"(./. + "/crates\\a")"#)
+        );
+        assert_eq!(
+            relative("D:/projects"),
+            PathBuf::from(r#"(./. + "/D:/projects")"#)
+        );
+
+        use std::os::unix::ffi::OsStrExt;
+        let invalid = std::ffi::OsStr::from_bytes(b"crates/\xff");
+        let error = relative_nix_path(Path::new(invalid)).unwrap_err();
+        assert!(error.to_string().contains("not valid UTF-8"), "{}", error);
+    }
+}
+
+/// Removes `.` and `..` components without touching the file system.
+fn normalize_path(path: &Path) -> PathBuf {
+    use std::path::Component;
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[test]
+pub fn out_of_tree_crates_use_source_root() {
+    let mut env = test::MetadataEnv::default();
+    let temp_dir = env.temp_dir();
+    let project = temp_dir.join("repos").join("project");
+    let other = temp_dir
+        .join("repos")
+        .join("other")
+        .join("crates")
+        .join("dep");
+    std::fs::create_dir_all(&project).unwrap();
+    std::fs::create_dir_all(&other).unwrap();
+
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("dep");
+    let indexed = env.indexed_metadata();
+    let local = |path: &str| {
+        ResolvedSource::LocalDirectory(LocalDirectorySource {
+            path: PathBuf::from(path),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            test_include: Vec::new(),
+        })
+    };
+    let mut crates: Vec<CrateDerivation> = indexed
+        .pkgs_by_id
+        .values()
+        .map(|package| {
+            let mut crate_derivation =
+                CrateDerivation::unresolved(&indexed, package, String::new());
+            crate_derivation.source = match package.name.as_str() {
+                "dep" => local("../other/crates/dep"),
+                _ => local("./."),
+            };
+            crate_derivation
+        })
+        .collect();
+
+    let mut config = test::generate_config();
+    config.output = project.join("Cargo.nix");
+    let source_root = use_source_root_for_out_of_tree_crates(&mut crates, &config).unwrap();
+
+    assert_eq!(source_root, Some(PathBuf::from("../.")));
+    for crate_derivation in &crates {
+        let expected = match &*crate_derivation.crate_name {
+            "dep" => local("(sourceRoot + \"/other/crates/dep\")"),
+            _ => local("./."),
+        };
+        assert_eq!(crate_derivation.source, expected);
+    }
+
+    // A configured source root must contain all out-of-tree crates.
+    config.source_root = Some(project.clone());
+    crates
+        .iter_mut()
+        .filter(|c| &*c.crate_name == "dep")
+        .for_each(|c| c.source = local("../other/crates/dep"));
+    assert!(use_source_root_for_out_of_tree_crates(&mut crates, &config).is_err());
+
+    env.close();
+}
+
+/// Collects sources that are used by more than one crate and points these crates to the
+/// shared source by setting their `shared_source` key.
+///
+/// This is the case for multiple crates from the same git checkout. Otherwise, every crate
+/// would render its own, identical `fetchgit` call.
+pub fn share_duplicate_sources(crates: &mut [CrateDerivation]) -> BTreeMap<String, ResolvedSource> {
+    let mut users: HashMap<ResolvedSource, Vec<usize>> = HashMap::new();
+    for (index, crate_derivation) in crates.iter().enumerate() {
+        if crate_derivation.problem.is_some() {
+            continue;
+        }
+        if let ResolvedSource::Git(GitSource {
+            sha256: Some(_), ..
+        }) = crate_derivation.source
+        {
+            users
+                .entry(crate_derivation.source.clone())
+                .or_default()
+                .push(index);
+        }
+    }
+
+    let mut shared_sources = BTreeMap::new();
+    let mut shared: Vec<_> = users
+        .into_iter()
+        .filter(|(_, indices)| indices.len() > 1)
+        .collect();
+    // Keep the keys stable between runs.
+    shared.sort_by_key(|(_, indices)| indices[0]);
+    for (source, indices) in shared {
+        let ResolvedSource::Git(git) = &source else {
+            unreachable!("only git sources are shared");
+        };
+        let repo_name = git
+            .url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|name| name.trim_end_matches(".git"))
+            .filter(|name| !name.is_empty())
+            .unwrap_or("git");
+        let rev: String = git.rev.chars().take(12).collect();
+        let mut key = format!("{}-{}", repo_name, rev);
+        let mut suffix = 1;
+        while shared_sources.contains_key(&key) {
+            suffix += 1;
+            key = format!("{}-{}-{}", repo_name, rev, suffix);
+        }
+        for index in indices {
+            crates[index].shared_source = Some(key.clone());
+        }
+        shared_sources.insert(key, source);
+    }
+    shared_sources
+}
+
+#[test]
+pub fn crates_from_same_git_checkout_share_source() {
+    let mut env = test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("first");
+    env.add_package_and_node("second");
+    env.add_package_and_node("other");
+    let indexed = env.indexed_metadata();
+
+    let git_source = |url: &str| {
+        ResolvedSource::Git(GitSource {
+            url: Url::parse(url).unwrap(),
+            rev: "0123456789abcdef0123".to_string(),
+            r#ref: None,
+            sha256: Some("sha".to_string()),
+            fetch_submodules: false,
+        })
+    };
+    let mut crates: Vec<CrateDerivation> = indexed
+        .pkgs_by_id
+        .values()
+        .map(|package| {
+            let mut crate_derivation =
+                CrateDerivation::unresolved(&indexed, package, String::new());
+            crate_derivation.problem = None;
+            crate_derivation.source = match package.name.as_str() {
+                "first" | "second" => git_source("https://github.com/some/repo.git"),
+                "other" => git_source("https://github.com/some/other"),
+                _ => crate_derivation.source,
+            };
+            crate_derivation
+        })
+        .collect();
+
+    let shared_sources = share_duplicate_sources(&mut crates);
+
+    assert_eq!(
+        shared_sources.keys().collect::<Vec<_>>(),
+        vec!["repo-0123456789ab"]
+    );
+    for crate_derivation in &crates {
+        let expected = match &*crate_derivation.crate_name {
+            "first" | "second" => Some("repo-0123456789ab"),
+            _ => None,
+        };
+        assert_eq!(crate_derivation.shared_source.as_deref(), expected);
+    }
+
+    env.close();
+}
+
 /// A build target of a crate.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BuildTarget {
     /// The name of the build target.
     pub name: String,
@@ -344,23 +1513,68 @@ pub struct BuildTarget {
 }
 
 impl BuildTarget {
+    /// Records `target` with its source path relative to the canonical `package_path`.
     pub fn new(target: &Target, package_path: impl AsRef<Path>) -> Result<BuildTarget, Error> {
         Ok(BuildTarget {
             name: target.name.clone(),
-            src_path: target
-                .src_path
-                .canonicalize()?
-                .strip_prefix(&package_path)?
-                .to_path_buf(),
+            src_path: relative_src_path(target.src_path.as_std_path(), package_path.as_ref())?,
             required_features: target.required_features.clone(),
         })
     }
 }
 
+/// Returns the path of a target source file relative to the canonical package directory.
+///
+/// Only the directory of `src_path` is canonicalized, so that a symlinked source file keeps
+/// its own name, e.g. for `path = "src/tools/foo.rs"` in `[[bin]]`.
+fn relative_src_path(src_path: &Path, package_path: &Path) -> Result<PathBuf, Error> {
+    let (Some(dir), Some(file_name)) = (src_path.parent(), src_path.file_name()) else {
+        bail!("invalid target path {}", src_path.display());
+    };
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| format_err!("while canonicalizing {}: {}", dir.display(), e))?;
+    match dir.strip_prefix(package_path) {
+        Ok(relative_dir) => Ok(nix_path(&relative_dir.join(file_name))?.into()),
+        Err(_) => bail!(
+            "{} is outside of the package directory {}, which is not supported by buildRustCrate",
+            src_path.display(),
+            package_path.display()
+        ),
+    }
+}
+
+#[test]
+fn test_relative_src_path() {
+    let dir = tempdir::TempDir::new("crate2nix_targets").expect("temp dir");
+    let package_path = dir.path().join("main");
+    std::fs::create_dir_all(package_path.join("src/tools")).unwrap();
+    std::fs::create_dir_all(dir.path().join("shared")).unwrap();
+    let package_path = package_path.canonicalize().unwrap();
+
+    assert_eq!(
+        relative_src_path(&package_path.join("src/tools/foo.rs"), &package_path).unwrap(),
+        PathBuf::from("src/tools/foo.rs")
+    );
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(package_path.join("src"), dir.path().join("linked")).unwrap();
+        assert_eq!(
+            relative_src_path(&dir.path().join("linked/main.rs"), &package_path).unwrap(),
+            PathBuf::from("src/main.rs")
+        );
+    }
+    let outside = relative_src_path(&package_path.join("../shared/main.rs"), &package_path);
+    assert!(outside.unwrap_err().to_string().contains("outside"));
+
+    dir.close().unwrap();
+}
+
 /// Specifies how to retrieve the source code.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub enum ResolvedSource {
     CratesIo(CratesIoSource),
+    Registry(RegistrySource),
     Git(GitSource),
     LocalDirectory(LocalDirectorySource),
     Nix(NixSource),
@@ -374,6 +1588,8 @@ impl From<crate::config::Source> for ResolvedSource {
                 rev,
                 r#ref: None,
                 sha256: Some(sha256),
+                // `crate2nix source add git` prefetches with submodules.
+                fetch_submodules: true,
             }),
             crate::config::Source::CratesIo {
                 name,
@@ -383,6 +1599,7 @@ impl From<crate::config::Source> for ResolvedSource {
                 name,
                 version,
                 sha256: Some(sha256),
+                mirror: None,
             }),
             crate::config::Source::Nix { file, attr } => {
                 ResolvedSource::Nix(NixSource { file, attr })
@@ -391,24 +1608,214 @@ impl From<crate::config::Source> for ResolvedSource {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
-pub struct CratesIoSource {
-    pub name: String,
-    pub version: Version,
-    pub sha256: Option<String>,
-}
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct CratesIoSource {
+    pub name: String,
+    pub version: Version,
+    pub sha256: Option<String>,
+    /// The download URL template of a crates.io mirror, see `GenerateConfig::crates_io_mirror`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<String>,
+}
+
+/// A crate from a registry other than crates.io.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct RegistrySource {
+    /// The source ID of the registry, e.g. `sparse+https://my.registry/index/`.
+    pub index: String,
+    pub name: String,
+    pub version: Version,
+    /// The download URL, expanded from the `dl` template of the registry.
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+impl RegistrySource {
+    /// Expands the `{sha256-checksum}` marker in the URL with the hex checksum from `Cargo.lock`.
+    pub fn with_checksum(&self, checksum: &str) -> RegistrySource {
+        RegistrySource {
+            url: self.url.replace(CHECKSUM_MARKER, checksum),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct GitSource {
+    pub url: Url,
+    pub rev: String,
+    pub r#ref: Option<String>,
+    pub sha256: Option<String>,
+    /// Whether the repository has submodules that need to be fetched as well.
+    #[serde(default)]
+    pub fetch_submodules: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct LocalDirectorySource {
+    path: PathBuf,
+    /// `package.include` from `Cargo.toml`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    /// `package.exclude` from `Cargo.toml`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<String>,
+    /// Additional globs for the source of test runs, see `with_test_globs`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    test_include: Vec<String>,
+}
+
+impl LocalDirectorySource {
+    /// The path of the crate as rendered, e.g. `./crates/a` or `(sourceRoot + "/other")`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the `include` and `exclude` globs of the `[package]` section in the given manifest.
+    ///
+    /// Like `cargo package`, we use these to restrict the files that become part of the source.
+    fn with_globs_from_manifest(
+        path: PathBuf,
+        manifest_path: &Path,
+    ) -> Result<LocalDirectorySource, Error> {
+        if !manifest_path.exists() {
+            // Synthetic metadata, e.g. in tests.
+            return Ok(LocalDirectorySource {
+                path,
+                include: Vec::new(),
+                exclude: Vec::new(),
+                test_include: Vec::new(),
+            });
+        }
+        let content = std::fs::read_to_string(manifest_path)
+            .map_err(|e| format_err!("while reading {}: {}", manifest_path.display(), e))?;
+        let manifest: toml::Table = toml::from_str(&content)
+            .map_err(|e| format_err!("while parsing {}: {}", manifest_path.display(), e))?;
+        let globs = |key: &str| -> Vec<String> {
+            let Some(value) = manifest.get("package").and_then(|package| package.get(key)) else {
+                return Vec::new();
+            };
+            match value.as_array() {
+                Some(globs) => globs
+                    .iter()
+                    .filter_map(|glob| glob.as_str().map(str::to_string))
+                    .collect(),
+                None => {
+                    warn!(
+                        "Ignoring package.{} in {}: only plain lists of globs are supported.",
+                        key,
+                        manifest_path.display()
+                    );
+                    Vec::new()
+                }
+            }
+        };
+        Ok(LocalDirectorySource {
+            path,
+            include: globs("include"),
+            exclude: globs("exclude"),
+            test_include: Vec::new(),
+        })
+    }
+
+    /// Adds the globs, e.g. from `[package.metadata.crate2nix]`.
+    fn with_globs(mut self, include: &[String], exclude: &[String]) -> LocalDirectorySource {
+        self.include.extend(include.iter().cloned());
+        self.exclude.extend(exclude.iter().cloned());
+        self
+    }
+
+    /// Sets `test_include` to the `tests` and `benches` directories and the directories of the
+    /// declared test and bench targets of `package`, if `include` or `exclude` restrict the source.
+    fn with_test_globs(self, package: &Package) -> LocalDirectorySource {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return self;
+        }
+        let package_path = package
+            .manifest_path
+            .parent()
+            .and_then(|dir| dir.canonicalize().ok());
+        let mut test_include: BTreeSet<String> = ["/tests", "/benches"]
+            .iter()
+            .map(|g| g.to_string())
+            .collect();
+        for target in &package.targets {
+            if !target.kind.iter().any(|k| k == "test" || k == "bench") {
+                continue;
+            }
+            let Some(src_path) = package_path
+                .as_ref()
+                .and_then(|dir| relative_src_path(target.src_path.as_std_path(), dir).ok())
+            else {
+                continue;
+            };
+            let glob = match src_path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => &src_path,
+            };
+            test_include.insert(format!("/{}", glob.display()));
+        }
+        LocalDirectorySource {
+            test_include: test_include.into_iter().collect(),
+            ..self
+        }
+    }
+}
+
+#[test]
+fn test_local_directory_globs_from_manifest() {
+    let dir = tempdir::TempDir::new("crate2nix_globs").expect("temp dir");
+    let manifest_path = dir.path().join("Cargo.toml");
+    std::fs::write(
+        &manifest_path,
+        r#"
+        [package]
+        name = "globs"
+        version = "0.1.0"
+        exclude = ["/ci", "*.png"]
+        "#,
+    )
+    .unwrap();
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
-pub struct GitSource {
-    pub url: Url,
-    pub rev: String,
-    pub r#ref: Option<String>,
-    pub sha256: Option<String>,
+    let source =
+        LocalDirectorySource::with_globs_from_manifest("./.".into(), &manifest_path).unwrap();
+    assert!(source.include.is_empty());
+    assert_eq!(source.exclude, vec!["/ci", "*.png"]);
+
+    dir.close().unwrap();
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
-pub struct LocalDirectorySource {
-    path: PathBuf,
+#[test]
+fn test_local_directory_test_globs() {
+    let mut package = test::package("fixtures", "0.1.0");
+    let dir = package
+        .manifest_path
+        .parent()
+        .unwrap()
+        .as_std_path()
+        .to_path_buf();
+    std::fs::create_dir_all(dir.join("integration")).unwrap();
+    let mut integration = test::bin_target(&package, "main");
+    integration.kind = vec!["test".to_string()];
+    integration.src_path = dir.join("integration/main.rs").try_into().unwrap();
+    package.targets = vec![test::bin_target(&package, "main"), integration];
+    let source = |exclude: Vec<String>| LocalDirectorySource {
+        path: "./.".into(),
+        include: Vec::new(),
+        exclude,
+        test_include: Vec::new(),
+    };
+
+    assert!(source(Vec::new())
+        .with_test_globs(&package)
+        .test_include
+        .is_empty());
+    assert_eq!(
+        source(vec!["/integration".to_string()])
+            .with_test_globs(&package)
+            .test_include,
+        vec!["/benches", "/integration", "/tests"]
+    );
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
@@ -417,14 +1824,65 @@ pub struct NixSource {
     attr: Option<String>,
 }
 
+/// Where the source of a crate comes from, see `ResolvedSource::provenance`.
+///
+/// It becomes `provenance` of the crate in `Cargo.nix` and `meta.provenance` of its derivation.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SourceProvenance {
+    /// `crates-io`, `registry`, `git`, `local` or `nix`.
+    pub kind: String,
+    /// The download URL or the URL of the git repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The source ID of the index of an alternative registry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// The git commit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    /// The git branch or tag from `Cargo.toml`, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#ref: Option<String>,
+    /// The path of a local crate as in `Cargo.nix`, or the nix expression of a nix source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
 const GIT_SOURCE_PREFIX: &str = "git+";
 
+/// Adjusts a relative path for rendering into a file one directory below the output, `None` if
+/// the path is absolute.
+pub(crate) fn one_level_up(path: &str) -> Option<String> {
+    if let Some(expression) = path.strip_prefix('(') {
+        // A path literal with a string appended, see `relative_nix_path`.
+        return one_level_up(expression).map(|expression| format!("({}", expression));
+    }
+    if path.starts_with("./") || path.starts_with("../") {
+        Some(format!("../{}", path.strip_prefix("./").unwrap_or(path)))
+    } else {
+        None
+    }
+}
+
 impl ResolvedSource {
     pub fn new(
         config: &GenerateConfig,
         package: &Package,
         package_path: impl AsRef<Path>,
     ) -> Result<ResolvedSource, Error> {
+        if let Some(source) = package.source.as_ref() {
+            if let Some(replaced) =
+                crate::cargo_config::replaced_source(&config.cargo_toml, &source.repr)?
+            {
+                return ResolvedSource::from_replacement(
+                    config,
+                    package,
+                    package_path,
+                    source,
+                    replaced,
+                );
+            }
+        }
         match package.source.as_ref() {
             Some(source) if source.is_crates_io() => {
                 // Will sha256 will be filled later by prefetch_and_fill_crates_sha256.
@@ -432,14 +1890,148 @@ impl ResolvedSource {
                     name: package.name.clone(),
                     version: package.version.clone(),
                     sha256: None,
+                    mirror: config.crates_io_mirror.clone(),
                 }))
             }
+            Some(source)
+                if source.repr.starts_with("registry+") || source.repr.starts_with("sparse+") =>
+            {
+                match crate::registry::download_template(&source.repr) {
+                    Ok(template) => Ok(ResolvedSource::Registry(RegistrySource {
+                        index: source.repr.clone(),
+                        name: package.name.clone(),
+                        version: package.version.clone(),
+                        url: download_url(&template, &package.name, &package.version.to_string()),
+                        sha256: None,
+                    })),
+                    Err(e) => ResolvedSource::fallback_to_local_directory(
+                        config,
+                        package,
+                        &package_path,
+                        &format!("{:#}.", e),
+                    ),
+                }
+            }
             Some(source) => {
                 ResolvedSource::git_or_local_directory(config, package, &package_path, source)
             }
-            None => Ok(ResolvedSource::LocalDirectory(LocalDirectorySource {
-                path: ResolvedSource::relative_directory(config, package_path)?,
+            None => Ok(ResolvedSource::LocalDirectory(
+                LocalDirectorySource::with_globs_from_manifest(
+                    ResolvedSource::relative_directory(config, package_path)?,
+                    package.manifest_path.as_ref(),
+                )?
+                .with_test_globs(package),
+            )),
+        }
+    }
+
+    /// The source that replaces the source of `package` in `.cargo/config.toml`.
+    fn from_replacement(
+        config: &GenerateConfig,
+        package: &Package,
+        package_path: impl AsRef<Path>,
+        source: &Source,
+        replaced: crate::cargo_config::ReplacedSource,
+    ) -> Result<ResolvedSource, Error> {
+        use crate::cargo_config::ReplacedSource;
+
+        match replaced {
+            ReplacedSource::Directory(directory) => {
+                debug!(
+                    "Using {} from the directory source {}.",
+                    package.id,
+                    directory.display()
+                );
+                Ok(ResolvedSource::LocalDirectory(
+                    LocalDirectorySource::with_globs_from_manifest(
+                        ResolvedSource::relative_directory(config, package_path)?,
+                        package.manifest_path.as_ref(),
+                    )?,
+                ))
+            }
+            ReplacedSource::LocalRegistry(directory) => {
+                ResolvedSource::fallback_to_local_directory(
+                    config,
+                    package,
+                    &package_path,
+                    &format!(
+                        "The local registry {} is not supported.",
+                        directory.display()
+                    ),
+                )
+            }
+            ReplacedSource::Registry(registry) => {
+                match crate::registry::download_template(&registry) {
+                    // Cargo.lock and the vendored sources refer to the replaced source.
+                    Ok(template) => Ok(ResolvedSource::Registry(RegistrySource {
+                        index: source.repr.clone(),
+                        name: package.name.clone(),
+                        version: package.version.clone(),
+                        url: download_url(&template, &package.name, &package.version.to_string()),
+                        sha256: None,
+                    })),
+                    Err(e) => ResolvedSource::fallback_to_local_directory(
+                        config,
+                        package,
+                        &package_path,
+                        &format!("{:#}.", e),
+                    ),
+                }
+            }
+        }
+    }
+
+    /// The source configured in the `sourceOverrides` of `crate2nix.json`.
+    fn from_override(
+        config: &GenerateConfig,
+        package: &Package,
+        source_override: &crate::config::SourceOverride,
+    ) -> Result<ResolvedSource, Error> {
+        warn!(
+            "Using {} for {} {} as configured in sourceOverrides.",
+            source_override, package.name, package.version
+        );
+        match source_override {
+            crate::config::SourceOverride::Path { path } => {
+                let crate2nix_json = crate::crate2nix_json_path(config);
+                let directory = crate2nix_json
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."))
+                    .join(path);
+                let manifest_path = directory.join("Cargo.toml");
+                if !manifest_path.exists() {
+                    bail!(
+                        "the source override {} of {} in {} contains no Cargo.toml",
+                        path.display(),
+                        package.name,
+                        crate2nix_json.display()
+                    );
+                }
+                Ok(ResolvedSource::LocalDirectory(
+                    LocalDirectorySource::with_globs_from_manifest(
+                        ResolvedSource::relative_directory(config, &directory)?,
+                        &manifest_path,
+                    )?,
+                ))
+            }
+            crate::config::SourceOverride::Git {
+                url,
+                rev,
+                sha256,
+                fetch_submodules,
+            } => Ok(ResolvedSource::Git(GitSource {
+                url: url.clone(),
+                rev: rev.clone(),
+                r#ref: None,
+                sha256: Some(sha256.clone()),
+                fetch_submodules: *fetch_submodules,
             })),
+            crate::config::SourceOverride::Nix { file, attr } => {
+                Ok(ResolvedSource::Nix(NixSource {
+                    file: file.clone(),
+                    attr: attr.clone(),
+                }))
+            }
         }
     }
 
@@ -459,16 +2051,11 @@ impl ResolvedSource {
             );
         }
         let mut url = url::Url::parse(&source_string[GIT_SOURCE_PREFIX.len()..])?;
-        let mut query_pairs = url.query_pairs();
-
-        let branch = query_pairs
+        let branch = url
+            .query_pairs()
             .find(|(k, _)| k == "branch")
             .map(|(_, v)| v.to_string());
-        let rev = if let Some((_, rev)) = query_pairs.find(|(k, _)| k == "rev") {
-            rev.to_string()
-        } else if let Some(rev) = url.fragment() {
-            rev.to_string()
-        } else {
+        let Some(rev) = locked_git_rev(&url) else {
             return ResolvedSource::fallback_to_local_directory(
                 config,
                 package,
@@ -476,6 +2063,13 @@ impl ResolvedSource {
                 "No git revision found.",
             );
         };
+        if !is_commit_hash(&rev) {
+            warn!(
+                "{} is not pinned to a commit but to '{}'. \
+                 The build is not reproducible if the reference moves.",
+                package.id, rev
+            );
+        }
         url.set_query(None);
         url.set_fragment(None);
         Ok(ResolvedSource::Git(GitSource {
@@ -483,6 +2077,7 @@ impl ResolvedSource {
             rev,
             r#ref: branch,
             sha256: None,
+            fetch_submodules: has_git_submodules(package_path.as_ref()),
         }))
     }
 
@@ -493,8 +2088,8 @@ impl ResolvedSource {
         warning: &str,
     ) -> Result<ResolvedSource, Error> {
         let path = Self::relative_directory(config, package_path)?;
-        eprintln!(
-            "WARNING: {} Falling back to local directory for crate {} with source {}: {}",
+        warn!(
+            "{} Falling back to local directory for crate {} with source {}: {}",
             warning,
             package.id,
             package
@@ -506,9 +2101,34 @@ impl ResolvedSource {
         );
         Ok(ResolvedSource::LocalDirectory(LocalDirectorySource {
             path,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            test_include: Vec::new(),
         }))
     }
 
+    /// The path of a patch of `crateOverrides` relative to the output, checking that it exists.
+    fn patch_path(
+        config: &GenerateConfig,
+        package: &Package,
+        patch: &Path,
+    ) -> Result<PathBuf, Error> {
+        let crate2nix_json = crate::crate2nix_json_path(config);
+        let path = crate2nix_json
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(patch);
+        if !path.is_file() {
+            bail!(
+                "the patch {} of {} in {} does not exist",
+                patch.display(),
+                package.name,
+                crate2nix_json.display()
+            );
+        }
+        ResolvedSource::relative_directory(config, &path)
+    }
+
     fn relative_directory(
         config: &GenerateConfig,
         package_path: impl AsRef<Path>,
@@ -539,26 +2159,91 @@ impl ResolvedSource {
             )
         })?;
 
-        Ok(if package_path.as_ref() == output_build_file_directory {
-            "./.".into()
-        } else {
-            let path = diff_paths(package_path.as_ref(), &output_build_file_directory)
-                .unwrap_or_else(|| package_path.as_ref().to_path_buf());
-            if path == PathBuf::from("../") {
-                path.join(PathBuf::from("."))
-            } else if path.starts_with("../") {
-                path
-            } else {
-                PathBuf::from("./").join(path)
+        // Both paths need to be canonical, otherwise a symlink in one of them (e.g. a symlinked
+        // workspace member or a workspace reached through a symlink) breaks the relative path.
+        // Nix also copies symlinks as they are, so we want to point to the real directory.
+        let package_path = package_path
+            .as_ref()
+            .canonicalize()
+            .unwrap_or_else(|_| package_path.as_ref().to_path_buf());
+
+        if package_path == output_build_file_directory {
+            return Ok("./.".into());
+        }
+        let path = diff_paths(&package_path, &output_build_file_directory)
+            .unwrap_or_else(|| package_path.clone());
+        relative_nix_path(&path)
+            .map_err(|e| format_err!("while referencing {}: {}", package_path.display(), e))
+    }
+
+    /// Adjusts relative paths for rendering into a file one directory below the output,
+    /// e.g. for the crate files of a sharded output.
+    pub fn in_subdirectory(&self) -> ResolvedSource {
+        match self {
+            Self::LocalDirectory(source) => match source.path.to_str().and_then(one_level_up) {
+                Some(path) => Self::LocalDirectory(LocalDirectorySource {
+                    path: path.into(),
+                    ..source.clone()
+                }),
+                None => self.clone(),
+            },
+            Self::Nix(NixSource { file, attr }) => {
+                let file = match file {
+                    crate::config::NixFile::Import(path) => one_level_up(path)
+                        .map(crate::config::NixFile::Import)
+                        .unwrap_or_else(|| file.clone()),
+                    crate::config::NixFile::Package(path) => one_level_up(path)
+                        .map(crate::config::NixFile::Package)
+                        .unwrap_or_else(|| file.clone()),
+                };
+                Self::Nix(NixSource {
+                    file,
+                    attr: attr.clone(),
+                })
             }
-        })
+            _ => self.clone(),
+        }
+    }
+
+    /// Where the source comes from, e.g. for auditing the supply chain.
+    pub fn provenance(&self) -> SourceProvenance {
+        match self {
+            Self::CratesIo(source) => SourceProvenance {
+                kind: "crates-io".to_string(),
+                url: Some(source.url()),
+                ..Default::default()
+            },
+            Self::Registry(source) => SourceProvenance {
+                kind: "registry".to_string(),
+                url: Some(source.url.clone()),
+                registry: Some(source.index.clone()),
+                ..Default::default()
+            },
+            Self::Git(source) => SourceProvenance {
+                kind: "git".to_string(),
+                url: Some(source.url.to_string()),
+                rev: Some(source.rev.clone()),
+                r#ref: source.r#ref.clone(),
+                ..Default::default()
+            },
+            Self::LocalDirectory(source) => SourceProvenance {
+                kind: "local".to_string(),
+                path: Some(source.path.to_string_lossy().to_string()),
+                ..Default::default()
+            },
+            Self::Nix(source) => SourceProvenance {
+                kind: "nix".to_string(),
+                path: Some(source.to_string()),
+                ..Default::default()
+            },
+        }
     }
 
     pub fn sha256(&self) -> Option<&String> {
         match self {
-            Self::CratesIo(CratesIoSource { sha256, .. }) | Self::Git(GitSource { sha256, .. }) => {
-                sha256.as_ref()
-            }
+            Self::CratesIo(CratesIoSource { sha256, .. })
+            | Self::Registry(RegistrySource { sha256, .. })
+            | Self::Git(GitSource { sha256, .. }) => sha256.as_ref(),
             _ => None,
         }
     }
@@ -569,6 +2254,10 @@ impl ResolvedSource {
                 sha256: Some(sha256),
                 ..source.clone()
             }),
+            Self::Registry(source) => Self::Registry(RegistrySource {
+                sha256: Some(sha256),
+                ..source.clone()
+            }),
             Self::Git(source) => Self::Git(GitSource {
                 sha256: Some(sha256),
                 ..source.clone()
@@ -583,6 +2272,10 @@ impl ResolvedSource {
                 sha256: None,
                 ..source.clone()
             }),
+            Self::Registry(source) => Self::Registry(RegistrySource {
+                sha256: None,
+                ..source.clone()
+            }),
             Self::Git(source) => Self::Git(GitSource {
                 sha256: None,
                 ..source.clone()
@@ -592,19 +2285,92 @@ impl ResolvedSource {
     }
 }
 
-impl ToString for ResolvedSource {
-    fn to_string(&self) -> String {
+/// Returns true if the git checkout containing `package_path` has a `.gitmodules` file.
+fn has_git_submodules(package_path: &Path) -> bool {
+    package_path
+        .ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .is_some_and(|checkout| checkout.join(".gitmodules").is_file())
+}
+
+/// Returns the commit of a git source of `cargo metadata` or `Cargo.lock`.
+///
+/// The fragment is the commit that `Cargo.lock` records. The `rev` query parameter is only the
+/// `rev` from `Cargo.toml`, which may be a short hash or a tag, as for `branch` and `tag`.
+fn locked_git_rev(url: &Url) -> Option<String> {
+    url.fragment()
+        .filter(|fragment| !fragment.is_empty())
+        .map(str::to_string)
+        .or_else(|| {
+            url.query_pairs()
+                .find(|(k, _)| k == "rev")
+                .map(|(_, rev)| rev.to_string())
+        })
+}
+
+/// Whether `rev` is a full SHA-1 or SHA-256 commit hash.
+fn is_commit_hash(rev: &str) -> bool {
+    matches!(rev.len(), 40 | 64) && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[test]
+fn test_locked_git_rev() {
+    let commit = "0f6b3f8e2c1d4a5b6c7d8e9f0a1b2c3d4e5f6a7b";
+    let rev = |source: &str| locked_git_rev(&Url::parse(source).unwrap());
+    for query in ["branch=main", "tag=v1.0", "rev=0f6b3f8", "rev=v1.0", ""] {
+        assert_eq!(
+            rev(&format!("https://github.com/a/b?{}#{}", query, commit)).as_deref(),
+            Some(commit),
+            "{}",
+            query
+        );
+    }
+    assert_eq!(
+        rev(&format!("https://github.com/a/b?rev={}", commit)).as_deref(),
+        Some(commit)
+    );
+    assert_eq!(rev("https://github.com/a/b?branch=main"), None);
+    assert!(is_commit_hash(commit));
+    assert!(!is_commit_hash("0f6b3f8"));
+    assert!(!is_commit_hash("main"));
+}
+
+#[test]
+fn test_has_git_submodules() {
+    let mut env = test::MetadataEnv::default();
+    let checkout = env.temp_dir();
+    let crate_dir = checkout.join("crates").join("sys");
+    std::fs::create_dir_all(&crate_dir).unwrap();
+    std::fs::create_dir(checkout.join(".git")).unwrap();
+    assert!(!has_git_submodules(&crate_dir));
+
+    std::fs::write(
+        checkout.join(".gitmodules"),
+        "[submodule \"vendor\"]\n\tpath = vendor\n",
+    )
+    .unwrap();
+    assert!(has_git_submodules(&crate_dir));
+
+    env.close();
+}
+
+impl Display for ResolvedSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::CratesIo(source) => source.to_string(),
-            Self::Git(source) => source.to_string(),
-            Self::LocalDirectory(source) => source.to_string(),
-            Self::Nix(source) => source.to_string(),
+            Self::CratesIo(source) => source.fmt(f),
+            Self::Registry(source) => source.fmt(f),
+            Self::Git(source) => source.fmt(f),
+            Self::LocalDirectory(source) => source.fmt(f),
+            Self::Nix(source) => source.fmt(f),
         }
     }
 }
 
 impl CratesIoSource {
     pub fn url(&self) -> String {
+        if let Some(mirror) = self.mirror.as_ref() {
+            return download_url(mirror, &self.name, &self.version.to_string());
+        }
         // https://www.pietroalbini.org/blog/downloading-crates-io/
         // Not rate-limited, CDN URL.
         format!(
@@ -615,12 +2381,102 @@ impl CratesIoSource {
     }
 }
 
+/// The markers in download URL templates, see
+/// https://doc.rust-lang.org/cargo/reference/registry-index.html#index-configuration.
+///
+/// `crateDownloadUrl` in `default.nix` must expand them in the same way.
+const DOWNLOAD_URL_MARKERS: &[&str] = &["{crate}", "{version}", "{prefix}", "{lowerprefix}"];
+
+/// The marker for the checksum of a crate, which is only known once `Cargo.lock` is read.
+const CHECKSUM_MARKER: &str = "{sha256-checksum}";
+
+/// Returns an error if crate2nix cannot expand the download URL template in nix.
+pub fn check_download_url_template(template: &str) -> Result<(), Error> {
+    if template.contains(CHECKSUM_MARKER) {
+        bail!(
+            "The {{sha256-checksum}} marker in the download URL template {} is not supported.",
+            template
+        );
+    }
+    Ok(())
+}
+
+/// Expands a download URL template like cargo does for the `dl` field in a registry's `config.json`.
+fn download_url(template: &str, name: &str, version: &str) -> String {
+    fn prefix(name: &str) -> String {
+        match name.len() {
+            1 => "1".to_string(),
+            2 => "2".to_string(),
+            3 => format!("3/{}", &name[..1]),
+            _ => format!("{}/{}", &name[..2], &name[2..4]),
+        }
+    }
+
+    let template = if DOWNLOAD_URL_MARKERS
+        .iter()
+        .chain(&[CHECKSUM_MARKER])
+        .any(|m| template.contains(m))
+    {
+        template.to_string()
+    } else {
+        format!("{}/{{crate}}/{{version}}/download", template)
+    };
+    template
+        .replace("{crate}", name)
+        .replace("{version}", version)
+        .replace("{prefix}", &prefix(name))
+        .replace("{lowerprefix}", &prefix(&name.to_lowercase()))
+}
+
+#[test]
+fn test_download_url() {
+    assert_eq!(
+        download_url("https://mirror.example/crates", "serde", "1.0.0"),
+        "https://mirror.example/crates/serde/1.0.0/download"
+    );
+    assert_eq!(
+        download_url(
+            "https://mirror.example/{lowerprefix}/{crate}/{crate}-{version}.crate",
+            "Inflector",
+            "0.11.4"
+        ),
+        "https://mirror.example/in/fl/Inflector/Inflector-0.11.4.crate"
+    );
+    assert_eq!(
+        download_url("https://mirror.example/{prefix}/{crate}", "syn", "2.0.0"),
+        "https://mirror.example/3/s/syn"
+    );
+    assert!(check_download_url_template("https://m/{sha256-checksum}").is_err());
+
+    let source = RegistrySource {
+        index: "sparse+https://my.registry/index/".to_string(),
+        name: "serde".to_string(),
+        version: Version::new(1, 0, 0),
+        url: download_url(
+            "https://my.registry/files/{crate}-{version}/{sha256-checksum}",
+            "serde",
+            "1.0.0",
+        ),
+        sha256: None,
+    };
+    assert_eq!(
+        source.with_checksum("abc123").url,
+        "https://my.registry/files/serde-1.0.0/abc123"
+    );
+}
+
 impl Display for CratesIoSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.url())
     }
 }
 
+impl Display for RegistrySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
 impl Display for GitSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let base = format!("{}#{}", self.url, self.rev);
@@ -649,8 +2505,12 @@ impl Display for NixSource {
 }
 
 /// Normalize a package name such as cargo does.
-fn normalize_package_name(package_name: &str) -> String {
-    package_name.replace('-', "_")
+fn normalize_package_name(package_name: &str) -> Cow<'_, str> {
+    if package_name.contains('-') {
+        Cow::Owned(package_name.replace('-', "_"))
+    } else {
+        Cow::Borrowed(package_name)
+    }
 }
 
 #[derive(Debug)]
@@ -662,8 +2522,11 @@ fn normalize_package_name(package_name: &str) -> String {
 /// on which to perform the join in the general case.
 struct ResolvedDependencies<'a> {
     package: &'a Package,
+    interner: &'a Interner,
     /// Packages references in the NodeDeps of this package.
-    resolved_packages_by_crate_name: HashMap<String, Vec<&'a Package>>,
+    resolved_packages_by_crate_name: HashMap<Cow<'a, str>, Vec<&'a Package>>,
+    /// Artifact specifications by dependency kind and (renamed) name.
+    artifacts: HashMap<(DependencyKind, String), DependencyArtifact>,
 }
 
 impl<'a> ResolvedDependencies<'a> {
@@ -672,23 +2535,28 @@ impl<'a> ResolvedDependencies<'a> {
         package: &'a Package,
     ) -> Result<ResolvedDependencies<'a>, Error> {
         let node: &Node = metadata.nodes_by_id.get(&package.id).ok_or_else(|| {
-            format_err!(
-                "Could not find node for {}.\n-- Package\n{}",
-                &package.id,
+            debug!(
+                "-- Package\n{}",
                 to_string_pretty(&package).unwrap_or_else(|_| "ERROR".to_string())
-            )
+            );
+            Crate2NixError::MissingNode {
+                package_id: package.id.clone(),
+            }
         })?;
 
-        let mut resolved_packages_by_crate_name: HashMap<String, Vec<&'a Package>> = HashMap::new();
+        let mut resolved_packages_by_crate_name: HashMap<Cow<'a, str>, Vec<&'a Package>> =
+            HashMap::new();
         for node_dep in &node.deps {
             let package = metadata.pkgs_by_id.get(&node_dep.pkg).ok_or_else(|| {
-                format_err!(
-                    "No matching package for dependency with package id {} in {}.\n-- Package\n{}\n-- Node\n{}",
-                    node_dep.pkg,
-                    package.id,
+                debug!(
+                    "-- Package\n{}\n-- Node\n{}",
                     to_string_pretty(&package).unwrap_or_else(|_| "ERROR".to_string()),
                     to_string_pretty(&node).unwrap_or_else(|_| "ERROR".to_string()),
-                )
+                );
+                Crate2NixError::MissingDependencyPackage {
+                    package_id: package.id.clone(),
+                    dependency_id: node_dep.pkg.clone(),
+                }
             })?;
             let packages = resolved_packages_by_crate_name
                 .entry(normalize_package_name(&package.name))
@@ -697,7 +2565,9 @@ impl<'a> ResolvedDependencies<'a> {
         }
         Ok(ResolvedDependencies {
             package,
+            interner: &metadata.interner,
             resolved_packages_by_crate_name,
+            artifacts: artifact_dependencies(package.manifest_path.as_ref())?,
         })
     }
 
@@ -707,7 +2577,9 @@ impl<'a> ResolvedDependencies<'a> {
     ) -> Vec<ResolvedDependency> {
         let ResolvedDependencies {
             package,
+            interner,
             resolved_packages_by_crate_name,
+            artifacts,
         } = self;
 
         let mut resolved = package
@@ -715,43 +2587,70 @@ impl<'a> ResolvedDependencies<'a> {
             .iter()
             .filter(|package_dep| filter(package_dep))
             .flat_map(|package_dep| {
-                let name: String = normalize_package_name(&package_dep.name);
-                let resolved = resolved_packages_by_crate_name
-                    .get(&name)
-                    .and_then(|packages| {
-                        let exact_match = packages
-                            .iter()
-                            .find(|p| package_dep.req.matches(&p.version));
-
-                        // Strip prerelease/build info from versions if we
-                        // did not find an exact match.
-                        //
-                        // E.g. "*" does not match a prerelease version in this
-                        // library but cargo thinks differently.
-
-                        exact_match.or_else(|| {
-                            packages.iter().find(|p| {
-                                let without_metadata = {
-                                    let mut version = p.version.clone();
-                                    version.pre = semver::Prerelease::EMPTY;
-                                    version.build = semver::BuildMetadata::EMPTY;
-                                    version
-                                };
-                                package_dep.req.matches(&without_metadata)
+                let name = normalize_package_name(&package_dep.name);
+                let resolved =
+                    resolved_packages_by_crate_name
+                        .get(name.as_ref())
+                        .and_then(|packages| {
+                            let exact_match = packages
+                                .iter()
+                                .find(|p| package_dep.req.matches(&p.version));
+
+                            // Strip prerelease/build info from versions if we
+                            // did not find an exact match.
+                            //
+                            // E.g. "*" does not match a prerelease version in this
+                            // library but cargo thinks differently.
+
+                            exact_match.or_else(|| {
+                                packages.iter().find(|p| {
+                                    let without_metadata = {
+                                        let mut version = p.version.clone();
+                                        version.pre = semver::Prerelease::EMPTY;
+                                        version.build = semver::BuildMetadata::EMPTY;
+                                        version
+                                    };
+                                    package_dep.req.matches(&without_metadata)
+                                })
                             })
-                        })
-                    });
-
-                let dep_package = resolved?;
+                        });
+
+                let Some(dep_package) = resolved else {
+                    trace!(
+                        "Skipping dependency {} of {}: not part of the resolved graph.",
+                        package_dep.name,
+                        package.id
+                    );
+                    return None;
+                };
+                if let Some(target) = package_dep.target.as_ref() {
+                    debug!(
+                        "Dependency {} of {} is only enabled for target {}.",
+                        dep_package.id, package.id, target
+                    );
+                }
 
                 Some(ResolvedDependency {
-                    name: package_dep.name.clone(),
+                    name: interner.str(&package_dep.name),
                     rename: package_dep.rename.clone(),
-                    package_id: dep_package.id.clone(),
+                    package_id: interner.package_id(&dep_package.id),
                     target: package_dep.target.clone(),
                     optional: package_dep.optional,
                     uses_default_features: package_dep.uses_default_features,
-                    features: package_dep.features.clone(),
+                    features: interner.strs(&package_dep.features),
+                    // Artifact dependencies are rare, avoid building the key otherwise.
+                    artifact: (!artifacts.is_empty())
+                        .then(|| {
+                            artifacts.get(&(
+                                package_dep.kind,
+                                package_dep
+                                    .rename
+                                    .clone()
+                                    .unwrap_or_else(|| package_dep.name.clone()),
+                            ))
+                        })
+                        .flatten()
+                        .cloned(),
                 })
             })
             .collect::<Vec<ResolvedDependency>>();
@@ -860,12 +2759,60 @@ pub fn resolved_dependencies_filtered_dependencies_with_double_crate() {
     env.close();
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[test]
+pub fn resolve_target_specific_build_dependencies() {
+    let mut env = test::MetadataEnv::default();
+
+    let mut main = env.add_package_and_node("main");
+    main.make_root();
+    main.add_dependency("cc")
+        .update_package_dep(|d| d.kind = DependencyKind::Build);
+    main.add_dependency("cc").update_package_dep(|d| {
+        d.kind = DependencyKind::Build;
+        d.target = Some("cfg(unix)".parse().unwrap());
+        d.features = vec!["parallel".to_string()];
+    });
+    main.add_dependency("pkg-config").update_package_dep(|d| {
+        d.kind = DependencyKind::Build;
+        d.target = Some("x86_64-unknown-linux-gnu".parse().unwrap());
+    });
+
+    let indexed = env.indexed_metadata();
+    let root_package = indexed.root_package().expect("root package");
+    let main = CrateDerivation::resolve(
+        &test::generate_config(),
+        &crate::config::Config::default(),
+        &indexed,
+        root_package,
+    )
+    .unwrap();
+
+    // Every edge keeps its own target, like the edges of normal dependencies.
+    assert!(main.dependencies.is_empty());
+    let targets: Vec<(&str, Option<String>)> = main
+        .build_dependencies
+        .iter()
+        .map(|d| (&*d.name, d.target.as_ref().map(|t| t.to_string())))
+        .collect();
+    assert_eq!(
+        targets,
+        vec![
+            ("cc", None),
+            ("cc", Some("cfg(unix)".to_string())),
+            ("pkg-config", Some("x86_64-unknown-linux-gnu".to_string())),
+        ]
+    );
+    assert_eq!(main.build_dependencies[1].features, [Arc::from("parallel")]);
+
+    env.close();
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ResolvedDependency {
-    pub name: String,
+    pub name: Arc<str>,
     /// New name for the dependency if it is renamed.
     pub rename: Option<String>,
-    pub package_id: PackageId,
+    pub package_id: Arc<PackageId>,
     /// The cfg expression for conditionally enabling the dependency (if any).
     /// Can also be a target "triplet".
     pub target: Option<Platform>,
@@ -874,5 +2821,260 @@ pub struct ResolvedDependency {
     /// Whether the crate uses this dependency with default features enabled.
     pub uses_default_features: bool,
     /// Extra-enabled features.
-    pub features: Vec<String>,
+    pub features: Vec<Arc<str>>,
+    /// Set for artifact dependencies (`artifact = "bin"`) which are not linked as library
+    /// unless `lib = true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact: Option<DependencyArtifact>,
+}
+
+/// The artifact specification of an artifact dependency, see
+/// <https://doc.rust-lang.org/cargo/reference/unstable.html#artifact-dependencies>.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DependencyArtifact {
+    /// The requested artifact kinds, e.g. `bin`, `bin:<name>`, `cdylib` or `staticlib`.
+    pub kinds: Vec<String>,
+    /// The target to build the artifact for, e.g. `target` for the compilation target.
+    pub target: Option<String>,
+    /// Whether the dependency is also used as a library.
+    pub lib: bool,
+}
+
+impl DependencyArtifact {
+    /// Whether the artifact contains binaries.
+    pub fn has_binaries(&self) -> bool {
+        self.kinds
+            .iter()
+            .any(|kind| kind == "bin" || kind.starts_with("bin:"))
+    }
+}
+
+/// Returns the feature resolver version of the workspace with the given root manifest, "1" or "2".
+///
+/// Resolver "3" only changes how versions are selected, features are resolved as with "2".
+pub fn feature_resolver(manifest_path: &Path) -> Result<&'static str, Error> {
+    if !manifest_path.exists() {
+        // Synthetic metadata, e.g. in tests.
+        return Ok("1");
+    }
+    let content = std::fs::read_to_string(manifest_path)
+        .map_err(|e| format_err!("while reading {}: {}", manifest_path.display(), e))?;
+    let manifest: toml::Table = toml::from_str(&content)
+        .map_err(|e| format_err!("while parsing {}: {}", manifest_path.display(), e))?;
+    Ok(feature_resolver_of_manifest(&manifest))
+}
+
+fn feature_resolver_of_manifest(manifest: &toml::Table) -> &'static str {
+    let workspace = manifest.get("workspace");
+    let package = manifest.get("package");
+    let resolver = workspace
+        .and_then(|w| w.get("resolver"))
+        .or_else(|| package.and_then(|p| p.get("resolver")))
+        .and_then(|r| r.as_str());
+    match resolver {
+        Some("1") => "1",
+        Some(_) => "2",
+        None => {
+            // Packages of edition 2021 and later default to resolver "2", virtual workspaces to "1".
+            let edition = package.and_then(|p| p.get("edition")).and_then(|e| {
+                e.as_str().or_else(|| {
+                    // `edition.workspace = true`
+                    workspace
+                        .and_then(|w| w.get("package"))
+                        .and_then(|p| p.get("edition"))
+                        .and_then(|e| e.as_str())
+                })
+            });
+            edition
+                .and_then(Edition::find)
+                .map_or("1", |edition| edition.default_feature_resolver)
+        }
+    }
+}
+
+#[test]
+fn test_feature_resolver_of_manifest() {
+    let resolver = |toml: &str| feature_resolver_of_manifest(&toml::from_str(toml).unwrap());
+    assert_eq!(resolver("[package]\nname = \"a\"\nedition = \"2018\""), "1");
+    assert_eq!(resolver("[package]\nname = \"a\"\nedition = \"2021\""), "2");
+    assert_eq!(resolver("[workspace]\nmembers = [\"a\"]"), "1");
+    assert_eq!(resolver("[workspace]\nresolver = \"3\""), "2");
+    assert_eq!(
+        resolver(
+            "[workspace.package]\nedition = \"2024\"\n[package]\nname = \"a\"\nedition.workspace = true"
+        ),
+        "2"
+    );
+}
+
+/// Reads the artifact specifications of all artifact dependencies in the given manifest.
+///
+/// `cargo metadata` does not report the `artifact` keys of dependencies, so we read them from
+/// the manifest, keyed by the dependency section and the (renamed) dependency name.
+fn artifact_dependencies(
+    manifest_path: &Path,
+) -> Result<HashMap<(DependencyKind, String), DependencyArtifact>, Error> {
+    let mut artifacts = HashMap::new();
+    if !manifest_path.exists() {
+        // Synthetic metadata, e.g. in tests.
+        return Ok(artifacts);
+    }
+    let content = std::fs::read_to_string(manifest_path)
+        .map_err(|e| format_err!("while reading {}: {}", manifest_path.display(), e))?;
+    if !content.contains("artifact") {
+        return Ok(artifacts);
+    }
+    let manifest: toml::Table = toml::from_str(&content)
+        .map_err(|e| format_err!("while parsing {}: {}", manifest_path.display(), e))?;
+
+    let target_tables = manifest
+        .get("target")
+        .and_then(|targets| targets.as_table())
+        .into_iter()
+        .flat_map(|targets| targets.values())
+        .filter_map(|target| target.as_table());
+    for table in std::iter::once(&manifest).chain(target_tables) {
+        for (section, kind) in [
+            ("dependencies", DependencyKind::Normal),
+            ("build-dependencies", DependencyKind::Build),
+            ("dev-dependencies", DependencyKind::Development),
+        ] {
+            let Some(dependencies) = table.get(section).and_then(|d| d.as_table()) else {
+                continue;
+            };
+            for (name, dependency) in dependencies {
+                let Some(artifact) = dependency.get("artifact") else {
+                    continue;
+                };
+                let kinds = match artifact {
+                    toml::Value::String(kind) => vec![kind.clone()],
+                    toml::Value::Array(kinds) => kinds
+                        .iter()
+                        .filter_map(|kind| kind.as_str().map(str::to_string))
+                        .collect(),
+                    _ => bail!(
+                        "unexpected artifact value for {} in {}",
+                        name,
+                        manifest_path.display()
+                    ),
+                };
+                let target = dependency
+                    .get("target")
+                    .and_then(|t| t.as_str())
+                    .map(str::to_string);
+                if let Some(target) = target.as_ref().filter(|t| *t != "target") {
+                    warn!(
+                        "Artifact dependency {} in {} is built for the default platform \
+                         instead of {}.",
+                        name,
+                        manifest_path.display(),
+                        target
+                    );
+                }
+                artifacts.insert(
+                    (kind, name.clone()),
+                    DependencyArtifact {
+                        kinds,
+                        target,
+                        lib: dependency
+                            .get("lib")
+                            .and_then(|l| l.as_bool())
+                            .unwrap_or(false),
+                    },
+                );
+            }
+        }
+    }
+    Ok(artifacts)
+}
+
+#[test]
+fn test_artifact_dependencies() {
+    let dir = tempdir::TempDir::new("crate2nix_artifacts").expect("temp dir");
+    let manifest_path = dir.path().join("Cargo.toml");
+    std::fs::write(
+        &manifest_path,
+        r#"
+[package]
+name = "main"
+
+[dependencies]
+helper = { path = "../helper", artifact = "bin" }
+plain = "1"
+
+[target.'cfg(unix)'.build-dependencies]
+codegen = { version = "1", artifact = ["bin:gen", "cdylib"], target = "target", lib = true }
+"#,
+    )
+    .unwrap();
+
+    let artifacts = artifact_dependencies(&manifest_path).unwrap();
+    assert_eq!(artifacts.len(), 2);
+    assert_eq!(
+        artifacts[&(DependencyKind::Normal, "helper".to_string())],
+        DependencyArtifact {
+            kinds: vec!["bin".to_string()],
+            target: None,
+            lib: false,
+        }
+    );
+    let codegen = &artifacts[&(DependencyKind::Build, "codegen".to_string())];
+    assert_eq!(codegen.kinds, vec!["bin:gen", "cdylib"]);
+    assert_eq!(codegen.target.as_deref(), Some("target"));
+    assert!(codegen.lib && codegen.has_binaries());
+
+    dir.close().unwrap();
+}
+
+/// Marks crates that are used as artifact dependencies with binaries so that their
+/// binaries are built.
+pub fn mark_artifact_dependencies(crates: &mut [CrateDerivation]) {
+    let artifact_package_ids: HashSet<Arc<PackageId>> = crates
+        .iter()
+        .flat_map(|c| {
+            c.dependencies
+                .iter()
+                .chain(&c.build_dependencies)
+                .chain(&c.dev_dependencies)
+        })
+        .filter(|d| d.artifact.as_ref().is_some_and(|a| a.has_binaries()))
+        .map(|d| d.package_id.clone())
+        .collect();
+    for crate_derivation in crates {
+        crate_derivation.is_artifact_dependency =
+            artifact_package_ids.contains(&crate_derivation.package_id);
+    }
+}
+
+#[test]
+fn test_source_provenance() {
+    let git = ResolvedSource::Git(GitSource {
+        url: "https://github.com/kolloch/crate2nix".parse().unwrap(),
+        rev: "abc".to_string(),
+        r#ref: Some("main".to_string()),
+        sha256: None,
+        fetch_submodules: false,
+    });
+    assert_eq!(
+        serde_json::to_value(git.provenance()).unwrap(),
+        serde_json::json!({
+            "kind": "git",
+            "url": "https://github.com/kolloch/crate2nix",
+            "rev": "abc",
+            "ref": "main",
+        })
+    );
+    let registry = ResolvedSource::Registry(RegistrySource {
+        index: "sparse+https://my.registry/index/".to_string(),
+        name: "a".to_string(),
+        version: Version::new(0, 1, 0),
+        url: "https://my.registry/a-0.1.0.crate".to_string(),
+        sha256: None,
+    });
+    let provenance = registry.provenance();
+    assert_eq!(provenance.kind, "registry");
+    assert_eq!(
+        provenance.registry.as_deref(),
+        Some("sparse+https://my.registry/index/")
+    );
 }
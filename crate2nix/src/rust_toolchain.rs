@@ -0,0 +1,138 @@
+//! Reading the toolchain that `rust-toolchain.toml` pins for a project.
+//!
+//! Nix builds do not use rustup, so the pinned toolchain only takes effect if it is passed as
+//! `rustc` and `cargo` to `Cargo.nix`. We record what the file requests so that `Cargo.nix` can
+//! point this out and warn about mismatching versions.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{format_err, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// The file names that rustup looks for, in the order of precedence.
+const FILE_NAMES: &[&str] = &["rust-toolchain", "rust-toolchain.toml"];
+
+/// The toolchain requested by a `rust-toolchain.toml` or legacy `rust-toolchain` file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RustToolchain {
+    /// The path of the file relative to the output directory, starting with `./` or `../`.
+    pub file: String,
+    /// The channel, e.g. `stable`, `1.75.0` or `nightly-2024-01-01`.
+    pub channel: String,
+    /// Additional components such as `clippy` or `rust-src`.
+    pub components: Vec<String>,
+    /// Additional targets to install the standard library for.
+    pub targets: Vec<String>,
+}
+
+/// The `[toolchain]` section of `rust-toolchain.toml`.
+#[derive(Debug, Default, Deserialize)]
+struct ToolchainSection {
+    channel: Option<String>,
+    #[serde(default)]
+    components: Vec<String>,
+    #[serde(default)]
+    targets: Vec<String>,
+    path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolchainFile {
+    toolchain: ToolchainSection,
+}
+
+impl RustToolchain {
+    /// Searches `dir` and its ancestors for a toolchain file like rustup does.
+    ///
+    /// `output_dir` is the directory of the generated `Cargo.nix`.
+    pub fn find(dir: &Path, output_dir: &Path) -> Result<Option<RustToolchain>, Error> {
+        for dir in dir.ancestors() {
+            for file_name in FILE_NAMES {
+                let path = dir.join(file_name);
+                if path.is_file() {
+                    return RustToolchain::read(&path, output_dir);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(path: &Path, output_dir: &Path) -> Result<Option<RustToolchain>, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format_err!("while reading {}: {}", path.display(), e))?;
+        let section = match toml::from_str::<ToolchainFile>(&content) {
+            Ok(file) => file.toolchain,
+            // The legacy format only contains the channel name.
+            Err(_) if !content.trim().is_empty() && !content.trim().contains(['\n', '=']) => {
+                ToolchainSection {
+                    channel: Some(content.trim().to_string()),
+                    ..ToolchainSection::default()
+                }
+            }
+            Err(e) => return Err(format_err!("while parsing {}: {}", path.display(), e)),
+        };
+        if let Some(toolchain_path) = section.path {
+            warn!(
+                "Ignoring {}: the custom toolchain {} cannot be used in nix builds.",
+                path.display(),
+                toolchain_path
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(RustToolchain {
+            file: relative_file(path, output_dir),
+            channel: section.channel.unwrap_or_else(|| "stable".to_string()),
+            components: section.components,
+            targets: section.targets,
+        }))
+    }
+}
+
+/// The path of `file` relative to `dir` as nix path literal.
+pub(crate) fn relative_file(file: &Path, dir: &Path) -> String {
+    let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let relative = pathdiff::diff_paths(&file, &dir).unwrap_or_else(|| file.clone());
+    if relative.is_absolute() || relative.starts_with("..") {
+        relative.to_string_lossy().to_string()
+    } else {
+        PathBuf::from(".")
+            .join(relative)
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+#[test]
+fn test_find() {
+    let dir = tempdir::TempDir::new("crate2nix_rust_toolchain").expect("temp dir");
+    let project = dir.path().join("project");
+    let member = project.join("crates").join("member");
+    std::fs::create_dir_all(&member).unwrap();
+    assert_eq!(RustToolchain::find(&member, &project).unwrap(), None);
+
+    std::fs::write(
+        project.join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"1.75.0\"\ncomponents = [\"clippy\", \"rust-src\"]\n",
+    )
+    .unwrap();
+    assert_eq!(
+        RustToolchain::find(&member, &project).unwrap(),
+        Some(RustToolchain {
+            file: "./rust-toolchain.toml".to_string(),
+            channel: "1.75.0".to_string(),
+            components: vec!["clippy".to_string(), "rust-src".to_string()],
+            targets: vec![],
+        })
+    );
+
+    // The legacy file takes precedence.
+    std::fs::write(project.join("rust-toolchain"), "nightly-2024-01-01\n").unwrap();
+    let toolchain = RustToolchain::find(&member, &member).unwrap().unwrap();
+    assert_eq!(toolchain.file, "../../rust-toolchain");
+    assert_eq!(toolchain.channel, "nightly-2024-01-01");
+
+    dir.close().unwrap();
+}
@@ -0,0 +1,144 @@
+//! Sharing the lists that many crates repeat in `Cargo.nix`, see `--share-lists`.
+//!
+//! Large graphs repeat the same authors and feature lists thousands of times. Every list that
+//! occurs often enough to make the file smaller is defined once in `internal.sharedLists` and
+//! the crates refer to it, e.g. `authors = sharedLists.s3;`. nix then also allocates the list
+//! only once.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tera::{Function, Tera, Value};
+
+use crate::render::escape_nix_string;
+use crate::resolve::CrateDerivation;
+
+/// The name of the tera function that `crate.tera` calls for every list it renders.
+const FUNCTION: &str = "shared_list";
+
+/// The attribute of `internal` that contains the shared lists.
+const ATTRIBUTE: &str = "sharedLists";
+
+/// Returns the lists to share for `crates`, the most frequent first.
+///
+/// `sharedLists.s<index>` refers to the list at `index`.
+pub fn of<'a>(crates: impl IntoIterator<Item = &'a CrateDerivation>) -> Vec<Vec<Arc<str>>> {
+    let mut counts: HashMap<&[Arc<str>], usize> = HashMap::new();
+    for crate_derivation in crates {
+        let dependency_features = crate_derivation
+            .dependencies
+            .iter()
+            .chain(&crate_derivation.build_dependencies)
+            .chain(&crate_derivation.dev_dependencies)
+            .map(|dependency| &dependency.features);
+        let lists = crate_lists(crate_derivation).chain(dependency_features);
+        for list in lists.filter(|list| !list.is_empty()) {
+            *counts.entry(list.as_slice()).or_default() += 1;
+        }
+    }
+    let repeated = counts.values().filter(|count| **count > 1).count();
+    let reference_len = ATTRIBUTE.len() + ".s".len() + repeated.to_string().len();
+    let mut shared: Vec<(&[Arc<str>], usize)> = counts
+        .into_iter()
+        .filter(|(list, count)| {
+            let rendered_len = rendered_len(list);
+            // The definition costs about as much as the list and its name.
+            let definition_len = rendered_len + reference_len;
+            *count * rendered_len.saturating_sub(reference_len) > definition_len
+        })
+        .collect();
+    shared.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    shared.into_iter().map(|(list, _)| list.to_vec()).collect()
+}
+
+/// The lists of the crate itself, without the features of its dependencies.
+fn crate_lists(crate_derivation: &CrateDerivation) -> impl Iterator<Item = &Vec<Arc<str>>> {
+    std::iter::once(&crate_derivation.authors)
+        .chain(crate_derivation.features.values())
+        .chain(std::iter::once(&crate_derivation.resolved_default_features))
+}
+
+/// The length of `[ "a" "b" ]`.
+fn rendered_len(list: &[Arc<str>]) -> usize {
+    list.iter()
+        .map(|value| escape_nix_string(value).len() + 1)
+        .sum::<usize>()
+        + "[ ]".len()
+}
+
+/// Registers the function that `crate.tera` calls with the references to `shared`.
+///
+/// The function returns `null` for the lists that are not shared, the template renders them
+/// itself.
+pub fn register(tera: &mut Tera, shared: &[Vec<Arc<str>>]) {
+    let references = shared
+        .iter()
+        .enumerate()
+        .map(|(index, list)| (key(list), format!("{}.s{}", ATTRIBUTE, index)))
+        .collect();
+    tera.register_function(FUNCTION, SharedList { references });
+}
+
+/// The `shared_list(values=...)` function.
+struct SharedList {
+    /// The references by `key`.
+    references: HashMap<String, String>,
+}
+
+impl Function for SharedList {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let values = args
+            .get("values")
+            .ok_or_else(|| tera::Error::msg("shared_list needs values"))?;
+        let reference = self.references.get(&serde_json::to_string(values)?);
+        Ok(reference.map_or(Value::Null, |reference| Value::String(reference.clone())))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// The lists are identified by their JSON, the same for the strings in rust and the values in
+/// tera.
+fn key(list: &[Arc<str>]) -> String {
+    serde_json::to_string(list).expect("strings to serialize")
+}
+
+#[test]
+fn test_shared_lists() {
+    let mut env = crate::test::MetadataEnv::default();
+    env.add_package_and_node("main").make_root();
+    env.add_package_and_node("a");
+    env.add_package_and_node("b");
+    let indexed = env.indexed_metadata();
+    let authors: Vec<Arc<str>> = vec!["The Rust Project Developers".into()];
+    let crates: Vec<CrateDerivation> = indexed
+        .pkgs_by_id
+        .values()
+        .map(|package| {
+            let mut crate_derivation =
+                CrateDerivation::unresolved(&indexed, package, String::new());
+            crate_derivation.authors = authors.clone();
+            crate_derivation.resolved_default_features = vec!["std".into()];
+            crate_derivation
+        })
+        .collect();
+
+    // Short lists are cheaper than their reference.
+    let shared = of(&crates);
+    assert_eq!(shared, vec![authors.clone()]);
+
+    let mut tera = Tera::default();
+    tera.add_raw_template(
+        "test",
+        "{{ shared_list(values=authors) }}|{% set other = shared_list(values=other) %}{{ other }}",
+    )
+    .unwrap();
+    register(&mut tera, &shared);
+    let mut context = tera::Context::new();
+    context.insert("authors", &authors);
+    context.insert("other", &vec!["Someone".to_string()]);
+    assert_eq!(tera.render("test", &context).unwrap(), "sharedLists.s0|");
+    env.close();
+}
@@ -6,6 +6,7 @@ use crate::{
     resolve::{CratesIoSource, GitSource},
 };
 use anyhow::{bail, format_err, Context, Error};
+use log::{info, warn};
 use semver::Version;
 use std::{
     borrow::Cow,
@@ -20,11 +21,11 @@ pub fn crates_io_source(name: String, version: Version) -> Result<config::Source
         name: name.clone(),
         version: version.clone(),
         sha256: None,
+        mirror: None,
     };
 
-    eprint!("Prefetching {}: ", prefetchable);
+    info!("Prefetching {}.", prefetchable);
     let sha256 = prefetchable.prefetch()?;
-    eprintln!("done.");
 
     Ok(config::Source::CratesIo {
         name,
@@ -40,11 +41,11 @@ pub fn git_io_source(url: Url, rev: String) -> Result<config::Source, Error> {
         rev: rev.clone(),
         r#ref: None,
         sha256: None,
+        fetch_submodules: true,
     };
 
-    eprint!("Prefetching {}: ", prefetchable);
+    info!("Prefetching {}.", prefetchable);
     let sha256 = prefetchable.prefetch()?;
-    eprintln!("done.");
 
     Ok(config::Source::Git { url, rev, sha256 })
 }
@@ -143,7 +144,7 @@ impl<'a> FetchedSources<'a> {
             symlink_generated < sources_modified
         };
         if has_nix_sources || outdated() {
-            eprintln!("Fetching sources.");
+            info!("Fetching sources.");
             self.fetch()?;
         }
 
@@ -167,16 +168,16 @@ impl<'a> FetchedSources<'a> {
             if path.is_dir() {
                 let cargo_toml = path.join("Cargo.toml");
                 if !cargo_toml.exists() {
-                    eprintln!(
-                        "WARNING: No Cargo.toml found in {}.\n\
+                    warn!(
+                        "No Cargo.toml found in {}.\n\
                                This will lead to later failures.",
                         path.to_string_lossy()
                     );
                 }
                 let cargo_lock = path.join("Cargo.lock");
                 if !cargo_lock.exists() {
-                    eprintln!(
-                        "WARNING: No Cargo.lock found in {}.\n\
+                    warn!(
+                        "No Cargo.lock found in {}.\n\
                                This will lead to later failures.",
                         path.to_string_lossy()
                     );
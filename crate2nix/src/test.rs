@@ -1,4 +1,4 @@
-///! Constructor functions for test data.
+//! Constructor functions for test data.
 use cargo_metadata::{Dependency, Metadata, Node, NodeDep, Package, PackageId, Resolve};
 use std::path::PathBuf;
 use tempdir::TempDir;
@@ -11,11 +11,12 @@ pub fn generate_config() -> crate::GenerateConfig {
         nixpkgs_path: "bogus-nixpkgs-path".into(),
         other_metadata_options: vec![],
         output: "Cargo.nix".into(),
-        use_cargo_lock_checksums: true,
-        read_crate_hashes: true,
+        metadata_cache: false,
+        ..Default::default()
     }
 }
 
+/// A `Metadata` builder that keeps track of the temporary crate directories.
 #[derive(Debug)]
 pub struct MetadataEnv {
     /// Keep track of temporary directories.
@@ -44,6 +45,7 @@ impl Default for MetadataEnv {
 }
 
 impl MetadataEnv {
+    /// Returns a new temporary directory that is removed on `close`.
     pub fn temp_dir(&mut self) -> PathBuf {
         let temp_dir = TempDir::new("crate2nix_test").expect("temp dir creation failed");
         let path = temp_dir.path().to_path_buf();
@@ -59,7 +61,8 @@ impl MetadataEnv {
         self.metadata.resolve.get_or_insert_with(empty_resolve)
     }
 
-    pub fn add_package_and_node(&mut self, name: &str) -> PackageAndNode {
+    /// Adds a package together with its resolve node.
+    pub fn add_package_and_node(&mut self, name: &str) -> PackageAndNode<'_> {
         let package = package(name, "0.1.0");
         let package_idx = self.metadata.packages.len();
         self.metadata.packages.push(package.clone());
@@ -74,14 +77,17 @@ impl MetadataEnv {
         }
     }
 
+    /// Returns a copy of the metadata built so far.
     pub fn metadata(&self) -> Metadata {
         self.metadata.clone()
     }
 
+    /// Returns the indexed metadata built so far.
     pub fn indexed_metadata(&self) -> crate::IndexedMetadata {
         crate::IndexedMetadata::new_from(self.metadata()).unwrap()
     }
 
+    /// Removes all temporary directories.
     pub fn close(&mut self) {
         for temp_dir in self.temp_dirs.drain(..) {
             temp_dir.close().expect("while closing temp");
@@ -89,6 +95,7 @@ impl MetadataEnv {
     }
 }
 
+/// A package and its node in a `MetadataEnv`.
 #[derive(Debug)]
 pub struct PackageAndNode<'a> {
     env: &'a mut MetadataEnv,
@@ -97,6 +104,7 @@ pub struct PackageAndNode<'a> {
 }
 
 impl<'a> PackageAndNode<'a> {
+    /// Makes this package the root package and a workspace member.
     pub fn make_root(&mut self) -> &mut Self {
         let package_id = self.get_mut_package().id.clone();
         self.env
@@ -107,32 +115,40 @@ impl<'a> PackageAndNode<'a> {
         self
     }
 
+    /// Returns the package.
     pub fn get_mut_package(&mut self) -> &mut Package {
         &mut self.env.metadata.packages[self.package_idx]
     }
+    /// Returns the package.
     pub fn get_package(&self) -> &Package {
         &self.env.metadata.packages[self.package_idx]
     }
+    /// Updates the package with the given function.
     pub fn update_package(&mut self, f: impl FnOnce(&mut Package)) -> &mut Self {
         f(self.get_mut_package());
         self
     }
+    /// Returns the resolve node.
     pub fn get_mut_node(&mut self) -> &mut Node {
         &mut self.env.mut_resolve().nodes[self.node_idx]
     }
+    /// Returns the resolve node.
     pub fn get_node(&mut self) -> &Node {
         &self.env.mut_resolve().nodes[self.node_idx]
     }
+    /// Updates the resolve node with the given function.
     pub fn update_node(&mut self, f: impl FnOnce(&mut Node)) -> &mut Self {
         f(self.get_mut_node());
         self
     }
+    /// Sets the version and a matching crates.io package ID.
     pub fn version_and_package_id(&mut self, version: &str) -> &mut Self {
         let p = self.get_mut_package();
         p.version = semver::Version::parse(version).expect("version incorrect");
         p.id = crates_io_package_id(&p.name, version);
         self
     }
+    /// Adds a new package as a dependency of this package.
     pub fn add_dependency<'b>(&'b mut self, name: &str) -> PackageAndNodeDep<'b> {
         let package_dep_idx = self.get_mut_package().dependencies.len();
         let node_dep_idx = self.get_mut_node().dependencies.len();
@@ -165,6 +181,7 @@ impl<'a> PackageAndNode<'a> {
     }
 }
 
+/// A dependency edge between two packages in a `MetadataEnv`.
 #[derive(Debug)]
 pub struct PackageAndNodeDep<'a> {
     package_and_node: PackageAndNode<'a>,
@@ -175,17 +192,21 @@ pub struct PackageAndNodeDep<'a> {
 }
 
 impl<'a> PackageAndNodeDep<'a> {
+    /// Updates the dependency package and node with the given function.
     pub fn update_package_and_node(&mut self, f: impl FnOnce(&mut PackageAndNode)) -> &mut Self {
         f(&mut self.package_and_node);
         self
     }
+    /// Returns the dependency package.
     pub fn get_package(&self) -> &Package {
         self.package_and_node.get_package()
     }
+    /// Updates the dependency package with the given function.
     pub fn update_package(&mut self, f: impl FnOnce(&mut Package)) -> &mut Self {
         self.package_and_node.update_package(f);
         self
     }
+    /// Updates the dependency node with the given function.
     pub fn update_node(&mut self, f: impl FnOnce(&mut Node)) -> &mut Self {
         self.package_and_node.update_node(f);
         self
@@ -194,10 +215,12 @@ impl<'a> PackageAndNodeDep<'a> {
         let parent_package_idx = self.parent_package_idx;
         &mut self.package_and_node.env.metadata.packages[parent_package_idx]
     }
+    /// Returns the `Dependency` entry in the parent package.
     pub fn get_mut_package_dep(&mut self) -> &mut Dependency {
         let package_dep_idx = self.package_dep_idx;
         &mut self.get_mut_parent_package().dependencies[package_dep_idx]
     }
+    /// Updates the `Dependency` entry in the parent package.
     pub fn update_package_dep(&mut self, f: impl FnOnce(&mut Dependency)) -> &mut Self {
         f(self.get_mut_package_dep());
         self
@@ -206,14 +229,17 @@ impl<'a> PackageAndNodeDep<'a> {
         let parent_node_idx = self.parent_node_idx;
         &mut self.package_and_node.env.mut_resolve().nodes[parent_node_idx]
     }
+    /// Returns the `NodeDep` entry in the parent node.
     pub fn get_mut_node_dep(&mut self) -> &mut NodeDep {
         let node_dep_idx = self.node_dep_idx;
         &mut self.get_mut_parent_node().deps[node_dep_idx]
     }
+    /// Updates the `NodeDep` entry in the parent node.
     pub fn update_node_dep(&mut self, f: impl FnOnce(&mut NodeDep)) -> &mut Self {
         f(self.get_mut_node_dep());
         self
     }
+    /// Sets the version and package ID of the dependency and updates all references.
     pub fn version_and_package_id(&mut self, version: &str) -> &mut Self {
         self.update_package_and_node(|pn| {
             pn.version_and_package_id(version);
@@ -232,6 +258,7 @@ impl<'a> PackageAndNodeDep<'a> {
     }
 }
 
+/// A package with a temporary crate directory.
 #[derive(Debug)]
 #[must_use = "Please close."]
 pub struct TestPackage {
@@ -240,6 +267,7 @@ pub struct TestPackage {
 }
 
 impl TestPackage {
+    /// Removes the temporary crate directory.
     pub fn close(self) -> Result<(), std::io::Error> {
         self.crate_dir.close()
     }
@@ -284,6 +312,7 @@ macro_rules! from_json {
     };
 }
 
+/// Returns the package ID of the given crate on crates.io.
 pub fn crates_io_package_id(name: &str, version: &str) -> PackageId {
     PackageId {
         repr: format!(
@@ -316,6 +345,7 @@ pub fn package(name: &str, version: &str) -> TestPackage {
     }
 }
 
+/// Returns a bin target for the given source file.
 pub fn bin_target(package: &TestPackage, file: &str) -> cargo_metadata::Target {
     from_json!(
         {
@@ -334,6 +364,7 @@ pub fn bin_target(package: &TestPackage, file: &str) -> cargo_metadata::Target {
     )
 }
 
+/// Returns a lib target for the given source file.
 pub fn lib_target(package: &TestPackage, file: &str) -> cargo_metadata::Target {
     from_json!(
         {
@@ -368,6 +399,7 @@ pub fn lib_package(name: &str, version: &str) -> TestPackage {
     package
 }
 
+/// Returns a dependency on exactly the given package.
 pub fn dependency_from_package(package: &Package) -> Dependency {
     from_json!(
         {
@@ -385,11 +417,13 @@ pub fn dependency_from_package(package: &Package) -> Dependency {
     )
 }
 
+/// Checks that `package` returns a valid package.
 #[test]
 pub fn test_package() {
     println!("{:#?}", package("test", "0.1.0"));
 }
 
+/// Returns a resolve node without dependencies.
 pub fn node(package_id: &str) -> Node {
     from_json!({
           "id": package_id,
@@ -400,6 +434,7 @@ pub fn node(package_id: &str) -> Node {
     )
 }
 
+/// Returns a normal dependency edge to the given package.
 pub fn node_dep(name: &str, package_id: &PackageId) -> NodeDep {
     from_json!({
         "name": name,
@@ -414,6 +449,7 @@ pub fn node_dep(name: &str, package_id: &PackageId) -> NodeDep {
     )
 }
 
+/// Returns a resolve node with the given dependencies.
 pub fn node_with_deps(package_id: &str, deps: Vec<NodeDep>) -> Node {
     let mut node = node(package_id);
     node.dependencies = deps.iter().map(|d| &d.pkg).cloned().collect();
@@ -421,22 +457,26 @@ pub fn node_with_deps(package_id: &str, deps: Vec<NodeDep>) -> Node {
     node
 }
 
+/// Checks that `node` returns a valid node.
 #[test]
 pub fn test_valid_node() {
     println!("{:#?}", node("bogus package id"));
 }
 
+/// Returns a resolve without nodes.
 pub fn empty_resolve() -> Resolve {
     from_json!({
             "nodes": [],
     })
 }
 
+/// Checks that `empty_resolve` returns a valid resolve.
 #[test]
 pub fn test_valid_empty_resolve() {
     println!("{:#?}", empty_resolve());
 }
 
+/// Returns metadata without packages.
 pub fn empty_metadata() -> Metadata {
     from_json!({
         "version": 1,
@@ -447,6 +487,7 @@ pub fn empty_metadata() -> Metadata {
     })
 }
 
+/// Checks that `empty_metadata` returns valid metadata.
 #[test]
 pub fn test_valid_empty_metadata() {
     println!("{:#?}", empty_metadata());
@@ -0,0 +1,251 @@
+//! Checks the templates against the bundled fixture projects, see `crate2nix test-templates`.
+//!
+//! Every fixture is written to a temporary directory, `Cargo.nix` is generated for it and
+//! evaluated with `nix-instantiate --eval` by instantiating the derivations of all workspace
+//! members. This catches template changes that render valid nix which does not evaluate, without
+//! building anything.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, format_err, Context, Error};
+use log::info;
+
+use crate::{BuildInfo, GenerateConfig, GenerateInfo};
+
+/// The placeholder in a fixture's `Cargo.toml` for the URL of its git dependency.
+const GIT_DEPENDENCY_URL: &str = "GIT_DEPENDENCY_URL";
+
+/// A small project to generate `Cargo.nix` for.
+#[derive(Debug)]
+pub struct Fixture {
+    /// The name of the fixture.
+    pub name: &'static str,
+    /// The files of the project by relative path.
+    files: &'static [(&'static str, &'static str)],
+    /// The files of a git repository that the project depends on, if any.
+    git_dependency: &'static [(&'static str, &'static str)],
+}
+
+macro_rules! fixture_file {
+    ($fixture:expr, $path:expr) => {
+        (
+            $path,
+            include_str!(concat!("../fixtures/", $fixture, "/", $path)),
+        )
+    };
+}
+
+/// All bundled fixtures.
+pub const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "simple_bin",
+        files: &[
+            fixture_file!("simple_bin", "Cargo.toml"),
+            fixture_file!("simple_bin", "src/main.rs"),
+        ],
+        git_dependency: &[],
+    },
+    Fixture {
+        name: "workspace",
+        files: &[
+            fixture_file!("workspace", "Cargo.toml"),
+            fixture_file!("workspace", "app/Cargo.toml"),
+            fixture_file!("workspace", "app/src/main.rs"),
+            fixture_file!("workspace", "greeting/Cargo.toml"),
+            fixture_file!("workspace", "greeting/src/lib.rs"),
+        ],
+        git_dependency: &[],
+    },
+    Fixture {
+        name: "proc_macro",
+        files: &[
+            fixture_file!("proc_macro", "Cargo.toml"),
+            fixture_file!("proc_macro", "src/main.rs"),
+            fixture_file!("proc_macro", "macros/Cargo.toml"),
+            fixture_file!("proc_macro", "macros/src/lib.rs"),
+        ],
+        git_dependency: &[],
+    },
+    Fixture {
+        name: "build_script",
+        files: &[
+            fixture_file!("build_script", "Cargo.toml"),
+            fixture_file!("build_script", "build.rs"),
+            fixture_file!("build_script", "src/main.rs"),
+        ],
+        git_dependency: &[],
+    },
+    Fixture {
+        name: "git_dep",
+        files: &[
+            fixture_file!("git_dep", "Cargo.toml"),
+            fixture_file!("git_dep", "src/main.rs"),
+        ],
+        git_dependency: &[
+            (
+                "Cargo.toml",
+                include_str!("../fixtures/git_dep/dependency/Cargo.toml"),
+            ),
+            (
+                "src/lib.rs",
+                include_str!("../fixtures/git_dep/dependency/src/lib.rs"),
+            ),
+        ],
+    },
+];
+
+impl Fixture {
+    /// Whether the fixture depends on a git repository, which needs `nix-prefetch-git`.
+    pub fn has_git_dependency(&self) -> bool {
+        !self.git_dependency.is_empty()
+    }
+
+    /// Writes the fixture to `dir` and generates its `Cargo.nix`, returning the path of it.
+    pub fn generate(&self, dir: &Path, nixpkgs_path: &str) -> Result<PathBuf, Error> {
+        let project_dir = dir.join(self.name);
+        let mut git_url = None;
+        if self.has_git_dependency() {
+            let repo_dir = dir.join(format!("{}_dependency", self.name));
+            write_files(&repo_dir, self.git_dependency)?;
+            create_git_repository(&repo_dir)?;
+            git_url = Some(format!("file://{}", repo_dir.display()));
+        }
+        write_files(&project_dir, self.files)?;
+        if let Some(url) = git_url {
+            let cargo_toml = project_dir.join("Cargo.toml");
+            let content = std::fs::read_to_string(&cargo_toml)?;
+            std::fs::write(&cargo_toml, content.replace(GIT_DEPENDENCY_URL, &url))?;
+        }
+
+        crate::command::run(
+            &format!("Locking {}", self.name),
+            Command::new("cargo")
+                .current_dir(&project_dir)
+                .arg("generate-lockfile"),
+        )?;
+
+        let output = project_dir.join("Cargo.nix");
+        let config = GenerateConfig {
+            cargo_toml: vec![project_dir.join("Cargo.toml")],
+            output: output.clone(),
+            crate_hashes_json: project_dir.join("crate-hashes.json"),
+            nixpkgs_path: nixpkgs_path.to_string(),
+            read_crate_hashes: false,
+            metadata_cache: false,
+            ..Default::default()
+        };
+        let build_info = BuildInfo::for_config(&GenerateInfo::default(), &config)
+            .with_context(|| format!("while generating the fixture {}", self.name))?;
+        crate::render::write_cargo_nix(&build_info)?;
+        Ok(output)
+    }
+}
+
+/// Evaluates the derivations of all workspace members of the given `Cargo.nix`.
+///
+/// Returns the derivation paths by workspace member as JSON.
+pub fn evaluate(cargo_nix: &Path) -> Result<String, Error> {
+    let cargo_nix = cargo_nix
+        .canonicalize()
+        .with_context(|| format!("while canonicalizing {}", cargo_nix.display()))?;
+    let expression = format!(
+        "builtins.mapAttrs (name: member: member.build.drvPath) (import {} {{ }}).workspaceMembers",
+        cargo_nix.display()
+    );
+    let output = Command::new("nix-instantiate")
+        .args(["--eval", "--strict", "--json", "--expr", &expression])
+        .output()
+        .map_err(|e| format_err!("while spawning nix-instantiate: {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "nix-instantiate --eval failed for {}:\n{}",
+            cargo_nix.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Generates and evaluates `Cargo.nix` for all fixtures in subdirectories of `dir`.
+///
+/// Returns an error listing the failed fixtures.
+pub fn run(dir: &Path, nixpkgs_path: &str) -> Result<(), Error> {
+    let mut failed = Vec::new();
+    for fixture in FIXTURES {
+        match fixture
+            .generate(dir, nixpkgs_path)
+            .and_then(|cargo_nix| evaluate(&cargo_nix))
+        {
+            Ok(derivations) => info!("{}: {}", fixture.name, derivations),
+            Err(e) => {
+                eprintln!("{}: {:#}", fixture.name, e);
+                failed.push(fixture.name);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} fixtures failed: {}",
+            failed.len(),
+            FIXTURES.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn write_files(dir: &Path, files: &[(&str, &str)]) -> Result<(), Error> {
+    for (path, content) in files {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("while creating {}", parent.display()))?;
+        }
+        std::fs::write(&path, content)
+            .with_context(|| format!("while writing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn create_git_repository(dir: &Path) -> Result<(), Error> {
+    let git = |args: &[&str]| {
+        crate::command::run(
+            &format!("Running git {}", args[0]),
+            Command::new("git")
+                .current_dir(dir)
+                .args([
+                    "-c",
+                    "user.name=crate2nix",
+                    "-c",
+                    "user.email=crate2nix@localhost",
+                ])
+                .args(args),
+        )
+    };
+    git(&["init", "--quiet"])?;
+    git(&["add", "."])?;
+    git(&["commit", "--quiet", "--message", "Fixture"])
+}
+
+#[test]
+fn test_fixtures() {
+    let dir = tempdir::TempDir::new("crate2nix_test_templates").expect("temp dir");
+    let has_nix = Command::new("nix-instantiate")
+        .arg("--version")
+        .output()
+        .is_ok();
+    for fixture in FIXTURES {
+        // Prefetching the git dependency needs nix.
+        if fixture.has_git_dependency() && !has_nix {
+            continue;
+        }
+        let cargo_nix = fixture.generate(dir.path(), "<nixpkgs>").unwrap();
+        let content = std::fs::read_to_string(&cargo_nix).unwrap();
+        assert!(content.contains("workspaceMembers"), "{}", fixture.name);
+        if has_nix {
+            evaluate(&cargo_nix).unwrap();
+        }
+    }
+    dir.close().unwrap();
+}
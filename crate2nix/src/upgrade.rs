@@ -0,0 +1,87 @@
+//! Updating the locked dependencies and regenerating in one step, e.g. for `crate2nix upgrade`.
+//!
+//! Prefetching only happens for crates whose hashes are neither in `Cargo.lock` nor in
+//! `crate-hashes.json`, which after a `cargo update` are exactly the changed git dependencies.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{format_err, Error};
+use log::warn;
+
+use crate::diff::{CrateSummary, CratesDiff};
+use crate::incremental::GenerationCache;
+use crate::{GenerateConfig, GenerateInfo};
+
+/// Runs `cargo update` for `packages`, or all packages if empty, regenerates the output of
+/// `config` and returns the changes to its crates.
+pub fn upgrade(
+    info: &GenerateInfo,
+    config: &GenerateConfig,
+    packages: &[String],
+) -> Result<CratesDiff, Error> {
+    let old = generated_crates(&config.output);
+    for cargo_toml in &config.cargo_toml {
+        cargo_update(cargo_toml, packages)?;
+    }
+    GenerationCache::regenerate_if_stale(info, config)?;
+    Ok(CratesDiff::new(&old, &generated_crates(&config.output)))
+}
+
+/// Runs `cargo update` in the workspace of `cargo_toml`.
+pub fn cargo_update(cargo_toml: &Path, packages: &[String]) -> Result<(), Error> {
+    let mut command = Command::new("cargo");
+    command.arg("update").arg("--manifest-path").arg(cargo_toml);
+    for package in packages {
+        command.args(["-p", package]);
+    }
+    crate::command::run(
+        &format!("Updating {}", cargo_toml.to_string_lossy()),
+        &mut command,
+    )
+    .map_err(|e| format_err!("while updating Cargo.lock: {}", e))
+}
+
+/// The crates of a generated file, empty if it cannot be read, e.g. before the first generation.
+fn generated_crates(output: &Path) -> BTreeMap<String, CrateSummary> {
+    if !output.exists() {
+        return BTreeMap::new();
+    }
+    crate::diff::read_crates(output).unwrap_or_else(|e| {
+        warn!("Cannot summarize the changes: {:#}", e);
+        BTreeMap::new()
+    })
+}
+
+#[test]
+fn test_upgrade() {
+    let mut env = crate::test::MetadataEnv::default();
+    let dir = env.temp_dir();
+    std::fs::write(
+        dir.join("Cargo.toml"),
+        "[package]\nname = \"upgraded\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+    )
+    .unwrap();
+    std::fs::create_dir(dir.join("src")).unwrap();
+    std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+    let config = GenerateConfig {
+        cargo_toml: vec![dir.join("Cargo.toml")],
+        output: dir.join("Cargo.nix"),
+        crate_hashes_json: dir.join("crate-hashes.json"),
+        incremental: true,
+        ..crate::test::generate_config()
+    };
+    let info = GenerateInfo::default();
+
+    let diff = upgrade(&info, &config, &[]).unwrap();
+    assert_eq!(
+        diff.added,
+        vec![("upgraded".to_string(), "0.1.0".to_string())]
+    );
+    assert!(dir.join("Cargo.lock").exists());
+
+    assert!(upgrade(&info, &config, &[]).unwrap().is_empty());
+
+    env.close();
+}
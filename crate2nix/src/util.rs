@@ -14,3 +14,32 @@ pub fn find_duplicates<'a, T: Ord>(source: impl Iterator<Item = &'a T>) -> Vec<&
     let mut seen = BTreeSet::new();
     source.filter(|v| !seen.insert(*v)).collect()
 }
+
+/// Matches `name` against a pattern with `*` for any number of characters and `?` for exactly one.
+/// ```
+/// use crate2nix::util::wildcard_match;
+/// assert!(wildcard_match("*", "crate"));
+/// assert!(wildcard_match("crate-*", "crate-a"));
+/// assert!(wildcard_match("crate-?", "crate-ä"));
+/// assert!(!wildcard_match("crate-?", "crate-ab"));
+/// assert!(!wildcard_match("*-lib", "crate-bin"));
+/// ```
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut pattern_chars = pattern.chars();
+    match pattern_chars.next() {
+        None => name.is_empty(),
+        Some('*') => name
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(name.len()))
+            .any(|i| wildcard_match(pattern_chars.as_str(), &name[i..])),
+        Some('?') => {
+            let mut name_chars = name.chars();
+            name_chars.next().is_some()
+                && wildcard_match(pattern_chars.as_str(), name_chars.as_str())
+        }
+        Some(c) => {
+            name.starts_with(c) && wildcard_match(pattern_chars.as_str(), &name[c.len_utf8()..])
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! The source replacements that make cargo use the vendored sources of `Cargo.nix`.
+//!
+//! Like `cargo vendor`, we replace every registry and git source of the build with a directory
+//! source. The directory itself is only known in nix, so `Cargo.nix` writes the
+//! `.cargo/config.toml` from the replaced sources.
+
+use std::collections::BTreeMap;
+
+use log::warn;
+
+/// Source IDs of crates.io, which is replaced as `crates-io`.
+const CRATES_IO: &[&str] = &[
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// The definitions of replaced sources in `.cargo/config.toml` by key, without `replace-with`.
+pub type SourceReplacements = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Returns the sources to replace for the given source IDs,
+/// e.g. `registry+https://github.com/rust-lang/crates.io-index`.
+pub fn source_replacements<'a>(
+    source_ids: impl IntoIterator<Item = &'a str>,
+) -> SourceReplacements {
+    let mut replacements = SourceReplacements::new();
+    for source_id in source_ids {
+        match replacement(source_id) {
+            Some((key, source)) => {
+                replacements.insert(key, source);
+            }
+            None => warn!(
+                "Not vendoring the sources of {}: unknown kind of source.",
+                source_id
+            ),
+        }
+    }
+    replacements
+}
+
+/// The key and definition of a source in `.cargo/config.toml`, e.g. `crates-io`.
+pub(crate) fn replacement(source_id: &str) -> Option<(String, BTreeMap<String, String>)> {
+    let mut source = BTreeMap::new();
+    if CRATES_IO.contains(&source_id) {
+        return Some(("crates-io".to_string(), source));
+    }
+    if source_id.starts_with("registry+") || source_id.starts_with("sparse+") {
+        source.insert("registry".to_string(), source_id.to_string());
+        return Some((source_id.to_string(), source));
+    }
+    let url = url::Url::parse(source_id.strip_prefix("git+")?).ok()?;
+    // The fragment is the locked commit, which is not part of the source.
+    let key = source_id.split('#').next()?;
+    let mut git = url.clone();
+    git.set_query(None);
+    git.set_fragment(None);
+    source.insert("git".to_string(), git.to_string());
+    for (name, value) in url.query_pairs() {
+        if ["branch", "tag", "rev"].contains(&name.as_ref()) {
+            source.insert(name.to_string(), value.to_string());
+        }
+    }
+    Some((key.to_string(), source))
+}
+
+#[test]
+fn test_source_replacements() {
+    let replacements = source_replacements([
+        "registry+https://github.com/rust-lang/crates.io-index",
+        "git+https://github.com/kolloch/nix-base32?branch=main#42f5544e51187f0c7535d453fcffb4b524c99eb2",
+        "sparse+https://my.registry/index/",
+        "path+file:///some/crate",
+    ]);
+    let source = |entries: &[(&str, &str)]| {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>()
+    };
+    assert_eq!(
+        replacements,
+        vec![
+            ("crates-io".to_string(), source(&[])),
+            (
+                "git+https://github.com/kolloch/nix-base32?branch=main".to_string(),
+                source(&[
+                    ("branch", "main"),
+                    ("git", "https://github.com/kolloch/nix-base32"),
+                ])
+            ),
+            (
+                "sparse+https://my.registry/index/".to_string(),
+                source(&[("registry", "sparse+https://my.registry/index/")])
+            ),
+        ]
+        .into_iter()
+        .collect::<SourceReplacements>()
+    );
+    assert!(source_replacements([]).is_empty());
+}
@@ -0,0 +1,61 @@
+{ lib, crate2nix }:
+let
+  crateConfigs = {
+    "helper" = {
+      crateName = "helper";
+      crateBin = [{ name = "helper"; } { name = "helper-cli"; }];
+    };
+    "shared" = {
+      crateName = "shared";
+    };
+  };
+  buildByPackageId = packageId: {
+    outPath = "/nix/store/${packageId}";
+    lib = "/nix/store/${packageId}-lib";
+  };
+  buildForTargetByPackageId = packageId: {
+    outPath = "/nix/store/${packageId}-target";
+  };
+  env = dependencies: crate2nix.artifactDependencyEnv {
+    inherit crateConfigs buildByPackageId buildForTargetByPackageId dependencies;
+    features = [ ];
+    target = { };
+  };
+in
+{
+  testAllBinaries = {
+    expr = env [{ name = "helper"; packageId = "helper"; artifact.kinds = [ "bin" ]; }];
+    expected = {
+      CARGO_BIN_DIR_HELPER = "/nix/store/helper/bin";
+      CARGO_BIN_FILE_HELPER = "/nix/store/helper/bin/helper";
+      CARGO_BIN_FILE_HELPER_helper = "/nix/store/helper/bin/helper";
+      CARGO_BIN_FILE_HELPER_helper-cli = "/nix/store/helper/bin/helper-cli";
+    };
+  };
+
+  testSelectedBinaryForTarget = {
+    expr = env [{
+      name = "helper";
+      rename = "my-helper";
+      packageId = "helper";
+      artifact = { kinds = [ "bin:helper-cli" ]; target = "target"; };
+    }];
+    expected = {
+      CARGO_BIN_DIR_MY_HELPER = "/nix/store/helper-target/bin";
+      CARGO_BIN_FILE_MY_HELPER_helper-cli = "/nix/store/helper-target/bin/helper-cli";
+    };
+  };
+
+  testLibraries = {
+    expr = env [{ name = "shared"; packageId = "shared"; artifact.kinds = [ "cdylib" "staticlib" ]; }];
+    expected = {
+      CARGO_CDYLIB_DIR_SHARED = "/nix/store/shared-lib/lib";
+      CARGO_STATICLIB_DIR_SHARED = "/nix/store/shared-lib/lib";
+    };
+  };
+
+  testIgnoresOtherDependencies = {
+    expr = env [{ name = "shared"; packageId = "shared"; }];
+    expected = { };
+  };
+}
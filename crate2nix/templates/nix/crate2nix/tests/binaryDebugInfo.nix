@@ -0,0 +1,28 @@
+{ lib, crate2nix }:
+{
+  testDefault = {
+    expr = crate2nix.binaryDebugInfo { };
+    expected = { extraRustcOpts = [ ]; attrs = { }; };
+  };
+
+  testStrip = {
+    expr = crate2nix.binaryDebugInfo { strip = true; };
+    expected = {
+      extraRustcOpts = [ ];
+      attrs = { dontStrip = false; stripAllList = [ "bin" ]; };
+    };
+  };
+
+  testSeparate = {
+    expr = crate2nix.binaryDebugInfo { isRelease = true; strip = true; separate = true; };
+    expected = {
+      extraRustcOpts = [ "-C debuginfo=2" ];
+      attrs = { dontStrip = false; stripAllList = [ "bin" ]; separateDebugInfo = true; };
+    };
+  };
+
+  testDevBuild = {
+    expr = crate2nix.binaryDebugInfo { isRelease = false; strip = true; separate = true; };
+    expected = { extraRustcOpts = [ ]; attrs = { }; };
+  };
+}
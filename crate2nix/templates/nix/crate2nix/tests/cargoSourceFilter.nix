@@ -0,0 +1,54 @@
+{ lib, crate2nix }:
+let
+  src = ./cargo-source-filter;
+  isIncluded = globs: path: type:
+    (crate2nix.cargoSourceFilter globs src) "${toString src}/${path}" type;
+in
+{
+  testExcludeFile = {
+    expr = isIncluded { exclude = [ "*.png" ]; } "docs/logo.png" "regular";
+    expected = false;
+  };
+
+  testExcludeKeepsOtherFiles = {
+    expr = isIncluded { exclude = [ "*.png" ]; } "src/lib.rs" "regular";
+    expected = true;
+  };
+
+  testExcludeAnchoredDirectory = {
+    expr = isIncluded { exclude = [ "/ci" ]; } "ci/run.sh" "regular";
+    expected = false;
+  };
+
+  testIncludeDirectory = {
+    expr = isIncluded { include = [ "/src" ]; } "src/bin/main.rs" "regular";
+    expected = true;
+  };
+
+  testIncludeDropsUnmatchedFiles = {
+    expr = isIncluded { include = [ "/src" ]; } "README.md" "regular";
+    expected = false;
+  };
+
+  testIncludeKeepsManifest = {
+    expr = isIncluded { include = [ "/src" ]; } "Cargo.toml" "regular";
+    expected = true;
+  };
+
+  testIncludeOverridesExclude = {
+    expr = isIncluded { include = [ "src/**" ]; exclude = [ "src" ]; } "src/lib.rs" "regular";
+    expected = true;
+  };
+
+  testTestIncludeOverridesExclude = {
+    expr = isIncluded { exclude = [ "/tests" ]; testInclude = [ "/tests" ]; } "tests/fixtures/data.json" "regular";
+    expected = true;
+  };
+
+  testTestIncludeWithInclude = {
+    expr = builtins.map
+      (path: isIncluded { include = [ "/src" ]; testInclude = [ "/tests" ]; } path "regular")
+      [ "tests/it.rs" "README.md" ];
+    expected = [ true false ];
+  };
+}
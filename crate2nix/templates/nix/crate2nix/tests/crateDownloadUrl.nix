@@ -0,0 +1,24 @@
+{ lib, crate2nix }:
+{
+  testWithoutMarkers = {
+    expr = crate2nix.crateDownloadUrl "https://mirror.example/crates" "serde" "1.0.0";
+    expected = "https://mirror.example/crates/serde/1.0.0/download";
+  };
+
+  testLowerPrefix = {
+    expr = crate2nix.crateDownloadUrl
+      "https://mirror.example/{lowerprefix}/{crate}/{crate}-{version}.crate" "Inflector" "0.11.4";
+    expected = "https://mirror.example/in/fl/Inflector/Inflector-0.11.4.crate";
+  };
+
+  testShortPrefixes = {
+    expr = builtins.map
+      (name: crate2nix.crateDownloadUrl "https://mirror.example/{prefix}" name "1.0.0")
+      [ "a" "ab" "syn" ];
+    expected = [
+      "https://mirror.example/1"
+      "https://mirror.example/2"
+      "https://mirror.example/3/s"
+    ];
+  };
+}
@@ -0,0 +1,23 @@
+{ lib, crate2nix }:
+let
+  withSources = crate2nix.override {
+    crateSources = {
+      "serde-1.0.0" = "/mirror/serde-1.0.0.crate";
+      "helper-0.1.0" = "/vendor/helper-0.1.0";
+    };
+  };
+in
+{
+  testReplacesCratesIoSource = {
+    expr = withSources.crateSrc { crateName = "serde"; version = "1.0.0"; sha256 = "0000000000000000000000000000000000000000000000000000"; };
+    expected = "/mirror/serde-1.0.0.crate";
+  };
+  testReplacesSrc = {
+    expr = withSources.crateSrc { crateName = "helper"; version = "0.1.0"; src = "/src/helper"; };
+    expected = "/vendor/helper-0.1.0";
+  };
+  testKeepsOtherVersions = {
+    expr = withSources.crateSrc { crateName = "helper"; version = "0.2.0"; src = "/src/helper"; };
+    expected = "/src/helper";
+  };
+}
@@ -0,0 +1,40 @@
+{ lib, crate2nix }:
+let
+  crates = crate2nix.cratesFromJSON {
+    "serde 1.0.0" = {
+      crateName = "serde";
+      version = "1.0.0";
+      edition = "2018";
+      sha256 = "abc";
+      dependencies = [
+        { name = "libc"; packageId = "libc"; target = { all = [ { name = "unix"; } { not = { key = "target_os"; value = "macos"; }; } ]; }; }
+        { name = "derive"; packageId = "derive"; target = { any = [ { key = "feature"; value = "derive"; } ]; }; }
+        { name = "helper"; packageId = "helper"; }
+      ];
+    };
+  };
+  targets = features: target: builtins.map
+    (dep: if dep ? target then dep.target { inherit target features; } else true)
+    crates."serde 1.0.0".dependencies;
+in
+{
+  testKeepsAttributes = {
+    expr = builtins.removeAttrs crates."serde 1.0.0" [ "dependencies" ];
+    expected = {
+      crateName = "serde";
+      version = "1.0.0";
+      edition = "2018";
+      sha256 = "abc";
+    };
+  };
+
+  testLinux = {
+    expr = targets [ ] { unix = true; os = "linux"; };
+    expected = [ true false true ];
+  };
+
+  testMacosWithFeature = {
+    expr = targets [ "derive" ] { unix = true; os = "macos"; };
+    expected = [ false true true ];
+  };
+}
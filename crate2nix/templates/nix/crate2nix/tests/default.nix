@@ -3,11 +3,32 @@ let
   inherit (pkgs) lib;
   crate2nix = pkgs.callPackage ../default.nix { };
   testFiles = [
+    "artifactDependencyEnv"
+    "binaryDebugInfo"
+    "cargoSourceFilter"
+    "crateDownloadUrl"
+    "crateSrc"
+    "cratesFromJSON"
     "dependencyDerivations"
     "dependencyFeatures"
     "enableFeatures"
     "expandFeatures"
+    "flakeApps"
+    "gitignoreSourceFilter"
+    "libtestCommand"
+    "linksDependencyHook"
+    "mergeCrateOverrides"
+    "nixosModule"
+    "outDirDependencyEnv"
     "packageFeatures"
+    "reproducibleBuild"
+    "rustToolchainWarning"
+    "rustVersionTooOldError"
+    "sourceReplacementsHook"
+    "stablePackageIds"
+    "unsupportedPlatformWarning"
+    "vendoredPackageIds"
+    "workspaceMemberRunTests"
   ];
   testsInFile = f:
     let
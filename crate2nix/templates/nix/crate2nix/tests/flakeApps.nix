@@ -0,0 +1,25 @@
+{ lib, crate2nix }:
+let
+  members = {
+    client.build = "/nix/store/client";
+    server.build = "/nix/store/server";
+  };
+in
+{
+  testFlakeApps = {
+    expr = crate2nix.flakeApps members {
+      client = [ "client" "shared" ];
+      server = [ "server" "shared" ];
+    };
+    expected = {
+      client = { type = "app"; program = "/nix/store/client/bin/client"; };
+      server = { type = "app"; program = "/nix/store/server/bin/server"; };
+      shared = { type = "app"; program = "/nix/store/client/bin/shared"; };
+    };
+  };
+
+  testNoBinaries = {
+    expr = crate2nix.flakeApps members { };
+    expected = { };
+  };
+}
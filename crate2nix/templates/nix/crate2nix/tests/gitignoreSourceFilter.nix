@@ -0,0 +1,33 @@
+{ lib, crate2nix }:
+let
+  root = ./gitignore;
+  member = ./gitignore/member;
+  filter = crate2nix.gitignoreSourceFilter root member;
+  isIncluded = path: type: filter (toString path) type;
+in
+{
+  testRegularFileIsIncluded = {
+    expr = isIncluded ./gitignore/member/src/lib.rs "regular";
+    expected = true;
+  };
+
+  testIgnoredByRootGitignore = {
+    expr = isIncluded ./gitignore/member/debug.log "regular";
+    expected = false;
+  };
+
+  testIgnoredByMemberGitignore = {
+    expr = isIncluded ./gitignore/member/generated "directory";
+    expected = false;
+  };
+
+  testRootGitignoreDoesNotApplyToUnrelatedDir = {
+    expr = (crate2nix.gitignoreSourceFilter member member) (toString ./gitignore/member/debug.log) "regular";
+    expected = true;
+  };
+
+  testSourceFilterStillApplies = {
+    expr = isIncluded ./gitignore/member/target "directory";
+    expected = false;
+  };
+}
@@ -0,0 +1,34 @@
+{ lib, crate2nix }:
+{
+  testDefaultCommand = {
+    expr = crate2nix.libtestCommand { };
+    expected = "$f $testCrateFlags 2>&1 | tee -a $out";
+  };
+
+  testThreadsAndFilters = {
+    expr = crate2nix.libtestCommand { testThreads = 2; testFilters = [ "parser::" "it's slow" ]; };
+    expected = "$f $testCrateFlags --test-threads=2 parser:: 'it'\\''s slow' 2>&1 | tee -a $out";
+  };
+
+  testShard = {
+    expr = crate2nix.libtestCommand { testShard = { index = 1; count = 3; }; };
+    expected = ''
+      tests=$($f $testCrateFlags --list \
+        | sed -n 's/: test$//p' | awk '(NR - 1) % 3 == 1')
+      if [ -n "$tests" ]; then
+        $f $testCrateFlags --exact $tests 2>&1 | tee -a $out
+      else
+        echo "No tests of $f in shard 1 of 3." >> $out
+      fi'';
+  };
+
+  testWasmtimeRunner = {
+    expr = crate2nix.libtestCommand { testRunner = crate2nix.wasmtimeTestRunner "/wasmtime"; };
+    expected = "/wasmtime/bin/wasmtime run --dir=. $f $testCrateFlags 2>&1 | tee -a $out";
+  };
+
+  testShardOutOfRange = {
+    expr = (builtins.tryEval (crate2nix.libtestCommand { testShard = { index = 3; count = 3; }; })).success;
+    expected = false;
+  };
+}
@@ -0,0 +1,44 @@
+{ lib, crate2nix }:
+let
+  crateConfigs = {
+    "libz-sys" = {
+      crateName = "libz-sys";
+      links = "z";
+    };
+    "openssl-sys" = {
+      crateName = "openssl-sys";
+      links = "openssl";
+    };
+    "helper" = {
+      crateName = "helper";
+    };
+  };
+  buildByPackageId = packageId: {
+    lib = "/nix/store/${packageId}-lib";
+  };
+  hook = dependencies: crate2nix.linksDependencyHook {
+    inherit crateConfigs buildByPackageId dependencies;
+    features = [ ];
+    target = { };
+  };
+in
+{
+  testRenamesPrefix = {
+    expr = hook [{ name = "libz-sys"; packageId = "libz-sys"; }];
+    expected = ''
+      if [ -f /nix/store/libz-sys-lib/env ]; then
+        source <(sed -n 's/^export DEP_LIBZ_/export DEP_Z_/p' /nix/store/libz-sys-lib/env)
+      fi
+    '';
+  };
+
+  testMatchingPrefix = {
+    expr = hook [{ name = "openssl-sys"; packageId = "openssl-sys"; }];
+    expected = "";
+  };
+
+  testIgnoresOtherDependencies = {
+    expr = hook [{ name = "helper"; packageId = "helper"; }];
+    expected = "";
+  };
+}
@@ -0,0 +1,38 @@
+{ lib, crate2nix }:
+let
+  merged = crate2nix.mergeCrateOverrides [
+    {
+      zstd-sys = attrs: {
+        buildInputs = (attrs.buildInputs or [ ]) ++ [ "zstd" ];
+        ZSTD_SYS_USE_PKG_CONFIG = "1";
+      };
+      openssl-sys = _: { buildInputs = [ "openssl" ]; };
+    }
+    {
+      zstd-sys = attrs: { buildInputs = attrs.buildInputs ++ [ "pkg-config" ]; };
+      openssl-sys = _: { buildInputs = [ "libressl" ]; };
+    }
+    {
+      my-crate = _: { CARGO_FEATURE_X = "1"; };
+    }
+  ];
+in
+{
+  testLaterOverridesSeeEarlierResults = {
+    expr = merged.zstd-sys { crateName = "zstd-sys"; buildInputs = [ "cc" ]; };
+    expected = {
+      buildInputs = [ "cc" "zstd" "pkg-config" ];
+      ZSTD_SYS_USE_PKG_CONFIG = "1";
+    };
+  };
+
+  testLaterOverridesTakePrecedence = {
+    expr = merged.openssl-sys { crateName = "openssl-sys"; };
+    expected = { buildInputs = [ "libressl" ]; };
+  };
+
+  testKeepsOverridesOfSingleSets = {
+    expr = builtins.attrNames merged;
+    expected = [ "my-crate" "openssl-sys" "zstd-sys" ];
+  };
+}
@@ -0,0 +1,47 @@
+{ lib, crate2nix }:
+let
+  crate = {
+    type = "derivation";
+    outPath = "/nix/store/00000000000000000000000000000000-rust_hello-0.1.0";
+  };
+  # Stand-ins for the NixOS options that the module sets.
+  nixosOptions = {
+    options.systemd.services = lib.mkOption { type = lib.types.attrsOf lib.types.anything; };
+    options.users.users = lib.mkOption { type = lib.types.attrsOf lib.types.anything; };
+    options.users.groups = lib.mkOption { type = lib.types.attrsOf lib.types.anything; };
+  };
+  evalWith = serviceConfig: (lib.evalModules {
+    modules = [
+      (crate2nix.nixosModule { inherit crate; binName = "hello"; })
+      nixosOptions
+      { services.hello = serviceConfig; }
+    ];
+  }).config;
+  enabled = evalWith { enable = true; port = 8080; extraArgs = [ "--verbose" ]; };
+in
+{
+  testDisabledByDefault = {
+    expr = (evalWith { }).systemd.services;
+    expected = { };
+  };
+
+  testExecStart = {
+    expr = enabled.systemd.services.hello.serviceConfig.ExecStart;
+    expected = lib.escapeShellArgs [ "${crate.outPath}/bin/hello" "--verbose" ];
+  };
+
+  testPort = {
+    expr = enabled.systemd.services.hello.environment;
+    expected = { PORT = "8080"; };
+  };
+
+  testCreatesDefaultUser = {
+    expr = enabled.users.users.hello;
+    expected = { isSystemUser = true; group = "hello"; };
+  };
+
+  testKeepsConfiguredUser = {
+    expr = (evalWith { enable = true; user = "nobody"; }).users.users;
+    expected = { };
+  };
+}
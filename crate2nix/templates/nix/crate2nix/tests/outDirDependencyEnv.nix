@@ -0,0 +1,40 @@
+{ lib, crate2nix }:
+let
+  crateConfigs = {
+    "protos" = {
+      crateName = "protos";
+      exportOutDir = true;
+    };
+    "helper" = {
+      crateName = "helper";
+    };
+  };
+  buildByPackageId = packageId: {
+    lib = "/nix/store/${packageId}-lib";
+  };
+  env = dependencies: crate2nix.outDirDependencyEnv {
+    inherit crateConfigs buildByPackageId dependencies;
+    features = [ ];
+    target = { };
+  };
+in
+{
+  testExportedOutDir = {
+    expr = env [{ name = "protos"; packageId = "protos"; }];
+    expected = {
+      DEP_PROTOS_OUT_DIR = "/nix/store/protos-lib/out-dir";
+    };
+  };
+
+  testRenamedDependency = {
+    expr = env [{ name = "protos"; rename = "my-protos"; packageId = "protos"; }];
+    expected = {
+      DEP_MY_PROTOS_OUT_DIR = "/nix/store/protos-lib/out-dir";
+    };
+  };
+
+  testIgnoresOtherDependencies = {
+    expr = env [{ name = "helper"; packageId = "helper"; }];
+    expected = { };
+  };
+}
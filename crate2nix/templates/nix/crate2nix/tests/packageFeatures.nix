@@ -38,6 +38,24 @@ let
         }
       ];
     };
+    "pkg_with_platform_features" = {
+      crateName = "with_platform_features";
+      dependencies = [
+        {
+          name = "id1";
+          packageId = "pkg_id1";
+          target = { target, features }: ("macos" == target."os" or null);
+          features = [ "vendored" ];
+        }
+      ];
+      buildDependencies = [
+        {
+          name = "id2";
+          packageId = "pkg_id2";
+          target = { target, features }: ("macos" == target."os" or null);
+        }
+      ];
+    };
     "pkg_id1" = {
       crateName = "id1";
       features = {
@@ -63,6 +81,30 @@ let
       ];
     };
 
+    "pkg_dev_cycle" = {
+      crateName = "dev_cycle";
+      features = {
+        "default" = [ ];
+        "helper" = [ ];
+      };
+      devDependencies = [
+        {
+          name = "dev_cycle_helper";
+          packageId = "pkg_dev_cycle_helper";
+        }
+      ];
+    };
+    "pkg_dev_cycle_helper" = {
+      crateName = "dev_cycle_helper";
+      dependencies = [
+        {
+          name = "dev_cycle";
+          packageId = "pkg_dev_cycle";
+          features = [ "helper" ];
+        }
+      ];
+    };
+
     "pkg_num_bigint" = {
       crateName = "num-bigint";
     };
@@ -152,4 +194,61 @@ in
       "pkg_id1" = [ "default" "for_build" ];
     };
   };
+
+  testPlatformFeaturesOnLinuxFromMacos = {
+    expr = crate2nix.mergePackageFeatures {
+      target = crate2nix.makeDefaultTarget stdenv.hostPlatform // { os = "linux"; };
+      buildTarget = crate2nix.makeDefaultTarget stdenv.hostPlatform // { os = "macos"; };
+      packageId = "pkg_with_platform_features";
+      features = [ ];
+      inherit crateConfigs;
+    };
+    expected = {
+      "pkg_with_platform_features" = [ ];
+      "pkg_id2" = [ "default" ];
+    };
+  };
+
+  testPlatformFeaturesOnMacos = {
+    expr = crate2nix.mergePackageFeatures {
+      target = crate2nix.makeDefaultTarget stdenv.hostPlatform // { os = "macos"; };
+      packageId = "pkg_with_platform_features";
+      features = [ ];
+      inherit crateConfigs;
+    };
+    expected = {
+      "pkg_with_platform_features" = [ ];
+      "pkg_id1" = [ "default" "vendored" ];
+      "pkg_id2" = [ "default" ];
+    };
+  };
+
+  testPackageWithFeatureClashSplitBuildFeatures = {
+    expr = crate2nix.mergePackageFeatures {
+      target = crate2nix.makeDefaultTarget stdenv.hostPlatform;
+      packageId = "pkg_with_feature_clash";
+      features = [ ];
+      splitBuildFeatures = true;
+      inherit crateConfigs;
+    };
+    expected = {
+      "pkg_with_feature_clash" = [ ];
+      "pkg_id1" = [ "default" ];
+      "build pkg_id1" = [ "default" "for_build" ];
+    };
+  };
+
+  testDevDependencyCycle = {
+    expr = crate2nix.mergePackageFeatures {
+      target = crate2nix.makeDefaultTarget stdenv.hostPlatform // { test = true; };
+      packageId = "pkg_dev_cycle";
+      features = [ "default" ];
+      runTests = true;
+      inherit crateConfigs;
+    };
+    expected = {
+      "pkg_dev_cycle" = [ "default" "helper" ];
+      "pkg_dev_cycle_helper" = [ "default" ];
+    };
+  };
 }
@@ -0,0 +1,15 @@
+{ lib, crate2nix }:
+{
+  testDefault = {
+    expr = crate2nix.reproducibleBuild { };
+    expected = {
+      extraRustcOpts = [ "--remap-path-prefix=$NIX_BUILD_TOP=/build" ];
+      attrs = { SOURCE_DATE_EPOCH = "315532800"; };
+    };
+  };
+
+  testDisabled = {
+    expr = crate2nix.reproducibleBuild { enable = false; };
+    expected = { extraRustcOpts = [ ]; attrs = { }; };
+  };
+}
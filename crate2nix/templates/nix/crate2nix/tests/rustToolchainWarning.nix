@@ -0,0 +1,31 @@
+{ lib, crate2nix }:
+let
+  toolchain = channel: { inherit channel; components = [ ]; targets = [ ]; };
+in
+{
+  testNoToolchain = {
+    expr = crate2nix.rustToolchainWarning { toolchain = null; rustcVersion = "1.75.0"; };
+    expected = null;
+  };
+
+  testNamedChannel = {
+    expr = crate2nix.rustToolchainWarning { toolchain = toolchain "stable"; rustcVersion = "1.75.0"; };
+    expected = null;
+  };
+
+  testMatchingMinorVersion = {
+    expr = crate2nix.rustToolchainWarning { toolchain = toolchain "1.75"; rustcVersion = "1.75.2"; };
+    expected = null;
+  };
+
+  testOtherRustc = {
+    expr = crate2nix.rustToolchainWarning { toolchain = toolchain "1.75.0"; rustcVersion = null; };
+    expected = null;
+  };
+
+  testMismatchingVersion = {
+    expr = crate2nix.rustToolchainWarning { toolchain = toolchain "1.75.0"; rustcVersion = "1.75.2"; };
+    expected = "rust-toolchain.toml requests rust 1.75.0 but rustc 1.75.2 of pkgs is used. "
+      + "Pass the requested toolchain as `rustc`, e.g. from fenix or rust-overlay.";
+  };
+}
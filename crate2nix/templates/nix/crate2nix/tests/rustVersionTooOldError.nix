@@ -0,0 +1,46 @@
+{ lib, crate2nix }:
+let
+  crateConfigs = {
+    "old" = { crateName = "old"; version = "1.0.0"; rustVersion = "1.56.0"; };
+    "new" = { crateName = "new"; version = "2.0.0"; rustVersion = "1.75.0"; };
+    "any" = { crateName = "any"; version = "0.1.0"; };
+  };
+in
+{
+  testNewEnoughRustc = {
+    expr = crate2nix.rustVersionTooOldError {
+      inherit crateConfigs;
+      packageIds = [ "old" "new" "any" ];
+      rustcVersion = "1.75.0";
+    };
+    expected = null;
+  };
+
+  testOnlyCratesInBuild = {
+    expr = crate2nix.rustVersionTooOldError {
+      inherit crateConfigs;
+      packageIds = [ "old" "any" ];
+      rustcVersion = "1.60.0";
+    };
+    expected = null;
+  };
+
+  testUnknownRustcVersion = {
+    expr = crate2nix.rustVersionTooOldError {
+      inherit crateConfigs;
+      packageIds = [ "new" ];
+      rustcVersion = null;
+    };
+    expected = null;
+  };
+
+  testTooOldRustc = {
+    expr = crate2nix.rustVersionTooOldError {
+      inherit crateConfigs;
+      packageIds = [ "old" "new" "any" ];
+      rustcVersion = "1.70.0";
+    };
+    expected = "new 2.0.0 requires rustc 1.75.0 but rustc 1.70.0 is used. "
+      + "Pass a newer `rustc` to Cargo.nix or set `checkRustVersion = false` to try anyway.";
+  };
+}
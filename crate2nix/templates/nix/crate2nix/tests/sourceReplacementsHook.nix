@@ -0,0 +1,20 @@
+{ lib, crate2nix }:
+{
+  testNoReplacements = {
+    expr = crate2nix.sourceReplacementsHook [ ];
+    expected = "";
+  };
+
+  testQuotesArguments = {
+    expr = crate2nix.sourceReplacementsHook [
+      { file = "src/lib.rs"; from = "it's broken"; to = "fixed"; }
+      { file = "build.rs"; from = "/usr/include"; to = "$NIX_INCLUDE"; }
+    ];
+    expected = ''
+      substituteInPlace src/lib.rs \
+        --replace-fail 'it'\'''s broken' fixed
+      substituteInPlace build.rs \
+        --replace-fail /usr/include '$NIX_INCLUDE'
+    '';
+  };
+}
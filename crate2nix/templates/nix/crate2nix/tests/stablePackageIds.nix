@@ -0,0 +1,29 @@
+{ lib, crate2nix }:
+let
+  crateConfigs = {
+    "serde 1.0.100" = {
+      crateName = "serde";
+      version = "1.0.100";
+    };
+    "serde 1.0.200" = {
+      crateName = "serde";
+      version = "1.0.200";
+    };
+    "wasi" = {
+      crateName = "wasi";
+      version = "0.11.0+wasi-snapshot-preview1";
+    };
+  };
+in
+{
+  testStablePackageIds = {
+    expr = crate2nix.stablePackageIds { inherit crateConfigs; };
+    expected = {
+      "serde" = "serde 1.0.200";
+      "serde_1_0_100" = "serde 1.0.100";
+      "serde_1_0_200" = "serde 1.0.200";
+      "wasi" = "wasi";
+      "wasi_0_11_0_wasi_snapshot_preview1" = "wasi";
+    };
+  };
+}
@@ -0,0 +1,24 @@
+{ lib, crate2nix }:
+{
+  testAllPlatforms = {
+    expr = crate2nix.unsupportedPlatformWarning { system = "x86_64-linux"; generatedFor = null; };
+    expected = null;
+  };
+
+  testListedPlatform = {
+    expr = crate2nix.unsupportedPlatformWarning {
+      system = "aarch64-darwin";
+      generatedFor = [ "x86_64-linux" "aarch64-darwin" ];
+    };
+    expected = null;
+  };
+
+  testOtherPlatform = {
+    expr = crate2nix.unsupportedPlatformWarning {
+      system = "x86_64-windows";
+      generatedFor = [ "x86_64-linux" "aarch64-darwin" ];
+    };
+    expected = "Cargo.nix was generated with --platforms x86_64-linux,aarch64-darwin "
+      + "and lacks the dependencies for x86_64-windows. Add x86_64-windows to --platforms and regenerate it.";
+  };
+}
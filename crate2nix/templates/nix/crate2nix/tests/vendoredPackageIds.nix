@@ -0,0 +1,32 @@
+{ lib, crate2nix }:
+let
+  fakeDerivation = name: { type = "derivation"; inherit name; outPath = "/nix/store/${name}"; };
+  crateConfigs = {
+    "crates-io" = {
+      crateName = "serde";
+      version = "1.0.200";
+      sha256 = "1rlfd0d8k2mq4cm5d4b2i5zcmvk1gfmb7lkp1yfm8n3c2l2xzfsm";
+    };
+    "git" = {
+      crateName = "with_sub_crates";
+      version = "0.1.0";
+      src = fakeDerivation "source";
+    };
+    "local" = {
+      crateName = "my_crate";
+      version = "0.1.0";
+      src = lib.cleanSourceWith { src = ./.; };
+    };
+    "unresolved" = {
+      crateName = "broken";
+      version = "0.1.0";
+      src = builtins.throw "could not resolve";
+    };
+  };
+in
+{
+  testVendoredPackageIds = {
+    expr = crate2nix.vendoredPackageIds { inherit crateConfigs; };
+    expected = [ "crates-io" "git" ];
+  };
+}
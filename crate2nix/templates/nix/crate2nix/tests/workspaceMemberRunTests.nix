@@ -0,0 +1,30 @@
+{ lib, crate2nix }:
+let
+  crateConfigs = {
+    "main" = {
+      crateName = "main";
+      version = "0.1.0";
+    };
+    "helper" = {
+      crateName = "helper";
+      version = "0.1.0";
+    };
+  };
+in
+{
+  testDefault = {
+    expr = crate2nix.workspaceMemberRunTests { packageId = "main"; inherit crateConfigs; };
+    expected = false;
+  };
+
+  testByCrateName = {
+    expr = builtins.map
+      (packageId: crate2nix.workspaceMemberRunTests {
+        inherit packageId crateConfigs;
+        default = true;
+        byCrateName = { helper = false; };
+      })
+      [ "main" "helper" ];
+    expected = [ true false ];
+  };
+}
@@ -32,12 +32,12 @@ fn self_up_to_date() {
             nixpkgs_path: "../nix/nixpkgs.nix".to_string(),
             crate_hashes_json: PathBuf::from("./crate-hashes.json"),
             other_metadata_options: vec![],
-            use_cargo_lock_checksums: true,
-            read_crate_hashes: true,
+            metadata_cache: false,
+            ..Default::default()
         },
     )
     .unwrap();
-    let rerendered_default_nix = render::CARGO_NIX.render(&metadata).unwrap();
+    let rerendered_default_nix = render::render_cargo_nix(&metadata).unwrap();
     let actual_default_nix = std::fs::read_to_string("./Cargo.nix").unwrap();
     assert_eq!(actual_default_nix, rerendered_default_nix);
 
@@ -72,13 +72,12 @@ fn assert_up_to_date(project_dir: &Path) {
     let config = GenerateConfig {
         cargo_toml: vec![PathBuf::from("../").join(cargo_toml.clone())],
         output: PathBuf::from("../").join(output.clone()),
-        nixpkgs_path: "<nixpkgs>".to_string(),
         crate_hashes_json: PathBuf::from("../")
             .join(project_dir)
             .join("./crate-hashes.json"),
         other_metadata_options: vec![],
-        use_cargo_lock_checksums: true,
-        read_crate_hashes: true,
+        metadata_cache: false,
+        ..Default::default()
     };
     let metadata = BuildInfo::for_config(
         &GenerateInfo {
@@ -97,7 +96,7 @@ fn assert_up_to_date(project_dir: &Path) {
         &config,
     )
     .unwrap();
-    let rerendered_default_nix = render::CARGO_NIX.render(&metadata).unwrap();
+    let rerendered_default_nix = render::render_cargo_nix(&metadata).unwrap();
     let actual_default_nix = std::fs::read_to_string(&config.output).unwrap();
 
     assert_eq!(
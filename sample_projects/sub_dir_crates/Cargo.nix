@@ -7,9 +7,29 @@
 , pkgs ? import nixpkgs { config = {}; }
 , lib ? pkgs.lib
 , stdenv ? pkgs.stdenv
-, buildRustCrateForPkgs ? pkgs: pkgs.buildRustCrate
-  # This is used as the `crateOverrides` argument for `buildRustCrate`.
+  # The rustc and cargo to build with, the ones of `pkgs` by default.
+  # `pkgs.callPackage` passes the ones of `pkgs`, so these are only used if they differ.
+, rustc ? null
+, cargo ? rustc
+, buildRustCrateForPkgs ? pkgs:
+    if rustc == null || rustc == pkgs.rustc
+    then pkgs.buildRustCrate
+    else pkgs.buildRustCrate.override { inherit rustc cargo; }
+  # Whether to fail evaluation if a crate declares a `rust-version` newer than `rustc`.
+, checkRustVersion ? true
+  # This is used as the `crateOverrides` argument for `buildRustCrate`, merged with the
+  # built-in overrides for `-sys` crates and `extraCrateOverrides`, see `crateOverrides`.
 , defaultCrateOverrides ? pkgs.defaultCrateOverrides
+  # Crate overrides that take precedence over `defaultCrateOverrides`. Each override of a crate
+  # sees the attributes returned by the overrides with lower precedence.
+  # The overrides in crate2nix-overrides.nix next to this file are used if it exists.
+, extraCrateOverrides ?
+    if builtins.pathExists ./crate2nix-overrides.nix
+    then pkgs.callPackage ./crate2nix-overrides.nix { }
+    else { }
+  # Whether to add the built-in overrides of crate2nix for `-sys` crates, e.g. `pkg-config`
+  # and the library for `zstd-sys`, with the lowest precedence.
+, builtinSysOverrides ? true
   # The features to enable for the root_crate or the workspace_members.
 , rootFeatures ? [ "default" ]
   # If true, throw errors instead of issueing deprecation warnings.
@@ -20,11 +40,31 @@
 , targetFeatures ? []
   # Whether to perform release builds: longer compile times, faster binaries.
 , release ? true
+  # Whether `rootCrate.build` and `workspaceMembers.<name>.build` also build and run the tests
+  # of the crate. The tests of dependencies never run.
+, runWorkspaceTests ? false
+  # Overrides `runWorkspaceTests` for individual workspace members, e.g. `{ my_crate = true; }`.
+, runTestsByCrateName ? { }
+  # Whether to strip the symbol tables of the binaries of workspace members in release builds.
+, stripBinaries ? false
+  # Whether to move the debug info of the binaries of workspace members in release builds
+  # to a separate `debug` output, see `separateDebugInfo` of `stdenv.mkDerivation`.
+, separateDebugInfo ? false
+  # Whether to remove the build directory from paths that rustc embeds in the crates
+  # and to pin `SOURCE_DATE_EPOCH`, so that builds are reproducible across machines.
+, reproducible ? true
+  # Sources that replace the ones of individual crates by `<crateName>-<version>`, e.g.
+  # `{ "serde-1.0.203" = ./mirror/serde-1.0.203.crate; }` for air-gapped builds.
+  # Both packed crates and directories work.
+, crateSources ? { }
   # Additional crate2nix configuration if it exists.
 , crateConfig
   ? if builtins.pathExists ./crate-config.nix
     then pkgs.callPackage ./crate-config.nix {}
     else {}
+  # Additional arguments between these markers are kept when this file is regenerated.
+  # crate2nix:user-begin arguments
+  # crate2nix:user-end arguments
 }:
 
 rec {
@@ -39,6 +79,7 @@ rec {
     # You can override the features with rootCrate.build.override { features = [ "default" "feature1" ... ]; }.
     build = internal.buildRustCrateWithFeatures {
       inherit packageId;
+      runTests = internal.workspaceMemberRunTests { inherit packageId; };
     };
 
     # Debug support which might change between releases.
@@ -53,6 +94,7 @@ rec {
       packageId = "sub_dir_crates";
       build = internal.buildRustCrateWithFeatures {
         packageId = "sub_dir_crates";
+        runTests = internal.workspaceMemberRunTests { packageId = "sub_dir_crates"; };
       };
 
       # Debug support which might change between releases.
@@ -61,6 +103,13 @@ rec {
     };
   };
 
+  # Build derivations for all crates by attribute names that stay stable if other versions
+  # of a crate are added or removed: `<crateName>_<version>` (e.g. `serde_1_0_200`) and
+  # `<crateName>` for the newest version of a crate.
+  cratesByName = lib.mapAttrs
+    (_: packageId: internal.buildRustCrateWithFeatures { inherit packageId; })
+    (internal.stablePackageIds { });
+
   # A derivation that joins the outputs of all workspace members together.
   allWorkspaceMembers = pkgs.symlinkJoin {
       name = "all-workspace-members";
@@ -69,6 +118,28 @@ rec {
         in builtins.map (m: m.build) members;
   };
 
+  # Flake apps for the binaries of all workspace members.
+  # Use `apps = cargoNix.apps;` in the `perSystem` outputs of a flake for `nix run .#<binary>`.
+  apps = internal.flakeApps workspaceMembers {
+    "sub_dir_crates" = [ "sub_dir_crates" ];
+  };
+
+  # The downloaded crates in the layout of `cargo vendor --versioned-dirs`, for tools that
+  # expect a vendor directory. The crates of the workspace are not included.
+  vendorDir = internal.vendoredSources { };
+
+  # A shell in which plain `cargo build` and `cargo test` work offline with exactly the crates
+  # of this file, e.g. `nix-shell Cargo.nix -A devShell`.
+  devShell = internal.cargoDevShell {
+    sourceReplacements = {
+      "git+https://github.com/kolloch/with_sub_crates.git?rev=f8ad2b98ff0eb5fea4962f55e3ced5b0b5afe973" = { git = "https://github.com/kolloch/with_sub_crates.git"; rev = "f8ad2b98ff0eb5fea4962f55e3ced5b0b5afe973"; };
+    };
+  };
+
+  # Additional attributes between these markers are kept when this file is regenerated.
+  # crate2nix:user-begin attributes
+  # crate2nix:user-end attributes
+
   #
   # "internal" ("private") attributes that may change in every new version of crate2nix.
   #
@@ -84,17 +155,24 @@ rec {
     # * `devDependencies` as of now not used by `buildRustCrate` but used to
     #   inject test dependencies into the build
 
+    # Sources that are fetched once and used by several crates.
+    sources = {
+      "with_sub_crates-f8ad2b98ff0e" = pkgs.fetchgit {
+        url = "https://github.com/kolloch/with_sub_crates.git";
+        rev = "f8ad2b98ff0eb5fea4962f55e3ced5b0b5afe973";
+        fetchSubmodules = false;
+        sha256 = "0nlw7rg28p6bya040cbipq4jdcdp4h3q9shdjygfk2xkva9bjl8w";
+      };
+    };
+
     crates = {
       "lib1" = rec {
         crateName = "lib1";
         version = "0.1.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         workspace_member = null;
-        src = pkgs.fetchgit {
-          url = "https://github.com/kolloch/with_sub_crates.git";
-          rev = "f8ad2b98ff0eb5fea4962f55e3ced5b0b5afe973";
-          sha256 = "0nlw7rg28p6bya040cbipq4jdcdp4h3q9shdjygfk2xkva9bjl8w";
-        };
+        src = sources."with_sub_crates-f8ad2b98ff0e";
         authors = [
           "Peter Kolloch <info@eigenvalue.net>"
         ];
@@ -104,12 +182,9 @@ rec {
         crateName = "lib2";
         version = "0.1.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         workspace_member = null;
-        src = pkgs.fetchgit {
-          url = "https://github.com/kolloch/with_sub_crates.git";
-          rev = "f8ad2b98ff0eb5fea4962f55e3ced5b0b5afe973";
-          sha256 = "0nlw7rg28p6bya040cbipq4jdcdp4h3q9shdjygfk2xkva9bjl8w";
-        };
+        src = sources."with_sub_crates-f8ad2b98ff0e";
         authors = [
           "Peter Kolloch <info@eigenvalue.net>"
         ];
@@ -119,6 +194,7 @@ rec {
         crateName = "sub_dir_crates";
         version = "0.1.0";
         edition = "2018";
+        rustVersion = "1.31.0";
         crateBin = [
           {
             name = "sub_dir_crates";
@@ -126,7 +202,7 @@ rec {
             requiredFeatures = [ ];
           }
         ];
-        src = lib.cleanSourceWith { filter = sourceFilter;  src = ./.; };
+        src = lib.cleanSourceWith { filter = gitignoreSourceFilter ./. ./.;  src = ./.; };
         authors = [
           "Peter Kolloch <info@eigenvalue.net>"
         ];
@@ -144,6 +220,22 @@ rec {
       };
     };
 
+    # Whether to download crates.io crates with `pkgs.fetchCrate`, see `--no-fetch-crate`.
+    useFetchCrate = true;
+    # The download URL template for crates.io crates, see `--crates-io-mirror`.
+    cratesIoMirror = null;
+    # The version of cargo's feature resolver, "2" resolves the features of crates for the build
+    # platform separately.
+    featureResolver = "1";
+    # The nix systems whose dependencies this file contains, see `--platforms`. `null` for all.
+    platforms = null;
+    # The command that runs the test executables of the workspace members, see `--wasi`.
+    workspaceTestRunner = null;
+    # The rust target whose crates only see `core` and `alloc`, see `--no-std`. `null` for none.
+    noStd = null;
+    # The toolchain that rust-toolchain.toml requests. `null` if there is none.
+    rustToolchain = null;
+
     #
 # crate2nix/default.nix (excerpt start)
 #
@@ -171,7 +263,6 @@ rec {
   };
 
   /* Filters common temp files and build files. */
-  # TODO(pkolloch): Substitute with gitignore filter
   sourceFilter = name: type:
     let
       baseName = builtins.baseNameOf (builtins.toString name);
@@ -216,13 +307,97 @@ rec {
         || baseName == "tests.nix"
       );
 
+  /* Filters like `sourceFilter` and additionally drops everything that is matched by the
+    `.gitignore` files in `root` and in the directories between `root` and `src`.
+
+    `root` is the directory of the generated `Cargo.nix`, `src` the directory of a local crate.
+    If `src` is not below `root`, only the `.gitignore` file in `src` is considered.
+  */
+  gitignoreSourceFilter = root: src:
+    let
+      rootString = toString root;
+      srcString = toString src;
+      directories =
+        if srcString == rootString
+        then [ root ]
+        else if lib.hasPrefix "${rootString}/" srcString
+        then
+          let
+            components = lib.splitString "/" (lib.removePrefix "${rootString}/" srcString);
+          in
+          [ root ] ++ builtins.genList
+            (n: root + "/${lib.concatStringsSep "/" (lib.take (n + 1) components)}")
+            (builtins.length components)
+        else [ src ];
+      gitignoreFilters = builtins.map
+        (dir: pkgs.nix-gitignore.gitignoreFilterPure
+          (_: _: true)
+          (builtins.readFile (dir + "/.gitignore"))
+          dir)
+        (builtins.filter (dir: builtins.pathExists (dir + "/.gitignore")) directories);
+    in
+    name: type:
+      sourceFilter name type
+      && builtins.all (filter: filter name type) gitignoreFilters;
+
+  /* Filters the files of the local crate in `src` like `cargo package` does with the
+    `package.include` and `package.exclude` lists of gitignore-style patterns in `Cargo.toml`.
+
+    If `include` is not empty, `exclude` is ignored. `Cargo.toml` and the files matched by
+    `testInclude` are always kept, the latter is used for the sources of test runs.
+  */
+  cargoSourceFilter = { include ? [ ], exclude ? [ ], testInclude ? [ ] }: src:
+    let
+      srcString = toString src;
+      # Whether `relativePath` or any of its parent directories is matched by `patterns`.
+      matchedBy = patterns:
+        let
+          notMatched = pkgs.nix-gitignore.gitignoreFilterPure
+            (_: _: true)
+            (lib.concatStringsSep "\n" patterns)
+            src;
+        in
+        relativePath: type:
+          let
+            components = lib.splitString "/" relativePath;
+            parents = builtins.genList
+              (n: "${srcString}/${lib.concatStringsSep "/" (lib.take (n + 1) components)}")
+              (builtins.length components - 1);
+          in
+          !(notMatched "${srcString}/${relativePath}" type)
+          || builtins.any (parent: !(notMatched parent "directory")) parents;
+      included = matchedBy include;
+      excluded = matchedBy exclude;
+      testIncluded = matchedBy testInclude;
+    in
+    name: type:
+      let
+        relativePath = lib.removePrefix "${srcString}/" (toString name);
+      in
+      if relativePath == "Cargo.toml" then true
+      else if testInclude != [ ] && testIncluded relativePath type then true
+      else if include != [ ] then type == "directory" || included relativePath type
+      else !(excluded relativePath type);
+
   /* Returns a crate which depends on successful test execution
     of crate given as the second argument.
 
     testCrateFlags: list of flags to pass to the test exectuable
     testInputs: list of packages that should be available during test execution
+    testThreads, testFilters, testShard, testRunner: see `libtestCommand`
   */
-  crateWithTest = { crate, testCrate, testCrateFlags, testInputs, testPreRun, testPostRun }:
+  crateWithTest =
+    { crate
+    , testCrate
+    , testCrateFlags
+    , testInputs
+    , testPreRun
+    , testPostRun
+    , testThreads ? null
+    , testFilters ? [ ]
+    , testShard ? null
+    , testRunner ? null
+    }:
     assert builtins.typeOf testCrateFlags == "list";
     assert builtins.typeOf testInputs == "list";
     assert builtins.typeOf testPreRun == "string";
@@ -246,14 +421,14 @@ rec {
           testCommand = pkgs.lib.concatStringsSep "\n"
             (pkgs.lib.filter (s: s != "") [
               testPreRun
-              "$f $testCrateFlags 2>&1 | tee -a $out"
+              (libtestCommand { inherit testThreads testFilters testShard testRunner; })
               testPostRun
             ]);
         in
         pkgs.stdenvNoCC.mkDerivation {
           name = "run-tests-${testCrate.name}";
 
-          inherit (crate) src;
+          inherit (testCrate) src;
 
           inherit testCrateFlags;
 
@@ -293,17 +468,488 @@ rec {
           inherit test;
         };
       }
-      (lib.optionalString (stdenv.buildPlatform.canExecute stdenv.hostPlatform) ''
+      (lib.optionalString (testRunner != null || stdenv.buildPlatform.canExecute stdenv.hostPlatform) ''
         echo tested by ${test}
       '' + ''
         ${lib.concatMapStringsSep "\n" (output: "ln -s ${crate.${output}} ${"$"}${output}") crate.outputs}
       '');
 
+  /* Returns the command that runs the tests of the test executable `$f` with the flags in
+    `$testCrateFlags` and appends their output to `$out`.
+
+    testThreads: the number of tests to run in parallel, by default the number of CPUs
+    testFilters: only run the tests whose names contain one of these strings
+    testShard: `{ index; count; }` to only run every `count`th test, starting at the
+      zero-based `index`, so that `count` derivations run all tests
+    testRunner: a command that runs the test executable on the building platform, e.g.
+      `wasmtimeTestRunner wasmtime` for WASI, `null` to run it directly
+  */
+  libtestCommand = { testThreads ? null, testFilters ? [ ], testShard ? null, testRunner ? null }:
+    assert testThreads == null || builtins.isInt testThreads && testThreads > 0;
+    assert builtins.isList testFilters;
+    assert testShard == null || testShard.count > 0 && testShard.index >= 0 && testShard.index < testShard.count;
+    let
+      threadArgs = lib.optional (testThreads != null) "--test-threads=${toString testThreads}";
+      runner = lib.optionalString (testRunner != null) "${testRunner} ";
+      run = args: "${runner}$f $testCrateFlags${lib.concatMapStrings (arg: " ${arg}") args} 2>&1 | tee -a $out";
+    in
+    if testShard == null
+    then run (threadArgs ++ builtins.map lib.escapeShellArg testFilters)
+    else ''
+      tests=$(${runner}$f $testCrateFlags --list${lib.concatMapStrings (filter: " ${lib.escapeShellArg filter}") testFilters} \
+        | sed -n 's/: test$//p' | awk '(NR - 1) % ${toString testShard.count} == ${toString testShard.index}')
+      if [ -n "$tests" ]; then
+        ${run (threadArgs ++ [ "--exact" "$tests" ])}
+      else
+        echo "No tests of $f in shard ${toString testShard.index} of ${toString testShard.count}." >> $out
+      fi'';
+
+  /* Returns the test runner that runs the WASI test executables with `wasmtime`, with access to
+    the directory of the tests like cargo runs them.
+  */
+  wasmtimeTestRunner = wasmtime: "${wasmtime}/bin/wasmtime run --dir=.";
+
+  /* Returns the package IDs of all crates by attribute names that do not change when other
+    versions of a crate are added or removed.
+
+    Every crate is available as `<crateName>_<version>` with `.`, `+` and `-` in the version
+    replaced by `_`, e.g. `serde_1_0_200`. Additionally, `<crateName>` refers to the newest
+    version of a crate.
+  */
+  stablePackageIds = { crateConfigs ? crates }:
+    let
+      sanitizeVersion = builtins.replaceStrings [ "." "+" "-" ] [ "_" "_" "_" ];
+      withPackageIds = lib.mapAttrsToList
+        (packageId: crate: crate // { inherit packageId; })
+        crateConfigs;
+      versioned = builtins.map
+        (crate: lib.nameValuePair
+          "${crate.crateName}_${sanitizeVersion crate.version}"
+          crate.packageId)
+        withPackageIds;
+      newest = lib.mapAttrsToList
+        (crateName: versions:
+          let
+            sorted = builtins.sort
+              (a: b: builtins.compareVersions a.version b.version > 0)
+              versions;
+          in
+          lib.nameValuePair crateName (builtins.head sorted).packageId)
+        (builtins.groupBy (crate: crate.crateName) withPackageIds);
+    in
+    builtins.listToAttrs (versioned ++ newest);
+
+  /* Returns whether the `build` attribute of the workspace member `packageId` runs its tests:
+    the entry for its crate name in `byCrateName` if there is one, `default` otherwise.
+  */
+  workspaceMemberRunTests =
+    { packageId
+    , crateConfigs ? crates
+    , default ? runWorkspaceTests
+    , byCrateName ? runTestsByCrateName
+    }:
+    byCrateName.${crateConfigs.${packageId}.crateName} or default;
+
+  /* Returns how to build the crate that `buildRustCrateWithFeatures` was asked for:
+    `extraRustcOpts` and the attributes to set with `overrideAttrs`.
+
+    With `strip`, the binaries lose their symbol tables. With `separate`, rustc emits debug info
+    which ends up in a separate `debug` output, like `separateDebugInfo` of `mkDerivation`: the
+    `out` closure stays small while debuggers find the symbols via their build id. Dev builds keep
+    their debug info where it is.
+  */
+  binaryDebugInfo =
+    { isRelease ? release
+    , strip ? stripBinaries
+    , separate ? separateDebugInfo
+    }:
+    let
+      enabled = isRelease && (strip || separate);
+    in
+    {
+      extraRustcOpts = lib.optional (isRelease && separate) "-C debuginfo=2";
+      attrs = lib.optionalAttrs enabled (
+        { dontStrip = false; }
+        // lib.optionalAttrs strip { stripAllList = [ "bin" ]; }
+        // lib.optionalAttrs separate { separateDebugInfo = true; }
+      );
+    };
+
+  /* Returns the `extraRustcOpts` and attributes that make crate builds independent of
+    where and when they run.
+
+    rustc embeds source paths in panic messages, `file!()` and debug info. Those below the build
+    directory are replaced by `/build`, the build directory of sandboxed Linux builds, so that
+    builds without sandbox, e.g. on Darwin, produce the same binaries. `$NIX_BUILD_TOP` is
+    expanded by the build script of `buildRustCrate`. Build scripts that embed the build time
+    get a fixed `SOURCE_DATE_EPOCH`.
+  */
+  reproducibleBuild = { enable ? reproducible }:
+    {
+      extraRustcOpts = lib.optional enable "--remap-path-prefix=$NIX_BUILD_TOP=/build";
+      attrs = lib.optionalAttrs enable { SOURCE_DATE_EPOCH = "315532800"; };
+    };
+
+  /* Returns a sysroot with only the `core` and `alloc` libraries of `rustc` for `rustTarget`,
+    so that crates built against it fail to compile if they use std.
+  */
+  coreAllocSysroot = { rustc, rustTarget }:
+    pkgs.runCommand "core-alloc-sysroot-${rustTarget}" { } ''
+      lib=lib/rustlib/${rustTarget}/lib
+      src=$(${rustc}/bin/rustc --print sysroot)/$lib
+      mkdir -p $out/$lib
+      for name in core alloc compiler_builtins; do
+        ln -s $src/lib$name-*.rlib $out/$lib/
+      done
+    '';
+
+  /* Returns the `extraRustcOpts` that build crates for the `--no-std` target `noStd` against
+    a sysroot without std. Crates for other targets, the build platform and tests keep std.
+  */
+  noStdRustcOpts = { pkgs, isBuild, isTested }:
+    lib.optionals
+      (noStd != null && !isBuild && !isTested
+        && pkgs.stdenv.hostPlatform.rust.rustcTarget == noStd.rustTarget)
+      [
+        "--sysroot"
+        "${if noStd.sysroot != null then noStd.sysroot else coreAllocSysroot {
+          rustc = if rustc != null then rustc else pkgs.buildPackages.rustc;
+          inherit (noStd) rustTarget;
+        }}"
+      ];
+
+  /* Returns an OCI image that runs the binary `binName` of the crate derivation `crate`.
+
+    The image configuration can be changed with `.override`, e.g.
+    `dockerImage { ... }.override { exposedPorts = [ 8080 "53/udp" ]; }`.
+    Ports without protocol use TCP.
+  */
+  dockerImage = lib.makeOverridable (
+    { crate
+    , binName
+    , name ? binName
+    , tag ? crate.version or "latest"
+    , entrypoint ? [ "${crate}/bin/${binName}" ]
+    , exposedPorts ? [ ]
+    , config ? { }
+    }:
+    let
+      portName = port: if builtins.isString port then port else "${toString port}/tcp";
+    in
+    pkgs.dockerTools.buildLayeredImage {
+      inherit name tag;
+      contents = [ crate ];
+      config = {
+        Entrypoint = entrypoint;
+        ExposedPorts = builtins.listToAttrs
+          (builtins.map (port: lib.nameValuePair (portName port) { }) exposedPorts);
+      } // config;
+    }
+  );
+
+  /* Returns the C header of the workspace member `packageId` in the `dev` output and the
+    `staticlib`/`cdylib` libraries of the crate derivation `crate` in `out`.
+
+    The header is generated by cbindgen from the library source, with the `cbindgen.toml` of
+    the crate if there is one and as C otherwise. Use `lang` to override the language of
+    the configuration.
+  */
+  cHeader = lib.makeOverridable (
+    { crate
+    , packageId
+    , crateConfig ? crates.${packageId}
+    , headerName ? "${lib.replaceStrings [ "-" ] [ "_" ] crateConfig.crateName}.h"
+    , lang ? null
+    , cbindgen ? pkgs.rust-cbindgen
+    }:
+    let
+      src = crateSrc crateConfig;
+      libPath = crateConfig.libPath or "src/lib.rs";
+    in
+    pkgs.runCommand "${crateConfig.crateName}-c-header-${crateConfig.version}"
+      {
+        outputs = [ "out" "dev" ];
+        nativeBuildInputs = [ cbindgen ];
+      }
+      ''
+        args=()
+        if [ -f ${src}/cbindgen.toml ]; then
+          args+=(--config ${src}/cbindgen.toml)
+        ${lib.optionalString (lang == null) ''
+        else
+          args+=(--lang c)
+        ''}
+        fi
+        ${lib.optionalString (lang != null) ''args+=(--lang ${lib.escapeShellArg lang})''}
+        mkdir -p $dev/include $out
+        cbindgen "''${args[@]}" --output $dev/include/${lib.escapeShellArg headerName} ${src}/${libPath}
+        ln -s ${crate.lib or crate}/lib $out/lib
+      ''
+  );
+
+  /* Returns the PyO3 extension module of the workspace member `packageId` as a package of
+    `python.pkgs`, e.g. for the `propagatedBuildInputs` of a Python package.
+
+    `crate` is built again with the pyo3 crates configured for `python`. The library is
+    installed as `moduleName`, where dots separate the packages, e.g. `my_package._native`.
+  */
+  pythonModule = lib.makeOverridable (
+    { crate
+    , packageId
+    , moduleName
+    , crateConfig ? crates.${packageId}
+    , python ? pkgs.python3
+    }:
+    let
+      withPython = overrides: name: extraAttrs:
+        overrides // {
+          ${name} = attrs:
+            let previous = (overrides.${name} or (_: { })) attrs;
+            in
+            previous // {
+              PYO3_PYTHON = python.interpreter;
+              nativeBuildInputs = (previous.nativeBuildInputs or attrs.nativeBuildInputs or [ ]) ++ [ python ];
+            } // extraAttrs previous;
+        };
+      # `-undefined dynamic_lookup` resolves the symbols of libpython when the module is loaded.
+      memberAttrs = previous: lib.optionalAttrs stdenv.hostPlatform.isDarwin {
+        extraRustcOpts = (previous.extraRustcOpts or [ ])
+          ++ [ "-C" "link-arg=-undefined" "-C" "link-arg=dynamic_lookup" ];
+      };
+      pythonCrateOverrides = overrides: lib.foldl'
+        (overrides: name: withPython overrides name (_: { }))
+        (withPython overrides crateConfig.crateName memberAttrs)
+        [ "pyo3" "pyo3-build-config" "pyo3-ffi" "pyo3-macros-backend" ];
+      built = crate.override (args: {
+        crateOverrides = pythonCrateOverrides args.crateOverrides;
+      });
+      libName = crateConfig.libName or (lib.replaceStrings [ "-" ] [ "_" ] crateConfig.crateName);
+      modulePath = "${python.sitePackages}/${lib.replaceStrings [ "." ] [ "/" ] moduleName}.so";
+    in
+    python.pkgs.toPythonModule (pkgs.runCommand "${python.libPrefix}-${crateConfig.crateName}-${crateConfig.version}"
+      { }
+      ''
+        mkdir -p $(dirname $out/${modulePath})
+        cp ${built.lib or built}/lib/lib${libName}${stdenv.hostPlatform.extensions.sharedLibrary} $out/${modulePath}
+      '')
+  );
+
+  /* Returns flake apps by binary name. `binaries` maps the names of workspace `members` to
+    the names of their binaries. If several members have a binary with the same name,
+    the member that comes first alphabetically wins.
+  */
+  flakeApps = members: binaries:
+    builtins.listToAttrs (lib.concatLists (lib.mapAttrsToList
+      (member: binNames: builtins.map
+        (binName: lib.nameValuePair binName {
+          type = "app";
+          program = "${members.${member}.build}/bin/${binName}";
+        })
+        binNames)
+      binaries));
+
+  /* Returns the checks for `nix flake check`: the tests of all `members` as `<member>-tests`,
+    except for the crates with `skip-tests` in `[package.metadata.crate2nix]`, and, if the
+    workspace source `src` is given, `clippy`, `rustfmt` and `doc`. With an `advisoryDb`, e.g.
+    a checkout of https://github.com/rustsec/advisory-db, `audit` checks `Cargo.lock` with
+    `cargo audit`.
+
+    `cargoConfig` is a `.cargo/config.toml` that replaces the sources with vendored ones, so
+    that cargo runs offline. Set `clippy`, `rustfmt` or `doc` to `false` to leave them out.
+  */
+  flakeChecks =
+    { members
+    , src ? null
+    , cargoConfig ? null
+    , clippy ? true
+    , rustfmt ? true
+    , doc ? true
+    , advisoryDb ? null
+    , clippyArgs ? [ "-D" "warnings" ]
+    }:
+    let
+      toolchain =
+        if rustc == null
+        then [ pkgs.rustc pkgs.cargo ]
+        else lib.unique [ rustc cargo ];
+      cargoCheck = name: nativeBuildInputs: script: pkgs.runCommand "${name}-check"
+        {
+          nativeBuildInputs = toolchain ++ nativeBuildInputs;
+          CARGO_NET_OFFLINE = "true";
+        } ''
+        export CARGO_HOME="$(mktemp -d)"
+        ${lib.optionalString (cargoConfig != null) "ln -s ${cargoConfig} \"$CARGO_HOME/config.toml\""}
+        export CARGO_TARGET_DIR="$(mktemp -d)"
+        cp -r ${src} source
+        chmod -R u+w source
+        cd source
+        ${script}
+        mkdir -p $out
+      '';
+      tests = lib.mapAttrs'
+        (name: member: lib.nameValuePair "${name}-tests" (member.build.override { runTests = true; }))
+        (lib.filterAttrs (name: member: !(crates.${member.packageId}.skipTests or false)) members);
+    in
+    tests
+    // lib.optionalAttrs (src != null && clippy) {
+      clippy = cargoCheck "clippy" [ pkgs.clippy ]
+        "cargo clippy --frozen --workspace --all-targets -- ${lib.escapeShellArgs clippyArgs}";
+    }
+    // lib.optionalAttrs (src != null && rustfmt) {
+      rustfmt = cargoCheck "rustfmt" [ pkgs.rustfmt ] "cargo fmt --all --check";
+    }
+    // lib.optionalAttrs (src != null && doc) {
+      doc = cargoCheck "doc" [ ] ''
+        cargo doc --frozen --workspace --no-deps
+        mkdir -p $out/share
+        cp -r "$CARGO_TARGET_DIR/doc" $out/share/doc
+      '';
+    }
+    // lib.optionalAttrs (src != null && advisoryDb != null) {
+      audit = cargoCheck "audit" [ pkgs.cargo-audit ]
+        "cargo audit --no-fetch --db ${advisoryDb} --file Cargo.lock";
+    };
+
+  /* Returns a NixOS module that defines the `services.<name>` options and a systemd service
+    running the binary `binName` of the crate derivation `crate`.
+
+    This is meant as a starting point: if `port` is set, it is only passed to the service
+    in the `PORT` environment variable.
+  */
+  nixosModule = { crate, binName, name ? binName }:
+    { config, lib, ... }:
+    let
+      cfg = config.services.${name};
+      createUser = cfg.user == name;
+    in
+    {
+      options.services.${name} = {
+        enable = lib.mkEnableOption "the ${binName} service";
+        package = lib.mkOption {
+          type = lib.types.package;
+          default = crate;
+          description = "The package that provides bin/${binName}.";
+        };
+        user = lib.mkOption {
+          type = lib.types.str;
+          default = name;
+          description = "The user that runs the service. Created as system user if left at the default.";
+        };
+        port = lib.mkOption {
+          type = lib.types.nullOr lib.types.port;
+          default = null;
+          description = "The port passed to the service in the PORT environment variable.";
+        };
+        extraArgs = lib.mkOption {
+          type = lib.types.listOf lib.types.str;
+          default = [ ];
+          description = "Additional command line arguments for ${binName}.";
+        };
+      };
+
+      config = lib.mkIf cfg.enable {
+        systemd.services.${name} = {
+          description = "${binName} (built by crate2nix)";
+          wantedBy = [ "multi-user.target" ];
+          after = [ "network.target" ];
+          environment = lib.optionalAttrs (cfg.port != null) { PORT = toString cfg.port; };
+          serviceConfig = {
+            ExecStart = lib.escapeShellArgs ([ "${cfg.package}/bin/${binName}" ] ++ cfg.extraArgs);
+            User = cfg.user;
+            Restart = "on-failure";
+          };
+        };
+        users.users = lib.mkIf createUser {
+          ${name} = { isSystemUser = true; group = name; };
+        };
+        users.groups = lib.mkIf createUser { ${name} = { }; };
+      };
+    };
+
+  /* Turns the crates of a JSON file written with `crate2nix generate --crates-json` into the
+    attribute sets of `crates`: sources into derivations and dependency targets into functions.
+  */
+  cratesFromJSON = lib.mapAttrs (_: crate:
+    let
+      dependency = dep: dep // lib.optionalAttrs (dep ? target) {
+        target = { target, features }: evalCfgJSON { inherit target features; } dep.target;
+      };
+      dependencyLists = builtins.filter (name: crate ? ${name})
+        [ "dependencies" "buildDependencies" "devDependencies" ];
+    in
+    crate
+    // lib.genAttrs dependencyLists (name: builtins.map dependency crate.${name})
+    // lib.optionalAttrs (crate ? src) {
+      src =
+        if crate.src ? fetchurl
+        then pkgs.fetchurl ({ name = "${crate.crateName}-${crate.version}.tar.gz"; } // crate.src.fetchurl)
+        else pkgs.fetchgit crate.src.fetchgit;
+    });
+
+  /* Evaluates the cfg tree of a dependency target in the JSON file of `cratesFromJSON`
+    like the nix expressions of the targets in `Cargo.nix`.
+  */
+  evalCfgJSON = { target, features }: cfg:
+    let
+      eval = evalCfgJSON { inherit target features; };
+      targetAttr = lib.removePrefix "target_";
+    in
+    if cfg ? all then lib.all eval cfg.all
+    else if cfg ? any then lib.any eval cfg.any
+    else if cfg ? not then !(eval cfg.not)
+    else if cfg ? rustcTarget then stdenv.hostPlatform.rust.rustcTarget == cfg.rustcTarget
+    else if cfg ? name then target.${targetAttr cfg.name} or false
+    else if cfg.key == "feature" then builtins.elem cfg.value features
+    else if cfg.key == "target_feature" then builtins.elem cfg.value targetFeatures
+    else if cfg.key == "target_family" then builtins.elem cfg.value target.family
+    else cfg.value == target.${targetAttr cfg.key} or null;
+
+  /* Merges a list of crate override sets, e.g. for the `crateOverrides` of `buildRustCrate`.
+
+    Later sets take precedence: the overrides of a crate are applied in order, each to the
+    attributes of the crate with the results of the previous ones, and later results replace the
+    attributes of earlier ones. To add to a list, use e.g.
+    `buildInputs = (attrs.buildInputs or [ ]) ++ [ pkgs.openssl ];`.
+  */
+  mergeCrateOverrides = overrideSets:
+    lib.zipAttrsWith
+      (_: overrides: attrs:
+        lib.foldl' (merged: override: merged // override (attrs // merged)) { } overrides)
+      (builtins.map (lib.filterAttrs (_: lib.isFunction)) overrideSets);
+
+  /* Overrides for `-sys` crates that link to libraries found with `pkg-config`.
+
+    They have the lowest precedence, so `pkgs.defaultCrateOverrides` wins for crates that it knows.
+  */
+  sysCrateOverrides =
+    let
+      withLibraries = libraries: env: attrs: {
+        nativeBuildInputs = (attrs.nativeBuildInputs or [ ]) ++ [ pkgs.pkg-config ];
+        buildInputs = (attrs.buildInputs or [ ]) ++ libraries;
+      } // env;
+    in
+    {
+      bzip2-sys = withLibraries [ pkgs.bzip2 ] { };
+      libdbus-sys = withLibraries [ pkgs.dbus ] { };
+      libssh2-sys = withLibraries [ pkgs.libssh2 pkgs.openssl pkgs.zlib ] { };
+      libsodium-sys = withLibraries [ pkgs.libsodium ] { SODIUM_USE_PKG_CONFIG = "1"; };
+      libudev-sys = withLibraries [ pkgs.udev ] { };
+      libusb1-sys = withLibraries [ pkgs.libusb1 ] { };
+      lzma-sys = withLibraries [ pkgs.xz ] { };
+      onig_sys = withLibraries [ pkgs.oniguruma ] { RUSTONIG_SYSTEM_LIBONIG = "1"; };
+      pq-sys = withLibraries [ pkgs.postgresql ] { };
+      zstd-sys = withLibraries [ pkgs.zstd ] { ZSTD_SYS_USE_PKG_CONFIG = "1"; };
+    };
+
+  /* The crate overrides that the crates are built with by default. */
+  mergedCrateOverrides = mergeCrateOverrides
+    ((lib.optional builtinSysOverrides sysCrateOverrides) ++ [ defaultCrateOverrides extraCrateOverrides ]);
+
   /* A restricted overridable version of builtRustCratesWithFeatures. */
   buildRustCrateWithFeatures =
     { packageId
     , features ? rootFeatures
-    , crateOverrides ? defaultCrateOverrides
+    , crateOverrides ? mergedCrateOverrides
     , buildRustCrateForPkgsFunc ? null
     , runTests ? false
     , testCrateFlags ? [ ]
@@ -312,6 +958,13 @@ rec {
     , testPreRun ? ""
       # Any command run immediatelly after a test is executed.
     , testPostRun ? ""
+      # The number of tests to run in parallel, the tests to run and the shard of them, see
+      # `libtestCommand`.
+    , testThreads ? null
+    , testFilters ? [ ]
+    , testShard ? null
+      # The command that runs the test executables, e.g. wasmtime for WASI, see `libtestCommand`.
+    , testRunner ? workspaceTestRunner
     }:
     lib.makeOverridable
       (
@@ -322,6 +975,10 @@ rec {
         , testInputs
         , testPreRun
         , testPostRun
+        , testThreads
+        , testFilters
+        , testShard
+        , testRunner
         }:
         let
           buildRustCrateForPkgsFuncOverriden =
@@ -354,16 +1011,95 @@ rec {
                 {
                   crate = drv;
                   testCrate = testDrv;
-                  inherit testCrateFlags testInputs testPreRun testPostRun;
+                  inherit testCrateFlags testInputs testPreRun testPostRun testThreads testFilters testShard testRunner;
                 }
             else drv;
+          warnings = builtins.filter (warning: warning != null) [
+            (unsupportedPlatformWarning { })
+            (rustToolchainWarning { })
+          ];
+          rustVersionError =
+            if checkRustVersion
+            then rustVersionTooOldError { inherit (builtRustCrates) packageIds; }
+            else null;
         in
-        derivation
+        lib.foldr lib.warn
+          (if rustVersionError != null then builtins.throw rustVersionError else derivation)
+          warnings
       )
-      { inherit features crateOverrides runTests testCrateFlags testInputs testPreRun testPostRun; };
+      { inherit features crateOverrides runTests testCrateFlags testInputs testPreRun testPostRun testThreads testFilters testShard testRunner; };
+
+  /* Returns a warning if `Cargo.nix` was generated with `--platforms` and lacks the dependencies
+    for `system`, `null` otherwise.
+  */
+  unsupportedPlatformWarning =
+    { system ? stdenv.hostPlatform.system
+    , generatedFor ? platforms
+    }:
+    if generatedFor == null || builtins.elem system generatedFor
+    then null
+    else
+      "Cargo.nix was generated with --platforms ${lib.concatStringsSep "," generatedFor} "
+      + "and lacks the dependencies for ${system}. Add ${system} to --platforms and regenerate it.";
+
+  /* Returns a warning if `rust-toolchain.toml` pins a rust version but the rustc of `pkgs` with
+    a different version is used, `null` otherwise.
+
+    Channels like "stable" or "nightly" are not checked.
+  */
+  rustToolchainWarning =
+    { toolchain ? rustToolchain
+    , rustcVersion ? if rustc == null || rustc == pkgs.rustc then pkgs.rustc.version else null
+    }:
+    let
+      channel = toolchain.channel;
+      pinsVersion = builtins.match "[0-9]+\\.[0-9]+(\\.[0-9]+)?" channel != null;
+      matches =
+        if builtins.length (lib.splitString "." channel) == 3
+        then rustcVersion == channel
+        else lib.versions.majorMinor rustcVersion == channel;
+    in
+    if toolchain == null || rustcVersion == null || !pinsVersion || matches
+    then null
+    else
+      "rust-toolchain.toml requests rust ${channel} but rustc ${rustcVersion} of pkgs is used. "
+      + "Pass the requested toolchain as `rustc`, e.g. from fenix or rust-overlay.";
+
+  /* Returns an error message if one of the crates of `packageIds` needs a newer rustc than
+    `rustcVersion`, `null` otherwise.
+
+    The `rustVersion` of a crate is its `rust-version` or the first rustc version that supports its
+    edition. The message names the crate with the newest `rustVersion`.
+  */
+  rustVersionTooOldError =
+    { packageIds
+    , crateConfigs ? crates
+    , rustcVersion ? (if rustc == null then pkgs.rustc else rustc).version or null
+    }:
+    let
+      withRustVersion = builtins.filter
+        (packageId: crateConfigs.${packageId} ? rustVersion)
+        packageIds;
+      newest = lib.foldl'
+        (newest: packageId:
+          let crateConfig = crateConfigs.${packageId};
+          in
+          if newest == null || lib.versionOlder newest.rustVersion crateConfig.rustVersion
+          then crateConfig
+          else newest)
+        null
+        withRustVersion;
+    in
+    if rustcVersion == null || newest == null || !(lib.versionOlder rustcVersion newest.rustVersion)
+    then null
+    else
+      "${newest.crateName} ${newest.version} requires rustc ${newest.rustVersion} but rustc ${rustcVersion} is used. "
+      + "Pass a newer `rustc` to Cargo.nix or set `checkRustVersion = false` to try anyway.";
 
   /* Returns an attr set with packageId mapped to the result of buildRustCrateForPkgsFunc
     for the corresponding crate.
+
+    `packageIds` lists the crates that are part of the build.
   */
   builtRustCratesWithFeatures =
     { packageId
@@ -380,34 +1116,39 @@ rec {
       assert (builtins.isBool runTests);
       let
         rootPackageId = packageId;
+        splitBuildFeatures = featureResolver == "2";
         mergedFeatures = mergePackageFeatures
           (
             args // {
-              inherit rootPackageId;
+              inherit rootPackageId splitBuildFeatures;
               target = makeTarget stdenv.hostPlatform // { test = runTests; };
+              buildTarget = makeTarget stdenv.buildPlatform;
             }
           );
         # Memoize built packages so that reappearing packages are only built once.
-        builtByPackageIdByPkgs = mkBuiltByPackageIdByPkgs pkgs;
-        mkBuiltByPackageIdByPkgs = pkgs:
+        builtByPackageIdByPkgs = mkBuiltByPackageIdByPkgs false pkgs;
+        mkBuiltByPackageIdByPkgs = isBuild: pkgs:
           let
             self = {
-              crates = lib.mapAttrs (packageId: value: buildByPackageIdForPkgsImpl self pkgs packageId) crateConfigs;
-              target = makeTarget stdenv.hostPlatform;
-              build = mkBuiltByPackageIdByPkgs pkgs.buildPackages;
+              crates = lib.mapAttrs (packageId: value: buildByPackageIdForPkgsImpl self pkgs packageId false) crateConfigs;
+              target = makeTarget (if isBuild then stdenv.buildPlatform else stdenv.hostPlatform);
+              build = mkBuiltByPackageIdByPkgs true pkgs.buildPackages;
+              inherit isBuild;
             };
           in
           self;
-        buildByPackageIdForPkgsImpl = self: pkgs: packageId:
+        # `isTested` is only true for the root crate when running its tests.
+        buildByPackageIdForPkgsImpl = self: pkgs: packageId: isTested:
           let
-            features = mergedFeatures."${packageId}" or [ ];
+            features = mergedFeatures.${packageFeaturesKey {
+              inherit splitBuildFeatures packageId;
+              inherit (self) isBuild;
+            }} or [ ];
             crateConfig' = crateConfigs."${packageId}";
             crateConfig =
-              builtins.removeAttrs crateConfig' [ "resolvedDefaultFeatures" "devDependencies" ];
+              builtins.removeAttrs crateConfig' [ "resolvedDefaultFeatures" "devDependencies" "buildScriptEnv" "preBuilt" "exportOutDir" "storeManifestDir" "skipTests" "crateEnv" "crateReplacements" "testSrc" "rustVersion" "profileRustcOpts" "crateRustcOpts" "provenance" ];
             devDependencies =
-              lib.optionals
-                (runTests && packageId == rootPackageId)
-                (crateConfig'.devDependencies or [ ]);
+              lib.optionals isTested (crateConfig'.devDependencies or [ ]);
             dependencies =
               dependencyDerivations {
                 inherit features;
@@ -464,27 +1205,277 @@ rec {
                   { inherit (dep) rename; inherit (package) version; };
               in
               lib.mapAttrs (name: builtins.map versionAndRename) grouped;
+            # Paths to the binaries and libraries of artifact dependencies as cargo would set them.
+            artifactEnv =
+              artifactDependencyEnv
+                {
+                  inherit features crateConfigs;
+                  inherit (self) target;
+                  buildByPackageId = depPackageId: self.crates.${depPackageId};
+                  dependencies = crateConfig.dependencies or [ ] ++ devDependencies;
+                }
+              // artifactDependencyEnv {
+                inherit features crateConfigs;
+                inherit (self.build) target;
+                buildByPackageId = depPackageId: self.build.crates.${depPackageId};
+                buildForTargetByPackageId = depPackageId: self.crates.${depPackageId};
+                dependencies = crateConfig.buildDependencies or [ ];
+              };
+            # The build script outputs of dependencies with `exportOutDir`.
+            outDirEnv =
+              outDirDependencyEnv {
+                inherit features crateConfigs;
+                inherit (self) target;
+                buildByPackageId = depPackageId: self.crates.${depPackageId};
+                dependencies = crateConfig.dependencies or [ ];
+              };
+            # Installs the `OUT_DIR` of the build script and points the metadata in `env` there.
+            exportOutDirHook = lib.optionalString (crateConfig'.exportOutDir or false) ''
+              if [ -d target/build/${crateConfig.crateName}.out ]; then
+                mkdir -p $lib/out-dir
+                cp -r target/build/${crateConfig.crateName}.out/. $lib/out-dir
+                if [ -f $lib/env ]; then
+                  sed -i "s|$(pwd)/target/build/${crateConfig.crateName}.out|$lib/out-dir|g" $lib/env
+                fi
+              fi
+            '';
+            # Copies the crate to the nix store so that paths from `env!("CARGO_MANIFEST_DIR")`
+            # still exist at runtime.
+            storeManifestDirHook = lib.optionalString (crateConfig'.storeManifestDir or false) ''
+              mkdir -p $lib/manifest-dir
+              tar --exclude=./target -cf - . | tar -xf - -C $lib/manifest-dir
+              export CARGO_MANIFEST_DIR=$lib/manifest-dir
+            '';
+            # The `replacements` of crate2nix.json.
+            replacementsHook = sourceReplacementsHook (crateConfig'.crateReplacements or [ ]);
+            # The metadata of `links` dependencies under the names that cargo uses.
+            linksHook =
+              linksDependencyHook {
+                inherit features crateConfigs;
+                inherit (self) target;
+                buildByPackageId = depPackageId: self.crates.${depPackageId};
+                dependencies = crateConfig.dependencies or [ ];
+              };
+            # The flags for the `[profile]` settings of the workspace. Tests need to unwind, so crates
+            # are built with `panic = "abort"` only if no tests run.
+            profileRustcOpts = builtins.filter
+              (opt: !(runTests && opt == "-C panic=abort"))
+              (crateConfig'.profileRustcOpts.${if release then "release" else "dev"} or [ ]);
+            # Stripping and separate debug info only apply to the requested crate, e.g. a workspace member.
+            binaryDebugInfo' =
+              if packageId == rootPackageId && !self.isBuild
+              then binaryDebugInfo { }
+              else binaryDebugInfo { strip = false; separate = false; };
+            reproducibleBuild' = reproducibleBuild { };
+            withBinaryDebugInfo = drv:
+              if binaryDebugInfo'.attrs == { }
+              then drv
+              else drv.overrideAttrs (_: binaryDebugInfo'.attrs);
+            # Where the source comes from, e.g. for auditing the supply chain.
+            withProvenance = drv:
+              if crateConfig' ? provenance
+              then lib.addMetaAttrs { inherit (crateConfig') provenance; } drv
+              else drv;
           in
-          buildRustCrateForPkgsFunc pkgs
+          # Substitutes for crates whose build scripts need network access, see `buildScripts` in crate2nix.json.
+          crateConfig'.preBuilt or (withProvenance (withBinaryDebugInfo (buildRustCrateForPkgsFunc pkgs
             (
               crateConfig // {
-                src = crateConfig.src or (
-                  pkgs.fetchurl rec {
-                    name = "${crateConfig.crateName}-${crateConfig.version}.tar.gz";
-                    # https://www.pietroalbini.org/blog/downloading-crates-io/
-                    # Not rate-limited, CDN URL.
-                    url = "https://static.crates.io/crates/${crateConfig.crateName}/${crateConfig.crateName}-${crateConfig.version}.crate";
-                    sha256 =
-                      assert (lib.assertMsg (crateConfig ? sha256) "Missing sha256 for ${name}");
-                      crateConfig.sha256;
-                  }
-                );
-                extraRustcOpts = lib.lists.optional (targetFeatures != [ ]) "-C target-feature=${lib.concatMapStringsSep "," (x: "+${x}") targetFeatures}";
+                src = crateSrc crateConfig;
+                extraRustcOpts = lib.lists.optional (targetFeatures != [ ]) "-C target-feature=${lib.concatMapStringsSep "," (x: "+${x}") targetFeatures}"
+                  ++ profileRustcOpts
+                  ++ (crateConfig'.crateRustcOpts or [ ])
+                  ++ binaryDebugInfo'.extraRustcOpts
+                  ++ reproducibleBuild'.extraRustcOpts
+                  ++ noStdRustcOpts { inherit pkgs isTested; inherit (self) isBuild; };
                 inherit features dependencies buildDependencies crateRenames release;
-              }
-            );
+              } // lib.optionalAttrs (linksHook != "") { preConfigure = linksHook + (crateConfig.preConfigure or ""); }
+              // lib.optionalAttrs (replacementsHook != "") { postPatch = (crateConfig.postPatch or "") + replacementsHook; }
+              // lib.optionalAttrs (exportOutDirHook != "") { postInstall = exportOutDirHook + (crateConfig.postInstall or ""); }
+              // lib.optionalAttrs (storeManifestDirHook != "") { preBuild = storeManifestDirHook + (crateConfig.preBuild or ""); }
+              // reproducibleBuild'.attrs // artifactEnv // outDirEnv // (crateConfig'.buildScriptEnv or { }) // (crateConfig'.crateEnv or { })
+              // lib.optionalAttrs (isTested && crateConfig' ? testSrc) { src = crateConfig'.testSrc; }
+            ))));
       in
-      builtByPackageIdByPkgs;
+      # Crates that depend on the root crate, e.g. because it dev-depends on them,
+      # use the root crate without its dev dependencies to avoid a cycle.
+      (if runTests
+      then
+        builtByPackageIdByPkgs // {
+          crates = builtByPackageIdByPkgs.crates // {
+            ${rootPackageId} = buildByPackageIdForPkgsImpl builtByPackageIdByPkgs pkgs rootPackageId true;
+          };
+        }
+      else builtByPackageIdByPkgs) // {
+        packageIds = lib.unique
+          (builtins.map (lib.removePrefix "build ") (builtins.attrNames mergedFeatures));
+      };
+
+  /* Returns the output paths of all crates built for the given root crates by packageId,
+    e.g. for `crate2nix report`, or another attribute of their derivations like `drvPath`.
+
+    Crates that cannot be evaluated, e.g. because they are not supported on this platform,
+    are left out.
+  */
+  builtCrateOutPaths = { packageIds, features ? rootFeatures, attr ? "outPath" }:
+    let
+      outPaths = packageId:
+        let
+          built = builtRustCratesWithFeatures {
+            inherit packageId features;
+            buildRustCrateForPkgsFunc = buildRustCrateForPkgs;
+            runTests = false;
+          };
+          outPath = id: builtins.tryEval built.crates.${id}.${attr};
+          evaluated = lib.genAttrs built.packageIds outPath;
+        in
+        lib.mapAttrs (id: result: result.value)
+          (lib.filterAttrs (id: result: result.success) evaluated);
+    in
+    lib.foldl' (acc: packageId: acc // outPaths packageId) { } packageIds;
+
+  /* Returns the source of a crate: its entry in `crateSources`, its `src` or the crate
+    downloaded from crates.io.
+  */
+  crateSrc = crateConfig:
+    crateSources."${crateConfig.crateName}-${crateConfig.version}" or crateConfig.src or (
+      let
+        name = "${crateConfig.crateName}-${crateConfig.version}.tar.gz";
+        sha256 =
+          assert (lib.assertMsg (crateConfig ? sha256) "Missing sha256 for ${name}");
+          crateConfig.sha256;
+      in
+      if cratesIoMirror != null
+      then
+        pkgs.fetchurl {
+          inherit name sha256;
+          url = crateDownloadUrl cratesIoMirror crateConfig.crateName crateConfig.version;
+        }
+      # The checksums in Cargo.lock are of the packed crate, hence `unpack = false`.
+      else if useFetchCrate
+      then
+        pkgs.fetchCrate {
+          inherit (crateConfig) crateName version;
+          inherit sha256;
+          unpack = false;
+        }
+      else
+        pkgs.fetchurl {
+          inherit name sha256;
+          # https://www.pietroalbini.org/blog/downloading-crates-io/
+          # Not rate-limited, CDN URL.
+          url = "https://static.crates.io/crates/${crateConfig.crateName}/${crateConfig.crateName}-${crateConfig.version}.crate";
+        }
+    );
+
+  /* Expands a download URL template like cargo does for the `dl` field in a registry's config.json.
+
+    Mirrors `download_url` in crate2nix/src/resolve.rs.
+  */
+  crateDownloadUrl = template: crateName: version:
+    let
+      prefix = name:
+        let length = builtins.stringLength name;
+        in
+        if length <= 2 then toString length
+        else if length == 3 then "3/${builtins.substring 0 1 name}"
+        else "${builtins.substring 0 2 name}/${builtins.substring 2 2 name}";
+      markers = [ "{crate}" "{version}" "{prefix}" "{lowerprefix}" ];
+      template' =
+        if lib.any (marker: lib.hasInfix marker template) markers
+        then template
+        else "${template}/{crate}/{version}/download";
+    in
+    builtins.replaceStrings
+      markers
+      [ crateName version (prefix crateName) (prefix (lib.toLower crateName)) ]
+      template';
+
+  /* Returns the package IDs of the crates from registries and git repositories,
+    i.e. the ones that are downloaded.
+  */
+  vendoredPackageIds = { crateConfigs ? crates }:
+    builtins.filter
+      (packageId:
+        let crateConfig = crateConfigs.${packageId};
+        in
+        !(crateConfig ? src)
+        # Crates that crate2nix could not resolve throw.
+        || (builtins.tryEval (lib.isDerivation crateConfig.src)).value)
+      (builtins.attrNames crateConfigs);
+
+  /* Returns a directory source for cargo with the downloaded crates of `crateConfigs`.
+
+    The layout is the one of `cargo vendor --versioned-dirs`: every crate is in a
+    `<crateName>-<version>` directory with a `.cargo-checksum.json` that lists the checksums of
+    its files and of the packed crate. Crates from git repositories are looked up by name in the
+    checkout and have no package checksum, like with cargo.
+  */
+  vendoredSources = { crateConfigs ? crates }:
+    let
+      vendorCrate = packageId:
+        let crateConfig = crateConfigs.${packageId};
+        in
+        lib.escapeShellArgs [
+          crateConfig.crateName
+          "${crateConfig.crateName}-${crateConfig.version}"
+          (crateSrc crateConfig)
+        ];
+    in
+    pkgs.runCommand "vendored-sources" { nativeBuildInputs = [ pkgs.jq ]; } ''
+      mkdir $out
+      vendor_crate() {
+        local name="$1" dir="$out/$2" src="$3"
+        if [ -f "$src" ]; then
+          mkdir "$dir"
+          tar -xzf "$src" --strip-components=1 -C "$dir"
+          checksum="\"$(sha256sum "$src" | cut -d' ' -f1)\""
+        else
+          mapfile -t manifests < <(grep -l -r -s --include Cargo.toml -E "^name *= *\"$name\"" "$src")
+          manifest="''${manifests[0]:-}"
+          if [ -z "$manifest" ]; then
+            echo "Could not find the crate $name in $src" >&2
+            exit 1
+          fi
+          cp -r "$(dirname "$manifest")" "$dir"
+          chmod -R u+w "$dir"
+          checksum=null
+        fi
+        # `sha256sum` prints the 64 hex digits of the checksum, two spaces and the file name.
+        files=$(cd "$dir" && find . -type f -printf '%P\0' | sort -z | xargs -0 -r sha256sum \
+          | jq -R -s 'split("\n") | map(select(. != "") | { key: .[66:], value: .[0:64] }) | from_entries')
+        jq -n -c --argjson files "$files" --argjson package "$checksum" \
+          '{ files: $files, package: $package }' > "$dir/.cargo-checksum.json"
+      }
+      ${lib.concatMapStringsSep "\n" (packageId: "vendor_crate ${vendorCrate packageId}")
+        (vendoredPackageIds { inherit crateConfigs; })}
+    '';
+
+  /* Returns a shell in which cargo builds offline with the crates of `crateConfigs`.
+
+    `sourceReplacements` are the `[source]` definitions in `.cargo/config.toml` of all downloaded
+    sources, by key. `CARGO_HOME` points to a temporary directory with that configuration.
+  */
+  cargoDevShell = { sourceReplacements, crateConfigs ? crates }:
+    let
+      replaceWith = "vendored-sources";
+      config = (pkgs.formats.toml { }).generate "cargo-config.toml" {
+        source = lib.mapAttrs (_: source: source // { replace-with = replaceWith; }) sourceReplacements
+          // { ${replaceWith}.directory = "${vendoredSources { inherit crateConfigs; }}"; };
+      };
+    in
+    pkgs.mkShell {
+      nativeBuildInputs =
+        if rustc == null
+        then [ pkgs.rustc pkgs.cargo ]
+        else lib.unique [ rustc cargo ];
+      CARGO_NET_OFFLINE = "true";
+      shellHook = ''
+        export CARGO_HOME="$(mktemp -d)"
+        ln -s ${config} "$CARGO_HOME/config.toml"
+      '';
+      passthru.cargoConfig = config;
+    };
 
   /* Returns the actual derivations for the given dependencies. */
   dependencyDerivations =
@@ -500,9 +1491,129 @@ rec {
         enabledDependencies = filterEnabledDependencies {
           inherit dependencies features target;
         };
+        # Artifact dependencies are only linked if they are also requested as library.
+        isLinked = dependency: !(dependency ? artifact) || dependency.artifact.lib or false;
         depDerivation = dependency: buildByPackageId dependency.packageId;
       in
-      map depDerivation enabledDependencies;
+      map depDerivation (builtins.filter isLinked enabledDependencies);
+
+  /* Returns the environment variables that cargo sets for the enabled artifact dependencies,
+    e.g. `CARGO_BIN_FILE_<DEP>_<NAME>` for `artifact = "bin"`.
+
+    Artifacts with `target = "target"` are built with `buildForTargetByPackageId`, all others
+    with `buildByPackageId`.
+  */
+  artifactDependencyEnv =
+    { buildByPackageId
+    , buildForTargetByPackageId ? buildByPackageId
+    , crateConfigs ? crates
+    , features
+    , dependencies
+    , target
+    }:
+    let
+      artifactDependencies = builtins.filter (dep: dep ? artifact)
+        (filterEnabledDependencies { inherit dependencies features target; });
+      envForDependency = dep:
+        let
+          depName = lib.toUpper (builtins.replaceStrings [ "-" ] [ "_" ] (dep.rename or dep.name));
+          crateConfig = crateConfigs.${dep.packageId};
+          drv =
+            if (dep.artifact.target or null) == "target"
+            then buildForTargetByPackageId dep.packageId
+            else buildByPackageId dep.packageId;
+          inherit (dep.artifact) kinds;
+          allBinNames = builtins.map (bin: bin.name)
+            (crateConfig.crateBin or [{ name = crateConfig.crateName; }]);
+          binNames =
+            if builtins.elem "bin" kinds
+            then allBinNames
+            else builtins.map (lib.removePrefix "bin:") (builtins.filter (lib.hasPrefix "bin:") kinds);
+          binFile = bin: "${drv}/bin/${bin}";
+        in
+        lib.optionalAttrs (binNames != [ ])
+          (
+            { "CARGO_BIN_DIR_${depName}" = "${drv}/bin"; }
+            // builtins.listToAttrs
+              (builtins.map (bin: lib.nameValuePair "CARGO_BIN_FILE_${depName}_${bin}" (binFile bin)) binNames)
+            // lib.optionalAttrs (builtins.elem crateConfig.crateName binNames) {
+              "CARGO_BIN_FILE_${depName}" = binFile crateConfig.crateName;
+            }
+          )
+        // lib.optionalAttrs (builtins.elem "cdylib" kinds) {
+          "CARGO_CDYLIB_DIR_${depName}" = "${drv.lib}/lib";
+        }
+        // lib.optionalAttrs (builtins.elem "staticlib" kinds) {
+          "CARGO_STATICLIB_DIR_${depName}" = "${drv.lib}/lib";
+        };
+    in
+    lib.foldl' (env: dep: env // envForDependency dep) { } artifactDependencies;
+
+  /* Returns the environment variables `DEP_<NAME>_OUT_DIR` with the installed `OUT_DIR`s of
+    the enabled dependencies with `exportOutDir`, by their names in `Cargo.toml`.
+  */
+  outDirDependencyEnv =
+    { buildByPackageId
+    , crateConfigs ? crates
+    , features
+    , dependencies
+    , target
+    }:
+    let
+      exportingDependencies = builtins.filter (dep: crateConfigs.${dep.packageId}.exportOutDir or false)
+        (filterEnabledDependencies { inherit dependencies features target; });
+      envForDependency = dep:
+        let
+          depName = lib.toUpper (builtins.replaceStrings [ "-" ] [ "_" ] (dep.rename or dep.name));
+        in
+        lib.nameValuePair "DEP_${depName}_OUT_DIR" "${(buildByPackageId dep.packageId).lib}/out-dir";
+    in
+    builtins.listToAttrs (builtins.map envForDependency exportingDependencies);
+
+  /* Returns a shell snippet that exports the metadata of the enabled dependencies with a
+    `links` key as `DEP_<LINKS>_<KEY>` for the build script, see
+    https://doc.rust-lang.org/cargo/reference/build-scripts.html#the-links-manifest-key.
+
+    buildRustCrate derives the prefix of these variables from the crate name, e.g. `DEP_LIBZ_`
+    for `libz-sys`, while cargo uses the `links` value, e.g. `DEP_Z_` for `links = "z"`.
+  */
+  linksDependencyHook =
+    { buildByPackageId
+    , crateConfigs ? crates
+    , features
+    , dependencies
+    , target
+    }:
+    let
+      envPrefix = name: lib.toUpper (builtins.replaceStrings [ "-" ] [ "_" ] name);
+      linkingDependencies = builtins.filter (dep: (crateConfigs.${dep.packageId}.links or null) != null)
+        (filterEnabledDependencies { inherit dependencies features target; });
+      hookForDependency = dep:
+        let
+          crateConfig = crateConfigs.${dep.packageId};
+          namePrefix = envPrefix (lib.removeSuffix "-sys" crateConfig.crateName);
+          linksPrefix = envPrefix crateConfig.links;
+          env = "${(buildByPackageId dep.packageId).lib}/env";
+        in
+        lib.optionalString (namePrefix != linksPrefix) ''
+          if [ -f ${env} ]; then
+            source <(sed -n 's/^export DEP_${namePrefix}_/export DEP_${linksPrefix}_/p' ${env})
+          fi
+        '';
+    in
+    lib.concatMapStrings hookForDependency linkingDependencies;
+
+  /* Returns a script that replaces `from` with `to` in `file` for every replacement, relative
+    to the crate source. The build fails if `from` does not occur, e.g. because a new release of
+    the crate made the replacement obsolete.
+  */
+  sourceReplacementsHook = replacements:
+    lib.concatMapStrings
+      (replacement: ''
+        substituteInPlace ${lib.escapeShellArg replacement.file} \
+          --replace-fail ${lib.escapeShellArg replacement.from} ${lib.escapeShellArg replacement.to}
+      '')
+      replacements;
 
   /* Returns a sanitized version of val with all values substituted that cannot
     be serialized as JSON.
@@ -517,7 +1628,11 @@ rec {
     else val;
 
   /* Returns various tools to debug a crate. */
-  debugCrate = { packageId, target ? makeDefaultTarget stdenv.hostPlatform }:
+  debugCrate =
+    { packageId
+    , target ? makeDefaultTarget stdenv.hostPlatform
+    , buildTarget ? makeDefaultTarget stdenv.buildPlatform
+    }:
     assert (builtins.isString packageId);
     let
       debug = rec {
@@ -538,9 +1653,12 @@ rec {
               inherit packageId;
             }
           );
+        # With feature resolver "2", crates built for the build platform appear separately
+        # as "build <packageId>".
         mergedPackageFeatures = mergePackageFeatures {
           features = rootFeatures;
-          inherit packageId target;
+          splitBuildFeatures = featureResolver == "2";
+          inherit packageId target buildTarget;
         };
         diffedDefaultPackageFeatures = diffDefaultPackageFeatures {
           inherit packageId target;
@@ -587,10 +1705,21 @@ rec {
         inherit onlyInCargo onlyInCrate2Nix differentFeatures;
       };
 
+  /* Returns the key of a package in the result of mergePackageFeatures.
+
+    With `splitBuildFeatures`, crates that are built for the build platform have their own
+    features, as with cargo's feature resolver version 2.
+  */
+  packageFeaturesKey = { splitBuildFeatures, isBuild, packageId }:
+    if splitBuildFeatures && isBuild then "build ${packageId}" else packageId;
+
   /* Returns an attrset mapping packageId to the list of enabled features.
 
     If multiple paths to a dependency enable different features, the
     corresponding feature sets are merged. Features in rust are additive.
+
+    With `splitBuildFeatures`, build dependencies, proc macros and their dependencies
+    are merged separately under the keys returned by packageFeaturesKey.
   */
   mergePackageFeatures =
     { crateConfigs ? crates
@@ -599,9 +1728,15 @@ rec {
     , features ? rootFeatures
     , dependencyPath ? [ crates.${packageId}.crateName ]
     , featuresByPackageId ? { }
+      # The platform that this package is built for.
     , target
+      # The platform that build dependencies and proc macros are built for.
+    , buildTarget ? target
       # Adds devDependencies to the crate with rootPackageId.
     , runTests ? false
+    , splitBuildFeatures ? false
+      # Whether this package is built for the build platform.
+    , isBuild ? false
     , ...
     } @ args:
       assert (builtins.isAttrs crateConfigs);
@@ -611,6 +1746,7 @@ rec {
       assert (builtins.isList dependencyPath);
       assert (builtins.isAttrs featuresByPackageId);
       assert (builtins.isAttrs target);
+      assert (builtins.isAttrs buildTarget);
       assert (builtins.isBool runTests);
       let
         crateConfig = crateConfigs."${packageId}" or (builtins.throw "Package not found: ${packageId}");
@@ -627,7 +1763,8 @@ rec {
           assert (builtins.isList dependencies);
           let
             enabledDependencies = filterEnabledDependencies {
-              inherit dependencies target;
+              inherit dependencies;
+              target = if path == "build" then buildTarget else target;
               features = enabledFeatures;
             };
             directDependencies = map depWithResolvedFeatures enabledDependencies;
@@ -637,25 +1774,34 @@ rec {
             (
               cache: { packageId, features }:
                 let
-                  cacheFeatures = cache.${packageId} or [ ];
+                  isBuild' =
+                    isBuild || path == "build" || (crateConfigs.${packageId}.procMacro or false);
+                  key = packageFeaturesKey {
+                    inherit splitBuildFeatures packageId;
+                    isBuild = isBuild';
+                  };
+                  cacheFeatures = cache.${key} or [ ];
                   combinedFeatures = sortedUnique (cacheFeatures ++ features);
                 in
-                if cache ? ${packageId} && cache.${packageId} == combinedFeatures
+                if cache ? ${key} && cache.${key} == combinedFeatures
                 then cache
                 else
                   mergePackageFeatures {
                     features = combinedFeatures;
                     featuresByPackageId = cache;
-                    inherit crateConfigs packageId target runTests rootPackageId;
+                    isBuild = isBuild';
+                    target = if isBuild' then buildTarget else target;
+                    inherit crateConfigs packageId buildTarget runTests rootPackageId splitBuildFeatures;
                   }
             );
         cacheWithSelf =
           let
-            cacheFeatures = featuresByPackageId.${packageId} or [ ];
+            key = packageFeaturesKey { inherit splitBuildFeatures isBuild packageId; };
+            cacheFeatures = featuresByPackageId.${key} or [ ];
             combinedFeatures = sortedUnique (cacheFeatures ++ enabledFeatures);
           in
           featuresByPackageId // {
-            "${packageId}" = combinedFeatures;
+            "${key}" = combinedFeatures;
           };
         cacheWithDependencies =
           resolveDependencies cacheWithSelf "dep"
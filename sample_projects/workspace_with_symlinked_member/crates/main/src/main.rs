@@ -0,0 +1,3 @@
+fn main() {
+    println!("Hello, {}!", linked_lib::get_info());
+}
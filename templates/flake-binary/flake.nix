@@ -53,11 +53,11 @@
           };
         in
         rec {
-          checks = {
-            rustnix = cargoNix.rootCrate.build.override {
-              runTests = true;
-            };
-          };
+          # Tests, clippy, rustfmt and the documentation of the workspace for `nix flake check`.
+          inherit (cargoNix) checks;
+
+          # Apps for all binaries of the workspace members, e.g. `nix run .#rustnix`.
+          inherit (cargoNix) apps;
 
           packages = {
             rustnix = cargoNix.rootCrate.build;
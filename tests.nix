@@ -496,6 +496,13 @@ let
       derivationAttrPath = [ "workspaceMembers" "main" ];
     }
 
+    {
+      name = "workspace_with_symlinked_member";
+      src = ./sample_projects/workspace_with_symlinked_member;
+      expectedOutput = "Hello, workspace_with_symlinked_member!";
+      derivationAttrPath = [ "workspaceMembers" "main" ];
+    }
+
     {
       name = "with_problematic_crates";
       src = ./sample_projects/with_problematic_crates;